@@ -1,24 +1,986 @@
 use git2::*;
 use fuser::{self, MountOption};
-use env_logger;
-use std::env;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use openat::Dir;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing_subscriber::{fmt::format::FmtSpan, fmt::writer::BoxMakeWriter, prelude::*, reload, EnvFilter, Layer, Registry};
 
 extern crate rockmore_git;
 use rockmore_git::gitfs::*;
+use rockmore_git::logging::JsonLayer;
+use rockmore_git::options::{CaseCollisions, Options};
+#[cfg(feature = "otlp")]
+use rockmore_git::otlp::OtlpLayer;
+#[cfg(target_os = "linux")]
+use rockmore_git::landlock::{Access, Ruleset};
+/// There's no Landlock to restrict git-mount with.
+#[cfg(not(target_os = "linux"))]
+type Ruleset = ();
+
+/// Changes what is logged, once logging is set up.
+type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// What `--serve` takes.
+#[cfg(feature = "http-api")]
+const PROTOCOLS: &[&str] = &["9p", "api", "nfs", "sftp", "webdav"];
+#[cfg(not(feature = "http-api"))]
+const PROTOCOLS: &[&str] = &["9p", "nfs", "sftp", "webdav"];
 
 fn main() {
-    env_logger::init();
-    let repo_path = env::args().nth(1).unwrap();
-    let mountpoint = env::args().nth(2).unwrap();
-    let dir = Dir::open(&mountpoint).unwrap();
+    let app = App::new("git-mount")
+        .about("Mount a git repository without checking it out")
+        .arg(Arg::with_name("REPO").required(true).help("Path to the repository"))
+        .arg(Arg::with_name("MOUNTPOINT").required(true).help("Where to mount the repository"))
+        .arg(Arg::with_name("repo")
+             .long("repo")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("PATH")
+             .help("Mount this repository too; each is then a dir of MOUNTPOINT named after it, with its dirty files in that dir underneath"))
+        .arg(Arg::with_name("compare")
+             .long("compare")
+             .takes_value(true)
+             .number_of_values(2)
+             .value_names(&["REV_A", "REV_B"])
+             .conflicts_with_all(&["repo", "ref"])
+             .help("Mount two commits side by side, as the a and b dirs of MOUNTPOINT, e.g. for a dir diff tool"))
+        .arg(Arg::with_name("also")
+             .long("also")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("REFSPEC:MOUNTPOINT")
+             .conflicts_with_all(&["repo", "compare", "fetch", "serve"])
+             .help("Mount this ref or commit of the repository too, at MOUNTPOINT, from the same process and with the same caches"))
+        .arg(Arg::with_name("ref")
+             .long("ref")
+             .short("r")
+             .takes_value(true)
+             .value_name("REFSPEC")
+             .help("The ref or commit to mount (default: HEAD)"))
+        .arg(Arg::with_name("subdir")
+             .long("subdir")
+             .takes_value(true)
+             .value_name("PATH")
+             .help("Mount only this dir of the commit at the root, e.g. services/api"))
+        .arg(Arg::with_name("log-format")
+             .long("log-format")
+             .takes_value(true)
+             .possible_values(&["text", "json"])
+             .help("How to write logs (default: text); RUST_LOG sets what is logged"))
+        .arg(Arg::with_name("config")
+             .long("config")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("Take the settings in FILE (TTLs, cache size, path filters, log level, ...) over the options, and read it again on SIGHUP or 'reload' in .gitfs/ctl"))
+        .arg(Arg::with_name("ttl")
+             .long("ttl")
+             .takes_value(true)
+             .value_name("SECONDS")
+             .validator(seconds)
+             .help("How long the kernel may cache entries and attributes"))
+        .arg(Arg::with_name("blob-cache-size")
+             .long("blob-cache-size")
+             .takes_value(true)
+             .validator(number::<usize>)
+             .value_name("BYTES")
+             .help("Upper bound of blob contents kept in memory"))
+        .arg(Arg::with_name("max-readahead")
+             .long("max-readahead")
+             .takes_value(true)
+             .validator(number::<u32>)
+             .value_name("BYTES")
+             .help("Maximum readahead requested from the kernel"))
+        .arg(Arg::with_name("max-write")
+             .long("max-write")
+             .takes_value(true)
+             .validator(number::<u32>)
+             .value_name("BYTES")
+             .help("Maximum size of a write request from the kernel"))
+        .arg(Arg::with_name("max-background")
+             .long("max-background")
+             .takes_value(true)
+             .validator(number::<u16>)
+             .value_name("N")
+             .help("Maximum number of background requests in flight"))
+        .arg(Arg::with_name("congestion-threshold")
+             .long("congestion-threshold")
+             .takes_value(true)
+             .validator(number::<u16>)
+             .value_name("N")
+             .help("Background requests in flight at which the mount is congested"))
+        .arg(Arg::with_name("overlay-quota")
+             .long("overlay-quota")
+             .takes_value(true)
+             .validator(number::<u64>)
+             .value_name("BYTES")
+             .help("Refuse writes with EDQUOT once dirty files take up BYTES"))
+        .arg(Arg::with_name("read-rate")
+             .long("read-rate")
+             .takes_value(true)
+             .validator(number::<u64>)
+             .value_name("BYTES")
+             .help("Read at most BYTES per second from files"))
+        .arg(Arg::with_name("write-rate")
+             .long("write-rate")
+             .takes_value(true)
+             .validator(number::<u64>)
+             .value_name("BYTES")
+             .help("Write at most BYTES per second to files"))
+        .arg(Arg::with_name("iops")
+             .long("iops")
+             .takes_value(true)
+             .validator(number::<u64>)
+             .value_name("N")
+             .help("Read and write files at most N times per second"))
+        .arg(Arg::with_name("blob-store")
+             .long("blob-store")
+             .takes_value(true)
+             .value_name("DIR")
+             .help("Keep checked out blobs in DIR, hard-linked by other mounts until changed"))
+        .arg(Arg::with_name("escape-names")
+             .long("escape-names")
+             .help("Escape names the overlay file system may reject when storing dirty files"))
+        .arg(Arg::with_name("case-collisions")
+             .long("case-collisions")
+             .takes_value(true)
+             .possible_values(&["ignore", "error", "read-only"])
+             .help("How to handle names that only differ in case"))
+        .arg(Arg::with_name("mtime")
+             .long("mtime")
+             .takes_value(true)
+             .possible_values(&["epoch", "commit"])
+             .help("Where the times of files come from"))
+        .arg(Arg::with_name("uid")
+             .long("uid")
+             .takes_value(true)
+             .validator(number::<u32>)
+             .help("The owner of files (default: the user running git-mount)"))
+        .arg(Arg::with_name("gid")
+             .long("gid")
+             .takes_value(true)
+             .validator(number::<u32>)
+             .help("The group of files (default: the group running git-mount)"))
+        .arg(Arg::with_name("watch")
+             .long("watch")
+             .takes_value(true)
+             .value_name("SECONDS")
+             .validator(interval)
+             .help("Follow the mounted ref, checking for updates every SECONDS"))
+        .arg(Arg::with_name("fetch")
+             .long("fetch")
+             .takes_value(true)
+             .value_name("REMOTE/BRANCH")
+             .conflicts_with_all(&["repo", "compare", "ref"])
+             .help("Mount BRANCH as fetched from REMOTE, read-only, fetching it again every --fetch-interval"))
+        .arg(Arg::with_name("fetch-interval")
+             .long("fetch-interval")
+             .takes_value(true)
+             .value_name("SECONDS")
+             .requires("fetch")
+             .validator(interval)
+             .help("How often --fetch fetches (default: 60)"))
+        .arg(Arg::with_name("watch-overlay")
+             .long("watch-overlay")
+             .help("Watch the mountpoint for changes made by others (Linux only)"))
+        .arg(Arg::with_name("force")
+             .long("force")
+             .help("Mount even where it's likely to cause trouble"))
+        .arg(Arg::with_name("read-only")
+             .long("read-only")
+             .help("Refuse all changes to the mounted tree"))
+        .arg(Arg::with_name("tidy")
+             .long("tidy")
+             .help("Drop dirty files that are left the same as in the tree when they're closed"))
+        .arg(Arg::with_name("last-commit-xattrs")
+             .long("last-commit-xattrs")
+             .help("Tell the last commit that changed each file in its user.gitfs.last-* xattrs"))
+        .arg(Arg::with_name("chunk-size")
+             .long("chunk-size")
+             .takes_value(true)
+             .validator(positive)
+             .value_name("BYTES")
+             .help("Show files larger than BYTES in chunks of BYTES too, in .gitfs/chunks"))
+        .arg(Arg::with_name("slow-op-threshold")
+             .long("slow-op-threshold")
+             .takes_value(true)
+             .validator(seconds)
+             .value_name("SECONDS")
+             .help("Warn about operations taking longer than SECONDS"))
+        .arg(Arg::with_name("summary")
+             .long("summary")
+             .help("Log a summary of the operations, bytes read, cache hits and slowest paths on unmount, whatever RUST_LOG says"))
+        .arg(Arg::with_name("deny")
+             .long("deny")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("GLOB")
+             .help("Hide the paths matching GLOB, e.g. 'secrets/**'"))
+        .arg(Arg::with_name("readonly")
+             .long("readonly")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("GLOB")
+             .help("Refuse changes to the paths matching GLOB, e.g. 'vendor/**'"))
+        .arg(Arg::with_name("include")
+             .long("include")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("GLOB")
+             .help("Only show the paths matching GLOB, with what is in them, e.g. 'src' or 'docs/**/*.md'"))
+        .arg(Arg::with_name("exclude")
+             .long("exclude")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("GLOB")
+             .help("Don't show the paths matching GLOB, even if included, e.g. 'src/generated/**'"))
+        .arg(Arg::with_name("pin")
+             .long("pin")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .value_name("GLOB")
+             .help("Load the paths matching GLOB at mount and keep them in memory, e.g. 'Cargo.lock'"))
+        .arg(Arg::with_name("audit-log")
+             .long("audit-log")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("Append a line to FILE for every change made through the mount"))
+        .arg(Arg::with_name("overlay-key")
+             .long("overlay-key")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("Encrypt the files in the underlying dir with the key in FILE (32 bytes, or 64 hex digits)"))
+        .arg(Arg::with_name("smudge")
+             .long("smudge")
+             .help("Convert files as a checkout would (eol, ident and working-tree-encoding attributes)"))
+        .arg(Arg::with_name("lfs")
+             .long("lfs")
+             .help("Show Git LFS pointer files as their objects, fetching them from the LFS server (lfs.url, or after origin) on first read"))
+        .arg(Arg::with_name("annex")
+             .long("annex")
+             .help("Show git-annex files as their objects, when those are in the local annex"))
+        .arg(Arg::with_name("whiteouts")
+             .long("whiteouts")
+             .requires("read-only")
+             .help("Show the whiteouts and opaque dirs of an OCI image layer in the commit as overlayfs does, to stack the mount as a lowerdir"))
+        .arg(Arg::with_name("samba")
+             .long("samba")
+             .conflicts_with("whiteouts")
+             .help("Make the mount fit to share with Samba: DOS attributes in user.DOSATTRIB, names that only differ in case read-only unless --case-collisions says otherwise, and no control dir, whose files SMB clients would read as empty"))
+        .arg(Arg::with_name("no-control-dir")
+             .long("no-control-dir")
+             .help("Leave out the control dir /.gitfs"))
+        .arg(Arg::with_name("git-file")
+             .long("git-file")
+             .conflicts_with("subdir")
+             .help("Show a read-only /.git file pointing at the repository, so that git and IDEs work in the mount"))
+        .arg(Arg::with_name("selfcheck")
+             .long("selfcheck")
+             .conflicts_with("serve")
+             .help("Instead of mounting, list the whole tree, check that what gitfs makes of it is consistent, and exit with 1 if it isn't"))
+        .arg(Arg::with_name("serve")
+             .long("serve")
+             .takes_value(true)
+             .possible_values(PROTOCOLS)
+             .help("Serve the tree over 9P2000.L, export it read-only over NFSv3, serve it over SFTP on stdin and stdout (as an sshd subsystem), over WebDAV, or read-only over an HTTP API (if built with it), instead of mounting it; MOUNTPOINT keeps the dirty files"))
+        .arg(Arg::with_name("listen")
+             .long("listen")
+             .takes_value(true)
+             .validator(address)
+             .value_name("ADDR")
+             .requires("serve")
+             .help("Where to serve with --serve 9p, nfs, webdav or api, unless systemd passes a socket (default: 127.0.0.1:5640 for 9p, 127.0.0.1:2049 for nfs, 127.0.0.1:8080 for webdav, 127.0.0.1:8081 for api)"))
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .subcommand(SubCommand::with_name("layer")
+             .about("Write an OCI image layer of a commit, or of what differs in a mount from its commit")
+             .arg(Arg::with_name("REPO").required_unless("mount").help("Path to the repository"))
+             .arg(Arg::with_name("ref")
+                  .long("ref")
+                  .short("r")
+                  .takes_value(true)
+                  .value_name("REFSPEC")
+                  .help("The ref or commit (default: HEAD)"))
+             .arg(Arg::with_name("mount")
+                  .long("mount")
+                  .takes_value(true)
+                  .value_name("MOUNTPOINT")
+                  .conflicts_with_all(&["REPO", "ref"])
+                  .help("Write the changes in this mount instead, with whiteouts for deleted paths, as a layer on top of its commit"))
+             .arg(Arg::with_name("gzip")
+                  .long("gzip")
+                  .short("z")
+                  .help("Compress the layer with gzip"))
+             .arg(Arg::with_name("output")
+                  .long("output")
+                  .short("o")
+                  .takes_value(true)
+                  .value_name("FILE")
+                  .help("Where to write the layer (default: stdout)")));
+    #[cfg(feature = "metrics")]
+    let app = app.arg(Arg::with_name("metrics")
+             .long("metrics")
+             .takes_value(true)
+             .validator(address)
+             .value_name("ADDR")
+             .help("Export metrics to Prometheus at http://ADDR/metrics"));
+    #[cfg(feature = "otlp")]
+    let app = app.arg(Arg::with_name("otlp")
+             .long("otlp")
+             .takes_value(true)
+             .value_name("URL")
+             .help("Export the spans of operations to an OTLP/HTTP collector, e.g. http://localhost:4318/v1/traces"));
+    #[cfg(target_os = "linux")]
+    let app = app.arg(Arg::with_name("landlock")
+             .long("landlock")
+             .help("Once mounted or serving, restrict git-mount with Landlock to the repository, MOUNTPOINT, and the configuration it reads"));
+    #[cfg(target_os = "linux")]
+    let app = app.arg(Arg::with_name("seccomp")
+             .long("seccomp")
+             .takes_value(true)
+             .value_name("ACTION")
+             .possible_values(&["kill", "errno", "log"])
+             .help("Once mounted or serving, confine git-mount to the system calls it needs, killing it on others, failing them, or only logging them"));
+    // How Finder shows the mount, told to macFUSE.
+    #[cfg(target_os = "macos")]
+    let app = app
+        .arg(Arg::with_name("volname")
+             .long("volname")
+             .takes_value(true)
+             .value_name("NAME")
+             .help("The name of the volume in Finder (default: the name of the repository)"))
+        .arg(Arg::with_name("volicon")
+             .long("volicon")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("The icon of the volume in Finder, an .icns file"))
+        .arg(Arg::with_name("local")
+             .long("local")
+             .help("Show the volume as a local disk rather than a network one"))
+        .arg(Arg::with_name("noappledouble")
+             .long("noappledouble")
+             .help("Refuse the ._* and .DS_Store files Finder makes, rather than keep them in the overlay"));
+    let matches = app.get_matches();
+    if let Some(matches) = matches.subcommand_matches("layer") {
+        return write_layer(matches);
+    }
+
+    // Every FUSE operation runs in its own span; report span closes
+    // so that each operation is logged with its duration.
+    let json = matches.value_of("log-format") == Some("json");
+    // Serving SFTP takes stdout, so logs go to stderr then.
+    let sftp = matches.value_of("serve") == Some("sftp");
+    let writer = || if sftp { BoxMakeWriter::new(std::io::stderr) } else { BoxMakeWriter::new(std::io::stdout) };
+    let summary = matches.is_present("summary");
+    // What is logged may change with the `log` setting of --config.
+    let (filter, log_handle) = reload::Layer::new(log_filter(None, summary).unwrap());
+    let log: Box<dyn Layer<Registry> + Send + Sync> = match json {
+        false => Box::new(
+            tracing_subscriber::fmt::layer()
+                .with_writer(writer())
+                .with_span_events(FmtSpan::CLOSE)
+                .with_filter(filter),
+        ),
+        true => Box::new(JsonLayer::new(writer()).with_span_closes(true).with_filter(filter)),
+    };
+    let registry = tracing_subscriber::registry().with(log);
+    // Operations are exported whatever RUST_LOG says.
+    #[cfg(feature = "otlp")]
+    let registry = registry.with(matches.value_of("otlp").map(|endpoint| {
+        let targets = tracing_subscriber::filter::Targets::new()
+            .with_target("rockmore_git", tracing_subscriber::filter::LevelFilter::DEBUG);
+        OtlpLayer::new(endpoint, "git-mount")
+            .unwrap_or_else(|e| fail(&format!("invalid --otlp: {}", e)))
+            .with_filter(targets)
+    }));
+    registry.init();
+
+    let mut opts = Options::default();
+    if let Some(ttl) = matches.value_of("ttl") {
+        // Checked by clap.
+        let ttl = Duration::from_secs_f64(ttl.parse().unwrap());
+        opts.attr_ttl = ttl;
+        opts.entry_ttl = ttl;
+    }
+    if let Some(size) = parsed(&matches, "blob-cache-size") {
+        opts.blob_cache_size = size;
+    }
+    if let Some(size) = parsed(&matches, "max-readahead") {
+        opts.max_readahead = size;
+    }
+    if let Some(size) = parsed(&matches, "max-write") {
+        opts.max_write = size;
+    }
+    if let Some(n) = parsed(&matches, "max-background") {
+        opts.max_background = n;
+    }
+    if let Some(n) = parsed(&matches, "congestion-threshold") {
+        opts.congestion_threshold = Some(n);
+    }
+    if let Some(quota) = parsed(&matches, "overlay-quota") {
+        opts.overlay_quota = Some(quota);
+    }
+    if let Some(rate) = parsed(&matches, "read-rate") {
+        opts.read_rate = Some(rate);
+    }
+    if let Some(rate) = parsed(&matches, "write-rate") {
+        opts.write_rate = Some(rate);
+    }
+    if let Some(iops) = parsed(&matches, "iops") {
+        opts.iops = Some(iops);
+    }
+    opts.blob_store = matches.value_of("blob-store").map(Into::into);
+    opts.escape_names = matches.is_present("escape-names");
+    opts.smudge = matches.is_present("smudge");
+    opts.lfs = matches.is_present("lfs");
+    opts.annex = matches.is_present("annex");
+    opts.whiteouts = matches.is_present("whiteouts");
+    let samba = matches.is_present("samba");
+    if let Some(policy) = matches.value_of("case-collisions") {
+        opts.case_collisions = policy.parse().unwrap();
+    } else if samba {
+        opts.case_collisions = CaseCollisions::ReadOnly;
+    }
+    opts.dos_attributes = samba;
+    opts.uid = parsed(&matches, "uid");
+    opts.gid = parsed(&matches, "gid");
+    if let Some(policy) = matches.value_of("mtime") {
+        opts.mtime = policy.parse().unwrap();
+    }
+    // A mirror of what was fetched, which dirty files would get in the
+    // way of.
+    opts.read_only = matches.is_present("read-only") || matches.is_present("fetch");
+    let read_only = opts.read_only;
+    opts.control_dir = !matches.is_present("no-control-dir") && !samba;
+    opts.git_file = matches.is_present("git-file");
+    opts.tidy = matches.is_present("tidy");
+    opts.last_commit_xattrs = matches.is_present("last-commit-xattrs");
+    opts.chunk_size = parsed(&matches, "chunk-size");
+    opts.denied_paths = matches.values_of("deny").into_iter().flatten().map(str::to_owned).collect();
+    opts.read_only_paths = matches.values_of("readonly").into_iter().flatten().map(str::to_owned).collect();
+    opts.included_paths = matches.values_of("include").into_iter().flatten().map(str::to_owned).collect();
+    opts.excluded_paths = matches.values_of("exclude").into_iter().flatten().map(str::to_owned).collect();
+    opts.pinned_paths = matches.values_of("pin").into_iter().flatten().map(str::to_owned).collect();
+    if let Some(threshold) = parsed(&matches, "slow-op-threshold") {
+        opts.slow_op_threshold = Some(Duration::from_secs_f64(threshold));
+    }
+    opts.summary_on_unmount = summary;
+
+    let repo_path = matches.value_of("REPO").unwrap();
+    let mountpoint = matches.value_of("MOUNTPOINT").unwrap();
+    if let Some(more) = matches.values_of("repo") {
+        let members: Vec<_> = std::iter::once(repo_path)
+            .chain(more)
+            .map(|path| Member { path, name: None, refspec: None })
+            .collect();
+        return mount_several(&matches, &log_handle, &members, mountpoint, opts);
+    }
+    if let Some(revs) = matches.values_of("compare") {
+        let members: Vec<_> = ["a", "b"]
+            .iter()
+            .zip(revs)
+            .map(|(&name, rev)| Member { path: repo_path, name: Some(name), refspec: Some(rev) })
+            .collect();
+        return mount_several(&matches, &log_handle, &members, mountpoint, opts);
+    }
     let repo = Repository::open(repo_path).unwrap();
+    // The remote-tracking ref must be there to be mounted.
+    if let Some((remote, branch)) = fetched(&matches) {
+        rockmore_git::watch::fetch(repo.path(), remote, branch)
+            .unwrap_or_else(|e| fail(&format!("cannot fetch {} from {}: {}", branch, remote, e)));
+    }
+    #[cfg(target_os = "macos")]
+    let volname = match matches.value_of("volname") {
+        Some(name) => name.to_owned(),
+        None => {
+            let dir = repo.workdir().unwrap_or_else(|| repo.path());
+            dir.file_name().map_or_else(|| "gitfs".to_owned(), |name| name.to_string_lossy().into_owned())
+        }
+    };
+    check_layout(&matches, &repo, Path::new(mountpoint));
+    let dir = Dir::open(mountpoint).unwrap();
+    let mut ruleset = landlock(&matches);
+    allow(&mut ruleset, &repo, Path::new(mountpoint));
+
+    let fs = builder(&matches, &log_handle, repo, dir).options(opts.clone()).build();
+    load_config(&matches, &fs);
+    let also: Vec<_> = matches.values_of("also").into_iter().flatten().map(|also| {
+        // Refs can't have a colon.
+        let (refspec, mountpoint) = match also.split_once(':') {
+            Some((refspec, mountpoint)) if !refspec.is_empty() && !mountpoint.is_empty() => (refspec, mountpoint),
+            _ => fail(&format!("invalid --also: {}", also)),
+        };
+        let repo = Repository::open(repo_path).unwrap();
+        check_layout(&matches, &repo, Path::new(mountpoint));
+        let dir = Dir::open(mountpoint).unwrap_or_else(|e| fail(&format!("cannot open {}: {}", mountpoint, e)));
+        allow(&mut ruleset, &repo, Path::new(mountpoint));
+        let also = builder(&matches, &log_handle, repo, dir)
+            .refspec(refspec)
+            .share_repository(&fs)
+            .options(opts.clone())
+            .build();
+        load_config(&matches, &also);
+        (mountpoint, also)
+    }).collect();
+    if matches.is_present("selfcheck") {
+        let repos: Vec<_> = std::iter::once((None, &fs))
+            .chain(also.iter().map(|(mountpoint, fs)| (Some(Path::new(*mountpoint)), fs)))
+            .collect();
+        return self_check(&repos);
+    }
+    lock_overlay(&matches, &fs, Path::new(mountpoint));
+    for (mountpoint, fs) in &also {
+        lock_overlay(&matches, fs, Path::new(mountpoint));
+    }
+    // Servers start threads of their own, restricted when they are.
+    if matches.is_present("serve") {
+        restrict(ruleset.take());
+    }
+    let mut watchers = watch(&matches, &fs);
+    for (_, fs) in &also {
+        watchers.extend(watch(&matches, fs));
+    }
+    // `kill -USR1` logs what the mount holds, with RUST_LOG=info.
+    let _state_dump = fs.dump_state_on_sigusr1().unwrap();
+    #[cfg(feature = "metrics")]
+    let _metrics = parsed(&matches, "metrics").map(|addr| fs.serve_metrics(addr).unwrap());
+    if sftp {
+        let (stdin, stdout) = (std::io::stdin(), std::io::stdout());
+        confine(&matches);
+        fs.serve_sftp(stdin.lock(), stdout.lock()).unwrap();
+        return;
+    }
+    if let Some(protocol) = matches.value_of("serve") {
+        let default_addr = match protocol {
+            "nfs" => "127.0.0.1:2049",
+            "webdav" => "127.0.0.1:8080",
+            "api" => "127.0.0.1:8081",
+            _ => "127.0.0.1:5640",
+        };
+        let addr = parsed(&matches, "listen").unwrap_or_else(|| default_addr.parse().unwrap());
+        let _server = match protocol {
+            "nfs" => fs.serve_nfs(addr),
+            "webdav" => fs.serve_webdav(addr),
+            #[cfg(feature = "http-api")]
+            "api" => fs.serve_api(addr),
+            _ => fs.serve_9p(addr),
+        }.unwrap();
+        ready();
+        confine(&matches);
+        // Served until killed.
+        loop {
+            std::thread::park();
+        }
+    }
+    #[allow(unused_mut)]
+    let mut options = mount_options(&matches, read_only);
+    #[cfg(target_os = "macos")]
+    options.push(MountOption::CUSTOM(format!("volname={}", volname)));
+    run(&matches, ruleset, fs, mountpoint, also, &options);
+}
+
+/// A repository mounted as a dir of the mountpoint by `--repo`, or a
+/// commit of one by `--compare`.
+struct Member<'a> {
+    path: &'a str,
+    /// The name of the dir, if not that of the repository.
+    name: Option<&'a str>,
+    /// The ref or commit, if not that of `--ref`.
+    refspec: Option<&'a str>,
+}
+
+/// Mount each of `members` as the dir of `mountpoint` of its name,
+/// which its overlay is in.  They share a blob cache, which makes
+/// commits of the same repository cheap to mount side by side.
+fn mount_several(matches: &ArgMatches, log_handle: &LogHandle, members: &[Member], mountpoint: &str, opts: Options) {
+    if matches.is_present("serve") {
+        fail("only one repository can be served");
+    }
+    #[cfg(feature = "metrics")]
+    if matches.is_present("metrics") {
+        fail("--metrics is only for one repository");
+    }
+    let read_only = opts.read_only;
+    let mut repos: Vec<(std::ffi::OsString, GitFS)> = vec![];
+    let mut watchers = vec![];
+    let mut ruleset = landlock(matches);
+    for member in members {
+        let path = member.path;
+        let repo = Repository::open(path).unwrap_or_else(|e| fail(&format!("cannot open {}: {}", path, e)));
+        let name = match member.name {
+            Some(name) => name.into(),
+            None => {
+                let dir = repo.workdir().unwrap_or_else(|| repo.path());
+                let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+                match dir.file_name() {
+                    Some(name) => name.to_owned(),
+                    None => fail(&format!("cannot name a dir after {}", path)),
+                }
+            }
+        };
+        let underlying = Path::new(mountpoint).join(&name);
+        fs::create_dir_all(&underlying)
+            .unwrap_or_else(|e| fail(&format!("cannot create {}: {}", underlying.display(), e)));
+        check_layout(matches, &repo, &underlying);
+        let dir = Dir::open(&underlying).unwrap();
+        allow(&mut ruleset, &repo, &underlying);
+        let mut builder = builder(matches, log_handle, repo, dir).options(opts.clone());
+        if let Some(refspec) = member.refspec {
+            builder = builder.refspec(refspec);
+        }
+        // Commits of the same repository share all of it.
+        if let Some((_, fs)) = members.iter().zip(&repos).find(|(other, _)| other.path == path).map(|(_, repo)| repo) {
+            builder = builder.share_repository(fs);
+        } else if let Some((_, first)) = repos.first() {
+            builder = builder.share_blob_cache(first);
+        }
+        let fs = builder.build();
+        load_config(matches, &fs);
+        if !matches.is_present("selfcheck") {
+            lock_overlay(matches, &fs, &underlying);
+        }
+        watchers.extend(watch(matches, &fs));
+        repos.push((name, fs));
+    }
+    if matches.is_present("selfcheck") {
+        let repos: Vec<_> = repos.iter().map(|(name, fs)| (Some(Path::new(name)), fs)).collect();
+        return self_check(&repos);
+    }
+    // SIGUSR1 sets a single flag, so there's no state dump of several.
+    let fs = MultiFS::new(repos).unwrap_or_else(|e| fail(&e.to_string()));
+    #[allow(unused_mut)]
+    let mut options = mount_options(matches, read_only);
+    #[cfg(target_os = "macos")]
+    options.push(MountOption::CUSTOM(format!("volname={}", matches.value_of("volname").unwrap_or("gitfs"))));
+    run(matches, ruleset, fs, mountpoint, vec![], &options);
+}
+
+/// `--selfcheck`: report what's wrong in each repository, named if
+/// there are several, and exit with 1 if anything is.
+fn self_check(repos: &[(Option<&Path>, &GitFS)]) {
+    let mut consistent = true;
+    for (name, fs) in repos {
+        for problem in fs.list_all().into_iter().chain(fs.self_check()) {
+            match name {
+                Some(name) => eprintln!("git-mount: {}: {}", name.display(), problem),
+                None => eprintln!("git-mount: {}", problem),
+            }
+            consistent = false;
+        }
+    }
+    if !consistent {
+        process::exit(1);
+    }
+}
+
+/// Warn about the risks of mounting `repo` at `mountpoint`, and exit
+/// unless forced.
+fn check_layout(matches: &ArgMatches, repo: &Repository, mountpoint: &Path) {
+    let risks = rockmore_git::layout::check(repo, mountpoint);
+    for risk in &risks {
+        eprintln!("git-mount: warning: {}", risk);
+    }
+    if !risks.is_empty() && !matches.is_present("force") {
+        eprintln!("git-mount: refusing to mount, use --force to mount anyway");
+        process::exit(1);
+    }
+}
+
+/// Lock the underlying dir at `path` for `fs`, and exit if another
+/// gitfs has it, unless forced.
+fn lock_overlay(matches: &ArgMatches, fs: &GitFS, path: &Path) {
+    match fs.lock_overlay() {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && matches.is_present("force") => {
+            eprintln!("git-mount: warning: {} is in use by another gitfs", path.display());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            fail(&format!("{} is in use by another gitfs, use --force to mount anyway", path.display()))
+        }
+        Err(e) => fail(&format!("cannot lock {}: {}", path.display(), e)),
+    }
+}
+
+/// A builder for `repo` with what is given besides the options.
+fn builder(matches: &ArgMatches, log_handle: &LogHandle, repo: Repository, dir: Dir) -> GitFSBuilder {
+    let refspec = match fetched(matches) {
+        Some((remote, branch)) => format!("refs/remotes/{}/{}", remote, branch),
+        None => matches.value_of("ref").unwrap_or("HEAD").to_owned(),
+    };
+    let mut builder = GitFS::builder(repo, dir).refspec(&refspec);
+    if let Some(path) = matches.value_of("config") {
+        let (log_handle, summary) = (log_handle.clone(), matches.is_present("summary"));
+        builder = builder.config_file(Path::new(path)).log_filter(move |directives| {
+            let filter = log_filter(directives, summary)?;
+            log_handle.reload(filter).map_err(|e| e.to_string())
+        });
+    }
+    if let Some(path) = matches.value_of("subdir") {
+        builder = builder.subdir(Path::new(path));
+    }
+    if let Some(path) = matches.value_of("audit-log") {
+        let file = OpenOptions::new().create(true).append(true).open(path).expect("cannot open --audit-log");
+        builder = builder.audit_log(file);
+    }
+    if let Some(path) = matches.value_of("overlay-key") {
+        let key = OverlayKey::from_file(Path::new(path)).expect("cannot read --overlay-key");
+        builder = builder.overlay_key(key);
+    }
+    builder
+}
+
+/// The remote and branch of `--fetch`.
+fn fetched<'a>(matches: &'a ArgMatches) -> Option<(&'a str, &'a str)> {
+    let fetch = matches.value_of("fetch")?;
+    match fetch.split_once('/') {
+        Some((remote, branch)) if !remote.is_empty() && !branch.is_empty() => Some((remote, branch)),
+        _ => fail(&format!("invalid --fetch: {}, expected REMOTE/BRANCH", fetch)),
+    }
+}
+
+/// What is logged: what `directives` say, as in `RUST_LOG`, or else
+/// what `RUST_LOG` says, with the summary of `--summary` whatever
+/// they say.
+fn log_filter(directives: Option<&str>, summary: bool) -> Result<EnvFilter, String> {
+    let filter = match directives {
+        Some(directives) => EnvFilter::try_new(directives).map_err(|e| e.to_string())?,
+        None => EnvFilter::from_default_env(),
+    };
+    Ok(match summary {
+        true => filter.add_directive("rockmore_git::gitfs::stats=info".parse().unwrap()),
+        false => filter,
+    })
+}
+
+/// Apply `--config` before mounting.
+fn load_config(matches: &ArgMatches, fs: &GitFS) {
+    if let Some(path) = matches.value_of("config") {
+        fs.reload_config().unwrap_or_else(|e| fail(&format!("cannot read {}: {}", path, e)));
+    }
+}
+
+/// The watchers asked for, which run until dropped.
+fn watch(matches: &ArgMatches, fs: &GitFS) -> Vec<rockmore_git::watch::Watcher> {
+    let mut watchers = vec![];
+    // `kill -HUP` reloads --config.
+    if matches.is_present("config") {
+        watchers.push(fs.reload_config_on_sighup().unwrap());
+    }
+    if matches.is_present("watch-overlay") {
+        watchers.push(fs.watch_overlay().unwrap());
+    }
+    if let Some(interval) = matches.value_of("watch") {
+        // Checked by clap.
+        let interval = Duration::from_secs_f64(interval.parse().unwrap());
+        watchers.push(fs.watch(interval).unwrap());
+    }
+    if let Some((remote, branch)) = fetched(matches) {
+        let interval = matches.value_of("fetch-interval").unwrap_or("60");
+        // Checked by clap.
+        let interval = Duration::from_secs_f64(interval.parse().unwrap());
+        watchers.push(fs.watch_remote(remote, branch, interval).unwrap());
+    }
+    watchers
+}
+
+#[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
+fn mount_options(matches: &ArgMatches, read_only: bool) -> Vec<MountOption> {
+    let mut options = vec![MountOption::FSName("gitfs".to_string())];
+    // Both are fusermount's: the BSDs mount over non-empty dirs anyway,
+    // and unmount when the session ends, as there is none to do it.
+    if cfg!(not(any(target_os = "freebsd", target_os = "openbsd"))) {
+        options.push(MountOption::AutoUnmount);
+        options.push(MountOption::CUSTOM("nonempty".to_string()));
+    }
+    if read_only {
+        options.push(MountOption::RO);
+    }
+    #[cfg(target_os = "macos")]
+    {
+        if let Some(icon) = matches.value_of("volicon") {
+            options.push(MountOption::CUSTOM(format!("volicon={}", icon)));
+        }
+        if matches.is_present("local") {
+            options.push(MountOption::CUSTOM("local".to_string()));
+        }
+        if matches.is_present("noappledouble") {
+            options.push(MountOption::CUSTOM("noappledouble".to_string()));
+        }
+    }
+    options
+}
+
+fn run<FS: fuser::Filesystem>(
+    matches: &ArgMatches,
+    ruleset: Option<Ruleset>,
+    fs: FS,
+    mountpoint: &str,
+    also: Vec<(&str, GitFS)>,
+    options: &[MountOption],
+) {
+    // Not mount2(), so that systemd is told once mounted.
+    let mut session = fuser::Session::new(fs, Path::new(mountpoint), options).unwrap();
+    let also: Vec<_> = also.into_iter().map(|(mountpoint, fs)| {
+        fuser::Session::new(fs, Path::new(mountpoint), options)
+            .unwrap_or_else(|e| fail(&format!("cannot mount {}: {}", mountpoint, e)))
+    }).collect();
+    // The session is served in this thread, and those of --also in
+    // threads of their own, which are unmounted once it's done.
+    restrict(ruleset);
+    let _also: Vec<_> = also.into_iter().map(|session| session.spawn().unwrap()).collect();
+    ready();
+    confine(matches);
+    session.run().unwrap();
+}
+
+/// Tell systemd, if it runs git-mount as a `Type=notify` service, that
+/// the mount can be used.
+fn ready() {
+    if let Err(e) = rockmore_git::systemd::notify("READY=1") {
+        eprintln!("git-mount: warning: cannot notify systemd: {}", e);
+    }
+}
+
+/// The Landlock rules of `--landlock`, made while the dirs they allow
+/// can still be opened.
+#[cfg(target_os = "linux")]
+fn landlock(matches: &ArgMatches) -> Option<Ruleset> {
+    let mut ruleset = matches.is_present("landlock")
+        .then(|| Ruleset::new().unwrap_or_else(|e| fail(&format!("cannot use Landlock: {}", e))))?;
+    // The dir of --config, as editors replace the file when saving it.
+    if let Some(path) = matches.value_of("config") {
+        let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        ruleset.allow(dir, Access::Read).unwrap_or_else(|e| fail(&format!("cannot make the Landlock rules: {}", e)));
+    }
+    Some(ruleset)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn landlock(_matches: &ArgMatches) -> Option<Ruleset> {
+    None
+}
+
+/// Allow the repository and the overlay of a mount.
+#[cfg(target_os = "linux")]
+fn allow(ruleset: &mut Option<Ruleset>, repo: &Repository, overlay: &Path) {
+    if let Some(ruleset) = ruleset {
+        ruleset.allow_repository(repo)
+            .and_then(|()| ruleset.allow(overlay, Access::Write))
+            .unwrap_or_else(|e| fail(&format!("cannot make the Landlock rules: {}", e)));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allow(_ruleset: &mut Option<Ruleset>, _repo: &Repository, _overlay: &Path) {}
+
+/// Enforce the Landlock rules, on this thread and those it starts.
+#[cfg(target_os = "linux")]
+fn restrict(ruleset: Option<Ruleset>) {
+    if let Some(ruleset) = ruleset {
+        ruleset.restrict_self().unwrap_or_else(|e| fail(&format!("cannot enforce the Landlock rules: {}", e)));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn restrict(_ruleset: Option<Ruleset>) {}
+
+/// Install the seccomp filter of `--seccomp`, now that what takes more
+/// (mounting, with the setuid fusermount3) is done.
+#[cfg(target_os = "linux")]
+fn confine(matches: &ArgMatches) {
+    if let Some(action) = matches.value_of("seccomp") {
+        rockmore_git::seccomp::confine(action.parse().unwrap())
+            .unwrap_or_else(|e| fail(&format!("cannot install the seccomp filter: {}", e)));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn confine(_matches: &ArgMatches) {}
+
+/// `git-mount layer`: the layer of a commit is made from the
+/// repository, while that of a mount is read from its control dir,
+/// since only the mount knows what was deleted through it.
+fn write_layer(matches: &ArgMatches) {
+    let gzip = matches.is_present("gzip");
+    let layer = match matches.value_of("mount") {
+        Some(mountpoint) => {
+            let name = if gzip { "layer.tar.gz" } else { "layer.tar" };
+            fs::read(Path::new(mountpoint).join(".gitfs").join(name))
+                .unwrap_or_else(|e| fail(&format!("cannot read the layer of {}: {}", mountpoint, e)))
+        }
+        None => {
+            let repo = Repository::open(matches.value_of("REPO").unwrap()).unwrap();
+            commit_layer(&repo, matches.value_of("ref").unwrap_or("HEAD"), gzip)
+                .unwrap_or_else(|e| fail(&format!("cannot make the layer: {}", e)))
+        }
+    };
+    let written = match matches.value_of("output") {
+        Some(path) => fs::write(path, &layer),
+        None => std::io::stdout().write_all(&layer),
+    };
+    if let Err(e) = written {
+        fail(&format!("cannot write the layer: {}", e));
+    }
+}
+
+/// The value of an option, which its validator has checked.
+fn parsed<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).and_then(|value| value.parse().ok())
+}
+
+/// A number that fits in `T`.
+fn number<T: FromStr>(number: String) -> Result<(), String> {
+    match number.parse::<T>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("not a number, or out of range: {}", number)),
+    }
+}
+
+/// A number above zero.
+fn positive(number: String) -> Result<(), String> {
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("not a positive number: {}", number)),
+    }
+}
+
+/// An address and port to listen on, e.g. 127.0.0.1:8080.
+fn address(address: String) -> Result<(), String> {
+    match address.parse::<SocketAddr>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("not an address and port: {}", address)),
+    }
+}
+
+/// A number of seconds, zero included.
+fn seconds(seconds: String) -> Result<(), String> {
+    match seconds.parse::<f64>() {
+        Ok(secs) if secs >= 0.0 && secs <= u32::MAX.into() => Ok(()),
+        _ => Err(format!("not a number of seconds: {}", seconds)),
+    }
+}
+
+/// A number of seconds to wait between doing something again.
+fn interval(seconds: String) -> Result<(), String> {
+    match seconds.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds <= u32::MAX.into() => Ok(()),
+        _ => Err(format!("not a number of seconds: {}", seconds)),
+    }
+}
 
-    let fs = GitFS::new(repo, dir);
-    let options = [
-        MountOption::AutoUnmount,
-        MountOption::FSName("gitfs".to_string()),
-        MountOption::CUSTOM("nonempty".to_string())
-    ];
-    fuser::mount2(fs, &mountpoint, &options).unwrap();
+fn fail(message: &str) -> ! {
+    eprintln!("git-mount: {}", message);
+    process::exit(1);
 }
@@ -0,0 +1,128 @@
+use clap::{App, Arg};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use git2::Repository;
+
+extern crate rockmore_git;
+use rockmore_git::gitfs::{tree_usage, Usage};
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+/// What to show of a dir's usage.
+#[derive(Clone, Copy)]
+enum Unit {
+    /// 1024-byte blocks, as du shows by default.
+    Kibibytes,
+    /// Bytes, as with `du -b`.
+    Bytes,
+    Human,
+}
+
+fn show(usage: &Usage, unit: Unit, apparent: bool) -> String {
+    let bytes = match apparent {
+        true => usage.bytes,
+        false => usage.blocks * 512,
+    };
+    match unit {
+        Unit::Kibibytes => bytes.div_ceil(1024).to_string(),
+        Unit::Bytes => bytes.to_string(),
+        Unit::Human => {
+            let mut n = bytes as f64;
+            for suffix in ["", "K", "M", "G", "T"] {
+                if n < 1024.0 || suffix == "T" {
+                    return match suffix {
+                        "" => format!("{}", bytes),
+                        _ => format!("{:.1}{}", n, suffix),
+                    };
+                }
+                n /= 1024.0;
+            }
+            unreachable!()
+        }
+    }
+}
+
+fn main() {
+    let matches = App::new("gitfs-du")
+        .about("Show how much space each dir of a tree takes in a mount, from object headers only")
+        .arg(Arg::with_name("REPO").required(true).help("Path to the repository"))
+        .arg(Arg::with_name("REV").help("The commit or tree to account for (default: HEAD)"))
+        .arg(Arg::with_name("max-depth")
+             .long("max-depth")
+             .short("d")
+             .takes_value(true)
+             .value_name("N")
+             .help("Only show dirs at most N levels under the root"))
+        .arg(Arg::with_name("apparent-size")
+             .long("apparent-size")
+             .help("Show the sizes of the files rather than the space they take"))
+        .arg(Arg::with_name("bytes")
+             .long("bytes")
+             .short("b")
+             .conflicts_with("human-readable")
+             .help("Show bytes, and the sizes of the files (same as --apparent-size in bytes)"))
+        .arg(Arg::with_name("human-readable")
+             .long("human-readable")
+             .short("h")
+             .help("Show sizes in K, M, G"))
+        .arg(Arg::with_name("count")
+             .long("count")
+             .short("c")
+             .help("Show how many files are under each dir too"))
+        .get_matches();
+
+    let path = matches.value_of("REPO").unwrap();
+    let repo = Repository::open(path).unwrap_or_else(|e| fail(&format!("cannot open {}: {}", path, e)));
+    let rev = matches.value_of("REV").unwrap_or("HEAD");
+    let tree = repo
+        .revparse_single(rev)
+        .and_then(|object| object.peel_to_tree())
+        .unwrap_or_else(|e| fail(&format!("cannot find the tree of {}: {}", rev, e)));
+    let max_depth = matches.value_of("max-depth").map(|n| {
+        n.parse::<usize>().unwrap_or_else(|_| {
+            eprintln!("invalid --max-depth: {}", n);
+            process::exit(2)
+        })
+    });
+    let unit = match (matches.is_present("bytes"), matches.is_present("human-readable")) {
+        (true, _) => Unit::Bytes,
+        (_, true) => Unit::Human,
+        _ => Unit::Kibibytes,
+    };
+    let apparent = matches.is_present("apparent-size") || matches.is_present("bytes");
+
+    let usage = tree_usage(&repo, tree.id()).unwrap_or_else(|e| fail(&format!("cannot read {}: {}", rev, e)));
+    let root = &usage[Path::new("")];
+    if root.missing > 0 {
+        eprintln!("{} of {} blobs are missing, and counted as empty", root.missing, root.files);
+    }
+
+    // Dirs after what is under them, as du does, the root last.
+    let line = |dir: &Path| {
+        let usage = &usage[dir];
+        let name = match dir.as_os_str().is_empty() {
+            true => PathBuf::from("."),
+            false => Path::new(".").join(dir),
+        };
+        match matches.is_present("count") {
+            true => println!("{}\t{}\t{}", show(usage, unit, apparent), usage.files, name.display()),
+            false => println!("{}\t{}", show(usage, unit, apparent), name.display()),
+        }
+    };
+    let mut open: Vec<&Path> = vec![];
+    for dir in usage.keys() {
+        while open.last().is_some_and(|last| !dir.starts_with(last)) {
+            line(open.pop().unwrap());
+        }
+        if max_depth.is_none_or(|max| dir.components().count() <= max) {
+            open.push(dir);
+        }
+    }
+    while let Some(dir) = open.pop() {
+        line(dir);
+    }
+}
@@ -0,0 +1,55 @@
+use clap::{App, Arg};
+use std::path::Path;
+use std::process;
+
+extern crate rockmore_git;
+use rockmore_git::testing::{generate_at, Spec};
+
+fn main() {
+    let matches = App::new("gitfs-genrepo")
+        .about("Generate a repository to test gitfs against, the same for the same options")
+        .arg(Arg::with_name("PATH").required(true).help("Where to make the repository"))
+        .arg(Arg::with_name("seed").long("seed").takes_value(true).help("What the tree is drawn from (default: 0)"))
+        .arg(Arg::with_name("depth").long("depth").takes_value(true).help("Levels of dirs under the root (default: 2)"))
+        .arg(Arg::with_name("fanout").long("fanout").takes_value(true).help("Dirs in each dir but the deepest (default: 3)"))
+        .arg(Arg::with_name("files").long("files").takes_value(true).help("Files in each dir (default: 4)"))
+        .arg(Arg::with_name("min-size")
+             .long("min-size")
+             .takes_value(true)
+             .value_name("BYTES")
+             .help("Smallest size of the files (default: 0)"))
+        .arg(Arg::with_name("max-size")
+             .long("max-size")
+             .takes_value(true)
+             .value_name("BYTES")
+             .help("Size the files stay under (default: 4096)"))
+        .arg(Arg::with_name("symlinks").long("symlinks").takes_value(true).help("Symlinks in each dir (default: 1)"))
+        .arg(Arg::with_name("submodules").long("submodules").takes_value(true).help("Submodules in the root (default: 0)"))
+        .arg(Arg::with_name("unicode").long("unicode").help("Give non-ASCII names too"))
+        .get_matches();
+
+    let mut spec = Spec { unicode: matches.is_present("unicode"), ..Spec::default() };
+    let number = |name: &str, default: usize| match matches.value_of(name) {
+        Some(n) => n.parse().unwrap_or_else(|_| {
+            eprintln!("invalid --{}: {}", name, n);
+            process::exit(2)
+        }),
+        None => default,
+    };
+    spec.seed = number("seed", spec.seed as usize) as u64;
+    spec.depth = number("depth", spec.depth);
+    spec.fanout = number("fanout", spec.fanout);
+    spec.files = number("files", spec.files);
+    spec.file_sizes = number("min-size", spec.file_sizes.start)..number("max-size", spec.file_sizes.end);
+    spec.symlinks = number("symlinks", spec.symlinks);
+    spec.submodules = number("submodules", spec.submodules);
+
+    let path = Path::new(matches.value_of("PATH").unwrap());
+    match generate_at(path, &spec) {
+        Ok(repo) => println!("{}", repo.head().unwrap().target().unwrap()),
+        Err(e) => {
+            eprintln!("cannot generate {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
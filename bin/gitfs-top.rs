@@ -0,0 +1,257 @@
+use clap::{App, Arg};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+/// What `.gitfs/stats` said at some point: the counters, the calls of
+/// each operation, and the paths most operated on, in that order,
+/// separated by blank lines.
+#[derive(Default)]
+struct Snapshot {
+    counters: BTreeMap<String, u64>,
+    ops: BTreeMap<String, u64>,
+    paths: BTreeMap<String, u64>,
+}
+
+impl Snapshot {
+    fn read(stats: &Path) -> io::Result<Snapshot> {
+        let content = fs::read_to_string(stats)?;
+        let mut snapshot = Snapshot::default();
+        let mut sections = content.split("\n\n");
+        for section in [&mut snapshot.counters, &mut snapshot.ops, &mut snapshot.paths] {
+            for line in sections.next().unwrap_or_default().lines() {
+                // Paths may have ": " in them, but not counts.
+                if let Some((name, value)) = line.rsplit_once(": ") {
+                    section.insert(name.to_owned(), value.parse().unwrap_or_default());
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+
+    fn counter(&self, name: &str) -> u64 {
+        self.counters.get(name).copied().unwrap_or_default()
+    }
+}
+
+/// How much `name` went up from `old` to `new`.
+fn delta(old: &BTreeMap<String, u64>, new: &BTreeMap<String, u64>, name: &str) -> u64 {
+    new.get(name).copied().unwrap_or_default().saturating_sub(old.get(name).copied().unwrap_or_default())
+}
+
+fn ratio(hits: u64, misses: u64) -> String {
+    match hits + misses {
+        0 => "-".to_owned(),
+        all => format!("{:.1}%", hits as f64 * 100.0 / all as f64),
+    }
+}
+
+fn bytes(n: f64) -> String {
+    let mut n = n;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if n < 1024.0 || unit == "GiB" {
+            return format!("{:.1} {}", n, unit);
+        }
+        n /= 1024.0;
+    }
+    unreachable!()
+}
+
+/// The terminal, in raw mode while this lives, so that a key is read
+/// as soon as it's pressed and not echoed.
+struct Terminal {
+    saved: Option<libc::termios>,
+}
+
+impl Terminal {
+    fn new() -> Terminal {
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::isatty(0) == 0 || libc::tcgetattr(0, &mut termios) != 0 {
+                return Terminal { saved: None };
+            }
+            let saved = termios;
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+            libc::tcsetattr(0, libc::TCSANOW, &termios);
+            print!("\x1b[?25l");
+            Terminal { saved: Some(saved) }
+        }
+    }
+
+    /// Rows of the terminal, or 24 if it can't be told.
+    fn rows(&self) -> usize {
+        unsafe {
+            let mut size = std::mem::zeroed::<libc::winsize>();
+            match libc::ioctl(1, libc::TIOCGWINSZ, &mut size) {
+                0 if size.ws_row > 0 => size.ws_row as usize,
+                _ => 24,
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for a key, and return whether it was one to
+    /// quit with.
+    fn wait_for_quit(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::ZERO {
+                return false;
+            }
+            let mut fd = libc::pollfd { fd: 0, events: libc::POLLIN, revents: 0 };
+            let ready = unsafe { libc::poll(&mut fd, 1, left.as_millis() as libc::c_int) };
+            if ready <= 0 || self.saved.is_none() {
+                // Not a terminal: wait all the same.
+                if ready != 0 {
+                    std::thread::sleep(left);
+                }
+                continue;
+            }
+            let mut key = 0u8;
+            if unsafe { libc::read(0, &mut key as *mut u8 as *mut libc::c_void, 1) } == 1 {
+                // q, Esc, ^C, ^D.
+                if matches!(key, b'q' | 0x1b | 3 | 4) {
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            unsafe {
+                libc::tcsetattr(0, libc::TCSANOW, saved);
+            }
+            println!("\x1b[?25h");
+            let _ = io::stdout().flush();
+        }
+    }
+}
+
+/// One screen: the state now, and what changed since `old`, `elapsed`
+/// ago.
+fn render(mount: &Path, old: &Snapshot, new: &Snapshot, elapsed: Duration, rows: usize) -> String {
+    let secs = elapsed.as_secs_f64().max(1e-3);
+    let rate = |n: u64| n as f64 / secs;
+    let counter_delta = |name: &str| delta(&old.counters, &new.counters, name);
+    let mut out = String::new();
+    let mut line = |s: String| {
+        out.push_str(&s);
+        out.push_str("\x1b[K\n");
+    };
+
+    line(format!("gitfs-top - {}    (q to quit)", mount.display()));
+    line(String::new());
+    let entries = new.counter("entries");
+    let growth = entries as i64 - old.counter("entries") as i64;
+    line(format!(
+        "inomap  {} entries ({:+.1}/s)    dirty files {}    open handles {}",
+        entries,
+        growth as f64 / secs,
+        new.counter("dirty-files"),
+        new.counter("open-handles"),
+    ));
+    let (hits, misses) = (new.counter("blob-cache-hits"), new.counter("blob-cache-misses"));
+    line(format!(
+        "blob cache  {}    hits {} now, {} overall",
+        bytes(new.counter("blob-cache-bytes") as f64),
+        ratio(counter_delta("blob-cache-hits"), counter_delta("blob-cache-misses")),
+        ratio(hits, misses),
+    ));
+    line(format!(
+        "read {}/s    written {}/s",
+        bytes(rate(counter_delta("bytes-read"))),
+        bytes(rate(counter_delta("bytes-written"))),
+    ));
+
+    // What's left of the screen goes to the operations, then the paths.
+    let left = rows.saturating_sub(5 + 2 + 2 + 1);
+    let mut ops: Vec<(u64, &String)> = new.ops.keys().map(|op| (delta(&old.ops, &new.ops, op), op)).collect();
+    ops.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    let mut paths: Vec<(u64, &String)> = new.paths.keys().map(|path| (delta(&old.paths, &new.paths, path), path)).collect();
+    paths.sort_by(|a, b| b.0.cmp(&a.0).then(new.paths[b.1].cmp(&new.paths[a.1])).then(a.1.cmp(b.1)));
+    let shown_paths = paths.len().min(left / 2);
+    let shown_ops = ops.len().min(left - shown_paths);
+
+    line(String::new());
+    line(format!("{:>10} {:>10}  OPERATION", "CALLS/S", "CALLS"));
+    for (calls, op) in ops.into_iter().take(shown_ops) {
+        line(format!("{:>10.1} {:>10}  {}", rate(calls), new.ops[op], op));
+    }
+    line(String::new());
+    line(format!("{:>10} {:>10}  PATH", "CALLS/S", "CALLS"));
+    for (calls, path) in paths.into_iter().take(shown_paths) {
+        line(format!("{:>10.1} {:>10}  {}", rate(calls), new.paths[path], path));
+    }
+    out.push_str("\x1b[J");
+    out
+}
+
+fn main() {
+    let matches = App::new("gitfs-top")
+        .about("Show what a gitfs mount is doing, from its .gitfs/stats")
+        .arg(Arg::with_name("MOUNTPOINT").required(true).help("Where the repository is mounted"))
+        .arg(Arg::with_name("interval")
+             .long("interval")
+             .short("n")
+             .takes_value(true)
+             .value_name("SECONDS")
+             .help("How often to refresh (default: 1)"))
+        .get_matches();
+
+    let mount = PathBuf::from(matches.value_of("MOUNTPOINT").unwrap());
+    let interval = match matches.value_of("interval").map(str::parse::<f64>) {
+        None => Duration::from_secs(1),
+        Some(Ok(secs)) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+        Some(_) => {
+            eprintln!("invalid --interval: {}", matches.value_of("interval").unwrap());
+            process::exit(2);
+        }
+    };
+
+    // The stats file is all the control plane there is to read from.
+    let stats = mount.join(".gitfs/stats");
+    let read = |stats: &Path| {
+        Snapshot::read(stats).unwrap_or_else(|e| {
+            eprintln!("cannot read {}: {}", stats.display(), e);
+            process::exit(1);
+        })
+    };
+    let mut old = read(&stats);
+    let mut then = Instant::now();
+    let terminal = Terminal::new();
+    let mut first = true;
+    loop {
+        let wait = match first {
+            // Show something right away, if only the gauges.
+            true => Duration::ZERO,
+            false => interval,
+        };
+        if terminal.wait_for_quit(wait) {
+            break;
+        }
+        let new = match Snapshot::read(&stats) {
+            Ok(new) => new,
+            Err(e) => {
+                drop(terminal);
+                eprintln!("cannot read {}: {}", stats.display(), e);
+                process::exit(1);
+            }
+        };
+        let now = Instant::now();
+        print!("\x1b[H{}", render(&mount, &old, &new, now - then, terminal.rows()));
+        let _ = io::stdout().flush();
+        if !first {
+            old = new;
+            then = now;
+        }
+        first = false;
+    }
+}
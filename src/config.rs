@@ -0,0 +1,187 @@
+/// Settings of a mount read from a file, which can be changed while
+/// it's mounted: the file is read again on SIGHUP, or on `reload` in
+/// `.gitfs/ctl` (see `GitFSBuilder::config_file`), for mounts that
+/// live as long as the server they're on.
+///
+/// There is a setting per line, as `name = value`, named after the
+/// option of `git-mount` that sets it; `#` starts a comment:
+///
+/// ```text
+/// ttl = 5                    # attr-ttl and entry-ttl, in seconds
+/// attr-ttl = 5
+/// entry-ttl = 1
+/// blob-cache-size = 134217728
+/// slow-op-threshold = 0.5    # in seconds
+/// overlay-quota = 1073741824
+/// read-rate = 10485760       # bytes per second, as write-rate
+/// iops = 1000
+/// deny = secrets/**          # deny, include, exclude, readonly and
+/// include = src/**           # pin may be repeated
+/// exclude = src/generated/**
+/// readonly = vendor/**
+/// pin = Cargo.lock
+/// log = info,rockmore_git=debug   # what is logged, as in RUST_LOG
+/// ```
+///
+/// The settings of the file are applied over those the mount was
+/// made with, and replace them: a `deny` line in the file drops the
+/// `--deny` given to `git-mount`.  Settings left out of the file are
+/// as the mount was made with, so that removing a line undoes it on
+/// the next reload.  Settings negotiated with the kernel, or that
+/// change what the mount is, can't be changed this way.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::options::Options;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Config {
+    pub attr_ttl: Option<Duration>,
+    pub entry_ttl: Option<Duration>,
+    pub blob_cache_size: Option<usize>,
+    pub slow_op_threshold: Option<Duration>,
+    pub overlay_quota: Option<u64>,
+    pub read_rate: Option<u64>,
+    pub write_rate: Option<u64>,
+    pub iops: Option<u64>,
+    pub denied_paths: Option<Vec<String>>,
+    pub read_only_paths: Option<Vec<String>>,
+    pub included_paths: Option<Vec<String>>,
+    pub excluded_paths: Option<Vec<String>>,
+    pub pinned_paths: Option<Vec<String>>,
+    /// What is logged, as in `RUST_LOG`.
+    pub log: Option<String>,
+}
+
+impl Config {
+    /// Read the config file at `path`.  A malformed file is an
+    /// `InvalidData` error telling what is wrong and where.
+    pub fn read(path: &Path) -> io::Result<Config> {
+        let content = fs::read_to_string(path)?;
+        Config::parse(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(content: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        for (i, line) in content.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(at) => &line[..at],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => return Err(format!("line {}: expected name = value", i + 1)),
+            };
+            config.set(name, value).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid {}: {}", name, value);
+        let secs = || match value.parse::<f64>() {
+            Ok(secs) if secs >= 0.0 && secs.is_finite() => Ok(Duration::from_secs_f64(secs)),
+            _ => Err(invalid()),
+        };
+        let number = || value.parse::<u64>().map_err(|_| invalid());
+        let glob = |globs: &mut Option<Vec<String>>| match value.is_empty() {
+            true => Err(invalid()),
+            false => {
+                globs.get_or_insert_with(Vec::new).push(value.to_owned());
+                Ok(())
+            }
+        };
+        match name {
+            "ttl" => {
+                self.attr_ttl = Some(secs()?);
+                self.entry_ttl = self.attr_ttl;
+            }
+            "attr-ttl" => self.attr_ttl = Some(secs()?),
+            "entry-ttl" => self.entry_ttl = Some(secs()?),
+            "blob-cache-size" => self.blob_cache_size = Some(value.parse().map_err(|_| invalid())?),
+            "slow-op-threshold" => self.slow_op_threshold = Some(secs()?),
+            "overlay-quota" => self.overlay_quota = Some(number()?),
+            "read-rate" => self.read_rate = Some(number()?),
+            "write-rate" => self.write_rate = Some(number()?),
+            "iops" => self.iops = Some(number()?),
+            "deny" => glob(&mut self.denied_paths)?,
+            "readonly" => glob(&mut self.read_only_paths)?,
+            "include" => glob(&mut self.included_paths)?,
+            "exclude" => glob(&mut self.excluded_paths)?,
+            "pin" => glob(&mut self.pinned_paths)?,
+            "log" => self.log = Some(value.to_owned()),
+            _ => return Err(format!("unknown setting: {}", name)),
+        }
+        Ok(())
+    }
+
+    /// Apply the settings of the file to `options`.
+    pub fn apply(&self, options: &mut Options) {
+        fn set<T: Clone>(setting: &Option<T>, option: &mut T) {
+            if let Some(value) = setting {
+                *option = value.clone();
+            }
+        }
+        set(&self.attr_ttl, &mut options.attr_ttl);
+        set(&self.entry_ttl, &mut options.entry_ttl);
+        set(&self.blob_cache_size, &mut options.blob_cache_size);
+        set(&self.denied_paths, &mut options.denied_paths);
+        set(&self.read_only_paths, &mut options.read_only_paths);
+        set(&self.included_paths, &mut options.included_paths);
+        set(&self.excluded_paths, &mut options.excluded_paths);
+        set(&self.pinned_paths, &mut options.pinned_paths);
+        options.slow_op_threshold = self.slow_op_threshold.or(options.slow_op_threshold);
+        options.overlay_quota = self.overlay_quota.or(options.overlay_quota);
+        options.read_rate = self.read_rate.or(options.read_rate);
+        options.write_rate = self.write_rate.or(options.write_rate);
+        options.iops = self.iops.or(options.iops);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_applies_settings() {
+        let config = Config::parse(
+            "# a comment\n\
+             ttl = 2.5\n\
+             entry-ttl = 0  # right after\n\
+             \n\
+             blob-cache-size = 1024\n\
+             deny = secrets/**\n\
+             deny = *.key\n\
+             log = debug\n",
+        )
+        .unwrap();
+        let mut options = Options {
+            denied_paths: vec!["vendor/**".to_owned()],
+            read_only_paths: vec!["docs/**".to_owned()],
+            iops: Some(10),
+            ..Options::default()
+        };
+        config.apply(&mut options);
+        assert_eq!(options.attr_ttl, Duration::from_millis(2500));
+        assert_eq!(options.entry_ttl, Duration::ZERO);
+        assert_eq!(options.blob_cache_size, 1024);
+        assert_eq!(options.denied_paths, ["secrets/**", "*.key"]);
+        assert_eq!(options.read_only_paths, ["docs/**"]);
+        assert_eq!(options.iops, Some(10));
+        assert_eq!(config.log.as_deref(), Some("debug"));
+    }
+
+    #[test]
+    fn rejects_malformed_settings() {
+        assert_eq!(Config::parse("ttl\n").unwrap_err(), "line 1: expected name = value");
+        assert_eq!(Config::parse("\nttl = -1\n").unwrap_err(), "line 2: invalid ttl: -1");
+        assert_eq!(Config::parse("read-only = true").unwrap_err(), "line 1: unknown setting: read-only");
+        assert_eq!(Config::parse("deny =").unwrap_err(), "line 1: invalid deny: ");
+    }
+}
@@ -0,0 +1,97 @@
+/// Errors inside gitfs, and how they are reported to the kernel.
+///
+/// Every failure eventually becomes an errno in a FUSE reply.  The
+/// default translation can be overridden by embedders with an
+/// `ErrnoMapper` (see `GitFSBuilder::errno_mapper`).
+use std::fmt;
+use std::io;
+
+use git2::{ErrorClass, ErrorCode};
+use libc::{c_int, EBUSY, EEXIST, EINVAL, EIO, ENOENT};
+
+#[derive(Debug)]
+pub enum Error {
+    /// An error from libgit2, e.g. a missing object.
+    Git(git2::Error),
+    /// An error from the underlying directory.
+    Io(io::Error),
+    /// An error that already has a definite errno.
+    Errno(c_int),
+}
+
+/// A callback deciding the errno for an error.  Returning `None`
+/// falls back to the default translation.
+pub type ErrnoMapper = Box<dyn Fn(&Error) -> Option<c_int> + Send + Sync>;
+
+impl Error {
+    /// The errno reported when no mapper overrides it.
+    pub fn default_errno(&self) -> c_int {
+        match self {
+            Error::Io(e) => e.raw_os_error().unwrap_or(EIO),
+            Error::Errno(errno) => *errno,
+            Error::Git(e) => match (e.class(), e.code()) {
+                (ErrorClass::Reference, ErrorCode::NotFound) => ENOENT,
+                (_, ErrorCode::InvalidSpec) => EINVAL,
+                (_, ErrorCode::Exists) => EEXIST,
+                (_, ErrorCode::Locked) => EBUSY,
+                // Objects referenced by a tree are supposed to exist,
+                // so a missing one is an I/O error, not ENOENT.
+                _ => EIO,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Git(e) => write!(f, "git error: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Errno(errno) => write!(f, "{}", io::Error::from_raw_os_error(*errno)),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Git(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Errno(_) => None,
+        }
+    }
+}
+
+impl From<git2::Error> for Error {
+    fn from(e: git2::Error) -> Error {
+        Error::Git(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Translate errors to errnos, consulting the embedder's mapper first.
+#[derive(Default)]
+pub struct ErrnoMap {
+    mapper: Option<ErrnoMapper>,
+}
+
+impl ErrnoMap {
+    pub fn new(mapper: Option<ErrnoMapper>) -> ErrnoMap {
+        ErrnoMap { mapper }
+    }
+
+    pub fn errno(&self, err: &Error) -> c_int {
+        let errno = self
+            .mapper
+            .as_ref()
+            .and_then(|mapper| mapper(err))
+            .unwrap_or_else(|| err.default_errno());
+        debug!(%err, errno, "reporting error");
+        errno
+    }
+}
@@ -7,146 +7,796 @@
 /// may be created, so some new directories can appear.
 ///
 /// Please read the source code for the details.
-use std::ffi::{OsStr, OsString};
-use std::fs::Permissions;
+///
+/// This is Unix only. The overlay is reached through openat and errors
+/// are libc errnos all the way up to every front-end, so a Windows one
+/// (WinFsp or Dokan) would first need an overlay store on std::fs and
+/// an errno to NTSTATUS mapping, besides a binding to either.
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{File, Permissions};
 use std::io;
-use std::io::SeekFrom;
-use std::io::{Read, Seek, Write};
-use std::os::unix::{ffi::OsStrExt, fs::PermissionsExt};
-use std::time::{Duration, SystemTime};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::os::unix::{ffi::OsStrExt, fs::FileExt, fs::MetadataExt, fs::PermissionsExt, io::AsRawFd};
+use std::time::{Duration, Instant, SystemTime};
 
+use fuser::consts::{FOPEN_DIRECT_IO, FUSE_ASYNC_READ, FUSE_DO_READDIRPLUS, FUSE_PARALLEL_DIROPS, FUSE_READDIRPLUS_AUTO};
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    BackgroundSession, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
     ReplyEntry, ReplyOpen, ReplyWrite, Request, KernelConfig, TimeOrNow,
 };
-use git2::{Error as GitError, ObjectType, Oid, Repository, Tree};
-use libc::{c_int, mode_t, stat, EIO, EISDIR, ENOENT, ENOTDIR, O_RDONLY};
+use git2::{Commit, Error as GitError, ObjectType, Oid, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use libc::{c_int, mode_t, stat, EACCES, EBADF, EBUSY, EEXIST, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOTDIR, ENOTEMPTY, EPERM, EROFS, O_ACCMODE, O_RDONLY, O_RDWR};
 use openat::{Dir, SimpleType};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
 
-use crate::{Entry, EntryKind, Ino, InoMap};
+use crate::error::{Error, ErrnoMap, ErrnoMapper};
+use crate::glob;
+use crate::names::{self, NameMap};
+use crate::options::{CaseCollisions, MtimePolicy, Options, SharedOptions};
+use crate::watch::Watcher;
+use crate::{BlobCache, Entry, EntryKind, Handle, HandleTable, Ino, InoMap, Stamp, MODE_CHAR, MODE_FILE, MODE_SYMLINK, MODE_TYPE};
 
-macro_rules! some {
-    ($value:expr, $reply:ident, $errno:expr) => {
-        match $value {
-            Some(val) => val,
-            None => return $reply.error($errno),
-        }
-    };
-}
+mod annex;
+#[cfg(feature = "http-api")]
+mod api;
+mod archive;
+mod audit;
+mod changes;
+mod control;
+mod crypt;
+mod du;
+mod faults;
+mod history;
+mod lfs;
+mod lock;
+pub(crate) mod model;
+mod multi;
+mod nfs;
+mod ninep;
+mod pin;
+mod quota;
+mod reload;
+mod selfcheck;
+mod server;
+mod sftp;
+mod smudge;
+mod step;
+mod stats;
+mod store;
+mod submodule;
+mod throttle;
+mod tidy;
+mod webdav;
+mod xattr;
+use control::Control;
+use history::LastCommits;
+pub(crate) use control::common_dir;
+pub(crate) use smudge::Smudged;
+use smudge::{Attributes, Object};
+pub use crypt::OverlayKey;
+pub use du::{tree_usage, Usage};
+#[cfg(feature = "faults")]
+pub use faults::{Fault, FaultInjector, Site};
+#[cfg(not(feature = "faults"))]
+use faults::Site;
+pub use multi::MultiFS;
+pub use step::Step;
+use reload::{ConfigFile, LogFilter};
+use stats::Stats;
+use submodule::Submodules;
+use throttle::{Io, Throttles};
 
-macro_rules! io_ok {
-    ($value:expr, $reply:ident, $default_errno:expr) => {
+/// Unwrap a result, or reply with the errno the error maps to, and
+/// record it in the span of the operation.
+macro_rules! ok {
+    ($self:ident, $value:expr, $reply:ident) => {
         match $value {
             Ok(value) => value,
-            Err(e) => return $reply.error(e.raw_os_error().unwrap_or($default_errno)),
+            Err(e) => {
+                let errno = $self.errno(&Error::from(e));
+                tracing::Span::current().record("errno", errno);
+                return $reply.error(errno);
+            }
         }
     };
-    ($value:expr, $reply:ident) => {
-        io_ok!($value, $reply, libc::EIO)
+}
+
+/// Enter a span for a FUSE operation, and count it in the stats.
+///
+/// The span carries the ino and its resolved path, and the errno if
+/// the operation fails (see `ok!`); with span close
+/// events enabled in the subscriber, it also reports how long the
+/// operation took.
+macro_rules! op_span {
+    ($self:ident, $op:literal, $ino:ident $(, $($fields:tt)*)?) => {
+        (
+            $self.start_op($op, Ino::from($ino)),
+            debug_span!(
+                $op,
+                ino = $ino,
+                path = %$self.path_of(Ino::from($ino)).display(),
+                errno = tracing::field::Empty
+                $(, $($fields)*)?
+            )
+            .entered(),
+        )
     };
 }
 
+/// An OCI image layer of the tree of `refspec`, as a tarball, gzipped
+/// if `gzip`.  What differs in a mount goes in another layer, on top
+/// of this one: `.gitfs/layer.tar` (or `layer.tar.gz`) in the mount.
+pub fn commit_layer(repo: &Repository, refspec: &str, gzip: bool) -> Result<Vec<u8>, Error> {
+    let commit = repo.revparse_single(refspec)?.peel_to_commit()?;
+    let format = if gzip { archive::Format::TarGz } else { archive::Format::Tar };
+    archive::layer(repo, &commit, format)
+}
+
+/// Map the path of an entry to its owner (uid, gid).
+pub type OwnerMapper = Box<dyn Fn(&Path) -> Option<(u32, u32)> + Send + Sync>;
+
+/// A handle to a gitfs.
+///
+/// Cloning is cheap, and all clones refer to the same file system, so
+/// a clone kept aside can still control it (e.g. `refresh()`) after
+/// it's mounted.
+#[derive(Clone)]
 pub struct GitFS {
+    inner: Arc<Inner>,
+}
+
+/// The state of a gitfs, shared by all handles to it.
+///
+/// Each subsystem has its own lock, so that independent operations
+/// don't serialize on one big lock.  When an operation needs more
+/// than one lock, it must take them in this order:
+///
+/// ```text
+/// head -> inomap -> handles -> commit_times -> repo -> blob_cache
+/// ```
+///
+/// A lock may be released and taken again later, as long as no lock
+/// that comes earlier in the order is taken while holding it.
+/// `options`, `control`, `audit_log`, `overlay_usage`, `throttles`,
+/// `pinned`, `last_commits`, `submodules`, `overlay_lock` and the
+/// counters in `stats` are only ever held briefly; no other lock may be taken while
+/// holding any of them, nor while holding `file_locks` around the I/O
+/// of a sealed file.  `repo` and `blob_cache` may be shared with
+/// other mounts, which is safe as they come last.
+struct Inner {
+    head: Mutex<Head>,
+    inomap: Mutex<InoMap>,
+    handles: Mutex<HandleTable>,
+    commit_times: Mutex<Option<CommitTimes>>,
+    /// May be shared with other mounts (see `share_repository`).
+    repo: Arc<Mutex<Repository>>,
+    /// The last commit of each path asked about (see `history`).
+    last_commits: Mutex<LastCommits>,
+    /// The submodules found in the mounted commit (see `submodule`).
+    submodules: Mutex<Submodules>,
+    /// May be shared with other mounts (see `share_blob_cache`).
+    blob_cache: Arc<Mutex<BlobCache>>,
+    /// The blobs this mount has pinned in the blob cache (see `pin`).
+    pinned: Mutex<Vec<Oid>>,
+    config_file: Option<ConfigFile>,
+    log_filter: Option<LogFilter>,
+    options: SharedOptions,
+    control: Mutex<Control>,
+    stats: Stats,
+    /// Where changes are logged, if anywhere (see `audit`).
+    audit_log: Option<Mutex<File>>,
+    /// What the contents of files in the underlying dir are encrypted
+    /// with, if anything (see `crypt`).
+    overlay_key: Option<OverlayKey>,
+    /// Serializing the I/O of each sealed file (see `crypt`).
+    file_locks: crypt::FileLocks,
+    /// How much of the quota is used, once measured.
+    overlay_usage: Mutex<Option<quota::Usage>>,
+    throttles: Throttles,
+    /// The dir of each commit that is mounted at the root; empty for
+    /// the whole tree.
+    subdir: PathBuf,
+    /// The underlying dir is only accessed via *at() syscalls, which
+    /// need no locking.
+    underlying_dir: Dir,
+    /// The underlying dir, opened to hold its lock, once locked (see
+    /// `lock`).
+    overlay_lock: Mutex<Option<File>>,
+    errno_map: ErrnoMap,
+    owner_mapper: Option<OwnerMapper>,
+    #[cfg(feature = "faults")]
+    fault_injector: Option<FaultInjector>,
+    /// The longest name the underlying dir can hold.
+    name_max: usize,
+    /// The preferred I/O size of the underlying dir.
+    blksize: u32,
+    /// Whether the mode bits in the underlying dir can be trusted
+    /// (`core.fileMode`).
+    file_mode: bool,
+    /// Whether symlink blobs are shown as symlinks (`core.symlinks`),
+    /// rather than as files holding the target path.
+    symlinks: bool,
+    /// Whether changes to the underlying dir are reported by a
+    /// watcher, so that listed dirs don't have to be looked at on
+    /// every access.
+    overlay_watched: AtomicBool,
+    refresh_requested: RefreshHandle,
+}
+
+/// What is mounted.
+struct Head {
+    /// e.g. "HEAD", a branch or a commit id.
+    refspec: String,
+    /// The commit currently shown, once mounted.
+    commit: Option<Oid>,
+    /// The commit `Step::Next` steps toward, once stepped away from.
+    tip: Option<Oid>,
+}
+
+/// The attributes to change in `do_setattr`.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct SetAttr {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<TimeOrNow>,
+    mtime: Option<TimeOrNow>,
+    crtime: Option<SystemTime>,
+    /// BSD flags (chflags), which only macOS passes.
+    flags: Option<u32>,
+}
+
+/// When each path was last changed in the history of a commit.
+struct CommitTimes {
+    commit: Oid,
+    times: Arc<HashMap<PathBuf, SystemTime>>,
+}
+
+/// Ask a `GitFS` to refresh from a signal handler: requesting only
+/// sets a flag, and the refresh itself runs before the next lookup,
+/// getattr or opendir.
+#[derive(Debug, Clone, Default)]
+pub struct RefreshHandle(Arc<AtomicBool>);
+
+impl RefreshHandle {
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// A file system mounted by `GitFS::spawn_mount`.
+///
+/// Dropping the handle unmounts the file system and waits for the
+/// session to end, so that all dirty files are flushed by then.
+#[derive(Debug)]
+pub struct MountHandle {
+    session: Option<BackgroundSession>,
+}
+
+impl MountHandle {
+    pub fn mountpoint(&self) -> Option<&Path> {
+        self.session.as_ref().map(|s| s.mountpoint.as_path())
+    }
+
+    /// Unmount the file system, and report how the session ended.
+    pub fn unmount(mut self) -> io::Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let session = match self.session.take() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        // Moving out the guard drops the rest of the session, which
+        // unmounts the file system and stops the session loop.
+        let guard = session.guard;
+        guard
+            .join()
+            .map_err(|_| io::Error::other("gitfs session panicked"))?
+    }
+}
+
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!(%e, "gitfs session ended with an error");
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // Normally destroy() has done this already.
+        if let Ok(handles) = self.handles.get_mut() {
+            handles.close_all();
+        }
+    }
+}
+
+/// Configure and create a `GitFS`.
+pub struct GitFSBuilder {
     repo: Repository,
+    refspec: String,
+    subdir: PathBuf,
     underlying_dir: Dir,
-    inomap: InoMap,
+    options: Options,
+    errno_mapper: Option<ErrnoMapper>,
+    owner_mapper: Option<OwnerMapper>,
+    #[cfg(feature = "faults")]
+    fault_injector: Option<FaultInjector>,
+    audit_log: Option<File>,
+    overlay_key: Option<OverlayKey>,
+    blob_cache: Option<Arc<Mutex<BlobCache>>>,
+    shared_repo: Option<Arc<Mutex<Repository>>>,
+    config_file: Option<PathBuf>,
+    log_filter: Option<LogFilter>,
+}
+
+impl GitFSBuilder {
+    /// Mount this ref or commit instead of HEAD.
+    pub fn refspec(mut self, refspec: &str) -> GitFSBuilder {
+        self.refspec = refspec.to_owned();
+        self
+    }
+
+    /// Mount only this dir of the commit (e.g. `services/api`) at the
+    /// root, rather than the whole tree.  Committing keeps the rest of
+    /// the tree as it is in the parent, and commits the mounted ref can
+    /// be switched to must have the dir.  What is in the control dir
+    /// is of that dir only, but for `log`; `.gitattributes` outside of
+    /// it don't apply.
+    pub fn subdir(mut self, path: &Path) -> GitFSBuilder {
+        self.subdir = path.components().collect();
+        self
+    }
+
+    pub fn options(mut self, options: Options) -> GitFSBuilder {
+        self.options = options;
+        self
+    }
+
+    /// Override how errors are reported.  The mapper is consulted
+    /// before the default translation, and returning `None` falls
+    /// back to it, e.g.
+    ///
+    /// ```ignore
+    /// builder.errno_mapper(|err| match err {
+    ///     Error::Git(e) if e.code() == git2::ErrorCode::NotFound => Some(libc::EAGAIN),
+    ///     _ => None,
+    /// })
+    /// ```
+    pub fn errno_mapper<F>(mut self, mapper: F) -> GitFSBuilder
+    where
+        F: Fn(&Error) -> Option<c_int> + Send + Sync + 'static,
+    {
+        self.errno_mapper = Some(Box::new(mapper));
+        self
+    }
+
+    /// Decide who owns entries, given their path in the mount.
+    /// Returning `None` falls back to the default (see `owner`).  The
+    /// mapper is called with the inomap locked, so it must not call
+    /// back into the file system.
+    pub fn owner_mapper<F>(mut self, mapper: F) -> GitFSBuilder
+    where
+        F: Fn(&Path) -> Option<(u32, u32)> + Send + Sync + 'static,
+    {
+        self.owner_mapper = Some(Box::new(mapper));
+        self
+    }
+
+    /// Inject failures where `injector` says so (see `faults`), e.g.
+    ///
+    /// ```ignore
+    /// builder.fault_injector(|site| match site {
+    ///     Site::Write(_) => Some(Fault::Errno(libc::ENOSPC)),
+    ///     _ => None,
+    /// })
+    /// ```
+    #[cfg(feature = "faults")]
+    pub fn fault_injector<F>(mut self, injector: F) -> GitFSBuilder
+    where
+        F: Fn(&Site) -> Option<Fault> + Send + Sync + 'static,
+    {
+        self.fault_injector = Some(Box::new(injector));
+        self
+    }
+
+    /// Log every change made through the mount to `file`, which
+    /// should be opened for appending.
+    pub fn audit_log(mut self, file: File) -> GitFSBuilder {
+        self.audit_log = Some(file);
+        self
+    }
+
+    /// Encrypt the contents of the files in the underlying dir with
+    /// `key`.  The underlying dir should not hold files from before,
+    /// which cannot be read once it's encrypted, and the same key must
+    /// be given to every later mount of it.
+    pub fn overlay_key(mut self, key: OverlayKey) -> GitFSBuilder {
+        self.overlay_key = Some(key);
+        self
+    }
+
+    /// Keep blobs in the same cache as `other`, e.g. for repositories
+    /// mounted together (see `MultiFS`).  Blobs are cached by id, so a
+    /// blob in several of them is only cached once.  The cache is as
+    /// large as the `blob_cache_size` of whichever reads last, so it
+    /// should be the same for all of them.
+    pub fn share_blob_cache(mut self, other: &GitFS) -> GitFSBuilder {
+        self.blob_cache = Some(other.inner.blob_cache.clone());
+        self
+    }
+
+    /// Read objects through the same handle of the repository as
+    /// `other`, a mount of the same repository (e.g. of another ref),
+    /// and keep blobs in the same cache (see `share_blob_cache`).  The
+    /// trees and commits libgit2 caches, and the packs it has opened,
+    /// then serve both, so that mounting several refs costs little more
+    /// than their ino maps.  The repository given to the builder is
+    /// only read for its config.  Mounts sharing it wait for each other
+    /// to read objects.
+    pub fn share_repository(mut self, other: &GitFS) -> GitFSBuilder {
+        self.shared_repo = Some(other.inner.repo.clone());
+        self.share_blob_cache(other)
+    }
+
+    /// Take the settings of the config file at `path` over the
+    /// options, once `reload_config` is called, and every time it is
+    /// (see `config`).  The file isn't read until then.
+    pub fn config_file(mut self, path: &Path) -> GitFSBuilder {
+        self.config_file = Some(path.to_owned());
+        self
+    }
+
+    /// Change what is logged as the config file says (its `log`
+    /// setting, `None` if it has none), e.g. through a reload handle
+    /// of `tracing_subscriber`.  The filter should change nothing if
+    /// it fails, and the reload fails then.
+    pub fn log_filter<F>(mut self, filter: F) -> GitFSBuilder
+    where
+        F: Fn(Option<&str>) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.log_filter = Some(Box::new(filter));
+        self
+    }
+
+    pub fn build(self) -> GitFS {
+        let st = statvfs(&self.underlying_dir).ok();
+        let name_max = st
+            .map(|st| st.f_namemax as usize)
+            .unwrap_or(libc::NAME_MAX as usize);
+        let blksize = st.map(|st| st.f_bsize as u32).unwrap_or(4096);
+        let config = self.repo.config().ok();
+        let config_bool = |name: &str| config.as_ref().and_then(|config| config.get_bool(name).ok());
+        let file_mode = config_bool("core.fileMode").unwrap_or(true);
+        let symlinks = config_bool("core.symlinks").unwrap_or(true);
+        let blob_cache_size = self.options.blob_cache_size;
+        let blob_cache = self.blob_cache.unwrap_or_else(|| Arc::new(Mutex::new(BlobCache::new(blob_cache_size))));
+        let options = self.options;
+        let inner = Inner {
+            head: Mutex::new(Head {
+                refspec: self.refspec,
+                commit: None,
+                tip: None,
+            }),
+            inomap: Mutex::new(InoMap::new()),
+            handles: Mutex::new(HandleTable::new()),
+            commit_times: Mutex::new(None),
+            repo: match self.shared_repo {
+                Some(repo) => repo,
+                None => Arc::new(Mutex::new(self.repo)),
+            },
+            last_commits: Mutex::new(LastCommits::default()),
+            submodules: Mutex::new(Submodules::default()),
+            blob_cache,
+            pinned: Mutex::new(vec![]),
+            config_file: self.config_file.map(|path| ConfigFile { path, base: options.clone() }),
+            log_filter: self.log_filter,
+            options: Arc::new(RwLock::new(options)),
+            control: Mutex::new(Control::default()),
+            stats: Stats::default(),
+            audit_log: self.audit_log.map(Mutex::new),
+            overlay_key: self.overlay_key,
+            file_locks: crypt::FileLocks::new(),
+            overlay_usage: Mutex::new(None),
+            throttles: Throttles::default(),
+            subdir: self.subdir,
+            underlying_dir: self.underlying_dir,
+            overlay_lock: Mutex::new(None),
+            errno_map: ErrnoMap::new(self.errno_mapper),
+            owner_mapper: self.owner_mapper,
+            #[cfg(feature = "faults")]
+            fault_injector: self.fault_injector,
+            name_max,
+            blksize,
+            file_mode,
+            symlinks,
+            overlay_watched: AtomicBool::new(false),
+            refresh_requested: RefreshHandle::default(),
+        };
+        GitFS {
+            inner: Arc::new(inner),
+        }
+    }
 }
 
 // public interfaces
 impl GitFS {
     pub fn new(repo: Repository, underlying_dir: Dir) -> GitFS {
-        GitFS {
+        Self::builder(repo, underlying_dir).build()
+    }
+
+    pub fn with_options(repo: Repository, underlying_dir: Dir, options: Options) -> GitFS {
+        Self::builder(repo, underlying_dir).options(options).build()
+    }
+
+    pub fn builder(repo: Repository, underlying_dir: Dir) -> GitFSBuilder {
+        GitFSBuilder {
             repo,
+            refspec: "HEAD".to_owned(),
+            subdir: PathBuf::new(),
             underlying_dir,
-            inomap: InoMap::new(),
+            options: Options::default(),
+            errno_mapper: None,
+            owner_mapper: None,
+            #[cfg(feature = "faults")]
+            fault_injector: None,
+            audit_log: None,
+            overlay_key: None,
+            blob_cache: None,
+            shared_repo: None,
+            config_file: None,
+            log_filter: None,
         }
     }
-}
 
-// file system interfaces
-impl Filesystem for GitFS {
-    fn init(&mut self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
-        let commit = self.repo.head().unwrap().peel_to_commit().unwrap();
-        let tree = commit.tree().unwrap();
-        self.inomap.add(self.root_entry(tree));
-        info!("gitfs is mounted");
-        Ok(())
+    /// Return a handle to the options of this file system.  Changes
+    /// made through the handle apply to subsequent operations, even
+    /// after the file system is mounted.
+    pub fn options(&self) -> SharedOptions {
+        self.inner.options.clone()
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let parent_entry = some!(self.inomap.get(parent.into()), reply, ENOENT);
-        match &parent_entry.u {
-            EntryKind::GitTree {
-                children: Some(children),
-                ..
-            } => {
-                let child = *some!(children.get(name), reply, ENOENT);
-                let child_entry = some!(self.inomap.get(child), reply, ENOENT);
-                return reply.entry(&Self::ttl(), &Self::make_attr(child, child_entry), 0);
-            }
-            EntryKind::DirtyDir {
-                children: Some(children),
-            } => {
-                let child = *some!(children.get(name), reply, ENOENT);
-                let child_entry = some!(self.inomap.get(child), reply, ENOENT);
-                return reply.entry(&Self::ttl(), &Self::make_attr(child, child_entry), 0);
-            }
-            EntryKind::GitTree { children: None, .. } => match self.do_opendir(parent.into()) {
-                Ok(_) => (),
-                Err(e) => return reply.error(e),
-            },
-            EntryKind::DirtyDir { children: None, .. } => match self.do_opendir(parent.into()) {
-                Ok(_) => (),
-                Err(e) => return reply.error(e),
-            },
-            _ => return reply.error(ENOTDIR),
+    /// Mount the file system in a background thread.  It stays
+    /// mounted until the returned handle is dropped or unmounted.
+    pub fn spawn_mount<P: AsRef<Path>>(
+        self,
+        mountpoint: P,
+        options: &[MountOption],
+    ) -> io::Result<MountHandle> {
+        let session = fuser::spawn_mount2(self, mountpoint, options)?;
+        Ok(MountHandle {
+            session: Some(session),
+        })
+    }
+
+    /// Refresh whenever the refs of the repository change, checking
+    /// every `interval`, until the returned watcher is dropped.
+    pub fn watch(&self, interval: Duration) -> io::Result<Watcher> {
+        let git_dir = self.repo().path().to_owned();
+        Watcher::spawn(self.clone(), &git_dir, interval)
+    }
+
+    /// Fetch `branch` of `remote` every `interval` (see
+    /// `watch::fetch`), and refresh, until the returned watcher is
+    /// dropped: a mount of `refs/remotes/<remote>/<branch>` then
+    /// follows the branch as it advances there.
+    pub fn watch_remote(&self, remote: &str, branch: &str, interval: Duration) -> io::Result<Watcher> {
+        let git_dir = self.repo().path().to_owned();
+        Watcher::spawn_fetch(self.clone(), &git_dir, remote, branch, interval)
+    }
+
+    /// Export the stats to Prometheus over HTTP at `addr`, until the
+    /// returned watcher is dropped.  Also return the address listened
+    /// on, which tells the port when `addr` has none.
+    #[cfg(feature = "metrics")]
+    pub fn serve_metrics(&self, addr: SocketAddr) -> io::Result<(Watcher, SocketAddr)> {
+        crate::metrics::serve(self.clone(), addr)
+    }
+
+    /// Serve the file system over 9P2000.L at `addr`, as an
+    /// alternative to mounting it with FUSE, until the returned
+    /// watcher is dropped.  Also return the address listened on.
+    pub fn serve_9p(&self, addr: SocketAddr) -> Result<(Watcher, SocketAddr), Error> {
+        ninep::serve(self.clone(), addr)
+    }
+
+    /// Export the file system, read-only, over NFSv3 at `addr`, until
+    /// the returned watcher is dropped.  Also return the address
+    /// listened on.
+    pub fn serve_nfs(&self, addr: SocketAddr) -> Result<(Watcher, SocketAddr), Error> {
+        nfs::serve(self.clone(), addr)
+    }
+
+    /// Serve the file system over WebDAV at `addr`, until the returned
+    /// watcher is dropped.  Also return the address listened on.
+    pub fn serve_webdav(&self, addr: SocketAddr) -> Result<(Watcher, SocketAddr), Error> {
+        webdav::serve(self.clone(), addr)
+    }
+
+    /// Serve the tree, read-only, over an HTTP API at `addr`, until
+    /// the returned watcher is dropped.  Also return the address
+    /// listened on.
+    #[cfg(feature = "http-api")]
+    pub fn serve_api(&self, addr: SocketAddr) -> Result<(Watcher, SocketAddr), Error> {
+        api::serve(self.clone(), addr)
+    }
+
+    /// Serve the file system over SFTP on `input` and `output`, as
+    /// sshd runs a subsystem, until the client hangs up.
+    pub fn serve_sftp<R: Read, W: Write>(&self, input: R, output: W) -> Result<(), Error> {
+        sftp::serve(self.clone(), input, output)
+    }
+
+    /// Watch the underlying dir, so that changes made there by others
+    /// are noticed without looking at it on every access, until the
+    /// returned watcher is dropped.  Only supported on Linux.
+    pub fn watch_overlay(&self) -> io::Result<Watcher> {
+        Watcher::spawn_overlay(self.clone(), self.inner.underlying_dir.as_raw_fd())
+    }
+
+    /// Reload the config file (see `reload_config`) whenever the
+    /// process gets SIGHUP, until the returned watcher is dropped.
+    pub fn reload_config_on_sighup(&self) -> io::Result<Watcher> {
+        Watcher::spawn_config_reload(self.clone())
+    }
+
+    /// Log the state of the mount (see `dump_state`) whenever the
+    /// process gets SIGUSR1, until the returned watcher is dropped.
+    pub fn dump_state_on_sigusr1(&self) -> io::Result<Watcher> {
+        Watcher::spawn_state_dump(self.clone())
+    }
+
+    pub fn refresh_handle(&self) -> RefreshHandle {
+        self.inner.refresh_requested.clone()
+    }
+
+    /// Re-resolve the mounted ref, and switch to its tree if it has
+    /// moved (see `checkout`).  Return whether anything changed.
+    pub fn refresh(&self) -> Result<bool, Error> {
+        let mut head = self.head();
+        let (commit_id, _) = self.resolve(&head.refspec)?;
+        if head.commit == Some(commit_id) {
+            return Ok(false);
         }
+        let refspec = head.refspec.clone();
+        self.switch_head(&mut head, &refspec)?;
+        Ok(true)
+    }
 
-        // if we reachabled here, it means we have filled children of
-        // a directory
-        let parent_entry = some!(self.inomap.get(parent.into()), reply, ENOENT);
-        match &parent_entry.u {
-            EntryKind::GitTree {
-                children: Some(children),
-                ..
-            } => {
-                let child = *some!(children.get(name), reply, ENOENT);
-                let child_entry = some!(self.inomap.get(child), reply, ENOENT);
-                return reply.entry(&Self::ttl(), &Self::make_attr(child, child_entry), 0);
-            }
-            EntryKind::DirtyDir {
-                children: Some(children),
-            } => {
-                let child = *some!(children.get(name), reply, ENOENT);
-                let child_entry = some!(self.inomap.get(child), reply, ENOENT);
-                return reply.entry(&Self::ttl(), &Self::make_attr(child, child_entry), 0);
-            }
-            EntryKind::GitTree { children: None, .. } => {
-                warn!("children is empty after fill, skipping");
-                return reply.error(ENOENT);
-            }
-            EntryKind::DirtyDir { children: None, .. } => {
-                warn!("children is empty after fill, skipping");
-                return reply.error(ENOENT);
+    /// Switch the mounted tree to another ref or commit.
+    ///
+    /// Cached trees are dropped and walked again on next access,
+    /// while dirty files stay in the underlying dir and keep showing
+    /// up.  The switch is refused if a dirty file is open (EBUSY), or
+    /// if a dirty entry would clash with an entry of a different kind
+    /// in the new tree (EEXIST); nothing changes in that case.
+    pub fn checkout(&self, refspec: &str) -> Result<(), Error> {
+        let mut head = self.head();
+        self.switch_head(&mut head, refspec)
+    }
+
+    /// Commit the changes against the mounted tree on top of the
+    /// mounted commit, and switch to the new commit.  The mounted ref
+    /// is moved along if it's a branch (or `HEAD`); the mount is left
+    /// on the bare commit otherwise.  Return the new commit, or the
+    /// mounted one if there is nothing to commit.
+    ///
+    /// Refused on a read-only mount (EROFS), or while a dirty file is
+    /// open (EBUSY).
+    pub fn commit(&self, message: &str) -> Result<Oid, Error> {
+        self.check_rules(|| None, true)?;
+        let mut head = self.head();
+        if self.handles().iter().any(|(_, handle)| handle.file.is_some()) {
+            return Err(Error::Errno(EBUSY));
+        }
+        let changes = self.changes()?;
+        let (commit_id, refspec) = {
+            let repo = self.repo();
+            let parent = match head.commit {
+                Some(commit) => repo.find_commit(commit)?,
+                None => return Err(Error::Errno(EIO)),
+            };
+            let base = self.mounted_tree(&repo, &parent)?;
+            let tree_id = self.apply_changes(&repo, &base, &changes)?;
+            if tree_id == base.id() {
+                return Ok(parent.id());
             }
-            _ => return reply.error(ENOTDIR),
+            let tree_id = match self.inner.subdir.as_os_str().is_empty() {
+                true => tree_id,
+                false => {
+                    let mut update = git2::build::TreeUpdateBuilder::new();
+                    update.upsert(&self.inner.subdir, tree_id, git2::FileMode::Tree);
+                    update.create_updated(&repo, &parent.tree()?)?
+                }
+            };
+            let tree = repo.find_tree(tree_id)?;
+            let update_ref = match repo.resolve_reference_from_short_name(&head.refspec) {
+                Ok(reference) if head.refspec == "HEAD" || reference.is_branch() => reference.name().map(str::to_owned),
+                _ => None,
+            };
+            let signature = repo.signature()?;
+            let commit_id = repo.commit(update_ref.as_deref(), &signature, &signature, message, &tree, &[&parent])?;
+            info!(commit = %commit_id, "committed");
+            let refspec = match update_ref {
+                Some(_) => head.refspec.clone(),
+                None => commit_id.to_string(),
+            };
+            (commit_id, refspec)
+        };
+        self.switch_head(&mut head, &refspec)?;
+        debug_assert_eq!(head.commit, Some(commit_id));
+        Ok(commit_id)
+    }
+}
+
+// file system interfaces
+impl Filesystem for GitFS {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        let (max_readahead, max_write, max_background, congestion_threshold) = {
+            let options = self.options_read();
+            (options.max_readahead, options.max_write, options.max_background, options.congestion_threshold)
+        };
+        // The kernel offers little readahead, and writes a page at a
+        // time, unless asked; max_write also needs big writes and
+        // max_pages (abi-7-28) to go past 128 KiB.
+        if let Err(nearest) = config.set_max_readahead(max_readahead) {
+            warn!(max_readahead, nearest, "max_readahead is not supported, using nearest");
+            let _ = config.set_max_readahead(nearest);
         }
+        if let Err(nearest) = config.set_max_write(max_write) {
+            warn!(max_write, nearest, "max_write is not supported, using nearest");
+            let _ = config.set_max_write(nearest);
+        }
+        if let Err(nearest) = config.set_max_background(max_background) {
+            warn!(max_background, nearest, "max_background is not supported, using nearest");
+            let _ = config.set_max_background(nearest);
+        }
+        let congestion_threshold = congestion_threshold.unwrap_or(max_background / 4 * 3).max(1);
+        if let Err(nearest) = config.set_congestion_threshold(congestion_threshold) {
+            warn!(congestion_threshold, nearest, "congestion_threshold is not supported, using nearest");
+            let _ = config.set_congestion_threshold(nearest);
+        }
+        negotiate_capabilities(config);
+        self.mount_root().map_err(|e| {
+            error!(%e, "cannot mount the repository");
+            self.errno(&e)
+        })
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _span = op_span!(self, "lookup", parent, name = ?name);
+        self.refresh_if_requested();
+        let attr = ok!(self, self.do_lookup(parent.into(), name), reply);
+        reply.entry(&self.entry_ttl(), &attr, 0)
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        let ino = Ino::from(ino);
-        let entry = some!(self.inomap.get(ino), reply, ENOENT);
-        return reply.attr(&Self::ttl(), &Self::make_attr(ino, entry));
+        let _span = op_span!(self, "getattr", ino);
+        self.refresh_if_requested();
+        let attr = ok!(self, self.do_getattr(ino.into()), reply);
+        reply.attr(&self.attr_ttl(), &attr)
     }
 
     fn setattr(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
         size: Option<u64>,
         atime: Option<TimeOrNow>,
         mtime: Option<TimeOrNow>,
@@ -155,34 +805,32 @@ impl Filesystem for GitFS {
         crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
+        flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        let ino = Ino::from(ino);
-        let entry = some!(self.inomap.get_mut(ino), reply, ENOENT);
-        // We are just making up numbers to satisfy FUSE.  Git has its
-        // own idea of these attributes, so don't take them seriously.
-        mode.map(|x| entry.perm = Permissions::from_mode(x));
-        size.map(|x| entry.size = x);
-        atime.map(|x| entry.atime = match x {
-            TimeOrNow::SpecificTime(time) => time,
-            TimeOrNow::Now => SystemTime::now()
-        });
-        mtime.map(|x| entry.mtime = match x {
-            TimeOrNow::SpecificTime(time) => time,
-            TimeOrNow::Now => SystemTime::now()
-        });
-        crtime.map(|x| entry.crtime = x);
-        dbg!(&entry);
-        return reply.attr(&Self::ttl(), &Self::make_attr(ino, entry));
+        let _span = op_span!(self, "setattr", ino);
+        let attrs = SetAttr {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            crtime,
+            flags,
+        };
+        let attr = ok!(self, self.do_setattr(ino.into(), attrs), reply);
+        if let Some(size) = size {
+            self.audit(req, "truncate", || format!("{:?} size={}", self.path_of(ino.into()), size));
+        }
+        reply.attr(&self.attr_ttl(), &attr)
     }
 
     fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
-        let ino = Ino::from(ino);
-        match self.do_opendir(ino) {
-            Ok(_) => reply.opened(0, 0),
-            Err(e) => reply.error(e),
-        }
+        let _span = op_span!(self, "opendir", ino);
+        self.refresh_if_requested();
+        ok!(self, self.do_opendir(ino.into()), reply);
+        reply.opened(0, 0)
     }
 
     fn readdir(
@@ -193,122 +841,62 @@ impl Filesystem for GitFS {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        let ino = Ino::from(ino);
-        let entry = some!(self.inomap.get(ino), reply, ENOENT);
-        match &entry.u {
-            EntryKind::GitBlob { .. } | EntryKind::DirtyFile { .. } => return reply.error(ENOTDIR),
-            EntryKind::GitTree {
-                children: Some(children),
-                ..
-            } => {
-                for (i, (name, &child)) in children.iter().enumerate().skip(offset as usize) {
-                    if reply.add(child.into(), (i + 1) as i64, entry.into(), name) {
-                        return reply.ok();
-                    }
-                }
-                return reply.ok();
-            }
-            EntryKind::DirtyDir {
-                children: Some(children),
-            } => {
-                dbg!(&children);
-                dbg!(offset);
-                for (i, (name, &child)) in children.iter().enumerate().skip(offset as usize) {
-                    println!("{:?} {:?}", Ino::from(child), name);
-                    if reply.add(child.into(), (i + 1) as i64, entry.into(), name) {
-                        return reply.ok();
-                    }
-                }
-                return reply.ok();
-            }
-            _ => {
-                // readdir cannot be called before opendir
-                unreachable!()
+        let _span = op_span!(self, "readdir", ino, offset);
+        let children = ok!(self, self.do_readdir(ino.into()), reply);
+        for (i, (name, child, kind)) in children.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(child.into(), (i + 1) as i64, kind, name) {
+                break;
             }
         }
+        reply.ok()
     }
 
     fn releasedir(&mut self, _req: &Request, ino: u64, _fh: u64, _flags: i32, reply: ReplyEmpty) {
-        let ino = Ino::from(ino);
-        let entry = some!(self.inomap.get_mut(ino), reply, ENOENT);
+        let _span = op_span!(self, "releasedir", ino);
+        if control::owns(ino.into()) {
+            return reply.ok();
+        }
+        let inomap = self.inomap();
+        let entry = ok!(self, inomap.get(ino.into()).ok_or(Error::Errno(ENOENT)), reply);
         match entry.u {
-            EntryKind::DirtyFile { .. } | EntryKind::GitBlob { .. } => return reply.error(ENOTDIR),
+            EntryKind::DirtyFile | EntryKind::GitBlob { .. } => return reply.error(ENOTDIR),
             EntryKind::GitTree { .. } | EntryKind::DirtyDir { .. } => (),
         }
         return reply.ok();
     }
 
     fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
-        dbg!(flags);
-        let ino = Ino::from(ino);
-        let entry = some!(self.inomap.get_mut(ino), reply, ENOENT);
-        match entry.u {
-            EntryKind::GitTree { .. } | EntryKind::DirtyDir { .. } => return reply.error(EISDIR),
-            EntryKind::DirtyFile {
-                file: Some(_),
-                ref mut refcnt,
-            } => {
-                *refcnt += 1;
-            }
-            EntryKind::DirtyFile {
-                file: None,
-                ref mut refcnt,
-            } => {
-                *refcnt = 1;
-                let path = self.inomap.prefix(ino).unwrap();
-                debug!("Open dirty file {:?}", path);
-                io_ok!(self.open_dirty_file(ino), reply);
-                return reply.opened(0, 0);
-            }
-            EntryKind::GitBlob { .. } if flags & !O_RDONLY == 0 => {
-                return reply.opened(0, 0);
-            }
-            EntryKind::GitBlob { oid } => {
-                io_ok!(self.open_git_blob_for_update(oid, ino), reply);
-                return reply.opened(0, 0);
-            }
-        }
+        let _span = op_span!(self, "open", ino, flags);
+        let fh = ok!(self, self.do_open(ino.into(), flags), reply);
+        // Virtual files show no size, so reads must not stop at it.
+        let open_flags = if control::owns(ino.into()) { FOPEN_DIRECT_IO } else { 0 };
+        reply.opened(fh, open_flags)
     }
 
     fn read(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        let entry = some!(self.inomap.get_mut(ino.into()), reply, ENOENT);
-        let offset = offset as usize;
-        let size = size as usize;
-        match &mut entry.u {
-            EntryKind::GitBlob { oid, .. } => {
-                let blob = self.repo.find_blob(*oid).unwrap();
-                return reply.data(&blob.content()[offset..offset + size]);
-            }
-            EntryKind::DirtyFile { file, .. } => {
-                if file.is_none() {
-                    warn!("read closed file!");
-                    return reply.error(libc::EIO);
-                }
-                let file = file.as_mut().unwrap();
-                let mut buf = vec![0; size];
-                io_ok!(file.seek(SeekFrom::Start(offset as u64)), reply);
-                let nbytes = io_ok!(file.read(&mut buf), reply);
-                return reply.data(&buf[0..nbytes]);
-            }
-            EntryKind::GitTree { .. } | EntryKind::DirtyDir { .. } => return reply.error(EISDIR),
+        let _span = op_span!(self, "read", ino, offset, size);
+        if offset < 0 {
+            return reply.error(EINVAL);
         }
+        let data = ok!(self, self.do_read(ino.into(), fh, offset as u64, size), reply);
+        reply.data(&data)
     }
 
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _write_flags: u32,
@@ -316,84 +904,45 @@ impl Filesystem for GitFS {
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        let ino = Ino::from(ino);
-        let entry = some!(self.inomap.get_mut(ino), reply, ENOENT);
-        match &mut entry.u {
-            EntryKind::GitTree { .. } | EntryKind::DirtyDir { .. } => return reply.error(EISDIR),
-            EntryKind::DirtyFile {
-                file: Some(ref mut file),
-                ..
-            } => {
-                io_ok!(file.seek(SeekFrom::Start(offset as u64)), reply);
-                let nbytes = io_ok!(file.write(data), reply);
-
-                // Maintain size.
-                entry.size = entry.size.max((offset as u64) + (data.len() as u64));
-
-                return reply.written(nbytes as u32);
-            }
-            _ => {
-                // 1. We should have already replaced all GitBlob with DirtyFile
-                // 2. Such files must have been opened for updating
-                unreachable!()
-            }
-        }
+        let _span = op_span!(self, "write", ino, offset, size = data.len());
+        let nbytes = ok!(self, self.do_write(ino.into(), fh, offset as u64, data), reply);
+        self.audit(req, "write", || format!("{:?} offset={} size={}", self.path_of(ino.into()), offset, nbytes));
+        reply.written(nbytes)
     }
 
-    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        let ino = Ino::from(ino);
-        let entry = some!(self.inomap.get_mut(ino), reply, ENOENT);
-        match entry.u {
-            EntryKind::GitTree { .. } | EntryKind::DirtyDir { .. } => return reply.error(EISDIR),
-            EntryKind::GitBlob { .. } => {
-                // A flush() will be called on read-only files as well.
-                return reply.ok();
-            }
-            EntryKind::DirtyFile {
-                file: Some(ref mut f),
-                ..
-            } => {
-                io_ok!(f.flush(), reply);
-                return reply.ok();
-            }
-            _ => {
-                unreachable!();
-            }
-        }
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let _span = op_span!(self, "flush", ino);
+        ok!(self, self.do_flush(ino.into(), fh), reply);
+        reply.ok()
     }
 
     fn release(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        let ino = Ino::from(ino);
-        let entry = some!(self.inomap.get_mut(ino), reply, ENOENT);
-        match entry.u {
-            EntryKind::DirtyDir { .. } | EntryKind::GitTree { .. } => return reply.error(EISDIR),
-            EntryKind::GitBlob { .. } => (),
-            EntryKind::DirtyFile {
-                ref mut file,
-                ref mut refcnt,
-            } => {
-                *refcnt -= 1;
-                if *refcnt <= 0 {
-                    *refcnt = 0;
-                    *file = None;
-                }
+        let _span = op_span!(self, "release", ino);
+        if control::owns(ino.into()) {
+            self.control_release(fh);
+        } else {
+            // Not in the condition, which would hold the handles
+            // locked while tidying takes them again.
+            let removed = self.handles().remove(fh);
+            if let Some(handle) = removed {
+                self.tidy_on_release(&handle);
             }
         }
-        return reply.ok();
+        reply.ok()
     }
 
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
@@ -401,98 +950,43 @@ impl Filesystem for GitFS {
         _flags: i32,
         reply: ReplyCreate,
     ) {
-        let path = {
-            let mut p = some!(self.inomap.prefix(Ino::from(parent)), reply, EIO);
-            p.push(name);
-            p
-        };
-        let file = io_ok!(self.underlying_dir.write_file(&path, mode as mode_t), reply);
-        let fentry = Entry {
-            name: name.to_owned(),
-            parent: Ino::from(parent),
-            ctime: SystemTime::now(),
-            mtime: SystemTime::now(),
-            atime: SystemTime::now(),
-            crtime: SystemTime::now(),
-            perm: Permissions::from_mode(mode),
-            size: 0,
-            u: EntryKind::DirtyFile {
-                refcnt: 1,
-                file: Some(file),
-            },
-        };
-        let attr = Self::make_attr(self.inomap.next_ino(), &fentry);
-        let ino = self.inomap.add(fentry);
-        let dir = some!(self.inomap.get_mut(Ino::from(parent)), reply, ENOENT);
-        let children = match &mut dir.u {
-            EntryKind::GitTree {
-                children: Some(ref mut c),
-                ..
-            } => c,
-            EntryKind::DirtyDir {
-                children: Some(ref mut c),
-                ..
-            } => c,
-            _ => unreachable!(),
-        };
-        children.insert(name.to_owned(), ino);
-        reply.created(&Self::ttl(), &attr, 0, 0, 0)
+        let _span = op_span!(self, "create", parent, name = ?name);
+        let (attr, fh) = ok!(self, self.do_create(parent.into(), name, mode), reply);
+        self.audit(req, "create", || format!("{:?}", self.child_path(parent, name)));
+        reply.created(&self.entry_ttl(), &attr, 0, fh, 0)
     }
 
     fn mkdir(&mut self,
-             _req: &Request,
+             req: &Request,
              parent: u64,
              name: &OsStr,
              mode: u32,
              _umask: u32,
              reply: ReplyEntry
     ) {
-        let path = {
-            let mut p = some!(self.inomap.prefix(Ino::from(parent)), reply, EIO);
-            p.push(name);
-            p
-        };
-        io_ok!(self.underlying_dir.create_dir(&path, mode as mode_t), reply);
-        let dentry = Entry {
-            name: name.to_owned(),
-            parent: Ino::from(parent),
-            ctime: SystemTime::now(),
-            mtime: SystemTime::now(),
-            atime: SystemTime::now(),
-            crtime: SystemTime::now(),
-            perm: Permissions::from_mode(mode),
-            size: 0,
-            u: EntryKind::DirtyDir { children: None },
-        };
-        let attr = Self::make_attr(self.inomap.next_ino(), &dentry);
-        let ino = self.inomap.add(dentry);
-        let dir = some!(self.inomap.get_mut(Ino::from(parent)), reply, ENOENT);
-        let children = match &mut dir.u {
-            EntryKind::GitTree {
-                children: Some(ref mut c),
-                ..
-            } => c,
-            EntryKind::DirtyDir {
-                children: Some(ref mut c),
-                ..
-            } => c,
-            _ => unreachable!(),
-        };
-        children.insert(name.to_owned(), ino);
-        reply.entry(&Self::ttl(), &attr, 0);
+        let _span = op_span!(self, "mkdir", parent, name = ?name);
+        let attr = ok!(self, self.do_mkdir(parent.into(), name, mode), reply);
+        self.audit(req, "mkdir", || format!("{:?}", self.child_path(parent, name)));
+        reply.entry(&self.entry_ttl(), &attr, 0)
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        self.do_remove(parent.into(), name, reply)
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _span = op_span!(self, "unlink", parent, name = ?name);
+        ok!(self, self.do_remove(parent.into(), name), reply);
+        self.audit(req, "unlink", || format!("{:?}", self.child_path(parent, name)));
+        reply.ok()
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        self.do_remove(parent.into(), name, reply)
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _span = op_span!(self, "rmdir", parent, name = ?name);
+        ok!(self, self.do_remove(parent.into(), name), reply);
+        self.audit(req, "rmdir", || format!("{:?}", self.child_path(parent, name)));
+        reply.ok()
     }
 
     fn rename(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         newparent: u64,
@@ -500,44 +994,49 @@ impl Filesystem for GitFS {
         _flags: u32,
         reply: ReplyEmpty,
     ) {
-        let oldp = parent.into();
-        let oldpent = some!(self.inomap.get(oldp), reply, ENOENT);
-        let c = some!(oldpent.get_child(name), reply, ENOENT);
-        let cent = some!(self.inomap.get(c), reply, ENOENT);
-        let newp = newparent.into();
-        let _newpent = some!(self.inomap.get(newp), reply, ENOENT);
-
-        // Move dirty files/directories physically.
-        match cent.u {
-            EntryKind::DirtyFile { .. } | EntryKind::DirtyDir { .. } => {
-                let oldpath = self.inomap.prefix(c).unwrap();
-                let mut newpath = self.inomap.prefix(newp).unwrap();
-                newpath.push(newname);
-                debug!("move {:?} to {:?}", oldpath, newpath);
-                io_ok!(self.underlying_dir.local_rename(&oldpath, &newpath), reply);
-            }
-            _ => (),
+        let _span = op_span!(self, "rename", parent, name = ?name, newparent, newname = ?newname);
+        ok!(self, self.do_rename(parent.into(), name, newparent.into(), newname), reply);
+        self.audit(req, "rename", || {
+            format!("{:?} {:?}", self.child_path(parent, name), self.child_path(newparent, newname))
+        });
+        reply.ok()
+    }
+
+    fn destroy(&mut self) {
+        let _span = debug_span!("destroy").entered();
+        if self.options_read().summary_on_unmount {
+            self.log_summary();
         }
+        self.handles().close_all();
+        self.unpin();
+        self.blob_cache().set_capacity(0);
+        *self.inomap() = InoMap::new();
+        info!("gitfs is unmounted");
+    }
 
-        // Move entry from oldp to newp. Keep ino intact.
-        let cent = self.inomap.get_mut(c).unwrap();
-        cent.name = newname.to_os_string();
-        let oldpent = self.inomap.get_mut(oldp).unwrap();
-        oldpent.remove_child(name).unwrap();
-        let newpent = self.inomap.get_mut(newp).unwrap();
-        newpent.add_child(newname.to_os_string(), c);
-        return reply.ok();
+    fn statfs(&mut self, _req: &Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        let _span = op_span!(self, "statfs", ino);
+        let st = ok!(self, statvfs(&self.inner.underlying_dir), reply);
+        reply.statfs(
+            st.f_blocks as u64,
+            st.f_bfree as u64,
+            st.f_bavail as u64,
+            st.f_files as u64,
+            st.f_ffree as u64,
+            st.f_bsize as u32,
+            self.inner.name_max as u32,
+            st.f_frsize as u32,
+        )
     }
 
-    fn destroy(&mut self) {}
-
-    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
-
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
-        debug!("[Not Implemented] readlink(ino: {:#x?})", ino);
-        reply.error(libc::ENOSYS);
+        let _span = op_span!(self, "readlink", ino);
+        let target = ok!(self, self.do_readlink(ino.into()), reply);
+        reply.data(&target)
     }
 
+    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
+
     fn mknod(
         &mut self,
         _req: &Request<'_>,
@@ -548,6 +1047,7 @@ impl Filesystem for GitFS {
         rdev: u32,
         reply: ReplyEntry,
     ) {
+        let _span = op_span!(self, "mknod", parent, name = ?name);
         debug!(
             "[Not Implemented] mknod(parent: {:#x?}, name: {:?}, mode: {}, \
             umask: {:#x?}, rdev: {})",
@@ -564,6 +1064,7 @@ impl Filesystem for GitFS {
         link: &std::path::Path,
         reply: ReplyEntry,
     ) {
+        let _span = op_span!(self, "symlink", parent, name = ?name);
         debug!(
             "[Not Implemented] symlink(parent: {:#x?}, name: {:?}, link: {:?})",
             parent, name, link,
@@ -579,6 +1080,7 @@ impl Filesystem for GitFS {
         newname: &OsStr,
         reply: ReplyEntry,
     ) {
+        let _span = op_span!(self, "link", ino);
         debug!(
             "[Not Implemented] link(ino: {:#x?}, newparent: {:#x?}, newname: {:?})",
             ino, newparent, newname
@@ -587,6 +1089,7 @@ impl Filesystem for GitFS {
     }
 
     fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let _span = op_span!(self, "fsync", ino);
         debug!(
             "[Not Implemented] fsync(ino: {:#x?}, fh: {}, datasync: {})",
             ino, fh, datasync
@@ -598,15 +1101,24 @@ impl Filesystem for GitFS {
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        fh: u64,
+        _fh: u64,
         offset: i64,
-        reply: fuser::ReplyDirectoryPlus,
+        mut reply: fuser::ReplyDirectoryPlus,
     ) {
-        debug!(
-            "[Not Implemented] readdirplus(ino: {:#x?}, fh: {}, offset: {})",
-            ino, fh, offset
-        );
-        reply.error(libc::ENOSYS);
+        let _span = op_span!(self, "readdirplus", ino, offset);
+        let children = ok!(self, self.do_readdirplus(ino.into()), reply);
+        let ttl = self.entry_ttl();
+        for (i, (name, attr)) in children.into_iter().enumerate().skip(offset as usize) {
+            // Offsets are those of readdir, which the kernel may switch to.
+            let attr = match attr {
+                Some(attr) => attr,
+                None => continue,
+            };
+            if reply.add(attr.ino, (i + 1) as i64, name, &ttl, &attr, 0) {
+                break;
+            }
+        }
+        reply.ok()
     }
 
     fn fsyncdir(
@@ -617,6 +1129,7 @@ impl Filesystem for GitFS {
         datasync: bool,
         reply: ReplyEmpty,
     ) {
+        let _span = op_span!(self, "fsyncdir", ino);
         debug!(
             "[Not Implemented] fsyncdir(ino: {:#x?}, fh: {}, datasync: {})",
             ino, fh, datasync
@@ -624,25 +1137,19 @@ impl Filesystem for GitFS {
         reply.error(libc::ENOSYS);
     }
 
-    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
-    }
-
     fn setxattr(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
         name: &OsStr,
-        _value: &[u8],
+        value: &[u8],
         flags: i32,
-        position: u32,
+        _position: u32,
         reply: ReplyEmpty,
     ) {
-        debug!(
-            "[Not Implemented] setxattr(ino: {:#x?}, name: {:?}, flags: {:#x?}, position: {})",
-            ino, name, flags, position
-        );
-        reply.error(libc::ENOSYS);
+        let _span = op_span!(self, "setxattr", ino, name = ?name);
+        ok!(self, self.do_setxattr(ino.into(), name, value, flags), reply);
+        reply.ok()
     }
 
     fn getxattr(
@@ -653,30 +1160,25 @@ impl Filesystem for GitFS {
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        debug!(
-            "[Not Implemented] getxattr(ino: {:#x?}, name: {:?}, size: {})",
-            ino, name, size
-        );
-        reply.error(libc::ENOSYS);
+        let _span = op_span!(self, "getxattr", ino, name = ?name);
+        let value = ok!(self, self.do_getxattr(ino.into(), name), reply);
+        reply_xattr(&value, size, reply)
     }
 
     fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
-        debug!(
-            "[Not Implemented] listxattr(ino: {:#x?}, size: {})",
-            ino, size
-        );
-        reply.error(libc::ENOSYS);
+        let _span = op_span!(self, "listxattr", ino);
+        let names = ok!(self, self.do_listxattr(ino.into()), reply);
+        reply_xattr(&names, size, reply)
     }
 
     fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
-        debug!(
-            "[Not Implemented] removexattr(ino: {:#x?}, name: {:?})",
-            ino, name
-        );
-        reply.error(libc::ENOSYS);
+        let _span = op_span!(self, "removexattr", ino, name = ?name);
+        ok!(self, self.do_removexattr(ino.into(), name), reply);
+        reply.ok()
     }
 
     fn access(&mut self, _req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let _span = op_span!(self, "access", ino);
         debug!("[Not Implemented] access(ino: {:#x?}, mask: {})", ino, mask);
         reply.error(libc::ENOSYS);
     }
@@ -693,6 +1195,7 @@ impl Filesystem for GitFS {
         pid: u32,
         reply: fuser::ReplyLock,
     ) {
+        let _span = op_span!(self, "getlk", ino);
         debug!(
             "[Not Implemented] getlk(ino: {:#x?}, fh: {}, lock_owner: {}, start: {}, \
             end: {}, typ: {}, pid: {})",
@@ -714,6 +1217,7 @@ impl Filesystem for GitFS {
         sleep: bool,
         reply: ReplyEmpty,
     ) {
+        let _span = op_span!(self, "setlk", ino);
         debug!(
             "[Not Implemented] setlk(ino: {:#x?}, fh: {}, lock_owner: {}, start: {}, \
             end: {}, typ: {}, pid: {}, sleep: {})",
@@ -723,6 +1227,7 @@ impl Filesystem for GitFS {
     }
 
     fn bmap(&mut self, _req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: fuser::ReplyBmap) {
+        let _span = op_span!(self, "bmap", ino);
         debug!(
             "[Not Implemented] bmap(ino: {:#x?}, blocksize: {}, idx: {})",
             ino, blocksize, idx,
@@ -741,6 +1246,7 @@ impl Filesystem for GitFS {
         out_size: u32,
         reply: fuser::ReplyIoctl,
     ) {
+        let _span = op_span!(self, "ioctl", ino);
         debug!(
             "[Not Implemented] ioctl(ino: {:#x?}, fh: {}, flags: {}, cmd: {}, \
             in_data.len(): {}, out_size: {})",
@@ -764,6 +1270,7 @@ impl Filesystem for GitFS {
         mode: i32,
         reply: ReplyEmpty,
     ) {
+        let _span = op_span!(self, "fallocate", ino, offset, length);
         debug!(
             "[Not Implemented] fallocate(ino: {:#x?}, fh: {}, offset: {}, \
             length: {}, mode: {})",
@@ -781,6 +1288,7 @@ impl Filesystem for GitFS {
         whence: i32,
         reply: fuser::ReplyLseek,
     ) {
+        let _span = op_span!(self, "lseek", ino, offset);
         debug!(
             "[Not Implemented] lseek(ino: {:#x?}, fh: {}, offset: {}, whence: {})",
             ino, fh, offset, whence
@@ -801,6 +1309,7 @@ impl Filesystem for GitFS {
         flags: u32,
         reply: ReplyWrite,
     ) {
+        let _span = op_span!(self, "copy_file_range", ino_in, ino_out);
         debug!(
             "[Not Implemented] copy_file_range(ino_in: {:#x?}, fh_in: {}, \
             offset_in: {}, ino_out: {:#x?}, fh_out: {}, offset_out: {}, \
@@ -810,11 +1319,13 @@ impl Filesystem for GitFS {
         reply.error(libc::ENOSYS);
     }
 
+    #[cfg(target_os = "macos")]
     fn setvolname(&mut self, _req: &Request<'_>, name: &OsStr, reply: ReplyEmpty) {
         debug!("[Not Implemented] setvolname(name: {:?})", name);
         reply.error(libc::ENOSYS);
     }
 
+    #[cfg(target_os = "macos")]
     fn exchange(
         &mut self,
         _req: &Request<'_>,
@@ -825,6 +1336,7 @@ impl Filesystem for GitFS {
         options: u64,
         reply: ReplyEmpty,
     ) {
+        let _span = op_span!(self, "exchange", parent, name = ?name);
         debug!(
             "[Not Implemented] exchange(parent: {:#x?}, name: {:?}, newparent: {:#x?}, \
             newname: {:?}, options: {})",
@@ -833,409 +1345,4211 @@ impl Filesystem for GitFS {
         reply.error(libc::ENOSYS);
     }
 
+    #[cfg(target_os = "macos")]
     fn getxtimes(&mut self, _req: &Request<'_>, ino: u64, reply: fuser::ReplyXTimes) {
+        let _span = op_span!(self, "getxtimes", ino);
         debug!("[Not Implemented] getxtimes(ino: {:#x?})", ino);
         reply.error(libc::ENOSYS);
     }
 }
 
-// private interfaces
+// operations
+//
+// The FUSE handlers above only translate between FUSE and these
+// methods, which do the actual work and report failures as `Error`.
 impl GitFS {
-    /// by default, ttl = 1 second
-    fn ttl() -> Duration {
-        Duration::from_secs(1)
+    fn do_lookup(&self, parent: Ino, name: &OsStr) -> Result<FileAttr, Error> {
+        if control::owns(parent) || self.is_control_entry(parent, name) {
+            return self.control_lookup(parent, name);
+        }
+        self.do_opendir(parent)?;
+        let mut inomap = self.inomap();
+        self.check_rules(|| Some(inomap.prefix(parent)?.join(name)), false)?;
+        let parent_entry = inomap.get(parent).ok_or(Error::Errno(ENOENT))?;
+        let child = parent_entry.get_child(name).ok_or(Error::Errno(ENOENT))?;
+        if inomap.get(child).is_some_and(|entry| FileType::from(entry) != FileType::Directory) {
+            self.check_included_file(|| Some(inomap.prefix(parent)?.join(name)))?;
+        }
+        self.attr(&mut inomap, child)
     }
 
-    /// Create the root entry.
-    #[cfg(target_os = "macos")]
-    fn root_entry(&self, tree: Tree<'_>) -> Entry {
-        let metadata = self.underlying_dir.self_metadata().unwrap();
-        let stat = metadata.stat();
-        let atime = SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_atime as u64);
-        let ctime = SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_ctime as u64);
-        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_mtime as u64);
-        let crtime = SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_birthtime as u64);
-        Entry {
-            name: "".to_string().into(),
-            parent: Ino::ROOT,
-            size: 0,
-            atime,
-            ctime,
-            mtime,
-            crtime,
-            perm: metadata.permissions(),
-            u: EntryKind::GitTree {
-                oid: tree.id(),
-                children: None,
-            },
+    fn do_getattr(&self, ino: Ino) -> Result<FileAttr, Error> {
+        if control::owns(ino) {
+            return self.control_getattr(ino);
         }
+        self.attr(&mut self.inomap(), ino)
     }
 
-    #[cfg(not(target_os = "macos"))]
-    fn root_entry(&self, tree: Tree<'_>) -> Entry {
-        let metadata = self.underlying_dir.self_metadata().unwrap();
-        let stat = metadata.stat();
-        let atime = Timespec::new(stat.st_atime, stat.st_atime_nsec as i32);
-        let mtime = Timespec::new(stat.st_mtime, stat.st_mtime_nsec as i32);
-        let ctime = Timespec::new(stat.st_ctime, stat.st_ctime_nsec as i32);
-        let crtime = Timespec::new(0, 0);
-        Entry {
-            name: "".to_string().into(),
-            parent: Ino::ROOT,
-            size: 0,
+    fn do_setattr(&self, ino: Ino, attrs: SetAttr) -> Result<FileAttr, Error> {
+        if control::owns(ino) {
+            return self.control_setattr(ino, attrs);
+        }
+        let SetAttr {
+            mode,
+            uid,
+            gid,
+            size,
             atime,
-            ctime,
             mtime,
             crtime,
-            perm: metadata.permissions(),
-            u: EntryKind::GitTree {
-                oid: tree.id(),
-                children: None,
-            },
+            flags,
+        } = attrs;
+        // Checked before anything changes, truncating included.
+        {
+            let inomap = self.inomap();
+            self.check_rules(|| inomap.prefix(ino), true)?;
+            self.check_submodule(&inomap, ino)?;
+        }
+        if let Some(size) = size {
+            self.truncate(ino, size)?;
+        }
+        let mut inomap = self.inomap();
+        if mode.is_some() || uid.is_some() || gid.is_some() || flags.is_some() || atime.is_some() || mtime.is_some() {
+            self.unshare(&inomap, ino)?;
+        }
+        if let Some(mode) = mode {
+            self.chmod(&inomap, ino, mode)?;
+        }
+        if uid.is_some() || gid.is_some() {
+            self.chown(&mut inomap, ino, uid, gid)?;
         }
+        if let Some(flags) = flags {
+            self.chflags(&mut inomap, ino, flags)?;
+        }
+        if atime.is_some() || mtime.is_some() {
+            self.utimens(&inomap, ino, atime, mtime)?;
+        }
+        let entry = inomap.get_mut(ino).ok_or(Error::Errno(ENOENT))?;
+        // We are just making up numbers to satisfy FUSE.  Git has its
+        // own idea of these attributes, so don't take them seriously.
+        if let Some(x) = mode {
+            entry.perm = Permissions::from_mode(x);
+        }
+        if let Some(x) = size {
+            entry.size = x;
+        }
+        if let Some(x) = atime {
+            entry.atime = match x {
+                TimeOrNow::SpecificTime(time) => time,
+                TimeOrNow::Now => SystemTime::now()
+            };
+        }
+        if let Some(x) = mtime {
+            entry.mtime = match x {
+                TimeOrNow::SpecificTime(time) => time,
+                TimeOrNow::Now => SystemTime::now()
+            };
+        }
+        if let Some(x) = crtime {
+            entry.crtime = x;
+        }
+        trace!(?entry, "attributes updated");
+        self.attr(&mut inomap, ino)
     }
 
-    /// Remove a file or a directory.
-    fn do_remove(&mut self, parent: Ino, name: &OsStr, reply: ReplyEmpty) {
-        let parent_entry = some!(self.inomap.get(parent.into()), reply, ENOENT);
-        let child = match parent_entry.u {
-            EntryKind::DirtyDir {
-                children: Some(ref c),
-            } => *some!(c.get(name), reply, ENOENT),
-            EntryKind::GitTree {
-                children: Some(ref c),
-                ..
-            } => *some!(c.get(name), reply, ENOENT),
-            _ => unreachable!(),
-        };
-
-        match self.remove_entry(child) {
-            Ok(_) => return reply.ok(),
-            Err((entry, err)) => {
-                let ino = self.inomap.add(entry);
-                let parent_entry = self.inomap.get_mut(parent.into()).unwrap();
-                let c = match parent_entry.u {
-                    EntryKind::DirtyDir {
-                        children: Some(ref mut c),
-                    } => c,
-                    EntryKind::GitTree {
-                        children: Some(ref mut c),
-                        ..
-                    } => c,
-                    _ => unreachable!(),
-                };
-                c.insert(name.to_os_string(), ino);
-                return reply.error(err.raw_os_error().unwrap_or(EIO));
+    /// Change the mode of an entry.  Dirty entries are changed in the
+    /// underlying dir, whose mode bits are what gets committed, unless
+    /// it doesn't keep them (`core.fileMode`).  The mode of clean
+    /// entries is only recorded, and given to the file once it's
+    /// materialized.
+    fn chmod(&self, inomap: &InoMap, ino: Ino, mode: u32) -> Result<(), Error> {
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        match entry.u {
+            // Symlinks have no mode of their own.
+            EntryKind::DirtyFile | EntryKind::DirtyDir { .. }
+                if self.inner.file_mode && FileType::from(entry) != FileType::Symlink =>
+            {
+                let path = self.overlay_path(inomap, ino)?;
+                Ok(chmod_at(&self.inner.underlying_dir, &path, mode)?)
             }
+            _ => Ok(()),
         }
     }
 
-    /// Remove ino from inomap. If the entry fails to be removed
-    /// (e.g. cannot delete dirty file on disk), the entry itself is
-    /// returned so that it can be inserted.
-    fn remove_entry(&mut self, ino: Ino) -> Result<(), (Entry, io::Error)> {
-        let path = self.inomap.prefix(ino).unwrap();
-        let mut entry = self.inomap.remove(ino).unwrap();
+    /// Change the owner of an entry.  Dirty entries are changed in the
+    /// underlying dir, which may refuse it (EPERM).  The owner of clean
+    /// entries is only recorded, and given to the file once it's
+    /// materialized.  Otherwise, checking permissions is left to the
+    /// kernel (see the `default_permissions` mount option).
+    fn chown(&self, inomap: &mut InoMap, ino: Ino, uid: Option<u32>, gid: Option<u32>) -> Result<(), Error> {
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
         match entry.u {
-            EntryKind::DirtyFile {
-                ref mut refcnt,
-                ref mut file,
-            } => match self.underlying_dir.remove_file(&path) {
-                Ok(_) => {
-                    *refcnt = 0;
-                    let _ = file.take();
-                    Ok(())
-                }
-                Err(err) => Err((entry, err)),
-            },
-            EntryKind::DirtyDir { .. } => match self.underlying_dir.remove_dir(&path) {
-                Ok(_) => Ok(()),
-                Err(err) => Err((entry, err)),
-            },
-            EntryKind::GitBlob { .. } => {
-                // TODO: Perhaps we should record such information, so
-                // that when the repo is mounted here, we can restore
-                // the unstaged deletion.
-                return Ok(());
+            EntryKind::DirtyFile | EntryKind::DirtyDir { .. } => {
+                let path = self.overlay_path(inomap, ino)?;
+                chown_at(&self.inner.underlying_dir, &path, uid, gid)?;
+            }
+            EntryKind::GitBlob { .. } | EntryKind::GitTree { .. } => {
+                let (old_uid, old_gid) = self.owner(inomap, ino, None);
+                let owner = (uid.unwrap_or(old_uid), gid.unwrap_or(old_gid));
+                inomap.get_mut(ino).ok_or(Error::Errno(ENOENT))?.owner = Some(owner);
             }
-            EntryKind::GitTree { .. } => Ok(()),
         }
+        Ok(())
     }
 
-    fn open_dirty_file(&mut self, ino: Ino) -> Result<(), io::Error> {
-        let path = self.inomap.prefix(ino).unwrap();
-        let entry = self.inomap.get_mut(ino).unwrap();
-        let f = self
-            .underlying_dir
-            .update_file(&path, entry.perm.mode() as mode_t)?;
-        entry.u = EntryKind::DirtyFile {
-            file: Some(f),
-            refcnt: 1,
-        };
+    /// Set the BSD flags of an entry, e.g. `hidden` or `uchg`.  Dirty
+    /// entries are changed in the underlying dir.  The flags of clean
+    /// entries are only recorded, as their owner is, and given to the
+    /// file once it's materialized; system flags, which take the
+    /// superuser, are refused.
+    fn chflags(&self, inomap: &mut InoMap, ino: Ino, flags: u32) -> Result<(), Error> {
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        match entry.u {
+            EntryKind::DirtyFile | EntryKind::DirtyDir { .. } => {
+                let path = self.overlay_path(inomap, ino)?;
+                chflags_at(&self.inner.underlying_dir, &path, flags)?;
+            }
+            EntryKind::GitBlob { .. } | EntryKind::GitTree { .. } => {
+                if flags & SF_FLAGS != 0 {
+                    return Err(Error::Errno(EPERM));
+                }
+                inomap.get_mut(ino).ok_or(Error::Errno(ENOENT))?.flags = Some(flags);
+            }
+        }
         Ok(())
     }
 
-    fn open_git_blob_for_update(&mut self, oid: Oid, ino: Ino) -> Result<(), io::Error> {
-        // checkout git blob
-        let path = self.inomap.prefix(ino).unwrap();
-        let blob = self.repo.find_blob(oid).unwrap();
-        let entry = self.inomap.get_mut(ino).unwrap();
-        let mut f = self
-            .underlying_dir
-            .update_file(&path, entry.perm.mode() as mode_t)?;
-        f.write_all(blob.content())?;
-
-        // replace git blob entry with a dirty file entry
-        entry.u = EntryKind::DirtyFile {
-            file: Some(f),
-            refcnt: 1,
-        };
-
+    /// Set the times of a dirty entry in the underlying dir, so that
+    /// they outlive the ino map.  Clean entries only have the times in
+    /// their `Entry`.
+    fn utimens(&self, inomap: &InoMap, ino: Ino, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>) -> Result<(), Error> {
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        if let EntryKind::DirtyFile | EntryKind::DirtyDir { .. } = entry.u {
+            let path = self.overlay_path(inomap, ino)?;
+            utimens_at(&self.inner.underlying_dir, &path, atime, mtime)?;
+        }
         Ok(())
     }
 
+    /// Set the length of file `ino`, materializing it first if it's
+    /// clean, as opening it for writing does.
+    fn truncate(&self, ino: Ino, size: u64) -> Result<(), Error> {
+        let fh = self.do_open(ino, libc::O_RDWR)?;
+        let unshared = self.unshare(&self.inomap(), ino);
+        let handle = self.handles().remove(fh);
+        unshared?;
+        let file = handle.and_then(|handle| handle.file).ok_or(Error::Errno(EIO))?;
+        let len = self.overlay_len(file.metadata()?.len());
+        self.check_quota(size.saturating_sub(len))?;
+        debug!(ino = u64::from(ino), size, "truncate");
+        Ok(self.overlay_set_len(&file, size)?)
+    }
+
     /// List a GitTree or open a dirty dir.
-    fn do_opendir(&mut self, ino: Ino) -> Result<(), c_int> {
-        let dir_entry = self.inomap.get(ino).ok_or(ENOENT)?;
-
-        // Step1: check if has been listed. if so, return early;
-        // otherwise, list the dir.
-        //
-        // We treat directories specially, because dir_entry points to
-        // inomap, but inomap should stay unchanged during our walk.
-        let walk;
-        match dir_entry.u {
-            EntryKind::DirtyDir { children: Some(_) } => return Ok(()),
-            EntryKind::GitTree {
-                children: Some(_), ..
-            } => return Ok(()),
-            EntryKind::GitTree {
-                oid,
-                children: None,
-                ..
-            } => {
-                walk = self.walk_dir(ino, Some(oid))?;
-            }
-            EntryKind::DirtyDir { children: None, .. } => {
-                walk = self.walk_dir(ino, None)?;
-            }
-            _ => return Err(ENOTDIR),
+    ///
+    /// A listed dir is listed again if its underlying dir has changed
+    /// since, e.g. edited by another process.  Children that are still
+    /// there keep their inos.
+    fn do_opendir(&self, ino: Ino) -> Result<(), Error> {
+        if control::owns(ino) {
+            return self.control_opendir(ino);
         }
-        dbg!(&walk);
-
-        // Step2: walk done, insert data to inomap so that we have inos
-        let children_entries = walk
-            .into_iter()
-            .map(|(name, entry)| (name, self.inomap.add(entry)))
-            .collect::<HashMap<OsString, Ino>>();
-        dbg!(&children_entries);
-
-        // Step3: lookup dir_entry again in case it's moved
-        let dir_entry = self.inomap.get_mut(ino).ok_or(ENOENT)?;
-        match dir_entry.u {
-            EntryKind::GitTree {
-                children: ref mut c @ None,
-                ..
-            } => {
-                c.replace(children_entries);
+        // Step1: check if has been listed and is up to date. if so,
+        // return early; otherwise, find out what to walk.
+        let (tree_id, path, prefix, shadowed, stamp) = {
+            let inomap = self.inomap();
+            let dir_entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+            let (tree_id, listed) = match &dir_entry.u {
+                EntryKind::GitTree { oid, children, .. } => (Some(*oid), children.is_some()),
+                EntryKind::DirtyDir { children } => (None, children.is_some()),
+                _ => return Err(Error::Errno(ENOTDIR)),
+            };
+            let path = inomap.prefix(ino).ok_or(Error::Errno(ENOENT))?;
+            let prefix = self.overlay_path(&inomap, ino)?;
+            if listed && dir_entry.stamp.is_some() && self.inner.overlay_watched.load(Ordering::SeqCst) {
                 return Ok(());
             }
-            EntryKind::DirtyDir {
-                children: ref mut c @ None,
-                ..
-            } => {
-                c.replace(children_entries);
+            let stamp = self.overlay_stamp(&prefix);
+            if listed && stamp == dir_entry.stamp {
                 return Ok(());
             }
-            _ => unreachable!(),
-        }
-    }
+            (tree_id, path, prefix, dir_entry.shadowed, stamp)
+        };
 
-    fn walk_tree(&self, ino: Ino, tree_id: Oid) -> Result<HashMap<OsString, Entry>, GitError> {
-        let tree = self.repo.find_tree(tree_id)?;
-        let mut entries = HashMap::new();
+        // Step2: walk without holding the inomap, which may be rather
+        // slow for large trees.
+        let walk = self.walk_dir(ino, &path, &prefix, tree_id, shadowed)?;
+        trace!(?walk, "walked directory");
 
-        for tree_entry in tree.iter() {
-            let name = OsString::from(OsStr::from_bytes(tree_entry.name_bytes()));
-            let perm = Permissions::from_mode(tree_entry.filemode() as u32);
-            let entry = match tree_entry.kind() {
-                Some(ObjectType::Blob) => {
-                    let blob = self.repo.find_blob(tree_entry.id())?;
-                    Entry {
-                        name: name.clone(),
-                        parent: ino,
-                        size: blob.size() as u64,
-                        perm,
-                        ctime: SystemTime::UNIX_EPOCH,
-                        atime: SystemTime::UNIX_EPOCH,
-                        mtime: SystemTime::UNIX_EPOCH,
-                        crtime: SystemTime::UNIX_EPOCH,
-                        u: EntryKind::GitBlob {
-                            oid: tree_entry.id(),
-                        },
+        // Step3: lookup dir_entry again, since it might have been
+        // removed or switched to another tree meanwhile.
+        let mut inomap = self.inomap();
+        let dir_entry = inomap.get_mut(ino).ok_or(Error::Errno(ENOENT))?;
+        let listed = match &mut dir_entry.u {
+            EntryKind::GitTree { oid, children, .. } if Some(*oid) == tree_id => children.take(),
+            EntryKind::DirtyDir { children } if tree_id.is_none() => children.take(),
+            _ => return Ok(()),
+        };
+        // Dirty subdirs may not have been counted so far.
+        dir_entry.subdirs = None;
+        dir_entry.stamp = stamp;
+
+        // Step4: insert data to inomap so that we have inos
+        let relisting = listed.is_some();
+        let mut stale = listed.unwrap_or_default();
+        let mut children_entries = HashMap::new();
+        for (name, entry) in walk {
+            let child = match stale.remove(&name) {
+                // What the tree has there, or what was renamed over it
+                // through the mount.
+                Some(child) if entry.is_clean() && inomap.get(child).is_some_and(Entry::is_clean) => child,
+                Some(child) if inomap.get(child).map(FileType::from) == Some(FileType::from(&entry)) => {
+                    // Listed dirs keep their children; other entries
+                    // are as in the walk.
+                    if let Some(old) = inomap.get_mut(child) {
+                        if FileType::from(&entry) != FileType::Directory {
+                            let (owner, flags) = (old.owner, old.flags);
+                            *old = entry;
+                            old.owner = owner;
+                            old.flags = flags;
+                        }
                     }
+                    child
                 }
-                Some(ObjectType::Tree) => Entry {
-                    parent: ino,
-                    name: name.clone(),
-                    perm: Permissions::from_mode(0o755), // tree doesn't have a proper mode
-                    size: 0,
-                    ctime: SystemTime::UNIX_EPOCH,
-                    atime: SystemTime::UNIX_EPOCH,
-                    mtime: SystemTime::UNIX_EPOCH,
-                    crtime: SystemTime::UNIX_EPOCH,
-                    u: EntryKind::GitTree {
-                        oid: tree_entry.id(),
-                        children: None,
-                    },
-                },
-                _ => {
-                    warn!(
-                        "{} ({}) is not supported, skipping",
-                        tree_entry.id(),
-                        String::from_utf8_lossy(tree_entry.name_bytes())
-                    );
-                    continue;
+                Some(child) => {
+                    inomap.invalidate(child);
+                    inomap.remove(child);
+                    inomap.add(entry)
                 }
+                // Clean entries missing from the listing were removed
+                // or renamed through the mount.
+                None if relisting && entry.is_clean() => continue,
+                None => inomap.add(entry),
             };
-            entries.insert(name, entry);
+            children_entries.insert(name, child);
         }
-        Ok(entries)
+        for (name, child) in stale {
+            // Clean entries renamed here through the mount aren't in
+            // the tree of the dir, nor in the underlying dir.
+            if inomap.get(child).is_some_and(Entry::is_clean) {
+                children_entries.insert(name, child);
+                continue;
+            }
+            inomap.invalidate(child);
+            inomap.remove(child);
+        }
+        match &mut inomap.get_mut(ino).ok_or(Error::Errno(ENOENT))?.u {
+            EntryKind::GitTree { children, .. } | EntryKind::DirtyDir { children } => {
+                children.replace(children_entries);
+            }
+            // Checked above with the inomap locked.
+            _ => return Err(Error::Errno(EIO)),
+        }
+        Ok(())
     }
 
-    /// If tree_id is None, then the directory is considered a dirty
-    /// dir. All files and dirs under a dirty dir are dirty.
-    /// Of course, it can be recursive, but laziness is a virtue.
-    fn walk_dir(
-        &self,
-        ino: Ino,
-        tree_id: Option<Oid>,
-    ) -> Result<HashMap<OsString, Entry>, c_int> {
-        let mut entries = match tree_id {
-            Some(tree_id) => self.walk_tree(ino, tree_id).map_err(|_| ENOENT)?,
-            None => HashMap::new(),
+    /// Return the children of a listed directory with their names and
+    /// kinds, in a stable order.
+    fn do_readdir(&self, ino: Ino) -> Result<Vec<(OsString, Ino, FileType)>, Error> {
+        if control::owns(ino) {
+            return self.control_readdir(ino);
+        }
+        let inomap = self.inomap();
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        let children = match &entry.u {
+            EntryKind::GitBlob { .. } | EntryKind::DirtyFile => return Err(Error::Errno(ENOTDIR)),
+            EntryKind::GitTree {
+                children: Some(children),
+                ..
+            } => children,
+            EntryKind::DirtyDir {
+                children: Some(children),
+            } => children,
+            // readdir cannot be called before opendir, but the dir
+            // may have been invalidated since.
+            _ => return Err(Error::Errno(ENOENT)),
         };
+        let dir = inomap.prefix(ino);
+        Ok(children
+            .iter()
+            // The control dir hides what it's named after.
+            .filter(|(name, _)| !self.is_control_entry(ino, name))
+            .filter(|(name, _)| self.check_rules(|| Some(dir.as_ref()?.join(name)), false).is_ok())
+            .filter_map(|(name, &child)| {
+                let kind = FileType::from(inomap.get(child)?);
+                if kind != FileType::Directory && self.check_included_file(|| Some(dir.as_ref()?.join(name))).is_err() {
+                    return None;
+                }
+                Some((name.clone(), child, kind))
+            })
+            .collect())
+    }
 
-        // look at underlying_dir/prefix
-        let prefix = self.inomap.prefix(ino).unwrap();
-        let dir_iter = if ino.is_root() {
-            self.underlying_dir.list_self()
-        } else {
-            self.underlying_dir.list_dir(prefix.as_os_str())
+    /// The children of a dir, as `do_readdir` lists them, with their
+    /// attributes as `do_lookup` gives them, or `None` for those gone
+    /// in between.
+    fn do_readdirplus(&self, ino: Ino) -> Result<Vec<(OsString, Option<FileAttr>)>, Error> {
+        self.do_readdir(ino)?
+            .into_iter()
+            .map(|(name, _, _)| match self.do_lookup(ino, &name) {
+                Ok(attr) => Ok((name, Some(attr))),
+                Err(e) if self.errno(&e) == ENOENT => Ok((name, None)),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    /// Open a file and return a new file handle for it.  A clean file
+    /// opened for writing is materialized in the underlying dir first.
+    /// Return the target of a symlink, which git stores as the content
+    /// of its blob.
+    fn do_readlink(&self, ino: Ino) -> Result<Vec<u8>, Error> {
+        let oid = match self.inomap().get(ino).ok_or(Error::Errno(ENOENT))? {
+            entry @ Entry { u: EntryKind::GitBlob { oid, .. }, .. } if FileType::from(entry) == FileType::Symlink => *oid,
+            _ => return Err(Error::Errno(EINVAL)),
         };
+        Ok(self.blob_content(oid)?.to_vec())
+    }
 
-        if dir_iter.is_err() && tree_id.is_some() {
-            // git tree doesn't have a ghost dir, return early
-            return Ok(entries);
-        } else if dir_iter.is_err() && tree_id.is_none() {
-            // error inside dirty dir
-            return Err(EIO);
+    fn do_open(&self, ino: Ino, flags: i32) -> Result<u64, Error> {
+        if control::owns(ino) {
+            return self.control_open(ino, flags);
         }
-        let dir_iter = dir_iter.unwrap();
-
-        // try to collect dirty entries
-        for dirty_entry in dir_iter {
-            if dirty_entry.is_err() {
-                warn!("a dirty entry cannot be read, skipping");
-                continue;
-            }
-
-            let dirty_entry = dirty_entry.unwrap();
-            let mut path = prefix.clone();
-            path.push(dirty_entry.file_name());
-            let metadata = match self.underlying_dir.metadata(&path) {
-                Ok(metadata) => metadata,
-                Err(e) => {
-                    warn!("metadata of a dirty entry cannot be read {}, skipping", e);
-                    continue;
+        let mut inomap = self.inomap();
+        self.check_rules(|| inomap.prefix(ino), flags & O_ACCMODE != O_RDONLY)?;
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        let mode = entry.perm.mode() as mode_t;
+        let overlay_flags = overlay_flags(flags, self.inner.overlay_key.is_some());
+        let file = match entry.u {
+            EntryKind::GitTree { .. } | EntryKind::DirtyDir { .. } => return Err(Error::Errno(EISDIR)),
+            // Read-only handles open the underlying file on first read.
+            EntryKind::GitBlob { .. } | EntryKind::DirtyFile if flags & O_ACCMODE == O_RDONLY => None,
+            EntryKind::GitBlob { oid, ref smudged } => {
+                let smudged = smudged.clone();
+                self.check_writable(entry)?;
+                self.check_submodule(&inomap, ino)?;
+                self.check_quota(entry.size)?;
+                let path = self.overlay_path(&inomap, ino)?;
+                self.make_overlay_dirs(&path)?;
+                // Linked to the blob store unless it's given its own
+                // owner or flags.
+                let shared = entry.owner.is_none() && entry.flags.is_none();
+                let mut file = self.materialize(&path, oid, smudged.as_deref(), mode, shared)?;
+                // Materialized files are written to as they are made.
+                if overlay_flags != O_RDWR {
+                    file = open_at(&self.inner.underlying_dir, &path, overlay_flags, mode)?;
                 }
-            };
-            let stat = metadata.stat();
-            match dirty_entry.simple_type() {
-                Some(SimpleType::Dir) => {
-                    println!("found dir: {:?}", dirty_entry.file_name());
-                    // a dir is dirty <=> it's on disk but not in git tree
-                    if !entries.contains_key(dirty_entry.file_name()) {
-                        let name = dirty_entry.file_name().to_owned();
-                        entries.insert(
-                            name.clone(),
-                            Entry {
-                                name: name,
-                                parent: ino,
-                                perm: Permissions::from_mode(stat.st_mode as u32),
-                                size: stat.st_size as u64,
-                                atime: SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_atime as u64),
-                                mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_mtime as u64),
-                                ctime: SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_ctime as u64),
-                                crtime: birthtime(stat),
-                                u: EntryKind::DirtyDir { children: None },
-                            },
-                        );
+                // replace git blob entry with a dirty file entry
+                let entry = inomap.get_mut(ino).ok_or(Error::Errno(ENOENT))?;
+                entry.u = EntryKind::DirtyFile;
+                if let Some((uid, gid)) = entry.owner {
+                    match std::os::unix::fs::fchown(&file, Some(uid), Some(gid)) {
+                        Ok(()) => entry.owner = None,
+                        // Keep showing the owner it was given.
+                        Err(e) => warn!(?path, uid, gid, %e, "cannot give the materialized file its owner"),
                     }
                 }
-                Some(SimpleType::File) => {
-                    // a file on disk is always considered dirty
-                    println!("found file: {:?}", dirty_entry.file_name());
-                    let name = dirty_entry.file_name().to_owned();
-                    entries.insert(
-                        name.clone(),
-                        Entry {
-                            name,
-                            parent: ino,
-                            perm: Permissions::from_mode(stat.st_mode as u32),
-                            size: stat.st_size as u64,
-                            atime: SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_atime as u64),
-                            mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_mtime as u64),
-                            ctime: SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_ctime as u64),
-                            crtime: birthtime(stat),
-                            u: EntryKind::DirtyFile {
-                                file: None,
-                                refcnt: 0,
-                            },
-                        },
-                    );
-                }
-                _ => {
-                    warn!("unknown file type, skipping");
-                    continue;
+                if let Some(flags) = entry.flags {
+                    match fchflags(&file, flags) {
+                        Ok(()) => entry.flags = None,
+                        Err(e) => warn!(?path, flags, %e, "cannot give the materialized file its flags"),
+                    }
                 }
+                Some(file)
             }
+            EntryKind::DirtyFile => {
+                let path = self.overlay_path(&inomap, ino)?;
+                debug!(?path, "open dirty file");
+                Some(open_at(&self.inner.underlying_dir, &path, overlay_flags | libc::O_CREAT, mode)?)
+            }
+        };
+        let fh = self.handles().add(Handle {
+            ino,
+            file: file.map(Arc::new),
+            flags: overlay_flags,
+        });
+        Ok(fh)
+    }
+
+    fn do_read(&self, ino: Ino, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        if control::owns(ino) {
+            return self.control_read(fh, offset, size);
+        }
+        self.throttle(Io::Read, size.into());
+        let size = self.inject_short(Site::Read(ino), size as usize)? as u32;
+        if let Some(file) = self.handle_file(ino, fh)? {
+            // Not spliced from the file to /dev/fuse: fuser 0.12 neither
+            // gives out its fd nor replies with anything but a buffer,
+            // which it writev()s as is, and encrypted overlays need the
+            // copy anyway.
+            let mut buf = vec![0; size as usize];
+            let nbytes = self.overlay_read_at(&file, &mut buf, offset)?;
+            buf.truncate(nbytes);
+            stats::add(&self.inner.stats.bytes_read, nbytes as u64);
+            stats::add(&self.inner.stats.bytes_read_overlay, nbytes as u64);
+            return Ok(buf);
         }
 
-        Ok(entries)
+        let (oid, smudged) = match &self.inomap().get(ino).ok_or(Error::Errno(ENOENT))?.u {
+            EntryKind::GitBlob { oid, smudged } => (*oid, smudged.clone()),
+            _ => return Err(Error::Errno(EISDIR)),
+        };
+        if let Some(object) = smudged.as_ref().and_then(|smudged| smudged.object.as_ref()) {
+            let file = self.object_file(object)?;
+            let mut buf = vec![0; read_range(file.metadata()?.len() as usize, offset, size).len()];
+            let nbytes = read_full_at(&file, &mut buf, offset)?;
+            buf.truncate(nbytes);
+            stats::add(&self.inner.stats.bytes_read, nbytes as u64);
+            return Ok(buf);
+        }
+        let content = self.mount_content(oid, smudged.as_deref())?;
+        let range = read_range(content.len(), offset, size);
+        stats::add(&self.inner.stats.bytes_read, range.len() as u64);
+        Ok(content[range].to_vec())
     }
 
-    fn make_attr(ino: Ino, entry: &Entry) -> FileAttr {
-        FileAttr {
-            ino: ino.into(),
-            size: entry.size,
-            blocks: 0,
-            atime: entry.atime,
-            mtime: entry.mtime,
-            ctime: entry.ctime,
-            crtime: entry.crtime,
-            kind: FileType::from(entry),
-            perm: entry.perm.mode() as u16,
-            nlink: if FileType::from(entry) == FileType::Directory {
-                2
-            } else {
-                1
-            },
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
-            rdev: 0,
-            blksize: 0,
-            flags: 0,
+    fn do_write(&self, ino: Ino, fh: u64, offset: u64, data: &[u8]) -> Result<u32, Error> {
+        if control::owns(ino) {
+            return self.control_write(fh, data);
         }
+        let size = {
+            let inomap = self.inomap();
+            // Handles opened for writing may predate the switch to
+            // read-only, or the rules.
+            self.check_rules(|| inomap.prefix(ino), true)?;
+            inomap.get(ino).map_or(0, |entry| entry.size)
+        };
+        self.check_quota((offset + data.len() as u64).saturating_sub(size))?;
+        let opened = || match self.handles().get(fh) {
+            Some(Handle { ino: i, file: Some(file), .. }) if *i == ino => Ok(file.clone()),
+            // Files opened for writing always have an underlying file.
+            _ => Err(Error::Errno(EBADF)),
+        };
+        let mut file = opened()?;
+        if file.metadata()?.nlink() > 1 {
+            self.unshare(&self.inomap(), ino)?;
+            file = opened()?;
+        }
+        self.throttle(Io::Write, data.len() as u64);
+        let data = &data[..self.inject_short(Site::Write(ino), data.len())?];
+        // A short write is reported as such, so that the caller gets
+        // the error (e.g. ENOSPC) when it retries the rest.
+        let nbytes = self.overlay_write_at(&file, data, offset)?;
+        stats::add(&self.inner.stats.bytes_written, nbytes as u64);
+
+        // Maintain size.
+        if let Some(entry) = self.inomap().get_mut(ino) {
+            entry.size = entry.size.max(offset + nbytes as u64);
+        }
+        Ok(nbytes as u32)
     }
-}
 
-#[cfg(target_os = "macos")]
-fn birthtime(stat: &stat) -> SystemTime {
-    SystemTime::UNIX_EPOCH + Duration::from_secs(stat.st_birthtime as u64)
-}
+    fn do_flush(&self, ino: Ino, fh: u64) -> Result<(), Error> {
+        if control::owns(ino) {
+            return self.control_flush(fh);
+        }
+        // A flush() will be called on read-only files as well.
+        if let Some(Handle { ino: i, file: Some(file), .. }) = self.handles().get(fh) {
+            if *i == ino {
+                (&**file).flush()?;
+            }
+        }
+        Ok(())
+    }
 
-#[cfg(not(target_os = "macos"))]
-fn birthtime(_: &stat) -> SystemTime {
+    fn do_getxattr(&self, ino: Ino, name: &OsStr) -> Result<Vec<u8>, Error> {
+        // Asked of files on each write, for capabilities to drop.
+        if xattr::is_security(name) {
+            return self.security_xattr(ino, name);
+        }
+        if xattr::is_system(name) {
+            return Err(Error::Errno(ENOATTR));
+        }
+        self.xattrs(ino)?
+            .into_iter()
+            .find(|(xattr, _)| OsStr::new(xattr) == name)
+            .map(|(_, value)| value)
+            .ok_or(Error::Errno(ENOATTR))
+    }
+
+    /// The names of the xattrs, each followed by NUL.
+    fn do_listxattr(&self, ino: Ino) -> Result<Vec<u8>, Error> {
+        let mut names = vec![];
+        for (name, _) in self.xattrs(ino)? {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        names.extend(self.security_xattr_names(ino)?);
+        Ok(names)
+    }
+
+    fn do_create(&self, parent: Ino, name: &OsStr, mode: u32) -> Result<(FileAttr, u64), Error> {
+        self.check_quota(0)?;
+        let mut inomap = self.inomap();
+        let path = self.new_child_path(&inomap, parent, name)?;
+        // Opened for reading too, since writes to encrypted files read
+        // back the blocks they only partly cover.
+        let file = self.inner.underlying_dir.update_file(&path, mode as mode_t)?;
+        file.set_len(0)?;
+        let fentry = Entry {
+            name: name.to_owned(),
+            parent,
+            ctime: SystemTime::now(),
+            mtime: SystemTime::now(),
+            atime: SystemTime::now(),
+            crtime: SystemTime::now(),
+            perm: Permissions::from_mode(mode),
+            size: 0,
+            shadowed: false,
+            subdirs: None,
+            owner: None,
+            flags: None,
+            stamp: None,
+            u: EntryKind::DirtyFile,
+        };
+        let ino = inomap.add(fentry);
+        if let Some(parent_entry) = inomap.get_mut(parent) {
+            parent_entry.add_child(name.to_owned(), ino);
+        }
+        let attr = self.attr(&mut inomap, ino)?;
+        let fh = self.handles().add(Handle {
+            ino,
+            file: Some(Arc::new(file)),
+            flags: O_RDWR,
+        });
+        Ok((attr, fh))
+    }
+
+    /// List `name` in `parent` if it's a dir, so that whether it's
+    /// empty is known.
+    fn list_child(&self, parent: Ino, name: &OsStr) -> Result<(), Error> {
+        let dir = {
+            let inomap = self.inomap();
+            let child = inomap.get(parent).and_then(|entry| entry.get_child(name));
+            child.filter(|&child| inomap.get(child).map(FileType::from) == Some(FileType::Directory))
+        };
+        match dir {
+            Some(dir) => self.do_opendir(dir),
+            None => Ok(()),
+        }
+    }
+
+    fn do_mkdir(&self, parent: Ino, name: &OsStr, mode: u32) -> Result<FileAttr, Error> {
+        self.check_quota(0)?;
+        let mut inomap = self.inomap();
+        let path = self.new_child_path(&inomap, parent, name)?;
+        self.inner.underlying_dir.create_dir(&path, mode as mode_t)?;
+        let dentry = Entry {
+            name: name.to_owned(),
+            parent,
+            ctime: SystemTime::now(),
+            mtime: SystemTime::now(),
+            atime: SystemTime::now(),
+            crtime: SystemTime::now(),
+            perm: Permissions::from_mode(mode),
+            size: 0,
+            shadowed: false,
+            subdirs: None,
+            owner: None,
+            flags: None,
+            stamp: None,
+            u: EntryKind::DirtyDir {
+                children: Some(HashMap::new()),
+            },
+        };
+        let ino = inomap.add(dentry);
+        if let Some(parent_entry) = inomap.get_mut(parent) {
+            parent_entry.add_child(name.to_owned(), ino);
+        }
+        self.attr(&mut inomap, ino)
+    }
+
+    /// Remove a file or an empty directory (ENOTEMPTY otherwise).
+    /// Dirty ones are removed from the underlying dir first; the entry
+    /// is only forgotten once that succeeded.
+    fn do_remove(&self, parent: Ino, name: &OsStr) -> Result<(), Error> {
+        self.check_control(parent, name)?;
+        self.list_child(parent, name)?;
+        let mut inomap = self.inomap();
+        self.check_rules(|| Some(inomap.prefix(parent)?.join(name)), true)?;
+        self.check_submodule(&inomap, parent)?;
+        let parent_entry = inomap.get(parent).ok_or(Error::Errno(ENOENT))?;
+        let child = parent_entry.get_child(name).ok_or(Error::Errno(ENOENT))?;
+        let entry = inomap.get(child).ok_or(Error::Errno(ENOENT))?;
+        if entry.has_children() {
+            return Err(Error::Errno(ENOTEMPTY));
+        }
+        let path = self.overlay_path(&inomap, child)?;
+        match entry.u {
+            // Open handles keep their own descriptors of the file.
+            EntryKind::DirtyFile => self.inner.underlying_dir.remove_file(&path)?,
+            EntryKind::DirtyDir { .. } => self.inner.underlying_dir.remove_dir(&path)?,
+            EntryKind::GitBlob { .. } => {
+                // TODO: Perhaps we should record such information, so
+                // that when the repo is mounted here, we can restore
+                // the unstaged deletion.
+            }
+            EntryKind::GitTree { .. } => optional(self.inner.underlying_dir.remove_dir(&path))?,
+        }
+        inomap.invalidate(child);
+        inomap.remove(child);
+        if let Some(parent_entry) = inomap.get_mut(parent) {
+            parent_entry.remove_child(name);
+        }
+        Ok(())
+    }
+
+    fn do_rename(&self, oldp: Ino, name: &OsStr, newp: Ino, newname: &OsStr) -> Result<(), Error> {
+        self.check_control(oldp, name)?;
+        self.check_control(newp, newname)?;
+        self.list_child(newp, newname)?;
+        let mut inomap = self.inomap();
+        self.check_rules(|| Some(inomap.prefix(oldp)?.join(name)), true)?;
+        self.check_rules(|| Some(inomap.prefix(newp)?.join(newname)), true)?;
+        self.check_submodule(&inomap, oldp)?;
+        self.check_submodule(&inomap, newp)?;
+        let oldpent = inomap.get(oldp).ok_or(Error::Errno(ENOENT))?;
+        let c = oldpent.get_child(name).ok_or(Error::Errno(ENOENT))?;
+        let cent = inomap.get(c).ok_or(Error::Errno(ENOENT))?;
+        let target = inomap.get(newp).ok_or(Error::Errno(ENOENT))?.get_child(newname);
+        if target == Some(c) {
+            return Ok(());
+        }
+        let is_dir = FileType::from(cent) == FileType::Directory;
+        // Nor into itself.
+        let mut dir = newp;
+        while !dir.is_root() {
+            if dir == c {
+                return Err(Error::Errno(EINVAL));
+            }
+            dir = inomap.get(dir).ok_or(Error::Errno(ENOENT))?.parent;
+        }
+        // What is renamed over goes away, if it can.
+        let target = match target.and_then(|target| Some((target, inomap.get(target)?))) {
+            Some((_, tent)) if is_dir && FileType::from(tent) != FileType::Directory => return Err(Error::Errno(ENOTDIR)),
+            Some((_, tent)) if !is_dir && FileType::from(tent) == FileType::Directory => return Err(Error::Errno(EISDIR)),
+            Some((_, tent)) if tent.has_children() => return Err(Error::Errno(ENOTEMPTY)),
+            Some((target, tent)) => Some((target, tent.is_clean(), FileType::from(tent) == FileType::Directory)),
+            None => None,
+        };
+
+        if !names::is_valid(newname) {
+            return Err(Error::Errno(EINVAL));
+        }
+        if newname.len() > self.inner.name_max {
+            return Err(Error::Errno(ENAMETOOLONG));
+        }
+        if self.collides(&inomap, newp, newname) {
+            return Err(Error::Errno(EEXIST));
+        }
+
+        // Move dirty files/directories physically, and the dirs of
+        // clean ones if anything was put there.  What they are renamed
+        // over is replaced, or would show up again if left.
+        let dirty = !cent.is_clean();
+        let oldpath = self.overlay_path(&inomap, c)?;
+        let mut newpath = self.overlay_path(&inomap, newp)?;
+        newpath.push(self.names().overlay_name(newname));
+        self.check_path(&newpath)?;
+        if dirty || target.is_some_and(|(_, clean, _)| !clean) {
+            self.check_writable(inomap.get(newp).ok_or(Error::Errno(ENOENT))?)?;
+        }
+        match target {
+            Some((_, false, false)) if !dirty => self.inner.underlying_dir.remove_file(&newpath)?,
+            Some((_, _, true)) if !dirty => optional(self.inner.underlying_dir.remove_dir(&newpath))?,
+            _ => (),
+        }
+        match cent.u {
+            EntryKind::DirtyFile | EntryKind::DirtyDir { .. } => {
+                self.make_overlay_dirs(&newpath)?;
+                debug!("move {:?} to {:?}", oldpath, newpath);
+                self.inner.underlying_dir.local_rename(&oldpath, &newpath)?;
+            }
+            EntryKind::GitTree { .. } if self.inner.underlying_dir.metadata(&oldpath).is_ok() => {
+                self.make_overlay_dirs(&newpath)?;
+                debug!("move {:?} to {:?}", oldpath, newpath);
+                self.inner.underlying_dir.local_rename(&oldpath, &newpath)?;
+            }
+            _ => (),
+        }
+        if let Some((target, _, _)) = target {
+            inomap.invalidate(target);
+            inomap.remove(target);
+        }
+
+        // Move entry from oldp to newp. Keep ino intact.
+        if let Some(cent) = inomap.get_mut(c) {
+            cent.name = newname.to_os_string();
+            cent.parent = newp;
+        }
+        if let Some(oldpent) = inomap.get_mut(oldp) {
+            oldpent.remove_child(name);
+        }
+        if let Some(newpent) = inomap.get_mut(newp) {
+            newpent.add_child(newname.to_os_string(), c);
+        }
+        Ok(())
+    }
+}
+
+// private interfaces
+impl GitFS {
+    // A panic in one operation poisons the locks it held.  The state
+    // behind them is still usable, since a panic never leaves an entry
+    // half-updated, so carry on rather than failing every operation
+    // from then on.
+
+    fn head(&self) -> MutexGuard<'_, Head> {
+        self.inner.head.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn inomap(&self) -> MutexGuard<'_, InoMap> {
+        self.inner.inomap.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn handles(&self) -> MutexGuard<'_, HandleTable> {
+        self.inner.handles.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn repo(&self) -> MutexGuard<'_, Repository> {
+        self.inner.repo.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn blob_cache(&self) -> MutexGuard<'_, BlobCache> {
+        self.inner.blob_cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn options_read(&self) -> RwLockReadGuard<'_, Options> {
+        self.inner.options.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn errno(&self, err: &Error) -> c_int {
+        self.inner.errno_map.errno(err)
+    }
+
+    fn path_of(&self, ino: Ino) -> PathBuf {
+        self.inomap().prefix(ino).unwrap_or_default()
+    }
+
+    /// Resolve the mounted ref and add the root entry, unless the
+    /// root is there already, as it is when the file system is both
+    /// mounted and served over 9P.
+    fn mount_root(&self) -> Result<(), Error> {
+        let mut head = self.head();
+        if self.inomap().get(Ino::ROOT).is_some() {
+            return Ok(());
+        }
+        let (commit_id, tree_id) = self.resolve(&head.refspec)?;
+        let root = self.root_entry(tree_id)?;
+        self.inomap().add(root);
+        head.commit = Some(commit_id);
+        self.forget_submodules(commit_id);
+        info!(refspec = %head.refspec, commit = %commit_id, "gitfs is mounted");
+        self.pin(tree_id);
+        if let Some(store) = self.options_read().blob_store.clone() {
+            store::collect_in_background(store);
+        }
+        Ok(())
+    }
+
+    /// Switch to another ref with the head locked.  See `checkout`.
+    fn switch_head(&self, head: &mut Head, refspec: &str) -> Result<(), Error> {
+        let (commit_id, tree_id) = self.resolve(refspec)?;
+        let mut inomap = self.inomap();
+        if self.handles().iter().any(|(_, handle)| handle.file.is_some()) {
+            return Err(Error::Errno(EBUSY));
+        }
+
+        {
+            let repo = self.repo();
+            let tree = repo.find_tree(tree_id)?;
+            for (ino, entry) in inomap.iter() {
+                let is_dir = match entry.u {
+                    EntryKind::DirtyFile => false,
+                    EntryKind::DirtyDir { .. } => true,
+                    _ => continue,
+                };
+                let path = inomap.prefix(ino).ok_or(Error::Errno(EIO))?;
+                if let Ok(tree_entry) = tree.get_path(&path) {
+                    if (tree_entry.kind() == Some(ObjectType::Tree)) != is_dir {
+                        warn!(?path, "checkout would shadow a dirty entry");
+                        return Err(Error::Errno(EEXIST));
+                    }
+                }
+            }
+        }
+
+        // The kernel isn't told, and sees the new tree once what it
+        // has cached expires (see `Options::entry_ttl`): fuser 0.12 has
+        // no way of sending it FUSE_NOTIFY_INVAL_ENTRY and the like,
+        // nor gives out /dev/fuse to send them on.
+        inomap.invalidate(Ino::ROOT);
+        match inomap.get_mut(Ino::ROOT).map(|root| &mut root.u) {
+            Some(EntryKind::GitTree { oid, .. }) => *oid = tree_id,
+            _ => return Err(Error::Errno(EIO)),
+        }
+        head.refspec = refspec.to_owned();
+        head.commit = Some(commit_id);
+        head.tip = None;
+        self.forget_submodules(commit_id);
+        info!(refspec, commit = %commit_id, "checked out");
+        drop(inomap);
+        self.pin(tree_id);
+        Ok(())
+    }
+
+    fn refresh_if_requested(&self) {
+        if !self.inner.refresh_requested.take() {
+            return;
+        }
+        match self.refresh() {
+            Ok(changed) => debug!(changed, "refreshed"),
+            Err(e) => warn!(%e, "refresh failed"),
+        }
+    }
+
+    /// Resolve a ref or commit to the ids of its commit and tree.
+    /// The commit `refspec` points to, and its tree that is mounted.
+    fn resolve(&self, refspec: &str) -> Result<(Oid, Oid), Error> {
+        let repo = self.repo();
+        let commit = repo.revparse_single(refspec)?.peel_to_commit()?;
+        let tree = self.mounted_tree(&repo, &commit)?.id();
+        Ok((commit.id(), tree))
+    }
+
+    /// The tree of `commit` that is mounted: the whole of it, or its
+    /// subdir (ENOENT if it has none, ENOTDIR if it isn't a dir).
+    fn mounted_tree<'repo>(&self, repo: &'repo Repository, commit: &Commit) -> Result<Tree<'repo>, Error> {
+        let tree = repo.find_tree(commit.tree_id())?;
+        if self.inner.subdir.as_os_str().is_empty() {
+            return Ok(tree);
+        }
+        let entry = tree.get_path(&self.inner.subdir).map_err(|_| Error::Errno(ENOENT))?;
+        match entry.kind() {
+            Some(ObjectType::Tree) => Ok(repo.find_tree(entry.id())?),
+            _ => Err(Error::Errno(ENOTDIR)),
+        }
+    }
+
+    fn attr_ttl(&self) -> Duration {
+        self.options_read().attr_ttl
+    }
+
+    fn entry_ttl(&self) -> Duration {
+        self.options_read().entry_ttl
+    }
+
+    /// Get the content of a blob, preferably from the blob cache.
+    fn blob_content(&self, oid: Oid) -> Result<Arc<[u8]>, GitError> {
+        self.inject_missing(oid)?;
+        let capacity = self.options_read().blob_cache_size;
+        {
+            let mut cache = self.blob_cache();
+            cache.set_capacity(capacity);
+            if let Some(content) = cache.get(oid) {
+                stats::add(&self.inner.stats.blob_cache_hits, 1);
+                return Ok(content);
+            }
+        }
+        stats::add(&self.inner.stats.blob_cache_misses, 1);
+        let content: Arc<[u8]> = self.repo().find_blob(oid)?.content().into();
+        self.blob_cache().insert(oid, content.clone());
+        Ok(content)
+    }
+
+    /// Return the underlying file of a handle, opening it if the entry
+    /// has become dirty since the handle was opened.  Return None for
+    /// clean files.
+    fn handle_file(&self, ino: Ino, fh: u64) -> Result<Option<Arc<File>>, Error> {
+        let flags = match self.handles().get(fh) {
+            Some(Handle { ino: i, file: Some(file), .. }) if *i == ino => return Ok(Some(file.clone())),
+            Some(handle) => handle.flags,
+            None => O_RDONLY,
+        };
+
+        let inomap = self.inomap();
+        match inomap.get(ino).ok_or(Error::Errno(ENOENT))?.u {
+            EntryKind::DirtyFile => (),
+            _ => return Ok(None),
+        }
+        let path = self.overlay_path(&inomap, ino)?;
+        let file = Arc::new(open_at(&self.inner.underlying_dir, &path, flags, 0)?);
+        if let Some(handle) = self.handles().get_mut(fh) {
+            if handle.ino == ino {
+                handle.file = Some(file.clone());
+            }
+        }
+        Ok(Some(file))
+    }
+
+    /// The path of an entry in the underlying dir.
+    fn overlay_path(&self, inomap: &InoMap, ino: Ino) -> Result<PathBuf, Error> {
+        let path = inomap.prefix(ino).ok_or(Error::Errno(ENOENT))?;
+        let path = self.names().overlay_path(&path);
+        self.check_path(&path)?;
+        self.inject_errno(Site::Overlay(&path))?;
+        Ok(path)
+    }
+
+    /// Make sure that a path stays inside the underlying dir, and that
+    /// the underlying dir can hold it, rather than finding out halfway
+    /// through an operation (ENAMETOOLONG).
+    fn check_path(&self, path: &Path) -> Result<(), Error> {
+        if !names::is_contained(path) {
+            error!(?path, "refusing a path outside of the underlying dir");
+            return Err(Error::Errno(EINVAL));
+        }
+        let too_long = path.as_os_str().len() >= libc::PATH_MAX as usize
+            || path.iter().any(|name| name.len() > self.inner.name_max);
+        if too_long {
+            debug!(?path, "name too long for the underlying dir");
+            return Err(Error::Errno(ENAMETOOLONG));
+        }
+        Ok(())
+    }
+
+    /// Make the dirs leading to `path` in the underlying dir, which
+    /// clean dirs don't have until something dirty is put there.
+    fn make_overlay_dirs(&self, path: &Path) -> Result<(), Error> {
+        let mut dir = PathBuf::new();
+        for name in path.parent().into_iter().flat_map(Path::iter) {
+            dir.push(name);
+            match self.inner.underlying_dir.create_dir(&dir, 0o755) {
+                Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e.into()),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_overlay_watched(&self, watched: bool) {
+        self.inner.overlay_watched.store(watched, Ordering::SeqCst);
+    }
+
+    /// Forget the stamp of the dir at `path` in the underlying dir (or
+    /// of all dirs), so that it's listed again on next access.
+    pub(crate) fn overlay_changed(&self, path: Option<&Path>) {
+        let mut inomap = self.inomap();
+        let path = match path {
+            Some(path) => path,
+            None => {
+                let dirs: Vec<Ino> = inomap.iter().map(|(ino, _)| ino).collect();
+                for ino in dirs {
+                    if let Some(entry) = inomap.get_mut(ino) {
+                        entry.stamp = None;
+                    }
+                }
+                return;
+            }
+        };
+        let names = self.names();
+        let mut ino = Ino::ROOT;
+        for name in path.iter() {
+            let name = names.mount_name(name);
+            let entry = match inomap.get(ino) {
+                Some(entry) => entry,
+                None => return,
+            };
+            ino = match entry.get_child(&name) {
+                Some(child) => child,
+                None => match &entry.u {
+                    // The underlying dir may return the name in
+                    // another normalization or case.
+                    EntryKind::GitTree { children: Some(children), .. } | EntryKind::DirtyDir { children: Some(children) }
+                        if names.normalizes() =>
+                    {
+                        let key = names.merge_key(&name);
+                        match children.iter().find(|(child, _)| names.merge_key(child) == key) {
+                            Some((_, &child)) => child,
+                            None => return,
+                        }
+                    }
+                    // Not known yet, so nothing to forget.
+                    _ => return,
+                },
+            };
+        }
+        if let Some(entry) = inomap.get_mut(ino) {
+            trace!(?path, "underlying dir changed");
+            entry.stamp = None;
+        }
+    }
+
+    /// Stamp the entry at `path` in the underlying dir, if it's there.
+    fn overlay_stamp(&self, path: &Path) -> Option<Stamp> {
+        let path = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+        let metadata = self.inner.underlying_dir.metadata(path).ok()?;
+        Some(Stamp::from(metadata.stat()))
+    }
+
+    fn names(&self) -> NameMap {
+        NameMap::new(&self.options_read())
+    }
+
+    /// Return the path in the underlying dir of a new entry, after
+    /// making sure that it can be created, so that a failure leaves
+    /// nothing behind.
+    fn new_child_path(&self, inomap: &InoMap, parent: Ino, name: &OsStr) -> Result<PathBuf, Error> {
+        self.check_control(parent, name)?;
+        self.check_rules(|| Some(inomap.prefix(parent)?.join(name)), true)?;
+        self.check_submodule(inomap, parent)?;
+        let parent_entry = inomap.get(parent).ok_or(Error::Errno(ENOENT))?;
+        if FileType::from(parent_entry) != FileType::Directory {
+            return Err(Error::Errno(ENOTDIR));
+        }
+        self.check_writable(parent_entry)?;
+        if parent_entry.get_child(name).is_some() || self.collides(inomap, parent, name) {
+            return Err(Error::Errno(EEXIST));
+        }
+        if !names::is_valid(name) {
+            return Err(Error::Errno(EINVAL));
+        }
+        if name.len() > self.inner.name_max {
+            return Err(Error::Errno(ENAMETOOLONG));
+        }
+        let mut path = self.overlay_path(inomap, parent)?;
+        path.push(self.names().overlay_name(name));
+        self.check_path(&path)?;
+        self.make_overlay_dirs(&path)?;
+        Ok(path)
+    }
+
+    fn xattrs(&self, ino: Ino) -> Result<Vec<(&'static str, Vec<u8>)>, Error> {
+        let mut xattrs = self.commit_xattrs(ino)?;
+        xattrs.extend(self.last_commit_xattrs(ino)?);
+        if self.is_opaque(ino)? {
+            xattrs.push(("trusted.overlay.opaque", b"y".to_vec()));
+            xattrs.push(("user.overlay.opaque", b"y".to_vec()));
+        }
+        if self.options_read().dos_attributes {
+            xattrs.push(("user.DOSATTRIB", self.dos_attributes(ino)?));
+        }
+        Ok(xattrs)
+    }
+
+    /// The DOS attributes of an entry (see `Options::dos_attributes`),
+    /// in the oldest format Samba reads: in hex, terminated by NUL.
+    fn dos_attributes(&self, ino: Ino) -> Result<Vec<u8>, Error> {
+        let inomap = self.inomap();
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        let mut attributes = match entry.u {
+            EntryKind::GitTree { .. } | EntryKind::DirtyDir { .. } => DOS_DIRECTORY,
+            EntryKind::DirtyFile => DOS_ARCHIVE,
+            EntryKind::GitBlob { .. } => 0,
+        };
+        if entry.name.as_bytes().starts_with(b".") {
+            attributes |= DOS_HIDDEN;
+        }
+        let read_only = self.check_writable(entry).is_err()
+            || self.check_rules(|| inomap.prefix(ino), true).is_err();
+        if read_only && attributes & DOS_DIRECTORY == 0 {
+            attributes |= DOS_READONLY;
+        }
+        Ok(format!("0x{:x}\0", attributes).into_bytes())
+    }
+
+    /// Whether markers of image layers are shown as overlayfs expects
+    /// them (see `Options::whiteouts`).
+    fn whiteouts(&self) -> bool {
+        let options = self.options_read();
+        options.whiteouts && options.read_only
+    }
+
+    /// Whether `ino` is a dir that hides the dirs of the layers below
+    /// it, as it has a `.wh..wh..opq` marker.
+    fn is_opaque(&self, ino: Ino) -> Result<bool, Error> {
+        if !self.whiteouts() {
+            return Ok(false);
+        }
+        let oid = match self.inomap().get(ino).map(|entry| &entry.u) {
+            Some(EntryKind::GitTree { oid, .. }) => *oid,
+            _ => return Ok(false),
+        };
+        let repo = self.repo();
+        let tree = repo.find_tree(oid)?;
+        Ok(tree.get_name_bytes(archive::OPAQUE).is_some_and(|entry| entry.kind() == Some(ObjectType::Blob)))
+    }
+
+    /// The xattrs telling which commit is mounted, on the root.  Other
+    /// entries have none.
+    fn commit_xattrs(&self, ino: Ino) -> Result<Vec<(&'static str, Vec<u8>)>, Error> {
+        let commit = match self.head().commit {
+            Some(commit) if ino.is_root() => commit,
+            _ => return Ok(vec![]),
+        };
+        let repo = self.repo();
+        let commit = repo.find_commit(commit)?;
+        let xattrs = vec![
+            ("user.gitfs.commit", commit.id().to_string().into_bytes()),
+            ("user.gitfs.author", signature(commit.author())),
+            ("user.gitfs.committer", signature(commit.committer())),
+            ("user.gitfs.date", control::format_iso_date(commit.committer().when()).into_bytes()),
+        ];
+        Ok(xattrs)
+    }
+
+    /// Whether `name` collides with the name of another child of
+    /// `parent` in the underlying dir.
+    fn collides(&self, inomap: &InoMap, parent: Ino, name: &OsStr) -> bool {
+        let names = self.names();
+        if !names.folds_case() {
+            return false;
+        }
+        let key = names.merge_key(name);
+        let children = match inomap.get(parent).map(|entry| &entry.u) {
+            Some(EntryKind::GitTree { children: Some(children), .. }) => children,
+            Some(EntryKind::DirtyDir { children: Some(children) }) => children,
+            _ => return false,
+        };
+        children.keys().any(|child| child != name && names.merge_key(child) == key)
+    }
+
+    /// Check out a git blob into the underlying dir, converted if it
+    /// is in the mount.
+    fn materialize(&self, path: &Path, oid: Oid, smudged: Option<&Smudged>, mode: mode_t, shared: bool) -> Result<File, Error> {
+        let mut object = match smudged.and_then(|smudged| smudged.object.as_ref()) {
+            Some(object) => Some(self.object_file(object)?),
+            None => None,
+        };
+        let content = match smudged {
+            // Copied from the object instead.
+            _ if object.is_some() => Arc::from(&[][..]),
+            Some(_) => self.mount_content(oid, smudged)?,
+            // Not cached, as the file is read from the underlying dir
+            // from now on.
+            None => {
+                self.inject_missing(oid)?;
+                self.repo().find_blob(oid)?.content().into()
+            }
+        };
+        let blob_store = self.options_read().blob_store.clone();
+        if let Some(blob_store) = blob_store.filter(|_| shared && smudged.is_none() && self.inner.overlay_key.is_none()) {
+            match store::link(&blob_store, oid, mode, &content, &self.inner.underlying_dir, path) {
+                Ok(()) => return Ok(open_at(&self.inner.underlying_dir, path, O_RDWR, 0)?),
+                Err(e) => debug!(?path, %e, "cannot link from the blob store, copying"),
+            }
+        }
+        let mut f = self.inner.underlying_dir.update_file(path, mode)?;
+        // The mode given to open() is subject to the umask of gitfs,
+        // which may drop the executable bit of scripts.
+        let perm = Permissions::from_mode(mode & 0o777);
+        let copied = f.set_permissions(perm).and_then(|_| match &mut object {
+            Some(object) if self.inner.overlay_key.is_some() => {
+                let mut content = vec![];
+                object.read_to_end(&mut content)?;
+                self.overlay_write_at(&f, &content, 0).map(drop)
+            }
+            // io::copy clones the file where the file system can.
+            Some(object) => io::copy(object, &mut f).map(drop),
+            None if self.inner.overlay_key.is_some() => self.overlay_write_at(&f, &content, 0).map(drop),
+            None => f.write_all(&content),
+        });
+        if let Err(e) = copied.and_then(|_| f.flush()) {
+            // Don't leave a truncated copy behind, which would show up
+            // as a dirty file next time.
+            warn!(?path, %e, "cannot materialize file");
+            if let Err(e) = self.inner.underlying_dir.remove_file(path) {
+                error!(?path, %e, "cannot remove partially materialized file");
+            }
+            return Err(e.into());
+        }
+        Ok(f)
+    }
+
+    /// Create the root entry, with the times of the underlying dir.
+    fn root_entry(&self, tree_id: Oid) -> io::Result<Entry> {
+        let metadata = self.inner.underlying_dir.self_metadata()?;
+        let stat = metadata.stat();
+        let atime = system_time(stat.st_atime, stat.st_atime_nsec);
+        let mtime = system_time(stat.st_mtime, stat.st_mtime_nsec);
+        let ctime = system_time(stat.st_ctime, stat.st_ctime_nsec);
+        let crtime = birthtime(stat);
+        Ok(Entry {
+            name: "".to_string().into(),
+            parent: Ino::ROOT,
+            size: 0,
+            atime,
+            ctime,
+            mtime,
+            crtime,
+            perm: metadata.permissions(),
+            shadowed: false,
+            subdirs: None,
+            owner: None,
+            flags: None,
+            stamp: None,
+            u: EntryKind::GitTree {
+                oid: tree_id,
+                children: None,
+                commit: None,
+            },
+        })
+    }
+
+
+    /// `path` is the path of the tree in the mount.
+    fn walk_tree(&self, ino: Ino, path: &Path, tree_id: Oid) -> Result<HashMap<OsString, Entry>, GitError> {
+        let times = match self.options_read().mtime {
+            MtimePolicy::Epoch => None,
+            MtimePolicy::Commit => self.commit_times()?,
+        };
+        let time_of = |name: &OsStr| match &times {
+            Some(times) => times.get(&path.join(name)).copied().unwrap_or(SystemTime::UNIX_EPOCH),
+            None => SystemTime::UNIX_EPOCH,
+        };
+        let root_tree = match self.inomap().get(Ino::ROOT).map(|root| &root.u) {
+            Some(EntryKind::GitTree { oid, .. }) if self.options_read().smudge => Some(*oid),
+            _ => None,
+        };
+        let whiteouts = self.whiteouts();
+        let (lfs, annex) = {
+            let options = self.options_read();
+            (options.lfs, options.annex)
+        };
+        self.inject_missing(tree_id)?;
+        let repo = self.repo();
+        let tree = repo.find_tree(tree_id)?;
+        let attributes = match root_tree {
+            Some(root_tree) => Some(Attributes::load(&repo, &repo.find_tree(root_tree)?, path)),
+            None => None,
+        };
+        let mut entries = HashMap::new();
+
+        for tree_entry in tree.iter() {
+            let name = OsString::from(OsStr::from_bytes(tree_entry.name_bytes()));
+            if !names::is_valid(&name) {
+                warn!(?name, tree = %tree_id, "invalid name in tree, skipping");
+                continue;
+            }
+            if whiteouts && tree_entry.kind() == Some(ObjectType::Blob) {
+                match name.as_bytes().strip_prefix(archive::WHITEOUT) {
+                    // Shown as an xattr of the dir instead.
+                    _ if name.as_bytes() == archive::OPAQUE => continue,
+                    Some(hidden) if !hidden.is_empty() => {
+                        let name = OsStr::from_bytes(hidden).to_owned();
+                        let entry = Entry {
+                            name: name.clone(),
+                            parent: ino,
+                            size: 0,
+                            perm: Permissions::from_mode(MODE_CHAR),
+                            ctime: time_of(&name),
+                            atime: SystemTime::UNIX_EPOCH,
+                            mtime: time_of(&name),
+                            crtime: SystemTime::UNIX_EPOCH,
+                            shadowed: false,
+                            subdirs: None,
+                            owner: None,
+                            flags: None,
+                            stamp: None,
+                            u: EntryKind::GitBlob {
+                                oid: tree_entry.id(),
+                                smudged: None,
+                            },
+                        };
+                        // What is in the layer itself isn't whited out.
+                        entries.entry(name).or_insert(entry);
+                        continue;
+                    }
+                    _ => (),
+                }
+            }
+            let perm = match tree_entry.filemode() as u32 {
+                mode if mode & MODE_TYPE != MODE_SYMLINK => Permissions::from_mode(mode),
+                _ if self.inner.symlinks => Permissions::from_mode(MODE_SYMLINK | 0o777),
+                // A checkout would write a file holding the target.
+                _ => Permissions::from_mode(MODE_FILE | 0o644),
+            };
+            let entry = match tree_entry.kind() {
+                Some(ObjectType::Blob) => {
+                    // A missing or broken blob must not make the whole
+                    // dir unlistable; reading it fails instead.
+                    let blob = repo.find_blob(tree_entry.id());
+                    let symlink = tree_entry.filemode() as u32 & MODE_TYPE == MODE_SYMLINK;
+                    let object = match &blob {
+                        Ok(blob) if lfs && !symlink && blob.size() <= lfs::POINTER_MAX => {
+                            lfs::Pointer::parse(blob.content()).map(Object::Lfs)
+                        }
+                        _ => None,
+                    };
+                    let object = match &blob {
+                        Ok(blob) if annex && object.is_none() && (symlink || blob.size() <= annex::POINTER_MAX) => {
+                            annex::object_path(&repo, blob.content(), symlink).map(Object::Annex)
+                        }
+                        _ => object,
+                    };
+                    // Annexed symlinks are shown as regular files.
+                    let perm = match object {
+                        Some(Object::Annex(_)) if symlink => Permissions::from_mode(MODE_FILE | 0o644),
+                        _ => perm,
+                    };
+                    let (size, smudged) = match (&blob, &attributes, object) {
+                        (Ok(blob), _, Some(object)) => {
+                            let size = match &object {
+                                Object::Lfs(pointer) => pointer.size,
+                                Object::Annex(path) => std::fs::metadata(path).map_or(0, |metadata| metadata.len()),
+                            };
+                            let smudged = Smudged {
+                                filter: Default::default(),
+                                oid: blob.id(),
+                                object: Some(object),
+                            };
+                            (size, Some(Arc::new(smudged)))
+                        }
+                        // Symlinks are never converted.
+                        (Ok(blob), Some(attributes), None) if !symlink => {
+                            self.smudge_blob(attributes, &path.join(&name), blob.id(), blob.content())?
+                        }
+                        (Ok(blob), _, None) => (blob.size() as u64, None),
+                        (Err(e), _, _) => {
+                            warn!(oid = %tree_entry.id(), %e, "cannot read blob");
+                            (0, None)
+                        }
+                    };
+                    Entry {
+                        name: name.clone(),
+                        parent: ino,
+                        size,
+                        perm,
+                        ctime: time_of(&name),
+                        atime: SystemTime::UNIX_EPOCH,
+                        mtime: time_of(&name),
+                        crtime: SystemTime::UNIX_EPOCH,
+                        shadowed: false,
+                        subdirs: None,
+                        owner: None,
+                        flags: None,
+                        stamp: None,
+                        u: EntryKind::GitBlob {
+                            oid: tree_entry.id(),
+                            smudged,
+                        },
+                    }
+                }
+                Some(kind @ (ObjectType::Tree | ObjectType::Commit)) => {
+                    let (oid, commit) = match kind {
+                        ObjectType::Tree => (tree_entry.id(), None),
+                        // A submodule, seen as its tree if it's there.
+                        _ => match self.submodule_tree(&repo, &path.join(&name), tree_entry.id()) {
+                            Some(tree) => (tree, Some(tree_entry.id())),
+                            None => continue,
+                        },
+                    };
+                    Entry {
+                        parent: ino,
+                        name: name.clone(),
+                        perm: Permissions::from_mode(0o755), // tree doesn't have a proper mode
+                        size: 0,
+                        ctime: time_of(&name),
+                        atime: SystemTime::UNIX_EPOCH,
+                        mtime: time_of(&name),
+                        crtime: SystemTime::UNIX_EPOCH,
+                        shadowed: false,
+                        subdirs: None,
+                        owner: None,
+                        flags: None,
+                        stamp: None,
+                        u: EntryKind::GitTree { oid, children: None, commit },
+                    }
+                }
+                _ => {
+                    warn!(
+                        "{} ({}) is not supported, skipping",
+                        tree_entry.id(),
+                        String::from_utf8_lossy(tree_entry.name_bytes())
+                    );
+                    continue;
+                }
+            };
+            entries.insert(name, entry);
+        }
+        Ok(entries)
+    }
+
+    /// When each path was last changed, in the history of the mounted
+    /// commit.  Computed once per commit.
+    fn commit_times(&self) -> Result<Option<Arc<HashMap<PathBuf, SystemTime>>>, GitError> {
+        let commit = match self.head().commit {
+            Some(commit) => commit,
+            None => return Ok(None),
+        };
+        let mut cached = self.inner.commit_times.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cached) = &*cached {
+            if cached.commit == commit {
+                return Ok(Some(cached.times.clone()));
+            }
+        }
+        let times = Arc::new(commit_times(&self.repo(), commit, &self.inner.subdir)?);
+        *cached = Some(CommitTimes {
+            commit,
+            times: times.clone(),
+        });
+        Ok(Some(times))
+    }
+
+    /// If tree_id is None, then the directory is considered a dirty
+    /// dir. All files and dirs under a dirty dir are dirty.
+    /// Of course, it can be recursive, but laziness is a virtue.
+    ///
+    /// `path` is the path of the dir in the mount, and `prefix` its
+    /// path in the underlying dir.  The underlying dir of a shadowed
+    /// dir belongs to the dir it collides with, so it's not looked at.
+    fn walk_dir(
+        &self,
+        ino: Ino,
+        path: &Path,
+        prefix: &Path,
+        tree_id: Option<Oid>,
+        shadowed: bool,
+    ) -> Result<HashMap<OsString, Entry>, Error> {
+        let mut entries = match tree_id {
+            Some(tree_id) => self.walk_tree(ino, path, tree_id)?,
+            None => HashMap::new(),
+        };
+        let names = self.names();
+        if shadowed {
+            for entry in entries.values_mut() {
+                self.shadow(entry);
+            }
+            return Ok(entries);
+        }
+        if names.folds_case() {
+            self.shadow_collisions(&mut entries);
+        }
+
+        // look at underlying_dir/prefix
+        let dir_iter = if ino.is_root() {
+            // Not list_self(): the dir is opened with O_PATH, which
+            // cannot be read.
+            self.inner.underlying_dir.list_dir(".")
+        } else {
+            self.inner.underlying_dir.list_dir(prefix.as_os_str())
+        };
+        let dir_iter = match dir_iter {
+            Ok(dir_iter) => dir_iter,
+            // git tree doesn't have a ghost dir, return early
+            Err(_) if tree_id.is_some() => return Ok(entries),
+            // error inside dirty dir
+            Err(e) => return Err(e.into()),
+        };
+
+        // Names in the tree, by the key names in the underlying dir are
+        // matched with.  Of colliding names, the one that isn't
+        // shadowed is the one stored in the underlying dir.
+        let aliases: HashMap<OsString, OsString> = if names.normalizes() {
+            entries
+                .iter()
+                .filter(|(_, entry)| !entry.shadowed)
+                .map(|(name, _)| (names.merge_key(name).into_owned(), name.clone()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+
+        // try to collect dirty entries
+        for dirty_entry in dir_iter {
+            let dirty_entry = match dirty_entry {
+                Ok(dirty_entry) => dirty_entry,
+                // The error would only repeat itself.
+                Err(e) => {
+                    warn!(%e, "the underlying dir cannot be read, skipping the rest");
+                    break;
+                }
+            };
+            let mut path = prefix.to_owned();
+            path.push(dirty_entry.file_name());
+            let metadata = match self.inner.underlying_dir.metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!("metadata of a dirty entry cannot be read {}, skipping", e);
+                    continue;
+                }
+            };
+            let stat = metadata.stat();
+            let name = names.mount_name(dirty_entry.file_name()).into_owned();
+            let name = match aliases.get(&*names.merge_key(&name)) {
+                Some(alias) => alias.clone(),
+                None => name,
+            };
+            match dirty_entry.simple_type() {
+                Some(SimpleType::Dir) => {
+                    trace!(?name, "found dirty dir");
+                    // a dir is dirty <=> it's on disk but not in git tree,
+                    // where it may replace a file
+                    if !matches!(entries.get(&name), Some(Entry { u: EntryKind::GitTree { .. }, .. })) {
+                        entries.insert(
+                            name.clone(),
+                            Entry {
+                                name,
+                                parent: ino,
+                                perm: Permissions::from_mode(stat.st_mode as u32),
+                                size: self.overlay_len(stat.st_size as u64),
+                                atime: system_time(stat.st_atime, stat.st_atime_nsec),
+                                mtime: system_time(stat.st_mtime, stat.st_mtime_nsec),
+                                ctime: system_time(stat.st_ctime, stat.st_ctime_nsec),
+                                crtime: birthtime(stat),
+                                shadowed: false,
+                                subdirs: None,
+                                owner: None,
+                                flags: None,
+                                stamp: None,
+                                u: EntryKind::DirtyDir { children: None },
+                            },
+                        );
+                    }
+                }
+                Some(SimpleType::File) => {
+                    // a file on disk is always considered dirty
+                    trace!(?name, "found dirty file");
+                    let perm = match entries.get(&name) {
+                        _ if self.inner.file_mode => Permissions::from_mode(stat.st_mode as u32),
+                        // The underlying dir may not keep mode bits
+                        // (e.g. exFAT), so keep the ones in the tree.
+                        Some(Entry { u: EntryKind::GitBlob { .. }, perm, .. }) => perm.clone(),
+                        _ => Permissions::from_mode(MODE_FILE | 0o644),
+                    };
+                    entries.insert(
+                        name.clone(),
+                        Entry {
+                            name,
+                            parent: ino,
+                            perm,
+                            size: self.overlay_len(stat.st_size as u64),
+                            atime: system_time(stat.st_atime, stat.st_atime_nsec),
+                            mtime: system_time(stat.st_mtime, stat.st_mtime_nsec),
+                            ctime: system_time(stat.st_ctime, stat.st_ctime_nsec),
+                            crtime: birthtime(stat),
+                            shadowed: false,
+                            subdirs: None,
+                            owner: None,
+                            flags: None,
+                            stamp: Some(Stamp::from(stat)),
+                            u: EntryKind::DirtyFile,
+                        },
+                    );
+                }
+                _ => {
+                    warn!("unknown file type, skipping");
+                    continue;
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Find entries whose names collide in the underlying dir, and
+    /// shadow them according to the case collision policy.
+    fn shadow_collisions(&self, entries: &mut HashMap<OsString, Entry>) {
+        let names = self.names();
+        let mut groups: HashMap<OsString, Vec<OsString>> = HashMap::new();
+        for name in entries.keys() {
+            groups.entry(names.merge_key(name).into_owned()).or_default().push(name.clone());
+        }
+        let policy = self.options_read().case_collisions;
+        for (_, mut group) in groups.into_iter().filter(|(_, group)| group.len() > 1) {
+            group.sort();
+            warn!(?group, "names collide in the underlying dir");
+            let skip = match policy {
+                CaseCollisions::ReadOnly => 1,
+                _ => 0,
+            };
+            for name in group.iter().skip(skip) {
+                if let Some(entry) = entries.get_mut(name) {
+                    self.shadow(entry);
+                }
+            }
+        }
+    }
+
+    fn shadow(&self, entry: &mut Entry) {
+        entry.shadowed = true;
+        if self.options_read().case_collisions == CaseCollisions::ReadOnly {
+            entry.perm = Permissions::from_mode(entry.perm.mode() & !0o222);
+        }
+    }
+
+    /// Return an error if the entry must not be written to.
+    /// Apply `denied_paths`, `included_paths`, `excluded_paths` and
+    /// `read_only_paths` to the path given by `path`, which is only
+    /// called if there are rules: hidden paths aren't there, or can't
+    /// be created, and read-only ones can't be changed (`write`).
+    /// Paths are taken as dirs here; see `check_included_file`.
+    ///
+    /// On a read-only mount, every path is read-only.  Every change,
+    /// whichever front-end asks for it, goes through here with `write`
+    /// before anything is done, so that all of them fail alike.
+    fn check_rules<F: FnOnce() -> Option<PathBuf>>(&self, path: F, write: bool) -> Result<(), Error> {
+        if write && self.options_read().read_only {
+            debug!("refused on a read-only mount");
+            return Err(Error::Errno(EROFS));
+        }
+        let any = {
+            let options = self.options_read();
+            !options.denied_paths.is_empty()
+                || !options.included_paths.is_empty()
+                || !options.excluded_paths.is_empty()
+                || (write && !options.read_only_paths.is_empty())
+        };
+        if !any {
+            return Ok(());
+        }
+        let path = match path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let options = self.options_read();
+        if hidden(&options, &path, true) {
+            return Err(Error::Errno(if write { EACCES } else { ENOENT }));
+        }
+        if write && options.read_only_paths.iter().any(|pattern| glob::matches(pattern, &path)) {
+            return Err(Error::Errno(EROFS));
+        }
+        Ok(())
+    }
+
+    /// As `check_rules` for a file or symlink, which isn't shown for
+    /// leading to included paths, as a dir would be.
+    fn check_included_file<F: FnOnce() -> Option<PathBuf>>(&self, path: F) -> Result<(), Error> {
+        if self.options_read().included_paths.is_empty() {
+            return Ok(());
+        }
+        match path() {
+            Some(path) if !glob::included(&self.options_read().included_paths, &path, false) => Err(Error::Errno(ENOENT)),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_writable(&self, entry: &Entry) -> Result<(), Error> {
+        if !entry.shadowed {
+            return Ok(());
+        }
+        match self.options_read().case_collisions {
+            CaseCollisions::ReadOnly => Err(Error::Errno(EROFS)),
+            _ => Err(Error::Errno(EEXIST)),
+        }
+    }
+
+    /// The attributes of an entry.
+    fn attr(&self, inomap: &mut InoMap, ino: Ino) -> Result<FileAttr, Error> {
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        let nlink = match (FileType::from(entry), entry.subdirs) {
+            (FileType::Directory, Some(subdirs)) => 2 + subdirs,
+            (FileType::Directory, None) => match self.count_subdirs(inomap, ino) {
+                Ok(subdirs) => {
+                    if let Some(entry) = inomap.get_mut(ino) {
+                        entry.subdirs = Some(subdirs);
+                    }
+                    2 + subdirs
+                }
+                // The dir itself is still there; listing it will fail.
+                Err(e) => {
+                    debug!(%e, "cannot count subdirs");
+                    2
+                }
+            },
+            _ => 1,
+        };
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        let stat = match entry.u {
+            EntryKind::DirtyFile | EntryKind::DirtyDir { .. } => self
+                .overlay_path(inomap, ino)
+                .ok()
+                .and_then(|path| self.inner.underlying_dir.metadata(&path).ok())
+                .map(|metadata| *metadata.stat()),
+            _ => None,
+        };
+        // Dirty files may have been changed in the underlying dir by
+        // someone else.
+        if let (EntryKind::DirtyFile, Some(stat)) = (&entry.u, &stat) {
+            if entry.stamp != Some(Stamp::from(stat)) {
+                let file_mode = self.inner.file_mode;
+                let entry = inomap.get_mut(ino).ok_or(Error::Errno(ENOENT))?;
+                trace!(?entry, "dirty file changed in the underlying dir");
+                entry.size = self.overlay_len(stat.st_size as u64);
+                entry.atime = system_time(stat.st_atime, stat.st_atime_nsec);
+                entry.mtime = system_time(stat.st_mtime, stat.st_mtime_nsec);
+                entry.ctime = system_time(stat.st_ctime, stat.st_ctime_nsec);
+                if file_mode {
+                    entry.perm = Permissions::from_mode(stat.st_mode as _);
+                }
+                entry.stamp = Some(Stamp::from(stat));
+            }
+        }
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        let blocks = match (&entry.u, &stat) {
+            // Dirty files take the space they take in the underlying
+            // dir, which may differ from their size (e.g. sparse files).
+            (EntryKind::DirtyFile, Some(stat)) => stat.st_blocks as u64,
+            _ => entry.size.div_ceil(512),
+        };
+        let owner = self.owner(inomap, ino, stat.as_ref());
+        // As with the owner, flags given while clean come first.
+        let flags = entry.flags.or_else(|| stat.as_ref().map(st_flags)).unwrap_or(0);
+        Ok(self.make_attr(ino, entry, nlink, blocks, owner, flags))
+    }
+
+    /// The owner (uid, gid) of an entry.  In order of preference, it's
+    /// the one it was given by chown while clean, the one given by the
+    /// owner mapper, the owner in the underlying
+    /// dir for dirty entries, the one in the options, and finally the
+    /// user running gitfs.
+    fn owner(&self, inomap: &InoMap, ino: Ino, stat: Option<&stat>) -> (u32, u32) {
+        if let Some(owner) = inomap.get(ino).and_then(|entry| entry.owner) {
+            return owner;
+        }
+        if let Some(mapper) = &self.inner.owner_mapper {
+            if let Some(owner) = inomap.prefix(ino).and_then(|path| mapper(&path)) {
+                return owner;
+            }
+        }
+        if let Some(stat) = stat {
+            return (stat.st_uid, stat.st_gid);
+        }
+        self.default_owner()
+    }
+
+    /// The owner in the options, or else the user running gitfs.
+    fn default_owner(&self) -> (u32, u32) {
+        let options = self.options_read();
+        (
+            options.uid.unwrap_or_else(|| unsafe { libc::getuid() }),
+            options.gid.unwrap_or_else(|| unsafe { libc::getgid() }),
+        )
+    }
+
+    /// Count the subdirs of a dir, without listing it if it's not
+    /// listed yet.
+    fn count_subdirs(&self, inomap: &InoMap, ino: Ino) -> Result<u32, Error> {
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        let count = match &entry.u {
+            EntryKind::GitTree {
+                children: Some(children),
+                ..
+            }
+            | EntryKind::DirtyDir {
+                children: Some(children),
+            } => children
+                .values()
+                .filter_map(|&child| inomap.get(child))
+                .filter(|child| FileType::from(*child) == FileType::Directory)
+                .count(),
+            // Dirty dirs that are not listed yet don't count, but
+            // they are counted once the dir is listed.
+            EntryKind::GitTree { oid, children: None, .. } => self
+                .repo()
+                .find_tree(*oid)?
+                .iter()
+                .filter(|tree_entry| tree_entry.kind() == Some(ObjectType::Tree))
+                .count(),
+            EntryKind::DirtyDir { children: None } => {
+                let path = self.overlay_path(inomap, ino)?;
+                self.inner
+                    .underlying_dir
+                    .list_dir(&path)?
+                    .filter_map(|e| e.ok())
+                    .filter(|e| e.simple_type() == Some(SimpleType::Dir))
+                    .count()
+            }
+            EntryKind::GitBlob { .. } | EntryKind::DirtyFile => 0,
+        };
+        Ok(count as u32)
+    }
+
+    /// `blocks` is in units of 512 bytes, as in stat().
+    fn make_attr(&self, ino: Ino, entry: &Entry, nlink: u32, blocks: u64, owner: (u32, u32), flags: u32) -> FileAttr {
+        FileAttr {
+            ino: ino.into(),
+            size: entry.size,
+            blocks,
+            atime: entry.atime,
+            mtime: entry.mtime,
+            ctime: entry.ctime,
+            crtime: entry.crtime,
+            kind: FileType::from(entry),
+            perm: entry.perm.mode() as u16,
+            nlink,
+            uid: owner.0,
+            gid: owner.1,
+            rdev: 0,
+            blksize: self.inner.blksize,
+            flags,
+        }
+    }
+}
+
+/// When a file was created, on the platforms that keep it.
+/// Succeed if there was nothing to act on.
+fn optional(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+fn birthtime(stat: &stat) -> SystemTime {
+    system_time(stat.st_birthtime, stat.st_birthtime_nsec)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd")))]
+fn birthtime(_: &stat) -> SystemTime {
     SystemTime::UNIX_EPOCH
 }
+
+/// Statistics of the file system holding `dir`.
+fn statvfs(dir: &Dir) -> io::Result<libc::statvfs> {
+    let mut st = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // fstatvfs() works on the O_PATH descriptor the dir is opened with.
+    if unsafe { libc::fstatvfs(dir.as_raw_fd(), st.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { st.assume_init() })
+}
+
+/// Read until `buf` is full or EOF is reached, and return the number
+/// of bytes read.
+/// Walk the first-parent history of `commit`, and find when each path
+/// in it (under `subdir`, and relative to it) was last changed.
+fn commit_times(repo: &Repository, commit: Oid, subdir: &Path) -> Result<HashMap<PathBuf, SystemTime>, GitError> {
+    let started = Instant::now();
+    let mut remaining = HashSet::new();
+    let tree = repo.find_commit(commit)?.tree()?;
+    let tree = match subdir.as_os_str().is_empty() {
+        true => tree,
+        false => repo.find_tree(tree.get_path(subdir)?.id())?,
+    };
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        if let Some(name) = entry.name() {
+            remaining.insert(Path::new(dir).join(name));
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    let mut times = HashMap::new();
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(commit)?;
+    revwalk.simplify_first_parent()?;
+    for oid in revwalk {
+        if remaining.is_empty() {
+            break;
+        }
+        let commit = repo.find_commit(oid?)?;
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(commit.time().seconds().max(0) as u64);
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+        for delta in diff.deltas() {
+            // A change to a file changes the dirs it's in as well.
+            let changed = delta.new_file().path().and_then(|path| path.strip_prefix(subdir).ok());
+            let changed = changed.into_iter().flat_map(Path::ancestors);
+            for path in changed {
+                if remaining.remove(path) {
+                    times.insert(path.to_owned(), time);
+                }
+            }
+        }
+    }
+    debug!(%commit, paths = times.len(), elapsed = ?started.elapsed(), "walked history");
+    Ok(times)
+}
+
+/// A signature as in an xattr, `name <email>`.
+fn signature(signature: git2::Signature) -> Vec<u8> {
+    let mut value = signature.name_bytes().to_vec();
+    value.extend_from_slice(b" <");
+    value.extend_from_slice(signature.email_bytes());
+    value.push(b'>');
+    value
+}
+
+/// Change the owner of `path` in `dir`; `None` leaves it as is.
+fn chown_at(dir: &Dir, path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    let path = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let uid = uid.unwrap_or(u32::MAX);
+    let gid = gid.unwrap_or(u32::MAX);
+    let flags = libc::AT_SYMLINK_NOFOLLOW;
+    if unsafe { libc::fchownat(dir.as_raw_fd(), path.as_ptr(), uid, gid, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn chmod_at(dir: &Dir, path: &Path, mode: u32) -> io::Result<()> {
+    let path = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::fchmodat(dir.as_raw_fd(), path.as_ptr(), (mode & 0o7777) as mode_t, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The system flags of chflags (SF_*), as opposed to those of users
+/// (UF_*).
+const SF_FLAGS: u32 = 0xffff_0000;
+
+/// The BSD flags of a file, on macOS, which passes them through FUSE.
+#[cfg(target_os = "macos")]
+fn st_flags(stat: &stat) -> u32 {
+    stat.st_flags
+}
+
+#[cfg(not(target_os = "macos"))]
+fn st_flags(_: &stat) -> u32 {
+    0
+}
+
+#[cfg(target_os = "linux")]
+const O_NOATIME: c_int = libc::O_NOATIME;
+/// There's no O_NOATIME to pass on.
+#[cfg(not(target_os = "linux"))]
+const O_NOATIME: c_int = 0;
+
+/// The flags to open the underlying file of a handle with, given
+/// those of the open(): how it's accessed, and whether writes are
+/// synchronous or reads update its atime.  Files written to through
+/// an encrypted overlay are read too, as writes read back the blocks
+/// they only partly cover.  The other flags are what the kernel and
+/// gitfs take care of: O_APPEND, say, would make every pwrite() append.
+fn overlay_flags(flags: c_int, encrypted: bool) -> c_int {
+    let access = match flags & O_ACCMODE {
+        libc::O_WRONLY if encrypted => O_RDWR,
+        access => access,
+    };
+    access | flags & (libc::O_SYNC | libc::O_DSYNC | O_NOATIME)
+}
+
+/// Open `path` in `dir` with `flags` (and `mode`, if it's created),
+/// not following symlinks.  O_NOATIME is dropped where it's refused,
+/// as it is for files that aren't the caller's.
+fn open_at(dir: &Dir, path: &Path, flags: c_int, mode: mode_t) -> io::Result<File> {
+    use std::os::unix::io::FromRawFd;
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let open = |flags: c_int| unsafe {
+        libc::openat(dir.as_raw_fd(), cpath.as_ptr(), flags | libc::O_NOFOLLOW | libc::O_CLOEXEC, mode as libc::c_uint)
+    };
+    let mut fd = open(flags);
+    if fd < 0 && flags & O_NOATIME != 0 && io::Error::last_os_error().raw_os_error() == Some(EPERM) {
+        fd = open(flags & !O_NOATIME);
+    }
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Set the BSD flags of `path` in `dir`, not following symlinks.
+#[cfg(target_os = "macos")]
+fn chflags_at(dir: &Dir, path: &Path, flags: u32) -> io::Result<()> {
+    use std::os::unix::io::FromRawFd;
+    let path = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // There's no chflagsat(), but O_SYMLINK opens symlinks themselves.
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), path.as_ptr(), libc::O_RDONLY | libc::O_SYMLINK | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    fchflags(&unsafe { File::from_raw_fd(fd) }, flags)
+}
+
+#[cfg(not(target_os = "macos"))]
+fn chflags_at(_: &Dir, _: &Path, _: u32) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+#[cfg(target_os = "macos")]
+fn fchflags(file: &File, flags: u32) -> io::Result<()> {
+    if unsafe { libc::fchflags(file.as_raw_fd(), flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn fchflags(_: &File, _: u32) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+/// Set the times of `path` in `dir`; `None` leaves a time as is.
+fn utimens_at(dir: &Dir, path: &Path, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>) -> io::Result<()> {
+    let path = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [timespec(atime), timespec(mtime)];
+    let flags = libc::AT_SYMLINK_NOFOLLOW;
+    if unsafe { libc::utimensat(dir.as_raw_fd(), path.as_ptr(), times.as_ptr(), flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn timespec(time: Option<TimeOrNow>) -> libc::timespec {
+    let (tv_sec, tv_nsec) = match time {
+        None => (0, libc::UTIME_OMIT),
+        Some(TimeOrNow::Now) => (0, libc::UTIME_NOW),
+        Some(TimeOrNow::SpecificTime(time)) => match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(d) => (d.as_secs() as libc::time_t, d.subsec_nanos() as libc::c_long),
+            // Before the epoch: whole seconds down, nanoseconds up.
+            Err(e) => {
+                let d = e.duration();
+                let secs = -(d.as_secs() as libc::time_t);
+                match d.subsec_nanos() {
+                    0 => (secs, 0),
+                    nanos => (secs - 1, 1_000_000_000 - nanos as libc::c_long),
+                }
+            }
+        },
+    };
+    libc::timespec { tv_sec, tv_nsec }
+}
+
+/// The time of a stat: `secs`, which are negative before the epoch,
+/// and `nsecs` after them.  Times out of range are the epoch.
+// time_t isn't i64 on every platform.
+#[allow(clippy::unnecessary_cast)]
+fn system_time(secs: libc::time_t, nsecs: libc::c_long) -> SystemTime {
+    let time = match Duration::from_secs(secs.unsigned_abs() as u64) {
+        d if secs >= 0 => SystemTime::UNIX_EPOCH.checked_add(d),
+        d => SystemTime::UNIX_EPOCH.checked_sub(d),
+    };
+    let nsecs = Duration::from_nanos(nsecs.clamp(0, 999_999_999) as u64);
+    time.and_then(|time| time.checked_add(nsecs)).unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+/// DOS attributes, as in `FILE_ATTRIBUTE_*`.
+const DOS_READONLY: u32 = 0x1;
+const DOS_HIDDEN: u32 = 0x2;
+const DOS_DIRECTORY: u32 = 0x10;
+const DOS_ARCHIVE: u32 = 0x20;
+
+/// The errno for a missing xattr.
+#[cfg(target_os = "linux")]
+const ENOATTR: c_int = libc::ENODATA;
+#[cfg(not(target_os = "linux"))]
+const ENOATTR: c_int = libc::ENOATTR;
+
+/// Whether `path` is hidden by `Options::denied_paths`,
+/// `included_paths` or `excluded_paths`, as a `dir` or not.
+fn hidden(options: &Options, path: &Path, dir: bool) -> bool {
+    let matches = |patterns: &[String]| patterns.iter().any(|pattern| glob::matches(pattern, path));
+    matches(&options.denied_paths) || matches(&options.excluded_paths) || !glob::included(&options.included_paths, path, dir)
+}
+
+/// Reply with an xattr value or list: its size if asked for none of
+/// it, as the caller sizes its buffer that way.
+fn reply_xattr(data: &[u8], size: u32, reply: fuser::ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32)
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE)
+    } else {
+        reply.data(data)
+    }
+}
+
+/// The capabilities asked of the kernel, on top of those fuser asks
+/// for (big writes, and max_pages for max_write).  Each is asked for
+/// alone, so that a kernel without one still gets the others.
+///
+/// Left out on purpose: posix ACLs, as modes are all there is and ACL
+/// xattrs are not kept; the writeback cache, which would have the
+/// kernel read files opened write-only and keep mtimes of its own;
+/// atomic O_TRUNC, since without it the kernel follows the open with a
+/// setattr of size 0, which truncates the file the open materialized,
+/// and `do_open` drops the flag; and export support, as there is no
+/// lookup of `.` and `..`.
+const CAPABILITIES: &[(&str, u32)] = &[
+    // Reads of a file are served in any order, and overlapping.
+    ("async_read", FUSE_ASYNC_READ),
+    // Operations on a dir need no locking by the kernel: the inomap
+    // is locked around each, as it is for a multithreaded session.
+    ("parallel_dirops", FUSE_PARALLEL_DIROPS),
+    // Listing a dir gives the attributes of its children, when the
+    // kernel expects them to be looked up anyway, as with `ls -l`.
+    ("readdirplus_auto", FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO),
+];
+
+fn negotiate_capabilities(config: &mut KernelConfig) {
+    for &(name, capability) in CAPABILITIES {
+        match config.add_capabilities(capability) {
+            Ok(()) => debug!(capability = name, "capability enabled"),
+            Err(missing) => debug!(capability = name, missing, "capability is not supported by the kernel"),
+        }
+    }
+}
+
+fn read_full_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut nread = 0;
+    while nread < buf.len() {
+        match file.read_at(&mut buf[nread..], offset + nread as u64) {
+            Ok(0) => break,
+            Ok(n) => nread += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(nread)
+}
+
+/// Write as much of `data` as possible, and return the number of bytes
+/// written.  An error is only returned if nothing could be written.
+fn write_some_at(file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+    let mut nwritten = 0;
+    while nwritten < data.len() {
+        match file.write_at(&data[nwritten..], offset + nwritten as u64) {
+            Ok(0) => break,
+            Ok(n) => nwritten += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) if nwritten == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    if nwritten == 0 && !data.is_empty() {
+        return Err(io::ErrorKind::WriteZero.into());
+    }
+    Ok(nwritten)
+}
+
+/// The part of a `len`-byte file that a read of `size` bytes at
+/// `offset` covers.  Reads may be short near EOF, and are empty at or
+/// after EOF.
+fn read_range(len: usize, offset: u64, size: u32) -> Range<usize> {
+    let start = offset.min(len as u64) as usize;
+    let end = start + (size as usize).min(len - start);
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::atomic::AtomicUsize;
+
+    /// A mounted-but-not-really gitfs over a fresh repository, whose
+    /// objects can be broken on purpose.  Both dirs are removed on drop.
+    struct Fixture {
+        root: PathBuf,
+        fs: GitFS,
+        blobs: HashMap<&'static str, Oid>,
+        trees: HashMap<&'static str, Oid>,
+    }
+
+    impl Fixture {
+        /// A repo with `a.txt`, `broken.txt` and `dir/b.txt`.
+        fn new() -> Fixture {
+            Self::with_config(&[])
+        }
+
+        /// Same as `new`, with these boolean settings in the repo
+        /// config.
+        fn with_config(config: &[(&str, bool)]) -> Fixture {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let root = std::env::temp_dir().join(format!(
+                "gitfs-test-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::SeqCst)
+            ));
+            std::fs::create_dir_all(root.join("overlay")).unwrap();
+            let repo = Repository::init(root.join("repo")).unwrap();
+            for &(name, value) in config {
+                repo.config().unwrap().set_bool(name, value).unwrap();
+            }
+
+            let (blobs, trees) = Self::populate(&repo);
+            let fs = GitFS::new(repo, Dir::open(&root.join("overlay")).unwrap());
+            fs.mount_root().unwrap();
+            let fixture = Fixture { root, fs, blobs, trees };
+            fixture.remove_object(fixture.blobs["broken.txt"]);
+            fixture
+        }
+
+        #[allow(clippy::type_complexity)]
+        fn populate(repo: &Repository) -> (HashMap<&'static str, Oid>, HashMap<&'static str, Oid>) {
+            let mut blobs = HashMap::new();
+            let mut trees = HashMap::new();
+            blobs.insert("a.txt", repo.blob(b"hello world").unwrap());
+            blobs.insert("broken.txt", repo.blob(b"about to break").unwrap());
+            blobs.insert("b.txt", repo.blob(b"in a dir").unwrap());
+
+            let mut sub = repo.treebuilder(None).unwrap();
+            sub.insert("b.txt", blobs["b.txt"], 0o100644).unwrap();
+            trees.insert("dir", sub.write().unwrap());
+            let mut top = repo.treebuilder(None).unwrap();
+            top.insert("a.txt", blobs["a.txt"], 0o100644).unwrap();
+            top.insert("broken.txt", blobs["broken.txt"], 0o100644).unwrap();
+            top.insert("dir", trees["dir"], 0o040000).unwrap();
+            let tree = repo.find_tree(top.write().unwrap()).unwrap();
+            let sig = git2::Signature::now("test", "test@example.com").unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap();
+            (blobs, trees)
+        }
+
+        /// Delete a loose object from the repository.
+        fn remove_object(&self, oid: Oid) {
+            let hex = oid.to_string();
+            let path = self.root.join("repo/.git/objects").join(&hex[..2]).join(&hex[2..]);
+            std::fs::remove_file(path).unwrap();
+        }
+
+        /// Commit a root tree of these blobs, and switch to it.
+        fn checkout(&self, blobs: &[(&str, Oid, i32)]) {
+            let commit = {
+                let repo = self.fs.repo();
+                let mut top = repo.treebuilder(None).unwrap();
+                for &(name, oid, mode) in blobs {
+                    top.insert(name, oid, mode).unwrap();
+                }
+                let tree = repo.find_tree(top.write().unwrap()).unwrap();
+                let sig = git2::Signature::now("test", "test@example.com").unwrap();
+                repo.commit(None, &sig, &sig, "test", &tree, &[]).unwrap()
+            };
+            self.fs.checkout(&commit.to_string()).unwrap();
+        }
+
+        fn lookup(&self, parent: Ino, name: &str) -> Ino {
+            self.fs.do_lookup(parent, OsStr::new(name)).unwrap().ino.into()
+        }
+
+        fn errno<T: std::fmt::Debug>(&self, result: Result<T, Error>) -> c_int {
+            self.fs.errno(&result.unwrap_err())
+        }
+
+        /// The content of a file in the control dir.
+        fn read_control(&self, name: &str) -> String {
+            let dir = self.lookup(Ino::ROOT, control::NAME);
+            let ino = self.lookup(dir, name);
+            let fh = self.fs.do_open(ino, O_RDONLY).unwrap();
+            let content = self.fs.do_read(ino, fh, 0, 1 << 20).unwrap();
+            self.fs.control_release(fh);
+            String::from_utf8(content).unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// What `f` logs at info level and above, as text.
+    fn logged<F: FnOnce()>(f: F) -> String {
+        #[derive(Clone, Default)]
+        struct Log(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Log {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let log = Log::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .with_max_level(tracing::Level::INFO)
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let log = log.0.lock().unwrap().clone();
+        String::from_utf8(log).unwrap()
+    }
+
+    #[test]
+    fn broken_blob_does_not_hide_its_dir() {
+        let f = Fixture::new();
+        let attr = f.fs.do_lookup(Ino::ROOT, OsStr::new("broken.txt")).unwrap();
+        assert_eq!(attr.size, 0);
+        let attr = f.fs.do_lookup(Ino::ROOT, OsStr::new("a.txt")).unwrap();
+        assert_eq!(attr.size, 11);
+        assert_eq!(f.fs.do_readdir(Ino::ROOT).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn readdirplus_lists_as_readdir_with_attributes() {
+        let f = Fixture::new();
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        let children = f.fs.do_readdir(Ino::ROOT).unwrap();
+        let plus = f.fs.do_readdirplus(Ino::ROOT).unwrap();
+        assert_eq!(plus.len(), children.len());
+        for ((name, ino, _), (plus_name, attr)) in children.into_iter().zip(plus) {
+            let attr = attr.unwrap();
+            assert_eq!(name, plus_name);
+            assert_eq!(Ino::from(attr.ino), ino);
+            assert_eq!(attr, f.fs.do_lookup(Ino::ROOT, &name).unwrap());
+        }
+        assert_eq!(f.errno(f.fs.do_readdirplus(f.lookup(Ino::ROOT, "a.txt"))), ENOTDIR);
+    }
+
+    #[test]
+    fn reading_broken_blob_fails() {
+        let f = Fixture::new();
+        let ino = f.lookup(Ino::ROOT, "broken.txt");
+        let fh = f.fs.do_open(ino, O_RDONLY).unwrap();
+        assert_eq!(f.errno(f.fs.do_read(ino, fh, 0, 4096)), EIO);
+
+        // The rest of the mount is unaffected.
+        let ino = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(ino, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(ino, fh, 6, 4096).unwrap(), b"world");
+    }
+
+    #[test]
+    fn writing_broken_blob_leaves_it_clean() {
+        let f = Fixture::new();
+        let ino = f.lookup(Ino::ROOT, "broken.txt");
+        assert_eq!(f.errno(f.fs.do_open(ino, libc::O_RDWR)), EIO);
+        assert!(matches!(f.fs.inomap().get(ino).unwrap().u, EntryKind::GitBlob { .. }));
+        assert!(!f.root.join("overlay/broken.txt").exists());
+    }
+
+    #[test]
+    fn broken_tree_fails_only_opendir() {
+        let f = Fixture::new();
+        f.remove_object(f.trees["dir"]);
+        let dir = f.lookup(Ino::ROOT, "dir");
+        assert_eq!(f.errno(f.fs.do_opendir(dir)), EIO);
+        assert_eq!(f.errno(f.fs.do_lookup(dir, OsStr::new("b.txt"))), EIO);
+        f.lookup(Ino::ROOT, "a.txt");
+    }
+
+    #[test]
+    fn stale_inos_and_names_are_errors() {
+        let f = Fixture::new();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(f.errno(f.fs.do_opendir(a)), ENOTDIR);
+        assert_eq!(f.errno(f.fs.do_getattr(Ino(1000))), ENOENT);
+        assert_eq!(f.errno(f.fs.do_open(Ino(1000), O_RDONLY)), ENOENT);
+        assert_eq!(f.errno(f.fs.do_lookup(Ino::ROOT, OsStr::new("nope"))), ENOENT);
+
+        // Racing removals: the second one finds nothing to act on.
+        f.fs.do_remove(Ino::ROOT, OsStr::new("a.txt")).unwrap();
+        assert_eq!(f.errno(f.fs.do_remove(Ino::ROOT, OsStr::new("a.txt"))), ENOENT);
+        assert_eq!(f.errno(f.fs.do_getattr(a)), ENOENT);
+        let rename = f.fs.do_rename(Ino::ROOT, OsStr::new("a.txt"), Ino::ROOT, OsStr::new("c.txt"));
+        assert_eq!(f.errno(rename), ENOENT);
+    }
+
+    #[test]
+    fn read_range_within_file() {
+        assert_eq!(read_range(10, 0, 4), 0..4);
+        assert_eq!(read_range(10, 3, 4), 3..7);
+        assert_eq!(read_range(10, 0, 10), 0..10);
+    }
+
+    #[test]
+    fn read_range_is_short_near_eof() {
+        assert_eq!(read_range(10, 8, 4), 8..10);
+        assert_eq!(read_range(10, 0, 1 << 20), 0..10);
+        assert_eq!(read_range(3 << 20, 1 << 20, 4 << 20), (1 << 20)..(3 << 20));
+    }
+
+    #[test]
+    fn read_range_is_empty_at_and_after_eof() {
+        assert!(read_range(10, 10, 4).is_empty());
+        assert!(read_range(10, 11, 4).is_empty());
+        assert!(read_range(10, u64::MAX, u32::MAX).is_empty());
+        assert!(read_range(0, 0, 4).is_empty());
+    }
+
+    #[test]
+    fn read_full_at_is_short_at_eof() {
+        let path = std::env::temp_dir().join(format!("gitfs-read-{}", std::process::id()));
+        std::fs::write(&path, b"hello world").unwrap();
+        let file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut buf = [0; 8];
+        assert_eq!(read_full_at(&file, &mut buf, 6).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(read_full_at(&file, &mut buf, 11).unwrap(), 0);
+        assert_eq!(read_full_at(&file, &mut buf, 100).unwrap(), 0);
+    }
+
+    #[test]
+    fn long_names_are_refused_up_front() {
+        let f = Fixture::new();
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        let long = OsString::from("x".repeat(f.fs.inner.name_max + 1));
+        assert_eq!(f.errno(f.fs.do_create(Ino::ROOT, &long, 0o644)), ENAMETOOLONG);
+        assert_eq!(f.errno(f.fs.do_mkdir(Ino::ROOT, &long, 0o755)), ENAMETOOLONG);
+        let rename = f.fs.do_rename(Ino::ROOT, OsStr::new("a.txt"), Ino::ROOT, &long);
+        assert_eq!(f.errno(rename), ENAMETOOLONG);
+
+        // Nothing was left behind.
+        assert_eq!(f.fs.do_readdir(Ino::ROOT).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn crafted_names_are_skipped() {
+        let f = Fixture::new();
+        let commit = {
+            let repo = f.fs.repo();
+            let blob = f.blobs["a.txt"];
+            let mut raw = Vec::new();
+            for name in [&b".."[..], b"ok.txt"].iter() {
+                raw.extend_from_slice(b"100644 ");
+                raw.extend_from_slice(name);
+                raw.push(0);
+                raw.extend_from_slice(blob.as_bytes());
+            }
+            let tree_id = repo.odb().unwrap().write(ObjectType::Tree, &raw).unwrap();
+            let tree = repo.find_tree(tree_id).unwrap();
+            let sig = git2::Signature::now("test", "test@example.com").unwrap();
+            repo.commit(None, &sig, &sig, "crafted", &tree, &[]).unwrap()
+        };
+        f.fs.checkout(&commit.to_string()).unwrap();
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        let names: Vec<_> = f.fs.do_readdir(Ino::ROOT).unwrap().into_iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, vec![OsString::from("ok.txt")]);
+    }
+
+    #[test]
+    fn dirs_link_their_subdirs() {
+        let f = Fixture::new();
+        assert_eq!(f.fs.do_getattr(Ino::ROOT).unwrap().nlink, 3);
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        f.fs.do_mkdir(Ino::ROOT, OsStr::new("new"), 0o755).unwrap();
+        assert_eq!(f.fs.do_getattr(Ino::ROOT).unwrap().nlink, 4);
+        assert_eq!(f.errno(f.fs.do_remove(Ino::ROOT, OsStr::new("dir"))), ENOTEMPTY);
+        f.fs.do_remove(f.lookup(Ino::ROOT, "dir"), OsStr::new("b.txt")).unwrap();
+        f.fs.do_remove(Ino::ROOT, OsStr::new("dir")).unwrap();
+        assert_eq!(f.fs.do_getattr(Ino::ROOT).unwrap().nlink, 3);
+        let new = f.lookup(Ino::ROOT, "new");
+        assert_eq!(f.fs.do_getattr(new).unwrap().nlink, 2);
+        assert_eq!(f.fs.do_getattr(f.lookup(Ino::ROOT, "a.txt")).unwrap().nlink, 1);
+    }
+
+    #[test]
+    fn clean_dirs_take_dirty_files_and_renames() {
+        let f = Fixture::new();
+        let dir = f.lookup(Ino::ROOT, "dir");
+        let b = f.lookup(dir, "b.txt");
+        let fh = f.fs.do_open(b, libc::O_WRONLY).unwrap();
+        f.fs.do_write(b, fh, 0, b"IN").unwrap();
+        f.fs.do_create(dir, OsStr::new("new.txt"), 0o644).unwrap();
+        assert_eq!(std::fs::read(f.root.join("overlay/dir/b.txt")).unwrap(), b"IN a dir");
+
+        // Over a dirty file, which would show up again if left.
+        f.fs.do_rename(Ino::ROOT, OsStr::new("a.txt"), dir, OsStr::new("new.txt")).unwrap();
+        assert!(!f.root.join("overlay/dir/new.txt").exists());
+        f.fs.do_mkdir(dir, OsStr::new("sub"), 0o755).unwrap();
+        f.fs.do_opendir(dir).unwrap();
+        let new = f.lookup(dir, "new.txt");
+        let fh = f.fs.do_open(new, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(new, fh, 0, 100).unwrap(), b"hello world");
+        assert_eq!(f.errno(f.fs.do_rename(dir, OsStr::new("sub"), dir, OsStr::new("new.txt"))), ENOTDIR);
+        assert_eq!(f.errno(f.fs.do_rename(Ino::ROOT, OsStr::new("dir"), f.lookup(dir, "sub"), OsStr::new("x"))), EINVAL);
+        assert_eq!(f.fs.self_check(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn renames_and_removes_outlive_relisting() {
+        let f = Fixture::new();
+        // Whether a dir is empty is known before it's replaced.
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        f.fs.do_mkdir(Ino::ROOT, OsStr::new("empty"), 0o755).unwrap();
+        let rename = f.fs.do_rename(Ino::ROOT, OsStr::new("empty"), Ino::ROOT, OsStr::new("dir"));
+        assert_eq!(f.errno(rename), ENOTEMPTY);
+
+        let dir = f.lookup(Ino::ROOT, "dir");
+        f.fs.do_rename(Ino::ROOT, OsStr::new("a.txt"), dir, OsStr::new("moved.txt")).unwrap();
+        f.fs.do_mkdir(Ino::ROOT, OsStr::new("a.txt"), 0o755).unwrap();
+        // Changed from outside, so that both dirs are listed again.
+        std::fs::create_dir(f.root.join("overlay/dir")).unwrap();
+        std::fs::write(f.root.join("overlay/dir/other.txt"), "other").unwrap();
+        std::fs::write(f.root.join("overlay/other.txt"), "other").unwrap();
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        f.fs.do_opendir(dir).unwrap();
+
+        let moved = f.lookup(dir, "moved.txt");
+        let fh = f.fs.do_open(moved, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(moved, fh, 0, 100).unwrap(), b"hello world");
+        f.lookup(dir, "other.txt");
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(f.fs.do_getattr(a).unwrap().kind, FileType::Directory);
+        assert_eq!(f.fs.self_check(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn blocks_follow_the_size() {
+        let f = Fixture::new();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let attr = f.fs.do_getattr(a).unwrap();
+        assert_eq!(attr.blocks, 1);
+        assert!(attr.blksize >= 512);
+
+        let fh = f.fs.do_open(a, libc::O_RDWR).unwrap();
+        f.fs.do_write(a, fh, 0, &[b'x'; 5000]).unwrap();
+        let attr = f.fs.do_getattr(a).unwrap();
+        assert_eq!(attr.size, 5000);
+        assert!(attr.blocks * 512 >= 5000);
+    }
+
+    #[test]
+    fn submodules_are_shown_read_only() {
+        let f = Fixture::new();
+        let lib = {
+            let repo = Repository::init_bare(f.root.join("repo/.git/modules/lib")).unwrap();
+            let mut top = repo.treebuilder(None).unwrap();
+            top.insert("s.txt", repo.blob(b"in a submodule").unwrap(), 0o100644).unwrap();
+            let tree = repo.find_tree(top.write().unwrap()).unwrap();
+            let sig = git2::Signature::now("test", "test@example.com").unwrap();
+            repo.commit(None, &sig, &sig, "lib", &tree, &[]).unwrap()
+        };
+        let gone = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let gitmodules = {
+            let repo = f.fs.repo();
+            let content = b"[submodule \"lib\"]\n\tpath = lib\n[submodule \"gone\"]\n\tpath = gone\n";
+            repo.blob(content).unwrap()
+        };
+        f.checkout(&[(".gitmodules", gitmodules, 0o100644), ("lib", lib, 0o160000), ("gone", gone, 0o160000)]);
+
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        let names: Vec<_> = f.fs.do_readdir(Ino::ROOT).unwrap().into_iter().map(|(name, _, _)| name).collect();
+        assert!(!names.contains(&OsString::from("gone")));
+        let dir = f.lookup(Ino::ROOT, "lib");
+        assert_eq!(f.fs.do_getattr(dir).unwrap().kind, FileType::Directory);
+        let s = f.lookup(dir, "s.txt");
+        let fh = f.fs.do_open(s, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(s, fh, 0, 100).unwrap(), b"in a submodule");
+        f.fs.handles().remove(fh);
+        assert_eq!(f.errno(f.fs.do_open(s, libc::O_RDWR)), libc::EROFS);
+        assert_eq!(f.errno(f.fs.do_setattr(s, SetAttr { size: Some(0), ..SetAttr::default() })), libc::EROFS);
+        assert_eq!(f.errno(f.fs.do_create(dir, OsStr::new("new.txt"), 0o644)), libc::EROFS);
+        assert_eq!(f.errno(f.fs.do_remove(dir, OsStr::new("s.txt"))), libc::EROFS);
+        assert!(f.fs.changes().unwrap().is_empty());
+
+        // Moving it moves the gitlink.
+        f.fs.do_rename(Ino::ROOT, OsStr::new("lib"), Ino::ROOT, OsStr::new("vendor")).unwrap();
+        let diff = f.fs.changes().unwrap();
+        assert_eq!(diff.len(), 2);
+        assert_eq!(diff[Path::new("lib")], changes::Change::Deleted(lib, 0o160000));
+        assert_eq!(diff[Path::new("vendor")], changes::Change::Added(changes::Content::Object(lib, 0o160000)));
+        {
+            let repo = f.fs.repo();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+        }
+        let commit = f.fs.commit("moved").unwrap();
+        let repo = f.fs.repo();
+        let tree = repo.find_commit(commit).unwrap().tree().unwrap();
+        let vendor = tree.get_name("vendor").unwrap();
+        assert_eq!((vendor.id(), vendor.filemode()), (lib, 0o160000));
+        assert_eq!(tree.get_name("gone").unwrap().id(), gone);
+        assert!(tree.get_name("lib").is_none());
+    }
+
+    #[test]
+    fn mounts_of_the_same_repository_share_it() {
+        let f = Fixture::new();
+        std::fs::create_dir(f.root.join("other")).unwrap();
+        let commit = f.fs.repo().head().unwrap().target().unwrap();
+        let other = GitFS::builder(Repository::open(f.root.join("repo")).unwrap(), Dir::open(&f.root.join("other")).unwrap())
+            .refspec(&commit.to_string())
+            .share_repository(&f.fs)
+            .build();
+        other.mount_root().unwrap();
+        assert!(Arc::ptr_eq(&f.fs.inner.repo, &other.inner.repo));
+        assert!(Arc::ptr_eq(&f.fs.inner.blob_cache, &other.inner.blob_cache));
+
+        let a = other.do_lookup(Ino::ROOT, OsStr::new("a.txt")).unwrap().ino.into();
+        let fh = other.do_open(a, O_RDONLY).unwrap();
+        assert_eq!(other.do_read(a, fh, 0, 100).unwrap(), b"hello world");
+        // Read once, for both.
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(a, fh, 0, 100).unwrap(), b"hello world");
+        assert_eq!(f.fs.blob_cache().size(), 11);
+    }
+
+    #[test]
+    fn only_one_mount_locks_an_underlying_dir() {
+        let f = Fixture::new();
+        let other = || GitFS::new(Repository::open(f.root.join("repo")).unwrap(), Dir::open(&f.root.join("overlay")).unwrap());
+        // Dropped with the mount.
+        other().lock_overlay().unwrap();
+
+        f.fs.lock_overlay().unwrap();
+        f.fs.lock_overlay().unwrap();
+        assert_eq!(other().lock_overlay().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn tidying_drops_dirty_files_the_same_as_their_blob() {
+        let f = Fixture::new();
+        f.fs.options().write().unwrap().tidy = true;
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, libc::O_RDWR).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        f.fs.do_write(a, fh, 0, b"hello").unwrap();
+        f.fs.release_handle(a, fh);
+        assert!(!f.root.join("overlay/a.txt").exists());
+        assert_eq!(f.lookup(Ino::ROOT, "a.txt"), a);
+        assert!(matches!(f.fs.inomap().get(a).unwrap().u, EntryKind::GitBlob { .. }));
+        let fh = f.fs.do_open(a, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(a, fh, 0, 100).unwrap(), b"hello world");
+        f.fs.handles().remove(fh);
+
+        // Changed files stay, until they're the same again.
+        let dir = f.lookup(Ino::ROOT, "dir");
+        let b = f.lookup(dir, "b.txt");
+        let fh = f.fs.do_open(b, libc::O_RDWR).unwrap();
+        f.fs.do_write(b, fh, 0, b"IN").unwrap();
+        f.fs.release_handle(b, fh);
+        assert!(f.root.join("overlay/dir/b.txt").exists());
+        let fh = f.fs.do_open(b, libc::O_RDWR).unwrap();
+        f.fs.do_write(b, fh, 0, b"in").unwrap();
+        assert_eq!(f.fs.tidy(), 0, "open files stay");
+        f.fs.handles().remove(fh);
+        assert_eq!(f.fs.tidy(), 1);
+        assert!(!f.root.join("overlay/dir/b.txt").exists());
+        assert_eq!(f.fs.do_getattr(b).unwrap().size, 8);
+    }
+
+    #[test]
+    fn clean_entries_are_owned_as_configured() {
+        let f = Fixture::new();
+        {
+            let options = f.fs.options();
+            let mut options = options.write().unwrap();
+            options.uid = Some(1234);
+            options.gid = Some(5678);
+        }
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let attr = f.fs.do_getattr(a).unwrap();
+        assert_eq!((attr.uid, attr.gid), (1234, 5678));
+
+        // Dirty files are owned by whoever owns them in the overlay.
+        let fh = f.fs.do_open(a, libc::O_RDWR).unwrap();
+        f.fs.do_write(a, fh, 0, b"x").unwrap();
+        let attr = f.fs.do_getattr(a).unwrap();
+        assert_eq!((attr.uid, attr.gid), unsafe { (libc::getuid(), libc::getgid()) });
+    }
+
+    #[test]
+    fn read_only_mounts_refuse_changes() {
+        let f = Fixture::new();
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let rw = f.fs.do_open(a, libc::O_RDWR).unwrap();
+        f.fs.options().write().unwrap().read_only = true;
+
+        assert_eq!(f.errno(f.fs.do_write(a, rw, 0, b"x")), EROFS);
+        assert_eq!(f.errno(f.fs.do_open(f.lookup(Ino::ROOT, "a.txt"), libc::O_WRONLY)), EROFS);
+        let chmod = SetAttr {
+            mode: Some(0o600),
+            ..SetAttr::default()
+        };
+        assert_eq!(f.errno(f.fs.do_setattr(a, chmod)), EROFS);
+        assert_eq!(f.errno(f.fs.do_create(Ino::ROOT, OsStr::new("new"), 0o644)), EROFS);
+        assert_eq!(f.errno(f.fs.do_mkdir(Ino::ROOT, OsStr::new("new"), 0o755)), EROFS);
+        assert_eq!(f.errno(f.fs.do_remove(Ino::ROOT, OsStr::new("a.txt"))), EROFS);
+        assert_eq!(f.errno(f.fs.do_rename(Ino::ROOT, OsStr::new("a.txt"), Ino::ROOT, OsStr::new("b"))), EROFS);
+        assert_eq!(f.errno(f.fs.do_setxattr(a, OsStr::new("security.test"), b"x", 0)), EROFS);
+        assert_eq!(f.errno(f.fs.commit("change")), EROFS);
+
+        // Clean files are never materialized, but can still be read.
+        let dir = f.lookup(Ino::ROOT, "dir");
+        f.fs.do_opendir(dir).unwrap();
+        let b = f.lookup(dir, "b.txt");
+        assert_eq!(f.errno(f.fs.do_open(b, libc::O_RDWR)), EROFS);
+        let fh = f.fs.do_open(b, libc::O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(b, fh, 0, 100).unwrap(), b"in a dir");
+        assert!(!f.root.join("overlay/dir/b.txt").exists());
+        let fh = f.fs.do_open(a, libc::O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(a, fh, 0, 100).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn materialized_files_are_linked_from_the_blob_store() {
+        let f = Fixture::new();
+        let store = f.root.join("store");
+        f.fs.options().write().unwrap().blob_store = Some(store.clone());
+        let hex = f.blobs["a.txt"].to_string();
+        let stored = store.join(&hex[..2]).join(&hex[2..]);
+
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        assert_eq!(std::fs::metadata(&stored).unwrap().nlink(), 2);
+        assert_eq!(std::fs::metadata(&stored).unwrap().permissions().mode() & 0o777, 0o644);
+        let read = f.fs.do_open(a, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(a, read, 0, 100).unwrap(), b"hello world");
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        // The link is broken before the write, and open handles see it.
+        assert_eq!(f.fs.do_read(a, read, 0, 100).unwrap(), b"HELLO world");
+        assert_eq!(std::fs::read(f.root.join("overlay/a.txt")).unwrap(), b"HELLO world");
+        assert_eq!(std::fs::read(&stored).unwrap(), b"hello world");
+        assert_eq!(std::fs::metadata(&stored).unwrap().nlink(), 1);
+        f.fs.handles().remove(read);
+        f.fs.handles().remove(fh);
+        // Nothing is left aside, and the unused blob is collected.
+        assert_eq!(std::fs::read_dir(&store).unwrap().count(), 1);
+        assert_eq!(store::collect(&store).unwrap(), (1, 11));
+        assert!(!stored.exists());
+
+        // Changing times breaks it too.
+        let b = f.lookup(f.lookup(Ino::ROOT, "dir"), "b.txt");
+        f.fs.do_open(b, libc::O_WRONLY).map(|fh| f.fs.handles().remove(fh)).unwrap();
+        let hex = f.blobs["b.txt"].to_string();
+        let stored = store.join(&hex[..2]).join(&hex[2..]);
+        assert_eq!(std::fs::metadata(&stored).unwrap().nlink(), 2);
+        let mtime = Some(TimeOrNow::SpecificTime(std::time::UNIX_EPOCH));
+        f.fs.do_setattr(b, SetAttr { mtime, ..SetAttr::default() }).unwrap();
+        assert_eq!(std::fs::metadata(&stored).unwrap().nlink(), 1);
+        assert_eq!(std::fs::metadata(f.root.join("overlay/dir/b.txt")).unwrap().modified().unwrap(), std::time::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn setattr_sets_the_length_of_files() {
+        let f = Fixture::new();
+        let truncate = |ino, size| f.fs.do_setattr(ino, SetAttr { size: Some(size), ..SetAttr::default() });
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(truncate(a, 5).unwrap().size, 5);
+        assert_eq!(std::fs::read(f.root.join("overlay/a.txt")).unwrap(), b"hello");
+        assert_eq!(truncate(a, 7).unwrap().size, 7);
+        let fh = f.fs.do_open(a, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(a, fh, 0, 100).unwrap(), b"hello\0\0");
+        f.fs.handles().remove(fh);
+
+        // As O_TRUNC is done, after the open.
+        let b = f.lookup(f.lookup(Ino::ROOT, "dir"), "b.txt");
+        let fh = f.fs.do_open(b, libc::O_WRONLY).unwrap();
+        truncate(b, 0).unwrap();
+        f.fs.do_write(b, fh, 0, b"new").unwrap();
+        f.fs.handles().remove(fh);
+        assert_eq!(std::fs::read(f.root.join("overlay/dir/b.txt")).unwrap(), b"new");
+        assert_eq!(f.errno(truncate(f.lookup(Ino::ROOT, "dir"), 0)), EISDIR);
+    }
+
+    #[test]
+    fn encrypted_overlays_read_back_and_commit() {
+        let mut f = Fixture::new();
+        let mount = |f: &Fixture| {
+            let repo = Repository::open(f.root.join("repo")).unwrap();
+            let overlay = Dir::open(&f.root.join("overlay")).unwrap();
+            let fs = GitFS::builder(repo, overlay).overlay_key(OverlayKey::from([7; 32])).build();
+            fs.mount_root().unwrap();
+            fs
+        };
+        f.fs = mount(&f);
+
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, libc::O_RDWR).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        assert_eq!(f.fs.do_read(a, fh, 0, 100).unwrap(), b"HELLO world");
+        f.fs.do_setattr(a, SetAttr { size: Some(9), ..SetAttr::default() }).unwrap();
+        assert_eq!(f.fs.do_read(a, fh, 0, 100).unwrap(), b"HELLO wor");
+        let (attr, new_fh) = f.fs.do_create(Ino::ROOT, OsStr::new("new.txt"), 0o644).unwrap();
+        f.fs.do_write(attr.ino.into(), new_fh, 0, b"secret").unwrap();
+        f.fs.handles().remove(fh);
+        f.fs.handles().remove(new_fh);
+        let raw = std::fs::read(f.root.join("overlay/a.txt")).unwrap();
+        assert!(!raw.windows(5).any(|w| w == b"world"));
+
+        // Another mount with the same key sees the same files.
+        f.fs = mount(&f);
+        let new = f.lookup(Ino::ROOT, "new.txt");
+        assert_eq!(f.fs.do_getattr(new).unwrap().size, 6);
+        {
+            let repo = f.fs.repo();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+        }
+        let commit = f.fs.commit("encrypted").unwrap();
+        let repo = f.fs.repo();
+        let tree = repo.find_commit(commit).unwrap().tree().unwrap();
+        let content = |name: &str| repo.find_blob(tree.get_name(name).unwrap().id()).unwrap().content().to_vec();
+        assert_eq!(content("a.txt"), b"HELLO wor");
+        assert_eq!(content("new.txt"), b"secret");
+    }
+
+    #[test]
+    fn subdir_is_mounted_at_the_root() {
+        let mut f = Fixture::new();
+        let repo = Repository::open(f.root.join("repo")).unwrap();
+        f.fs = GitFS::builder(repo, Dir::open(&f.root.join("overlay")).unwrap()).subdir(Path::new("dir")).build();
+        f.fs.mount_root().unwrap();
+        assert_eq!(f.errno(f.fs.do_lookup(Ino::ROOT, OsStr::new("a.txt"))), ENOENT);
+        let b = f.lookup(Ino::ROOT, "b.txt");
+        let fh = f.fs.do_open(b, O_RDONLY).unwrap();
+        let content = f.fs.do_read(b, fh, 0, 100).unwrap();
+        f.fs.handles().remove(fh);
+
+        let (attr, fh) = f.fs.do_create(Ino::ROOT, OsStr::new("c.txt"), 0o644).unwrap();
+        f.fs.do_write(attr.ino.into(), fh, 0, b"new").unwrap();
+        f.fs.handles().remove(fh);
+        {
+            let repo = f.fs.repo();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+        }
+        let commit = f.fs.commit("in dir").unwrap();
+        {
+            // The rest of the tree is as it was.
+            let repo = f.fs.repo();
+            let tree = repo.find_commit(commit).unwrap().tree().unwrap();
+            let content = |path: &str| repo.find_blob(tree.get_path(Path::new(path)).unwrap().id()).unwrap().content().to_vec();
+            assert_eq!(content("a.txt"), b"hello world");
+            assert_eq!(content("dir/c.txt"), b"new");
+        }
+        let b = f.lookup(Ino::ROOT, "b.txt");
+        let fh = f.fs.do_open(b, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(b, fh, 0, 100).unwrap(), content);
+        f.fs.handles().remove(fh);
+        assert!(f.read_control("status").ends_with("\n\n"));
+
+        // A commit without the dir can't be switched to.
+        let a = f.blobs["a.txt"];
+        let commit = {
+            let repo = f.fs.repo();
+            let mut top = repo.treebuilder(None).unwrap();
+            top.insert("a.txt", a, 0o100644).unwrap();
+            let tree = repo.find_tree(top.write().unwrap()).unwrap();
+            let sig = git2::Signature::now("test", "test@example.com").unwrap();
+            repo.commit(None, &sig, &sig, "test", &tree, &[]).unwrap()
+        };
+        assert_eq!(f.errno(f.fs.checkout(&commit.to_string())), ENOENT);
+    }
+
+    #[test]
+    fn smudged_files_read_as_checked_out() {
+        let f = Fixture::new();
+        f.fs.options().write().unwrap().smudge = true;
+        let attributes = f.fs.repo().blob(b"*.txt text eol=crlf ident\n").unwrap();
+        let text = f.fs.repo().blob(b"one\ntwo $Id$\n").unwrap();
+        f.checkout(&[(".gitattributes", attributes, 0o100644), ("a.txt", text, 0o100644)]);
+
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let smudged = format!("one\r\ntwo $Id: {} $\r\n", text);
+        assert_eq!(f.fs.do_getattr(a).unwrap().size, smudged.len() as u64);
+        let fh = f.fs.do_open(a, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(a, fh, 0, 100).unwrap(), smudged.as_bytes());
+        let attributes_ino = f.lookup(Ino::ROOT, ".gitattributes");
+        assert_eq!(f.fs.do_getattr(attributes_ino).unwrap().size, 26);
+
+        // Checked out as it reads, which is no change until written.
+        let fh = f.fs.do_open(a, libc::O_RDWR).unwrap();
+        assert_eq!(std::fs::read(f.root.join("overlay/a.txt")).unwrap(), smudged.as_bytes());
+        assert!(f.fs.changes().unwrap().is_empty());
+        f.fs.do_write(a, fh, 0, b"ONE").unwrap();
+        let changes = f.fs.changes().unwrap();
+        let repo = f.fs.repo();
+        let base = repo.find_commit(f.fs.head().commit.unwrap()).unwrap().tree().unwrap();
+        let tree = repo.find_tree(f.fs.apply_changes(&repo, &base, &changes).unwrap()).unwrap();
+        let blob = repo.find_blob(tree.get_name("a.txt").unwrap().id()).unwrap();
+        assert_eq!(blob.content(), b"ONE\ntwo $Id$\n");
+    }
+
+    #[test]
+    fn materialized_files_keep_their_mode() {
+        let f = Fixture::new();
+        f.checkout(&[("a.txt", f.blobs["a.txt"], 0o100644), ("run.sh", f.blobs["b.txt"], 0o100755)]);
+
+        let overlay_mode = |name: &str| {
+            std::fs::metadata(f.root.join("overlay").join(name)).unwrap().permissions().mode() & 0o777
+        };
+        let run = f.lookup(Ino::ROOT, "run.sh");
+        f.fs.do_open(run, libc::O_WRONLY).unwrap();
+        assert_eq!(overlay_mode("run.sh"), 0o755);
+        assert_eq!(f.fs.do_getattr(run).unwrap().perm & 0o777, 0o755);
+
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        assert_eq!(overlay_mode("a.txt"), 0o644);
+    }
+
+    #[test]
+    fn open_flags_reach_the_underlying_file() {
+        let f = Fixture::new();
+        let status_flags = |fh: u64| {
+            let handles = f.fs.handles();
+            let file = handles.get(fh).unwrap().file.clone().unwrap();
+            unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) }
+        };
+        // Materialized, then opened as asked.
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, libc::O_WRONLY | libc::O_SYNC | libc::O_APPEND).unwrap();
+        let flags = status_flags(fh);
+        assert_eq!(flags & O_ACCMODE, libc::O_WRONLY);
+        assert_eq!(flags & libc::O_SYNC, libc::O_SYNC);
+        assert_eq!(flags & libc::O_APPEND, 0);
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+
+        // Dirty already.
+        let fh = f.fs.do_open(a, O_RDWR | libc::O_DSYNC).unwrap();
+        let flags = status_flags(fh);
+        assert_eq!(flags & O_ACCMODE, O_RDWR);
+        assert_eq!(flags & libc::O_SYNC, libc::O_DSYNC);
+
+        // Opened on first read, with the flags of the handle.
+        let fh = f.fs.do_open(a, O_RDONLY | O_NOATIME).unwrap();
+        assert!(f.fs.handles().get(fh).unwrap().file.is_none());
+        assert_eq!(f.fs.do_read(a, fh, 0, 5).unwrap(), b"HELLO");
+        let flags = status_flags(fh);
+        assert_eq!(flags & O_ACCMODE, O_RDONLY);
+        assert_eq!(flags & O_NOATIME, O_NOATIME);
+    }
+
+    #[test]
+    fn symlinks_follow_the_repo_config() {
+        let f = Fixture::new();
+        let target = f.fs.repo().blob(b"a.txt").unwrap();
+        f.checkout(&[("a.txt", f.blobs["a.txt"], 0o100644), ("link", target, 0o120000)]);
+        let link = f.lookup(Ino::ROOT, "link");
+        assert_eq!(f.fs.do_getattr(link).unwrap().kind, FileType::Symlink);
+        assert_eq!(f.fs.do_readlink(link).unwrap(), b"a.txt");
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(f.errno(f.fs.do_readlink(a)), EINVAL);
+
+        let f = Fixture::with_config(&[("core.symlinks", false)]);
+        let target = f.fs.repo().blob(b"a.txt").unwrap();
+        f.checkout(&[("link", target, 0o120000)]);
+        let link = f.lookup(Ino::ROOT, "link");
+        let attr = f.fs.do_getattr(link).unwrap();
+        assert_eq!((attr.kind, attr.perm & 0o777), (FileType::RegularFile, 0o644));
+        let fh = f.fs.do_open(link, libc::O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(link, fh, 0, 100).unwrap(), b"a.txt");
+    }
+
+    #[test]
+    fn chmod_is_committed() {
+        let f = Fixture::new();
+        let chmod = |ino, mode| f.fs.do_setattr(ino, SetAttr { mode: Some(mode), ..SetAttr::default() }).unwrap();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        f.fs.handles().remove(fh);
+        chmod(a, MODE_FILE | 0o755);
+        // Made executable before it's materialized.
+        let b = f.lookup(f.lookup(Ino::ROOT, "dir"), "b.txt");
+        chmod(b, MODE_FILE | 0o755);
+        let fh = f.fs.do_open(b, libc::O_WRONLY).unwrap();
+        f.fs.do_write(b, fh, 0, b"IN").unwrap();
+        f.fs.handles().remove(fh);
+        // Changed from outside, so that the root is listed again.
+        std::fs::write(f.root.join("overlay/other.txt"), "other").unwrap();
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        assert_eq!(f.fs.do_getattr(f.lookup(Ino::ROOT, "a.txt")).unwrap().perm & 0o777, 0o755);
+
+        {
+            let repo = f.fs.repo();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+        }
+        let commit = f.fs.commit("chmod +x").unwrap();
+        let repo = f.fs.repo();
+        let tree = repo.find_commit(commit).unwrap().tree().unwrap();
+        for path in ["a.txt", "dir/b.txt"].iter() {
+            assert_eq!(tree.get_path(Path::new(path)).unwrap().filemode(), 0o100755, "{}", path);
+        }
+    }
+
+    #[test]
+    fn file_modes_follow_the_repo_config() {
+        for &file_mode in [true, false].iter() {
+            let f = Fixture::with_config(&[("core.fileMode", file_mode)]);
+            let overlay = f.root.join("overlay");
+            std::fs::write(overlay.join("run.sh"), b"edited").unwrap();
+            std::fs::set_permissions(overlay.join("run.sh"), Permissions::from_mode(0o644)).unwrap();
+            std::fs::write(overlay.join("new"), b"new").unwrap();
+            std::fs::set_permissions(overlay.join("new"), Permissions::from_mode(0o700)).unwrap();
+            f.checkout(&[("run.sh", f.blobs["b.txt"], 0o100755)]);
+
+            let perm = |name| f.fs.do_getattr(f.lookup(Ino::ROOT, name)).unwrap().perm & 0o777;
+            if file_mode {
+                assert_eq!((perm("run.sh"), perm("new")), (0o644, 0o700));
+            } else {
+                assert_eq!((perm("run.sh"), perm("new")), (0o755, 0o644));
+            }
+        }
+    }
+
+    #[test]
+    fn external_changes_to_the_overlay_show_up() {
+        let f = Fixture::new();
+        let overlay = f.root.join("overlay");
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        assert_eq!(f.fs.do_getattr(a).unwrap().size, 11);
+
+        std::fs::write(overlay.join("a.txt"), b"edited elsewhere").unwrap();
+        assert_eq!(f.fs.do_getattr(a).unwrap().size, 16);
+
+        f.fs.do_remove(Ino::ROOT, OsStr::new("broken.txt")).unwrap();
+        std::fs::write(overlay.join("new.txt"), b"new").unwrap();
+        let new = f.lookup(Ino::ROOT, "new.txt");
+        assert_eq!(f.fs.do_getattr(new).unwrap().size, 3);
+        assert_eq!(f.lookup(Ino::ROOT, "a.txt"), a);
+        assert_eq!(f.errno(f.fs.do_lookup(Ino::ROOT, OsStr::new("broken.txt"))), ENOENT);
+
+        std::fs::remove_file(overlay.join("new.txt")).unwrap();
+        assert_eq!(f.errno(f.fs.do_lookup(Ino::ROOT, OsStr::new("new.txt"))), ENOENT);
+        assert_eq!(f.errno(f.fs.do_getattr(new)), ENOENT);
+    }
+
+    #[test]
+    fn watcher_follows_the_mounted_ref() {
+        let f = Fixture::new();
+        let watcher = f.fs.watch(Duration::from_millis(10)).unwrap();
+        {
+            let repo = f.fs.repo();
+            let mut top = repo.treebuilder(None).unwrap();
+            top.insert("c.txt", f.blobs["a.txt"], 0o100644).unwrap();
+            let tree = repo.find_tree(top.write().unwrap()).unwrap();
+            let parent = repo.head().unwrap().peel_to_commit().unwrap();
+            let sig = git2::Signature::now("test", "test@example.com").unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "next", &tree, &[&parent]).unwrap();
+        }
+        let deadline = SystemTime::now() + Duration::from_secs(10);
+        while f.fs.do_lookup(Ino::ROOT, OsStr::new("c.txt")).is_err() {
+            assert!(SystemTime::now() < deadline, "the commit never showed up");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(watcher);
+        assert_eq!(f.errno(f.fs.do_lookup(Ino::ROOT, OsStr::new("a.txt"))), ENOENT);
+    }
+
+    #[test]
+    fn remote_watcher_follows_the_fetched_branch() {
+        let f = Fixture::new();
+        let upstream = Repository::init(f.root.join("upstream")).unwrap();
+        let (blobs, _) = Fixture::populate(&upstream);
+        let branch = upstream.head().unwrap().shorthand().unwrap().to_owned();
+        let mirror = Repository::init(f.root.join("mirror")).unwrap();
+        mirror.remote("origin", f.root.join("upstream").to_str().unwrap()).unwrap();
+        crate::watch::fetch(mirror.path(), "origin", &branch).unwrap();
+        std::fs::create_dir_all(f.root.join("mirror-overlay")).unwrap();
+        let fs = GitFS::builder(mirror, Dir::open(&f.root.join("mirror-overlay")).unwrap())
+            .refspec(&format!("refs/remotes/origin/{}", branch))
+            .build();
+        fs.mount_root().unwrap();
+        let watcher = fs.watch_remote("origin", &branch, Duration::from_millis(10)).unwrap();
+        {
+            let mut top = upstream.treebuilder(None).unwrap();
+            top.insert("c.txt", blobs["a.txt"], 0o100644).unwrap();
+            let tree = upstream.find_tree(top.write().unwrap()).unwrap();
+            let parent = upstream.head().unwrap().peel_to_commit().unwrap();
+            let sig = git2::Signature::now("test", "test@example.com").unwrap();
+            upstream.commit(Some("HEAD"), &sig, &sig, "next", &tree, &[&parent]).unwrap();
+        }
+        let deadline = SystemTime::now() + Duration::from_secs(10);
+        while fs.do_lookup(Ino::ROOT, OsStr::new("c.txt")).is_err() {
+            assert!(SystemTime::now() < deadline, "the commit never showed up");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(watcher);
+        assert_eq!(fs.errno(&fs.do_lookup(Ino::ROOT, OsStr::new("a.txt")).unwrap_err()), ENOENT);
+    }
+
+    #[test]
+    fn root_has_the_times_of_the_overlay() {
+        let f = Fixture::new();
+        let metadata = std::fs::metadata(f.root.join("overlay")).unwrap();
+        let attr = f.fs.do_getattr(Ino::ROOT).unwrap();
+        assert_eq!(attr.mtime, metadata.modified().unwrap());
+        assert_eq!(attr.atime, metadata.accessed().unwrap());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd"))]
+    #[test]
+    fn root_has_the_birthtime_of_the_overlay() {
+        let f = Fixture::new();
+        let created = std::fs::metadata(f.root.join("overlay")).unwrap().created().unwrap();
+        assert_eq!(f.fs.do_getattr(Ino::ROOT).unwrap().crtime, created);
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd")))]
+    #[test]
+    fn root_has_no_birthtime() {
+        let f = Fixture::new();
+        assert_eq!(f.fs.do_getattr(Ino::ROOT).unwrap().crtime, SystemTime::UNIX_EPOCH);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn overlay_watcher_reports_changes() {
+        let f = Fixture::new();
+        let overlay = f.root.join("overlay");
+        let watcher = f.fs.watch_overlay().unwrap();
+        let wait_for = |what: &str, cond: &dyn Fn() -> bool| {
+            let deadline = SystemTime::now() + Duration::from_secs(10);
+            while !cond() {
+                assert!(SystemTime::now() < deadline, "{}", what);
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+        wait_for("never watched", &|| f.fs.inner.overlay_watched.load(Ordering::SeqCst));
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        assert!(f.fs.inomap().get(Ino::ROOT).unwrap().stamp.is_some());
+
+        std::fs::create_dir(overlay.join("new")).unwrap();
+        wait_for("root never changed", &|| f.fs.inomap().get(Ino::ROOT).unwrap().stamp.is_none());
+        let new = f.lookup(Ino::ROOT, "new");
+        f.fs.do_opendir(new).unwrap();
+
+        // Dirs created later are watched as well.
+        std::fs::write(overlay.join("new/file"), b"x").unwrap();
+        wait_for("new dir never changed", &|| f.fs.inomap().get(new).unwrap().stamp.is_none());
+        assert_eq!(f.fs.do_getattr(f.lookup(new, "file")).unwrap().size, 1);
+
+        drop(watcher);
+        assert!(!f.fs.inner.overlay_watched.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn clean_entries_can_have_commit_times() {
+        let f = Fixture::new();
+        f.fs.options().write().unwrap().mtime = MtimePolicy::Commit;
+        let commit = {
+            let repo = f.fs.repo();
+            let commit = |time, a, parents: &[&git2::Commit]| {
+                let mut top = repo.treebuilder(None).unwrap();
+                top.insert("a.txt", a, 0o100644).unwrap();
+                top.insert("dir", f.trees["dir"], 0o040000).unwrap();
+                let tree = repo.find_tree(top.write().unwrap()).unwrap();
+                let sig = git2::Signature::new("test", "test@example.com", &git2::Time::new(time, 0)).unwrap();
+                repo.commit(None, &sig, &sig, "test", &tree, parents).unwrap()
+            };
+            let first = repo.find_commit(commit(1000, f.blobs["a.txt"], &[])).unwrap();
+            commit(2000, f.blobs["b.txt"], &[&first])
+        };
+        f.fs.checkout(&commit.to_string()).unwrap();
+
+        let mtime = |ino| f.fs.do_getattr(ino).unwrap().mtime;
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let dir = f.lookup(Ino::ROOT, "dir");
+        assert_eq!(mtime(f.lookup(Ino::ROOT, "a.txt")), at(2000));
+        assert_eq!(mtime(dir), at(1000));
+        assert_eq!(mtime(f.lookup(dir, "b.txt")), at(1000));
+    }
+
+    #[test]
+    fn chown_sticks() {
+        let f = Fixture::new();
+        let root = unsafe { libc::getuid() } == 0;
+        let chown = |uid, gid| SetAttr {
+            uid: Some(uid),
+            gid: Some(gid),
+            ..SetAttr::default()
+        };
+        let owner = |ino| {
+            let attr = f.fs.do_getattr(ino).unwrap();
+            (attr.uid, attr.gid)
+        };
+        let overlay_owner = |name| {
+            let metadata = std::fs::metadata(f.root.join("overlay").join(name)).unwrap();
+            (metadata.uid(), metadata.gid())
+        };
+
+        // Clean files keep the owner they are given once materialized,
+        // if gitfs may give it to them.
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        f.fs.do_setattr(a, chown(1234, 5678)).unwrap();
+        assert_eq!(owner(a), (1234, 5678));
+        f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        assert_eq!(owner(a), (1234, 5678));
+        if root {
+            assert_eq!(overlay_owner("a.txt"), (1234, 5678));
+        }
+
+        // Dirty files are changed in the underlying dir.
+        let (fh, new) = {
+            let (attr, fh) = f.fs.do_create(Ino::ROOT, OsStr::new("new"), 0o644).unwrap();
+            (fh, Ino::from(attr.ino))
+        };
+        f.fs.handles().remove(fh);
+        let me = unsafe { (libc::getuid(), libc::getgid()) };
+        f.fs.do_setattr(new, chown(me.0, me.1)).unwrap();
+        assert_eq!(owner(new), me);
+        let result = f.fs.do_setattr(new, chown(4321, 8765));
+        if root {
+            assert_eq!((owner(new), overlay_owner("new")), ((4321, 8765), (4321, 8765)));
+        } else {
+            assert_eq!(f.errno(result), libc::EPERM);
+            assert_eq!(overlay_owner("new"), me);
+        }
+    }
+
+    #[test]
+    fn flags_of_clean_entries_stick() {
+        let f = Fixture::new();
+        let (hidden, immutable) = (0x8000, 0x20000);
+        let chflags = |flags| SetAttr {
+            flags: Some(flags),
+            ..SetAttr::default()
+        };
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(f.fs.do_getattr(a).unwrap().flags, 0);
+        assert_eq!(f.fs.do_setattr(a, chflags(hidden)).unwrap().flags, hidden);
+        assert_eq!(f.fs.do_getattr(a).unwrap().flags, hidden);
+        let dir = f.lookup(Ino::ROOT, "dir");
+        assert_eq!(f.fs.do_setattr(dir, chflags(hidden)).unwrap().flags, hidden);
+        // System flags take the superuser, like in the underlying dir.
+        assert_eq!(f.errno(f.fs.do_setattr(f.lookup(dir, "b.txt"), chflags(immutable))), libc::EPERM);
+        assert_eq!(f.fs.do_getattr(f.lookup(dir, "b.txt")).unwrap().flags, 0);
+    }
+
+    #[cfg(feature = "faults")]
+    #[test]
+    fn injected_faults_fail_the_operations() {
+        let f = Fixture::new();
+        let faults: Arc<Mutex<HashMap<&str, Fault>>> = Arc::default();
+        let fs = {
+            let faults = faults.clone();
+            let repo = Repository::open(f.root.join("repo")).unwrap();
+            GitFS::builder(repo, Dir::open(&f.root.join("overlay")).unwrap())
+                .fault_injector(move |site| {
+                    let kind = match site {
+                        Site::Overlay(_) => "overlay",
+                        Site::Object(_) => "object",
+                        Site::Read(_) => "read",
+                        Site::Write(_) => "write",
+                    };
+                    faults.lock().unwrap().get(kind).copied()
+                })
+                .build()
+        };
+        fs.mount_root().unwrap();
+        let inject = |kind, fault: Option<Fault>| {
+            let mut faults = faults.lock().unwrap();
+            faults.clear();
+            faults.extend(fault.map(|fault| (kind, fault)));
+        };
+        let errno = |result: Result<(), Error>| fs.errno(&result.unwrap_err());
+        let lookup = |parent, name| Ino::from(fs.do_lookup(parent, OsStr::new(name)).unwrap().ino);
+        let a = lookup(Ino::ROOT, "a.txt");
+        let fh = fs.do_open(a, O_RDONLY).unwrap();
+
+        inject("read", Some(Fault::Short(5)));
+        assert_eq!(fs.do_read(a, fh, 0, 100).unwrap(), b"hello");
+        inject("read", Some(Fault::Errno(libc::EIO)));
+        assert_eq!(errno(fs.do_read(a, fh, 0, 100).map(drop)), libc::EIO);
+        inject("object", Some(Fault::MissingObject));
+        assert_eq!(errno(fs.do_read(a, fh, 0, 100).map(drop)), libc::EIO);
+        assert_eq!(errno(fs.do_opendir(lookup(Ino::ROOT, "dir")).map(drop)), libc::EIO);
+        inject("overlay", Some(Fault::Errno(libc::ENOSPC)));
+        assert_eq!(errno(fs.do_open(a, libc::O_WRONLY).map(drop)), libc::ENOSPC);
+
+        inject("write", None);
+        let fh = fs.do_open(a, libc::O_WRONLY).unwrap();
+        inject("write", Some(Fault::Short(2)));
+        assert_eq!(fs.do_write(a, fh, 0, b"HELLO").unwrap(), 2);
+        inject("write", Some(Fault::Errno(libc::ENOSPC)));
+        assert_eq!(errno(fs.do_write(a, fh, 2, b"LLO").map(drop)), libc::ENOSPC);
+        inject("write", None);
+        assert_eq!(std::fs::read(f.root.join("overlay/a.txt")).unwrap(), b"HEllo world");
+    }
+
+    #[test]
+    fn self_check_reports_what_is_broken() {
+        let f = Fixture::new();
+        assert_eq!(f.fs.list_all(), Vec::<String>::new());
+        assert_eq!(f.fs.self_check(), Vec::<String>::new());
+
+        let dir = f.lookup(Ino::ROOT, "dir");
+        let b = f.lookup(dir, "b.txt");
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        f.fs.inomap().get_mut(b).unwrap().parent = Ino::ROOT;
+        f.fs.inomap().remove(a);
+        let problems = f.fs.self_check();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems.contains(&format!("ino 1 lists \"a.txt\" as ino {}, which is missing", a.0)));
+        assert!(problems.contains(&format!("ino {} lists \"b.txt\" as ino {}, which is \"b.txt\" in ino 1", dir.0, b.0)));
+        assert!(problems.contains(&format!("ino {} (\"b.txt\") is not listed in its parent, ino 1", b.0)));
+
+        // Dirs whose tree is gone can't be listed.
+        let f = Fixture::new();
+        f.remove_object(f.trees["dir"]);
+        let problems = f.fs.list_all();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("cannot list /dir: git error: object not found"), "{:?}", problems);
+    }
+
+    #[test]
+    fn times_of_dirty_files_are_kept_in_the_overlay() {
+        let f = Fixture::new();
+        let (fh, new) = {
+            let (attr, fh) = f.fs.do_create(Ino::ROOT, OsStr::new("new"), 0o644).unwrap();
+            (fh, Ino::from(attr.ino))
+        };
+        f.fs.handles().remove(fh);
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let attrs = SetAttr {
+            mtime: Some(TimeOrNow::SpecificTime(time)),
+            ..SetAttr::default()
+        };
+        assert_eq!(f.fs.do_setattr(new, attrs).unwrap().mtime, time);
+        let metadata = std::fs::metadata(f.root.join("overlay/new")).unwrap();
+        assert_eq!(metadata.modified().unwrap(), time);
+        assert_ne!(metadata.accessed().unwrap(), time);
+
+        let before = SystemTime::UNIX_EPOCH - Duration::from_millis(1500);
+        let attrs = SetAttr {
+            atime: Some(TimeOrNow::SpecificTime(before)),
+            ..SetAttr::default()
+        };
+        f.fs.do_setattr(new, attrs).unwrap();
+        let metadata = std::fs::metadata(f.root.join("overlay/new")).unwrap();
+        assert_eq!((metadata.atime(), metadata.atime_nsec()), (-2, 500_000_000));
+        assert_eq!(metadata.modified().unwrap(), time);
+
+        // Read back, also once listed again, as for `touch -d 1960-01-01`.
+        let dir = f.fs.do_mkdir(Ino::ROOT, OsStr::new("new dir"), 0o755).map(|attr| Ino::from(attr.ino)).unwrap();
+        let before = SystemTime::UNIX_EPOCH - Duration::new(315_619_200, 250_000_000);
+        for &ino in [new, dir].iter() {
+            let attrs = SetAttr {
+                mtime: Some(TimeOrNow::SpecificTime(before)),
+                ..SetAttr::default()
+            };
+            f.fs.do_setattr(ino, attrs).unwrap();
+            assert_eq!(f.fs.do_getattr(ino).unwrap().mtime, before);
+        }
+        // Changed from outside, so that the root is listed again.
+        std::fs::write(f.root.join("overlay/other.txt"), "other").unwrap();
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        for name in ["new", "new dir"].iter() {
+            assert_eq!(f.fs.do_getattr(f.lookup(Ino::ROOT, name)).unwrap().mtime, before);
+        }
+    }
+
+    #[test]
+    fn control_dir_is_hidden_but_there() {
+        let f = Fixture::new();
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        let names: Vec<_> = f.fs.do_readdir(Ino::ROOT).unwrap().into_iter().map(|(name, _, _)| name).collect();
+        assert!(!names.contains(&OsString::from(control::NAME)));
+
+        let dir = f.lookup(Ino::ROOT, control::NAME);
+        assert_eq!(f.fs.do_getattr(dir).unwrap().kind, FileType::Directory);
+        f.fs.do_opendir(dir).unwrap();
+        let names: Vec<_> = f.fs.do_readdir(dir).unwrap().into_iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, ["archive", "blame", "ctl", "health", "layer.tar", "layer.tar.gz", "lfs", "log", "objects", "stats", "status"].iter().map(OsString::from).collect::<Vec<_>>());
+        let status = f.lookup(dir, "status");
+        assert_eq!(f.errno(f.fs.do_open(status, libc::O_WRONLY)), libc::EACCES);
+        assert_eq!(f.errno(f.fs.do_create(dir, OsStr::new("new"), 0o644)), libc::EPERM);
+        assert_eq!(f.errno(f.fs.do_mkdir(Ino::ROOT, OsStr::new(control::NAME), 0o755)), libc::EPERM);
+        assert_eq!(f.errno(f.fs.do_rename(Ino::ROOT, OsStr::new("a.txt"), dir, OsStr::new("a.txt"))), libc::EPERM);
+
+        f.fs.options().write().unwrap().control_dir = false;
+        assert_eq!(f.errno(f.fs.do_lookup(Ino::ROOT, OsStr::new(control::NAME))), ENOENT);
+    }
+
+    #[test]
+    fn git_file_points_at_the_repository() {
+        let f = Fixture::new();
+        assert_eq!(f.errno(f.fs.do_lookup(Ino::ROOT, OsStr::new(".git"))), ENOENT);
+        f.fs.options().write().unwrap().git_file = true;
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        let names: Vec<_> = f.fs.do_readdir(Ino::ROOT).unwrap().into_iter().map(|(name, _, _)| name).collect();
+        assert!(!names.contains(&OsString::from(".git")));
+
+        let git = f.lookup(Ino::ROOT, ".git");
+        let attr = f.fs.do_getattr(git).unwrap();
+        let fh = f.fs.do_open(git, O_RDONLY).unwrap();
+        let content = f.fs.do_read(git, fh, 0, 1 << 20).unwrap();
+        f.fs.control_release(fh);
+        assert_eq!((attr.kind, attr.size), (FileType::RegularFile, content.len() as u64));
+
+        // Git finds the repository from a dir with that file in it.
+        let worktree = f.root.join("worktree");
+        std::fs::create_dir(&worktree).unwrap();
+        std::fs::write(worktree.join(".git"), &content).unwrap();
+        let found = Repository::open(&worktree).unwrap();
+        assert_eq!(found.path(), f.fs.repo().path());
+        assert_eq!(found.workdir(), Some(worktree.join("").as_path()));
+
+        assert_eq!(f.errno(f.fs.do_open(git, libc::O_WRONLY)), libc::EACCES);
+        assert_eq!(f.errno(f.fs.do_remove(Ino::ROOT, OsStr::new(".git"))), libc::EPERM);
+        assert_eq!(f.errno(f.fs.do_rename(Ino::ROOT, OsStr::new("a.txt"), Ino::ROOT, OsStr::new(".git"))), libc::EPERM);
+    }
+
+    #[test]
+    fn status_lists_dirty_paths() {
+        let f = Fixture::new();
+        let head = f.fs.head().commit.unwrap();
+        assert_eq!(
+            f.read_control("status"),
+            format!("ref: HEAD\ncommit: {}\nread-only: no\n\n", head)
+        );
+
+        f.checkout(&[
+            ("a.txt", f.blobs["a.txt"], 0o100644),
+            ("same.txt", f.blobs["b.txt"], 0o100644),
+            ("dir", f.trees["dir"], 0o040000),
+        ]);
+        // Changed, materialized but the same, added and removed.
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        f.fs.handles().remove(fh);
+        let same = f.lookup(Ino::ROOT, "same.txt");
+        let fh = f.fs.do_open(same, libc::O_WRONLY).unwrap();
+        f.fs.handles().remove(fh);
+        let (_, fh) = f.fs.do_create(Ino::ROOT, OsStr::new("new"), 0o644).unwrap();
+        f.fs.handles().remove(fh);
+        let dir = f.lookup(Ino::ROOT, "dir");
+        f.fs.do_opendir(dir).unwrap();
+        f.fs.do_remove(dir, OsStr::new("b.txt")).unwrap();
+        f.fs.options().write().unwrap().read_only = true;
+
+        let status = f.read_control("status");
+        assert!(status.contains("read-only: yes\n"));
+        let dirty: Vec<_> = status.lines().skip_while(|line| !line.is_empty()).skip(1).collect();
+        assert_eq!(dirty, vec!["M\ta.txt", "D\tdir/b.txt", "A\tnew"]);
+    }
+
+    #[test]
+    fn ctl_runs_commands() {
+        let f = Fixture::new();
+        f.fs.repo().config().unwrap().set_str("user.name", "test").unwrap();
+        f.fs.repo().config().unwrap().set_str("user.email", "test@example.com").unwrap();
+        let parent = f.fs.head().commit.unwrap();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        f.fs.handles().remove(fh);
+
+        let dir = f.lookup(Ino::ROOT, control::NAME);
+        let ctl = f.lookup(dir, "ctl");
+        let fh = f.fs.do_open(ctl, libc::O_RDWR).unwrap();
+        // Nothing runs until the line is complete.
+        f.fs.do_write(ctl, fh, 0, b"commit ").unwrap();
+        assert_eq!(f.fs.head().commit, Some(parent));
+        f.fs.do_write(ctl, fh, 7, b"msg\n").unwrap();
+        let commit = f.fs.head().commit.unwrap();
+        assert_ne!(commit, parent);
+        let results = f.fs.do_read(ctl, fh, 0, 4096).unwrap();
+        assert_eq!(results, format!("ok {}\n", commit).into_bytes());
+        {
+            let repo = f.fs.repo();
+            let commit = repo.find_commit(commit).unwrap();
+            assert_eq!(commit.message(), Some("msg"));
+            assert_eq!(commit.parent_id(0).unwrap(), parent);
+            assert_eq!(repo.head().unwrap().target(), Some(commit.id()));
+            let a = commit.tree().unwrap().get_path(Path::new("a.txt")).unwrap().id();
+            assert_eq!(repo.find_blob(a).unwrap().content(), b"HELLO world");
+        }
+        assert!(f.read_control("status").ends_with("read-only: no\n\n"));
+
+        assert_eq!(f.errno(f.fs.do_write(ctl, fh, 0, b"refresh\nreboot\n")), libc::EINVAL);
+        let results = f.fs.do_read(ctl, fh, 0, 4096).unwrap();
+        assert_eq!(results, format!("ok {}\nerror: unknown command: reboot\n", commit).into_bytes());
+        f.fs.control_release(fh);
+    }
+
+    #[test]
+    fn steps_go_through_the_history() {
+        let f = Fixture::new();
+        let first = f.fs.head().commit.unwrap();
+        let (second, third) = {
+            let repo = f.fs.repo();
+            let sig = git2::Signature::now("test", "test@example.com").unwrap();
+            let first = repo.find_commit(first).unwrap();
+            let tree = first.tree().unwrap();
+            let second = repo.commit(Some("HEAD"), &sig, &sig, "second", &tree, &[&first]).unwrap();
+            let second = repo.find_commit(second).unwrap();
+            let third = repo.commit(Some("HEAD"), &sig, &sig, "third", &tree, &[&second]).unwrap();
+            (second.id(), third)
+        };
+        f.fs.refresh().unwrap();
+
+        assert_eq!(f.fs.step(Step::Prev).unwrap(), second);
+        assert_eq!(f.fs.step(Step::Prev).unwrap(), first);
+        assert_eq!(f.errno(f.fs.step(Step::Prev)), ENOENT);
+        assert_eq!(f.fs.step(Step::Next).unwrap(), second);
+        // Refreshing doesn't undo the steps.
+        assert!(!f.fs.refresh().unwrap());
+        assert_eq!(f.fs.step(Step::Goto(first.to_string())).unwrap(), first);
+        assert_eq!(f.fs.step(Step::Next).unwrap(), second);
+        assert_eq!(f.fs.step(Step::Next).unwrap(), third);
+        assert_eq!(f.errno(f.fs.step(Step::Next)), ENOENT);
+        assert_eq!(f.fs.head().commit, Some(third));
+
+        // A checkout starts over.
+        f.fs.step(Step::Prev).unwrap();
+        f.fs.checkout(&second.to_string()).unwrap();
+        assert_eq!(f.errno(f.fs.step(Step::Next)), ENOENT);
+    }
+
+    #[test]
+    fn log_shows_the_history() {
+        let f = Fixture::new();
+        let first = f.fs.head().commit.unwrap();
+        let second = {
+            let repo = f.fs.repo();
+            let parent = repo.find_commit(first).unwrap();
+            let sig = git2::Signature::new("someone", "someone@example.com", &git2::Time::new(0, 0)).unwrap();
+            let tree = parent.tree().unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "second\n\nwith a body\n", &tree, &[&parent]).unwrap()
+        };
+        f.fs.refresh().unwrap();
+
+        let log = f.read_control("log");
+        let expected = format!(
+            "commit {}\nAuthor: someone <someone@example.com>\nDate:   Thu Jan  1 00:00:00 1970 +0000\n\n    second\n    \n    with a body\n\ncommit {}\n",
+            second, first
+        );
+        assert!(log.starts_with(&expected), "{}", log);
+        assert!(log.ends_with("\n    init\n"));
+    }
+
+    #[test]
+    fn stats_count_reads_and_writes() {
+        let f = Fixture::new();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        for _ in 0..2 {
+            let fh = f.fs.do_open(a, O_RDONLY).unwrap();
+            assert_eq!(f.fs.do_read(a, fh, 6, 100).unwrap(), b"world");
+            f.fs.handles().remove(fh);
+        }
+        let fh = f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        f.fs.handles().remove(fh);
+
+        for _ in 0..3 {
+            drop(f.fs.start_op("getattr", a));
+        }
+        drop(f.fs.start_op("getattr", Ino::ROOT));
+
+        let stats = f.read_control("stats");
+        assert!(stats.ends_with("\ngetattr: 4\n\n/a.txt: 3\n/: 1\n"), "{}", stats);
+        for line in &[
+            "dirty-files: 1",
+            "open-handles: 0",
+            "blob-cache-bytes: 11",
+            "blob-cache-hits: 1",
+            "blob-cache-misses: 1",
+            "bytes-read: 10",
+            "bytes-read-overlay: 0",
+            "bytes-written: 5",
+        ] {
+            assert!(stats.lines().any(|l| l == *line), "{} not in {}", line, stats);
+        }
+    }
+
+    #[test]
+    fn health_checks_the_repo_and_overlay() {
+        let f = Fixture::new();
+        assert_eq!(f.read_control("health"), "status: ok\nrepo: ok\noverlay: ok\n");
+
+        f.remove_object(f.fs.head().commit.unwrap());
+        std::fs::remove_dir_all(f.root.join("overlay")).unwrap();
+        let health = f.read_control("health");
+        let lines: Vec<_> = health.lines().collect();
+        assert_eq!(lines[0], "status: error");
+        assert!(lines[1].starts_with("repo: error: "), "{}", health);
+        assert!(lines[2].starts_with("overlay: error: "), "{}", health);
+    }
+
+    #[test]
+    fn overlay_quota_is_enforced() {
+        let f = Fixture::new();
+        f.fs.options().write().unwrap().overlay_quota = Some(10);
+        let (new, fh) = {
+            let (attr, fh) = f.fs.do_create(Ino::ROOT, OsStr::new("new.txt"), 0o644).unwrap();
+            (attr.ino.into(), fh)
+        };
+        assert_eq!(f.fs.do_write(new, fh, 0, b"12345678").unwrap(), 8);
+        assert_eq!(f.errno(f.fs.do_write(new, fh, 8, b"9abc")), libc::EDQUOT);
+        // Overwriting takes no more space.
+        assert_eq!(f.fs.do_write(new, fh, 0, b"abcd").unwrap(), 4);
+        assert_eq!(f.fs.do_write(new, fh, 8, b"9").unwrap(), 1);
+
+        // Checking out a clean file for writing counts too.
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(f.errno(f.fs.do_open(a, libc::O_WRONLY)), libc::EDQUOT);
+        assert!(f.fs.do_open(a, O_RDONLY).is_ok());
+
+        f.fs.do_write(new, fh, 9, b"0").unwrap();
+        assert_eq!(f.errno(f.fs.do_create(Ino::ROOT, OsStr::new("more.txt"), 0o644)), libc::EDQUOT);
+        assert_eq!(f.errno(f.fs.do_mkdir(Ino::ROOT, OsStr::new("dir2"), 0o755)), libc::EDQUOT);
+    }
+
+    #[test]
+    fn path_rules_hide_and_protect() {
+        let f = Fixture::new();
+        {
+            let options = f.fs.options();
+            let mut options = options.write().unwrap();
+            options.denied_paths = vec!["broken.txt".to_owned()];
+            options.read_only_paths = vec!["dir/**".to_owned()];
+        }
+        assert_eq!(f.errno(f.fs.do_lookup(Ino::ROOT, OsStr::new("broken.txt"))), ENOENT);
+        let names: HashSet<_> = f.fs.do_readdir(Ino::ROOT).unwrap().into_iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, ["a.txt", "dir"].iter().map(OsString::from).collect());
+        assert_eq!(f.errno(f.fs.do_create(Ino::ROOT, OsStr::new("broken.txt"), 0o644)), EACCES);
+
+        let dir = f.lookup(Ino::ROOT, "dir");
+        let b = f.lookup(dir, "b.txt");
+        assert!(f.fs.do_open(b, O_RDONLY).is_ok());
+        assert_eq!(f.errno(f.fs.do_open(b, libc::O_WRONLY)), EROFS);
+        let truncate = SetAttr { size: Some(0), ..SetAttr::default() };
+        assert_eq!(f.errno(f.fs.do_setattr(b, truncate)), EROFS);
+        assert!(!f.root.join("overlay/dir/b.txt").exists());
+        assert_eq!(f.errno(f.fs.do_create(dir, OsStr::new("c.txt"), 0o644)), EROFS);
+        assert_eq!(f.errno(f.fs.do_remove(dir, OsStr::new("b.txt"))), EROFS);
+        assert_eq!(f.errno(f.fs.do_rename(Ino::ROOT, OsStr::new("dir"), Ino::ROOT, OsStr::new("moved"))), EROFS);
+        assert_eq!(f.errno(f.fs.do_rename(Ino::ROOT, OsStr::new("a.txt"), dir, OsStr::new("a.txt"))), EROFS);
+        assert!(f.fs.do_rename(Ino::ROOT, OsStr::new("a.txt"), Ino::ROOT, OsStr::new("c.txt")).is_ok());
+    }
+
+    #[test]
+    fn included_and_excluded_paths_filter_the_mount() {
+        let f = Fixture::new();
+        let names = |dir| -> HashSet<OsString> {
+            f.fs.do_opendir(dir).unwrap();
+            f.fs.do_readdir(dir).unwrap().into_iter().map(|(name, _, _)| name).collect()
+        };
+        // Only what leads to an included path, as a dir.
+        f.fs.options().write().unwrap().included_paths = vec!["dir/b.txt".to_owned(), "a.txt/z".to_owned()];
+        assert_eq!(names(Ino::ROOT), ["dir"].iter().map(OsString::from).collect());
+        assert_eq!(f.errno(f.fs.do_lookup(Ino::ROOT, OsStr::new("a.txt"))), ENOENT);
+        let dir = f.lookup(Ino::ROOT, "dir");
+        f.lookup(dir, "b.txt");
+        assert_eq!(f.errno(f.fs.do_create(dir, OsStr::new("c.txt"), 0o644)), EACCES);
+        assert_eq!(f.errno(f.fs.do_mkdir(Ino::ROOT, OsStr::new("new"), 0o755)), EACCES);
+
+        // What is in an included dir is too, in the overlay as well.
+        {
+            let options = f.fs.options();
+            let mut options = options.write().unwrap();
+            options.included_paths = vec!["*.txt".to_owned(), "dir".to_owned()];
+            options.excluded_paths = vec!["broken.txt".to_owned()];
+        }
+        assert_eq!(names(Ino::ROOT), ["a.txt", "dir"].iter().map(OsString::from).collect());
+        assert_eq!(f.errno(f.fs.do_lookup(Ino::ROOT, OsStr::new("broken.txt"))), ENOENT);
+        f.fs.do_create(dir, OsStr::new("c.txt"), 0o644).unwrap();
+        assert_eq!(names(dir), ["b.txt", "c.txt"].iter().map(OsString::from).collect());
+    }
+
+    #[test]
+    fn pinned_paths_stay_in_the_blob_cache() {
+        let f = Fixture::new();
+        {
+            let options = f.fs.options();
+            let mut options = options.write().unwrap();
+            options.pinned_paths = vec!["dir/**".to_owned(), "broken.txt".to_owned()];
+            options.blob_cache_size = 0;
+        }
+        // Pinned on checkout; the broken blob is left out.
+        f.fs.checkout("HEAD").unwrap();
+        assert_eq!(f.fs.blob_cache().pinned_size(), b"in a dir".len());
+        let dir = f.fs.inomap().get(Ino::ROOT).unwrap().get_child(OsStr::new("dir")).unwrap();
+        assert!(f.fs.inomap().get(dir).unwrap().get_child(OsStr::new("b.txt")).is_some());
+
+        let b = f.lookup(dir, "b.txt");
+        let fh = f.fs.do_open(b, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(b, fh, 0, 100).unwrap(), b"in a dir");
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(a, fh, 0, 100).unwrap(), b"hello world");
+        assert_eq!(stats::get(&f.fs.inner.stats.blob_cache_hits), 1);
+        assert_eq!(stats::get(&f.fs.inner.stats.blob_cache_misses), 1);
+        assert!(f.read_control("stats").lines().any(|l| l == "blob-cache-pinned-bytes: 8"));
+
+        f.fs.options().write().unwrap().pinned_paths.clear();
+        f.fs.checkout("HEAD").unwrap();
+        assert_eq!(f.fs.blob_cache().pinned_size(), 0);
+    }
+
+    #[test]
+    fn the_least_recently_used_blob_is_evicted() {
+        let oid = |n: u8| Oid::from_bytes(&[n; 20]).unwrap();
+        let blob = || Arc::from(&b"1234"[..]);
+        let mut cache = BlobCache::new(12);
+        for n in 0..3 {
+            cache.insert(oid(n), blob());
+        }
+        // Hit over and over, which mustn't grow the order for good.
+        for _ in 0..100 {
+            assert!(cache.get(oid(0)).is_some());
+            assert!(cache.get(oid(1)).is_some());
+        }
+        assert!(cache.lru.len() <= 2 * 3 + 16);
+        cache.insert(oid(3), blob());
+        assert!(cache.get(oid(2)).is_none());
+        assert_eq!(cache.size(), 12);
+        cache.set_capacity(8);
+        assert!(cache.get(oid(0)).is_none());
+        assert!(cache.get(oid(1)).is_some() && cache.get(oid(3)).is_some());
+    }
+
+    #[test]
+    fn slow_ops_are_logged() {
+        let f = Fixture::new();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(logged(|| drop(f.fs.start_op("read", a))), "");
+        f.fs.options().write().unwrap().slow_op_threshold = Some(Duration::ZERO);
+        let log = logged(|| drop(f.fs.start_op("read", a)));
+        assert!(log.contains("WARN"), "{}", log);
+        assert!(log.contains("slow operation op=\"read\""), "{}", log);
+        assert!(log.contains("path=a.txt"), "{}", log);
+        assert_eq!(f.fs.inner.stats.ops()["read"].count, 2);
+    }
+
+    #[test]
+    fn state_dump_lists_dirty_files() {
+        let f = Fixture::new();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+
+        let log = logged(|| f.fs.dump_state());
+        assert!(log.contains("state: entries trees=2 listed_trees=1 blobs=1 dirty_dirs=0 dirty_files=1"), "{}", log);
+        assert!(log.contains("state: dirty file path=a.txt"), "{}", log);
+        assert!(log.contains("state: handles open_handles=1"), "{}", log);
+
+        // Stuck operations don't keep it from logging the rest.
+        let _handles = f.fs.handles();
+        let log = logged(|| f.fs.dump_state());
+        assert!(log.contains("state: handles are locked"), "{}", log);
+        assert!(log.contains("state: blob cache"), "{}", log);
+    }
+
+    #[test]
+    fn summary_is_logged_on_unmount() {
+        let f = Fixture::new();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(a, fh, 0, 5).unwrap(), b"hello");
+        f.fs.handles().remove(fh);
+        let fh = f.fs.do_open(a, libc::O_RDWR).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        assert_eq!(f.fs.do_read(a, fh, 0, 100).unwrap(), b"HELLO world");
+        f.fs.handles().remove(fh);
+        for _ in 0..2 {
+            drop(f.fs.start_op("read", a));
+        }
+        drop(f.fs.start_op("lookup", Ino::ROOT));
+
+        let mut fs = Fixture::new().fs.clone();
+        assert!(!logged(|| fs.destroy()).contains("summary:"));
+        let mut fs = f.fs.clone();
+        f.fs.options().write().unwrap().summary_on_unmount = true;
+        let log = logged(|| fs.destroy());
+        assert!(log.contains("summary: operations count=3"), "{}", log);
+        assert!(log.contains("summary: operation op=\"read\" count=2"), "{}", log);
+        assert!(log.contains("summary: bytes from_repository=5 from_overlay=11 written=5"), "{}", log);
+        assert!(log.contains("summary: blob cache hits=0 misses=1 hit_rate=0.0%"), "{}", log);
+        assert!(log.contains("summary: slow path path=/a.txt count=2"), "{}", log);
+        assert!(log.contains("summary: slow path path=/ count=1"), "{}", log);
+    }
+
+    #[test]
+    fn large_files_are_shown_in_chunks() {
+        let f = Fixture::new();
+        let dir = f.lookup(Ino::ROOT, control::NAME);
+        assert_eq!(f.errno(f.fs.do_lookup(dir, OsStr::new("chunks"))), ENOENT);
+        f.fs.options().write().unwrap().chunk_size = Some(4);
+        let chunks = f.lookup(dir, "chunks");
+        let names = |ino: Ino| -> Vec<_> { f.fs.do_readdir(ino).unwrap().into_iter().map(|(name, _, _)| name).collect() };
+        // broken.txt is missing, and dir/b.txt is larger too.
+        assert_eq!(names(chunks), ["a.txt", "dir"]);
+        let a = f.lookup(chunks, "a.txt");
+        assert_eq!(names(a), ["000001", "000002", "000003", "manifest"]);
+        let read = |ino: Ino| {
+            let fh = f.fs.do_open(ino, O_RDONLY).unwrap();
+            let content = f.fs.do_read(ino, fh, 0, 4096).unwrap();
+            f.fs.control_release(fh);
+            String::from_utf8(content).unwrap()
+        };
+        let pieces: Vec<_> = ["000001", "000002", "000003"].iter().map(|name| read(f.lookup(a, name))).collect();
+        assert_eq!(pieces, ["hell", "o wo", "rld"]);
+        assert_eq!(
+            read(f.lookup(a, "manifest")),
+            format!("path a.txt\nblob {}\nsize 11\nchunk-size 4\n000001 0 4\n000002 4 4\n000003 8 3\n", f.blobs["a.txt"])
+        );
+        assert_eq!(f.errno(f.fs.do_lookup(a, OsStr::new("000004"))), ENOENT);
+        let sub = f.lookup(chunks, "dir");
+        assert_eq!(names(f.lookup(sub, "b.txt")), ["000001", "000002", "manifest"]);
+
+        f.fs.options().write().unwrap().chunk_size = Some(11);
+        assert!(names(chunks).iter().all(|name| name != "a.txt"));
+    }
+
+    #[test]
+    fn blame_annotates_committed_files() {
+        let f = Fixture::new();
+        let first = f.fs.head().commit.unwrap();
+        let second = {
+            let repo = f.fs.repo();
+            let parent = repo.find_commit(first).unwrap();
+            let mut update = git2::build::TreeUpdateBuilder::new();
+            update.upsert("a.txt", repo.blob(b"hello\nworld\n").unwrap(), git2::FileMode::Blob);
+            let tree = repo.find_tree(update.create_updated(&repo, &parent.tree().unwrap()).unwrap()).unwrap();
+            let sig = git2::Signature::new("someone", "someone@example.com", &git2::Time::new(0, 0)).unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "second", &tree, &[&parent]).unwrap()
+        };
+        f.fs.refresh().unwrap();
+
+        let dir = f.lookup(Ino::ROOT, control::NAME);
+        let blame = f.lookup(dir, "blame");
+        let names: Vec<_> = f.fs.do_readdir(blame).unwrap().into_iter().map(|(name, _, kind)| (name, kind)).collect();
+        assert_eq!(names, vec![
+            (OsString::from("a.txt"), FileType::RegularFile),
+            (OsString::from("broken.txt"), FileType::RegularFile),
+            (OsString::from("dir"), FileType::Directory),
+        ]);
+        let read = |ino: Ino| {
+            let fh = f.fs.do_open(ino, O_RDONLY).unwrap();
+            let content = f.fs.do_read(ino, fh, 0, 4096).unwrap();
+            f.fs.control_release(fh);
+            String::from_utf8(content).unwrap()
+        };
+        let id = &second.to_string()[..8];
+        assert_eq!(
+            read(f.lookup(blame, "a.txt")),
+            format!(
+                "{} (someone 1970-01-01 00:00:00 +0000 1) hello\n{} (someone 1970-01-01 00:00:00 +0000 2) world\n",
+                id, id
+            )
+        );
+        let sub = f.lookup(blame, "dir");
+        let b = read(f.lookup(sub, "b.txt"));
+        assert!(b.starts_with(&format!("{} (test ", &first.to_string()[..8])), "{}", b);
+        assert!(b.ends_with(" 1) in a dir\n"), "{}", b);
+        assert_eq!(f.errno(f.fs.do_lookup(blame, OsStr::new("new"))), ENOENT);
+    }
+
+    #[test]
+    fn archives_of_revs() {
+        let f = Fixture::new();
+        f.checkout(&[("a.txt", f.blobs["a.txt"], 0o100644)]);
+        let head = f.fs.head().commit.unwrap();
+        {
+            let repo = f.fs.repo();
+            repo.tag_lightweight("v1", &repo.find_object(head, None).unwrap(), false).unwrap();
+        }
+
+        let dir = f.lookup(Ino::ROOT, control::NAME);
+        let archives = f.lookup(dir, "archive");
+        let names: Vec<_> = f.fs.do_readdir(archives).unwrap().into_iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, ["HEAD.tar", "HEAD.tar.gz", "master.tar", "master.tar.gz", "v1.tar", "v1.tar.gz"]
+            .iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>());
+
+        // Any rev can be looked up.
+        let tar = f.lookup(archives, &format!("{}.tar", head));
+        let fh = f.fs.do_open(tar, O_RDONLY).unwrap();
+        let content = f.fs.do_read(tar, fh, 0, 1 << 20).unwrap();
+        f.fs.control_release(fh);
+        assert_eq!(content.len(), 10240);
+        assert_eq!(&content[1024..1029], b"a.txt");
+        assert_eq!(&content[1536..1547], b"hello world");
+        assert_eq!(f.errno(f.fs.do_lookup(archives, OsStr::new("nowhere.tar"))), ENOENT);
+        assert_eq!(f.errno(f.fs.do_lookup(archives, OsStr::new("v1.zip"))), ENOENT);
+    }
+
+    #[test]
+    fn layers_of_changes() {
+        let f = Fixture::new();
+        f.checkout(&[
+            ("a.txt", f.blobs["a.txt"], 0o100644),
+            ("dir", f.trees["dir"], 0o040000),
+        ]);
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        f.fs.handles().remove(fh);
+        let (_, fh) = f.fs.do_create(Ino::ROOT, OsStr::new("new"), 0o644).unwrap();
+        f.fs.handles().remove(fh);
+        let dir = f.lookup(Ino::ROOT, "dir");
+        f.fs.do_opendir(dir).unwrap();
+        f.fs.do_remove(dir, OsStr::new("b.txt")).unwrap();
+
+        let control = f.lookup(Ino::ROOT, control::NAME);
+        let layer = f.lookup(control, "layer.tar");
+        let fh = f.fs.do_open(layer, O_RDONLY).unwrap();
+        let content = f.fs.do_read(layer, fh, 0, 1 << 20).unwrap();
+        f.fs.control_release(fh);
+        let mut entries = vec![];
+        let mut offset = 0;
+        while content[offset] != 0 {
+            let header = &content[offset..offset + 512];
+            let name = header[..100].split(|&b| b == 0).next().unwrap();
+            let size = usize::from_str_radix(std::str::from_utf8(&header[124..135]).unwrap(), 8).unwrap();
+            let data = &content[offset + 512..offset + 512 + size];
+            entries.push((String::from_utf8(name.to_vec()).unwrap(), String::from_utf8(data.to_vec()).unwrap()));
+            offset += 512 + size.div_ceil(512) * 512;
+        }
+        let entries: Vec<_> = entries.iter().map(|(name, data)| (name.as_str(), data.as_str())).collect();
+        assert_eq!(entries, vec![("a.txt", "HELLO world"), ("dir/", ""), ("dir/.wh.b.txt", ""), ("new", "")]);
+        assert_eq!(content.len() % 10240, 0);
+    }
+
+    #[test]
+    fn objects_are_counted() {
+        let f = Fixture::new();
+        let objects = f.read_control("objects");
+        let (counts, blobs) = objects.split_once("\n\n").unwrap();
+        // a.txt, b.txt, two trees and the commit; broken.txt is gone.
+        assert!(counts.starts_with("loose-objects: 5\n"), "{}", counts);
+        assert!(counts.contains("packs: 0\npacked-objects: 0\n"), "{}", counts);
+        assert!(counts.contains("\nobjects: 5\n"), "{}", counts);
+        assert_eq!(blobs, format!("{}\t11\n{}\t8\n", f.blobs["a.txt"], f.blobs["b.txt"]));
+    }
+
+    #[test]
+    fn root_has_commit_xattrs() {
+        let f = Fixture::new();
+        let head = f.fs.head().commit.unwrap();
+        let names = f.fs.do_listxattr(Ino::ROOT).unwrap();
+        assert_eq!(names, b"user.gitfs.commit\0user.gitfs.author\0user.gitfs.committer\0user.gitfs.date\0".to_vec());
+        let get = |name: &str| f.fs.do_getxattr(Ino::ROOT, OsStr::new(name));
+        assert_eq!(get("user.gitfs.commit").unwrap(), head.to_string().into_bytes());
+        assert_eq!(get("user.gitfs.author").unwrap(), b"test <test@example.com>".to_vec());
+        assert_eq!(f.errno(get("user.other")), ENOATTR);
+
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(f.fs.do_listxattr(a).unwrap(), b"");
+        assert_eq!(f.errno(f.fs.do_getxattr(a, OsStr::new("user.gitfs.commit"))), ENOATTR);
+    }
+
+    #[test]
+    fn clean_entries_have_last_commit_xattrs() {
+        let f = Fixture::new();
+        f.fs.options().write().unwrap().last_commit_xattrs = true;
+        let (first, second) = {
+            let repo = f.fs.repo();
+            let first = repo.head().unwrap().peel_to_commit().unwrap();
+            let mut top = repo.treebuilder(Some(&first.tree().unwrap())).unwrap();
+            top.insert("a.txt", f.blobs["b.txt"], 0o100644).unwrap();
+            let tree = repo.find_tree(top.write().unwrap()).unwrap();
+            let sig = git2::Signature::now("other", "other@example.com").unwrap();
+            let second = repo.commit(Some("HEAD"), &sig, &sig, "change a", &tree, &[&first]).unwrap();
+            (first.id(), second)
+        };
+        f.fs.refresh().unwrap();
+        let get = |ino: Ino, name: &str| f.fs.do_getxattr(ino, OsStr::new(name));
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(
+            f.fs.do_listxattr(a).unwrap(),
+            b"user.gitfs.last-commit\0user.gitfs.last-author\0user.gitfs.last-date\0".to_vec()
+        );
+        assert_eq!(get(a, "user.gitfs.last-commit").unwrap(), second.to_string().into_bytes());
+        assert_eq!(get(a, "user.gitfs.last-author").unwrap(), b"other <other@example.com>".to_vec());
+        let dir = f.lookup(Ino::ROOT, "dir");
+        let b = f.lookup(dir, "b.txt");
+        for ino in [dir, b] {
+            assert_eq!(get(ino, "user.gitfs.last-commit").unwrap(), first.to_string().into_bytes());
+        }
+        // Only the mounted commit on the root.
+        assert_eq!(f.errno(get(Ino::ROOT, "user.gitfs.last-commit")), ENOATTR);
+
+        let fh = f.fs.do_open(b, libc::O_RDWR).unwrap();
+        f.fs.do_write(b, fh, 0, b"IN").unwrap();
+        f.fs.handles().remove(fh);
+        assert_eq!(f.fs.do_listxattr(b).unwrap(), b"");
+    }
+
+    #[test]
+    fn security_xattrs_are_those_of_the_underlying_files() {
+        let f = Fixture::new();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let label = OsStr::new("security.gitfs-test");
+        assert_eq!(f.errno(f.fs.do_getxattr(a, OsStr::new("security.selinux"))), ENOATTR);
+        assert_eq!(f.errno(f.fs.do_getxattr(a, OsStr::new("system.posix_acl_access"))), ENOATTR);
+        assert_eq!(f.errno(f.fs.do_setxattr(a, label, b"x", 0)), libc::EOPNOTSUPP);
+        assert_eq!(f.errno(f.fs.do_setxattr(a, OsStr::new("user.x"), b"x", 0)), libc::EOPNOTSUPP);
+        assert!(!f.root.join("overlay/a.txt").exists());
+
+        let (attr, _) = f.fs.do_create(Ino::ROOT, OsStr::new("new.txt"), 0o644).unwrap();
+        let new = Ino::from(attr.ino);
+        assert_eq!(f.errno(f.fs.do_getxattr(new, label)), ENOATTR);
+        match f.fs.do_setxattr(new, label, b"label", 0) {
+            // Setting security xattrs takes CAP_SYS_ADMIN.
+            Err(e) if matches!(f.fs.errno(&e), EPERM | libc::EOPNOTSUPP) => return,
+            result => result.unwrap(),
+        }
+        assert_eq!(f.fs.do_getxattr(new, label).unwrap(), b"label");
+        assert!(f.fs.do_listxattr(new).unwrap().ends_with(b"security.gitfs-test\0"));
+        f.fs.do_removexattr(new, label).unwrap();
+        assert_eq!(f.errno(f.fs.do_getxattr(new, label)), ENOATTR);
+    }
+
+    #[test]
+    fn layer_markers_are_shown_to_overlayfs() {
+        let f = Fixture::new();
+        let (empty, opaque) = {
+            let repo = f.fs.repo();
+            let empty = repo.blob(b"").unwrap();
+            let mut sub = repo.treebuilder(None).unwrap();
+            sub.insert(".wh..wh..opq", empty, 0o100644).unwrap();
+            sub.insert("b.txt", f.blobs["b.txt"], 0o100644).unwrap();
+            (empty, sub.write().unwrap())
+        };
+        {
+            let options = f.fs.options();
+            let mut options = options.write().unwrap();
+            options.whiteouts = true;
+            options.read_only = true;
+        }
+        f.checkout(&[
+            (".wh.gone", empty, 0o100644),
+            (".wh.a.txt", empty, 0o100644),
+            ("a.txt", f.blobs["a.txt"], 0o100644),
+            ("dir", opaque, 0o040000),
+        ]);
+        f.fs.do_opendir(Ino::ROOT).unwrap();
+        let mut entries: Vec<_> = f.fs.do_readdir(Ino::ROOT).unwrap().into_iter().map(|(name, _, kind)| (name, kind)).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(entries, vec![
+            (OsString::from("a.txt"), FileType::RegularFile),
+            (OsString::from("dir"), FileType::Directory),
+            (OsString::from("gone"), FileType::CharDevice),
+        ]);
+        let gone = f.fs.do_getattr(f.lookup(Ino::ROOT, "gone")).unwrap();
+        assert_eq!((gone.kind, gone.rdev, gone.perm & 0o7777), (FileType::CharDevice, 0, 0));
+
+        let dir = f.lookup(Ino::ROOT, "dir");
+        f.fs.do_opendir(dir).unwrap();
+        let names: Vec<_> = f.fs.do_readdir(dir).unwrap().into_iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, ["b.txt"]);
+        assert_eq!(f.fs.do_getxattr(dir, OsStr::new("trusted.overlay.opaque")).unwrap(), b"y");
+        assert_eq!(f.fs.do_listxattr(dir).unwrap(), b"trusted.overlay.opaque\0user.overlay.opaque\0");
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(f.errno(f.fs.do_getxattr(a, OsStr::new("trusted.overlay.opaque"))), ENOATTR);
+        // The markers are no changes.
+        assert!(f.read_control("status").ends_with("read-only: yes\n\n"));
+    }
+
+    #[test]
+    fn dos_attributes_follow_the_entries() {
+        let f = Fixture::new();
+        f.fs.options().write().unwrap().dos_attributes = true;
+        let get = |ino: Ino| f.fs.do_getxattr(ino, OsStr::new("user.DOSATTRIB")).unwrap();
+        assert_eq!(get(Ino::ROOT), b"0x10\0");
+        assert!(f.fs.do_listxattr(Ino::ROOT).unwrap().ends_with(b"\0user.DOSATTRIB\0"));
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        assert_eq!(get(a), b"0x0\0");
+
+        let fh = f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        f.fs.handles().remove(fh);
+        assert_eq!(get(a), b"0x20\0");
+        let (attr, fh) = f.fs.do_create(Ino::ROOT, OsStr::new(".hidden"), 0o644).unwrap();
+        f.fs.handles().remove(fh);
+        assert_eq!(get(attr.ino.into()), b"0x22\0");
+
+        f.fs.options().write().unwrap().read_only = true;
+        assert_eq!(get(a), b"0x21\0");
+        assert_eq!(get(Ino::ROOT), b"0x10\0");
+    }
+
+    #[test]
+    fn grep_searches_the_mount() {
+        let f = Fixture::new();
+        let a = f.lookup(Ino::ROOT, "a.txt");
+        let fh = f.fs.do_open(a, libc::O_WRONLY).unwrap();
+        f.fs.do_write(a, fh, 0, b"HELLO").unwrap();
+        f.fs.handles().remove(fh);
+
+        let dir = f.lookup(Ino::ROOT, control::NAME);
+        let ctl = f.lookup(dir, "ctl");
+        let fh = f.fs.do_open(ctl, libc::O_RDWR).unwrap();
+        let grep = |command: &str| {
+            f.fs.do_write(ctl, fh, 0, command.as_bytes()).unwrap();
+            String::from_utf8(f.fs.do_read(ctl, fh, 0, 4096).unwrap()).unwrap()
+        };
+        assert_eq!(grep("grep HELLO\n"), "ok 1\na.txt:1:HELLO world\n");
+        assert_eq!(grep("grep hello\n"), "ok 0\n");
+        assert_eq!(grep("grep in a -- dir\n"), "ok 1\ndir/b.txt:1:in a dir\n");
+        assert_eq!(grep("grep in a -- a.txt\n"), "ok 0\n");
+        f.fs.control_release(fh);
+    }
+
+    #[test]
+    fn lfs_lists_pointer_files() {
+        let f = Fixture::new();
+        let (present, missing) = ("a".repeat(64), "b".repeat(64));
+        let pointer = |oid: &str, size: u64| {
+            let content = format!("version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize {}\n", oid, size);
+            f.fs.repo().blob(content.as_bytes()).unwrap()
+        };
+        let (big, large) = (pointer(&present, 1000), pointer(&missing, 2000));
+        f.checkout(&[("a.txt", f.blobs["a.txt"], 0o100644), ("big.bin", big, 0o100644), ("large.bin", large, 0o100644)]);
+        let store = f.root.join("repo/.git/lfs/objects/aa/aa");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(store.join(&present), b"").unwrap();
+
+        assert_eq!(
+            f.read_control("lfs"),
+            format!(
+                "pointers: 2\nmissing: 1\nmissing-size: 2000\n\n{}\t1000\tpresent\tbig.bin\n{}\t2000\tmissing\tlarge.bin\n",
+                present, missing
+            )
+        );
+    }
+
+    #[test]
+    fn lfs_pointer_files_read_as_their_objects() {
+        let f = Fixture::new();
+        f.fs.options().write().unwrap().lfs = true;
+        // The SHA-256 of "hello\n".
+        let (present, missing) = ("5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03", "b".repeat(64));
+        let pointer = |oid: &str, size: u64| {
+            let content = format!("version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize {}\n", oid, size);
+            f.fs.repo().blob(content.as_bytes()).unwrap()
+        };
+        let (hello, large) = (pointer(present, 6), pointer(&missing, 2000));
+        f.checkout(&[("hello.bin", hello, 0o100644), ("large.bin", large, 0o100644)]);
+        let store = f.root.join("repo/.git/lfs/objects/58/91");
+        std::fs::create_dir_all(&store).unwrap();
+        std::fs::write(store.join(present), b"hello\n").unwrap();
+        // Nothing listens there.
+        f.fs.repo().config().unwrap().set_str("lfs.url", "http://127.0.0.1:1/info/lfs").unwrap();
+
+        let hello = f.lookup(Ino::ROOT, "hello.bin");
+        assert_eq!(f.fs.do_getattr(hello).unwrap().size, 6);
+        let fh = f.fs.do_open(hello, O_RDONLY).unwrap();
+        assert_eq!(f.fs.do_read(hello, fh, 1, 100).unwrap(), b"ello\n");
+        let large = f.lookup(Ino::ROOT, "large.bin");
+        assert_eq!(f.fs.do_getattr(large).unwrap().size, 2000);
+        let fh = f.fs.do_open(large, O_RDONLY).unwrap();
+        assert!(matches!(f.fs.do_read(large, fh, 0, 100), Err(Error::Errno(EIO))));
+
+        // Checked out with the content of the object.
+        f.fs.do_open(hello, libc::O_RDWR).unwrap();
+        assert_eq!(std::fs::read(f.root.join("overlay/hello.bin")).unwrap(), b"hello\n");
+    }
+
+    #[test]
+    fn annexed_files_read_as_their_objects() {
+        let f = Fixture::new();
+        f.fs.options().write().unwrap().annex = true;
+        let key = "SHA256E-s31390--f50d7ac4c6b9031379986bc362fcefb65f1e52621ce1708d537e740fefc59cc0.mp3";
+        let locked = f.fs.repo().blob(format!("../.git/annex/objects/7P/x0/{}/{}", key, key).as_bytes()).unwrap();
+        let unlocked = f.fs.repo().blob(format!("/annex/objects/{}\n", key).as_bytes()).unwrap();
+        let missing = f.fs.repo().blob(b"../.git/annex/objects/zz/zz/KEY/KEY").unwrap();
+        f.checkout(&[("locked.mp3", locked, 0o120000), ("unlocked.mp3", unlocked, 0o100644), ("missing.mp3", missing, 0o120000)]);
+        let object = f.root.join("repo/.git/annex/objects/7P/x0").join(key);
+        std::fs::create_dir_all(&object).unwrap();
+        std::fs::write(object.join(key), b"music").unwrap();
+
+        for name in &["locked.mp3", "unlocked.mp3"] {
+            let ino = f.lookup(Ino::ROOT, name);
+            let attr = f.fs.do_getattr(ino).unwrap();
+            assert_eq!((attr.kind, attr.size), (FileType::RegularFile, 5), "{}", name);
+            let fh = f.fs.do_open(ino, O_RDONLY).unwrap();
+            assert_eq!(f.fs.do_read(ino, fh, 0, 100).unwrap(), b"music");
+        }
+        let ino = f.lookup(Ino::ROOT, "missing.mp3");
+        assert_eq!(f.fs.do_getattr(ino).unwrap().kind, FileType::Symlink);
+        assert_eq!(f.fs.do_readlink(ino).unwrap(), b"../.git/annex/objects/zz/zz/KEY/KEY");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_are_served() {
+        use std::io::Read;
+        let f = Fixture::new();
+        let (_server, addr) = f.fs.serve_metrics("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\ngitfs_entries 1\n"), "{}", response);
+        assert!(response.contains("# TYPE gitfs_op_duration_seconds summary\n"), "{}", response);
+    }
+}
@@ -0,0 +1,107 @@
+/// git-annex files shown as their content (`Options::annex`), when it's
+/// in the annex of the repository.
+///
+/// Annexed files are committed either as symlinks into
+/// `annex/objects` (locked files) or as pointer files naming the key
+/// of their content (unlocked files).  Either is shown as a regular
+/// file holding its object if that's in the local annex, and as it's
+/// committed otherwise: objects are never fetched, which takes
+/// `git annex get`, and dirs already listed only show objects got
+/// since once they're listed again.  As with LFS objects, files
+/// changed in the mount are committed with their content.
+use std::path::{Component, Path, PathBuf};
+use std::ptr;
+
+use git2::Repository;
+use openssl_sys as ssl;
+
+use super::control;
+
+/// git-annex pointer files are never larger than this.
+pub(super) const POINTER_MAX: usize = 32 << 10;
+
+/// The object in the local annex that `content` (of a symlink, if
+/// `symlink`) stands for, if it's there.
+pub(super) fn object_path(repo: &Repository, content: &[u8], symlink: bool) -> Option<PathBuf> {
+    let store = control::common_dir(repo).join("annex/objects");
+    let candidates = match symlink {
+        // The target is in the annex of the worktree it was made in,
+        // with the hash dirs of the key in it.
+        true => {
+            let target = std::str::from_utf8(content).ok()?;
+            let rest = Path::new(&target[target.find("/annex/objects/")? + "/annex/objects/".len()..]);
+            if !rest.components().all(|c| matches!(c, Component::Normal(_))) {
+                return None;
+            }
+            vec![store.join(rest)]
+        }
+        // Either layout may be in use: bare repositories and some file
+        // systems get the lower case one.
+        false => {
+            let key = pointer_key(content)?;
+            hash_dirs(key)?.iter().map(|dir| store.join(dir).join(key).join(key)).collect()
+        }
+    };
+    candidates.into_iter().find(|path| path.is_file())
+}
+
+/// The key in a git-annex pointer file.
+fn pointer_key(data: &[u8]) -> Option<&str> {
+    if data.len() > POINTER_MAX {
+        return None;
+    }
+    let text = std::str::from_utf8(data).ok()?;
+    let key = text.strip_prefix("/annex/objects/")?.lines().next()?;
+    Some(key).filter(|key| !key.is_empty() && !key.contains('/') && *key != "." && *key != "..")
+}
+
+/// The dirs git-annex keeps the object of `key` in: the mixed case
+/// (`hashdirmixed`) layout, then the lower case (`hashdirlower`) one.
+fn hash_dirs(key: &str) -> Option<[String; 2]> {
+    const CHARS: &[u8; 32] = b"0123456789zqjxkmvwgpfZQJXKMVWGPF";
+    let md5 = md5(key.as_bytes())?;
+    // 5 bits out of every 6 of the first word (little endian) of the
+    // MD5, swapped pairwise, as git-annex has it.
+    let word = u32::from_le_bytes([md5[0], md5[1], md5[2], md5[3]]);
+    let c = |i: u32| CHARS[(word >> (6 * i) & 31) as usize] as char;
+    let mixed = format!("{}{}/{}{}", c(1), c(0), c(3), c(2));
+    let hex: String = md5.iter().map(|b| format!("{:02x}", b)).collect();
+    let lower = format!("{}/{}", &hex[..3], &hex[3..6]);
+    Some([mixed, lower])
+}
+
+fn md5(data: &[u8]) -> Option<[u8; 16]> {
+    ssl::init();
+    let mut digest = [0; 16];
+    unsafe {
+        let ctx = ssl::EVP_MD_CTX_new();
+        if ctx.is_null() {
+            return None;
+        }
+        let ok = ssl::EVP_DigestInit_ex(ctx, ssl::EVP_md5(), ptr::null_mut()) == 1
+            && ssl::EVP_DigestUpdate(ctx, data.as_ptr().cast(), data.len()) == 1
+            && ssl::EVP_DigestFinal_ex(ctx, digest.as_mut_ptr(), ptr::null_mut()) == 1;
+        ssl::EVP_MD_CTX_free(ctx);
+        Some(digest).filter(|_| ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pointers() {
+        assert_eq!(pointer_key(b"/annex/objects/SHA256E-s6--abc.txt\n"), Some("SHA256E-s6--abc.txt"));
+        assert_eq!(pointer_key(b"/annex/objects/SHA256E-s6--abc.txt"), Some("SHA256E-s6--abc.txt"));
+        assert_eq!(pointer_key(b"/annex/objects/../x\n"), None);
+        assert_eq!(pointer_key(b"/annex/objects/..\n"), None);
+        assert_eq!(pointer_key(b"annex/objects/SHA256E-s6--abc.txt\n"), None);
+    }
+
+    #[test]
+    fn hashes_keys_into_dirs() {
+        let key = "SHA256E-s31390--f50d7ac4c6b9031379986bc362fcefb65f1e52621ce1708d537e740fefc59cc0.mp3";
+        assert_eq!(hash_dirs(key).unwrap(), ["7P/x0".to_owned(), "fe0/9b4".to_owned()]);
+    }
+}
@@ -0,0 +1,258 @@
+/// A read-only HTTP API over the tree of the mount (`--serve api`, the
+/// `http-api` feature), for web UIs and remote tools that want the
+/// view of gitfs, dirty files included, without mounting it:
+///
+/// ```text
+/// GET /                   {"ref": "HEAD", "commit": "..."}
+/// GET /tree/dir           the entries of a dir, with their metadata
+/// GET /meta/dir/a.txt     the metadata of an entry
+/// GET /blob/dir/a.txt     the content of a file; Range is honored
+/// ```
+///
+/// Metadata is a JSON object with `name`, `type` (`file`, `dir`,
+/// `symlink`, ...), `size`, `mode` (in octal), `mtime` (in seconds
+/// since the epoch), `oid` (of the blob or tree, or null if dirty) and
+/// `dirty`, and `target` for a symlink.  Paths under `/tree` and
+/// `/blob` follow symlinks, those under `/meta` don't.  Errors come as
+/// `{"error": "...", "errno": N}`, with a status as WebDAV would give.
+/// As with WebDAV, there is no authentication, nor CORS headers: serve
+/// on localhost, or behind a proxy that adds them.
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use fuser::{FileAttr, FileType};
+use libc::{EACCES, EINVAL, EISDIR, ELOOP, ENOENT, ENOTDIR, EPERM, O_RDONLY};
+
+use super::webdav::{self, Body, Request, RequestBody, Response};
+use super::GitFS;
+use crate::error::Error;
+use crate::logging::json_str;
+use crate::watch::Watcher;
+use crate::{EntryKind, Ino};
+
+/// Serve the API for `fs` at `addr`, until the returned watcher is
+/// dropped.
+pub(super) fn serve(fs: GitFS, addr: SocketAddr) -> Result<(Watcher, SocketAddr), Error> {
+    fs.mount_root()?;
+    Ok(super::server::listen("api", addr, move |stream| run(&fs, stream))?)
+}
+
+/// Answer requests until the client hangs up.
+fn run(fs: &GitFS, stream: &TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    while let Some(request) = webdav::read_head(&mut reader)? {
+        // Bodies are of no interest, but must be skipped.
+        let mut body = RequestBody::new(&request)?;
+        io::copy(&mut body.reader(&mut reader), &mut io::sink())?;
+        let response = respond(fs, &request);
+        let keep_alive = request.keep_alive();
+        webdav::send(fs, stream, request.method == "HEAD", response, keep_alive)?;
+        if !keep_alive {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn respond(fs: &GitFS, request: &Request) -> Response {
+    let _timer = fs.start_op("api", Ino::ROOT);
+    let span = debug_span!("api", method = &*request.method, path = %request.path.display(), status = tracing::field::Empty).entered();
+    fs.refresh_if_requested();
+    let response = match &*request.method {
+        "GET" | "HEAD" => handle(fs, request).unwrap_or_else(|e| {
+            let errno = fs.errno(&e);
+            let status = match errno {
+                ENOENT => 404,
+                EACCES | EPERM => 403,
+                EISDIR | ENOTDIR => 409,
+                EINVAL => 400,
+                ELOOP => 508,
+                _ => 500,
+            };
+            let message = io::Error::from_raw_os_error(errno).to_string();
+            json(status, format!("{{\"error\":{},\"errno\":{}}}\n", json_str(&message), errno))
+        }),
+        _ => json(405, "{\"error\":\"only GET and HEAD\"}\n".to_owned()).header("Allow", "GET, HEAD".to_owned()),
+    };
+    span.record("status", response.status);
+    response
+}
+
+fn handle(fs: &GitFS, request: &Request) -> Result<Response, Error> {
+    let mut names = request.path.iter();
+    // The root.
+    names.next();
+    let (endpoint, path) = match names.next() {
+        None => {
+            let (refspec, commit) = {
+                let head = fs.head();
+                (head.refspec.clone(), head.commit)
+            };
+            let commit = commit.map_or("null".to_owned(), |commit| json_str(&commit.to_string()));
+            return Ok(json(200, format!("{{\"ref\":{},\"commit\":{}}}\n", json_str(&refspec), commit)));
+        }
+        Some(endpoint) => (endpoint, Path::new("/").join(names.as_path())),
+    };
+    match endpoint.as_bytes() {
+        b"tree" => {
+            let ino = fs.resolve_path(&path, true)?;
+            fs.do_opendir(ino)?;
+            let mut listing = fs.do_readdir(ino)?;
+            listing.sort_by(|a, b| a.0.cmp(&b.0));
+            let mut out = format!("{{\"path\":{},\"entries\":[", json_str(&path.to_string_lossy()));
+            for (i, (name, _, _)) in listing.iter().enumerate() {
+                let attr = fs.do_lookup(ino, name)?;
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&metadata(fs, name, &attr)?);
+            }
+            out.push_str("]}\n");
+            Ok(json(200, out))
+        }
+        b"meta" => {
+            let ino = fs.resolve_path(&path, false)?;
+            let attr = fs.do_getattr(ino)?;
+            let name = path.file_name().unwrap_or_else(|| OsStr::new(""));
+            Ok(json(200, format!("{}\n", metadata(fs, name, &attr)?)))
+        }
+        b"blob" => {
+            let ino = fs.resolve_path(&path, true)?;
+            let attr = fs.do_getattr(ino)?;
+            if attr.kind == FileType::Directory {
+                return Err(Error::Errno(EISDIR));
+            }
+            let (status, offset, len) = match request.header("range").map(|range| webdav::parse_range(range, attr.size)) {
+                None | Some(Some(None)) => (200, 0, attr.size),
+                Some(Some(Some((start, end)))) => (206, start, end - start + 1),
+                Some(None) => {
+                    return Ok(Response::new(416).header("Content-Range", format!("bytes */{}", attr.size)));
+                }
+            };
+            let mut response = Response::new(status)
+                .header("Content-Type", webdav::content_type(&path).to_owned())
+                .header("Last-Modified", webdav::http_date(attr.mtime))
+                .header("ETag", webdav::etag(&attr))
+                .header("Accept-Ranges", "bytes".to_owned());
+            if status == 206 {
+                response = response.header("Content-Range", format!("bytes {}-{}/{}", offset, offset + len - 1, attr.size));
+            }
+            let fh = if request.method == "HEAD" { 0 } else { fs.do_open(ino, O_RDONLY)? };
+            response.body = Body::File { ino, fh, offset, len };
+            Ok(response)
+        }
+        _ => Err(Error::Errno(ENOENT)),
+    }
+}
+
+/// The metadata of the entry `name` with `attr`, as a JSON object.
+fn metadata(fs: &GitFS, name: &OsStr, attr: &FileAttr) -> Result<String, Error> {
+    let kind = match attr.kind {
+        FileType::RegularFile => "file",
+        FileType::Directory => "dir",
+        FileType::Symlink => "symlink",
+        FileType::NamedPipe => "fifo",
+        FileType::CharDevice => "char",
+        FileType::BlockDevice => "block",
+        FileType::Socket => "socket",
+    };
+    let ino = Ino::from(attr.ino);
+    let (oid, dirty) = match fs.inomap().get(ino).map(|entry| &entry.u) {
+        Some(EntryKind::GitBlob { oid, .. }) | Some(EntryKind::GitTree { oid, .. }) => (json_str(&oid.to_string()), false),
+        Some(EntryKind::DirtyFile) | Some(EntryKind::DirtyDir { .. }) => ("null".to_owned(), true),
+        // The control dir.
+        None => ("null".to_owned(), false),
+    };
+    let mtime = attr.mtime.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let mut out = format!(
+        "{{\"name\":{},\"type\":\"{}\",\"size\":{},\"mode\":\"{:o}\",\"mtime\":{},\"oid\":{},\"dirty\":{}",
+        json_str(&name.to_string_lossy()),
+        kind,
+        attr.size,
+        attr.perm,
+        mtime,
+        oid,
+        dirty
+    );
+    if attr.kind == FileType::Symlink {
+        let target = fs.do_readlink(ino)?;
+        let _ = write!(out, ",\"target\":{}", json_str(&String::from_utf8_lossy(&target)));
+    }
+    out.push('}');
+    Ok(out)
+}
+
+fn json(status: u16, body: String) -> Response {
+    let mut response = Response::new(status).header("Content-Type", "application/json".to_owned());
+    response.body = Body::Bytes(body.into_bytes());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Repository;
+    use openat::Dir;
+    use std::io::{Read, Write};
+
+    fn call(addr: SocketAddr, head: &str) -> (u16, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(format!("{}\r\nHost: localhost\r\nConnection: close\r\n\r\n", head).as_bytes()).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        let status = head[9..12].parse().unwrap();
+        (status, head, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn tree_is_listed_and_read() {
+        let root = std::env::temp_dir().join(format!("gitfs-api-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("overlay")).unwrap();
+        let repo = Repository::init(root.join("repo")).unwrap();
+        let (blob, commit) = {
+            let blob = repo.blob(b"hello world").unwrap();
+            let mut top = repo.treebuilder(None).unwrap();
+            top.insert("a.txt", blob, 0o100644).unwrap();
+            top.insert("link", repo.blob(b"a.txt").unwrap(), 0o120000).unwrap();
+            let tree = repo.find_tree(top.write().unwrap()).unwrap();
+            let sig = git2::Signature::now("test", "test@example.com").unwrap();
+            (blob, repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap())
+        };
+        std::fs::write(root.join("overlay/new.txt"), b"dirty").unwrap();
+        let fs = GitFS::new(repo, Dir::open(&root.join("overlay")).unwrap());
+        let (_server, addr) = fs.serve_api("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let (status, _, body) = call(addr, "GET / HTTP/1.1");
+        assert_eq!(status, 200);
+        assert_eq!(String::from_utf8(body).unwrap(), format!("{{\"ref\":\"HEAD\",\"commit\":\"{}\"}}\n", commit));
+
+        let (status, head, body) = call(addr, "GET /tree/ HTTP/1.1");
+        assert_eq!(status, 200);
+        assert!(head.contains("Content-Type: application/json\r\n"), "{}", head);
+        let body = String::from_utf8(body).unwrap();
+        let a = format!("{{\"name\":\"a.txt\",\"type\":\"file\",\"size\":11,\"mode\":\"100644\",\"mtime\":0,\"oid\":\"{}\",\"dirty\":false}}", blob);
+        assert!(body.starts_with(&format!("{{\"path\":\"/\",\"entries\":[{},", a)), "{}", body);
+        assert!(body.contains("\"name\":\"new.txt\",\"type\":\"file\",\"size\":5,"), "{}", body);
+        assert!(body.contains("\"oid\":null,\"dirty\":true"), "{}", body);
+        assert!(body.contains(",\"target\":\"a.txt\"}"), "{}", body);
+
+        let (_, _, body) = call(addr, "GET /meta/link HTTP/1.1");
+        assert!(String::from_utf8(body).unwrap().contains("\"type\":\"symlink\""));
+        let (status, _, body) = call(addr, "GET /blob/link HTTP/1.1\r\nRange: bytes=6-");
+        assert_eq!((status, &body[..]), (206, &b"world"[..]));
+        let (status, _, body) = call(addr, "GET /blob/nope HTTP/1.1");
+        assert_eq!(status, 404);
+        assert!(String::from_utf8(body).unwrap().contains("\"errno\":2}"));
+        assert_eq!(call(addr, "GET /blob/ HTTP/1.1").0, 409);
+        assert_eq!(call(addr, "PUT /blob/a.txt HTTP/1.1").0, 405);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
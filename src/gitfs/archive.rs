@@ -0,0 +1,645 @@
+/// Archives of commits, as `git archive` makes them, for the files
+/// under `/.gitfs/archive`.
+///
+/// Archives are made in memory, in one go.  The tar format is the same
+/// as `git archive`'s: a pax global header holding the commit id, then
+/// every entry with the commit time, owned by root, with the modes
+/// `git archive` gives them by default.  Paths too long for a ustar
+/// header get a pax header of their own.
+///
+/// As in `git archive`, the attributes of the archived tree are
+/// honored: paths with `export-ignore` are left out, and in files with
+/// `export-subst`, `$Format:<format>$` is replaced with the commit
+/// formatted as `git log --format=<format>` would.  The placeholders
+/// of ids (`%H`, `%h`, `%T`, `%t`, `%P`, `%p`), names, emails and dates
+/// (`%an`, `%ae`, `%ad`, `%aD`, `%ai`, `%aI`, `%at`, `%as`, and their
+/// `%c` counterparts), messages (`%s`, `%b`, `%B`), `%n`, `%%` and
+/// `%x<hex>` are expanded; others are left as they are.
+///
+/// OCI image layers are made the same way: that of a commit is its
+/// archive without the global header, and that of a mount holds what
+/// differs from its commit, with whiteouts for what is gone.
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use git2::{Commit, ObjectType, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use libc::{c_int, c_void, ENOENT};
+use libz_sys as z;
+
+use super::changes::{Change, Content, GIT_COMMIT, GIT_TREE};
+use super::smudge::Attributes;
+use super::GitFS;
+use crate::error::Error;
+
+const BLOCK: usize = 512;
+/// Archives are padded to a whole record of this many bytes, as tar
+/// does.
+const RECORD: usize = 20 * BLOCK;
+/// What the name of a whiteout starts with in an OCI layer.
+pub(super) const WHITEOUT: &[u8] = b".wh.";
+/// A whiteout in a dir that hides the whole dir of the lower layers.
+pub(super) const OPAQUE: &[u8] = b".wh..wh..opq";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum Format {
+    Tar,
+    TarGz,
+}
+
+impl Format {
+    pub(super) const ALL: [Format; 2] = [Format::Tar, Format::TarGz];
+
+    pub(super) fn suffix(self) -> &'static str {
+        match self {
+            Format::Tar => ".tar",
+            Format::TarGz => ".tar.gz",
+        }
+    }
+
+    /// Split the name of an archive into the rev and the format.
+    pub(super) fn parse(name: &OsStr) -> Option<(String, Format)> {
+        let name = name.to_str()?;
+        // `.tar.gz` first, since it ends in neither.
+        [Format::TarGz, Format::Tar].iter().find_map(|&format| {
+            let rev = name.strip_suffix(format.suffix())?;
+            Some((rev.to_owned(), format)).filter(|_| !rev.is_empty())
+        })
+    }
+}
+
+/// Make an archive of `tree`, of `commit`.
+pub(super) fn archive(repo: &Repository, commit: &Commit, tree: &Tree, format: Format) -> Result<Vec<u8>, Error> {
+    let mut out = vec![];
+    let mtime = commit_time(commit);
+    let comment = format!("comment={}", commit.id());
+    write_entry(&mut out, b"pax_global_header", b'g', 0o666, mtime, &pax_record_list(&[comment.as_bytes()]), b"");
+    write_tree(&mut out, repo, tree, b"", mtime, Some(commit))?;
+    finish(out, format)
+}
+
+/// Make an OCI image layer of the tree of `commit`: the archive
+/// without its global header, which image tools have no use for.
+pub(super) fn layer(repo: &Repository, commit: &Commit, format: Format) -> Result<Vec<u8>, Error> {
+    let mut out = vec![];
+    write_tree(&mut out, repo, &commit.tree()?, b"", commit_time(commit), None)?;
+    finish(out, format)
+}
+
+impl GitFS {
+    /// Make an OCI image layer of what differs in the mount from the
+    /// mounted commit, to go on top of the layer of that commit.
+    ///
+    /// Deleted paths get a whiteout (`.wh.<name>`), as do paths where
+    /// a file took the place of a dir or the other way round.  Dirs
+    /// moved over dirs of the commit are marked opaque
+    /// (`.wh..wh..opq`), so that nothing of the old dir shows through.
+    /// Every entry has the commit time, as in archives, so that the
+    /// same changes always make the same layer.
+    pub(super) fn changes_layer(&self, format: Format) -> Result<Vec<u8>, Error> {
+        let commit = self.head().commit.ok_or(Error::Errno(ENOENT))?;
+        let changes = self.changes()?;
+        let repo = self.repo();
+        let commit = repo.find_commit(commit)?;
+        let base = self.mounted_tree(&repo, &commit)?;
+        let mtime = commit_time(&commit);
+
+        let mut out = vec![];
+        let mut dirs = HashSet::new();
+        for (path, change) in &changes {
+            let mut parents: Vec<_> = path.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty()).collect();
+            parents.reverse();
+            for dir in parents {
+                if dirs.insert(dir.to_owned()) {
+                    write_dir(&mut out, dir.as_os_str().as_bytes(), mtime);
+                }
+            }
+
+            let content = match change {
+                Change::Deleted(..) => None,
+                Change::Added(content) | Change::Modified(content) | Change::TypeChanged(content) => Some(content),
+            };
+            let is_dir = matches!(content, Some(Content::Object(_, GIT_TREE)));
+            let was_dir = base.get_path(path).ok().map(|entry| entry.kind() == Some(ObjectType::Tree));
+            if was_dir.is_some() && (content.is_none() || was_dir != Some(is_dir)) {
+                let name = path.file_name().unwrap_or_default().as_bytes();
+                write_whiteout(&mut out, path.parent().unwrap_or(Path::new("")), &[WHITEOUT, name].concat(), mtime);
+            }
+
+            let path_bytes = path.as_os_str().as_bytes();
+            match content {
+                None => (),
+                Some(Content::Object(oid, GIT_TREE)) => {
+                    dirs.insert(path.clone());
+                    write_dir(&mut out, path_bytes, mtime);
+                    if was_dir == Some(true) {
+                        write_whiteout(&mut out, path, OPAQUE, mtime);
+                    }
+                    write_tree(&mut out, &repo, &repo.find_tree(*oid)?, &[path_bytes, b"/"].concat(), mtime, None)?;
+                }
+                // A moved submodule, which is an empty dir as in git archive.
+                Some(Content::Object(_, GIT_COMMIT)) => write_dir(&mut out, path_bytes, mtime),
+                Some(Content::Object(oid, mode)) => {
+                    write_blob(&mut out, path_bytes, *mode, repo.find_blob(*oid)?.content(), mtime);
+                }
+                Some(Content::Overlay(overlay_path, mode)) => {
+                    write_blob(&mut out, path_bytes, *mode, &self.overlay_read(overlay_path)?, mtime);
+                }
+            }
+        }
+        finish(out, format)
+    }
+}
+
+fn commit_time(commit: &Commit) -> u64 {
+    commit.time().seconds().max(0) as u64
+}
+
+/// Append every entry of `tree` to a tar archive, under `prefix`,
+/// which is empty or ends in a slash.  With the commit it's exported
+/// from, the export attributes of `tree` are honored.
+fn write_tree(
+    out: &mut Vec<u8>,
+    repo: &Repository,
+    tree: &Tree,
+    prefix: &[u8],
+    mtime: u64,
+    export: Option<&Commit>,
+) -> Result<(), Error> {
+    let mut attributes: HashMap<PathBuf, Attributes> = HashMap::new();
+    let mut entries = vec![];
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        let mut subst = false;
+        if export.is_some() {
+            let attributes = attributes.entry(PathBuf::from(dir)).or_insert_with(|| Attributes::load(repo, tree, Path::new(dir)));
+            let path = Path::new(dir).join(OsStr::from_bytes(entry.name_bytes()));
+            if attributes.is_set(&path, "export-ignore") {
+                return TreeWalkResult::Skip;
+            }
+            subst = attributes.is_set(&path, "export-subst");
+        }
+        let mut path = prefix.to_vec();
+        path.extend_from_slice(dir.as_bytes());
+        path.extend_from_slice(entry.name_bytes());
+        entries.push((path, entry.filemode(), entry.id(), entry.kind(), subst));
+        TreeWalkResult::Ok
+    })?;
+
+    for (path, mode, oid, kind, subst) in entries {
+        match kind {
+            Some(ObjectType::Blob) => {
+                let blob = repo.find_blob(oid)?;
+                match export {
+                    Some(commit) if subst => write_blob(out, &path, mode, &format_subst(repo, commit, blob.content()), mtime),
+                    _ => write_blob(out, &path, mode, blob.content(), mtime),
+                }
+            }
+            // Submodules show up as empty dirs, as in `git archive`.
+            Some(ObjectType::Tree) | Some(ObjectType::Commit) => write_dir(out, &path, mtime),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Replace each `$Format:<format>$` in `content` with `commit` in that
+/// format, as `git archive` does in files with `export-subst`.
+fn format_subst(repo: &Repository, commit: &Commit, content: &[u8]) -> Vec<u8> {
+    const START: &[u8] = b"$Format:";
+    let mut out = Vec::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(at) = rest.windows(START.len()).position(|window| window == START) {
+        let end = match rest[at + START.len()..].iter().position(|&b| b == b'$') {
+            Some(end) => at + START.len() + end,
+            None => break,
+        };
+        out.extend_from_slice(&rest[..at]);
+        format_commit(&mut out, repo, commit, &rest[at + START.len()..end]);
+        rest = &rest[end + 1..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+/// Append `commit` in `format`, as in `git log --format`.
+fn format_commit(out: &mut Vec<u8>, repo: &Repository, commit: &Commit, format: &[u8]) {
+    let short = |object: Result<git2::Object, git2::Error>| {
+        object.and_then(|object| object.short_id()).ok().and_then(|id| id.as_str().map(str::to_owned)).unwrap_or_default()
+    };
+    let message = commit.message_bytes();
+    let (subject, body) = split_message(message);
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' {
+            out.push(format[i]);
+            i += 1;
+            continue;
+        }
+        let spec = &format[i + 1..];
+        let (expansion, len): (Vec<u8>, usize) = match spec {
+            [b'H', ..] => (commit.id().to_string().into_bytes(), 1),
+            [b'h', ..] => (short(Ok(commit.as_object().clone())).into_bytes(), 1),
+            [b'T', ..] => (commit.tree_id().to_string().into_bytes(), 1),
+            [b't', ..] => (short(repo.find_object(commit.tree_id(), Some(ObjectType::Tree))).into_bytes(), 1),
+            [b'P', ..] => (commit.parent_ids().map(|id| id.to_string()).collect::<Vec<_>>().join(" ").into_bytes(), 1),
+            [b'p', ..] => {
+                let parents: Vec<_> = commit.parent_ids().map(|id| short(repo.find_object(id, None))).collect();
+                (parents.join(" ").into_bytes(), 1)
+            }
+            [who @ (b'a' | b'c'), what, ..] => {
+                let signature = if *who == b'a' { commit.author() } else { commit.committer() };
+                match format_signature(&signature, *what) {
+                    Some(expansion) => (expansion, 2),
+                    None => (vec![], 0),
+                }
+            }
+            [b's', ..] => (subject.clone(), 1),
+            [b'b', ..] => (body.to_vec(), 1),
+            [b'B', ..] => (message.to_vec(), 1),
+            [b'n', ..] => (b"\n".to_vec(), 1),
+            [b'%', ..] => (b"%".to_vec(), 1),
+            [b'x', hex @ ..] if hex.len() >= 2 => match std::str::from_utf8(&hex[..2]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                Some(byte) => (vec![byte], 3),
+                None => (vec![], 0),
+            },
+            _ => (vec![], 0),
+        };
+        // Unknown placeholders are left as they are.
+        if len == 0 {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+        out.extend_from_slice(&expansion);
+        i += 1 + len;
+    }
+}
+
+/// The name (`n`), email (`e`) or date (`d`, `D`, `i`, `I`, `t`, `s`)
+/// of a signature, as the placeholders of `git log --format` give them.
+fn format_signature(signature: &git2::Signature, what: u8) -> Option<Vec<u8>> {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let when = signature.when();
+    let offset = when.offset_minutes();
+    let tm = time::at_utc(time::Timespec::new(when.seconds() + i64::from(offset) * 60, 0));
+    let sign = if offset < 0 { '-' } else { '+' };
+    let (hours, minutes) = (offset.abs() / 60, offset.abs() % 60);
+    let (day, month, year) = (DAYS[tm.tm_wday as usize % 7], MONTHS[tm.tm_mon as usize % 12], tm.tm_year + 1900);
+    let clock = format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec);
+    let date = format!("{}-{:02}-{:02}", year, tm.tm_mon + 1, tm.tm_mday);
+    let expansion = match what {
+        b'n' => return Some(signature.name_bytes().to_vec()),
+        b'e' => return Some(signature.email_bytes().to_vec()),
+        b'd' => format!("{} {} {} {} {} {}{:02}{:02}", day, month, tm.tm_mday, clock, year, sign, hours, minutes),
+        b'D' => format!("{}, {} {} {} {} {}{:02}{:02}", day, tm.tm_mday, month, year, clock, sign, hours, minutes),
+        b'i' => format!("{} {} {}{:02}{:02}", date, clock, sign, hours, minutes),
+        b'I' => format!("{}T{}{}{:02}:{:02}", date, clock, sign, hours, minutes),
+        b't' => when.seconds().to_string(),
+        b's' => date,
+        _ => return None,
+    };
+    Some(expansion.into_bytes())
+}
+
+/// The subject of a commit message, its first paragraph on one line,
+/// and its body, the rest.
+fn split_message(message: &[u8]) -> (Vec<u8>, &[u8]) {
+    let start = message.iter().position(|&b| b != b'\n').unwrap_or(message.len());
+    let message = &message[start..];
+    let (first, body) = match message.windows(2).position(|window| window == b"\n\n") {
+        Some(at) => (&message[..at], &message[at + 2..]),
+        None => (message, &[][..]),
+    };
+    let start = body.iter().position(|&b| b != b'\n').unwrap_or(body.len());
+    let lines: Vec<_> = first.split(|&b| b == b'\n').map(|line| line.trim_ascii()).filter(|line| !line.is_empty()).collect();
+    (lines.join(&b' '), &body[start..])
+}
+
+/// Append a file or symlink with this mode in git, with the modes
+/// `git archive` gives them by default.
+fn write_blob(out: &mut Vec<u8>, path: &[u8], mode: i32, content: &[u8], mtime: u64) {
+    let (typeflag, perm, data, link) = match mode {
+        0o120000 => (b'2', 0o777, &[][..], content),
+        0o100755 => (b'0', 0o775, content, &[][..]),
+        _ => (b'0', 0o664, content, &[][..]),
+    };
+    write_entry(out, path, typeflag, perm, mtime, data, link);
+}
+
+fn write_dir(out: &mut Vec<u8>, path: &[u8], mtime: u64) {
+    write_entry(out, &[path, b"/"].concat(), b'5', 0o775, mtime, b"", b"");
+}
+
+/// Append an empty file named `name` in `dir`, which OCI layers take
+/// as a whiteout.
+fn write_whiteout(out: &mut Vec<u8>, dir: &Path, name: &[u8], mtime: u64) {
+    let path = if dir.as_os_str().is_empty() { name.to_vec() } else { [dir.as_os_str().as_bytes(), b"/", name].concat() };
+    write_entry(out, &path, b'0', 0o644, mtime, b"", b"");
+}
+
+/// End a tar archive, and compress it if asked to.
+fn finish(mut out: Vec<u8>, format: Format) -> Result<Vec<u8>, Error> {
+    out.resize(out.len() + 2 * BLOCK, 0);
+    out.resize(round_up(out.len(), RECORD), 0);
+    Ok(match format {
+        Format::Tar => out,
+        Format::TarGz => gzip(&out)?,
+    })
+}
+
+/// Append an entry to a tar archive, preceded by a pax header if its
+/// path, link or size don't fit in a ustar header.
+fn write_entry(out: &mut Vec<u8>, path: &[u8], typeflag: u8, perm: u32, mtime: u64, data: &[u8], link: &[u8]) {
+    let size = data.len() as u64;
+    let mut records = vec![];
+    let (path_record, link_record, size_record);
+    if path.len() > 100 {
+        path_record = [b"path=", path].concat();
+        records.push(&path_record[..]);
+    }
+    if link.len() > 100 {
+        link_record = [b"linkpath=", link].concat();
+        records.push(&link_record[..]);
+    }
+    if size > 0o77777777777 {
+        size_record = format!("size={}", size).into_bytes();
+        records.push(&size_record[..]);
+    }
+    if !records.is_empty() {
+        let pax = pax_record_list(&records);
+        let mut name = b"PaxHeaders/".to_vec();
+        name.extend_from_slice(&path[..path.len().min(100 - name.len())]);
+        write_entry(out, &name, b'x', 0o666, mtime, &pax, b"");
+    }
+
+    let mut header = [0u8; BLOCK];
+    put(&mut header[0..100], path);
+    put_octal(&mut header[100..108], u64::from(perm));
+    put_octal(&mut header[108..116], 0);
+    put_octal(&mut header[116..124], 0);
+    put_octal(&mut header[124..136], if size > 0o77777777777 { 0 } else { size });
+    put_octal(&mut header[136..148], mtime.min(0o77777777777));
+    header[156] = typeflag;
+    put(&mut header[157..257], link);
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    put(&mut header[265..297], b"root");
+    put(&mut header[297..329], b"root");
+    // The checksum is computed with its own field as spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    out.extend_from_slice(&header);
+    out.extend_from_slice(data);
+    out.resize(round_up(out.len(), BLOCK), 0);
+}
+
+/// Pax records, each as "<length> <key>=<value>\n" where the length
+/// counts itself.
+fn pax_record_list(records: &[&[u8]]) -> Vec<u8> {
+    let mut out = vec![];
+    for record in records {
+        let rest = record.len() + 2;
+        let mut len = rest + 1;
+        while len != rest + len.to_string().len() {
+            len = rest + len.to_string().len();
+        }
+        out.extend_from_slice(format!("{} ", len).as_bytes());
+        out.extend_from_slice(record);
+        out.push(b'\n');
+    }
+    out
+}
+
+/// Copy as much of `value` as fits into a field.
+fn put(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+/// Write a number as zero-padded octal, terminated by NUL.
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    put(field, digits.as_bytes());
+}
+
+fn round_up(n: usize, to: usize) -> usize {
+    n.div_ceil(to) * to
+}
+
+/// Compress `data` in the gzip format, with zlib.
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    unsafe extern "C" fn alloc(_: *mut c_void, items: z::uInt, size: z::uInt) -> *mut c_void {
+        libc::calloc(items as usize, size as usize)
+    }
+    unsafe extern "C" fn free(_: *mut c_void, address: *mut c_void) {
+        libc::free(address)
+    }
+    let error = |what: &str, ret: c_int| io::Error::other(format!("zlib {} failed: {}", what, ret));
+
+    let mut stream = z::z_stream {
+        next_in: ptr::null_mut(),
+        avail_in: 0,
+        total_in: 0,
+        next_out: ptr::null_mut(),
+        avail_out: 0,
+        total_out: 0,
+        msg: ptr::null_mut(),
+        state: ptr::null_mut(),
+        zalloc: alloc,
+        zfree: free,
+        opaque: ptr::null_mut(),
+        data_type: 0,
+        adler: 0,
+        reserved: 0,
+    };
+    // 16 more window bits ask for a gzip header and trailer.
+    let ret = unsafe {
+        z::deflateInit2_(
+            &mut stream,
+            z::Z_DEFAULT_COMPRESSION,
+            z::Z_DEFLATED,
+            15 + 16,
+            8,
+            z::Z_DEFAULT_STRATEGY,
+            z::zlibVersion(),
+            mem::size_of::<z::z_stream>() as c_int,
+        )
+    };
+    if ret != z::Z_OK {
+        return Err(error("init", ret));
+    }
+
+    let mut out: Vec<u8> = Vec::with_capacity(data.len() / 4 + 64);
+    let mut input = data;
+    let result = loop {
+        let chunk = input.len().min(z::uInt::MAX as usize);
+        let flush = if chunk == input.len() { z::Z_FINISH } else { z::Z_NO_FLUSH };
+        stream.next_in = input.as_ptr() as *mut u8;
+        stream.avail_in = chunk as z::uInt;
+        let ret = loop {
+            out.reserve(64 * 1024);
+            let spare = (out.capacity() - out.len()).min(z::uInt::MAX as usize);
+            stream.next_out = unsafe { out.as_mut_ptr().add(out.len()) };
+            stream.avail_out = spare as z::uInt;
+            let ret = unsafe { z::deflate(&mut stream, flush) };
+            unsafe { out.set_len(out.len() + spare - stream.avail_out as usize) };
+            match ret {
+                z::Z_OK | z::Z_BUF_ERROR if flush == z::Z_NO_FLUSH && stream.avail_in == 0 => break z::Z_OK,
+                z::Z_OK | z::Z_BUF_ERROR => continue,
+                ret => break ret,
+            }
+        };
+        input = &input[chunk..];
+        match ret {
+            z::Z_STREAM_END => break Ok(out),
+            z::Z_OK => continue,
+            ret => break Err(error("deflate", ret)),
+        }
+    };
+    unsafe { z::deflateEnd(&mut stream) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// The path, type, size and content of each entry of an archive.
+    fn list(tar: &[u8]) -> Vec<(String, u8, Vec<u8>)> {
+        let mut entries = vec![];
+        let mut offset = 0;
+        while tar[offset..offset + BLOCK].iter().any(|&b| b != 0) {
+            let header = &tar[offset..offset + BLOCK];
+            let checksum: u32 = header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { 32 } else { u32::from(b) }).sum();
+            assert_eq!(std::str::from_utf8(&header[148..154]).unwrap(), format!("{:06o}", checksum));
+            let name = header[..100].split(|&b| b == 0).next().unwrap();
+            let size = u64::from_str_radix(std::str::from_utf8(&header[124..135]).unwrap(), 8).unwrap() as usize;
+            let data = tar[offset + BLOCK..offset + BLOCK + size].to_vec();
+            entries.push((String::from_utf8(name.to_vec()).unwrap(), header[156], data));
+            offset += BLOCK + round_up(size, BLOCK);
+        }
+        assert_eq!(tar.len() % RECORD, 0);
+        entries
+    }
+
+    #[test]
+    fn makes_tar_archives() {
+        let root = std::env::temp_dir().join(format!("gitfs-archive-{}", std::process::id()));
+        let repo = Repository::init(&root).unwrap();
+        let long = "x".repeat(120);
+        let commit = {
+            let mut update = git2::build::TreeUpdateBuilder::new();
+            update.upsert("a.txt", repo.blob(b"hello").unwrap(), git2::FileMode::Blob);
+            update.upsert("dir/run", repo.blob(b"#!/bin/sh\n").unwrap(), git2::FileMode::BlobExecutable);
+            update.upsert(Path::new(&long), repo.blob(b"").unwrap(), git2::FileMode::Blob);
+            update.upsert("link", repo.blob(b"a.txt").unwrap(), git2::FileMode::Link);
+            let empty = repo.treebuilder(None).unwrap().write().unwrap();
+            let tree = repo.find_tree(update.create_updated(&repo, &repo.find_tree(empty).unwrap()).unwrap()).unwrap();
+            let sig = git2::Signature::new("test", "test@example.com", &git2::Time::new(1_000_000_000, 0)).unwrap();
+            let id = repo.commit(None, &sig, &sig, "test", &tree, &[]).unwrap();
+            repo.find_commit(id).unwrap()
+        };
+
+        let tar = archive(&repo, &commit, &commit.tree().unwrap(), Format::Tar).unwrap();
+        let entries = list(&tar);
+        let names: Vec<_> = entries.iter().map(|(name, typeflag, _)| (name.as_str(), *typeflag)).collect();
+        let pax_name = format!("PaxHeaders/{}", &long[..89]);
+        assert_eq!(names, vec![
+            ("pax_global_header", b'g'),
+            ("a.txt", b'0'),
+            ("dir/", b'5'),
+            ("dir/run", b'0'),
+            ("link", b'2'),
+            (&pax_name, b'x'),
+            (&long[..100], b'0'),
+        ]);
+        assert_eq!(entries[0].2, format!("52 comment={}\n", commit.id()).into_bytes());
+        assert_eq!(entries[1].2, b"hello");
+        assert_eq!(entries[5].2, format!("130 path={}\n", long).into_bytes());
+
+        let gz = archive(&repo, &commit, &commit.tree().unwrap(), Format::TarGz).unwrap();
+        assert_eq!(gz[..2], [0x1f, 0x8b]);
+        let isize = u32::from_le_bytes([gz[gz.len() - 4], gz[gz.len() - 3], gz[gz.len() - 2], gz[gz.len() - 1]]);
+        assert_eq!(isize as usize, tar.len());
+        assert!(gz.len() < tar.len() / 4);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn archives_honor_export_attributes() {
+        let root = std::env::temp_dir().join(format!("gitfs-export-{}", std::process::id()));
+        let repo = Repository::init(&root).unwrap();
+        let commit = {
+            let mut update = git2::build::TreeUpdateBuilder::new();
+            let attributes = b"secret export-ignore\nignored export-ignore\nversion.txt export-subst\n";
+            update.upsert(".gitattributes", repo.blob(attributes).unwrap(), git2::FileMode::Blob);
+            update.upsert("a.txt", repo.blob(b"$Format:%H$").unwrap(), git2::FileMode::Blob);
+            update.upsert("dir/secret", repo.blob(b"").unwrap(), git2::FileMode::Blob);
+            update.upsert("ignored/a.txt", repo.blob(b"").unwrap(), git2::FileMode::Blob);
+            let version = b"$Format:%h %an <%ae> %ad%n%aI %as %s%$ $Format:%x41%%%z$ $Format:";
+            update.upsert("version.txt", repo.blob(version).unwrap(), git2::FileMode::Blob);
+            let empty = repo.treebuilder(None).unwrap().write().unwrap();
+            let tree = repo.find_tree(update.create_updated(&repo, &repo.find_tree(empty).unwrap()).unwrap()).unwrap();
+            let sig = git2::Signature::new("test", "test@example.com", &git2::Time::new(1_000_000_000, 120)).unwrap();
+            let id = repo.commit(None, &sig, &sig, "a\nsubject\n\nbody\n", &tree, &[]).unwrap();
+            repo.find_commit(id).unwrap()
+        };
+
+        let tar = archive(&repo, &commit, &commit.tree().unwrap(), Format::Tar).unwrap();
+        let entries = list(&tar);
+        let names: Vec<_> = entries.iter().map(|(name, _, _)| name.as_str()).collect();
+        assert_eq!(names, ["pax_global_header", ".gitattributes", "a.txt", "dir/", "version.txt"]);
+        assert_eq!(entries[2].2, b"$Format:%H$");
+        let short = commit.as_object().short_id().unwrap();
+        let version = format!(
+            "{} test <test@example.com> Sun Sep 9 03:46:40 2001 +0200\n2001-09-09T03:46:40+02:00 2001-09-09 a subject% A%%z $Format:",
+            short.as_str().unwrap()
+        );
+        assert_eq!(String::from_utf8_lossy(&entries[4].2), version);
+
+        // Layers are of the tree as mounted.
+        let layer = list(&layer(&repo, &commit, Format::Tar).unwrap());
+        assert!(layer.iter().any(|(name, _, _)| name == "dir/secret"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn layers_have_no_global_header() {
+        let root = std::env::temp_dir().join(format!("gitfs-layer-{}", std::process::id()));
+        let repo = Repository::init(&root).unwrap();
+        let commit = {
+            let mut update = git2::build::TreeUpdateBuilder::new();
+            update.upsert("dir/a.txt", repo.blob(b"hello").unwrap(), git2::FileMode::Blob);
+            let empty = repo.treebuilder(None).unwrap().write().unwrap();
+            let tree = repo.find_tree(update.create_updated(&repo, &repo.find_tree(empty).unwrap()).unwrap()).unwrap();
+            let sig = git2::Signature::new("test", "test@example.com", &git2::Time::new(1_000_000_000, 0)).unwrap();
+            let id = repo.commit(None, &sig, &sig, "test", &tree, &[]).unwrap();
+            repo.find_commit(id).unwrap()
+        };
+
+        let tar = layer(&repo, &commit, Format::Tar).unwrap();
+        let entries = list(&tar);
+        let names: Vec<_> = entries.iter().map(|(name, typeflag, _)| (name.as_str(), *typeflag)).collect();
+        assert_eq!(names, vec![("dir/", b'5'), ("dir/a.txt", b'0')]);
+        assert_eq!(&layer(&repo, &commit, Format::TarGz).unwrap()[..2], [0x1f, 0x8b]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn parses_archive_names() {
+        assert_eq!(Format::parse(OsStr::new("main.tar")), Some(("main".to_owned(), Format::Tar)));
+        assert_eq!(Format::parse(OsStr::new("v1.0.tar.gz")), Some(("v1.0".to_owned(), Format::TarGz)));
+        assert_eq!(Format::parse(OsStr::new(".tar")), None);
+        assert_eq!(Format::parse(OsStr::new("main.zip")), None);
+    }
+}
@@ -0,0 +1,88 @@
+/// The audit log: a line for every change made through the mount, for
+/// mounts shared by several users.
+///
+/// Each line holds the time, the uid and pid of the process that made
+/// the change, the operation and the paths it was on:
+///
+/// ```text
+/// 2026-10-14T09:27:28.769192Z uid=1000 pid=4242 write "dir/a.txt" offset=0 size=5
+/// 2026-10-14T09:27:29.001274Z uid=1000 pid=4242 rename "dir/a.txt" "b.txt"
+/// ```
+///
+/// Paths are quoted and escaped as in Rust, so that any name fits on
+/// a line.  Only changes that succeeded are logged.
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::PoisonError;
+
+use fuser::Request;
+
+use super::GitFS;
+use crate::logging;
+
+impl GitFS {
+    /// Log a change made by `req` to the audit log, if there is one.
+    /// `describe` tells the paths and details of the change, and is
+    /// only called then.
+    pub(super) fn audit<F: FnOnce() -> String>(&self, req: &Request<'_>, op: &str, describe: F) {
+        self.audit_as(req.uid(), req.pid(), op, describe)
+    }
+
+    /// Log a change made by the process `pid` of the user `uid`.
+    pub(super) fn audit_as<F: FnOnce() -> String>(&self, uid: u32, pid: u32, op: &str, describe: F) {
+        let log = match &self.inner.audit_log {
+            Some(log) => log,
+            None => return,
+        };
+        let line = format!(
+            "{} uid={} pid={} {} {}\n",
+            logging::timestamp(),
+            uid,
+            pid,
+            op,
+            describe()
+        );
+        let log = log.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut file: &File = &log;
+        // One write per line, so that lines of several mounts sharing
+        // a log opened for appending don't interleave.
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            error!(%e, op, "cannot write to the audit log");
+        }
+    }
+
+    /// The path of the entry `name` in the dir `parent`.
+    pub(super) fn child_path(&self, parent: u64, name: &OsStr) -> PathBuf {
+        self.path_of(parent.into()).join(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn changes_are_audited() {
+        let root = std::env::temp_dir().join(format!("gitfs-audit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("overlay")).unwrap();
+        let repo = git2::Repository::init(root.join("repo")).unwrap();
+        let log = OpenOptions::new().create(true).append(true).open(root.join("audit.log")).unwrap();
+        let fs = GitFS::builder(repo, openat::Dir::open(&root.join("overlay")).unwrap()).audit_log(log).build();
+
+        fs.audit_as(1000, 42, "write", || format!("{:?} offset={} size={}", fs.child_path(1, OsStr::new("a b\n")), 0, 5));
+        fs.audit_as(0, 1, "unlink", || format!("{:?}", PathBuf::from("dir/c")));
+
+        let log = std::fs::read_to_string(root.join("audit.log")).unwrap();
+        let lines: Vec<_> = log.lines().map(|line| line.split_once(' ').unwrap()).collect();
+        assert!(lines.iter().all(|(time, _)| time.ends_with('Z')));
+        assert_eq!(
+            lines.iter().map(|(_, rest)| *rest).collect::<Vec<_>>(),
+            [r#"uid=1000 pid=42 write "a b\n" offset=0 size=5"#, r#"uid=0 pid=1 unlink "dir/c""#]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
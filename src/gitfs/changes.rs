@@ -0,0 +1,337 @@
+/// What differs from the mounted tree, and turning it into a tree.
+///
+/// Changes are found in two places: files in the underlying dir are
+/// compared with the tree by content, while clean entries that were
+/// removed or renamed through the mount are only known to the InoMap.
+/// The latter are forgotten when the mount switches to another tree.
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::os::unix::{ffi::OsStrExt, fs::PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use fuser::FileType;
+use git2::build::TreeUpdateBuilder;
+use git2::{FileMode, ObjectType, Oid, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use openat::SimpleType;
+
+use super::{archive, submodule, GitFS};
+use crate::error::Error;
+use crate::names;
+use crate::{Entry, EntryKind, Ino, MODE_SYMLINK, MODE_TYPE};
+
+/// Modes of tree entries, as in git.
+pub(super) const GIT_TREE: i32 = 0o040000;
+const GIT_BLOB: i32 = 0o100644;
+const GIT_EXECUTABLE: i32 = 0o100755;
+pub(super) const GIT_LINK: i32 = 0o120000;
+pub(super) const GIT_COMMIT: i32 = 0o160000;
+
+/// How a path differs from the mounted tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Change {
+    /// Not in the tree.
+    Added(Content),
+    /// A file in the tree, with other content or mode.
+    Modified(Content),
+    /// A file where the tree has a dir.
+    TypeChanged(Content),
+    /// In the tree, but removed or renamed through the mount.  Holds
+    /// the object and mode in the tree.
+    Deleted(Oid, i32),
+}
+
+/// What is at a changed path now.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Content {
+    /// A file in the underlying dir, at this path there, with its mode
+    /// in git.
+    Overlay(PathBuf, i32),
+    /// An object of the repository, with its mode, e.g. a clean file
+    /// that was renamed.
+    Object(Oid, i32),
+}
+
+impl Change {
+    /// As in `git diff --name-status`.
+    pub(super) fn letter(&self) -> char {
+        match self {
+            Change::Added(_) => 'A',
+            Change::Modified(_) => 'M',
+            Change::TypeChanged(_) => 'T',
+            Change::Deleted(..) => 'D',
+        }
+    }
+
+    /// The tree that the change applies to as a whole, if any.
+    pub(super) fn tree(&self) -> Option<Oid> {
+        match self {
+            Change::Added(Content::Object(oid, GIT_TREE)) | Change::Deleted(oid, GIT_TREE) => Some(*oid),
+            _ => None,
+        }
+    }
+}
+
+impl GitFS {
+    /// Every path that differs from the mounted tree.  Moved and
+    /// deleted dirs are a single change.
+    pub(super) fn changes(&self) -> Result<BTreeMap<PathBuf, Change>, Error> {
+        let mut changes = BTreeMap::new();
+        let mut files = vec![];
+        self.list_overlay_files(Path::new(""), &mut files);
+
+        let inomap = self.inomap();
+        let root_tree = match inomap.get(Ino::ROOT).map(|root| &root.u) {
+            Some(EntryKind::GitTree { oid, .. }) => *oid,
+            _ => return Ok(changes),
+        };
+        // Markers of image layers are under other names in the mount,
+        // or not there at all.
+        let whiteouts = self.whiteouts();
+        let repo = self.repo();
+        let tree = repo.find_tree(root_tree)?;
+
+        for (ino, entry) in inomap.iter() {
+            // What is in a submodule isn't in this repository.
+            if submodule::in_submodule(&inomap, ino) {
+                continue;
+            }
+            let path = match inomap.prefix(ino) {
+                Some(path) => path,
+                None => continue,
+            };
+            let in_place = ino.is_root() || tree.get_path(&path).map(|e| e.id()).ok() == entry_oid(entry);
+            match &entry.u {
+                EntryKind::GitBlob { .. } if FileType::from(entry) == FileType::CharDevice => (),
+                EntryKind::GitBlob { oid, .. } if !in_place => {
+                    changes.insert(path, Change::Added(Content::Object(*oid, blob_mode(entry))));
+                }
+                EntryKind::GitTree { commit: Some(commit), .. } if !in_place => {
+                    changes.insert(path, Change::Added(Content::Object(*commit, GIT_COMMIT)));
+                }
+                EntryKind::GitTree { commit: Some(_), .. } => (),
+                // The entries of listed dirs that were moved are looked
+                // at on their own.
+                EntryKind::GitTree { oid, children: None, .. } if !in_place => {
+                    changes.insert(path, Change::Added(Content::Object(*oid, GIT_TREE)));
+                }
+                EntryKind::GitTree { oid, children: Some(children), .. } if in_place => {
+                    let listed = match repo.find_tree(*oid) {
+                        Ok(listed) => listed,
+                        Err(e) => {
+                            debug!(%oid, %e, "cannot read tree");
+                            continue;
+                        }
+                    };
+                    for tree_entry in listed.iter() {
+                        let name = OsStr::from_bytes(tree_entry.name_bytes());
+                        let marker = whiteouts && tree_entry.name_bytes().starts_with(archive::WHITEOUT);
+                        // Submodules that can't be shown are still there.
+                        let hidden = tree_entry.kind() == Some(ObjectType::Commit)
+                            && self.is_hidden_submodule(&path.join(name), tree_entry.id());
+                        if names::is_valid(name) && !children.contains_key(name) && !marker && !hidden {
+                            let change = Change::Deleted(tree_entry.id(), tree_entry.filemode());
+                            changes.insert(path.join(name), change);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        drop(inomap);
+
+        for (path, overlay_path, size, mode) in files {
+            let change = match tree.get_path(&path) {
+                Err(_) => Some(Change::Added(Content::Overlay(overlay_path, mode.unwrap_or(GIT_BLOB)))),
+                Ok(tree_entry) if tree_entry.kind() == Some(ObjectType::Tree) => {
+                    Some(Change::TypeChanged(Content::Overlay(overlay_path, mode.unwrap_or(GIT_BLOB))))
+                }
+                Ok(tree_entry) => {
+                    let mode = mode.unwrap_or_else(|| tree_entry.filemode());
+                    match self.same_content(&repo, &tree, &path, tree_entry.id(), &overlay_path, size) {
+                        Ok(true) if mode == tree_entry.filemode() => None,
+                        Ok(_) => Some(Change::Modified(Content::Overlay(overlay_path, mode))),
+                        Err(e) => {
+                            debug!(?path, %e, "cannot compare with the tree");
+                            Some(Change::Modified(Content::Overlay(overlay_path, mode)))
+                        }
+                    }
+                }
+            };
+            if let Some(change) = change {
+                changes.insert(path, change);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Every file in the mount, with where its content is, given the
+    /// mounted tree `base`.
+    pub(super) fn mounted_files(&self, base: Oid) -> Result<BTreeMap<PathBuf, Content>, Error> {
+        let changes = self.changes()?;
+        let repo = self.repo();
+        let mut files = BTreeMap::new();
+        for_each_blob(&repo, base, Path::new(""), |path, oid, mode| {
+            files.insert(path, Content::Object(oid, mode));
+        });
+        for (path, change) in changes {
+            let content = match change {
+                Change::Deleted(..) => {
+                    files.retain(|file, _| !file.starts_with(&path));
+                    continue;
+                }
+                Change::TypeChanged(content) => {
+                    files.retain(|file, _| !file.starts_with(&path));
+                    content
+                }
+                Change::Added(content) | Change::Modified(content) => content,
+            };
+            match content {
+                Content::Object(oid, GIT_TREE) => for_each_blob(&repo, oid, &path, |path, oid, mode| {
+                    files.insert(path, Content::Object(oid, mode));
+                }),
+                // Nor are the files of submodules.
+                Content::Object(_, GIT_COMMIT) => (),
+                content => {
+                    files.insert(path, content);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Write the tree that results from applying `changes` to `base`.
+    pub(super) fn apply_changes(
+        &self,
+        repo: &Repository,
+        base: &Tree,
+        changes: &BTreeMap<PathBuf, Change>,
+    ) -> Result<Oid, Error> {
+        let mut update = TreeUpdateBuilder::new();
+        for (path, change) in changes {
+            let (oid, mode) = match change {
+                Change::Deleted(..) => {
+                    update.remove(path.as_path());
+                    continue;
+                }
+                Change::Added(content) | Change::Modified(content) | Change::TypeChanged(content) => match content {
+                    Content::Object(oid, mode) => (*oid, *mode),
+                    Content::Overlay(overlay_path, mode) => {
+                        let content = self.clean(repo, base, path, self.overlay_read(overlay_path)?)?;
+                        (repo.blob(&content)?, *mode)
+                    }
+                },
+            };
+            update.upsert(path.as_path(), oid, file_mode(mode));
+        }
+        Ok(update.create_updated(repo, base)?)
+    }
+
+    /// Append the files in `prefix` of the underlying dir to `files`,
+    /// recursively, with their paths in the mount and in the
+    /// underlying dir, their sizes, and their modes in git if the
+    /// underlying dir keeps mode bits.
+    #[allow(clippy::type_complexity)]
+    pub(super) fn list_overlay_files(&self, prefix: &Path, files: &mut Vec<(PathBuf, PathBuf, u64, Option<i32>)>) {
+        let dir = if prefix.as_os_str().is_empty() { Path::new(".") } else { prefix };
+        let entries = match self.inner.underlying_dir.list_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!(?prefix, %e, "cannot list the underlying dir");
+                return;
+            }
+        };
+        let names = self.names();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let overlay_path = prefix.join(entry.file_name());
+            match entry.simple_type() {
+                Some(SimpleType::Dir) => self.list_overlay_files(&overlay_path, files),
+                Some(SimpleType::File) => {
+                    let path: PathBuf = overlay_path.iter().map(|name| names.mount_name(name)).collect();
+                    let metadata = match self.inner.underlying_dir.metadata(&overlay_path) {
+                        Ok(metadata) => metadata,
+                        Err(_) => continue,
+                    };
+                    let mode = match metadata.permissions().mode() & 0o111 {
+                        _ if !self.inner.file_mode => None,
+                        0 => Some(GIT_BLOB),
+                        _ => Some(GIT_EXECUTABLE),
+                    };
+                    files.push((path, overlay_path, self.overlay_len(metadata.len()), mode));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Whether a file in the underlying dir holds the same content as
+    /// a blob, once cleaned (see `Options::smudge`).  Files of another
+    /// size are not read, unless they're cleaned.
+    fn same_content(
+        &self,
+        repo: &Repository,
+        root: &Tree,
+        path: &Path,
+        oid: Oid,
+        overlay_path: &Path,
+        size: u64,
+    ) -> Result<bool, Error> {
+        let (blob_size, _) = repo.odb()?.read_header(oid)?;
+        if blob_size as u64 != size && !self.options_read().smudge {
+            return Ok(false);
+        }
+        let content = self.clean(repo, root, path, self.overlay_read(overlay_path)?)?;
+        Ok(Oid::hash_object(ObjectType::Blob, &content)? == oid)
+    }
+}
+
+fn entry_oid(entry: &Entry) -> Option<Oid> {
+    match entry.u {
+        EntryKind::GitTree { commit: Some(commit), .. } => Some(commit),
+        EntryKind::GitBlob { oid, .. } | EntryKind::GitTree { oid, .. } => Some(oid),
+        _ => None,
+    }
+}
+
+/// The mode in git of a clean file, going by its permissions.
+fn blob_mode(entry: &Entry) -> i32 {
+    let mode = entry.perm.mode();
+    if mode & MODE_TYPE == MODE_SYMLINK {
+        GIT_LINK
+    } else if mode & 0o111 != 0 {
+        GIT_EXECUTABLE
+    } else {
+        GIT_BLOB
+    }
+}
+
+fn file_mode(mode: i32) -> FileMode {
+    match mode {
+        GIT_TREE => FileMode::Tree,
+        GIT_EXECUTABLE => FileMode::BlobExecutable,
+        GIT_LINK => FileMode::Link,
+        GIT_COMMIT => FileMode::Commit,
+        _ => FileMode::Blob,
+    }
+}
+
+/// Call `f` with the path, id and mode of every blob in a tree at
+/// `prefix`.
+pub(super) fn for_each_blob<F: FnMut(PathBuf, Oid, i32)>(repo: &Repository, tree_id: Oid, prefix: &Path, mut f: F) {
+    let tree = match repo.find_tree(tree_id) {
+        Ok(tree) => tree,
+        Err(e) => {
+            debug!(%tree_id, %e, "cannot read tree");
+            return;
+        }
+    };
+    let walked = tree.walk(TreeWalkMode::PreOrder, |dir, tree_entry| {
+        if tree_entry.kind() == Some(ObjectType::Blob) {
+            let name = OsStr::from_bytes(tree_entry.name_bytes());
+            f(prefix.join(dir).join(name), tree_entry.id(), tree_entry.filemode());
+        }
+        TreeWalkResult::Ok
+    });
+    if let Err(e) = walked {
+        debug!(%tree_id, %e, "cannot walk tree");
+    }
+}
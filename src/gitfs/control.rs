@@ -0,0 +1,1233 @@
+/// The control dir, `/.gitfs`: virtual files to look into and control
+/// a mount from within it, with nothing more than `cat` and `echo`.
+///
+/// The control dir is not listed in the root, so that walking the
+/// mount (`find`, `du`, `cp -r`) doesn't run into it, but it can be
+/// looked up by name.  It hides any entry of the same name in the tree
+/// or the underlying dir.
+///
+/// The content of a virtual file is generated when it's opened, and
+/// stays the same for as long as it's open.  As in procfs, virtual
+/// files show a size of 0; reads bypass the page cache, so that they
+/// get the whole content anyway.
+///
+/// With `Options::chunk_size`, `chunks` mirrors the dirs of the mounted
+/// commit with a dir for each file larger than the chunk size, holding
+/// the file as stored cut in chunks of that size (`000001`, `000002`
+/// and so on) and a `manifest` of them, for tools that can't take
+/// files that large in one piece.  `cat chunks/<path>/0*` puts it back
+/// together.
+///
+/// `ctl` takes commands, one per line, and runs each once its line is
+/// complete (or the file is closed):
+///
+/// ```text
+/// refresh             switch to where the mounted ref points now
+/// checkout <ref>      switch to another ref or commit
+/// prev                step to the first parent of the commit
+/// next                step back toward where the steps started
+/// goto <commit>       step to another commit, to go on from there
+/// commit <message>    commit the changes shown in status
+/// tidy                drop dirty files left the same as their blob
+/// invalidate [<path>] look at path (or everything) in the underlying dir again
+/// grep <string> [-- <path>...]
+///                     look for a string in the files of the mount
+/// selfcheck           check that what the mount holds is consistent
+/// reload              read the config file again, as on SIGHUP
+/// ```
+///
+/// Reading `ctl` returns the results of the commands of the last
+/// write, one line each: `ok`, possibly followed by a commit id, or
+/// `error: ` and what went wrong.  `grep` returns `ok` and the number
+/// of matching lines, then each of them as `path:number:line`,
+/// `selfcheck` the number of inconsistencies, then each of them, and
+/// `tidy` the number of files dropped.  The
+/// write fails too, with the errno of the first failed command, so
+/// that e.g. `echo refresh > ctl` fails in a script.
+///
+/// With `Options::git_file`, the root also has a `.git` file, not
+/// listed either, as git makes in a linked worktree: it holds
+/// `gitdir: ` and the path of the repository, so that `git status`,
+/// `git log` and IDEs find the repository from within the mount, which
+/// they take for its worktree.  The index is that of the repository,
+/// so `git status` shows how the mount differs from it, not from the
+/// mounted commit, unless they are the same.  Git gives up on a bare
+/// repository, having no worktree to take.
+///
+/// `ctl` is the only control plane: each mount is a `git-mount`
+/// process of its own, managed by systemd (`gitfs@.service`), and
+/// there is no daemon holding many mounts to put an RPC service such
+/// as gRPC in front of.  Such a service would also need an HTTP/2 and
+/// protobuf stack, which gitfs doesn't depend on.
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, MutexGuard, PoisonError};
+use std::time::SystemTime;
+
+use fuser::{FileAttr, FileType};
+use git2::{BlameOptions, ObjectType, Oid, Repository};
+use libc::{EACCES, EBADF, EINVAL, EISDIR, ENOENT, ENOTDIR, EPERM, O_ACCMODE, O_RDONLY};
+
+use super::archive::{self, Format};
+use super::changes::{self, Content, GIT_LINK};
+use super::lfs::{self, Pointer};
+use super::stats;
+use super::{GitFS, SetAttr, Step};
+use crate::error::Error;
+use crate::names;
+use crate::Ino;
+
+/// The name of the control dir in the root.
+pub(super) const NAME: &str = ".gitfs";
+
+/// The name of the file pointing at the repository in the root.
+const GIT_FILE: &str = ".git";
+
+/// The BSD flags of the control dir and all in it: Finder doesn't
+/// show them (`UF_HIDDEN`), as `ls` doesn't show dot files.
+#[cfg(target_os = "macos")]
+const HIDDEN: u32 = libc::UF_HIDDEN;
+#[cfg(not(target_os = "macos"))]
+const HIDDEN: u32 = 0;
+
+/// How many of the largest blobs `objects` lists.
+const LARGEST_BLOBS: usize = 10;
+
+/// How many of the paths most operated on `stats` lists.
+const HOT_PATHS: usize = 10;
+
+/// How many commits `log` shows at most, so that opening it stays
+/// quick in a long history.
+const LOG_LIMIT: usize = 1000;
+
+/// The name of chunk `number` of a file, which sorts by number as long
+/// as there are fewer than a million.
+fn chunk_name(number: u64) -> String {
+    format!("{:06}", number)
+}
+
+/// Nodes of the control dir get inos from here on, far above those
+/// handed out by the InoMap.
+const FIRST_INO: u64 = 1 << 63;
+
+/// Whether `ino` is in the control dir (or is the control dir).
+pub(super) fn owns(ino: Ino) -> bool {
+    u64::from(ino) >= FIRST_INO
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Node {
+    /// The control dir itself.
+    Dir,
+    /// `status`: what is mounted, and which paths are dirty.
+    Status,
+    /// `log`: the history of the mounted commit, as in `git log`.
+    Log,
+    /// `stats`: counters of what the mount has been doing, and where.
+    Stats,
+    /// `health`: whether the repository can be read and the
+    /// underlying dir written to.
+    Health,
+    /// `lfs`: the Git LFS pointer files in the mount, and whether their
+    /// objects are there.
+    Lfs,
+    /// `objects`: what the object database holds, as in
+    /// `git count-objects -v`, and its largest blobs.
+    Objects,
+    /// `ctl`: commands in, results out.
+    Ctl,
+    /// A dir under `blame`, mirroring a dir of the mounted commit.
+    BlameDir(PathBuf),
+    /// A file under `blame`: each line of a file of the mounted
+    /// commit, with the commit that last changed it, as in `git blame`.
+    BlameFile(PathBuf),
+    /// `archive`, with an archive of every branch and tag in it, and of
+    /// any other rev that is looked up.
+    ArchiveDir,
+    /// An archive of the tree of a rev.
+    Archive(String, Format),
+    /// A dir under `chunks`, mirroring a dir of the mounted commit.
+    ChunksDir(PathBuf),
+    /// The dir under `chunks` of a file of the mounted commit larger
+    /// than the chunk size, with its chunks and manifest.
+    Chunks(PathBuf),
+    /// A chunk of a file, numbered from 1.
+    Chunk(PathBuf, u64),
+    /// What the chunks of a file are: where each starts, and its size.
+    Manifest(PathBuf),
+    /// `layer.tar` and `layer.tar.gz`: an OCI image layer of what
+    /// differs from the mounted commit, to go on top of a layer of it.
+    Layer(Format),
+    /// `/.git`, with the path of the repository.
+    GitFile,
+}
+
+impl Node {
+    fn kind(&self) -> FileType {
+        match self {
+            Node::Dir | Node::BlameDir(_) | Node::ArchiveDir | Node::ChunksDir(_) | Node::Chunks(_) => FileType::Directory,
+            _ => FileType::RegularFile,
+        }
+    }
+
+    fn perm(&self) -> u16 {
+        match self {
+            Node::Dir | Node::BlameDir(_) | Node::ArchiveDir | Node::ChunksDir(_) | Node::Chunks(_) => 0o555,
+            Node::Ctl => 0o600,
+            _ => 0o444,
+        }
+    }
+}
+
+/// An open virtual file.
+#[derive(Debug)]
+enum OpenFile {
+    /// Content generated on open.
+    Generated(Arc<[u8]>),
+    /// `ctl`, with what was written to it after the last complete
+    /// line.
+    Ctl(Vec<u8>),
+}
+
+/// The nodes of the control dir that were looked up, and the virtual
+/// files that are open.
+#[derive(Debug, Default)]
+pub(super) struct Control {
+    /// The node of each ino, from `FIRST_INO` on.
+    nodes: Vec<Node>,
+    inos: HashMap<Node, Ino>,
+    files: HashMap<u64, OpenFile>,
+    next_fh: u64,
+    /// What the commands of the last write to `ctl` returned.
+    results: Arc<[u8]>,
+}
+
+impl Control {
+    /// The ino of a node; it's the same every time.
+    fn ino(&mut self, node: Node) -> Ino {
+        if let Some(&ino) = self.inos.get(&node) {
+            return ino;
+        }
+        let ino = Ino::from(FIRST_INO + self.nodes.len() as u64);
+        self.nodes.push(node.clone());
+        self.inos.insert(node, ino);
+        ino
+    }
+
+    fn node(&self, ino: Ino) -> Result<Node, Error> {
+        let index = u64::from(ino).checked_sub(FIRST_INO).ok_or(Error::Errno(ENOENT))?;
+        self.nodes.get(index as usize).cloned().ok_or(Error::Errno(ENOENT))
+    }
+}
+
+/// A command written to `ctl`.
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Refresh,
+    Checkout(String),
+    Step(Step),
+    Commit(String),
+    Tidy,
+    Invalidate(Option<PathBuf>),
+    /// A fixed string to look for, in these paths (or everywhere).
+    Grep(String, Vec<PathBuf>),
+    SelfCheck,
+    Reload,
+}
+
+impl Command {
+    fn parse(line: &[u8]) -> Result<Command, String> {
+        let line = std::str::from_utf8(line).map_err(|_| "not UTF-8".to_owned())?.trim();
+        let (name, arg) = match line.find(char::is_whitespace) {
+            Some(at) => (&line[..at], line[at..].trim_start()),
+            None => (line, ""),
+        };
+        match (name, arg) {
+            ("refresh", "") => Ok(Command::Refresh),
+            ("checkout", refspec) if !refspec.is_empty() => Ok(Command::Checkout(refspec.to_owned())),
+            ("prev", "") => Ok(Command::Step(Step::Prev)),
+            ("next", "") => Ok(Command::Step(Step::Next)),
+            ("goto", refspec) if !refspec.is_empty() => Ok(Command::Step(Step::Goto(refspec.to_owned()))),
+            ("commit", message) if !message.is_empty() => Ok(Command::Commit(message.to_owned())),
+            ("tidy", "") => Ok(Command::Tidy),
+            ("invalidate", "") => Ok(Command::Invalidate(None)),
+            ("selfcheck", "") => Ok(Command::SelfCheck),
+            ("reload", "") => Ok(Command::Reload),
+            ("invalidate", path) => Ok(Command::Invalidate(Some(PathBuf::from(path.trim_matches('/'))))),
+            ("grep", arg) if !arg.is_empty() => {
+                let (pattern, paths) = match arg.find(" -- ") {
+                    Some(at) => (&arg[..at], arg[at + 4..].split_whitespace().collect()),
+                    None => (arg, vec![]),
+                };
+                let paths = paths.into_iter().map(|path| PathBuf::from(path.trim_matches('/'))).collect();
+                Ok(Command::Grep(pattern.to_owned(), paths))
+            }
+            ("refresh", _) | ("checkout", _) | ("prev", _) | ("next", _) | ("goto", _) | ("commit", _) | ("grep", _)
+            | ("tidy", _) | ("selfcheck", _) | ("reload", _) => {
+                Err(format!("bad arguments to {}", name))
+            }
+            _ => Err(format!("unknown command: {}", name)),
+        }
+    }
+}
+
+impl GitFS {
+    fn control(&self) -> MutexGuard<'_, Control> {
+        self.inner.control.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether `name` in `parent` is the control dir.
+    pub(super) fn is_control_dir(&self, parent: Ino, name: &OsStr) -> bool {
+        parent.is_root() && name == NAME && self.options_read().control_dir
+    }
+
+    /// Whether `name` in `parent` is the control dir or `/.git`.
+    pub(super) fn is_control_entry(&self, parent: Ino, name: &OsStr) -> bool {
+        self.is_control_dir(parent, name) || (parent.is_root() && name == GIT_FILE && self.options_read().git_file)
+    }
+
+    /// Refuse to change anything in the control dir, the control dir
+    /// itself, or `/.git` (EPERM).
+    pub(super) fn check_control(&self, parent: Ino, name: &OsStr) -> Result<(), Error> {
+        if owns(parent) || self.is_control_entry(parent, name) {
+            return Err(Error::Errno(EPERM));
+        }
+        Ok(())
+    }
+
+    pub(super) fn control_lookup(&self, parent: Ino, name: &OsStr) -> Result<FileAttr, Error> {
+        let node = if self.is_control_dir(parent, name) {
+            Node::Dir
+        } else if self.is_control_entry(parent, name) {
+            Node::GitFile
+        } else {
+            let parent = self.control().node(parent)?;
+            if parent == Node::ArchiveDir {
+                return self.archive_lookup(name);
+            }
+            self.control_children(&parent)?
+                .into_iter()
+                .find(|(child, _)| child == name)
+                .map(|(_, node)| node)
+                .ok_or(Error::Errno(ENOENT))?
+        };
+        let ino = self.control().ino(node.clone());
+        Ok(self.control_attr(ino, &node))
+    }
+
+    pub(super) fn control_getattr(&self, ino: Ino) -> Result<FileAttr, Error> {
+        let node = self.control().node(ino)?;
+        Ok(self.control_attr(ino, &node))
+    }
+
+    /// Only `ctl` can be truncated, which is what opening it with
+    /// O_TRUNC (as a shell redirection does) amounts to.  There is
+    /// nothing to truncate.
+    pub(super) fn control_setattr(&self, ino: Ino, attrs: SetAttr) -> Result<FileAttr, Error> {
+        let node = self.control().node(ino)?;
+        let truncate = attrs.size;
+        let others = SetAttr {
+            size: None,
+            atime: None,
+            mtime: None,
+            ..attrs
+        };
+        if node != Node::Ctl || truncate.unwrap_or(0) != 0 || others != SetAttr::default() {
+            return Err(Error::Errno(EPERM));
+        }
+        Ok(self.control_attr(ino, &node))
+    }
+
+    pub(super) fn control_opendir(&self, ino: Ino) -> Result<(), Error> {
+        match self.control().node(ino)?.kind() {
+            FileType::Directory => Ok(()),
+            _ => Err(Error::Errno(ENOTDIR)),
+        }
+    }
+
+    pub(super) fn control_readdir(&self, ino: Ino) -> Result<Vec<(OsString, Ino, FileType)>, Error> {
+        let node = self.control().node(ino)?;
+        if node.kind() != FileType::Directory {
+            return Err(Error::Errno(ENOTDIR));
+        }
+        let children = self.control_children(&node)?;
+        let mut control = self.control();
+        Ok(children
+            .into_iter()
+            .map(|(name, child)| {
+                let kind = child.kind();
+                (name, control.ino(child), kind)
+            })
+            .collect())
+    }
+
+    fn control_children(&self, node: &Node) -> Result<Vec<(OsString, Node)>, Error> {
+        let mut children = match node {
+            Node::Dir => vec![
+                ("archive", Node::ArchiveDir),
+                ("blame", Node::BlameDir(PathBuf::new())),
+                ("ctl", Node::Ctl),
+                ("health", Node::Health),
+                ("layer.tar", Node::Layer(Format::Tar)),
+                ("layer.tar.gz", Node::Layer(Format::TarGz)),
+                ("lfs", Node::Lfs),
+                ("log", Node::Log),
+                ("objects", Node::Objects),
+                ("stats", Node::Stats),
+                ("status", Node::Status),
+            ],
+            Node::BlameDir(path) => return self.blame_children(path),
+            Node::ChunksDir(path) => return self.chunks_children(path),
+            Node::Chunks(path) => return self.chunk_files(path),
+            Node::ArchiveDir => return self.archive_children(),
+            _ => vec![],
+        };
+        if *node == Node::Dir && self.chunk_size().is_some() {
+            children.insert(2, ("chunks", Node::ChunksDir(PathBuf::new())));
+        }
+        Ok(children.into_iter().map(|(name, node)| (name.into(), node)).collect())
+    }
+
+    /// Any rev can be looked up in `archive`, not only the listed ones.
+    fn archive_lookup(&self, name: &OsStr) -> Result<FileAttr, Error> {
+        let (rev, format) = Format::parse(name).ok_or(Error::Errno(ENOENT))?;
+        if self.resolve(&rev).is_err() {
+            return Err(Error::Errno(ENOENT));
+        }
+        let node = Node::Archive(rev, format);
+        let ino = self.control().ino(node.clone());
+        Ok(self.control_attr(ino, &node))
+    }
+
+    /// `HEAD`, and the branches and tags whose names can be file names.
+    fn archive_children(&self) -> Result<Vec<(OsString, Node)>, Error> {
+        let mut revs = vec!["HEAD".to_owned()];
+        {
+            let repo = self.repo();
+            for reference in repo.references()? {
+                let reference = reference?;
+                if !(reference.is_branch() || reference.is_tag()) {
+                    continue;
+                }
+                match reference.shorthand() {
+                    Some(name) if !name.contains('/') && name != "HEAD" => revs.push(name.to_owned()),
+                    _ => (),
+                }
+            }
+        }
+        Ok(revs
+            .into_iter()
+            .flat_map(|rev| {
+                Format::ALL.iter().map(move |&format| {
+                    let name = format!("{}{}", rev, format.suffix());
+                    (name.into(), Node::Archive(rev.clone(), format))
+                })
+            })
+            .collect())
+    }
+
+    /// The entries of a dir of the mounted commit, as nodes under
+    /// `blame`.
+    fn blame_children(&self, path: &Path) -> Result<Vec<(OsString, Node)>, Error> {
+        let commit = self.head().commit.ok_or(Error::Errno(ENOENT))?;
+        let repo = self.repo();
+        let root = self.mounted_tree(&repo, &repo.find_commit(commit)?)?;
+        let tree = if path.as_os_str().is_empty() {
+            root
+        } else {
+            let entry = root.get_path(path).map_err(|_| Error::Errno(ENOENT))?;
+            entry.to_object(&repo)?.into_tree().map_err(|_| Error::Errno(ENOTDIR))?
+        };
+        Ok(tree
+            .iter()
+            .filter_map(|entry| {
+                let name = OsStr::from_bytes(entry.name_bytes());
+                if !names::is_valid(name) {
+                    return None;
+                }
+                let node = match entry.kind() {
+                    Some(ObjectType::Tree) => Node::BlameDir(path.join(name)),
+                    Some(ObjectType::Blob) => Node::BlameFile(path.join(name)),
+                    _ => return None,
+                };
+                Some((name.to_owned(), node))
+            })
+            .collect())
+    }
+
+    /// The dirs and the files larger than the chunk size in a dir of
+    /// the mounted commit, as nodes under `chunks`.  Hidden paths are
+    /// left out.
+    fn chunks_children(&self, path: &Path) -> Result<Vec<(OsString, Node)>, Error> {
+        let chunk_size = self.chunk_size().ok_or(Error::Errno(ENOENT))?;
+        let options = self.options_read().clone();
+        let commit = self.head().commit.ok_or(Error::Errno(ENOENT))?;
+        let repo = self.repo();
+        let root = self.mounted_tree(&repo, &repo.find_commit(commit)?)?;
+        let tree = if path.as_os_str().is_empty() {
+            root
+        } else {
+            let entry = root.get_path(path).map_err(|_| Error::Errno(ENOENT))?;
+            entry.to_object(&repo)?.into_tree().map_err(|_| Error::Errno(ENOTDIR))?
+        };
+        let odb = repo.odb()?;
+        Ok(tree
+            .iter()
+            .filter_map(|entry| {
+                let name = OsStr::from_bytes(entry.name_bytes());
+                let is_tree = entry.kind() == Some(ObjectType::Tree);
+                if !names::is_valid(name) || super::hidden(&options, &path.join(name), is_tree) {
+                    return None;
+                }
+                let node = match entry.kind() {
+                    Some(ObjectType::Tree) => Node::ChunksDir(path.join(name)),
+                    Some(ObjectType::Blob) => match odb.read_header(entry.id()) {
+                        Ok((size, _)) if size as u64 > chunk_size => Node::Chunks(path.join(name)),
+                        _ => return None,
+                    },
+                    _ => return None,
+                };
+                Some((name.to_owned(), node))
+            })
+            .collect())
+    }
+
+    fn chunk_size(&self) -> Option<u64> {
+        self.options_read().chunk_size.filter(|&size| size > 0)
+    }
+
+    /// The blob of a file of the mounted commit larger than the chunk
+    /// size, with its size and the chunk size.
+    fn chunked_blob(&self, path: &Path) -> Result<(Oid, u64, u64), Error> {
+        let chunk_size = self.chunk_size().ok_or(Error::Errno(ENOENT))?;
+        let commit = self.head().commit.ok_or(Error::Errno(ENOENT))?;
+        let repo = self.repo();
+        let entry = self.mounted_tree(&repo, &repo.find_commit(commit)?)?.get_path(path).map_err(|_| Error::Errno(ENOENT))?;
+        if entry.kind() != Some(ObjectType::Blob) {
+            return Err(Error::Errno(ENOENT));
+        }
+        let (size, _) = repo.odb()?.read_header(entry.id())?;
+        match size as u64 {
+            size if size > chunk_size => Ok((entry.id(), size, chunk_size)),
+            _ => Err(Error::Errno(ENOENT)),
+        }
+    }
+
+    /// The chunks of a file, then its manifest.
+    fn chunk_files(&self, path: &Path) -> Result<Vec<(OsString, Node)>, Error> {
+        let (_, size, chunk_size) = self.chunked_blob(path)?;
+        let mut files: Vec<_> = (1..=size.div_ceil(chunk_size))
+            .map(|number| (chunk_name(number).into(), Node::Chunk(path.to_owned(), number)))
+            .collect();
+        files.push(("manifest".into(), Node::Manifest(path.to_owned())));
+        Ok(files)
+    }
+
+    /// Open a virtual file, generating its content.
+    pub(super) fn control_open(&self, ino: Ino, flags: i32) -> Result<u64, Error> {
+        let node = self.control().node(ino)?;
+        let file = match node {
+            Node::Dir | Node::BlameDir(_) | Node::ArchiveDir | Node::ChunksDir(_) | Node::Chunks(_) => {
+                return Err(Error::Errno(EISDIR))
+            }
+            Node::Ctl => OpenFile::Ctl(vec![]),
+            _ if flags & O_ACCMODE != O_RDONLY => return Err(Error::Errno(EACCES)),
+            Node::Status => OpenFile::Generated(self.render_status()?.into()),
+            Node::Log => OpenFile::Generated(self.render_log()?.into()),
+            Node::Stats => OpenFile::Generated(self.render_stats().into()),
+            Node::Health => OpenFile::Generated(self.render_health().into()),
+            Node::Objects => OpenFile::Generated(self.render_objects()?.into()),
+            Node::Lfs => OpenFile::Generated(self.render_lfs()?.into()),
+            Node::BlameFile(path) => OpenFile::Generated(self.render_blame(&path)?.into()),
+            Node::Chunk(path, number) => OpenFile::Generated(self.render_chunk(&path, number)?),
+            Node::Manifest(path) => OpenFile::Generated(self.render_manifest(&path)?.into()),
+            Node::Archive(rev, format) => {
+                let (commit, _) = self.resolve(&rev)?;
+                let repo = self.repo();
+                let commit = repo.find_commit(commit)?;
+                let content = archive::archive(&repo, &commit, &self.mounted_tree(&repo, &commit)?, format)?;
+                OpenFile::Generated(content.into())
+            }
+            Node::Layer(format) => OpenFile::Generated(self.changes_layer(format)?.into()),
+            Node::GitFile => OpenFile::Generated(self.render_git_file().into()),
+        };
+        let mut control = self.control();
+        control.next_fh += 1;
+        let fh = control.next_fh;
+        control.files.insert(fh, file);
+        Ok(fh)
+    }
+
+    pub(super) fn control_read(&self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        let content = {
+            let control = self.control();
+            match control.files.get(&fh).ok_or(Error::Errno(EBADF))? {
+                OpenFile::Generated(content) => content.clone(),
+                OpenFile::Ctl(_) => control.results.clone(),
+            }
+        };
+        Ok(content[super::read_range(content.len(), offset, size)].to_vec())
+    }
+
+    /// Take commands written to `ctl`, and run the complete ones.
+    /// Where they are written is of no importance.
+    pub(super) fn control_write(&self, fh: u64, data: &[u8]) -> Result<u32, Error> {
+        let lines: Vec<u8> = match self.control().files.get_mut(&fh) {
+            Some(OpenFile::Ctl(pending)) => {
+                pending.extend_from_slice(data);
+                match pending.iter().rposition(|&b| b == b'\n') {
+                    Some(end) => pending.drain(..=end).collect(),
+                    None => return Ok(data.len() as u32),
+                }
+            }
+            _ => return Err(Error::Errno(EBADF)),
+        };
+        self.run_commands(&lines)?;
+        Ok(data.len() as u32)
+    }
+
+    /// Run what is left of a command without a final newline.
+    pub(super) fn control_flush(&self, fh: u64) -> Result<(), Error> {
+        let rest = match self.control().files.get_mut(&fh) {
+            Some(OpenFile::Ctl(pending)) => std::mem::take(pending),
+            _ => return Ok(()),
+        };
+        if rest.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+        self.run_commands(&rest)
+    }
+
+    pub(super) fn control_release(&self, fh: u64) {
+        if let Err(e) = self.control_flush(fh) {
+            debug!(%e, "command failed on release");
+        }
+        self.control().files.remove(&fh);
+    }
+
+    /// Run commands, one per line, and keep their results for `ctl`
+    /// to return.  Return the first error.
+    fn run_commands(&self, lines: &[u8]) -> Result<(), Error> {
+        let mut results = Vec::new();
+        let mut first_error = None;
+        for line in lines.split(|&b| b == b'\n') {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let result = match Command::parse(line) {
+                Ok(command) => {
+                    info!(?command, "running command");
+                    self.run_command(command).map_err(|e| (e.to_string(), e))
+                }
+                Err(message) => Err((message, Error::Errno(EINVAL))),
+            };
+            match result {
+                Ok(Some(output)) => results.extend_from_slice(format!("ok {}\n", output).as_bytes()),
+                Ok(None) => results.extend_from_slice(b"ok\n"),
+                Err((message, e)) => {
+                    results.extend_from_slice(format!("error: {}\n", message).as_bytes());
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        self.control().results = results.into();
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn run_command(&self, command: Command) -> Result<Option<String>, Error> {
+        match command {
+            Command::Refresh => {
+                self.refresh()?;
+            }
+            Command::Checkout(refspec) => self.checkout(&refspec)?,
+            Command::Step(step) => return Ok(Some(self.step(step)?.to_string())),
+            Command::Commit(message) => return Ok(Some(self.commit(&message)?.to_string())),
+            Command::Tidy => return Ok(Some(self.tidy().to_string())),
+            Command::Grep(pattern, paths) => return Ok(Some(self.grep(pattern.as_bytes(), &paths)?)),
+            Command::SelfCheck => {
+                let problems = self.self_check();
+                let lines: String = problems.iter().map(|problem| format!("\n{}", problem)).collect();
+                return Ok(Some(format!("{}{}", problems.len(), lines)));
+            }
+            Command::Reload => {
+                self.reload_config()?;
+                return Ok(None);
+            }
+            Command::Invalidate(path) => {
+                let names = self.names();
+                match path {
+                    Some(path) => {
+                        let path = names.overlay_path(&path);
+                        self.overlay_changed(Some(&path));
+                        // Names in the dir may have come and gone.
+                        self.overlay_changed(path.parent());
+                    }
+                    None => self.overlay_changed(None),
+                }
+                return Ok(None);
+            }
+        }
+        Ok(self.head().commit.map(|commit| commit.to_string()))
+    }
+
+    /// Look for `pattern` in the files of the mount, or in those under
+    /// `paths`, without materializing anything: clean files are read
+    /// from the repository.  Binary files are left out, as in
+    /// `git grep -I`.
+    fn grep(&self, pattern: &[u8], paths: &[PathBuf]) -> Result<String, Error> {
+        let commit = self.head().commit.ok_or(Error::Errno(ENOENT))?;
+        let tree = {
+            let repo = self.repo();
+            let commit = repo.find_commit(commit)?;
+            let tree = self.mounted_tree(&repo, &commit)?.id();
+            tree
+        };
+        let files = self.mounted_files(tree)?;
+
+        let mut matches = vec![];
+        for (path, content) in files {
+            if !paths.is_empty() && !paths.iter().any(|prefix| path.starts_with(prefix)) {
+                continue;
+            }
+            let data = match content {
+                Content::Object(_, GIT_LINK) => continue,
+                Content::Object(oid, _) => match self.repo().find_blob(oid) {
+                    Ok(blob) => blob.content().to_vec(),
+                    Err(e) => {
+                        debug!(?path, %e, "cannot read blob");
+                        continue;
+                    }
+                },
+                Content::Overlay(overlay_path, _) => match self.overlay_read(&overlay_path) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        debug!(?path, %e, "cannot read the underlying file");
+                        continue;
+                    }
+                },
+            };
+            if data[..data.len().min(8000)].contains(&0) {
+                continue;
+            }
+            for (i, line) in data.split(|&b| b == b'\n').enumerate() {
+                if line.windows(pattern.len()).any(|window| window == pattern) {
+                    let line = String::from_utf8_lossy(line);
+                    matches.push(format!("{}:{}:{}", path.display(), i + 1, line.trim_end_matches('\r')));
+                }
+            }
+        }
+        let mut out = matches.len().to_string();
+        for line in matches {
+            out.push('\n');
+            out.push_str(&line);
+        }
+        Ok(out)
+    }
+
+    fn control_attr(&self, ino: Ino, node: &Node) -> FileAttr {
+        let (uid, gid) = self.default_owner();
+        // Generated anew on every open.
+        let now = SystemTime::now();
+        // Git reads as much of `.git` as its size says.
+        let size = match node {
+            Node::GitFile => self.render_git_file().len() as u64,
+            _ => 0,
+        };
+        FileAttr {
+            ino: ino.into(),
+            size,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: node.kind(),
+            perm: node.perm(),
+            nlink: if node.kind() == FileType::Directory { 2 } else { 1 },
+            uid,
+            gid,
+            rdev: 0,
+            blksize: self.inner.blksize,
+            flags: HIDDEN,
+        }
+    }
+
+    /// Chunk `number` of the file at `path`.
+    fn render_chunk(&self, path: &Path, number: u64) -> Result<Arc<[u8]>, Error> {
+        let (oid, size, chunk_size) = self.chunked_blob(path)?;
+        let start = (number - 1) * chunk_size;
+        if start >= size {
+            return Err(Error::Errno(ENOENT));
+        }
+        let content = self.blob_content(oid)?;
+        Ok(content[start as usize..size.min(start + chunk_size) as usize].into())
+    }
+
+    /// The manifest of the chunks of the file at `path`: the file, then
+    /// each chunk with the offset it starts at and its size.
+    fn render_manifest(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let (oid, size, chunk_size) = self.chunked_blob(path)?;
+        let mut manifest = format!("path {}\nblob {}\nsize {}\nchunk-size {}\n", path.display(), oid, size, chunk_size);
+        for (i, start) in (0..size).step_by(chunk_size as usize).enumerate() {
+            let len = chunk_size.min(size - start);
+            manifest.push_str(&format!("{} {} {}\n", chunk_name(i as u64 + 1), start, len));
+        }
+        Ok(manifest.into_bytes())
+    }
+
+    /// The content of `/.git`, as in a linked worktree.
+    fn render_git_file(&self) -> Vec<u8> {
+        let repo = self.repo();
+        let mut out = b"gitdir: ".to_vec();
+        out.extend_from_slice(repo.path().as_os_str().as_bytes());
+        out.push(b'\n');
+        out
+    }
+
+    /// The content of `status`: the mounted ref and commit, whether
+    /// the mount is read-only, then each dirty path with how it
+    /// differs from the mounted tree, as in `git diff --name-status`.
+    fn render_status(&self) -> Result<Vec<u8>, Error> {
+        let (refspec, commit) = {
+            let head = self.head();
+            (head.refspec.clone(), head.commit)
+        };
+        let read_only = self.options_read().read_only;
+        let changes = self.changes()?;
+
+        let mut out = Vec::new();
+        out.extend_from_slice(format!("ref: {}\n", refspec).as_bytes());
+        if let Some(commit) = commit {
+            out.extend_from_slice(format!("commit: {}\n", commit).as_bytes());
+        }
+        out.extend_from_slice(format!("read-only: {}\n", if read_only { "yes" } else { "no" }).as_bytes());
+        out.push(b'\n');
+
+        let mut lines = vec![];
+        {
+            let repo = self.repo();
+            for (path, change) in &changes {
+                match change.tree() {
+                    // Each file of a moved or deleted dir.
+                    Some(tree) => changes::for_each_blob(&repo, tree, path, |path, _, _| lines.push((path, change.letter()))),
+                    None => lines.push((path.clone(), change.letter())),
+                }
+            }
+        }
+        lines.sort();
+        for (path, letter) in lines {
+            out.push(letter as u8);
+            out.push(b'\t');
+            out.extend_from_slice(path.as_os_str().as_bytes());
+            out.push(b'\n');
+        }
+        Ok(out)
+    }
+
+    /// The content of `lfs`: how many pointer files there are, and
+    /// how many of their objects (and bytes) are missing from the local
+    /// LFS store, then the object id, size, whether it's there, and the
+    /// path of each pointer file.
+    fn render_lfs(&self) -> Result<Vec<u8>, Error> {
+        let commit = self.head().commit.ok_or(Error::Errno(ENOENT))?;
+        let tree = {
+            let repo = self.repo();
+            let commit = repo.find_commit(commit)?;
+            let tree = self.mounted_tree(&repo, &commit)?.id();
+            tree
+        };
+        let files = self.mounted_files(tree)?;
+
+        let mut pointers = vec![];
+        for (path, content) in files {
+            let data = match content {
+                Content::Object(oid, _) => {
+                    let repo = self.repo();
+                    match repo.odb()?.read_header(oid) {
+                        Ok((size, _)) if size <= lfs::POINTER_MAX => (),
+                        _ => continue,
+                    }
+                    let data = match repo.find_blob(oid) {
+                        Ok(blob) => blob.content().to_vec(),
+                        Err(_) => continue,
+                    };
+                    data
+                }
+                Content::Overlay(overlay_path, _) => {
+                    let mut data = vec![0; lfs::POINTER_MAX + 1];
+                    match self.inner.underlying_dir.open_file(&overlay_path) {
+                        Ok(file) => {
+                            let nbytes = self.overlay_read_at(&file, &mut data, 0)?;
+                            data.truncate(nbytes);
+                        }
+                        Err(_) => continue,
+                    };
+                    data
+                }
+            };
+            if data.len() > lfs::POINTER_MAX {
+                continue;
+            }
+            if let Some(Pointer { oid, size }) = Pointer::parse(&data) {
+                let present = lfs::object_path(&self.repo(), &oid).exists();
+                pointers.push((oid, size, present, path));
+            }
+        }
+
+        let missing: Vec<_> = pointers.iter().filter(|(_, _, present, _)| !present).collect();
+        let mut out = format!(
+            "pointers: {}\nmissing: {}\nmissing-size: {}\n\n",
+            pointers.len(),
+            missing.len(),
+            missing.iter().map(|(_, size, _, _)| size).sum::<u64>()
+        )
+        .into_bytes();
+        for (oid, size, present, path) in &pointers {
+            let present = if *present { "present" } else { "missing" };
+            out.extend_from_slice(format!("{}\t{}\t{}\t", oid, size, present).as_bytes());
+            out.extend_from_slice(path.as_os_str().as_bytes());
+            out.push(b'\n');
+        }
+        Ok(out)
+    }
+
+    /// The content of `objects`: the loose objects and packs with
+    /// their size on disk, the number of distinct objects and their
+    /// total size unpacked, then the largest blobs.  Every object is
+    /// looked at, so it takes a while in a large repository.
+    fn render_objects(&self) -> Result<Vec<u8>, Error> {
+        let repo = self.repo();
+        let objects_dir = common_dir(&repo).join("objects");
+
+        let (mut loose, mut loose_size) = (0u64, 0u64);
+        for entry in fs::read_dir(&objects_dir)?.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name();
+            if name.len() != 2 || !name.to_string_lossy().chars().all(|c| c.is_ascii_hexdigit()) {
+                continue;
+            }
+            for object in fs::read_dir(entry.path())?.filter_map(|object| object.ok()) {
+                loose += 1;
+                loose_size += object.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+            }
+        }
+        let (mut packs, mut packed, mut pack_size) = (0u64, 0u64, 0u64);
+        if let Ok(entries) = fs::read_dir(objects_dir.join("pack")) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let path = entry.path();
+                if path.extension() != Some(OsStr::new("pack")) {
+                    continue;
+                }
+                packs += 1;
+                pack_size += entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                match index_object_count(&path.with_extension("idx")) {
+                    Ok(count) => packed += count,
+                    Err(e) => debug!(?path, %e, "cannot read pack index"),
+                }
+            }
+        }
+
+        let odb = repo.odb()?;
+        let (mut objects, mut size) = (0u64, 0u64);
+        let mut blobs = vec![];
+        let mut failed = None;
+        odb.foreach(|&oid| {
+            match odb.read_header(oid) {
+                Ok((object_size, kind)) => {
+                    objects += 1;
+                    size += object_size as u64;
+                    if kind == ObjectType::Blob {
+                        blobs.push((object_size, oid));
+                        if blobs.len() > 4 * LARGEST_BLOBS {
+                            blobs.sort_unstable_by(|a, b| b.cmp(a));
+                            blobs.truncate(LARGEST_BLOBS);
+                        }
+                    }
+                }
+                Err(e) => failed = Some(e),
+            }
+            // Objects in more than one pack are counted once.
+            true
+        })?;
+        if let Some(e) = failed {
+            debug!(%e, "cannot read some objects");
+        }
+        blobs.sort_unstable_by(|a, b| b.cmp(a));
+        blobs.truncate(LARGEST_BLOBS);
+
+        let mut out = String::new();
+        let mut line = |name: &str, value: u64| out.push_str(&format!("{}: {}\n", name, value));
+        line("loose-objects", loose);
+        line("loose-size", loose_size);
+        line("packs", packs);
+        line("packed-objects", packed);
+        line("pack-size", pack_size);
+        line("objects", objects);
+        line("object-size", size);
+        out.push('\n');
+        for (size, oid) in blobs {
+            out.push_str(&format!("{}\t{}\n", oid, size));
+        }
+        Ok(out.into_bytes())
+    }
+
+    /// The content of `stats`: one counter per line, then how many
+    /// times each operation was called, then the paths most operated
+    /// on, with how many times.
+    fn render_stats(&self) -> Vec<u8> {
+        let gauges = self.gauges();
+        let stats = &self.inner.stats;
+
+        let mut out = String::new();
+        let mut line = |name: &str, value: u64| out.push_str(&format!("{}: {}\n", name, value));
+        line("entries", gauges.entries as u64);
+        line("dirty-files", gauges.dirty_files as u64);
+        line("open-handles", gauges.open_handles as u64);
+        line("blob-cache-bytes", gauges.blob_cache_bytes as u64);
+        line("blob-cache-pinned-bytes", gauges.blob_cache_pinned_bytes as u64);
+        line("blob-cache-hits", stats::get(&stats.blob_cache_hits));
+        line("blob-cache-misses", stats::get(&stats.blob_cache_misses));
+        line("bytes-read", stats::get(&stats.bytes_read));
+        line("bytes-read-overlay", stats::get(&stats.bytes_read_overlay));
+        line("bytes-written", stats::get(&stats.bytes_written));
+        out.push('\n');
+        for (op, stats) in stats.ops() {
+            out.push_str(&format!("{}: {}\n", op, stats.count));
+        }
+        out.push('\n');
+        for (path, count) in self.hot_paths(HOT_PATHS) {
+            out.push_str(&format!("/{}: {}\n", path.display(), count));
+        }
+        out.into_bytes()
+    }
+
+    /// The content of `health`: `status: ok` if every check passed,
+    /// `status: error` otherwise, then each check with `ok` or what
+    /// went wrong.  The checks are cheap enough to be run every few
+    /// seconds: the mounted commit is read from the object database,
+    /// and the underlying dir is checked to be still there, writable
+    /// and not full, without writing to it.
+    fn render_health(&self) -> Vec<u8> {
+        let checks = [("repo", self.check_repo()), ("overlay", self.check_overlay())];
+        let healthy = checks.iter().all(|(_, result)| result.is_ok());
+        let mut out = format!("status: {}\n", if healthy { "ok" } else { "error" });
+        for (name, result) in &checks {
+            match result {
+                Ok(()) => out.push_str(&format!("{}: ok\n", name)),
+                Err(e) => out.push_str(&format!("{}: error: {}\n", name, e)),
+            }
+        }
+        out.into_bytes()
+    }
+
+    fn check_repo(&self) -> Result<(), Error> {
+        let commit = self.head().commit;
+        let repo = self.repo();
+        fs::metadata(repo.path())?;
+        if let Some(commit) = commit {
+            repo.odb()?.read_header(commit)?;
+        }
+        Ok(())
+    }
+
+    fn check_overlay(&self) -> Result<(), Error> {
+        if self.options_read().read_only {
+            return Ok(());
+        }
+        let fd = self.inner.underlying_dir.as_raw_fd();
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut st) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if st.st_nlink == 0 {
+            // Removed, and not coming back.
+            return Err(io::Error::from_raw_os_error(libc::ENOENT).into());
+        }
+        // EROFS too, unlike the permission bits.
+        if unsafe { libc::faccessat(fd, b".\0".as_ptr().cast(), libc::W_OK, 0) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstatvfs(fd, &mut stat) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if stat.f_bavail == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC).into());
+        }
+        Ok(())
+    }
+
+    /// The content of a file under `blame`: each line of the file in
+    /// the mounted commit, after the commit that last changed it, its
+    /// author and date, and the line number.
+    fn render_blame(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let commit = self.head().commit.ok_or(Error::Errno(ENOENT))?;
+        let repo = self.repo();
+        let entry = self.mounted_tree(&repo, &repo.find_commit(commit)?)?.get_path(path).map_err(|_| Error::Errno(ENOENT))?;
+        let blob = entry.to_object(&repo)?.into_blob().map_err(|_| Error::Errno(EISDIR))?;
+        let path = self.inner.subdir.join(path);
+        let blame = repo.blame_file(&path, Some(BlameOptions::new().newest_commit(commit)))?;
+
+        let mut rows = vec![];
+        for (i, line) in blob.content().split_inclusive(|&b| b == b'\n').enumerate() {
+            let annotation = match blame.get_line(i + 1) {
+                Some(hunk) => {
+                    let author = hunk.final_signature();
+                    (
+                        hunk.final_commit_id().to_string()[..8].to_owned(),
+                        String::from_utf8_lossy(author.name_bytes()).into_owned(),
+                        format_iso_date(author.when()),
+                    )
+                }
+                None => ("0".repeat(8), "Not Committed Yet".to_owned(), String::new()),
+            };
+            rows.push((annotation, line));
+        }
+        let author_width = rows.iter().map(|((_, author, _), _)| author.chars().count()).max().unwrap_or(0);
+        let number_width = rows.len().to_string().len();
+
+        let mut out = Vec::with_capacity(blob.size() * 2);
+        for (i, ((id, author, date), line)) in rows.into_iter().enumerate() {
+            let annotation = format!(
+                "{} ({:author_width$} {} {:>number_width$}) ",
+                id,
+                author,
+                date,
+                i + 1,
+                author_width = author_width,
+                number_width = number_width
+            );
+            out.extend_from_slice(annotation.as_bytes());
+            out.extend_from_slice(line);
+            if !line.ends_with(b"\n") {
+                out.push(b'\n');
+            }
+        }
+        Ok(out)
+    }
+
+    /// The content of `log`: the commits reachable from the mounted
+    /// one, newest first, up to `LOG_LIMIT` of them.
+    fn render_log(&self) -> Result<Vec<u8>, Error> {
+        let head = match self.head().commit {
+            Some(commit) => commit,
+            None => return Ok(vec![]),
+        };
+        let repo = self.repo();
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+        walk.push(head)?;
+
+        let mut out = String::new();
+        for oid in walk.take(LOG_LIMIT) {
+            let commit = repo.find_commit(oid?)?;
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("commit {}\n", commit.id()));
+            if commit.parent_count() > 1 {
+                let parents: Vec<_> = commit.parent_ids().map(|id| id.to_string()[..7].to_owned()).collect();
+                out.push_str(&format!("Merge: {}\n", parents.join(" ")));
+            }
+            let author = commit.author();
+            out.push_str(&format!(
+                "Author: {} <{}>\n",
+                String::from_utf8_lossy(author.name_bytes()),
+                String::from_utf8_lossy(author.email_bytes())
+            ));
+            out.push_str(&format!("Date:   {}\n\n", format_date(author.when())));
+            for line in String::from_utf8_lossy(commit.message_bytes()).trim_end().lines() {
+                out.push_str(&format!("    {}\n", line));
+            }
+        }
+        Ok(out.into_bytes())
+    }
+}
+
+/// The git dir of the main repository, which linked worktrees share
+/// objects with.
+pub(crate) fn common_dir(repo: &Repository) -> PathBuf {
+    let git_dir = repo.path();
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim_end()),
+        Err(_) => git_dir.to_owned(),
+    }
+}
+
+/// The number of objects in a pack, from the last entry of the fanout
+/// table of its index, which comes right after the header in version 2
+/// and first thing in version 1.
+fn index_object_count(path: &Path) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let mut header = [0u8; 8];
+    file.read_exact(&mut header)?;
+    let fanout = if header[..4] == *b"\xfftOc" { 8 } else { 0 };
+    let mut last = [0u8; 4];
+    file.seek(SeekFrom::Start(fanout + 255 * 4))?;
+    file.read_exact(&mut last)?;
+    Ok(u64::from(u32::from_be_bytes(last)))
+}
+
+/// A date as `git log` shows it, in the time zone it was recorded in.
+fn format_date(when: git2::Time) -> String {
+    format_date_as(when, "%a %b %e %H:%M:%S %Y")
+}
+
+/// A date as `git blame` and `--date=iso` show it.
+pub(super) fn format_iso_date(when: git2::Time) -> String {
+    format_date_as(when, "%Y-%m-%d %H:%M:%S")
+}
+
+/// A date in `format` (see `strftime`) and the time zone it was
+/// recorded in, followed by that time zone.
+fn format_date_as(when: git2::Time, format: &str) -> String {
+    let offset = when.offset_minutes();
+    let local = time::at_utc(time::Timespec::new(when.seconds() + i64::from(offset) * 60, 0));
+    let date = match local.strftime(format) {
+        Ok(date) => date.to_string(),
+        Err(_) => when.seconds().to_string(),
+    };
+    format!("{} {}{:02}{:02}", date, if offset < 0 { '-' } else { '+' }, offset.abs() / 60, offset.abs() % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse(b"refresh"), Ok(Command::Refresh));
+        assert_eq!(Command::parse(b"  checkout  main \r"), Ok(Command::Checkout("main".to_owned())));
+        assert_eq!(Command::parse(b"commit fix: a typo"), Ok(Command::Commit("fix: a typo".to_owned())));
+        assert_eq!(Command::parse(b"invalidate"), Ok(Command::Invalidate(None)));
+        assert_eq!(Command::parse(b"invalidate /dir/"), Ok(Command::Invalidate(Some(PathBuf::from("dir")))));
+        assert!(Command::parse(b"checkout").is_err());
+        assert_eq!(Command::parse(b"next"), Ok(Command::Step(Step::Next)));
+        assert_eq!(Command::parse(b"goto HEAD~2"), Ok(Command::Step(Step::Goto("HEAD~2".to_owned()))));
+        assert!(Command::parse(b"prev 2").is_err());
+        assert!(Command::parse(b"refresh now").is_err());
+        assert_eq!(Command::parse(b"tidy"), Ok(Command::Tidy));
+        assert!(Command::parse(b"reboot").is_err());
+        assert!(Command::parse(b"\xff").is_err());
+        assert_eq!(Command::parse(b"grep a b"), Ok(Command::Grep("a b".to_owned(), vec![])));
+        assert_eq!(
+            Command::parse(b"grep x -- src/ README"),
+            Ok(Command::Grep("x".to_owned(), vec![PathBuf::from("src"), PathBuf::from("README")]))
+        );
+        assert!(Command::parse(b"grep").is_err());
+    }
+
+    #[test]
+    fn formats_dates_as_git_log() {
+        assert_eq!(format_date(git2::Time::new(0, 0)), "Thu Jan  1 00:00:00 1970 +0000");
+        assert_eq!(format_date(git2::Time::new(1_000_000_000, 120)), "Sun Sep  9 03:46:40 2001 +0200");
+        assert_eq!(format_date(git2::Time::new(1_000_000_000, -330)), "Sat Sep  8 20:16:40 2001 -0530");
+    }
+}
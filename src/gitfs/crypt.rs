@@ -0,0 +1,484 @@
+/// Encryption of the overlay at rest (`GitFSBuilder::overlay_key`).
+///
+/// Files are sealed with AES-256-GCM in blocks of `BLOCK` bytes, so
+/// that they can still be read and written at any offset.  A file
+/// starts with `MAGIC` and a random id, then each block is stored as
+/// a random nonce, its ciphertext and its tag.  The id of the file and
+/// the index of a block are authenticated along with it, so a block
+/// moved within a file or between files fails to read (EIO).  Cutting
+/// whole blocks off the end of a file is not detected.
+///
+/// Writes read back the blocks they only partly cover, and the first
+/// one gives the file its id, so the I/O of each file is serialized
+/// (`FileLocks`): writes and truncations one at a time, and reads
+/// between them.
+///
+/// Only contents are encrypted: names, modes and sizes (give or take
+/// a block) are not.  Files written into the underlying dir by anyone
+/// but the mount are not sealed, so they cannot be read (EIO).
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+use std::ptr;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use libc::c_int;
+use openssl_sys as ssl;
+
+use super::{read_full_at, GitFS};
+
+const MAGIC: &[u8; 8] = b"gitfsae1";
+const ID: usize = 16;
+const HEADER: u64 = (MAGIC.len() + ID) as u64;
+/// Bytes of content per block.
+const BLOCK: u64 = 4096;
+const NONCE: usize = 12;
+const TAG: usize = 16;
+/// Bytes a block takes in the underlying dir.
+const SEALED: u64 = BLOCK + (NONCE + TAG) as u64;
+/// Gaps left by writing past the end are filled with this many zeros
+/// at a time.
+const ZEROS: u64 = 256 * BLOCK;
+/// How many locks the files of a mount share (see `FileLocks`).
+const LOCKS: usize = 64;
+
+/// The key the overlay of a mount is encrypted with.
+pub struct OverlayKey([u8; 32]);
+
+impl OverlayKey {
+    /// Read a key from a file holding either 32 bytes or 64 hex digits
+    /// (as made by `openssl rand -hex 32`).
+    pub fn from_file(path: &Path) -> io::Result<OverlayKey> {
+        let data = fs::read(path)?;
+        let hex = std::str::from_utf8(&data)
+            .ok()
+            .map(str::trim)
+            .filter(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()));
+        let mut key = [0; 32];
+        match hex {
+            Some(hex) => {
+                for (i, byte) in key.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).expect("checked to be hex");
+                }
+            }
+            None if data.len() == key.len() => key.copy_from_slice(&data),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "an overlay key is 32 bytes or 64 hex digits",
+                ))
+            }
+        }
+        Ok(OverlayKey(key))
+    }
+
+    /// Seal block `index` of file `id`: its nonce, ciphertext and tag.
+    fn seal(&self, id: &[u8; ID], index: u64, plain: &[u8]) -> io::Result<Vec<u8>> {
+        let mut sealed = vec![0; NONCE + plain.len() + TAG];
+        random(&mut sealed[..NONCE])?;
+        let (nonce, rest) = sealed.split_at_mut(NONCE);
+        let (output, tag) = rest.split_at_mut(plain.len());
+        self.gcm(true, nonce, &aad(id, index), plain, output, tag)?;
+        Ok(sealed)
+    }
+
+    /// Open block `index` of file `id`, as sealed by `seal`.
+    fn open(&self, id: &[u8; ID], index: u64, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < NONCE + TAG {
+            return Err(corrupt());
+        }
+        let (nonce, rest) = sealed.split_at(NONCE);
+        let (input, tag) = rest.split_at(rest.len() - TAG);
+        let mut plain = vec![0; input.len()];
+        self.gcm(false, nonce, &aad(id, index), input, &mut plain, &mut tag.to_vec())?;
+        Ok(plain)
+    }
+
+    /// Run AES-256-GCM over `input` into `output`, which is as long.
+    /// The tag is made when encrypting, and checked otherwise.
+    fn gcm(&self, encrypt: bool, nonce: &[u8], aad: &[u8], input: &[u8], output: &mut [u8], tag: &mut [u8]) -> io::Result<()> {
+        let failed = |what: &str| io::Error::other(format!("{} failed", what));
+        ssl::init();
+        unsafe {
+            let ctx = ssl::EVP_CIPHER_CTX_new();
+            if ctx.is_null() {
+                return Err(failed("EVP_CIPHER_CTX_new"));
+            }
+            let mut len: c_int = 0;
+            let result = if ssl::EVP_CipherInit_ex(
+                ctx,
+                ssl::EVP_aes_256_gcm(),
+                ptr::null_mut(),
+                self.0.as_ptr(),
+                nonce.as_ptr(),
+                encrypt as c_int,
+            ) != 1
+            {
+                Err(failed("EVP_CipherInit_ex"))
+            } else if ssl::EVP_CipherUpdate(ctx, ptr::null_mut(), &mut len, aad.as_ptr(), aad.len() as c_int) != 1
+                || ssl::EVP_CipherUpdate(ctx, output.as_mut_ptr(), &mut len, input.as_ptr(), input.len() as c_int) != 1
+            {
+                Err(failed("EVP_CipherUpdate"))
+            } else if !encrypt && ssl::EVP_CIPHER_CTX_ctrl(ctx, ssl::EVP_CTRL_GCM_SET_TAG, TAG as c_int, tag.as_mut_ptr().cast()) != 1 {
+                Err(failed("EVP_CIPHER_CTX_ctrl"))
+            } else if ssl::EVP_CipherFinal(ctx, output.as_mut_ptr().add(output.len()), &mut len) != 1 {
+                // GCM has nothing left to output; failing is the tag
+                // not matching.
+                Err(if encrypt { failed("EVP_CipherFinal") } else { corrupt() })
+            } else if encrypt && ssl::EVP_CIPHER_CTX_ctrl(ctx, ssl::EVP_CTRL_GCM_GET_TAG, TAG as c_int, tag.as_mut_ptr().cast()) != 1 {
+                Err(failed("EVP_CIPHER_CTX_ctrl"))
+            } else {
+                Ok(())
+            };
+            ssl::EVP_CIPHER_CTX_free(ctx);
+            result
+        }
+    }
+}
+
+impl From<[u8; 32]> for OverlayKey {
+    fn from(key: [u8; 32]) -> OverlayKey {
+        OverlayKey(key)
+    }
+}
+
+impl fmt::Debug for OverlayKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OverlayKey(..)")
+    }
+}
+
+/// The locks of the sealed files of a mount, each shared by the
+/// files whose inodes hash the same, which every handle of a file
+/// takes whatever fd it has open.  They're held around the I/O of one
+/// file, and no other lock is taken while holding them.
+pub(super) struct FileLocks(Vec<RwLock<()>>);
+
+impl FileLocks {
+    pub(super) fn new() -> FileLocks {
+        FileLocks((0..LOCKS).map(|_| RwLock::new(())).collect())
+    }
+
+    fn get(&self, file: &File) -> io::Result<&RwLock<()>> {
+        let metadata = file.metadata()?;
+        let hash = metadata.ino().wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ metadata.dev();
+        Ok(&self.0[(hash % LOCKS as u64) as usize])
+    }
+
+    fn read(&self, file: &File) -> io::Result<RwLockReadGuard<'_, ()>> {
+        Ok(self.get(file)?.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn write(&self, file: &File) -> io::Result<RwLockWriteGuard<'_, ()>> {
+        Ok(self.get(file)?.write().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+impl GitFS {
+    /// Read from a file in the underlying dir, decrypting it if the
+    /// overlay is encrypted.  Reads are only short at the end.
+    pub(super) fn overlay_read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match &self.inner.overlay_key {
+            Some(key) => {
+                let _locked = self.inner.file_locks.read(file)?;
+                read_at(key, file, buf, offset)
+            }
+            None => read_full_at(file, buf, offset),
+        }
+    }
+
+    /// Write to a file in the underlying dir, encrypting it if the
+    /// overlay is encrypted.  Writes may be short when not encrypted.
+    pub(super) fn overlay_write_at(&self, file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+        match &self.inner.overlay_key {
+            Some(key) => {
+                let _locked = self.inner.file_locks.write(file)?;
+                write_at(key, file, data, offset).map(|_| data.len())
+            }
+            None => super::write_some_at(file, data, offset),
+        }
+    }
+
+    /// Cut or extend a file in the underlying dir to `len` bytes of
+    /// content, which are zeros past the old end.
+    pub(super) fn overlay_set_len(&self, file: &File, len: u64) -> io::Result<()> {
+        match &self.inner.overlay_key {
+            Some(key) => {
+                let _locked = self.inner.file_locks.write(file)?;
+                set_len(key, file, len)
+            }
+            None => file.set_len(len),
+        }
+    }
+
+    /// The content of a file in the underlying dir.
+    pub(super) fn overlay_read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self.inner.underlying_dir.open_file(path)?;
+        let mut content = vec![0; self.overlay_len(file.metadata()?.len()) as usize];
+        let nbytes = self.overlay_read_at(&file, &mut content, 0)?;
+        content.truncate(nbytes);
+        Ok(content)
+    }
+
+    /// The size in the mount of a file that takes `len` bytes in the
+    /// underlying dir.
+    pub(super) fn overlay_len(&self, len: u64) -> u64 {
+        match self.inner.overlay_key {
+            Some(_) => plain_len(len),
+            None => len,
+        }
+    }
+}
+
+fn read_at(key: &OverlayKey, file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let id = match file_id(file, false)? {
+        Some(id) => id,
+        None => return Ok(0),
+    };
+    let len = plain_len(file.metadata()?.len());
+    if offset >= len || buf.is_empty() {
+        return Ok(0);
+    }
+    let end = len.min(offset + buf.len() as u64);
+    let first = offset / BLOCK;
+    let last = (end - 1) / BLOCK;
+    let mut sealed = vec![0; ((last - first + 1) * SEALED) as usize];
+    let nbytes = read_full_at(file, &mut sealed, HEADER + first * SEALED)?;
+    sealed.truncate(nbytes);
+
+    let want = (end - offset) as usize;
+    let mut nread = 0;
+    for (index, block) in (first..).zip(sealed.chunks(SEALED as usize)) {
+        let plain = key.open(&id, index, block)?;
+        let skip = if index == first { (offset - first * BLOCK) as usize } else { 0 };
+        let part = &plain[skip.min(plain.len())..];
+        let n = part.len().min(want - nread);
+        buf[nread..nread + n].copy_from_slice(&part[..n]);
+        nread += n;
+        if nread == want {
+            break;
+        }
+    }
+    Ok(nread)
+}
+
+fn write_at(key: &OverlayKey, file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let id = file_id(file, true)?.expect("files are given an id when empty");
+    let mut len = plain_len(file.metadata()?.len());
+    // What lies between the end and `offset` reads as zeros, as it
+    // would in a sparse file.
+    while len < offset {
+        let zeros = vec![0; (offset - len).min(ZEROS) as usize];
+        write_blocks(key, file, &id, &zeros, len, len)?;
+        len += zeros.len() as u64;
+    }
+    write_blocks(key, file, &id, data, offset, len)
+}
+
+fn set_len(key: &OverlayKey, file: &File, len: u64) -> io::Result<()> {
+    let old = plain_len(file.metadata()?.len());
+    if len > old {
+        return write_at(key, file, &[0], len - 1);
+    }
+    if len == old {
+        return Ok(());
+    }
+    if len == 0 {
+        return file.set_len(0);
+    }
+    // The last block left is sealed again with only what is kept of it.
+    let id = file_id(file, false)?.ok_or_else(corrupt)?;
+    let index = len / BLOCK;
+    let keep = (len - index * BLOCK) as usize;
+    let mut end = HEADER + index * SEALED;
+    if keep > 0 {
+        let mut block = vec![0; SEALED as usize];
+        let nbytes = read_full_at(file, &mut block, end)?;
+        block.truncate(nbytes);
+        let mut plain = key.open(&id, index, &block)?;
+        plain.truncate(keep);
+        let sealed = key.seal(&id, index, &plain)?;
+        file.write_all_at(&sealed, end)?;
+        end += sealed.len() as u64;
+    }
+    file.set_len(end)
+}
+
+/// Write `data` at `offset` of a file with `len` bytes of content, at
+/// least up to `offset`.  Blocks only partly written are read first.
+fn write_blocks(key: &OverlayKey, file: &File, id: &[u8; ID], data: &[u8], offset: u64, len: u64) -> io::Result<()> {
+    let end = offset + data.len() as u64;
+    let first = offset / BLOCK;
+    let mut sealed = Vec::with_capacity(((end - first * BLOCK) / BLOCK * SEALED + SEALED) as usize);
+    for index in first..end.div_ceil(BLOCK) {
+        let start = index * BLOCK;
+        let old_len = len.saturating_sub(start).min(BLOCK);
+        let from = offset.max(start);
+        let to = end.min(start + BLOCK);
+        let mut plain = if from > start || to < start + old_len {
+            let mut block = vec![0; SEALED as usize];
+            let nbytes = read_full_at(file, &mut block, HEADER + index * SEALED)?;
+            block.truncate(nbytes);
+            key.open(id, index, &block)?
+        } else {
+            vec![]
+        };
+        plain.resize(plain.len().max((to - start) as usize), 0);
+        plain[(from - start) as usize..(to - start) as usize]
+            .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+        sealed.extend_from_slice(&key.seal(id, index, &plain)?);
+    }
+    file.write_all_at(&sealed, HEADER + first * SEALED)
+}
+
+/// The id of a sealed file, or None if it's empty, in which case it's
+/// given one if `create`.
+fn file_id(file: &File, create: bool) -> io::Result<Option<[u8; ID]>> {
+    let mut header = [0; HEADER as usize];
+    let mut id = [0; ID];
+    match read_full_at(file, &mut header, 0)? {
+        0 if !create => return Ok(None),
+        0 => {
+            random(&mut id)?;
+            header[..MAGIC.len()].copy_from_slice(MAGIC);
+            header[MAGIC.len()..].copy_from_slice(&id);
+            file.write_all_at(&header, 0)?;
+        }
+        n if n == header.len() && header.starts_with(MAGIC) => id.copy_from_slice(&header[MAGIC.len()..]),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file in the underlying dir is not encrypted",
+            ))
+        }
+    }
+    Ok(Some(id))
+}
+
+/// The size of the content of a sealed file that takes `len` bytes.
+fn plain_len(len: u64) -> u64 {
+    let body = len.saturating_sub(HEADER);
+    body / SEALED * BLOCK + (body % SEALED).saturating_sub(SEALED - BLOCK)
+}
+
+/// What a block is authenticated with besides its content.
+fn aad(id: &[u8; ID], index: u64) -> [u8; ID + 8] {
+    let mut aad = [0; ID + 8];
+    aad[..ID].copy_from_slice(id);
+    aad[ID..].copy_from_slice(&index.to_le_bytes());
+    aad
+}
+
+fn random(buf: &mut [u8]) -> io::Result<()> {
+    ssl::init();
+    match unsafe { ssl::RAND_bytes(buf.as_mut_ptr(), buf.len() as c_int) } {
+        1 => Ok(()),
+        _ => Err(io::Error::other("RAND_bytes failed")),
+    }
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "a block of the file in the underlying dir fails to decrypt (wrong key, or corrupted)",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn sealed_files_read_back() {
+        let dir = std::env::temp_dir().join(format!("gitfs-crypt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let key = OverlayKey::from([7; 32]);
+
+        let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        write_at(&key, &file, &data[..5000], 0).unwrap();
+        write_at(&key, &file, &data[5000..], 5000).unwrap();
+        // Past the end, leaving a gap, and across blocks.
+        write_at(&key, &file, b"tail", 12000).unwrap();
+        write_at(&key, &file, b"across", 4093).unwrap();
+        let mut expected = data.clone();
+        expected.resize(12000, 0);
+        expected.extend_from_slice(b"tail");
+        expected[4093..4099].copy_from_slice(b"across");
+
+        let len = file.metadata().unwrap().len();
+        assert_eq!(plain_len(len), expected.len() as u64);
+        let mut buf = vec![0; 20000];
+        assert_eq!(read_at(&key, &file, &mut buf, 0).unwrap(), expected.len());
+        assert_eq!(&buf[..expected.len()], &expected[..]);
+        let mut buf = [0; 10];
+        assert_eq!(read_at(&key, &file, &mut buf, 4090).unwrap(), 10);
+        assert_eq!(&buf, &expected[4090..4100]);
+        assert_eq!(read_at(&key, &file, &mut buf, 12002).unwrap(), 2);
+        assert_eq!(read_at(&key, &file, &mut buf, 13000).unwrap(), 0);
+
+        let mut raw = vec![];
+        File::open(&path).unwrap().read_to_end(&mut raw).unwrap();
+        assert!(!raw.windows(6).any(|w| w == b"across"));
+        let wrong = OverlayKey::from([8; 32]);
+        assert_eq!(read_at(&wrong, &file, &mut buf, 0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Cut within a block and at its end, then extended with zeros.
+        for len in [5000, 4096] {
+            set_len(&key, &file, len).unwrap();
+            let mut buf = vec![0; 20000];
+            assert_eq!(read_at(&key, &file, &mut buf, 0).unwrap(), len as usize);
+            assert_eq!(&buf[..len as usize], &expected[..len as usize]);
+        }
+        set_len(&key, &file, 4100).unwrap();
+        let mut buf = vec![0; 10];
+        assert_eq!(read_at(&key, &file, &mut buf, 4090).unwrap(), 10);
+        assert_eq!(&buf[..6], &expected[4090..4096]);
+        assert_eq!(&buf[6..], &[0; 4]);
+        set_len(&key, &file, 0).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writes_to_a_block_are_kept() {
+        let dir = std::env::temp_dir().join(format!("gitfs-crypt-concurrent-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("overlay")).unwrap();
+        let repo = git2::Repository::init(dir.join("repo")).unwrap();
+        let overlay = openat::Dir::open(&dir.join("overlay")).unwrap();
+        let gitfs = GitFS::builder(repo, overlay).overlay_key(OverlayKey::from([7; 32])).build();
+        let path = dir.join("overlay/file");
+        File::create(&path).unwrap();
+
+        // Each with a file of its own, as handles have, racing to give
+        // it its id and to rewrite its first block.
+        let threads: Vec<_> = (0..8u8)
+            .map(|i| {
+                let (gitfs, path) = (gitfs.clone(), path.clone());
+                std::thread::spawn(move || {
+                    let file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+                    for round in 0..50u64 {
+                        gitfs.overlay_write_at(&file, &[i + 1; 8], (round * 8 + u64::from(i)) * 8).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let content = gitfs.overlay_read(Path::new("file")).unwrap();
+        assert_eq!(content.len(), 50 * 64);
+        for (n, chunk) in content.chunks(8).enumerate() {
+            assert_eq!(chunk, &[n as u8 % 8 + 1; 8], "at {}", n * 8);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
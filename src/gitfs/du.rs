@@ -0,0 +1,122 @@
+/// How much space each dir of a tree takes, as `du` over a mount of it
+/// would tell (`gitfs-du`), without mounting it nor reading a blob:
+/// only the headers of the blobs are, for their size.
+///
+/// Sizes are those of the blobs as stored, as with a mount that has no
+/// smudge filters, LFS or annex to apply.  Dirs take nothing of their
+/// own, and files take their size rounded up to 512 bytes, as in
+/// `getattr`.  Submodules are left out, as gitfs does, and blobs that
+/// are missing count as empty, as gitfs shows them.
+use std::collections::BTreeMap;
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+
+use git2::{ErrorCode, ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+
+/// What is in a dir and under it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// The sizes of the files and symlinks, added up (`du -b`).
+    pub bytes: u64,
+    /// The space they take, in units of 512 bytes, as in stat().
+    pub blocks: u64,
+    /// How many files and symlinks there are.
+    pub files: u64,
+    /// How many of their blobs are missing from the repository.
+    pub missing: u64,
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.bytes += other.bytes;
+        self.blocks += other.blocks;
+        self.files += other.files;
+        self.missing += other.missing;
+    }
+}
+
+/// The usage of every dir of `tree`, the root being the empty path,
+/// each with what is under it.
+pub fn tree_usage(repo: &Repository, tree: Oid) -> Result<BTreeMap<PathBuf, Usage>, git2::Error> {
+    let odb = repo.odb()?;
+    let tree = repo.find_tree(tree)?;
+    let mut dirs = BTreeMap::new();
+    dirs.insert(PathBuf::new(), Usage::default());
+    let mut failed = None;
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        // `dir` is the parent, with a trailing slash unless the root.
+        let dir = Path::new(dir);
+        let usage = match entry.kind() {
+            Some(ObjectType::Tree) => {
+                dirs.insert(dir.join(entry.name().unwrap_or_default()), Usage::default());
+                return TreeWalkResult::Ok;
+            }
+            Some(ObjectType::Blob) => match odb.read_header(entry.id()) {
+                Ok((size, _)) => Usage { bytes: size as u64, blocks: (size as u64).div_ceil(512), files: 1, missing: 0 },
+                Err(e) if e.code() == ErrorCode::NotFound => Usage { files: 1, missing: 1, ..Usage::default() },
+                Err(e) => {
+                    failed = Some(e);
+                    return TreeWalkResult::Abort;
+                }
+            },
+            _ => return TreeWalkResult::Skip,
+        };
+        for ancestor in dir.ancestors() {
+            if let Some(total) = dirs.get_mut(ancestor) {
+                *total += usage;
+            }
+        }
+        TreeWalkResult::Ok
+    })?;
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(dirs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{generate_at, Spec};
+    use crate::Ino;
+    use fuser::FileType;
+    use openat::Dir;
+
+    #[test]
+    fn usage_is_what_the_mount_shows() {
+        let base = std::env::temp_dir().join(format!("gitfs-du-{}", std::process::id()));
+        let spec = Spec { file_sizes: 0..3000, symlinks: 2, submodules: 1, unicode: true, ..Spec::default() };
+        let repo = generate_at(&base.join("repo"), &spec).unwrap();
+        let tree = repo.head().unwrap().peel_to_tree().unwrap().id();
+        let usage = tree_usage(&repo, tree).unwrap();
+
+        std::fs::create_dir_all(base.join("overlay")).unwrap();
+        let fs = super::super::GitFS::new(repo, Dir::open(&base.join("overlay")).unwrap());
+        fs.mount_root().unwrap();
+        // Add up the attributes under each dir, as `du` would.
+        let mut seen = BTreeMap::new();
+        let mut dirs = vec![(Ino::ROOT, PathBuf::new())];
+        while let Some((dir, dir_path)) = dirs.pop() {
+            fs.do_opendir(dir).unwrap();
+            for (name, ino, kind) in fs.do_readdir(dir).unwrap() {
+                if fs.is_control_dir(dir, &name) {
+                    continue;
+                }
+                let path = dir_path.join(&name);
+                if kind == FileType::Directory {
+                    seen.entry(path.clone()).or_insert_with(Usage::default);
+                    dirs.push((ino, path));
+                    continue;
+                }
+                let attr = fs.do_lookup(dir, &name).unwrap();
+                let file = Usage { bytes: attr.size, blocks: attr.blocks, files: 1, missing: 0 };
+                for ancestor in path.parent().unwrap().ancestors() {
+                    *seen.entry(ancestor.to_owned()).or_insert_with(Usage::default) += file;
+                }
+            }
+        }
+        assert_eq!(usage, seen);
+        assert_eq!(usage[Path::new("")].files, 13 * (4 + 2) + 1);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
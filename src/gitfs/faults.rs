@@ -0,0 +1,96 @@
+/// Failures injected on purpose (feature `faults`), so that the error
+/// paths of the handlers can be tested without breaking a disk or a
+/// repository.
+///
+/// gitfs has no storage traits to put a failing implementation
+/// behind: the underlying dir and the repository are used directly.
+/// Instead, the places that reach them ask the injector given to
+/// `GitFSBuilder::fault_injector` first: resolving a path in the
+/// underlying dir, reading an object, and reading or writing through
+/// a handle.  Without the feature, nothing is ever injected.
+use std::path::Path;
+
+use git2::{ErrorClass, ErrorCode, Oid};
+use libc::c_int;
+
+use super::{Error, GitError, GitFS};
+use crate::Ino;
+
+/// Where a fault may be injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "faults"), allow(dead_code))]
+pub enum Site<'a> {
+    /// A path in the underlying dir is about to be used, to open,
+    /// make, change, or remove what's there.
+    Overlay(&'a Path),
+    /// An object (blob or tree) is about to be read from the
+    /// repository, or the blob cache.
+    Object(Oid),
+    /// A file is read through a handle.
+    Read(Ino),
+    /// A file is written through a handle.
+    Write(Ino),
+}
+
+/// What goes wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "faults"), allow(dead_code))]
+pub enum Fault {
+    /// Fail with this errno, e.g. EIO or ENOSPC.  Not at `Object`.
+    Errno(c_int),
+    /// The object isn't in the repository.  Only at `Object`.
+    MissingObject,
+    /// Read or write only this many bytes.  Only at `Read` and `Write`.
+    Short(usize),
+}
+
+/// Decides what goes wrong where, if anything.  It's called with locks
+/// held, so it must not call back into the file system.
+#[cfg(feature = "faults")]
+pub type FaultInjector = Box<dyn Fn(&Site) -> Option<Fault> + Send + Sync>;
+
+impl GitFS {
+    #[cfg(feature = "faults")]
+    pub(super) fn inject(&self, site: Site) -> Option<Fault> {
+        let fault = self.inner.fault_injector.as_ref().and_then(|injector| injector(&site));
+        if let Some(fault) = fault {
+            debug!(?site, ?fault, "injecting a fault");
+        }
+        fault
+    }
+
+    #[cfg(not(feature = "faults"))]
+    #[inline]
+    pub(super) fn inject(&self, _site: Site) -> Option<Fault> {
+        None
+    }
+
+    /// Fail at `site` with the errno injected there, if any.
+    pub(super) fn inject_errno(&self, site: Site) -> Result<(), Error> {
+        match self.inject(site) {
+            Some(Fault::Errno(errno)) => Err(Error::Errno(errno)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail to find `oid` if it's made missing.
+    pub(super) fn inject_missing(&self, oid: Oid) -> Result<(), GitError> {
+        match self.inject(Site::Object(oid)) {
+            Some(Fault::MissingObject) => Err(GitError::new(
+                ErrorCode::NotFound,
+                ErrorClass::Odb,
+                format!("object not found - no match for id ({}), injected", oid),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// How many bytes out of `len` are to be read or written at `site`.
+    pub(super) fn inject_short(&self, site: Site, len: usize) -> Result<usize, Error> {
+        match self.inject(site) {
+            Some(Fault::Errno(errno)) => Err(Error::Errno(errno)),
+            Some(Fault::Short(n)) => Ok(n.min(len)),
+            _ => Ok(len),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+/// The last commit that changed each clean entry, as xattrs, when
+/// `Options::last_commit_xattrs` is set, so that file managers and
+/// scripts can tell when and by whom a file was last changed without
+/// running `git log -1 -- <path>`:
+///
+/// ```text
+/// user.gitfs.last-commit   the id of the commit
+/// user.gitfs.last-author   its author, as `name <email>`
+/// user.gitfs.last-date     its committer date, as user.gitfs.date
+/// ```
+///
+/// The commit is found by walking the first parents of the mounted
+/// commit back to the first one whose parent has something else at the
+/// path, as `git log --first-parent` does, which takes as long as the
+/// path hasn't changed.  It's cached per path until another commit is
+/// mounted.  Dirty entries, and clean ones moved through the mount,
+/// have none; the root has those of the mounted commit instead.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::PoisonError;
+
+use git2::{Oid, Repository};
+
+use super::{control, signature, GitFS};
+use crate::error::Error;
+use crate::{EntryKind, Ino};
+
+/// The last commits found, for the commit mounted when they were.
+#[derive(Default)]
+pub(super) struct LastCommits {
+    commit: Option<Oid>,
+    /// By path, and what is there, as it may have been moved there
+    /// through the mount.
+    paths: HashMap<(PathBuf, Oid), Option<Oid>>,
+}
+
+impl GitFS {
+    pub(super) fn last_commit_xattrs(&self, ino: Ino) -> Result<Vec<(&'static str, Vec<u8>)>, Error> {
+        if ino.is_root() || !self.options_read().last_commit_xattrs {
+            return Ok(vec![]);
+        }
+        let commit = match self.head().commit {
+            Some(commit) => commit,
+            None => return Ok(vec![]),
+        };
+        let (path, oid) = {
+            let inomap = self.inomap();
+            match (inomap.prefix(ino), inomap.get(ino).map(|entry| &entry.u)) {
+                (Some(path), Some(EntryKind::GitBlob { oid, .. } | EntryKind::GitTree { oid, .. })) => (path, *oid),
+                _ => return Ok(vec![]),
+            }
+        };
+        let last = match self.last_commit(commit, &path, oid)? {
+            Some(last) => last,
+            None => return Ok(vec![]),
+        };
+        let repo = self.repo();
+        let last = repo.find_commit(last)?;
+        let xattrs = vec![
+            ("user.gitfs.last-commit", last.id().to_string().into_bytes()),
+            ("user.gitfs.last-author", signature(last.author())),
+            ("user.gitfs.last-date", control::format_iso_date(last.committer().when()).into_bytes()),
+        ];
+        Ok(xattrs)
+    }
+
+    /// The last commit in the history of `commit` that changed `path`,
+    /// if it holds `oid` there.
+    fn last_commit(&self, commit: Oid, path: &Path, oid: Oid) -> Result<Option<Oid>, Error> {
+        {
+            let cached = self.inner.last_commits.lock().unwrap_or_else(PoisonError::into_inner);
+            if cached.commit == Some(commit) {
+                if let Some(&last) = cached.paths.get(&(path.to_owned(), oid)) {
+                    return Ok(last);
+                }
+            }
+        }
+        // Found without the lock, so that looking at other paths
+        // doesn't wait for this one.
+        let last = last_commit(&self.repo(), commit, &self.inner.subdir.join(path), oid)?;
+        let mut cached = self.inner.last_commits.lock().unwrap_or_else(PoisonError::into_inner);
+        if cached.commit != Some(commit) {
+            *cached = LastCommits { commit: Some(commit), paths: HashMap::new() };
+        }
+        cached.paths.insert((path.to_owned(), oid), last);
+        Ok(last)
+    }
+}
+
+/// The last commit on the first parents of `commit` that changed
+/// `path`, if `commit` has `oid` there.
+fn last_commit(repo: &Repository, commit: Oid, path: &Path, oid: Oid) -> Result<Option<Oid>, Error> {
+    let at = |commit: &git2::Commit| commit.tree().ok().and_then(|tree| tree.get_path(path).ok()).map(|entry| entry.id());
+    let mut commit = repo.find_commit(commit)?;
+    if at(&commit) != Some(oid) {
+        return Ok(None);
+    }
+    loop {
+        let parent = match commit.parent(0) {
+            Ok(parent) => parent,
+            // Added by the root commit.
+            Err(_) => return Ok(Some(commit.id())),
+        };
+        if at(&parent) != Some(oid) {
+            return Ok(Some(commit.id()));
+        }
+        commit = parent;
+    }
+}
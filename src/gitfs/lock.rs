@@ -0,0 +1,36 @@
+/// Keeping two gitfs from sharing an underlying dir, where each would
+/// take the other's dirty files for its own and undo its changes.
+///
+/// The lock is an advisory `flock` on the underlying dir itself, so
+/// there's no lockfile to show up in the mount or be left behind: it's
+/// held for as long as the mount, and dropped by the kernel however
+/// the process ends.
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::PoisonError;
+
+use libc::{LOCK_EX, LOCK_NB, O_DIRECTORY, O_RDONLY};
+
+use super::{open_at, GitFS};
+
+impl GitFS {
+    /// Lock the underlying dir for this mount, until it's dropped.
+    /// `WouldBlock` if another mount has it locked.
+    pub fn lock_overlay(&self) -> io::Result<()> {
+        let mut lock = self.inner.overlay_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        if lock.is_some() {
+            return Ok(());
+        }
+        let dir = open_at(&self.inner.underlying_dir, Path::new("."), O_RDONLY | O_DIRECTORY, 0)?;
+        if unsafe { libc::flock(dir.as_raw_fd(), LOCK_EX | LOCK_NB) } < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock => Err(io::Error::new(e.kind(), "the underlying dir is in use by another gitfs")),
+                _ => Err(e),
+            };
+        }
+        *lock = Some(dir);
+        Ok(())
+    }
+}
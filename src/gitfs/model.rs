@@ -0,0 +1,374 @@
+/// A reference model of the mount, for differential testing: what it
+/// should show after a sequence of operations on a tree, kept as a map
+/// of paths, with none of the overlay, ino map, or caching behind the
+/// real one.
+///
+/// A test applies each `Op` to both, `Model::apply` and `Op::run`,
+/// expects the same result, and has `Model::check` compare the whole
+/// view now and then.  Any sequence should pass, which is what makes
+/// it fit for generated ones: renames over files and dirs, unlinks of
+/// clean and dirty files, and what is hidden by them.
+///
+/// Ops are run as the kernel would, taking on the checks it makes
+/// itself rather than the file system (e.g. `unlink` of a dir).  Paths
+/// are relative to the root, and symlinks aren't followed: writing to
+/// one fails with ELOOP, as with `O_NOFOLLOW`, and paths shouldn't go
+/// through one.
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use fuser::FileType;
+use git2::{ObjectType, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use libc::{c_int, EINVAL, EISDIR, ELOOP, ENOENT, ENOTDIR, ENOTEMPTY, O_RDONLY, O_WRONLY};
+
+use super::{Error, GitError, GitFS};
+use crate::Ino;
+
+/// What is expected at a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    File(Vec<u8>),
+    Dir,
+    /// The target.
+    Symlink(Vec<u8>),
+}
+
+/// An operation on the mount.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Make a file, or empty one, and write this to it.
+    Write(PathBuf, Vec<u8>),
+    Mkdir(PathBuf),
+    Unlink(PathBuf),
+    Rmdir(PathBuf),
+    Rename(PathBuf, PathBuf),
+}
+
+/// The expected view: every path but the root, with what is there.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Model {
+    nodes: BTreeMap<PathBuf, Node>,
+}
+
+impl Model {
+    /// What a mount of `tree` shows before anything is done:
+    /// submodules are left out, as gitfs does with those it can't find.
+    pub fn from_tree(repo: &Repository, tree: &Tree) -> Result<Model, GitError> {
+        let mut nodes = BTreeMap::new();
+        let mut failed = None;
+        tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            let path = Path::new(OsStr::from_bytes(dir.as_bytes())).join(OsStr::from_bytes(entry.name_bytes()));
+            let node = match entry.kind() {
+                Some(ObjectType::Tree) => Node::Dir,
+                Some(ObjectType::Blob) => match repo.find_blob(entry.id()) {
+                    Ok(blob) if entry.filemode() == 0o120000 => Node::Symlink(blob.content().to_vec()),
+                    Ok(blob) => Node::File(blob.content().to_vec()),
+                    Err(e) => {
+                        failed = Some(e);
+                        return TreeWalkResult::Abort;
+                    }
+                },
+                _ => return TreeWalkResult::Skip,
+            };
+            nodes.insert(path, node);
+            TreeWalkResult::Ok
+        })?;
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(Model { nodes }),
+        }
+    }
+
+    /// Every path, with what is there, in order.
+    pub fn nodes(&self) -> impl Iterator<Item = (&Path, &Node)> {
+        self.nodes.iter().map(|(path, node)| (path.as_path(), node))
+    }
+
+    /// Apply `op` as a POSIX file system would, and return the errno
+    /// it should fail with, if any.
+    pub fn apply(&mut self, op: &Op) -> Result<(), c_int> {
+        match op {
+            Op::Write(path, data) => {
+                self.check_parent(path)?;
+                match self.nodes.get(path) {
+                    Some(Node::Dir) => return Err(EISDIR),
+                    Some(Node::Symlink(_)) => return Err(ELOOP),
+                    _ => (),
+                }
+                self.nodes.insert(path.clone(), Node::File(data.clone()));
+            }
+            Op::Mkdir(path) => {
+                self.check_parent(path)?;
+                if self.nodes.contains_key(path) {
+                    return Err(libc::EEXIST);
+                }
+                self.nodes.insert(path.clone(), Node::Dir);
+            }
+            Op::Unlink(path) => {
+                self.check_parent(path)?;
+                match self.nodes.get(path) {
+                    None => return Err(ENOENT),
+                    Some(Node::Dir) => return Err(EISDIR),
+                    Some(_) => self.nodes.remove(path),
+                };
+            }
+            Op::Rmdir(path) => {
+                self.check_parent(path)?;
+                match self.nodes.get(path) {
+                    None => return Err(ENOENT),
+                    Some(Node::Dir) if self.has_children(path) => return Err(ENOTEMPTY),
+                    Some(Node::Dir) => self.nodes.remove(path),
+                    Some(_) => return Err(ENOTDIR),
+                };
+            }
+            Op::Rename(from, to) => {
+                self.check_parent(from)?;
+                self.check_parent(to)?;
+                let is_dir = *self.nodes.get(from).ok_or(ENOENT)? == Node::Dir;
+                if from == to {
+                    return Ok(());
+                }
+                if to.starts_with(from) {
+                    return Err(EINVAL);
+                }
+                match (is_dir, self.nodes.get(to)) {
+                    (true, Some(Node::Dir)) if self.has_children(to) => return Err(ENOTEMPTY),
+                    (true, Some(Node::Dir)) | (false, Some(Node::File(_))) | (false, Some(Node::Symlink(_))) | (_, None) => (),
+                    (true, Some(_)) => return Err(ENOTDIR),
+                    (false, Some(Node::Dir)) => return Err(EISDIR),
+                }
+                self.nodes.remove(to);
+                let moved: Vec<PathBuf> = self.nodes.keys().filter(|path| path.starts_with(from)).cloned().collect();
+                for path in moved {
+                    let node = self.nodes.remove(&path).unwrap();
+                    let rest = path.strip_prefix(from).unwrap();
+                    let path = match rest.as_os_str().is_empty() {
+                        true => to.clone(),
+                        false => to.join(rest),
+                    };
+                    self.nodes.insert(path, node);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail as resolving the dir of `path` would.
+    fn check_parent(&self, path: &Path) -> Result<(), c_int> {
+        if path.file_name().is_none() {
+            return Err(EINVAL);
+        }
+        let mut dirs: Vec<&Path> = path.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty()).collect();
+        dirs.reverse();
+        for dir in dirs {
+            match self.nodes.get(dir) {
+                Some(Node::Dir) => (),
+                Some(_) => return Err(ENOTDIR),
+                None => return Err(ENOENT),
+            }
+        }
+        Ok(())
+    }
+
+    fn has_children(&self, dir: &Path) -> bool {
+        self.nodes.keys().any(|path| path.parent() == Some(dir))
+    }
+
+    /// Compare `fs` with the model, and return how they differ.  The
+    /// control dir is left out.
+    pub fn check(&self, fs: &GitFS) -> Vec<String> {
+        let mut problems = vec![];
+        let mut seen = BTreeMap::new();
+        let mut dirs = vec![(Ino::ROOT, PathBuf::new())];
+        while let Some((dir, dir_path)) = dirs.pop() {
+            let children = fs.do_opendir(dir).and_then(|()| fs.do_readdir(dir));
+            let children = match children {
+                Ok(children) => children,
+                Err(e) => {
+                    problems.push(format!("cannot list /{}: {}", dir_path.display(), e));
+                    continue;
+                }
+            };
+            for (name, ino, kind) in children {
+                if fs.is_control_dir(dir, &name) {
+                    continue;
+                }
+                let path = dir_path.join(&name);
+                let node = match kind {
+                    FileType::Directory => {
+                        dirs.push((ino, path.clone()));
+                        Ok(Node::Dir)
+                    }
+                    FileType::Symlink => fs.do_readlink(ino).map(Node::Symlink),
+                    _ => read_all(fs, ino).map(Node::File),
+                };
+                match node {
+                    Ok(node) => drop(seen.insert(path, node)),
+                    Err(e) => problems.push(format!("cannot read /{}: {}", path.display(), e)),
+                }
+            }
+        }
+        for (path, expected) in &self.nodes {
+            match seen.remove(path) {
+                None => problems.push(format!("/{} is missing, expected {}", path.display(), describe(expected))),
+                Some(node) if node != *expected => {
+                    problems.push(format!("/{} is {}, expected {}", path.display(), describe(&node), describe(expected)))
+                }
+                Some(_) => (),
+            }
+        }
+        for (path, node) in seen {
+            problems.push(format!("/{} is {}, expected nothing", path.display(), describe(&node)));
+        }
+        problems
+    }
+}
+
+fn describe(node: &Node) -> String {
+    match node {
+        Node::File(data) => format!("a file of {:?}", String::from_utf8_lossy(data)),
+        Node::Dir => "a dir".to_owned(),
+        Node::Symlink(target) => format!("a symlink to {:?}", String::from_utf8_lossy(target)),
+    }
+}
+
+fn read_all(fs: &GitFS, ino: Ino) -> Result<Vec<u8>, Error> {
+    let fh = fs.do_open(ino, O_RDONLY)?;
+    let mut data = vec![];
+    let read = loop {
+        match fs.do_read(ino, fh, data.len() as u64, 1 << 16) {
+            Ok(chunk) if chunk.is_empty() => break Ok(data),
+            Ok(chunk) => data.extend(chunk),
+            Err(e) => break Err(e),
+        }
+    };
+    fs.release_handle(ino, fh);
+    read
+}
+
+impl Op {
+    /// Run the op on `fs`, and return the errno it failed with.
+    pub fn run(&self, fs: &GitFS) -> Result<(), c_int> {
+        self.try_run(fs).map_err(|e| fs.errno(&e))
+    }
+
+    fn try_run(&self, fs: &GitFS) -> Result<(), Error> {
+        let kind = |parent, name: &OsStr| fs.do_lookup(parent, name).map(|attr| attr.kind);
+        match self {
+            Op::Write(path, data) => {
+                let (parent, name) = fs.resolve_parent(path)?;
+                let (ino, fh) = match fs.do_lookup(parent, &name) {
+                    Ok(attr) if attr.kind == FileType::Symlink => return Err(Error::Errno(ELOOP)),
+                    Ok(attr) => {
+                        let ino = Ino::from(attr.ino);
+                        let fh = fs.do_open(ino, O_WRONLY)?;
+                        (ino, fh)
+                    }
+                    Err(Error::Errno(ENOENT)) => {
+                        let (attr, fh) = fs.do_create(parent, &name, 0o644)?;
+                        (Ino::from(attr.ino), fh)
+                    }
+                    Err(e) => return Err(e),
+                };
+                let written = fs.truncate_handle(ino, fh).and_then(|()| {
+                    let mut offset = 0;
+                    while offset < data.len() {
+                        offset += fs.do_write(ino, fh, offset as u64, &data[offset..])? as usize;
+                    }
+                    fs.do_flush(ino, fh)
+                });
+                fs.release_handle(ino, fh);
+                written
+            }
+            Op::Mkdir(path) => {
+                let (parent, name) = fs.resolve_parent(path)?;
+                fs.do_mkdir(parent, &name, 0o755).map(drop)
+            }
+            Op::Unlink(path) | Op::Rmdir(path) => {
+                let (parent, name) = fs.resolve_parent(path)?;
+                match (self, kind(parent, &name)?) {
+                    (Op::Unlink(_), FileType::Directory) => Err(Error::Errno(EISDIR)),
+                    (Op::Rmdir(_), kind) if kind != FileType::Directory => Err(Error::Errno(ENOTDIR)),
+                    _ => fs.do_remove(parent, &name),
+                }
+            }
+            Op::Rename(from, to) => {
+                let (oldp, name) = fs.resolve_parent(from)?;
+                let (newp, newname) = fs.resolve_parent(to)?;
+                let is_dir = kind(oldp, &name)? == FileType::Directory;
+                if (oldp, &name) == (newp, &newname) {
+                    return Ok(());
+                }
+                if to.starts_with(from) {
+                    return Err(Error::Errno(EINVAL));
+                }
+                match (is_dir, kind(newp, &newname)) {
+                    (true, Ok(kind)) if kind != FileType::Directory => Err(Error::Errno(ENOTDIR)),
+                    (false, Ok(FileType::Directory)) => Err(Error::Errno(EISDIR)),
+                    (_, Ok(_)) | (_, Err(Error::Errno(ENOENT))) => fs.do_rename(oldp, &name, newp, &newname),
+                    (_, Err(e)) => Err(e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{generate_at, Rng, Spec};
+    use openat::Dir;
+
+    /// An op on what `model` has, or what it may have next, with some
+    /// bound to fail.
+    fn random_op(rng: &mut Rng, model: &Model, n: usize) -> Op {
+        let paths: Vec<&Path> = model.nodes().map(|(path, _)| path).collect();
+        let existing = |rng: &mut Rng| match paths.is_empty() {
+            true => PathBuf::from("none"),
+            false => paths[rng.below(paths.len())].to_owned(),
+        };
+        // Under a dir, or under a file now and then.
+        let parents: Vec<&Path> = model.nodes().filter(|(_, node)| !matches!(node, Node::Symlink(_))).map(|(path, _)| path).collect();
+        let new = |rng: &mut Rng| match rng.below(parents.len() + 1) {
+            i if i < parents.len() => parents[i].join(format!("new{}", n)),
+            _ => PathBuf::from(format!("new{}", n)),
+        };
+        match rng.below(10) {
+            0..=1 => Op::Write(new(rng), vec![b'a' + (n % 26) as u8; n % 5]),
+            2 => Op::Write(existing(rng), format!("changed {}", n).into_bytes()),
+            3 => Op::Mkdir(new(rng)),
+            4 => Op::Unlink(existing(rng)),
+            5 => Op::Rmdir(existing(rng)),
+            6..=7 => Op::Rename(existing(rng), new(rng)),
+            _ => Op::Rename(existing(rng), existing(rng)),
+        }
+    }
+
+    #[test]
+    fn random_ops_are_as_modelled() {
+        let base = std::env::temp_dir().join(format!("gitfs-model-{}", std::process::id()));
+        for seed in 0..16 {
+            let root = base.join(seed.to_string());
+            let spec = Spec { seed, depth: 2, fanout: 2, files: 2, file_sizes: 0..16, symlinks: 1, unicode: true, ..Spec::default() };
+            let repo = generate_at(&root.join("repo"), &spec).unwrap();
+            let mut model = Model::from_tree(&repo, &repo.head().unwrap().peel_to_tree().unwrap()).unwrap();
+            std::fs::create_dir_all(root.join("overlay")).unwrap();
+            let fs = GitFS::new(repo, Dir::open(&root.join("overlay")).unwrap());
+            fs.mount_root().unwrap();
+            assert_eq!(model.check(&fs), Vec::<String>::new());
+
+            let mut rng = Rng(seed);
+            for n in 0..40 {
+                let op = random_op(&mut rng, &model, n);
+                let expected = model.apply(&op);
+                assert_eq!(op.run(&fs), expected, "seed {} op {}: {:?}", seed, n, op);
+                let problems = model.check(&fs);
+                assert!(problems.is_empty(), "seed {} op {}: {:?}: {:#?}", seed, n, op, problems);
+            }
+            assert_eq!(fs.self_check(), Vec::<String>::new(), "seed {}", seed);
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
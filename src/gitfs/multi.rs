@@ -0,0 +1,615 @@
+/// Several repositories mounted as the top-level dirs of one mount,
+/// with one FUSE session for all of them, e.g. for a checkout made of
+/// many repositories, or a host of read-only mirrors:
+///
+/// ```text
+/// git-mount --repo repoB repoA /mnt      # /mnt/repoA and /mnt/repoB
+/// git-mount --compare v1 v2 repo /mnt     # /mnt/a and /mnt/b
+/// ```
+///
+/// Each dir is a `GitFS` of its own, with its own head, overlay and
+/// control dir, and operations in it are done by that `GitFS`; only
+/// the inos are told apart, by the repository they belong to.  Blobs
+/// are best cached once for all of them (see
+/// `GitFSBuilder::share_blob_cache`).  The root itself can't be
+/// changed, and nothing is renamed or linked across repositories.
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
+};
+use libc::{c_int, EINVAL, EISDIR, ENOENT, EPERM, EXDEV};
+
+use super::{reply_xattr, GitFS, MountHandle, SetAttr, ENOATTR};
+use crate::error::Error;
+use crate::Ino;
+
+/// The bit of the inos of the control dirs (see `control::owns`),
+/// which is kept where it is.
+const CONTROL: u64 = 1 << 63;
+/// An ino of a repository goes in the bits below this, and the index
+/// of the repository (from 1) in those above, up to `CONTROL`.
+const SHIFT: u32 = 48;
+const INO_MASK: u64 = (1 << SHIFT) - 1;
+const MAX_REPOS: usize = (1 << (63 - SHIFT)) - 1;
+
+/// As `ok!` for `GitFS`, with the errno the repository maps to.
+macro_rules! ok {
+    ($fs:expr, $value:expr, $reply:ident) => {
+        match $value {
+            Ok(value) => value,
+            Err(e) => {
+                let errno = $fs.errno(&Error::from(e));
+                tracing::Span::current().record("errno", errno);
+                return $reply.error(errno);
+            }
+        }
+    };
+}
+
+/// As `op_span!` for `GitFS`, naming the repository too.
+macro_rules! op_span {
+    ($fs:expr, $name:expr, $op:literal, $ino:expr) => {
+        (
+            $fs.start_op($op, $ino),
+            debug_span!(
+                "repo",
+                op = $op,
+                repo = ?$name,
+                ino = u64::from($ino),
+                path = %$fs.path_of($ino).display(),
+                errno = tracing::field::Empty
+            )
+            .entered(),
+        )
+    };
+}
+
+/// Pass an operation on `$ino` to the repository it's in, with the ino
+/// it has there, or fail with `$root` if it's on the root.
+macro_rules! forward {
+    ($self:ident, $ino:ident, $reply:ident, $root:expr, $fs:ident => $call:expr) => {
+        match $self.route($ino) {
+            Ok(Some((_, $fs, $ino))) => $call,
+            Ok(None) => $reply.error($root),
+            Err(errno) => $reply.error(errno),
+        }
+    };
+}
+
+pub struct MultiFS {
+    repos: Vec<(OsString, GitFS)>,
+    /// The times of the root.
+    created: SystemTime,
+}
+
+/// The ino in the mount of `ino` in the repository at `index`.
+fn outer(index: usize, ino: Ino) -> u64 {
+    let ino = u64::from(ino);
+    (ino & CONTROL) | ((index as u64 + 1) << SHIFT) | (ino & INO_MASK)
+}
+
+/// The index of the repository of an ino in the mount, and the ino it
+/// has there, or `None` for the root.
+fn split(ino: u64) -> Option<(usize, Ino)> {
+    match (ino & !CONTROL) >> SHIFT {
+        0 => None,
+        index => Some((index as usize - 1, Ino::from((ino & CONTROL) | (ino & INO_MASK)))),
+    }
+}
+
+fn outer_attr(index: usize, mut attr: FileAttr) -> FileAttr {
+    attr.ino = outer(index, Ino::from(attr.ino));
+    attr
+}
+
+impl MultiFS {
+    /// Mount each `GitFS` as the dir of its name.  Names must be
+    /// distinct, and each a single path component.
+    pub fn new(repos: Vec<(OsString, GitFS)>) -> io::Result<MultiFS> {
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        if repos.is_empty() {
+            return invalid("no repository to mount".to_owned());
+        }
+        if repos.len() > MAX_REPOS {
+            return invalid(format!("cannot mount more than {} repositories", MAX_REPOS));
+        }
+        let mut names = HashSet::new();
+        for (name, _) in &repos {
+            let bytes = name.as_bytes();
+            if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') || bytes.contains(&0) {
+                return invalid(format!("{:?} is not a valid name for a repository", name));
+            }
+            if !names.insert(name) {
+                return invalid(format!("more than one repository is named {:?}", name));
+            }
+        }
+        Ok(MultiFS {
+            repos,
+            created: SystemTime::now(),
+        })
+    }
+
+    /// Mount the file system in a background thread, as
+    /// `GitFS::spawn_mount` does.
+    pub fn spawn_mount<P: AsRef<Path>>(self, mountpoint: P, options: &[MountOption]) -> io::Result<MountHandle> {
+        let session = fuser::spawn_mount2(self, mountpoint, options)?;
+        Ok(MountHandle {
+            session: Some(session),
+        })
+    }
+
+    /// The repository of `ino` and its ino there, `None` for the root,
+    /// or ENOENT if there's no such repository.
+    fn route(&mut self, ino: u64) -> Result<Option<(usize, &mut GitFS, u64)>, c_int> {
+        match split(ino) {
+            None if ino == u64::from(Ino::ROOT) => Ok(None),
+            None => Err(ENOENT),
+            Some((index, ino)) => match self.repos.get_mut(index) {
+                Some((_, fs)) => Ok(Some((index, fs, ino.into()))),
+                None => Err(ENOENT),
+            },
+        }
+    }
+
+    fn index_of(&self, name: &OsStr) -> Option<usize> {
+        self.repos.iter().position(|(n, _)| n == name)
+    }
+
+    fn ttl(&self) -> Duration {
+        self.repos[0].1.attr_ttl()
+    }
+
+    fn root_attr(&self) -> FileAttr {
+        FileAttr {
+            ino: Ino::ROOT.into(),
+            size: 0,
+            blocks: 0,
+            atime: self.created,
+            mtime: self.created,
+            ctime: self.created,
+            crtime: self.created,
+            kind: FileType::Directory,
+            perm: 0o555,
+            nlink: 2 + self.repos.len() as u32,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for MultiFS {
+    fn init(&mut self, req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        for (_, fs) in &mut self.repos {
+            fs.init(req, config)?;
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        for (_, fs) in &mut self.repos {
+            fs.destroy();
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let (index, parent, name) = match self.route(parent) {
+            Ok(Some((index, _, parent))) => (index, Some(Ino::from(parent)), name),
+            Ok(None) => match self.index_of(name) {
+                Some(index) => (index, None, name),
+                None => return reply.error(ENOENT),
+            },
+            Err(errno) => return reply.error(errno),
+        };
+        let (repo, fs) = &self.repos[index];
+        let _span = op_span!(fs, repo, "lookup", parent.unwrap_or(Ino::ROOT));
+        fs.refresh_if_requested();
+        let attr = match parent {
+            Some(parent) => ok!(fs, fs.do_lookup(parent, name), reply),
+            None => ok!(fs, fs.do_getattr(Ino::ROOT), reply),
+        };
+        reply.entry(&fs.entry_ttl(), &outer_attr(index, attr), 0)
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let (index, ino) = match self.route(ino) {
+            Ok(Some((index, _, ino))) => (index, Ino::from(ino)),
+            Ok(None) => return reply.attr(&self.ttl(), &self.root_attr()),
+            Err(errno) => return reply.error(errno),
+        };
+        let (repo, fs) = &self.repos[index];
+        let _span = op_span!(fs, repo, "getattr", ino);
+        fs.refresh_if_requested();
+        let attr = ok!(fs, fs.do_getattr(ino), reply);
+        reply.attr(&fs.attr_ttl(), &outer_attr(index, attr))
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let (index, ino) = match self.route(ino) {
+            Ok(Some((index, _, ino))) => (index, Ino::from(ino)),
+            Ok(None) => return reply.error(EPERM),
+            Err(errno) => return reply.error(errno),
+        };
+        let (repo, fs) = &self.repos[index];
+        let _span = op_span!(fs, repo, "setattr", ino);
+        let attrs = SetAttr {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            crtime,
+            flags,
+        };
+        let attr = ok!(fs, fs.do_setattr(ino, attrs), reply);
+        if let Some(size) = size {
+            fs.audit(req, "truncate", || format!("{:?} size={}", fs.path_of(ino), size));
+        }
+        reply.attr(&fs.attr_ttl(), &outer_attr(index, attr))
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.route(ino) {
+            Ok(Some((_, fs, ino))) => fs.opendir(req, ino, flags, reply),
+            Ok(None) => reply.opened(0, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let (index, ino) = match self.route(ino) {
+            Ok(Some((index, _, ino))) => (index, Ino::from(ino)),
+            Ok(None) => {
+                for (i, (name, _)) in self.repos.iter().enumerate().skip(offset as usize) {
+                    if reply.add(outer(i, Ino::ROOT), (i + 1) as i64, FileType::Directory, name) {
+                        break;
+                    }
+                }
+                return reply.ok();
+            }
+            Err(errno) => return reply.error(errno),
+        };
+        let (repo, fs) = &self.repos[index];
+        let _span = op_span!(fs, repo, "readdir", ino);
+        let children = ok!(fs, fs.do_readdir(ino), reply);
+        for (i, (name, child, kind)) in children.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(outer(index, child), (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+
+    fn readdirplus(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectoryPlus) {
+        let (index, ino) = match self.route(ino) {
+            Ok(Some((index, _, ino))) => (index, Ino::from(ino)),
+            Ok(None) => {
+                let ttl = self.ttl();
+                for (i, (name, fs)) in self.repos.iter().enumerate().skip(offset as usize) {
+                    let attr = match fs.do_getattr(Ino::ROOT) {
+                        Ok(attr) => outer_attr(i, attr),
+                        Err(_) => continue,
+                    };
+                    if reply.add(attr.ino, (i + 1) as i64, name, &ttl, &attr, 0) {
+                        break;
+                    }
+                }
+                return reply.ok();
+            }
+            Err(errno) => return reply.error(errno),
+        };
+        let (repo, fs) = &self.repos[index];
+        let _span = op_span!(fs, repo, "readdirplus", ino);
+        let children = ok!(fs, fs.do_readdirplus(ino), reply);
+        let ttl = fs.entry_ttl();
+        for (i, (name, attr)) in children.into_iter().enumerate().skip(offset as usize) {
+            let attr = match attr {
+                Some(attr) => outer_attr(index, attr),
+                None => continue,
+            };
+            if reply.add(attr.ino, (i + 1) as i64, name, &ttl, &attr, 0) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+
+    fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        match self.route(ino) {
+            Ok(Some((_, fs, ino))) => fs.releasedir(req, ino, fh, flags, reply),
+            Ok(None) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        forward!(self, ino, reply, EISDIR, fs => fs.open(req, ino, flags, reply))
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        forward!(self, ino, reply, EISDIR, fs => fs.read(req, ino, fh, offset, size, flags, lock_owner, reply))
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        forward!(self, ino, reply, EISDIR, fs => fs.write(req, ino, fh, offset, data, write_flags, flags, lock_owner, reply))
+    }
+
+    fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        forward!(self, ino, reply, EISDIR, fs => fs.flush(req, ino, fh, lock_owner, reply))
+    }
+
+    fn release(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        forward!(self, ino, reply, EISDIR, fs => fs.release(req, ino, fh, flags, lock_owner, flush, reply))
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let (index, parent) = match self.route(parent) {
+            Ok(Some((index, _, parent))) => (index, parent),
+            Ok(None) => return reply.error(EPERM),
+            Err(errno) => return reply.error(errno),
+        };
+        let (repo, fs) = &self.repos[index];
+        let _span = op_span!(fs, repo, "create", Ino::from(parent));
+        let (attr, fh) = ok!(fs, fs.do_create(parent.into(), name, mode), reply);
+        fs.audit(req, "create", || format!("{:?}", fs.child_path(parent, name)));
+        reply.created(&fs.entry_ttl(), &outer_attr(index, attr), 0, fh, 0)
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
+        let (index, parent) = match self.route(parent) {
+            Ok(Some((index, _, parent))) => (index, parent),
+            Ok(None) => return reply.error(EPERM),
+            Err(errno) => return reply.error(errno),
+        };
+        let (repo, fs) = &self.repos[index];
+        let _span = op_span!(fs, repo, "mkdir", Ino::from(parent));
+        let attr = ok!(fs, fs.do_mkdir(parent.into(), name, mode), reply);
+        fs.audit(req, "mkdir", || format!("{:?}", fs.child_path(parent, name)));
+        reply.entry(&fs.entry_ttl(), &outer_attr(index, attr), 0)
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        forward!(self, parent, reply, EPERM, fs => fs.unlink(req, parent, name, reply))
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        forward!(self, parent, reply, EPERM, fs => fs.rmdir(req, parent, name, reply))
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let newparent = match self.route(newparent) {
+            Ok(Some((index, _, newparent))) => (index, newparent),
+            Ok(None) => return reply.error(EPERM),
+            Err(errno) => return reply.error(errno),
+        };
+        match self.route(parent) {
+            Ok(Some((index, fs, parent))) if index == newparent.0 => {
+                fs.rename(req, parent, name, newparent.1, newname, flags, reply)
+            }
+            Ok(Some(_)) => reply.error(EXDEV),
+            Ok(None) => reply.error(EPERM),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        match self.route(ino) {
+            Ok(Some((_, fs, ino))) => fs.statfs(req, ino, reply),
+            Ok(None) => self.repos[0].1.statfs(req, Ino::ROOT.into(), reply),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        forward!(self, ino, reply, EINVAL, fs => fs.readlink(req, ino, reply))
+    }
+
+    fn mknod(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, rdev: u32, reply: ReplyEntry) {
+        forward!(self, parent, reply, EPERM, fs => fs.mknod(req, parent, name, mode, umask, rdev, reply))
+    }
+
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        forward!(self, parent, reply, EPERM, fs => fs.symlink(req, parent, name, link, reply))
+    }
+
+    fn link(&mut self, req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        let newparent = match self.route(newparent) {
+            Ok(Some((index, _, newparent))) => (index, newparent),
+            Ok(None) => return reply.error(EPERM),
+            Err(errno) => return reply.error(errno),
+        };
+        match self.route(ino) {
+            Ok(Some((index, fs, ino))) if index == newparent.0 => fs.link(req, ino, newparent.1, newname, reply),
+            Ok(Some(_)) => reply.error(EXDEV),
+            Ok(None) => reply.error(EPERM),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        forward!(self, ino, reply, EPERM, fs => fs.setxattr(req, ino, name, value, flags, position, reply))
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: fuser::ReplyXattr) {
+        forward!(self, ino, reply, ENOATTR, fs => fs.getxattr(req, ino, name, size, reply))
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        match self.route(ino) {
+            Ok(Some((_, fs, ino))) => fs.listxattr(req, ino, size, reply),
+            Ok(None) => reply_xattr(&[], size, reply),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        forward!(self, ino, reply, EPERM, fs => fs.removexattr(req, ino, name, reply))
+    }
+
+    fn fallocate(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        forward!(self, ino, reply, EISDIR, fs => fs.fallocate(req, ino, fh, offset, length, mode, reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Oid, Repository};
+    use openat::Dir;
+    use std::path::PathBuf;
+
+    /// A repo at `root/name` with `a.txt` holding `content`, and a dir
+    /// for its overlay.
+    fn repo(root: &Path, name: &str, content: &[u8]) -> (Repository, Dir, Oid) {
+        let repo = Repository::init(root.join(name)).unwrap();
+        let blob = repo.blob(content).unwrap();
+        {
+            let mut builder = repo.treebuilder(None).unwrap();
+            builder.insert("a.txt", blob, 0o100644).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let sig = git2::Signature::now("test", "test@example.com").unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap();
+        }
+        let overlay = root.join(format!("{}-overlay", name));
+        std::fs::create_dir_all(&overlay).unwrap();
+        (repo, Dir::open(&overlay).unwrap(), blob)
+    }
+
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("gitfs-multi-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn inos_of_repos_are_told_apart() {
+        for &(index, ino) in &[(0, 1), (0, 42), (1, 1), (MAX_REPOS - 1, INO_MASK), (2, CONTROL + 3)] {
+            let outer = outer(index, Ino::from(ino));
+            assert_ne!(outer, u64::from(Ino::ROOT));
+            assert_eq!(split(outer), Some((index, Ino::from(ino))));
+        }
+        assert_ne!(outer(0, Ino::from(7)), outer(1, Ino::from(7)));
+        assert_eq!(split(u64::from(Ino::ROOT)), None);
+    }
+
+    #[test]
+    fn names_are_distinct_components() {
+        let root = root("names");
+        let fs = |name: &str| {
+            let (repo, dir, _) = repo(&root, name, b"a");
+            GitFS::new(repo, dir)
+        };
+        for (i, names) in [&["a", "a"][..], &["a/b"], &[".."], &[""], &[]].iter().enumerate() {
+            let repos = names.iter().enumerate().map(|(j, &name)| (OsString::from(name), fs(&format!("r{}{}", i, j)))).collect();
+            assert!(MultiFS::new(repos).is_err(), "{:?}", names);
+        }
+        let mut multi = MultiFS::new(vec![(OsString::from("a"), fs("a")), (OsString::from("b"), fs("b"))]).unwrap();
+        assert_eq!(multi.index_of(OsStr::new("b")), Some(1));
+        assert_eq!(multi.index_of(OsStr::new("c")), None);
+        assert!(multi.route(u64::from(Ino::ROOT)).unwrap().is_none());
+        assert_eq!(multi.route(outer(2, Ino::ROOT)).err(), Some(ENOENT));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn blobs_are_cached_once() {
+        let root = root("cache");
+        let (repo_a, dir_a, oid) = repo(&root, "a", b"same");
+        let (repo_b, dir_b, _) = repo(&root, "b", b"same");
+        let a = GitFS::new(repo_a, dir_a);
+        let b = GitFS::builder(repo_b, dir_b).share_blob_cache(&a).build();
+        for fs in &[&a, &b] {
+            fs.mount_root().unwrap();
+            let attr = fs.do_lookup(Ino::ROOT, OsStr::new("a.txt")).unwrap();
+            let fh = fs.do_open(Ino::from(attr.ino), libc::O_RDONLY).unwrap();
+            assert_eq!(fs.do_read(Ino::from(attr.ino), fh, 0, 16).unwrap(), b"same");
+        }
+        assert!(a.blob_cache().get(oid).is_some());
+        assert_eq!(super::super::stats::get(&b.inner.stats.blob_cache_hits), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
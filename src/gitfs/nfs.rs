@@ -0,0 +1,879 @@
+/// A read-only NFSv3 export of the tree (`--serve nfs`), so that
+/// machines without gitfs, e.g. the nodes of a build farm, can mount a
+/// commit over the network from one instance:
+///
+/// ```text
+/// mount -t nfs -o vers=3,proto=tcp,port=2049,mountport=2049,nolock host:/ /mnt
+/// ```
+///
+/// The MOUNT and NFS programs are both served on the one port, so no
+/// portmapper is needed; there is no lock manager either (`nolock`).
+/// Calls are answered by the same operations as the FUSE handlers,
+/// so the export shows dirty files and follows the rules of the mount,
+/// but every change is refused with `NFS3ERR_ROFS`.  A file handle is
+/// the ino, tagged with when the server started, so that handles given
+/// out by an earlier server are stale rather than pointing elsewhere.
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType};
+use libc::{
+    c_int, EACCES, EDQUOT, EEXIST, EFBIG, EINVAL, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EPERM,
+    EROFS, O_RDONLY,
+};
+
+use super::{control, server, statvfs, GitFS};
+use crate::error::Error;
+use crate::watch::Watcher;
+use crate::Ino;
+
+/// The largest read, and the largest RPC record accepted.
+const MAX_IO: u32 = 1 << 20;
+/// What READDIR replies need besides their entries.
+const READDIR_HEADER: usize = 128;
+
+const RPC_VERSION: u32 = 2;
+const CALL: u32 = 0;
+const REPLY: u32 = 1;
+const MSG_ACCEPTED: u32 = 0;
+const MSG_DENIED: u32 = 1;
+const RPC_MISMATCH: u32 = 0;
+const SUCCESS: u32 = 0;
+const PROG_UNAVAIL: u32 = 1;
+const PROG_MISMATCH: u32 = 2;
+const PROC_UNAVAIL: u32 = 3;
+const GARBAGE_ARGS: u32 = 4;
+const AUTH_NONE: u32 = 0;
+const AUTH_UNIX: u32 = 1;
+
+const MOUNT_PROGRAM: u32 = 100005;
+const NFS_PROGRAM: u32 = 100003;
+const VERSION: u32 = 3;
+
+const NFS3_OK: u32 = 0;
+const NFS3ERR_IO: u32 = 5;
+const NFS3ERR_ROFS: u32 = 30;
+const NFS3ERR_STALE: u32 = 70;
+const NFS3ERR_BADHANDLE: u32 = 10001;
+const NFS3ERR_TOOSMALL: u32 = 10005;
+
+const ACCESS_READ: u32 = 0x1;
+const ACCESS_LOOKUP: u32 = 0x2;
+const ACCESS_EXECUTE: u32 = 0x20;
+const FSF3_SYMLINK: u32 = 0x2;
+const FSF3_HOMOGENEOUS: u32 = 0x8;
+
+/// Serve `fs` over NFSv3 at `addr`, until the returned watcher is
+/// dropped.
+pub(super) fn serve(fs: GitFS, addr: SocketAddr) -> Result<(Watcher, SocketAddr), Error> {
+    fs.mount_root()?;
+    let instance = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    let server = Server { fs, instance };
+    Ok(server::listen("nfs", addr, move |stream| server.run(stream))?)
+}
+
+#[derive(Clone)]
+struct Server {
+    fs: GitFS,
+    /// Tags the file handles given out by this server.
+    instance: u64,
+}
+
+/// Arguments that cannot be decoded.
+struct Garbage;
+
+/// An NFS (or MOUNT) status other than OK.
+type Status = u32;
+
+impl Server {
+    /// Answer calls until the client hangs up.
+    fn run(&self, stream: &TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut writer = stream;
+        while let Some(call) = read_record(&mut reader)? {
+            if let Some(reply) = self.call(&call) {
+                // A reply always fits in one fragment.
+                let mut record = (0x8000_0000 | reply.len() as u32).to_be_bytes().to_vec();
+                record.extend_from_slice(&reply);
+                writer.write_all(&record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer an RPC call.  Anything but a call is ignored.
+    fn call(&self, call: &[u8]) -> Option<Vec<u8>> {
+        let mut m = Xdr(call);
+        let xid = m.u32().ok()?;
+        if m.u32().ok()? != CALL {
+            return None;
+        }
+        let mut r = Encoder::default();
+        r.u32(xid);
+        r.u32(REPLY);
+        let header = (|| {
+            let rpcvers = m.u32()?;
+            let (prog, vers, proc) = (m.u32()?, m.u32()?, m.u32()?);
+            // Credentials are not checked: the export is read-only,
+            // and permissions are left to the client.
+            for _ in 0..2 {
+                m.u32()?;
+                m.opaque()?;
+            }
+            Ok((rpcvers, prog, vers, proc))
+        })();
+        let (rpcvers, prog, vers, proc) = match header {
+            Ok(header) => header,
+            Err(Garbage) => {
+                r.accepted(GARBAGE_ARGS);
+                return Some(r.0);
+            }
+        };
+        if rpcvers != RPC_VERSION {
+            r.u32(MSG_DENIED);
+            r.u32(RPC_MISMATCH);
+            r.u32(RPC_VERSION);
+            r.u32(RPC_VERSION);
+            return Some(r.0);
+        }
+        let mut body = Encoder::default();
+        let result = match (prog, vers) {
+            (NFS_PROGRAM, VERSION) => self.nfs(proc, &mut m, &mut body),
+            (MOUNT_PROGRAM, VERSION) => self.mount(proc, &mut m, &mut body),
+            (NFS_PROGRAM, _) | (MOUNT_PROGRAM, _) => {
+                r.accepted(PROG_MISMATCH);
+                r.u32(VERSION);
+                r.u32(VERSION);
+                return Some(r.0);
+            }
+            _ => Err(PROG_UNAVAIL),
+        };
+        match result {
+            Ok(()) => {
+                r.accepted(SUCCESS);
+                r.0.extend_from_slice(&body.0);
+            }
+            Err(stat) => r.accepted(stat),
+        }
+        Some(r.0)
+    }
+
+    /// Answer a call to the NFS program, or fail with the accept_stat
+    /// to reply.
+    fn nfs(&self, proc: u32, m: &mut Xdr<'_>, r: &mut Encoder) -> Result<(), u32> {
+        // Nearly every call starts with the file handle it's about.
+        let ino = Xdr(m.0).opaque().ok().and_then(|fh| self.decode_fh(fh).ok()).unwrap_or(Ino::ROOT);
+        let op = nfs_op_name(proc).ok_or(PROC_UNAVAIL)?;
+        let _timer = self.fs.start_op(op, ino);
+        let span = debug_span!(
+            "nfs",
+            op,
+            ino = u64::from(ino),
+            path = %self.fs.path_of(ino).display(),
+            status = tracing::field::Empty
+        )
+        .entered();
+        if proc != 0 {
+            self.fs.refresh_if_requested();
+        }
+        let status = match proc {
+            0 => return Ok(()),
+            1 => self.getattr(m, r),
+            3 => self.lookup(m, r),
+            4 => self.access(m, r),
+            5 => self.readlink(m, r),
+            6 => self.read(m, r),
+            16 => self.readdir(m, r, false),
+            17 => self.readdir(m, r, true),
+            18 => self.fsstat(m, r),
+            19 => self.fsinfo(m, r),
+            20 => self.pathconf(m, r),
+            // Every change is refused.  The data of the refusal
+            // depends on the call: a file attr for LINK, and weak
+            // cache consistency data for each dir or file changed.
+            _ => {
+                let status = r.status(NFS3ERR_ROFS);
+                if proc == 15 {
+                    r.bool(false);
+                }
+                for _ in 0..if proc == 14 { 2 } else { 1 } {
+                    r.bool(false);
+                    r.bool(false);
+                }
+                Ok(status)
+            }
+        };
+        if let Ok(Some(status)) = status {
+            span.record("status", status);
+        }
+        status.map(drop).map_err(|Garbage| GARBAGE_ARGS)
+    }
+
+    /// Answer a call to the MOUNT program.
+    fn mount(&self, proc: u32, m: &mut Xdr<'_>, r: &mut Encoder) -> Result<(), u32> {
+        let op = match proc {
+            0 => "null",
+            1 => "mnt",
+            2 => "dump",
+            3 => "umnt",
+            4 => "umntall",
+            5 => "export",
+            _ => return Err(PROC_UNAVAIL),
+        };
+        let _timer = self.fs.start_op(op, Ino::ROOT);
+        let _span = debug_span!("mount", op).entered();
+        match proc {
+            1 => {
+                let dirpath = m.opaque().map_err(|Garbage| GARBAGE_ARGS)?;
+                match self.mount_dir(Path::new(OsStr::from_bytes(dirpath))) {
+                    Ok(ino) => {
+                        r.u32(NFS3_OK);
+                        r.opaque(&self.encode_fh(ino));
+                        r.u32(1);
+                        r.u32(AUTH_UNIX);
+                    }
+                    Err(status) => r.u32(status),
+                }
+            }
+            // Nobody is remembered as having mounted.
+            2 => r.bool(false),
+            // The one export, with no groups named.
+            5 => {
+                r.bool(true);
+                r.opaque(b"/");
+                r.bool(false);
+                r.bool(false);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Resolve the dir a client asks to mount.
+    fn mount_dir(&self, dirpath: &Path) -> Result<Ino, Status> {
+        let mut ino = Ino::ROOT;
+        for name in dirpath.iter().filter(|name| *name != "/") {
+            ino = self.fs.do_lookup(ino, name).map_err(|e| self.status(&e))?.ino.into();
+        }
+        match self.attr(ino)?.kind {
+            FileType::Directory => Ok(ino),
+            _ => Err(self.status(&Error::Errno(ENOTDIR))),
+        }
+    }
+
+    fn getattr(&self, m: &mut Xdr<'_>, r: &mut Encoder) -> Result<Option<Status>, Garbage> {
+        let fh = m.opaque()?;
+        let attr = match self.decode_fh(fh).and_then(|ino| self.attr(ino)) {
+            Ok(attr) => attr,
+            Err(status) => return Ok(r.status(status)),
+        };
+        r.u32(NFS3_OK);
+        r.fattr(&attr, self.instance);
+        Ok(None)
+    }
+
+    fn lookup(&self, m: &mut Xdr<'_>, r: &mut Encoder) -> Result<Option<Status>, Garbage> {
+        let fh = m.opaque()?;
+        let name = OsStr::from_bytes(m.opaque()?);
+        let dir = match self.decode_fh(fh) {
+            Ok(dir) => dir,
+            Err(status) => {
+                let status = r.status(status);
+                r.bool(false);
+                return Ok(status);
+            }
+        };
+        let child = match name.as_bytes() {
+            b"." => Ok(dir),
+            b".." => Ok(self.parent(dir)),
+            _ => self.fs.do_lookup(dir, name).map(|attr| attr.ino.into()).map_err(|e| self.status(&e)),
+        };
+        match child.and_then(|child| Ok((child, self.attr(child)?))) {
+            Ok((child, attr)) => {
+                r.u32(NFS3_OK);
+                r.opaque(&self.encode_fh(child));
+                r.post_op(Some(&attr), self.instance);
+                r.post_op(self.attr(dir).ok().as_ref(), self.instance);
+                Ok(None)
+            }
+            Err(status) => {
+                let status = r.status(status);
+                r.post_op(self.attr(dir).ok().as_ref(), self.instance);
+                Ok(status)
+            }
+        }
+    }
+
+    fn access(&self, m: &mut Xdr<'_>, r: &mut Encoder) -> Result<Option<Status>, Garbage> {
+        let fh = m.opaque()?;
+        let access = m.u32()?;
+        let attr = match self.decode_fh(fh).and_then(|ino| self.attr(ino)) {
+            Ok(attr) => attr,
+            Err(status) => {
+                let status = r.status(status);
+                r.bool(false);
+                return Ok(status);
+            }
+        };
+        r.u32(NFS3_OK);
+        r.post_op(Some(&attr), self.instance);
+        // Whatever reads may be done; the client checks the mode.
+        r.u32(access & (ACCESS_READ | ACCESS_LOOKUP | ACCESS_EXECUTE));
+        Ok(None)
+    }
+
+    fn readlink(&self, m: &mut Xdr<'_>, r: &mut Encoder) -> Result<Option<Status>, Garbage> {
+        let fh = m.opaque()?;
+        let result = self.decode_fh(fh).and_then(|ino| {
+            let target = self.fs.do_readlink(ino).map_err(|e| self.status(&e))?;
+            Ok((self.attr(ino)?, target))
+        });
+        match result {
+            Ok((attr, target)) => {
+                r.u32(NFS3_OK);
+                r.post_op(Some(&attr), self.instance);
+                r.opaque(&target);
+                Ok(None)
+            }
+            Err(status) => {
+                let status = r.status(status);
+                r.bool(false);
+                Ok(status)
+            }
+        }
+    }
+
+    fn read(&self, m: &mut Xdr<'_>, r: &mut Encoder) -> Result<Option<Status>, Garbage> {
+        let fh = m.opaque()?;
+        let (offset, count) = (m.u64()?, m.u32()?.min(MAX_IO));
+        let result = self.decode_fh(fh).and_then(|ino| {
+            let attr = self.attr(ino)?;
+            if attr.kind == FileType::Directory {
+                return Err(self.status(&Error::Errno(EISDIR)));
+            }
+            Ok((attr, self.read_at(ino, offset, count).map_err(|e| self.status(&e))?))
+        });
+        match result {
+            Ok((attr, data)) => {
+                r.u32(NFS3_OK);
+                r.post_op(Some(&attr), self.instance);
+                r.u32(data.len() as u32);
+                r.bool(data.len() < count as usize || offset + data.len() as u64 >= attr.size);
+                r.opaque(&data);
+                Ok(None)
+            }
+            Err(status) => {
+                let status = r.status(status);
+                r.bool(false);
+                Ok(status)
+            }
+        }
+    }
+
+    /// Read from a file, which is opened just for that: NFS has no
+    /// open files.
+    fn read_at(&self, ino: Ino, offset: u64, count: u32) -> Result<Vec<u8>, Error> {
+        let fh = self.fs.do_open(ino, O_RDONLY)?;
+        let data = self.fs.do_read(ino, fh, offset, count);
+        self.fs.release_handle(ino, fh);
+        data
+    }
+
+    /// READDIR, or READDIRPLUS with the attrs and handle of each entry.
+    fn readdir(&self, m: &mut Xdr<'_>, r: &mut Encoder, plus: bool) -> Result<Option<Status>, Garbage> {
+        let fh = m.opaque()?;
+        let cookie = m.u64()?;
+        let _verifier = m.take(8)?;
+        let count = m.u32()? as usize;
+        // READDIRPLUS bounds the names by dircount, and the whole
+        // reply by maxcount.
+        let (dircount, maxcount) = if plus { (count, m.u32()? as usize) } else { (count, count) };
+        let result = self.decode_fh(fh).and_then(|dir| Ok((self.attr(dir)?, self.listing(dir)?)));
+        let (attr, listing) = match result {
+            Ok(listing) => listing,
+            Err(status) => {
+                let status = r.status(status);
+                r.bool(false);
+                return Ok(status);
+            }
+        };
+        let mut entries = Encoder::default();
+        let mut names = 0;
+        let mut eof = true;
+        for (i, (name, child, _)) in listing.iter().enumerate().skip(cookie as usize) {
+            let mut entry = Encoder::default();
+            entry.bool(true);
+            entry.u64((*child).into());
+            entry.opaque(name.as_bytes());
+            entry.u64(i as u64 + 1);
+            names += entry.0.len();
+            if plus {
+                entry.post_op(self.attr(*child).ok().as_ref(), self.instance);
+                entry.bool(true);
+                entry.opaque(&self.encode_fh(*child));
+            }
+            if names > dircount || entries.0.len() + entry.0.len() + READDIR_HEADER > maxcount {
+                eof = false;
+                break;
+            }
+            entries.0.extend_from_slice(&entry.0);
+        }
+        if entries.0.is_empty() && !eof {
+            let status = r.status(NFS3ERR_TOOSMALL);
+            r.post_op(Some(&attr), self.instance);
+            return Ok(status);
+        }
+        r.u32(NFS3_OK);
+        r.post_op(Some(&attr), self.instance);
+        // Entries are told apart by cookie alone.
+        r.0.extend_from_slice(&[0; 8]);
+        r.0.extend_from_slice(&entries.0);
+        r.bool(false);
+        r.bool(eof);
+        Ok(None)
+    }
+
+    /// The entries of a dir, with `.` and `..`, in an order that stays
+    /// the same while the dir does, so that cookies stay valid.
+    fn listing(&self, dir: Ino) -> Result<Vec<(OsString, Ino, FileType)>, Status> {
+        self.fs.do_opendir(dir).map_err(|e| self.status(&e))?;
+        let mut children = self.fs.do_readdir(dir).map_err(|e| self.status(&e))?;
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut listing = vec![
+            (OsString::from("."), dir, FileType::Directory),
+            (OsString::from(".."), self.parent(dir), FileType::Directory),
+        ];
+        listing.extend(children);
+        Ok(listing)
+    }
+
+    // The counts aren't u64 on every platform.
+    #[allow(clippy::unnecessary_cast)]
+    fn fsstat(&self, m: &mut Xdr<'_>, r: &mut Encoder) -> Result<Option<Status>, Garbage> {
+        let fh = m.opaque()?;
+        let result = self.decode_fh(fh).and_then(|ino| {
+            let st = statvfs(&self.fs.inner.underlying_dir).map_err(|e| self.status(&e.into()))?;
+            Ok((self.attr(ino)?, st))
+        });
+        let (attr, st) = match result {
+            Ok(result) => result,
+            Err(status) => {
+                let status = r.status(status);
+                r.bool(false);
+                return Ok(status);
+            }
+        };
+        r.u32(NFS3_OK);
+        r.post_op(Some(&attr), self.instance);
+        let frsize = st.f_frsize as u64;
+        r.u64(st.f_blocks as u64 * frsize);
+        r.u64(st.f_bfree as u64 * frsize);
+        r.u64(st.f_bavail as u64 * frsize);
+        r.u64(st.f_files as u64);
+        r.u64(st.f_ffree as u64);
+        r.u64(st.f_favail as u64);
+        // The tree may change at any time, on refresh.
+        r.u32(0);
+        Ok(None)
+    }
+
+    fn fsinfo(&self, m: &mut Xdr<'_>, r: &mut Encoder) -> Result<Option<Status>, Garbage> {
+        let fh = m.opaque()?;
+        let attr = match self.decode_fh(fh).and_then(|ino| self.attr(ino)) {
+            Ok(attr) => attr,
+            Err(status) => {
+                let status = r.status(status);
+                r.bool(false);
+                return Ok(status);
+            }
+        };
+        r.u32(NFS3_OK);
+        r.post_op(Some(&attr), self.instance);
+        // rtmax, rtpref and rtmult, then the same for writes.
+        for _ in 0..2 {
+            r.u32(MAX_IO);
+            r.u32(MAX_IO);
+            r.u32(4096);
+        }
+        r.u32(1 << 16);
+        r.u64(u64::MAX);
+        r.u32(0);
+        r.u32(1);
+        r.u32(FSF3_SYMLINK | FSF3_HOMOGENEOUS);
+        Ok(None)
+    }
+
+    fn pathconf(&self, m: &mut Xdr<'_>, r: &mut Encoder) -> Result<Option<Status>, Garbage> {
+        let fh = m.opaque()?;
+        let attr = match self.decode_fh(fh).and_then(|ino| self.attr(ino)) {
+            Ok(attr) => attr,
+            Err(status) => {
+                let status = r.status(status);
+                r.bool(false);
+                return Ok(status);
+            }
+        };
+        r.u32(NFS3_OK);
+        r.post_op(Some(&attr), self.instance);
+        r.u32(1);
+        r.u32(self.fs.inner.name_max as u32);
+        // no_trunc, chown_restricted, case_insensitive and
+        // case_preserving.
+        r.bool(true);
+        r.bool(true);
+        r.bool(false);
+        r.bool(true);
+        Ok(None)
+    }
+
+    fn attr(&self, ino: Ino) -> Result<FileAttr, Status> {
+        self.fs.do_getattr(ino).map_err(|e| match self.fs.errno(&e) {
+            // The entry is gone, e.g. after a refresh.
+            ENOENT => NFS3ERR_STALE,
+            errno => nfs_status(errno),
+        })
+    }
+
+    /// The parent of a dir.  The root is its own parent.
+    fn parent(&self, ino: Ino) -> Ino {
+        if control::owns(ino) {
+            return Ino::ROOT;
+        }
+        self.fs.inomap().get(ino).map_or(Ino::ROOT, |entry| entry.parent)
+    }
+
+    fn status(&self, e: &Error) -> Status {
+        nfs_status(self.fs.errno(e))
+    }
+
+    fn encode_fh(&self, ino: Ino) -> Vec<u8> {
+        let mut fh = self.instance.to_be_bytes().to_vec();
+        fh.extend_from_slice(&u64::from(ino).to_be_bytes());
+        fh
+    }
+
+    fn decode_fh(&self, fh: &[u8]) -> Result<Ino, Status> {
+        if fh.len() != 16 {
+            return Err(NFS3ERR_BADHANDLE);
+        }
+        let mut instance = [0; 8];
+        let mut ino = [0; 8];
+        instance.copy_from_slice(&fh[..8]);
+        ino.copy_from_slice(&fh[8..]);
+        if u64::from_be_bytes(instance) != self.instance {
+            return Err(NFS3ERR_STALE);
+        }
+        Ok(u64::from_be_bytes(ino).into())
+    }
+}
+
+/// Read a record made of fragments, each with a header telling its
+/// length and whether it's the last.  Return `None` at EOF.
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut record = vec![];
+    loop {
+        let mut header = [0; 4];
+        match reader.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && record.is_empty() => return Ok(None),
+            result => result?,
+        }
+        let header = u32::from_be_bytes(header);
+        let len = (header & 0x7fff_ffff) as usize;
+        if record.len() + len > MAX_IO as usize + 4096 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "RPC record too long"));
+        }
+        let start = record.len();
+        record.resize(start + len, 0);
+        reader.read_exact(&mut record[start..])?;
+        if header & 0x8000_0000 != 0 {
+            return Ok(Some(record));
+        }
+    }
+}
+
+/// The name under which a procedure is counted and logged.
+fn nfs_op_name(proc: u32) -> Option<&'static str> {
+    let names = [
+        "null", "getattr", "setattr", "lookup", "access", "readlink", "read", "write", "create", "mkdir", "symlink",
+        "mknod", "remove", "rmdir", "rename", "link", "readdir", "readdirplus", "fsstat", "fsinfo", "pathconf", "commit",
+    ];
+    names.get(proc as usize).copied()
+}
+
+/// The NFS status for an errno.  Most have the same number as on
+/// Linux; the others are an I/O error.
+fn nfs_status(errno: c_int) -> Status {
+    match errno {
+        EPERM => 1,
+        ENOENT => 2,
+        EACCES => 13,
+        EEXIST => 17,
+        ENOTDIR => 20,
+        EISDIR => 21,
+        EINVAL => 22,
+        EFBIG => 27,
+        ENOSPC => 28,
+        EROFS => NFS3ERR_ROFS,
+        ENAMETOOLONG => 63,
+        ENOTEMPTY => 66,
+        EDQUOT => 69,
+        _ => NFS3ERR_IO,
+    }
+}
+
+/// Reads XDR: big-endian words, with opaque data padded to a word.
+struct Xdr<'a>(&'a [u8]);
+
+impl<'a> Xdr<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Garbage> {
+        if self.0.len() < n {
+            return Err(Garbage);
+        }
+        let (field, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u32(&mut self) -> Result<u32, Garbage> {
+        let mut field = [0; 4];
+        field.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(field))
+    }
+
+    fn u64(&mut self) -> Result<u64, Garbage> {
+        let mut field = [0; 8];
+        field.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(field))
+    }
+
+    fn opaque(&mut self) -> Result<&'a [u8], Garbage> {
+        let len = self.u32()? as usize;
+        let field = self.take(len)?;
+        self.take(padding(len))?;
+        Ok(field)
+    }
+}
+
+/// Builds XDR.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u32(&mut self, x: u32) {
+        self.0.extend_from_slice(&x.to_be_bytes());
+    }
+
+    fn u64(&mut self, x: u64) {
+        self.0.extend_from_slice(&x.to_be_bytes());
+    }
+
+    fn bool(&mut self, x: bool) {
+        self.u32(x as u32);
+    }
+
+    fn opaque(&mut self, x: &[u8]) {
+        self.u32(x.len() as u32);
+        self.0.extend_from_slice(x);
+        self.0.extend_from_slice(&[0; 3][..padding(x.len())]);
+    }
+
+    /// An accepted reply, with no verifier, and how it went.
+    fn accepted(&mut self, stat: u32) {
+        self.u32(MSG_ACCEPTED);
+        self.u32(AUTH_NONE);
+        self.u32(0);
+        self.u32(stat);
+    }
+
+    /// A failed status, which is also returned to be logged.
+    fn status(&mut self, status: Status) -> Option<Status> {
+        self.u32(status);
+        Some(status)
+    }
+
+    fn fattr(&mut self, attr: &FileAttr, fsid: u64) {
+        self.u32(match attr.kind {
+            FileType::RegularFile => 1,
+            FileType::Directory => 2,
+            FileType::BlockDevice => 3,
+            FileType::CharDevice => 4,
+            FileType::Symlink => 5,
+            FileType::Socket => 6,
+            FileType::NamedPipe => 7,
+        });
+        self.u32(u32::from(attr.perm) & 0o7777);
+        self.u32(attr.nlink);
+        self.u32(attr.uid);
+        self.u32(attr.gid);
+        self.u64(attr.size);
+        self.u64(attr.blocks * 512);
+        // rdev, as major and minor
+        self.u64(0);
+        self.u64(fsid);
+        self.u64(attr.ino);
+        for time in [attr.atime, attr.mtime, attr.ctime].iter() {
+            let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            self.u32(since.as_secs() as u32);
+            self.u32(since.subsec_nanos());
+        }
+    }
+
+    fn post_op(&mut self, attr: Option<&FileAttr>, fsid: u64) {
+        self.bool(attr.is_some());
+        if let Some(attr) = attr {
+            self.fattr(attr, fsid);
+        }
+    }
+}
+
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fs_for_test;
+    use std::path::PathBuf;
+
+    struct Client {
+        stream: TcpStream,
+        xid: u32,
+    }
+
+    impl Client {
+        /// Call a procedure, and return the body of the reply.
+        fn call<F: FnOnce(&mut Encoder)>(&mut self, prog: u32, proc: u32, args: F) -> Vec<u8> {
+            self.xid += 1;
+            let mut call = Encoder::default();
+            for word in [self.xid, CALL, RPC_VERSION, prog, VERSION, proc, AUTH_NONE, 0, AUTH_NONE, 0].iter() {
+                call.u32(*word);
+            }
+            args(&mut call);
+            let mut record = (0x8000_0000 | call.0.len() as u32).to_be_bytes().to_vec();
+            record.extend_from_slice(&call.0);
+            self.stream.write_all(&record).unwrap();
+            let reply = read_record(&mut self.stream).unwrap().unwrap();
+            let mut m = Xdr(&reply);
+            assert_eq!((m.u32().ok(), m.u32().ok()), (Some(self.xid), Some(REPLY)));
+            // accepted, no verifier, success
+            for _ in 0..4 {
+                assert_eq!(m.u32().ok(), Some(0));
+            }
+            m.0.to_vec()
+        }
+
+        fn lookup(&mut self, dir: &[u8], name: &str) -> (u32, Vec<u8>) {
+            let reply = self.call(NFS_PROGRAM, 3, |r| {
+                r.opaque(dir);
+                r.opaque(name.as_bytes());
+            });
+            let mut m = Xdr(&reply);
+            match m.u32().ok().unwrap() {
+                NFS3_OK => (NFS3_OK, m.opaque().ok().unwrap().to_vec()),
+                status => (status, vec![]),
+            }
+        }
+    }
+
+    /// Export a repo with `a.txt` and `dir/b.txt`, and mount its root.
+    fn serve(name: &str) -> (PathBuf, Watcher, Client, Vec<u8>) {
+        let (root, fs) = fs_for_test(&format!("nfs-{}", name));
+        let (server, addr) = fs.serve_nfs("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = Client {
+            stream: TcpStream::connect(addr).unwrap(),
+            xid: 0,
+        };
+        let reply = client.call(MOUNT_PROGRAM, 1, |r| r.opaque(b"/"));
+        let mut m = Xdr(&reply);
+        assert_eq!(m.u32().ok(), Some(NFS3_OK));
+        let fh = m.opaque().ok().unwrap().to_vec();
+        (root, server, client, fh)
+    }
+
+    #[test]
+    fn files_are_looked_up_and_read() {
+        let (root, _server, mut c, top) = serve("read");
+        let (status, dir) = c.lookup(&top, "dir");
+        assert_eq!(status, NFS3_OK);
+        let (_, file) = c.lookup(&dir, "b.txt");
+        let reply = c.call(NFS_PROGRAM, 6, |r| {
+            r.opaque(&file);
+            r.u64(3);
+            r.u32(100);
+        });
+        let mut m = Xdr(&reply);
+        assert_eq!(m.u32().ok(), Some(NFS3_OK));
+        assert_eq!(m.u32().ok(), Some(1));
+        m.take(84).ok().unwrap();
+        assert_eq!((m.u32().ok(), m.u32().ok()), (Some(5), Some(1)));
+        assert_eq!(m.opaque().ok(), Some(&b"a dir"[..]));
+
+        assert_eq!(c.lookup(&top, "missing").0, 2);
+        assert_eq!(c.lookup(&dir, "..").1, top);
+        let mut stale = top.clone();
+        stale[0] ^= 1;
+        assert_eq!(c.lookup(&stale, "a.txt").0, NFS3ERR_STALE);
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn dirs_are_listed_in_pages() {
+        let (root, _server, mut c, top) = serve("readdir");
+        let mut names = vec![];
+        let mut cookie = 0;
+        loop {
+            // Room for about one entry with its attrs at a time.
+            let reply = c.call(NFS_PROGRAM, 17, |r| {
+                r.opaque(&top);
+                r.u64(cookie);
+                r.0.extend_from_slice(&[0; 8]);
+                r.u32(1024);
+                r.u32(READDIR_HEADER as u32 + 160);
+            });
+            let mut m = Xdr(&reply);
+            assert_eq!(m.u32().ok(), Some(NFS3_OK));
+            assert_eq!(m.u32().ok(), Some(1));
+            m.take(84 + 8).ok().unwrap();
+            while m.u32().ok() == Some(1) {
+                let _fileid = m.u64().ok().unwrap();
+                names.push(String::from_utf8(m.opaque().ok().unwrap().to_vec()).unwrap());
+                cookie = m.u64().ok().unwrap();
+                assert_eq!(m.u32().ok(), Some(1));
+                m.take(84).ok().unwrap();
+                assert_eq!(m.u32().ok(), Some(1));
+                assert_eq!(m.opaque().ok().unwrap().len(), 16);
+            }
+            if m.u32().ok() == Some(1) {
+                break;
+            }
+        }
+        assert_eq!(names, [".", "..", "a.txt", "dir"]);
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn changes_are_refused() {
+        let (root, _server, mut c, top) = serve("write");
+        let (_, file) = c.lookup(&top, "a.txt");
+        let reply = c.call(NFS_PROGRAM, 7, |r| {
+            r.opaque(&file);
+            r.u64(0);
+            r.u32(2);
+            r.u32(0);
+            r.opaque(b"hi");
+        });
+        assert_eq!(Xdr(&reply).u32().ok(), Some(NFS3ERR_ROFS));
+        let reply = c.call(NFS_PROGRAM, 8, |r| {
+            r.opaque(&top);
+            r.opaque(b"new.txt");
+        });
+        assert_eq!(Xdr(&reply).u32().ok(), Some(NFS3ERR_ROFS));
+        assert!(!root.join("overlay/a.txt").exists());
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
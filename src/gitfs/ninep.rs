@@ -0,0 +1,1036 @@
+/// A 9P2000.L server exposing the same tree as the FUSE mount
+/// (`--serve 9p`), for consumers without FUSE: QEMU and crosvm guests,
+/// or WSL, mount it with
+///
+/// ```text
+/// mount -t 9p -o trans=tcp,port=5640,version=9p2000.L host /mnt
+/// ```
+///
+/// Every message is answered by the same operations as the FUSE
+/// handlers, so dirty files, rules, quotas and the control dir behave
+/// alike.  Each connection is served by its own thread, one message
+/// at a time; `Tflush` has nothing to cancel then.  Changes are
+/// audited with the uid given in `Tattach` and pid 0, as 9P doesn't
+/// tell which process made them.  Locks are always granted, which
+/// leaves them to the client, as with FUSE.
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType, TimeOrNow};
+use libc::{c_int, EBADF, EINVAL, EISDIR, ENOENT, ENOTDIR, EOPNOTSUPP, EPERM, EPROTO, O_ACCMODE};
+
+use super::server::{self, mode_bits};
+use super::{control, statvfs, GitFS, SetAttr};
+use crate::error::Error;
+use crate::watch::Watcher;
+use crate::{Ino, MODE_FILE};
+
+/// The largest message accepted, unless the client asks for less.
+const MSIZE: u32 = 1 << 20;
+/// size[4] type[1] tag[2]
+const HEADER: usize = 7;
+/// Room for the header and count[4] of `Rread` and `Rreaddir`.
+const IO_HEADER: u32 = HEADER as u32 + 4;
+const NOFID: u32 = !0;
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TXATTRCREATE: u8 = 32;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0;
+
+/// The open flags of 9P2000.L are those of Linux, whatever the host.
+const L_O_TRUNC: u32 = 0o1000;
+const AT_REMOVEDIR: u32 = 0x200;
+
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+/// Everything `Rgetattr` can tell, up to and including btime.
+const GETATTR_ALL: u64 = 0xfff;
+
+/// Serve `fs` over 9P at `addr`, until the returned watcher is
+/// dropped.
+pub(super) fn serve(fs: GitFS, addr: SocketAddr) -> Result<(Watcher, SocketAddr), Error> {
+    fs.mount_root()?;
+    Ok(server::listen("9p", addr, move |stream| Session::new(fs.clone()).run(stream))?)
+}
+
+/// The fids of a connection.
+struct Session {
+    fs: GitFS,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+/// What a fid points to.
+#[derive(Clone)]
+struct Fid {
+    ino: Ino,
+    /// The dirs walked through from the attached dir, each with the
+    /// name of the next step, so that `..` and `Tremove` know where
+    /// the fid is.
+    parents: Vec<(Ino, OsString)>,
+    /// The uid given in `Tattach`.
+    uid: u32,
+    open: Option<Open>,
+}
+
+#[derive(Clone)]
+enum Open {
+    File(u64),
+    /// The listing of a dir, taken when it's read from the start.
+    Dir(Vec<(OsString, Ino, FileType)>),
+    /// The value of an xattr, or the list of their names.
+    Xattr(Vec<u8>),
+}
+
+impl Session {
+    fn new(fs: GitFS) -> Session {
+        Session {
+            fs,
+            msize: MSIZE,
+            fids: HashMap::new(),
+        }
+    }
+
+    /// Answer messages until the client hangs up.
+    fn run(&mut self, stream: &TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut writer = stream;
+        loop {
+            let mut size = [0; 4];
+            match reader.read_exact(&mut size) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                result => result?,
+            }
+            let size = u32::from_le_bytes(size);
+            if (size as usize) < HEADER || size > self.msize {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad message size {}", size)));
+            }
+            let mut message = vec![0; size as usize - 4];
+            reader.read_exact(&mut message)?;
+            writer.write_all(&self.respond(&message))?;
+        }
+    }
+
+    /// Answer a message, given without its size.
+    fn respond(&mut self, message: &[u8]) -> Vec<u8> {
+        let (ty, tag) = (message[0], u16::from_le_bytes([message[1], message[2]]));
+        let mut body = Decoder(&message[3..]);
+        let mut reply = Encoder::new(ty.wrapping_add(1), tag);
+        if let Err(errno) = self.dispatch(ty, &mut body, &mut reply) {
+            reply = Encoder::new(RLERROR, tag);
+            reply.u32(errno as u32);
+        }
+        reply.finish()
+    }
+
+    /// Answer a message in `r`, or fail with the errno to reply.
+    fn dispatch(&mut self, ty: u8, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), c_int> {
+        // Most messages start with the fid they are about.
+        let ino = m.peek_u32().and_then(|fid| self.fids.get(&fid)).map_or(Ino::ROOT, |fid| fid.ino);
+        let op = op_name(ty);
+        // Timed on a clone, as the session is changed meanwhile.
+        let fs = self.fs.clone();
+        let _timer = fs.start_op(op, ino);
+        let span = debug_span!(
+            "9p",
+            op,
+            ino = u64::from(ino),
+            path = %fs.path_of(ino).display(),
+            errno = tracing::field::Empty
+        )
+        .entered();
+        if ty != TVERSION && ty != TATTACH && ty != TFLUSH {
+            fs.refresh_if_requested();
+        }
+        self.handle(ty, m, r).map_err(|e| {
+            let errno = fs.errno(&e);
+            span.record("errno", errno);
+            errno
+        })
+    }
+
+    fn handle(&mut self, ty: u8, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        match ty {
+            TVERSION => self.version(m, r),
+            TAUTH => Err(Error::Errno(EOPNOTSUPP)),
+            TATTACH => self.attach(m, r),
+            TFLUSH => m.u16().map(drop),
+            TWALK => self.walk(m, r),
+            TCLUNK => {
+                let fid = m.u32()?;
+                self.clunk(fid)
+            }
+            TREMOVE => self.remove(m),
+            TSTATFS => self.statfs(m, r),
+            TGETATTR => self.getattr(m, r),
+            TSETATTR => self.setattr(m),
+            TLOPEN => self.lopen(m, r),
+            TLCREATE => self.lcreate(m, r),
+            TREAD => self.read(m, r),
+            TWRITE => self.write(m, r),
+            TREADDIR => self.readdir(m, r),
+            TREADLINK => {
+                let fid = self.fid(m.u32()?)?;
+                r.bytes(&self.fs.do_readlink(fid.ino)?);
+                Ok(())
+            }
+            TFSYNC => match self.fid(m.u32()?)? {
+                Fid { ino, open: Some(Open::File(fh)), .. } => self.fs.do_flush(*ino, *fh),
+                _ => Ok(()),
+            },
+            TMKDIR => self.mkdir(m, r),
+            TRENAME => self.rename(m),
+            TRENAMEAT => self.renameat(m),
+            TUNLINKAT => self.unlinkat(m),
+            TXATTRWALK => self.xattrwalk(m, r),
+            TXATTRCREATE => Err(Error::Errno(EOPNOTSUPP)),
+            TLOCK => {
+                self.fid(m.u32()?)?;
+                r.u8(0);
+                Ok(())
+            }
+            TGETLOCK => self.getlock(m, r),
+            TSYMLINK | TMKNOD | TLINK => Err(Error::Errno(EPERM)),
+            _ => Err(Error::Errno(EOPNOTSUPP)),
+        }
+    }
+
+    fn fid(&self, fid: u32) -> Result<&Fid, Error> {
+        self.fids.get(&fid).ok_or(Error::Errno(EBADF))
+    }
+
+    /// Store a new fid, which must not be in use.
+    fn add_fid(&mut self, fid: u32, value: Fid) -> Result<(), Error> {
+        if fid == NOFID || self.fids.contains_key(&fid) {
+            return Err(Error::Errno(EBADF));
+        }
+        self.fids.insert(fid, value);
+        Ok(())
+    }
+
+    fn version(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let msize = m.u32()?;
+        let version = m.bytes()?;
+        // A new version starts the session afresh.
+        for fid in self.fids.keys().copied().collect::<Vec<_>>() {
+            self.clunk(fid)?;
+        }
+        self.msize = msize.clamp(IO_HEADER + 1, MSIZE);
+        r.u32(self.msize);
+        r.bytes(if version.starts_with(b"9P2000.L") { b"9P2000.L" } else { b"unknown" });
+        Ok(())
+    }
+
+    fn attach(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let fid = m.u32()?;
+        let _afid = m.u32()?;
+        let _uname = m.bytes()?;
+        let aname = OsStr::from_bytes(m.bytes()?).to_owned();
+        let uid = m.u32().unwrap_or(NOFID);
+        // The aname picks a dir of the tree to attach to.
+        let mut root = Fid {
+            ino: Ino::ROOT,
+            parents: vec![],
+            uid,
+            open: None,
+        };
+        for name in std::path::Path::new(&aname).iter().filter(|name| *name != "/") {
+            self.step(&mut root, name)?;
+        }
+        let attr = self.fs.do_getattr(root.ino)?;
+        if attr.kind != FileType::Directory {
+            return Err(Error::Errno(ENOTDIR));
+        }
+        self.add_fid(fid, root)?;
+        r.qid(&attr);
+        Ok(())
+    }
+
+    fn walk(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let (fid, newfid) = (m.u32()?, m.u32()?);
+        let mut names = vec![];
+        for _ in 0..m.u16()? {
+            names.push(OsStr::from_bytes(m.bytes()?));
+        }
+        let mut walked = Fid {
+            open: None,
+            ..self.fid(fid)?.clone()
+        };
+        let mut qids = vec![];
+        for name in names.iter() {
+            match self.step(&mut walked, name) {
+                Ok(attr) => qids.push(attr),
+                // Only a failed first step is an error.
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+        if qids.len() == names.len() {
+            if newfid != fid {
+                self.add_fid(newfid, walked)?;
+            } else {
+                self.clunk(fid)?;
+                self.fids.insert(fid, walked);
+            }
+        }
+        r.u16(qids.len() as u16);
+        for attr in &qids {
+            r.qid(attr);
+        }
+        Ok(())
+    }
+
+    /// Walk a fid one step, to `name` or up with `..`.
+    fn step(&self, fid: &mut Fid, name: &OsStr) -> Result<FileAttr, Error> {
+        if name == ".." {
+            // Walking up stops at the attached dir.
+            if let Some((parent, _)) = fid.parents.pop() {
+                fid.ino = parent;
+            }
+            return self.fs.do_getattr(fid.ino);
+        }
+        let attr = self.fs.do_lookup(fid.ino, name)?;
+        fid.parents.push((fid.ino, name.to_owned()));
+        fid.ino = attr.ino.into();
+        Ok(attr)
+    }
+
+    /// Forget a fid, and release what it has open.
+    fn clunk(&mut self, fid: u32) -> Result<(), Error> {
+        let fid = self.fids.remove(&fid).ok_or(Error::Errno(EBADF))?;
+        if let Some(Open::File(fh)) = fid.open {
+            self.fs.release_handle(fid.ino, fh);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, m: &mut Decoder<'_>) -> Result<(), Error> {
+        let fid = m.u32()?;
+        let value = self.fid(fid)?.clone();
+        // The fid is clunked even if the removal fails.
+        self.clunk(fid)?;
+        let (parent, name) = value.parents.last().ok_or(Error::Errno(EBADF))?;
+        let dir = self.fs.do_getattr(value.ino)?.kind == FileType::Directory;
+        self.fs.do_remove(*parent, name)?;
+        self.fs.audit_as(value.uid, 0, if dir { "rmdir" } else { "unlink" }, || {
+            format!("{:?}", self.fs.child_path((*parent).into(), name))
+        });
+        Ok(())
+    }
+
+    fn statfs(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        self.fid(m.u32()?)?;
+        let st = statvfs(&self.fs.inner.underlying_dir)?;
+        r.u32(V9FS_MAGIC);
+        r.u32(st.f_bsize as u32);
+        r.u64(st.f_blocks as u64);
+        r.u64(st.f_bfree as u64);
+        r.u64(st.f_bavail as u64);
+        r.u64(st.f_files as u64);
+        r.u64(st.f_ffree as u64);
+        r.u64(0);
+        r.u32(self.fs.inner.name_max as u32);
+        Ok(())
+    }
+
+    fn getattr(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let fid = self.fid(m.u32()?)?;
+        let attr = self.fs.do_getattr(fid.ino)?;
+        r.u64(GETATTR_ALL);
+        r.qid(&attr);
+        r.u32(mode_bits(attr.kind) | u32::from(attr.perm));
+        r.u32(attr.uid);
+        r.u32(attr.gid);
+        r.u64(attr.nlink.into());
+        r.u64(attr.rdev.into());
+        r.u64(attr.size);
+        r.u64(attr.blksize.into());
+        r.u64(attr.blocks);
+        for time in [attr.atime, attr.mtime, attr.ctime, attr.crtime].iter() {
+            r.time(*time);
+        }
+        // gen and data_version are not kept.
+        r.u64(0);
+        r.u64(0);
+        Ok(())
+    }
+
+    fn setattr(&mut self, m: &mut Decoder<'_>) -> Result<(), Error> {
+        let fid = self.fid(m.u32()?)?;
+        let valid = m.u32()?;
+        let (mode, uid, gid, size) = (m.u32()?, m.u32()?, m.u32()?, m.u64()?);
+        let atime = m.time()?;
+        let mtime = m.time()?;
+        let time = |set: u32, time_set: u32, time: SystemTime| match (valid & set != 0, valid & time_set != 0) {
+            (false, _) => None,
+            (true, true) => Some(TimeOrNow::SpecificTime(time)),
+            (true, false) => Some(TimeOrNow::Now),
+        };
+        let attrs = SetAttr {
+            mode: Some(mode).filter(|_| valid & SETATTR_MODE != 0),
+            uid: Some(uid).filter(|_| valid & SETATTR_UID != 0),
+            gid: Some(gid).filter(|_| valid & SETATTR_GID != 0),
+            size: Some(size).filter(|_| valid & SETATTR_SIZE != 0),
+            atime: time(SETATTR_ATIME, SETATTR_ATIME_SET, atime),
+            mtime: time(SETATTR_MTIME, SETATTR_MTIME_SET, mtime),
+            crtime: None,
+            flags: None,
+        };
+        self.fs.do_setattr(fid.ino, attrs)?;
+        if valid & SETATTR_SIZE != 0 {
+            self.fs.audit_as(fid.uid, 0, "truncate", || format!("{:?} size={}", self.fs.path_of(fid.ino), size));
+        }
+        Ok(())
+    }
+
+    fn lopen(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let fid = m.u32()?;
+        let flags = m.u32()?;
+        let value = self.fid(fid)?;
+        if value.open.is_some() {
+            return Err(Error::Errno(EBADF));
+        }
+        let (ino, uid) = (value.ino, value.uid);
+        let attr = self.fs.do_getattr(ino)?;
+        let open = if attr.kind == FileType::Directory {
+            self.fs.do_opendir(ino)?;
+            Open::Dir(vec![])
+        } else {
+            let fh = self.fs.do_open(ino, flags as i32 & O_ACCMODE)?;
+            if flags & L_O_TRUNC != 0 && flags as i32 & O_ACCMODE != libc::O_RDONLY && !control::owns(ino) {
+                if let Err(e) = self.fs.truncate_handle(ino, fh) {
+                    self.fs.handles().remove(fh);
+                    return Err(e);
+                }
+                self.fs.audit_as(uid, 0, "truncate", || format!("{:?} size=0", self.fs.path_of(ino)));
+            }
+            Open::File(fh)
+        };
+        if let Some(value) = self.fids.get_mut(&fid) {
+            value.open = Some(open);
+        }
+        r.qid(&attr);
+        r.u32(0);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let fid = m.u32()?;
+        let name = OsStr::from_bytes(m.bytes()?);
+        let (_flags, mode, _gid) = (m.u32()?, m.u32()?, m.u32()?);
+        let value = self.fid(fid)?;
+        if value.open.is_some() {
+            return Err(Error::Errno(EBADF));
+        }
+        let (parent, uid) = (value.ino, value.uid);
+        self.fs.do_opendir(parent)?;
+        let (attr, fh) = self.fs.do_create(parent, name, MODE_FILE | (mode & 0o7777))?;
+        self.fs.audit_as(uid, 0, "create", || format!("{:?}", self.fs.child_path(parent.into(), name)));
+        // The fid now stands for the new file, opened.
+        if let Some(value) = self.fids.get_mut(&fid) {
+            value.parents.push((parent, name.to_owned()));
+            value.ino = attr.ino.into();
+            value.open = Some(Open::File(fh));
+        }
+        r.qid(&attr);
+        r.u32(0);
+        Ok(())
+    }
+
+    fn read(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let fid = self.fid(m.u32()?)?;
+        let (offset, count) = (m.u64()?, m.u32()?);
+        let count = count.min(self.msize - IO_HEADER);
+        let data = match &fid.open {
+            Some(Open::File(fh)) => self.fs.do_read(fid.ino, *fh, offset, count)?,
+            Some(Open::Xattr(value)) => {
+                let start = (offset.min(value.len() as u64)) as usize;
+                let end = (start + count as usize).min(value.len());
+                value[start..end].to_vec()
+            }
+            Some(Open::Dir(_)) => return Err(Error::Errno(EISDIR)),
+            None => return Err(Error::Errno(EBADF)),
+        };
+        r.bytes32(&data);
+        Ok(())
+    }
+
+    fn write(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let fid = self.fid(m.u32()?)?;
+        let offset = m.u64()?;
+        let data = m.bytes32()?;
+        let fh = match fid.open {
+            Some(Open::File(fh)) => fh,
+            _ => return Err(Error::Errno(EBADF)),
+        };
+        let nbytes = self.fs.do_write(fid.ino, fh, offset, data)?;
+        self.fs.audit_as(fid.uid, 0, "write", || {
+            format!("{:?} offset={} size={}", self.fs.path_of(fid.ino), offset, nbytes)
+        });
+        r.u32(nbytes);
+        Ok(())
+    }
+
+    fn readdir(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let fid = m.u32()?;
+        let (offset, count) = (m.u64()?, m.u32()?);
+        let count = count.min(self.msize - IO_HEADER) as usize;
+        let value = self.fid(fid)?;
+        let ino = value.ino;
+        let parent = value.parents.last().map_or(ino, |(parent, _)| *parent);
+        match value.open {
+            Some(Open::Dir(_)) => (),
+            _ => return Err(Error::Errno(EBADF)),
+        }
+        // Reading from the start lists the dir again, as rewinddir()
+        // does.
+        if offset == 0 {
+            self.fs.do_opendir(ino)?;
+            let mut listing = vec![
+                (OsString::from("."), ino, FileType::Directory),
+                (OsString::from(".."), parent, FileType::Directory),
+            ];
+            listing.extend(self.fs.do_readdir(ino)?);
+            if let Some(value) = self.fids.get_mut(&fid) {
+                value.open = Some(Open::Dir(listing));
+            }
+        }
+        let listing = match &self.fid(fid)?.open {
+            Some(Open::Dir(listing)) => listing,
+            _ => return Err(Error::Errno(ENOENT)),
+        };
+        let mut entries = Encoder::default();
+        for (i, (name, child, kind)) in listing.iter().enumerate().skip(offset as usize) {
+            let name = name.as_bytes();
+            // qid[13] offset[8] type[1] name[s]
+            if entries.0.len() + 24 + name.len() > count {
+                break;
+            }
+            entries.raw_qid(qid_type(*kind), (*child).into());
+            entries.u64(i as u64 + 1);
+            entries.u8(dirent_type(*kind));
+            entries.bytes(name);
+        }
+        r.bytes32(&entries.0);
+        Ok(())
+    }
+
+    fn mkdir(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let fid = self.fid(m.u32()?)?;
+        let name = OsStr::from_bytes(m.bytes()?);
+        let (mode, _gid) = (m.u32()?, m.u32()?);
+        self.fs.do_opendir(fid.ino)?;
+        let attr = self.fs.do_mkdir(fid.ino, name, mode & 0o7777)?;
+        self.fs.audit_as(fid.uid, 0, "mkdir", || format!("{:?}", self.fs.child_path(fid.ino.into(), name)));
+        r.qid(&attr);
+        Ok(())
+    }
+
+    fn rename(&mut self, m: &mut Decoder<'_>) -> Result<(), Error> {
+        let fid = m.u32()?;
+        let dir = self.fid(m.u32()?)?.ino;
+        let newname = OsStr::from_bytes(m.bytes()?);
+        let value = self.fid(fid)?;
+        let (parent, name) = value.parents.last().cloned().ok_or(Error::Errno(EBADF))?;
+        let uid = value.uid;
+        self.do_rename(uid, parent, &name, dir, newname)?;
+        if let Some(value) = self.fids.get_mut(&fid) {
+            value.parents.pop();
+            value.parents.push((dir, newname.to_owned()));
+        }
+        Ok(())
+    }
+
+    fn renameat(&mut self, m: &mut Decoder<'_>) -> Result<(), Error> {
+        let olddir = self.fid(m.u32()?)?;
+        let oldname = OsStr::from_bytes(m.bytes()?);
+        let newdir = self.fid(m.u32()?)?.ino;
+        let newname = OsStr::from_bytes(m.bytes()?);
+        self.do_rename(olddir.uid, olddir.ino, oldname, newdir, newname)
+    }
+
+    fn do_rename(&self, uid: u32, parent: Ino, name: &OsStr, newparent: Ino, newname: &OsStr) -> Result<(), Error> {
+        self.fs.do_opendir(parent)?;
+        self.fs.do_opendir(newparent)?;
+        self.fs.do_rename(parent, name, newparent, newname)?;
+        self.fs.audit_as(uid, 0, "rename", || {
+            format!("{:?} {:?}", self.fs.child_path(parent.into(), name), self.fs.child_path(newparent.into(), newname))
+        });
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, m: &mut Decoder<'_>) -> Result<(), Error> {
+        let dir = self.fid(m.u32()?)?;
+        let name = OsStr::from_bytes(m.bytes()?);
+        let flags = m.u32()?;
+        self.fs.do_opendir(dir.ino)?;
+        self.fs.do_remove(dir.ino, name)?;
+        let op = if flags & AT_REMOVEDIR != 0 { "rmdir" } else { "unlink" };
+        self.fs.audit_as(dir.uid, 0, op, || format!("{:?}", self.fs.child_path(dir.ino.into(), name)));
+        Ok(())
+    }
+
+    fn xattrwalk(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        let (fid, newfid) = (m.u32()?, m.u32()?);
+        let name = OsStr::from_bytes(m.bytes()?);
+        let value = self.fid(fid)?;
+        // No name asks for the list of names.
+        let xattr = if name.is_empty() {
+            self.fs.do_listxattr(value.ino)?
+        } else {
+            self.fs.do_getxattr(value.ino, name)?
+        };
+        r.u64(xattr.len() as u64);
+        let walked = Fid {
+            open: Some(Open::Xattr(xattr)),
+            ..value.clone()
+        };
+        if newfid == fid {
+            self.clunk(fid)?;
+            self.fids.insert(fid, walked);
+            Ok(())
+        } else {
+            self.add_fid(newfid, walked)
+        }
+    }
+
+    fn getlock(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Result<(), Error> {
+        self.fid(m.u32()?)?;
+        let _ty = m.u8()?;
+        let (start, length, proc_id) = (m.u64()?, m.u64()?, m.u32()?);
+        let client_id = m.bytes()?;
+        // Nothing is ever locked here.
+        r.u8(2);
+        r.u64(start);
+        r.u64(length);
+        r.u32(proc_id);
+        r.bytes(client_id);
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for fid in self.fids.keys().copied().collect::<Vec<_>>() {
+            let _ = self.clunk(fid);
+        }
+    }
+}
+
+/// The name under which a message is counted and logged.
+fn op_name(ty: u8) -> &'static str {
+    match ty {
+        TSTATFS => "statfs",
+        TLOPEN => "lopen",
+        TLCREATE => "lcreate",
+        TSYMLINK => "symlink",
+        TMKNOD => "mknod",
+        TRENAME => "rename",
+        TREADLINK => "readlink",
+        TGETATTR => "getattr",
+        TSETATTR => "setattr",
+        TXATTRWALK => "xattrwalk",
+        TXATTRCREATE => "xattrcreate",
+        TREADDIR => "readdir",
+        TFSYNC => "fsync",
+        TLOCK => "lock",
+        TGETLOCK => "getlock",
+        TLINK => "link",
+        TMKDIR => "mkdir",
+        TRENAMEAT => "renameat",
+        TUNLINKAT => "unlinkat",
+        TVERSION => "version",
+        TAUTH => "auth",
+        TATTACH => "attach",
+        TFLUSH => "flush",
+        TWALK => "walk",
+        TREAD => "read",
+        TWRITE => "write",
+        TCLUNK => "clunk",
+        TREMOVE => "remove",
+        _ => "unknown",
+    }
+}
+
+fn qid_type(kind: FileType) -> u8 {
+    match kind {
+        FileType::Directory => QTDIR,
+        FileType::Symlink => QTSYMLINK,
+        _ => QTFILE,
+    }
+}
+
+fn dirent_type(kind: FileType) -> u8 {
+    match kind {
+        FileType::NamedPipe => libc::DT_FIFO,
+        FileType::CharDevice => libc::DT_CHR,
+        FileType::BlockDevice => libc::DT_BLK,
+        FileType::Directory => libc::DT_DIR,
+        FileType::RegularFile => libc::DT_REG,
+        FileType::Symlink => libc::DT_LNK,
+        FileType::Socket => libc::DT_SOCK,
+    }
+}
+
+/// Reads the fields of a message, all little-endian.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < n {
+            return Err(Error::Errno(EPROTO));
+        }
+        let (field, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn peek_u32(&self) -> Option<u32> {
+        let field = self.0.get(..4)?;
+        Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let field = self.take(2)?;
+        Ok(u16::from_le_bytes([field[0], field[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut field = [0; 4];
+        field.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(field))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let mut field = [0; 8];
+        field.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(field))
+    }
+
+    /// A string: len[2] and its bytes.
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u16()?;
+        self.take(len.into())
+    }
+
+    /// Data: count[4] and its bytes.
+    fn bytes32(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+
+    /// A time: sec[8] and nsec[8], which a client may send out of
+    /// range (EINVAL).
+    fn time(&mut self) -> Result<SystemTime, Error> {
+        let (secs, nsecs) = (self.u64()?, self.u64()?);
+        if nsecs >= 1_000_000_000 {
+            return Err(Error::Errno(EINVAL));
+        }
+        UNIX_EPOCH.checked_add(Duration::new(secs, nsecs as u32)).ok_or(Error::Errno(EINVAL))
+    }
+}
+
+/// Builds a message; the size is filled in by `finish`.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn new(ty: u8, tag: u16) -> Encoder {
+        let mut encoder = Encoder(vec![0; 4]);
+        encoder.u8(ty);
+        encoder.u16(tag);
+        encoder
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+
+    fn u8(&mut self, x: u8) {
+        self.0.push(x);
+    }
+
+    fn u16(&mut self, x: u16) {
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+
+    fn u32(&mut self, x: u32) {
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+
+    fn u64(&mut self, x: u64) {
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+
+    /// A string, cut to the 64 KiB a string can hold.
+    fn bytes(&mut self, x: &[u8]) {
+        let x = &x[..x.len().min(u16::MAX as usize)];
+        self.u16(x.len() as u16);
+        self.0.extend_from_slice(x);
+    }
+
+    fn bytes32(&mut self, x: &[u8]) {
+        self.u32(x.len() as u32);
+        self.0.extend_from_slice(x);
+    }
+
+    /// The qid of an entry: its type, version 0 and its ino as path.
+    fn qid(&mut self, attr: &FileAttr) {
+        self.raw_qid(qid_type(attr.kind), attr.ino);
+    }
+
+    fn raw_qid(&mut self, ty: u8, path: u64) {
+        self.u8(ty);
+        self.u32(0);
+        self.u64(path);
+    }
+
+    fn time(&mut self, time: SystemTime) {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.u64(since.as_secs());
+        self.u64(since.subsec_nanos().into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fs_for_test;
+    use std::path::PathBuf;
+
+    struct Client {
+        stream: TcpStream,
+        tag: u16,
+    }
+
+    impl Client {
+        /// Send a message, and return the type and body of the reply.
+        fn call<F: FnOnce(&mut Encoder)>(&mut self, ty: u8, body: F) -> (u8, Vec<u8>) {
+            self.tag += 1;
+            let mut message = Encoder::new(ty, self.tag);
+            body(&mut message);
+            self.stream.write_all(&message.finish()).unwrap();
+            let mut size = [0; 4];
+            self.stream.read_exact(&mut size).unwrap();
+            let mut reply = vec![0; u32::from_le_bytes(size) as usize - 4];
+            self.stream.read_exact(&mut reply).unwrap();
+            assert_eq!(u16::from_le_bytes([reply[1], reply[2]]), self.tag);
+            (reply[0], reply[3..].to_vec())
+        }
+
+        /// Same as `call`, for a message that must succeed.
+        fn ok<F: FnOnce(&mut Encoder)>(&mut self, ty: u8, body: F) -> Vec<u8> {
+            let (rty, reply) = self.call(ty, body);
+            assert_eq!(rty, ty + 1, "{} failed: {:?}", op_name(ty), Decoder(&reply).u32().ok());
+            reply
+        }
+
+        fn errno<F: FnOnce(&mut Encoder)>(&mut self, ty: u8, body: F) -> c_int {
+            let (rty, reply) = self.call(ty, body);
+            assert_eq!(rty, RLERROR, "{} succeeded", op_name(ty));
+            Decoder(&reply).u32().unwrap() as c_int
+        }
+
+        fn walk(&mut self, fid: u32, newfid: u32, names: &[&str]) -> Vec<u8> {
+            self.ok(TWALK, |m| {
+                m.u32(fid);
+                m.u32(newfid);
+                m.u16(names.len() as u16);
+                for name in names {
+                    m.bytes(name.as_bytes());
+                }
+            })
+        }
+    }
+
+    /// Serve a repo with `a.txt` and `dir/b.txt`, and attach to it as
+    /// fid 0.
+    fn serve(name: &str) -> (PathBuf, Watcher, Client) {
+        let (root, fs) = fs_for_test(&format!("9p-{}", name));
+        let (server, addr) = fs.serve_9p("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = Client {
+            stream: TcpStream::connect(addr).unwrap(),
+            tag: 0,
+        };
+        let reply = client.ok(TVERSION, |m| {
+            m.u32(8192);
+            m.bytes(b"9P2000.L");
+        });
+        let mut reply = Decoder(&reply);
+        assert_eq!((reply.u32().unwrap(), reply.bytes().unwrap()), (8192, &b"9P2000.L"[..]));
+        let reply = client.ok(TATTACH, |m| {
+            m.u32(0);
+            m.u32(NOFID);
+            m.bytes(b"test");
+            m.bytes(b"");
+            m.u32(1000);
+        });
+        assert_eq!(reply[0], QTDIR);
+        (root, server, client)
+    }
+
+    #[test]
+    fn files_are_walked_and_read() {
+        let (root, _server, mut c) = serve("read");
+        let reply = c.walk(0, 1, &["dir", "b.txt"]);
+        assert_eq!(Decoder(&reply).u16().unwrap(), 2);
+        c.ok(TLOPEN, |m| {
+            m.u32(1);
+            m.u32(0);
+        });
+        let reply = c.ok(TREAD, |m| {
+            m.u32(1);
+            m.u64(3);
+            m.u32(100);
+        });
+        assert_eq!(Decoder(&reply).bytes32().unwrap(), b"a dir");
+
+        // A walk only fails if its first step does.
+        let reply = c.walk(0, 2, &["dir", "missing"]);
+        assert_eq!(Decoder(&reply).u16().unwrap(), 1);
+        let errno = c.errno(TWALK, |m| {
+            m.u32(0);
+            m.u32(2);
+            m.u16(1);
+            m.bytes(b"missing");
+        });
+        assert_eq!(errno, ENOENT);
+
+        c.walk(0, 2, &[]);
+        c.ok(TLOPEN, |m| {
+            m.u32(2);
+            m.u32(0);
+        });
+        let reply = c.ok(TREADDIR, |m| {
+            m.u32(2);
+            m.u64(0);
+            m.u32(4096);
+        });
+        let mut entries = Decoder(Decoder(&reply).bytes32().unwrap());
+        let mut names = vec![];
+        while !entries.0.is_empty() {
+            entries.take(13 + 8 + 1).unwrap();
+            names.push(String::from_utf8(entries.bytes().unwrap().to_vec()).unwrap());
+        }
+        names.sort();
+        assert_eq!(names, [".", "..", "a.txt", "dir"]);
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn files_are_created_and_written() {
+        let (root, _server, mut c) = serve("write");
+        c.walk(0, 1, &[]);
+        c.ok(TLCREATE, |m| {
+            m.u32(1);
+            m.bytes(b"new.txt");
+            m.u32(libc::O_RDWR as u32);
+            m.u32(0o644);
+            m.u32(0);
+        });
+        let reply = c.ok(TWRITE, |m| {
+            m.u32(1);
+            m.u64(0);
+            m.bytes32(b"written over 9P");
+        });
+        assert_eq!(Decoder(&reply).u32().unwrap(), 15);
+        c.ok(TCLUNK, |m| m.u32(1));
+        assert_eq!(std::fs::read(root.join("overlay/new.txt")).unwrap(), b"written over 9P");
+
+        // Opening with O_TRUNC empties a clean file.
+        c.walk(0, 2, &["a.txt"]);
+        c.ok(TLOPEN, |m| {
+            m.u32(2);
+            m.u32(libc::O_WRONLY as u32 | L_O_TRUNC);
+        });
+        c.ok(TCLUNK, |m| m.u32(2));
+        assert_eq!(std::fs::read(root.join("overlay/a.txt")).unwrap(), b"");
+
+        c.ok(TUNLINKAT, |m| {
+            m.u32(0);
+            m.bytes(b"a.txt");
+            m.u32(0);
+        });
+        assert!(!root.join("overlay/a.txt").exists());
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn times_out_of_range_are_refused() {
+        let (root, _server, mut c) = serve("times");
+        c.walk(0, 1, &["a.txt"]);
+        let setattr = |secs: u64, nsecs: u64| {
+            move |m: &mut Encoder| {
+                m.u32(1);
+                m.u32(SETATTR_MTIME | SETATTR_MTIME_SET);
+                m.u32(0);
+                m.u32(0);
+                m.u32(0);
+                m.u64(0);
+                m.u64(0);
+                m.u64(0);
+                m.u64(secs);
+                m.u64(nsecs);
+            }
+        };
+        assert_eq!(c.errno(TSETATTR, setattr(0, 1_000_000_000)), EINVAL);
+        assert_eq!(c.errno(TSETATTR, setattr(u64::MAX, 0)), EINVAL);
+        c.ok(TSETATTR, setattr(1_000_000, 999_999_999));
+        let reply = c.ok(TGETATTR, |m| {
+            m.u32(1);
+            m.u64(GETATTR_ALL);
+        });
+        // After valid, qid, mode, uid, gid, nlink, rdev, size, blksize,
+        // blocks and atime.
+        let mut attr = Decoder(&reply[89..]);
+        assert_eq!(attr.time().unwrap(), UNIX_EPOCH + Duration::new(1_000_000, 999_999_999));
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
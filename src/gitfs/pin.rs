@@ -0,0 +1,132 @@
+/// Keeping the files given by `Options::pinned_paths` at hand, for
+/// those that had better never wait on the repository, such as build
+/// scripts and lockfiles.  When a commit is mounted, the dirs leading
+/// to them are listed, and their blobs are read and pinned in the blob
+/// cache, where they stay until another commit is mounted or the file
+/// system is unmounted.
+///
+/// Blobs are pinned as stored: with `Options::smudge`, converted
+/// contents are cached as usual.  Hidden paths are left out.
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError};
+
+use git2::{ObjectType, Oid, TreeWalkMode, TreeWalkResult};
+
+use super::{hidden, GitFS};
+use crate::error::Error;
+use crate::options::Options;
+use crate::{glob, Ino};
+
+/// What to list and pin in a tree.
+#[derive(Default)]
+struct Pinned {
+    /// Dirs, each after its parent.
+    dirs: BTreeSet<PathBuf>,
+    blobs: Vec<(PathBuf, Oid)>,
+}
+
+impl GitFS {
+    /// Pin what `Options::pinned_paths` matches in `tree`, just
+    /// mounted at the root, and unpin what was pinned before.  Called
+    /// with the head locked.  What can't be pinned is logged and left
+    /// out, as it can still be read.
+    pub(super) fn pin(&self, tree: Oid) {
+        let options = self.options_read().clone();
+        let mut pinned = vec![];
+        if !options.pinned_paths.is_empty() {
+            let entries = self.pinned_entries(tree, &options).unwrap_or_else(|e| {
+                warn!(%e, "cannot find the paths to pin");
+                Pinned::default()
+            });
+            let listed = self.list_pinned_dirs(&entries.dirs);
+            let mut bytes = 0;
+            for (path, oid) in entries.blobs {
+                let content: Arc<[u8]> = match self.repo().find_blob(oid) {
+                    Ok(blob) => blob.content().into(),
+                    Err(e) => {
+                        warn!(?path, %e, "cannot pin");
+                        continue;
+                    }
+                };
+                bytes += content.len();
+                self.blob_cache().pin(oid, content);
+                pinned.push(oid);
+            }
+            info!(dirs = listed, blobs = pinned.len(), bytes, "pinned paths");
+        }
+        // Blobs still pinned are only unpinned once pinned again.
+        let old = std::mem::replace(&mut *self.inner.pinned.lock().unwrap_or_else(PoisonError::into_inner), pinned);
+        let mut cache = self.blob_cache();
+        for oid in old {
+            cache.unpin(oid);
+        }
+    }
+
+    /// Unpin everything this mount has pinned.
+    pub(super) fn unpin(&self) {
+        let old = std::mem::take(&mut *self.inner.pinned.lock().unwrap_or_else(PoisonError::into_inner));
+        let mut cache = self.blob_cache();
+        for oid in old {
+            cache.unpin(oid);
+        }
+    }
+
+    /// The dirs to list, with the dirs leading to them, and the blobs
+    /// to pin, in `tree`.
+    fn pinned_entries(&self, tree: Oid, options: &Options) -> Result<Pinned, Error> {
+        let repo = self.repo();
+        let tree = repo.find_tree(tree)?;
+        let mut dirs = BTreeSet::new();
+        let mut blobs = vec![];
+        tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            let path = Path::new(dir).join(OsStr::from_bytes(entry.name_bytes()));
+            if hidden(options, &path, entry.kind() == Some(ObjectType::Tree)) {
+                return TreeWalkResult::Skip;
+            }
+            if !options.pinned_paths.iter().any(|pattern| glob::matches(pattern, &path)) {
+                return TreeWalkResult::Ok;
+            }
+            dirs.extend(path.parent().into_iter().flat_map(Path::ancestors).map(Path::to_owned));
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    dirs.insert(path);
+                }
+                Some(ObjectType::Blob) => blobs.push((path, entry.id())),
+                _ => {}
+            }
+            TreeWalkResult::Ok
+        })?;
+        Ok(Pinned { dirs, blobs })
+    }
+
+    /// List `dirs`, and return how many could be.
+    fn list_pinned_dirs(&self, dirs: &BTreeSet<PathBuf>) -> usize {
+        let mut inos = HashMap::new();
+        for dir in dirs {
+            let ino = match (dir.parent(), dir.file_name()) {
+                (Some(parent), Some(name)) => {
+                    let parent = match inos.get(parent) {
+                        Some(&parent) => parent,
+                        None => continue,
+                    };
+                    match self.inomap().get(parent).and_then(|entry| entry.get_child(name)) {
+                        Some(ino) => ino,
+                        None => continue,
+                    }
+                }
+                _ => Ino::ROOT,
+            };
+            match self.do_opendir(ino) {
+                Ok(()) => {
+                    inos.insert(dir.as_path(), ino);
+                }
+                // e.g. shadowed by a dirty file.
+                Err(e) => debug!(?dir, %e, "cannot list a pinned dir"),
+            }
+        }
+        inos.len()
+    }
+}
@@ -0,0 +1,61 @@
+/// The quota of the underlying dir (`Options::overlay_quota`).
+///
+/// Walking the underlying dir on every write would be too slow, so its
+/// usage is measured at most once per `RESCAN`, and estimated from
+/// what is written in between.  Files only count with their size, as
+/// in `du --apparent-size`.
+use std::path::Path;
+use std::sync::{MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use libc::EDQUOT;
+
+use super::GitFS;
+use crate::error::Error;
+
+/// How long a measured usage is trusted.
+const RESCAN: Duration = Duration::from_secs(1);
+
+/// When the usage of the underlying dir was measured, and how many
+/// bytes it is estimated at now.
+#[derive(Debug)]
+pub(super) struct Usage {
+    measured: Instant,
+    bytes: u64,
+}
+
+impl GitFS {
+    /// Make sure `more` bytes (none for a new entry) fit within the
+    /// quota, and count them if so.
+    pub(super) fn check_quota(&self, more: u64) -> Result<(), Error> {
+        let quota = match self.options_read().overlay_quota {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+        let fresh = self
+            .overlay_usage()
+            .as_ref()
+            .is_some_and(|usage| usage.measured.elapsed() < RESCAN);
+        if !fresh {
+            // Measured without the lock, which is only held briefly.
+            let measured = Instant::now();
+            let mut files = vec![];
+            self.list_overlay_files(Path::new(""), &mut files);
+            let bytes = files.iter().map(|(_, _, size, _)| size).sum();
+            *self.overlay_usage() = Some(Usage { measured, bytes });
+        }
+
+        let mut usage = self.overlay_usage();
+        let usage = usage.as_mut().expect("usage was just measured");
+        if usage.bytes >= quota || more > quota - usage.bytes {
+            debug!(quota, usage = usage.bytes, more, "over the quota");
+            return Err(Error::Errno(EDQUOT));
+        }
+        usage.bytes += more;
+        Ok(())
+    }
+
+    fn overlay_usage(&self) -> MutexGuard<'_, Option<Usage>> {
+        self.inner.overlay_usage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
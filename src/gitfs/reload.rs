@@ -0,0 +1,118 @@
+/// Reloading the config file of a mount (see `config`), on SIGHUP or
+/// `reload` in `ctl`, without unmounting.
+///
+/// The settings of the file are applied over the options the file
+/// system was built with, so that a setting removed from the file goes
+/// back to what it was.  They take effect on the next operation, but
+/// what the kernel cached, for as long as the TTLs it was given.
+use std::io;
+use std::path::PathBuf;
+use std::sync::PoisonError;
+
+use libc::ENOENT;
+
+use super::GitFS;
+use crate::config::Config;
+use crate::error::Error;
+use crate::options::Options;
+
+/// Changes what is logged (see `GitFSBuilder::log_filter`).
+pub(super) type LogFilter = Box<dyn Fn(Option<&str>) -> Result<(), String> + Send + Sync>;
+
+pub(super) struct ConfigFile {
+    pub(super) path: PathBuf,
+    /// The options the file system was built with.
+    pub(super) base: Options,
+}
+
+impl GitFS {
+    /// Read the config file again and apply it (ENOENT if there is
+    /// none), then pin the pinned paths anew if they changed.  Nothing
+    /// changes if the file can't be read, or its `log` setting can't
+    /// be applied.
+    pub fn reload_config(&self) -> Result<(), Error> {
+        let file = self.inner.config_file.as_ref().ok_or(Error::Errno(ENOENT))?;
+        let config = Config::read(&file.path).map_err(|e| {
+            warn!(%e, "cannot reload the config file");
+            Error::Io(e)
+        })?;
+        if let Some(log_filter) = &self.inner.log_filter {
+            log_filter(config.log.as_deref()).map_err(|e| {
+                warn!(%e, "cannot change what is logged");
+                Error::Io(io::Error::new(io::ErrorKind::InvalidData, e))
+            })?;
+        }
+        let mut options = file.base.clone();
+        config.apply(&mut options);
+        let repin = {
+            let mut current = self.inner.options.write().unwrap_or_else(PoisonError::into_inner);
+            let repin = current.pinned_paths != options.pinned_paths;
+            *current = options;
+            repin
+        };
+        if repin {
+            let head = self.head();
+            if let Some(commit) = head.commit {
+                let (_, tree) = self.resolve(&commit.to_string())?;
+                self.pin(tree);
+            }
+        }
+        info!(path = ?file.path, "reloaded the config file");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{generate_at, Spec};
+    use openat::Dir;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn reload_applies_the_config_file_over_the_options() {
+        let base = std::env::temp_dir().join(format!("gitfs-reload-{}", std::process::id()));
+        let repo = generate_at(&base.join("repo"), &Spec::default()).unwrap();
+        std::fs::create_dir_all(base.join("overlay")).unwrap();
+        let config = base.join("gitfs.conf");
+        let logged = Arc::new(Mutex::new(vec![]));
+        let fs = {
+            let logged = logged.clone();
+            let options = Options { read_rate: Some(100), ..Options::default() };
+            GitFS::builder(repo, Dir::open(&base.join("overlay")).unwrap())
+                .options(options)
+                .config_file(&config)
+                .log_filter(move |filter| {
+                    if filter == Some("nonsense") {
+                        return Err("invalid filter".to_owned());
+                    }
+                    logged.lock().unwrap().push(filter.map(str::to_owned));
+                    Ok(())
+                })
+                .build()
+        };
+        fs.mount_root().unwrap();
+        assert_eq!(fs.errno(&fs.reload_config().unwrap_err()), ENOENT);
+
+        std::fs::write(&config, "ttl = 3\npin = **\nlog = debug\n").unwrap();
+        fs.reload_config().unwrap();
+        assert_eq!(fs.options_read().attr_ttl, Duration::from_secs(3));
+        assert_eq!(fs.options_read().read_rate, Some(100));
+        assert!(fs.blob_cache().pinned_size() > 0);
+
+        // Nothing changes if the file is wrong.
+        for wrong in ["ttl = soon\n", "log = nonsense\n"] {
+            std::fs::write(&config, wrong).unwrap();
+            assert!(fs.reload_config().is_err());
+            assert_eq!(fs.options_read().attr_ttl, Duration::from_secs(3));
+        }
+
+        std::fs::write(&config, "# nothing\n").unwrap();
+        fs.reload_config().unwrap();
+        assert_eq!(fs.options_read().attr_ttl, Duration::from_secs(1));
+        assert_eq!(fs.blob_cache().pinned_size(), 0);
+        assert_eq!(*logged.lock().unwrap(), [Some("debug".to_owned()), None]);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+/// Checking that what a mount holds is consistent (`--selfcheck`, and
+/// `selfcheck` in `ctl`), so that corruption of the ino map is caught
+/// and reported as such, rather than later as EIO or ENOENT on some
+/// unrelated path.
+use fuser::FileType;
+
+use super::GitFS;
+use crate::{EntryKind, Ino};
+
+impl GitFS {
+    /// List every dir of the mount, as walking it would, and return
+    /// those that can't be listed, with why.
+    pub fn list_all(&self) -> Vec<String> {
+        if let Err(e) = self.mount_root() {
+            return vec![format!("cannot mount: {}", e)];
+        }
+        let mut problems = vec![];
+        let mut dirs = vec![Ino::ROOT];
+        while let Some(dir) = dirs.pop() {
+            if let Err(e) = self.do_opendir(dir) {
+                problems.push(format!("cannot list /{}: {}", self.path_of(dir).display(), e));
+                continue;
+            }
+            let inomap = self.inomap();
+            let children = match inomap.get(dir).map(|entry| &entry.u) {
+                Some(EntryKind::GitTree { children: Some(children), .. }) | Some(EntryKind::DirtyDir { children: Some(children) }) => children,
+                _ => continue,
+            };
+            dirs.extend(
+                children
+                    .values()
+                    .filter(|&&child| inomap.get(child).map(FileType::from) == Some(FileType::Directory)),
+            );
+        }
+        problems
+    }
+
+    /// The inconsistencies in what the mount holds, each logged as a
+    /// warning: those of the ino map (see `InoMap::check`), then open
+    /// handles of inos never handed out or of dirs, and handles of an
+    /// underlying file whose entry is clean.
+    pub fn self_check(&self) -> Vec<String> {
+        let inomap = self.inomap();
+        let mut problems = inomap.check();
+        for (fh, handle) in self.handles().iter() {
+            if handle.ino >= inomap.next_ino {
+                problems.push(format!("fh {} is of ino {}, which was never handed out", fh, handle.ino.0));
+            }
+            // Entries removed since the handle was opened are fine:
+            // unlinked files can still be used.
+            let entry = match inomap.get(handle.ino) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if FileType::from(entry) == FileType::Directory {
+                problems.push(format!("fh {} is of ino {}, a dir", fh, handle.ino.0));
+            } else if handle.file.is_some() && matches!(entry.u, EntryKind::GitBlob { .. }) {
+                problems.push(format!("fh {} has an underlying file, but ino {} is clean", fh, handle.ino.0));
+            }
+        }
+        for problem in &problems {
+            warn!(%problem, "inconsistent state");
+        }
+        problems
+    }
+}
@@ -0,0 +1,212 @@
+/// What the front-ends other than FUSE (`--serve`) share: the accept
+/// loop of the network ones, handling of open files, and resolving
+/// paths for those that name files by path.
+///
+/// Connections are accepted by a thread polling the listener, and each
+/// is served by a thread of its own.  Stopping the server shuts down
+/// the connections still open, and waits for their threads.
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::TryRecvError;
+use std::thread::{self, JoinHandle};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use fuser::FileType;
+use libc::{EINVAL, ELOOP, ENOTDIR};
+
+use super::{control, GitFS, SetAttr};
+use crate::error::Error;
+use crate::systemd;
+use crate::watch::Watcher;
+use crate::{Ino, MODE_FILE, MODE_SYMLINK};
+
+/// Symlinks followed while resolving a path, as in Linux.
+const MAX_SYMLINKS: usize = 40;
+
+/// Serve every connection made to `addr` with `serve`, until the
+/// returned watcher is dropped.  Also return the address listened on.
+/// `protocol` names the threads and the log lines.
+///
+/// A socket passed by systemd is listened on instead of `addr`.
+pub(super) fn listen<F>(protocol: &'static str, addr: SocketAddr, serve: F) -> io::Result<(Watcher, SocketAddr)>
+where
+    F: Fn(&TcpStream) -> io::Result<()> + Clone + Send + 'static,
+{
+    let listener = match systemd::take_listener()? {
+        Some(listener) => listener,
+        None => TcpListener::bind(addr)?,
+    };
+    let addr = listener.local_addr()?;
+    // Polled, so that the server notices when it's stopped.
+    listener.set_nonblocking(true)?;
+    let watcher = Watcher::run(&format!("gitfs-{}", protocol), move |stopped| {
+        let mut connections: Vec<(TcpStream, JoinHandle<()>)> = vec![];
+        while let Err(TryRecvError::Empty) = stopped.try_recv() {
+            match listener.accept() {
+                Ok((stream, peer)) => match spawn(protocol, stream, peer, serve.clone()) {
+                    Ok(connection) => connections.push(connection),
+                    Err(e) => warn!(protocol, %peer, %e, "cannot serve a connection"),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    connections.retain(|(_, thread)| !thread.is_finished());
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => {
+                    error!(protocol, %e, "cannot accept connections");
+                    break;
+                }
+            }
+        }
+        for (stream, thread) in connections {
+            let _ = stream.shutdown(Shutdown::Both);
+            let _ = thread.join();
+        }
+    })?;
+    info!(protocol, %addr, "serving");
+    Ok((watcher, addr))
+}
+
+/// Serve a connection in a thread of its own.  Also return a clone of
+/// the stream, by which the connection can be shut down.
+fn spawn<F>(protocol: &'static str, stream: TcpStream, peer: SocketAddr, serve: F) -> io::Result<(TcpStream, JoinHandle<()>)>
+where
+    F: FnOnce(&TcpStream) -> io::Result<()> + Send + 'static,
+{
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    let shutdown = stream.try_clone()?;
+    let thread = thread::Builder::new().name(format!("gitfs-{}-conn", protocol)).spawn(move || {
+        debug!(protocol, %peer, "connection");
+        match serve(&stream) {
+            Ok(()) => debug!(protocol, %peer, "connection closed"),
+            Err(e) => debug!(protocol, %peer, %e, "connection failed"),
+        }
+        // The clone kept for stopping would hold it open meanwhile.
+        let _ = stream.shutdown(Shutdown::Both);
+    })?;
+    Ok((shutdown, thread))
+}
+
+impl GitFS {
+    /// Release a handle opened with `do_open` or `do_create`.
+    pub(super) fn release_handle(&self, ino: Ino, fh: u64) {
+        if control::owns(ino) {
+            self.control_release(fh);
+        } else {
+            // Not in the condition, which would hold the handles
+            // locked while tidying takes them again.
+            let removed = self.handles().remove(fh);
+            if let Some(handle) = removed {
+                self.tidy_on_release(&handle);
+            }
+        }
+    }
+
+    /// Empty a file opened for writing, for `O_TRUNC`, which `do_open`
+    /// drops: the kernel follows a FUSE open with a setattr of size 0,
+    /// and this is that setattr for the other protocols.
+    pub(super) fn truncate_handle(&self, ino: Ino, fh: u64) -> Result<(), Error> {
+        self.handle_file(ino, fh)?;
+        let truncate = SetAttr {
+            mode: None,
+            uid: None,
+            gid: None,
+            size: Some(0),
+            atime: None,
+            mtime: None,
+            crtime: None,
+            flags: None,
+        };
+        self.do_setattr(ino, truncate).map(drop)
+    }
+
+    /// Find the entry at `path`, from the root, following symlinks on
+    /// the way, and at the end with `follow`.  `..` is taken after the
+    /// symlinks before it are followed, as a kernel does.
+    pub(super) fn resolve_path(&self, path: &Path, follow: bool) -> Result<Ino, Error> {
+        let mut todo: Vec<OsString> = names(path).into_iter().rev().collect();
+        // The dirs walked through, for `..`.
+        let mut dirs = vec![Ino::ROOT];
+        let mut links = 0;
+        while let Some(name) = todo.pop() {
+            let dir = *dirs.last().unwrap_or(&Ino::ROOT);
+            if name == ".." {
+                if dirs.len() > 1 {
+                    dirs.pop();
+                }
+                continue;
+            }
+            let attr = self.do_lookup(dir, &name)?;
+            if attr.kind == FileType::Symlink && (follow || !todo.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(Error::Errno(ELOOP));
+                }
+                let target = self.do_readlink(attr.ino.into())?;
+                let target = Path::new(OsStr::from_bytes(&target));
+                if target.has_root() {
+                    dirs.truncate(1);
+                }
+                todo.extend(names(target).into_iter().rev());
+                continue;
+            }
+            if !todo.is_empty() && attr.kind != FileType::Directory {
+                return Err(Error::Errno(ENOTDIR));
+            }
+            dirs.push(attr.ino.into());
+        }
+        Ok(*dirs.last().unwrap_or(&Ino::ROOT))
+    }
+
+    /// Find the dir of `path` and the name in it, for creating,
+    /// removing or renaming the entry at `path`.
+    pub(super) fn resolve_parent(&self, path: &Path) -> Result<(Ino, OsString), Error> {
+        let path = normalize(path);
+        let name = path.file_name().ok_or(Error::Errno(EINVAL))?.to_owned();
+        let parent = self.resolve_path(path.parent().unwrap_or_else(|| Path::new("/")), true)?;
+        self.do_opendir(parent)?;
+        Ok((parent, name))
+    }
+}
+
+/// The type bits of a mode, as Linux (and POSIX) has them.
+pub(super) fn mode_bits(kind: FileType) -> u32 {
+    match kind {
+        FileType::NamedPipe => 0o010000,
+        FileType::CharDevice => 0o020000,
+        FileType::Directory => 0o040000,
+        FileType::BlockDevice => 0o060000,
+        FileType::RegularFile => MODE_FILE,
+        FileType::Symlink => MODE_SYMLINK,
+        FileType::Socket => 0o140000,
+    }
+}
+
+/// The names in a path, without `.`.  The root is left out, as every
+/// path is resolved from it.
+fn names(path: &Path) -> Vec<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_owned()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `path` made absolute, without `.` and `..`, without looking at
+/// symlinks.
+pub(super) fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::from("/");
+    for name in names(path) {
+        if name == ".." {
+            normal.pop();
+        } else {
+            normal.push(name);
+        }
+    }
+    normal
+}
@@ -0,0 +1,850 @@
+/// An SFTP (version 3) server over stdin and stdout (`--serve sftp`),
+/// for sshfs, WinSCP and the remote modes of IDEs to browse and edit
+/// the tree where FUSE isn't available.
+///
+/// SSH itself is left to sshd, which runs the server as a subsystem,
+/// as it runs `sftp-server`:
+///
+/// ```text
+/// Subsystem sftp /usr/local/bin/git-mount --serve sftp /srv/repo /srv/overlay
+/// ```
+///
+/// or for sshfs alone, `-o sftp_server="git-mount --serve sftp REPO DIR"`.
+/// Paths are resolved from the root of the tree, which is also the
+/// home dir, following symlinks as a kernel would.  Changes are
+/// audited with the uid of the server, which is the user logged in,
+/// and its pid, which tells the session.
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType, TimeOrNow};
+use libc::{
+    EACCES, EEXIST, EISDIR, ENOENT, ENOSYS, ENOTDIR, EOPNOTSUPP, EPERM, EROFS, O_RDONLY, O_RDWR,
+    O_WRONLY,
+};
+
+use super::server::{mode_bits, normalize};
+use super::{statvfs, GitFS, SetAttr};
+use crate::error::Error;
+use crate::{Ino, MODE_FILE};
+
+/// The longest packet accepted; clients keep to 34000 bytes or so.
+const MAX_PACKET: u32 = 1 << 20;
+/// Entries given out per READDIR.
+const READDIR_BATCH: usize = 100;
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_FSTAT: u8 = 8;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_FSETSTAT: u8 = 10;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_RMDIR: u8 = 15;
+const SSH_FXP_REALPATH: u8 = 16;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_READLINK: u8 = 19;
+const SSH_FXP_SYMLINK: u8 = 20;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+const SSH_FXP_EXTENDED: u8 = 200;
+const SSH_FXP_EXTENDED_REPLY: u8 = 201;
+
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+const SSH_FX_FAILURE: u32 = 4;
+const SSH_FX_BAD_MESSAGE: u32 = 5;
+const SSH_FX_OP_UNSUPPORTED: u32 = 8;
+
+const SSH_FXF_READ: u32 = 0x1;
+const SSH_FXF_WRITE: u32 = 0x2;
+const SSH_FXF_APPEND: u32 = 0x4;
+const SSH_FXF_CREAT: u32 = 0x8;
+const SSH_FXF_TRUNC: u32 = 0x10;
+const SSH_FXF_EXCL: u32 = 0x20;
+
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_UIDGID: u32 = 0x2;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+/// Extensions of OpenSSH that are supported, with their versions.
+const EXTENSIONS: &[(&str, &str)] = &[("posix-rename@openssh.com", "1"), ("statvfs@openssh.com", "2")];
+
+/// Serve SFTP on `input` and `output` until the client hangs up.
+pub(super) fn serve<R: Read, W: Write>(fs: GitFS, mut input: R, mut output: W) -> Result<(), Error> {
+    fs.mount_root()?;
+    let mut session = Session {
+        fs,
+        handles: HashMap::new(),
+        next_handle: 0,
+    };
+    loop {
+        let mut len = [0; 4];
+        match input.read_exact(&mut len) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let len = u32::from_be_bytes(len);
+        if len == 0 || len > MAX_PACKET {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad packet length {}", len)).into());
+        }
+        let mut packet = vec![0; len as usize];
+        input.read_exact(&mut packet)?;
+        let reply = session.respond(&packet);
+        output.write_all(&reply)?;
+        output.flush()?;
+    }
+}
+
+struct Session {
+    fs: GitFS,
+    handles: HashMap<u32, Open>,
+    next_handle: u32,
+}
+
+/// What a handle has open.
+enum Open {
+    File { ino: Ino, fh: u64, append: bool },
+    /// The listing of a dir, with how much of it was read.
+    Dir { dir: PathBuf, listing: Vec<(OsString, Ino)>, read: usize },
+}
+
+/// Why a request failed: an error of the file system, or a request
+/// that is malformed or not supported.
+enum Failure {
+    Fs(Error),
+    Status(u32, &'static str),
+}
+
+impl<E: Into<Error>> From<E> for Failure {
+    fn from(e: E) -> Failure {
+        Failure::Fs(e.into())
+    }
+}
+
+type Reply = Result<(), Failure>;
+
+impl Session {
+    /// Answer a packet, given without its length.
+    fn respond(&mut self, packet: &[u8]) -> Vec<u8> {
+        let ty = packet[0];
+        let mut m = Decoder(&packet[1..]);
+        if ty == SSH_FXP_INIT {
+            let mut r = Encoder::new(SSH_FXP_VERSION);
+            r.u32(3);
+            for (name, version) in EXTENSIONS {
+                r.string(name.as_bytes());
+                r.string(version.as_bytes());
+            }
+            return r.finish();
+        }
+        let id = match m.u32() {
+            Ok(id) => id,
+            Err(_) => return status(0, SSH_FX_BAD_MESSAGE, "bad message"),
+        };
+        let op = op_name(ty);
+        let fs = self.fs.clone();
+        let _timer = fs.start_op(op, Ino::ROOT);
+        let span = debug_span!("sftp", op, id, errno = tracing::field::Empty).entered();
+        fs.refresh_if_requested();
+        let mut r = Encoder::new(0);
+        r.u32(id);
+        match self.dispatch(ty, &mut m, &mut r) {
+            Ok(()) => r.finish(),
+            Err(Failure::Fs(e)) => {
+                let errno = fs.errno(&e);
+                span.record("errno", errno);
+                let (code, message) = match errno {
+                    ENOENT => (SSH_FX_NO_SUCH_FILE, "No such file".to_owned()),
+                    EACCES | EPERM | EROFS => (SSH_FX_PERMISSION_DENIED, "Permission denied".to_owned()),
+                    ENOSYS | EOPNOTSUPP => (SSH_FX_OP_UNSUPPORTED, "Operation unsupported".to_owned()),
+                    errno => (SSH_FX_FAILURE, io::Error::from_raw_os_error(errno).to_string()),
+                };
+                status(id, code, &message)
+            }
+            Err(Failure::Status(code, message)) => status(id, code, message),
+        }
+    }
+
+    /// Answer a request in `r`, which already holds its id.
+    fn dispatch(&mut self, ty: u8, m: &mut Decoder<'_>, r: &mut Encoder) -> Reply {
+        match ty {
+            SSH_FXP_OPEN => self.open(m, r),
+            SSH_FXP_CLOSE => {
+                let handle = m.handle()?;
+                match self.handles.remove(&handle) {
+                    Some(Open::File { ino, fh, .. }) => self.fs.release_handle(ino, fh),
+                    Some(Open::Dir { .. }) => (),
+                    None => return Err(Error::Errno(libc::EBADF).into()),
+                }
+                r.ok()
+            }
+            SSH_FXP_READ => self.read(m, r),
+            SSH_FXP_WRITE => self.write(m, r),
+            SSH_FXP_LSTAT | SSH_FXP_STAT => {
+                let path = m.path()?;
+                let ino = self.fs.resolve_path(&path, ty == SSH_FXP_STAT)?;
+                r.set_type(SSH_FXP_ATTRS);
+                r.attrs(&self.fs.do_getattr(ino)?);
+                Ok(())
+            }
+            SSH_FXP_FSTAT => {
+                let ino = match self.handles.get(&m.handle()?) {
+                    Some(Open::File { ino, .. }) => *ino,
+                    Some(Open::Dir { dir, .. }) => self.fs.resolve_path(dir, true)?,
+                    None => return Err(Error::Errno(libc::EBADF).into()),
+                };
+                r.set_type(SSH_FXP_ATTRS);
+                r.attrs(&self.fs.do_getattr(ino)?);
+                Ok(())
+            }
+            SSH_FXP_SETSTAT => {
+                let path = m.path()?;
+                let ino = self.fs.resolve_path(&path, true)?;
+                self.setstat(ino, m)?;
+                r.ok()
+            }
+            SSH_FXP_FSETSTAT => {
+                let ino = match self.handles.get(&m.handle()?) {
+                    Some(Open::File { ino, .. }) => *ino,
+                    _ => return Err(Error::Errno(libc::EBADF).into()),
+                };
+                self.setstat(ino, m)?;
+                r.ok()
+            }
+            SSH_FXP_OPENDIR => {
+                let dir = normalize(&m.path()?);
+                let ino = self.fs.resolve_path(&dir, true)?;
+                self.fs.do_opendir(ino)?;
+                let mut listing = vec![(OsString::from("."), ino), (OsString::from(".."), self.fs.resolve_path(&dir.join(".."), true)?)];
+                listing.extend(self.fs.do_readdir(ino)?.into_iter().map(|(name, child, _)| (name, child)));
+                let handle = self.add_handle(Open::Dir { dir, listing, read: 0 });
+                r.set_type(SSH_FXP_HANDLE);
+                r.string(&handle.to_be_bytes());
+                Ok(())
+            }
+            SSH_FXP_READDIR => self.readdir(m, r),
+            SSH_FXP_REMOVE | SSH_FXP_RMDIR => {
+                let path = m.path()?;
+                let (parent, name) = self.fs.resolve_parent(&path)?;
+                let is_dir = self.fs.do_lookup(parent, &name)?.kind == FileType::Directory;
+                match (ty, is_dir) {
+                    (SSH_FXP_REMOVE, true) => return Err(Error::Errno(EISDIR).into()),
+                    (SSH_FXP_RMDIR, false) => return Err(Error::Errno(ENOTDIR).into()),
+                    _ => (),
+                }
+                self.fs.do_remove(parent, &name)?;
+                self.audit(if is_dir { "rmdir" } else { "unlink" }, || format!("{:?}", self.fs.child_path(parent.into(), &name)));
+                r.ok()
+            }
+            SSH_FXP_MKDIR => {
+                let path = m.path()?;
+                let attrs = m.attrs()?;
+                let (parent, name) = self.fs.resolve_parent(&path)?;
+                self.fs.do_mkdir(parent, &name, attrs.permissions.map_or(0o755, |mode| mode & 0o7777))?;
+                self.audit("mkdir", || format!("{:?}", self.fs.child_path(parent.into(), &name)));
+                r.ok()
+            }
+            SSH_FXP_REALPATH => {
+                let path = normalize(&m.path()?);
+                r.set_type(SSH_FXP_NAME);
+                r.u32(1);
+                r.string(path.as_os_str().as_bytes());
+                r.string(path.as_os_str().as_bytes());
+                r.u32(0);
+                Ok(())
+            }
+            SSH_FXP_RENAME => {
+                let (from, to) = (m.path()?, m.path()?);
+                // Unlike rename(2), RENAME doesn't replace.
+                if self.fs.resolve_path(&to, false).is_ok() {
+                    return Err(Error::Errno(EEXIST).into());
+                }
+                self.rename(&from, &to)?;
+                r.ok()
+            }
+            SSH_FXP_READLINK => {
+                let path = m.path()?;
+                let target = self.fs.do_readlink(self.fs.resolve_path(&path, false)?)?;
+                r.set_type(SSH_FXP_NAME);
+                r.u32(1);
+                r.string(&target);
+                r.string(&target);
+                r.u32(0);
+                Ok(())
+            }
+            SSH_FXP_SYMLINK => Err(Error::Errno(EPERM).into()),
+            SSH_FXP_EXTENDED => self.extended(m, r),
+            _ => Err(Failure::Status(SSH_FX_OP_UNSUPPORTED, "Operation unsupported")),
+        }
+    }
+
+    fn add_handle(&mut self, open: Open) -> u32 {
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.handles.insert(handle, open);
+        handle
+    }
+
+    fn open(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Reply {
+        let path = m.path()?;
+        let pflags = m.u32()?;
+        let attrs = m.attrs()?;
+        let (ino, fh) = match self.fs.resolve_path(&path, true) {
+            Ok(_) if pflags & SSH_FXF_CREAT != 0 && pflags & SSH_FXF_EXCL != 0 => return Err(Error::Errno(EEXIST).into()),
+            Ok(ino) => {
+                let flags = match (pflags & SSH_FXF_READ != 0, pflags & SSH_FXF_WRITE != 0) {
+                    (_, false) => O_RDONLY,
+                    (false, true) => O_WRONLY,
+                    (true, true) => O_RDWR,
+                };
+                let fh = self.fs.do_open(ino, flags)?;
+                if pflags & SSH_FXF_TRUNC != 0 && flags != O_RDONLY {
+                    if let Err(e) = self.fs.truncate_handle(ino, fh) {
+                        self.fs.release_handle(ino, fh);
+                        return Err(e.into());
+                    }
+                    self.audit("truncate", || format!("{:?} size=0", self.fs.path_of(ino)));
+                }
+                (ino, fh)
+            }
+            Err(Error::Errno(ENOENT)) if pflags & SSH_FXF_CREAT != 0 => {
+                let (parent, name) = self.fs.resolve_parent(&path)?;
+                let mode = MODE_FILE | attrs.permissions.map_or(0o644, |mode| mode & 0o7777);
+                let (attr, fh) = self.fs.do_create(parent, &name, mode)?;
+                self.audit("create", || format!("{:?}", self.fs.child_path(parent.into(), &name)));
+                (attr.ino.into(), fh)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let append = pflags & SSH_FXF_APPEND != 0;
+        let handle = self.add_handle(Open::File { ino, fh, append });
+        r.set_type(SSH_FXP_HANDLE);
+        r.string(&handle.to_be_bytes());
+        Ok(())
+    }
+
+    fn read(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Reply {
+        let handle = m.handle()?;
+        let (offset, len) = (m.u64()?, m.u32()?.min(MAX_PACKET - 64));
+        let (ino, fh) = match self.handles.get(&handle) {
+            Some(Open::File { ino, fh, .. }) => (*ino, *fh),
+            Some(Open::Dir { .. }) => return Err(Error::Errno(EISDIR).into()),
+            None => return Err(Error::Errno(libc::EBADF).into()),
+        };
+        let data = self.fs.do_read(ino, fh, offset, len)?;
+        if data.is_empty() && len > 0 {
+            return Err(Failure::Status(SSH_FX_EOF, "End of file"));
+        }
+        r.set_type(SSH_FXP_DATA);
+        r.string(&data);
+        Ok(())
+    }
+
+    fn write(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Reply {
+        let handle = m.handle()?;
+        let offset = m.u64()?;
+        let data = m.string()?;
+        let (ino, fh, append) = match self.handles.get(&handle) {
+            Some(Open::File { ino, fh, append }) => (*ino, *fh, *append),
+            _ => return Err(Error::Errno(libc::EBADF).into()),
+        };
+        let offset = if append { self.fs.do_getattr(ino)?.size } else { offset };
+        // There is no short write in SFTP.
+        let mut written = 0;
+        while written < data.len() {
+            written += self.fs.do_write(ino, fh, offset + written as u64, &data[written..])? as usize;
+        }
+        self.audit("write", || format!("{:?} offset={} size={}", self.fs.path_of(ino), offset, written));
+        r.ok()
+    }
+
+    fn readdir(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Reply {
+        let handle = m.handle()?;
+        let fs = &self.fs;
+        let (listing, read) = match self.handles.get_mut(&handle) {
+            Some(Open::Dir { listing, read, .. }) => (listing, read),
+            _ => return Err(Error::Errno(libc::EBADF).into()),
+        };
+        if *read == listing.len() {
+            return Err(Failure::Status(SSH_FX_EOF, "End of directory"));
+        }
+        let batch = &listing[*read..listing.len().min(*read + READDIR_BATCH)];
+        *read += batch.len();
+        // Entries may be gone since the dir was opened.
+        let entries: Vec<_> = batch
+            .iter()
+            .filter_map(|(name, child)| Some((name, fs.do_getattr(*child).ok()?)))
+            .collect();
+        r.set_type(SSH_FXP_NAME);
+        r.u32(entries.len() as u32);
+        for (name, attr) in entries {
+            r.string(name.as_bytes());
+            r.string(long_name(name, &attr).as_bytes());
+            r.attrs(&attr);
+        }
+        Ok(())
+    }
+
+    fn setstat(&self, ino: Ino, m: &mut Decoder<'_>) -> Result<(), Failure> {
+        let attrs = m.attrs()?;
+        let time = |secs: u32| Some(TimeOrNow::SpecificTime(UNIX_EPOCH + Duration::from_secs(secs.into())));
+        // The type of the entry is kept whatever the client sends.
+        let kind = self.fs.do_getattr(ino)?.kind;
+        let setattr = SetAttr {
+            mode: attrs.permissions.map(|mode| mode_bits(kind) | (mode & 0o7777)),
+            uid: attrs.uid,
+            gid: attrs.gid,
+            size: attrs.size,
+            atime: attrs.times.and_then(|(atime, _)| time(atime)),
+            mtime: attrs.times.and_then(|(_, mtime)| time(mtime)),
+            crtime: None,
+            flags: None,
+        };
+        self.fs.do_setattr(ino, setattr)?;
+        if let Some(size) = attrs.size {
+            self.audit("truncate", || format!("{:?} size={}", self.fs.path_of(ino), size));
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let (oldp, name) = self.fs.resolve_parent(from)?;
+        let (newp, newname) = self.fs.resolve_parent(to)?;
+        self.fs.do_rename(oldp, &name, newp, &newname)?;
+        self.audit("rename", || {
+            format!("{:?} {:?}", self.fs.child_path(oldp.into(), &name), self.fs.child_path(newp.into(), &newname))
+        });
+        Ok(())
+    }
+
+    fn extended(&mut self, m: &mut Decoder<'_>, r: &mut Encoder) -> Reply {
+        match m.string()? {
+            b"posix-rename@openssh.com" => {
+                let (from, to) = (m.path()?, m.path()?);
+                self.rename(&from, &to)?;
+                r.ok()
+            }
+            b"statvfs@openssh.com" => {
+                let path = m.path()?;
+                self.fs.resolve_path(&path, true)?;
+                let st = statvfs(&self.fs.inner.underlying_dir)?;
+                r.set_type(SSH_FXP_EXTENDED_REPLY);
+                r.u64(st.f_bsize as u64);
+                r.u64(st.f_frsize as u64);
+                r.u64(st.f_blocks as u64);
+                r.u64(st.f_bfree as u64);
+                r.u64(st.f_bavail as u64);
+                r.u64(st.f_files as u64);
+                r.u64(st.f_ffree as u64);
+                r.u64(st.f_favail as u64);
+                r.u64(st.f_fsid as u64);
+                // SSH2_FXE_STATVFS_ST_RDONLY
+                r.u64(self.fs.options_read().read_only as u64);
+                r.u64(self.fs.inner.name_max as u64);
+                Ok(())
+            }
+            _ => Err(Failure::Status(SSH_FX_OP_UNSUPPORTED, "Extension unsupported")),
+        }
+    }
+
+    fn audit<F: FnOnce() -> String>(&self, op: &str, describe: F) {
+        self.fs.audit_as(unsafe { libc::getuid() }, std::process::id(), op, describe)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for (_, open) in self.handles.drain() {
+            if let Open::File { ino, fh, .. } = open {
+                self.fs.release_handle(ino, fh);
+            }
+        }
+    }
+}
+
+/// A line as `ls -l` shows an entry, which some clients show as is.
+fn long_name(name: &OsStr, attr: &FileAttr) -> String {
+    let kind = match attr.kind {
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        FileType::NamedPipe => 'p',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Socket => 's',
+        FileType::RegularFile => '-',
+    };
+    let mut mode = kind.to_string();
+    for shift in [6, 3, 0].iter() {
+        let bits = attr.perm >> shift;
+        mode.push(if bits & 4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 2 != 0 { 'w' } else { '-' });
+        mode.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    let mtime = attr.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mtime = time::at_utc(time::Timespec::new(mtime.as_secs() as i64, 0));
+    format!(
+        "{} {:>4} {:<8} {:<8} {:>8} {} {}",
+        mode,
+        attr.nlink,
+        attr.uid,
+        attr.gid,
+        attr.size,
+        mtime.strftime("%b %e %H:%M").map(|time| time.to_string()).unwrap_or_default(),
+        name.to_string_lossy()
+    )
+}
+
+/// The name under which a request is counted and logged.
+fn op_name(ty: u8) -> &'static str {
+    match ty {
+        SSH_FXP_OPEN => "open",
+        SSH_FXP_CLOSE => "close",
+        SSH_FXP_READ => "read",
+        SSH_FXP_WRITE => "write",
+        SSH_FXP_LSTAT => "lstat",
+        SSH_FXP_FSTAT => "fstat",
+        SSH_FXP_SETSTAT => "setstat",
+        SSH_FXP_FSETSTAT => "fsetstat",
+        SSH_FXP_OPENDIR => "opendir",
+        SSH_FXP_READDIR => "readdir",
+        SSH_FXP_REMOVE => "remove",
+        SSH_FXP_MKDIR => "mkdir",
+        SSH_FXP_RMDIR => "rmdir",
+        SSH_FXP_REALPATH => "realpath",
+        SSH_FXP_STAT => "stat",
+        SSH_FXP_RENAME => "rename",
+        SSH_FXP_READLINK => "readlink",
+        SSH_FXP_SYMLINK => "symlink",
+        SSH_FXP_EXTENDED => "extended",
+        _ => "unknown",
+    }
+}
+
+/// A STATUS reply.
+fn status(id: u32, code: u32, message: &str) -> Vec<u8> {
+    let mut r = Encoder::new(SSH_FXP_STATUS);
+    r.u32(id);
+    r.u32(code);
+    r.string(message.as_bytes());
+    r.string(b"");
+    r.finish()
+}
+
+/// The attrs a client sends, of which only these are known.
+#[derive(Default)]
+struct Attrs {
+    size: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    permissions: Option<u32>,
+    times: Option<(u32, u32)>,
+}
+
+/// Reads the fields of a packet, all big-endian.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Failure> {
+        if self.0.len() < n {
+            return Err(Failure::Status(SSH_FX_BAD_MESSAGE, "Bad message"));
+        }
+        let (field, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u32(&mut self) -> Result<u32, Failure> {
+        let mut field = [0; 4];
+        field.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(field))
+    }
+
+    fn u64(&mut self) -> Result<u64, Failure> {
+        let mut field = [0; 8];
+        field.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(field))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], Failure> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+
+    fn path(&mut self) -> Result<PathBuf, Failure> {
+        Ok(PathBuf::from(OsStr::from_bytes(self.string()?)))
+    }
+
+    /// A handle, which this server makes 4 bytes long.
+    fn handle(&mut self) -> Result<u32, Failure> {
+        let handle = self.string()?;
+        if handle.len() != 4 {
+            return Err(Error::Errno(libc::EBADF).into());
+        }
+        Ok(u32::from_be_bytes([handle[0], handle[1], handle[2], handle[3]]))
+    }
+
+    fn attrs(&mut self) -> Result<Attrs, Failure> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & ATTR_UIDGID != 0 {
+            attrs.uid = Some(self.u32()?);
+            attrs.gid = Some(self.u32()?);
+        }
+        if flags & ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & ATTR_ACMODTIME != 0 {
+            attrs.times = Some((self.u32()?, self.u32()?));
+        }
+        if flags & ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.string()?;
+                self.string()?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+/// Builds a packet; the length is filled in by `finish`.
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn new(ty: u8) -> Encoder {
+        Encoder(vec![0, 0, 0, 0, ty])
+    }
+
+    fn set_type(&mut self, ty: u8) {
+        self.0[4] = ty;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.0.len() as u32 - 4;
+        self.0[..4].copy_from_slice(&len.to_be_bytes());
+        self.0
+    }
+
+    fn u32(&mut self, x: u32) {
+        self.0.extend_from_slice(&x.to_be_bytes());
+    }
+
+    fn u64(&mut self, x: u64) {
+        self.0.extend_from_slice(&x.to_be_bytes());
+    }
+
+    fn string(&mut self, x: &[u8]) {
+        self.u32(x.len() as u32);
+        self.0.extend_from_slice(x);
+    }
+
+    /// A STATUS of success, after the id.
+    fn ok(&mut self) -> Reply {
+        self.set_type(SSH_FXP_STATUS);
+        self.u32(SSH_FX_OK);
+        self.string(b"Success");
+        self.string(b"");
+        Ok(())
+    }
+
+    fn attrs(&mut self, attr: &FileAttr) {
+        self.u32(ATTR_SIZE | ATTR_UIDGID | ATTR_PERMISSIONS | ATTR_ACMODTIME);
+        self.u64(attr.size);
+        self.u32(attr.uid);
+        self.u32(attr.gid);
+        self.u32(mode_bits(attr.kind) | u32::from(attr.perm));
+        for time in [attr.atime, attr.mtime].iter() {
+            self.u32(time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fs_for_test;
+
+    /// A session on a repo with `a.txt` and `dir/b.txt`.
+    fn session(name: &str) -> (PathBuf, Session) {
+        let (root, fs) = fs_for_test(&format!("sftp-{}", name));
+        fs.mount_root().unwrap();
+        let session = Session {
+            fs,
+            handles: HashMap::new(),
+            next_handle: 0,
+        };
+        (root, session)
+    }
+
+    impl Session {
+        /// Send a request, and return the type and body of the reply,
+        /// after the id.
+        fn call<F: FnOnce(&mut Encoder)>(&mut self, ty: u8, body: F) -> (u8, Vec<u8>) {
+            let mut request = Encoder::new(ty);
+            request.u32(7);
+            body(&mut request);
+            let reply = self.respond(&request.finish()[4..]);
+            assert_eq!(u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]) as usize, reply.len() - 4);
+            assert_eq!(&reply[5..9], &7u32.to_be_bytes());
+            (reply[4], reply[9..].to_vec())
+        }
+
+        /// The status code a request fails with.
+        fn status<F: FnOnce(&mut Encoder)>(&mut self, ty: u8, body: F) -> u32 {
+            let (rty, reply) = self.call(ty, body);
+            assert_eq!(rty, SSH_FXP_STATUS, "{} didn't fail", op_name(ty));
+            Decoder(&reply).u32().unwrap_or_else(|_| panic!("no status"))
+        }
+
+        fn open_path(&mut self, path: &str, pflags: u32) -> Vec<u8> {
+            let (rty, reply) = self.call(SSH_FXP_OPEN, |m| {
+                m.string(path.as_bytes());
+                m.u32(pflags);
+                m.u32(0);
+            });
+            assert_eq!(rty, SSH_FXP_HANDLE, "open {} failed", path);
+            Decoder(&reply).string().unwrap_or_else(|_| panic!("no handle")).to_vec()
+        }
+
+        fn read_at(&mut self, handle: &[u8], offset: u64) -> Vec<u8> {
+            let (rty, reply) = self.call(SSH_FXP_READ, |m| {
+                m.string(handle);
+                m.u64(offset);
+                m.u32(4096);
+            });
+            assert_eq!(rty, SSH_FXP_DATA);
+            Decoder(&reply).string().unwrap_or_else(|_| panic!("no data")).to_vec()
+        }
+    }
+
+    #[test]
+    fn files_are_found_and_read() {
+        let (root, mut s) = session("read");
+        let mut init = Encoder::new(SSH_FXP_INIT);
+        init.u32(3);
+        let packet = init.finish();
+        let mut output = Vec::new();
+        serve(s.fs.clone(), &packet[..], &mut output).unwrap();
+        assert_eq!(&output[4..9], &[SSH_FXP_VERSION, 0, 0, 0, 3]);
+
+        let (rty, reply) = s.call(SSH_FXP_REALPATH, |m| m.string(b"dir/../dir/./"));
+        assert_eq!(rty, SSH_FXP_NAME);
+        let mut reply = Decoder(&reply);
+        assert_eq!(reply.u32().ok(), Some(1));
+        assert_eq!(reply.string().ok(), Some(&b"/dir"[..]));
+
+        let handle = s.open_path("/dir/b.txt", SSH_FXF_READ);
+        assert_eq!(s.read_at(&handle, 3), b"a dir");
+        assert_eq!(
+            s.status(SSH_FXP_READ, |m| {
+                m.string(&handle);
+                m.u64(8);
+                m.u32(4096);
+            }),
+            SSH_FX_EOF
+        );
+        assert_eq!(s.status(SSH_FXP_CLOSE, |m| m.string(&handle)), SSH_FX_OK);
+        assert!(s.handles.is_empty());
+
+        let (rty, reply) = s.call(SSH_FXP_STAT, |m| m.string(b"a.txt"));
+        assert_eq!(rty, SSH_FXP_ATTRS);
+        let attrs = Decoder(&reply).attrs().unwrap_or_default();
+        assert_eq!(attrs.size, Some(11));
+        assert_eq!(attrs.permissions, Some(0o100644));
+
+        assert_eq!(s.status(SSH_FXP_STAT, |m| m.string(b"/nope")), SSH_FX_NO_SUCH_FILE);
+        assert_eq!(s.status(SSH_FXP_OPEN, |m| {
+            m.string(b"/a.txt/b");
+            m.u32(SSH_FXF_READ);
+            m.u32(0);
+        }), SSH_FX_FAILURE);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn dirs_are_listed() {
+        let (root, mut s) = session("readdir");
+        let (rty, reply) = s.call(SSH_FXP_OPENDIR, |m| m.string(b"/"));
+        assert_eq!(rty, SSH_FXP_HANDLE);
+        let handle = Decoder(&reply).string().unwrap_or_default().to_vec();
+        let (rty, reply) = s.call(SSH_FXP_READDIR, |m| m.string(&handle));
+        assert_eq!(rty, SSH_FXP_NAME);
+        let mut reply = Decoder(&reply);
+        let mut names = Vec::new();
+        for _ in 0..reply.u32().unwrap_or_default() {
+            let name = reply.string().unwrap_or_default().to_vec();
+            let long_name = reply.string().unwrap_or_default().to_vec();
+            assert!(long_name.ends_with(&name));
+            reply.attrs().unwrap_or_default();
+            names.push(String::from_utf8(name).unwrap());
+        }
+        names.sort();
+        assert_eq!(names, [".", "..", "a.txt", "dir"]);
+        assert_eq!(s.status(SSH_FXP_READDIR, |m| m.string(&handle)), SSH_FX_EOF);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn files_are_created_and_written() {
+        let (root, mut s) = session("write");
+        let handle = s.open_path("/new.txt", SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_EXCL);
+        let write = |s: &mut Session, data: &[u8], offset: u64| {
+            s.status(SSH_FXP_WRITE, |m| {
+                m.string(&handle);
+                m.u64(offset);
+                m.string(data);
+            })
+        };
+        assert_eq!(write(&mut s, b"hello", 0), SSH_FX_OK);
+        assert_eq!(write(&mut s, b" there", 5), SSH_FX_OK);
+        assert_eq!(s.status(SSH_FXP_CLOSE, |m| m.string(&handle)), SSH_FX_OK);
+        let handle = s.open_path("/new.txt", SSH_FXF_READ);
+        assert_eq!(s.read_at(&handle, 0), b"hello there");
+        assert_eq!(std::fs::read(root.join("overlay/new.txt")).unwrap(), b"hello there");
+
+        assert_eq!(s.status(SSH_FXP_OPEN, |m| {
+            m.string(b"/new.txt");
+            m.u32(SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_EXCL);
+            m.u32(0);
+        }), SSH_FX_FAILURE);
+        let handle = s.open_path("/a.txt", SSH_FXF_WRITE | SSH_FXF_TRUNC);
+        assert_eq!(s.status(SSH_FXP_CLOSE, |m| m.string(&handle)), SSH_FX_OK);
+        assert_eq!(std::fs::read(root.join("overlay/a.txt")).unwrap(), b"");
+
+        assert_eq!(s.status(SSH_FXP_RENAME, |m| {
+            m.string(b"/new.txt");
+            m.string(b"/a.txt");
+        }), SSH_FX_FAILURE);
+        assert_eq!(s.status(SSH_FXP_EXTENDED, |m| {
+            m.string(b"posix-rename@openssh.com");
+            m.string(b"/new.txt");
+            m.string(b"/a.txt");
+        }), SSH_FX_OK);
+        assert_eq!(s.status(SSH_FXP_RMDIR, |m| m.string(b"/a.txt")), SSH_FX_FAILURE);
+        assert_eq!(s.status(SSH_FXP_REMOVE, |m| m.string(b"/a.txt")), SSH_FX_OK);
+        assert_eq!(s.status(SSH_FXP_STAT, |m| m.string(b"/new.txt")), SSH_FX_NO_SUCH_FILE);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
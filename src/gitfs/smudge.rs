@@ -0,0 +1,515 @@
+/// Conversions of clean files, as a checkout makes them, when
+/// `Options::smudge` is set, and back when they're committed.
+///
+/// The `text`, `eol`, `ident` and `working-tree-encoding` attributes
+/// are honored, as are `core.autocrlf` and `core.eol`.  Attributes come
+/// from the `.gitattributes` files of the mounted tree, then from
+/// `info/attributes` in the repository; `[attr]` macros other than
+/// `binary` are not expanded.  Filter drivers (`filter=`, e.g. LFS)
+/// run programs, and are left out, but Git LFS and git-annex files can
+/// be shown as their objects (see `lfs` and `annex`).
+///
+/// Files are converted when their dir is listed, since the size of a
+/// file in the mount is that of its converted content.
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use git2::{Error as GitError, ObjectType, Oid, Repository, Tree};
+
+use super::lfs::Pointer;
+use super::{stats, GitFS};
+use crate::error::Error;
+use crate::glob;
+
+/// The bytes looked at to tell binary files, as git does.
+const BINARY_PROBE: usize = 8000;
+
+/// How a clean file is converted for the mount.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Whether it's text, whose line endings are normalized to LF
+    /// when committed.
+    text: bool,
+    /// Whether it was found to be text (`text=auto`), in which case
+    /// files already holding CRs are left as they are.
+    auto: bool,
+    /// Whether LF is turned into CRLF.
+    crlf: bool,
+    /// Whether `$Id$` is expanded to the id of the blob.
+    ident: bool,
+    /// The encoding in the mount, as UTF-8 is converted to it.
+    encoding: Option<String>,
+}
+
+/// A clean file as converted, with the id of its converted content,
+/// under which that is cached.
+#[derive(Debug)]
+pub struct Smudged {
+    pub(super) filter: Filter,
+    pub(super) oid: Oid,
+    /// The object shown in place of the file, instead of its
+    /// converted content.
+    pub(super) object: Option<Object>,
+}
+
+/// Where the content of a file in the mount is kept, apart from the
+/// object database.
+#[derive(Debug)]
+pub(super) enum Object {
+    /// A Git LFS object, fetched on first read if it's missing.
+    Lfs(Pointer),
+    /// An object in the local annex.
+    Annex(PathBuf),
+}
+
+/// The attributes of the files in a dir of the mounted tree.
+pub(super) struct Attributes {
+    /// Lowest priority first.
+    rules: Vec<Rule>,
+    autocrlf: AutoCrlf,
+    eol_crlf: bool,
+}
+
+/// A line of a `.gitattributes` file.
+struct Rule {
+    /// The dir of the file the rule is in.
+    base: PathBuf,
+    pattern: String,
+    attrs: Vec<(String, State)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    Set,
+    Unset,
+    Value(String),
+    Unspecified,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AutoCrlf {
+    False,
+    True,
+    Input,
+}
+
+impl Attributes {
+    /// The attributes that apply in `dir` of the tree `root`.
+    pub(super) fn load(repo: &Repository, root: &Tree, dir: &Path) -> Attributes {
+        let mut rules = vec![];
+        let mut base = PathBuf::new();
+        let mut add = |base: &Path, data: &[u8]| parse(base, &String::from_utf8_lossy(data), &mut rules);
+        for name in Some(Path::new("")).into_iter().chain(dir.iter().map(Path::new)) {
+            base.push(name);
+            if let Ok(blob) = root
+                .get_path(&base.join(".gitattributes"))
+                .and_then(|entry| entry.to_object(repo))
+                .and_then(|object| object.peel_to_blob())
+            {
+                add(&base, blob.content());
+            }
+        }
+        if let Ok(data) = std::fs::read(repo.path().join("info/attributes")) {
+            add(Path::new(""), &data);
+        }
+
+        let config = repo.config().ok();
+        let config_str = |name: &str| config.as_ref().and_then(|config| config.get_string(name).ok());
+        let autocrlf = match config_str("core.autocrlf").map(|value| value.to_ascii_lowercase()) {
+            Some(value) if value == "input" => AutoCrlf::Input,
+            Some(value) if ["true", "yes", "on", "1"].contains(&&*value) => AutoCrlf::True,
+            _ => AutoCrlf::False,
+        };
+        let eol_crlf = config_str("core.eol").is_some_and(|eol| eol.eq_ignore_ascii_case("crlf"));
+        Attributes {
+            rules,
+            autocrlf,
+            eol_crlf,
+        }
+    }
+
+    /// The state of `attr` for the file at `path`.
+    fn state(&self, path: &Path, attr: &str) -> State {
+        for rule in self.rules.iter().rev() {
+            if let Some((_, state)) = rule.attrs.iter().rev().find(|(name, _)| name == attr) {
+                if rule.matches(path) {
+                    return state.clone();
+                }
+            }
+        }
+        State::Unspecified
+    }
+
+    /// Whether `attr` is set for the entry at `path`.
+    pub(super) fn is_set(&self, path: &Path, attr: &str) -> bool {
+        self.state(path, attr) == State::Set
+    }
+
+    /// How the file at `path`, holding `content`, is converted.
+    pub(super) fn filter(&self, path: &Path, content: &[u8]) -> Filter {
+        let eol = self.state(path, "eol");
+        let binary = is_binary(content);
+        let (text, auto) = match self.state(path, "text") {
+            State::Set => (true, false),
+            State::Unset => (false, false),
+            State::Value(value) if value == "auto" => (!binary, true),
+            // `eol` implies `text`.
+            _ if eol != State::Unspecified => (true, false),
+            _ => (self.autocrlf != AutoCrlf::False && !binary, true),
+        };
+        let crlf = text
+            && match eol {
+                State::Value(eol) => eol == "crlf",
+                _ => self.autocrlf == AutoCrlf::True || (self.autocrlf == AutoCrlf::False && self.eol_crlf),
+            };
+        let encoding = match self.state(path, "working-tree-encoding") {
+            State::Value(encoding) if !is_utf8(&encoding) => Some(encoding),
+            _ => None,
+        };
+        Filter {
+            text,
+            auto,
+            crlf,
+            ident: self.state(path, "ident") == State::Set,
+            encoding,
+        }
+    }
+
+    /// Convert the file at `path` in the mount, holding `content`, back
+    /// to what is committed.
+    pub(super) fn clean(&self, path: &Path, content: &[u8]) -> io::Result<Vec<u8>> {
+        // Whether it's text is told once it's in UTF-8.
+        let content = match self.filter(path, b"").encoding {
+            Some(encoding) => iconv(content, &encoding, "UTF-8")?,
+            None => content.to_vec(),
+        };
+        let filter = self.filter(path, &content);
+        let mut content = content;
+        if filter.text {
+            let mut out = Vec::with_capacity(content.len());
+            for (i, &byte) in content.iter().enumerate() {
+                if !(byte == b'\r' && content.get(i + 1) == Some(&b'\n')) {
+                    out.push(byte);
+                }
+            }
+            content = out;
+        }
+        if filter.ident {
+            content = replace_ids(&content, b"$Id$");
+        }
+        Ok(content)
+    }
+}
+
+impl Rule {
+    fn matches(&self, path: &Path) -> bool {
+        let path = match path.strip_prefix(&self.base) {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+        // A pattern without a slash matches names at any depth.
+        match self.pattern.contains('/') {
+            true => glob::matches(&self.pattern, path),
+            false => path.file_name().is_some_and(|name| glob::matches(&self.pattern, Path::new(name))),
+        }
+    }
+}
+
+/// Add the rules of a `.gitattributes` file in `base`.
+fn parse(base: &Path, data: &str, rules: &mut Vec<Rule>) {
+    for line in data.lines() {
+        let mut words = line.split_whitespace();
+        let pattern = match words.next() {
+            Some(pattern) if !pattern.starts_with('#') && !pattern.starts_with("[attr]") => pattern,
+            _ => continue,
+        };
+        // Attributes only apply to files.
+        if pattern.ends_with('/') {
+            continue;
+        }
+        let mut attrs = vec![];
+        for word in words {
+            let (name, state) = if let Some(name) = word.strip_prefix('-') {
+                (name, State::Unset)
+            } else if let Some(name) = word.strip_prefix('!') {
+                (name, State::Unspecified)
+            } else if let Some((name, value)) = word.split_once('=') {
+                (name, State::Value(value.to_owned()))
+            } else {
+                (word, State::Set)
+            };
+            match (name, &state) {
+                ("binary", State::Set) => attrs.push(("text".to_owned(), State::Unset)),
+                _ => attrs.push((name.to_owned(), state)),
+            }
+        }
+        rules.push(Rule {
+            base: base.to_owned(),
+            pattern: pattern.to_owned(),
+            attrs,
+        });
+    }
+}
+
+impl Filter {
+    /// Whether the filter changes anything when checking out.
+    pub(super) fn smudges(&self) -> bool {
+        self.crlf || self.ident || self.encoding.is_some()
+    }
+
+    /// Convert the content of blob `oid`, as a checkout would.
+    pub(super) fn smudge(&self, oid: Oid, content: &[u8]) -> Vec<u8> {
+        let mut content = content.to_vec();
+        if self.ident {
+            content = replace_ids(&content, format!("$Id: {} $", oid).as_bytes());
+        }
+        // CRLF is not doubled.
+        if self.crlf && !(self.auto && content.contains(&b'\r')) {
+            let mut out = Vec::with_capacity(content.len() + content.len() / 32);
+            for (i, &byte) in content.iter().enumerate() {
+                if byte == b'\n' && (i == 0 || content[i - 1] != b'\r') {
+                    out.push(b'\r');
+                }
+                out.push(byte);
+            }
+            content = out;
+        }
+        if let Some(encoding) = &self.encoding {
+            match iconv(&content, "UTF-8", encoding) {
+                Ok(encoded) => content = encoded,
+                Err(e) => warn!(%oid, encoding = &**encoding, %e, "cannot convert to the working tree encoding"),
+            }
+        }
+        content
+    }
+
+}
+
+impl GitFS {
+    /// Convert blob `oid` at `path` in the mount, if its attributes
+    /// say so, and cache the result.  Return the size of the file in
+    /// the mount, and how it's converted.
+    pub(super) fn smudge_blob(
+        &self,
+        attributes: &Attributes,
+        path: &Path,
+        oid: Oid,
+        content: &[u8],
+    ) -> Result<(u64, Option<Arc<Smudged>>), GitError> {
+        let filter = attributes.filter(path, content);
+        if !filter.smudges() {
+            return Ok((content.len() as u64, None));
+        }
+        let smudged = filter.smudge(oid, content);
+        let smudged_oid = Oid::hash_object(ObjectType::Blob, &smudged)?;
+        let size = smudged.len() as u64;
+        self.blob_cache().insert(smudged_oid, smudged.into());
+        Ok((size, Some(Arc::new(Smudged {
+            filter,
+            oid: smudged_oid,
+            object: None,
+        }))))
+    }
+
+    /// The content of blob `oid` in the mount, converted if it is.
+    pub(super) fn mount_content(&self, oid: Oid, smudged: Option<&Smudged>) -> Result<Arc<[u8]>, GitError> {
+        let smudged = match smudged {
+            Some(smudged) => smudged,
+            None => return self.blob_content(oid),
+        };
+        if let Some(content) = self.blob_cache().get(smudged.oid) {
+            stats::add(&self.inner.stats.blob_cache_hits, 1);
+            return Ok(content);
+        }
+        let content: Arc<[u8]> = smudged.filter.smudge(oid, &self.blob_content(oid)?).into();
+        self.blob_cache().insert(smudged.oid, content.clone());
+        Ok(content)
+    }
+
+    /// The file holding `object`.
+    pub(super) fn object_file(&self, object: &Object) -> Result<File, Error> {
+        match object {
+            Object::Lfs(pointer) => self.lfs_object(pointer),
+            Object::Annex(path) => Ok(File::open(path)?),
+        }
+    }
+
+    /// What is committed for the file at `path` in the mount, holding
+    /// `content`, given the mounted tree `root`.
+    pub(super) fn clean(&self, repo: &Repository, root: &Tree, path: &Path, content: Vec<u8>) -> io::Result<Vec<u8>> {
+        if !self.options_read().smudge {
+            return Ok(content);
+        }
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Attributes::load(repo, root, dir).clean(path, &content)
+    }
+}
+
+/// Replace `$Id$` and expanded `$Id: ... $` in `content` with `id`.
+fn replace_ids(content: &[u8], id: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = find(rest, b"$Id") {
+        out.extend_from_slice(&rest[..start]);
+        let after = &rest[start + 3..];
+        let end = match after.first() {
+            Some(b'$') => Some(1),
+            // An expanded one ends on the same line.
+            Some(b':') => after
+                .iter()
+                .position(|&byte| byte == b'$' || byte == b'\n')
+                .filter(|&end| after[end] == b'$')
+                .map(|end| end + 1),
+            _ => None,
+        };
+        match end {
+            Some(end) => {
+                out.extend_from_slice(id);
+                rest = &after[end..];
+            }
+            None => {
+                out.extend_from_slice(b"$Id");
+                rest = after;
+            }
+        }
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn is_binary(content: &[u8]) -> bool {
+    content[..content.len().min(BINARY_PROBE)].contains(&0)
+}
+
+fn is_utf8(encoding: &str) -> bool {
+    encoding.eq_ignore_ascii_case("utf-8") || encoding.eq_ignore_ascii_case("utf8")
+}
+
+/// Convert `data` from encoding `from` to `to`.
+#[cfg(not(target_os = "openbsd"))]
+fn iconv(data: &[u8], from: &str, to: &str) -> io::Result<Vec<u8>> {
+    use libc::{c_char, size_t};
+    use std::ffi::CString;
+    use std::ptr;
+
+    let invalid = |_| io::Error::from_raw_os_error(libc::EINVAL);
+    let (from, to) = (CString::new(from).map_err(invalid)?, CString::new(to).map_err(invalid)?);
+    let cd = unsafe { libc::iconv_open(to.as_ptr(), from.as_ptr()) };
+    if cd as isize == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut out: Vec<u8> = Vec::with_capacity(data.len() * 2 + 16);
+    let mut input = data.as_ptr() as *mut c_char;
+    let mut input_left = data.len() as size_t;
+    // Once all of the input is converted, the state is flushed.
+    let mut flushing = false;
+    let result = loop {
+        out.reserve(data.len() + 16);
+        let spare = out.capacity() - out.len();
+        let mut output = unsafe { out.as_mut_ptr().add(out.len()) } as *mut c_char;
+        let mut output_left = spare as size_t;
+        let ret = if flushing {
+            unsafe { libc::iconv(cd, ptr::null_mut(), ptr::null_mut(), &mut output, &mut output_left) }
+        } else {
+            unsafe { libc::iconv(cd, &mut input, &mut input_left, &mut output, &mut output_left) }
+        };
+        let error = io::Error::last_os_error();
+        unsafe { out.set_len(out.len() + spare - output_left) };
+        match ret {
+            size_t::MAX if error.raw_os_error() == Some(libc::E2BIG) => continue,
+            size_t::MAX => break Err(error),
+            _ if flushing => break Ok(out),
+            _ => flushing = true,
+        }
+    };
+    unsafe { libc::iconv_close(cd) };
+    result
+}
+
+/// OpenBSD has iconv only in the libiconv port, which isn't linked, so
+/// `working-tree-encoding` is refused there.
+#[cfg(target_os = "openbsd")]
+fn iconv(_: &[u8], _: &str, _: &str) -> io::Result<Vec<u8>> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(data: &str, autocrlf: AutoCrlf) -> Attributes {
+        let mut rules = vec![];
+        parse(Path::new(""), data, &mut rules);
+        parse(Path::new("sub"), "*.txt -text\n", &mut rules);
+        Attributes {
+            rules,
+            autocrlf,
+            eol_crlf: false,
+        }
+    }
+
+    #[test]
+    fn attributes_make_filters() {
+        let attrs = attributes("# comment\n*.txt text eol=crlf\n*.c ident\n/docs/*.md working-tree-encoding=UTF-16LE\n*.png binary\n", AutoCrlf::False);
+        let filter = |path: &str, content: &[u8]| attrs.filter(Path::new(path), content);
+        assert!(filter("a.txt", b"").crlf);
+        assert!(filter("dir/a.txt", b"").crlf);
+        // Deeper files take precedence.
+        assert_eq!(filter("sub/a.txt", b""), Filter::default());
+        assert!(filter("main.c", b"").ident && !filter("main.c", b"").text);
+        assert_eq!(filter("docs/a.md", b"").encoding.as_deref(), Some("UTF-16LE"));
+        assert_eq!(filter("x/docs/a.md", b"").encoding, None);
+        assert_eq!(filter("a.png", b"\x89PNG\0"), Filter::default());
+
+        let attrs = attributes("", AutoCrlf::True);
+        assert!(attrs.filter(Path::new("any"), b"text\n").crlf);
+        assert!(!attrs.filter(Path::new("any"), b"bin\0ary\n").crlf);
+    }
+
+    #[test]
+    fn filters_convert_both_ways() {
+        let oid = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let filter = Filter {
+            text: true,
+            auto: false,
+            crlf: true,
+            ident: true,
+            encoding: None,
+        };
+        let smudged = filter.smudge(oid, b"a\n$Id$\nb $Id: old $ $Id\n");
+        assert_eq!(
+            smudged,
+            b"a\r\n$Id: 0123456789abcdef0123456789abcdef01234567 $\r\nb $Id: 0123456789abcdef0123456789abcdef01234567 $ $Id\r\n"
+        );
+        let attrs = attributes("*.txt text eol=crlf ident\n*.md working-tree-encoding=UTF-16LE eol=crlf\n", AutoCrlf::False);
+        assert_eq!(attrs.filter(Path::new("a.txt"), b""), filter);
+        assert_eq!(attrs.clean(Path::new("a.txt"), &smudged).unwrap(), b"a\n$Id$\nb $Id$ $Id\n");
+    }
+
+    #[cfg(not(target_os = "openbsd"))]
+    #[test]
+    fn encodings_convert_both_ways() {
+        let oid = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let attrs = attributes("*.md working-tree-encoding=UTF-16LE eol=crlf\n", AutoCrlf::False);
+        let filter = attrs.filter(Path::new("a.md"), "é\n".as_bytes());
+        let smudged = filter.smudge(oid, "é\n".as_bytes());
+        assert_eq!(smudged, b"\xe9\0\r\0\n\0");
+        assert_eq!(attrs.clean(Path::new("a.md"), &smudged).unwrap(), "é\n".as_bytes());
+    }
+
+    #[cfg(target_os = "openbsd")]
+    #[test]
+    fn encodings_are_refused() {
+        let oid = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let attrs = attributes("*.md working-tree-encoding=UTF-16LE\n", AutoCrlf::False);
+        let filter = attrs.filter(Path::new("a.md"), b"a\n");
+        assert_eq!(filter.smudge(oid, b"a\n"), b"a\n");
+        assert_eq!(attrs.clean(Path::new("a.md"), b"a\n").map_err(|e| e.raw_os_error()), Err(Some(libc::EOPNOTSUPP)));
+    }
+}
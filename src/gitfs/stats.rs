@@ -0,0 +1,269 @@
+/// Counters of what a mount has been doing, shown in `/.gitfs/stats`
+/// and, with the `metrics` feature, exported to Prometheus.
+///
+/// The counters are only ever added to, with relaxed atomics: they are
+/// for a rough picture, and need not be consistent with each other.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError, TryLockError};
+use std::time::{Duration, Instant};
+
+use super::GitFS;
+use crate::{EntryKind, Ino};
+
+/// How many of the paths most time was spent on the summary has.
+const SLOW_PATHS: usize = 10;
+
+#[derive(Debug, Default)]
+pub(super) struct Stats {
+    ops: Mutex<BTreeMap<&'static str, OpStats>>,
+    /// Operations by ino, for the paths most operated on.  Inos that
+    /// are gone are dropped when they're looked at.
+    inos: Mutex<BTreeMap<Ino, OpStats>>,
+    pub(super) blob_cache_hits: AtomicU64,
+    pub(super) blob_cache_misses: AtomicU64,
+    pub(super) bytes_read: AtomicU64,
+    /// The part of `bytes_read` read from the underlying dir, rather
+    /// than the repository.
+    pub(super) bytes_read_overlay: AtomicU64,
+    pub(super) bytes_written: AtomicU64,
+}
+
+/// How many times a FUSE operation was called, and how long it took
+/// in all.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(super) struct OpStats {
+    pub(super) count: u64,
+    pub(super) time: Duration,
+}
+
+/// Counts an operation, and how long it takes until dropped.  Logs
+/// the operation if it took longer than `slow_op_threshold`.
+pub(super) struct OpTimer<'a> {
+    fs: &'a GitFS,
+    op: &'static str,
+    ino: Ino,
+    start: Instant,
+}
+
+impl Stats {
+    pub(super) fn ops(&self) -> BTreeMap<&'static str, OpStats> {
+        self.ops.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+impl Drop for OpTimer<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        {
+            let mut ops = self.fs.inner.stats.ops.lock().unwrap_or_else(PoisonError::into_inner);
+            let op = ops.entry(self.op).or_default();
+            op.count += 1;
+            op.time += elapsed;
+        }
+        {
+            let mut inos = self.fs.inner.stats.inos.lock().unwrap_or_else(PoisonError::into_inner);
+            let ino = inos.entry(self.ino).or_default();
+            ino.count += 1;
+            ino.time += elapsed;
+        }
+        let threshold = self.fs.options_read().slow_op_threshold;
+        if threshold.is_some_and(|threshold| elapsed > threshold) {
+            // The path is only looked up now, as it's rarely needed.
+            warn!(op = self.op, ino = self.ino.0, path = %self.fs.path_of(self.ino).display(), ?elapsed, "slow operation");
+        }
+    }
+}
+
+fn try_lock<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    match mutex.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// Add `n` to a counter.
+pub(super) fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+pub(super) fn get(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// What the mount holds right now.
+pub(super) struct Gauges {
+    pub(super) entries: usize,
+    pub(super) dirty_files: usize,
+    pub(super) open_handles: usize,
+    pub(super) blob_cache_bytes: usize,
+    pub(super) blob_cache_pinned_bytes: usize,
+}
+
+impl GitFS {
+    /// Start timing the operation `op` on `ino`.
+    pub(super) fn start_op(&self, op: &'static str, ino: Ino) -> OpTimer<'_> {
+        OpTimer {
+            fs: self,
+            op,
+            ino,
+            start: Instant::now(),
+        }
+    }
+
+    /// The `n` paths most operated on, with how many times, most first.
+    pub(super) fn hot_paths(&self, n: usize) -> Vec<(PathBuf, u64)> {
+        let mut paths = self.path_stats();
+        paths.sort_by(|(a_path, a), (b_path, b)| b.count.cmp(&a.count).then(a_path.cmp(b_path)));
+        paths.into_iter().take(n).map(|(path, stats)| (path, stats.count)).collect()
+    }
+
+    /// The operations on each path that is still there.
+    fn path_stats(&self) -> Vec<(PathBuf, OpStats)> {
+        let inomap = self.inomap();
+        let mut inos = self.inner.stats.inos.lock().unwrap_or_else(PoisonError::into_inner);
+        inos.retain(|&ino, _| inomap.get(ino).is_some());
+        inos.iter().filter_map(|(&ino, stats)| Some((inomap.prefix(ino)?, *stats))).collect()
+    }
+
+    pub(super) fn gauges(&self) -> Gauges {
+        let (entries, dirty_files) = {
+            let inomap = self.inomap();
+            let dirty = inomap.iter().filter(|(_, entry)| matches!(entry.u, EntryKind::DirtyFile)).count();
+            (inomap.iter().count(), dirty)
+        };
+        let open_handles = self.handles().iter().count();
+        let (blob_cache_bytes, blob_cache_pinned_bytes) = {
+            let cache = self.blob_cache();
+            (cache.size(), cache.pinned_size())
+        };
+        Gauges {
+            entries,
+            dirty_files,
+            open_handles,
+            blob_cache_bytes,
+            blob_cache_pinned_bytes,
+        }
+    }
+
+    /// Log what the mount holds, at info level: the mounted commit,
+    /// the entries of each kind, the open handles, the caches, and
+    /// every dirty path.  A lock that is held is reported and skipped
+    /// rather than waited for, so that a hung mount can be looked at
+    /// too.
+    pub fn dump_state(&self) {
+        match try_lock(&self.inner.head) {
+            Some(head) => info!(refspec = %head.refspec, commit = ?head.commit, "state: mounted"),
+            None => warn!("state: head is locked"),
+        }
+        match try_lock(&self.inner.inomap) {
+            Some(inomap) => {
+                let (mut trees, mut listed_trees, mut blobs, mut dirty_dirs, mut dirty_files) = (0, 0, 0, 0, 0);
+                let mut dirty = vec![];
+                for (ino, entry) in inomap.iter() {
+                    match &entry.u {
+                        EntryKind::GitTree { children, .. } => {
+                            trees += 1;
+                            listed_trees += children.is_some() as usize;
+                        }
+                        EntryKind::GitBlob { .. } => blobs += 1,
+                        EntryKind::DirtyDir { .. } => dirty_dirs += 1,
+                        EntryKind::DirtyFile => {
+                            dirty_files += 1;
+                            dirty.extend(inomap.prefix(ino));
+                        }
+                    }
+                }
+                info!(trees, listed_trees, blobs, dirty_dirs, dirty_files, "state: entries");
+                dirty.sort();
+                for path in dirty {
+                    info!(path = %path.display(), "state: dirty file");
+                }
+            }
+            None => warn!("state: inomap is locked"),
+        }
+        match try_lock(&self.inner.handles) {
+            Some(handles) => info!(open_handles = handles.iter().count(), "state: handles"),
+            None => warn!("state: handles are locked"),
+        }
+        match try_lock(&self.inner.commit_times) {
+            Some(times) => info!(commit_times = times.as_ref().map_or(0, |cached| cached.times.len()), "state: commit times"),
+            None => warn!("state: commit times are locked"),
+        }
+        match try_lock(&self.inner.blob_cache) {
+            Some(cache) => info!(blob_cache_bytes = cache.size(), pinned_bytes = cache.pinned_size(), "state: blob cache"),
+            None => warn!("state: blob cache is locked"),
+        }
+    }
+
+    /// Log what the mount has been doing, at info level: the time spent
+    /// in each operation, where the bytes read came from, how well the
+    /// blob cache did, and the paths most time was spent on.  Logged
+    /// on unmount with `Options::summary_on_unmount`, before anything
+    /// is dropped, to tell whether a larger cache or preloading would
+    /// help the next mount.
+    pub fn log_summary(&self) {
+        let stats = &self.inner.stats;
+        let mut ops: Vec<_> = stats.ops().into_iter().collect();
+        let count = ops.iter().map(|(_, op)| op.count).sum::<u64>();
+        let time = ops.iter().map(|(_, op)| op.time).sum::<Duration>();
+        info!(count, ?time, "summary: operations");
+        ops.sort_by(|(a_name, a), (b_name, b)| b.time.cmp(&a.time).then(a_name.cmp(b_name)));
+        for (op, stats) in ops {
+            info!(op, count = stats.count, time = ?stats.time, "summary: operation");
+        }
+
+        let read = get(&stats.bytes_read);
+        let from_overlay = get(&stats.bytes_read_overlay);
+        info!(
+            from_repository = read.saturating_sub(from_overlay),
+            from_overlay,
+            written = get(&stats.bytes_written),
+            "summary: bytes"
+        );
+        let (hits, misses) = (get(&stats.blob_cache_hits), get(&stats.blob_cache_misses));
+        let hit_rate = match hits + misses {
+            0 => "-".to_owned(),
+            all => format!("{:.1}%", hits as f64 * 100.0 / all as f64),
+        };
+        info!(hits, misses, %hit_rate, bytes = self.blob_cache().size(), "summary: blob cache");
+
+        let mut paths = self.path_stats();
+        paths.sort_by(|(a_path, a), (b_path, b)| b.time.cmp(&a.time).then(a_path.cmp(b_path)));
+        for (path, stats) in paths.into_iter().take(SLOW_PATHS) {
+            info!(path = %Path::new("/").join(path).display(), count = stats.count, time = ?stats.time, "summary: slow path");
+        }
+    }
+
+    /// The counters and gauges in the Prometheus text format.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn metrics(&self) -> String {
+        let gauges = self.gauges();
+        let stats = &self.inner.stats;
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+        };
+        metric("gitfs_entries", "gauge", "Entries in the inode map.", gauges.entries as u64);
+        metric("gitfs_dirty_files", "gauge", "Files in the underlying dir.", gauges.dirty_files as u64);
+        metric("gitfs_open_handles", "gauge", "Open files and dirs.", gauges.open_handles as u64);
+        metric("gitfs_blob_cache_bytes", "gauge", "Size of the cached blobs.", gauges.blob_cache_bytes as u64);
+        metric("gitfs_blob_cache_pinned_bytes", "gauge", "Size of the pinned blobs.", gauges.blob_cache_pinned_bytes as u64);
+        metric("gitfs_blob_cache_hits_total", "counter", "Blob reads served from the cache.", get(&stats.blob_cache_hits));
+        metric("gitfs_blob_cache_misses_total", "counter", "Blob reads from the repository.", get(&stats.blob_cache_misses));
+        metric("gitfs_read_bytes_total", "counter", "Bytes read from files.", get(&stats.bytes_read));
+        metric("gitfs_read_overlay_bytes_total", "counter", "Bytes read from files in the underlying dir.", get(&stats.bytes_read_overlay));
+        metric("gitfs_written_bytes_total", "counter", "Bytes written to files.", get(&stats.bytes_written));
+
+        let ops = stats.ops();
+        out.push_str("# HELP gitfs_op_duration_seconds Time spent in FUSE operations.\n");
+        out.push_str("# TYPE gitfs_op_duration_seconds summary\n");
+        for (op, stats) in &ops {
+            out.push_str(&format!("gitfs_op_duration_seconds_sum{{op=\"{}\"}} {}\n", op, stats.time.as_secs_f64()));
+            out.push_str(&format!("gitfs_op_duration_seconds_count{{op=\"{}\"}} {}\n", op, stats.count));
+        }
+        out
+    }
+}
@@ -0,0 +1,68 @@
+/// Stepping the mount through the history of the mounted commit, as
+/// `git bisect run` scripts do with a worktree: `prev`, `next` and
+/// `goto` in `ctl` switch to another commit as `checkout` does, under
+/// the same mountpoint.
+///
+/// `prev` goes to the first parent of the mounted commit, and `next`
+/// back toward the commit the steps started from (the tip), along the
+/// first parents of the tip.  `goto` goes to any commit, and `next`
+/// from there only if it's on the way to the tip.  The mount is left
+/// on bare commits, so that refreshing doesn't undo the steps; a
+/// `checkout`, `refresh` or `commit` that switches commits starts over.
+use git2::{Oid, Sort};
+use libc::{EIO, ENOENT};
+
+use super::GitFS;
+use crate::error::Error;
+
+/// Where to step to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// The first parent of the mounted commit.
+    Prev,
+    /// The commit whose first parent is the mounted one, on the way to
+    /// the tip.
+    Next,
+    /// A ref or commit.
+    Goto(String),
+}
+
+impl GitFS {
+    /// Switch to another commit, as `checkout` does, and return it.
+    /// ENOENT if there is no commit to step to.
+    pub fn step(&self, step: Step) -> Result<Oid, Error> {
+        let mut head = self.head();
+        let current = head.commit.ok_or(Error::Errno(EIO))?;
+        let tip = head.tip.unwrap_or(current);
+        let target = match step {
+            Step::Prev => {
+                let repo = self.repo();
+                let commit = repo.find_commit(current)?;
+                commit.parent_id(0).map_err(|_| Error::Errno(ENOENT))?
+            }
+            Step::Next => self.next_toward(tip, current)?,
+            Step::Goto(refspec) => self.resolve(&refspec)?.0,
+        };
+        self.switch_head(&mut head, &target.to_string())?;
+        head.tip = Some(tip);
+        Ok(target)
+    }
+
+    /// The commit right after `current` on the first parents of `tip`.
+    fn next_toward(&self, tip: Oid, current: Oid) -> Result<Oid, Error> {
+        let repo = self.repo();
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(Sort::NONE)?;
+        walk.simplify_first_parent()?;
+        walk.push(tip)?;
+        let mut after = None;
+        for oid in walk {
+            let oid = oid?;
+            if oid == current {
+                return after.ok_or(Error::Errno(ENOENT));
+            }
+            after = Some(oid);
+        }
+        Err(Error::Errno(ENOENT))
+    }
+}
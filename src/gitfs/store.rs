@@ -0,0 +1,199 @@
+/// The blob store (`Options::blob_store`): blobs checked out into the
+/// underlying dir, kept by id in a dir that mounts of the same
+/// repository can share, and hard-linked from there.
+///
+/// A checked out file starts out as a link to its stored blob, so that
+/// mounts checking out the same blob keep one copy of it.  It's given
+/// an inode of its own before anything changes it: the first write,
+/// truncation, chown, chflags, utimens or security xattr through the
+/// mount copies it aside and renames the copy over the link, reopening
+/// the files of its handles.  Files with a mode, owner or flags of
+/// their own are copied from the start, as are those of a store on
+/// another file system, or owned by someone else.  Processes writing
+/// to the underlying dir themselves, rather than through the mount,
+/// change every link.
+///
+/// Blobs no underlying dir links to any more are removed when a mount
+/// starts (`collect`).
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use git2::Oid;
+use libc::{mode_t, EXDEV, O_RDONLY};
+use openat::Dir;
+
+use super::{open_at, GitFS};
+use crate::error::Error;
+use crate::{EntryKind, Ino, InoMap};
+
+/// How old what adding a blob left behind is before it's removed, as
+/// it may still be written.
+const STALE: Duration = Duration::from_secs(3600);
+
+/// Where blob `oid`, checked out with `mode`, is in the store at
+/// `store`, as in the objects dir of a repository.  `None` for modes
+/// blobs aren't stored with.
+fn stored_path(store: &Path, oid: Oid, mode: mode_t) -> Option<PathBuf> {
+    let hex = oid.to_string();
+    let name = match mode & 0o777 {
+        0o644 => hex[2..].to_owned(),
+        0o755 => format!("{}.x", &hex[2..]),
+        _ => return None,
+    };
+    Some(store.join(&hex[..2]).join(name))
+}
+
+/// Check out blob `oid` with `content` and `mode` at `path` in `dir`,
+/// as a link to the store, adding it there if it's not there yet.
+// dev_t isn't u64 on every platform.
+#[allow(clippy::unnecessary_cast)]
+pub(super) fn link(store: &Path, oid: Oid, mode: mode_t, content: &[u8], dir: &Dir, path: &Path) -> io::Result<()> {
+    let stored = stored_path(store, oid, mode).ok_or_else(|| io::Error::other("not a mode blobs are stored with"))?;
+    let blobs = stored.parent().expect("stored blobs are in a dir");
+    fs::create_dir_all(blobs)?;
+    // Rather than adding a blob that can't be linked to.
+    if fs::metadata(blobs)?.dev() != dir.self_metadata()?.stat().st_dev as u64 {
+        return Err(io::Error::from_raw_os_error(EXDEV));
+    }
+    let metadata = match fs::symlink_metadata(&stored) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            add(&stored, mode, content)?;
+            fs::symlink_metadata(&stored)?
+        }
+        Err(e) => return Err(e),
+    };
+    if !metadata.is_file() || metadata.uid() != unsafe { libc::geteuid() } {
+        return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+    }
+    let name = stored.file_name().expect("stored blobs have a name");
+    openat::hardlink(&Dir::open(blobs)?, name, dir, path)
+}
+
+/// Add a blob to the store at `stored`.
+fn add(stored: &Path, mode: mode_t, content: &[u8]) -> io::Result<()> {
+    // Written aside and renamed into place, so that mounts adding the
+    // same blob at the same time never see half of it.
+    let tmp = aside(stored.parent().expect("stored blobs are in a dir"));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.set_permissions(Permissions::from_mode(mode & 0o777))
+        })
+        .and_then(|_| fs::rename(&tmp, stored));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// A name in `dir` to write something aside as.
+fn aside(dir: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    dir.join(format!(".tmp-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)))
+}
+
+/// Give `path` in `dir`, a link, an inode of its own with the same
+/// content and mode.  It's copied aside in `tmp`, on the same file
+/// system, and renamed over the link.
+fn unlink(tmp: &Dir, dir: &Dir, path: &Path) -> io::Result<()> {
+    let mut linked = open_at(dir, path, O_RDONLY, 0)?;
+    let name = aside(Path::new(""));
+    let copied = tmp.new_file(&name, 0o600).and_then(|mut copy| {
+        copy.set_permissions(linked.metadata()?.permissions())?;
+        io::copy(&mut linked, &mut copy)?;
+        copy.flush()
+    });
+    if let Err(e) = copied.and_then(|_| openat::rename(tmp, &name, dir, path)) {
+        let _ = tmp.remove_file(&name);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Remove the blobs of the store no underlying dir links to, and what
+/// adding blobs left behind, and return how many files and bytes were.
+/// A mount adding a blob meanwhile copies it when it can't link to it.
+pub(super) fn collect(store: &Path) -> io::Result<(usize, u64)> {
+    let mut removed = (0, 0);
+    let mut remove = |entry: &fs::DirEntry, blob: bool| -> io::Result<()> {
+        let metadata = entry.metadata()?;
+        let unused = match entry.file_name().to_str() {
+            Some(name) if name.starts_with(".tmp-") => metadata.modified()?.elapsed().is_ok_and(|age| age > STALE),
+            _ => blob && metadata.nlink() == 1,
+        };
+        if unused && metadata.is_file() && fs::remove_file(entry.path()).is_ok() {
+            removed.0 += 1;
+            removed.1 += metadata.len();
+        }
+        Ok(())
+    };
+    for entry in fs::read_dir(store)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            // Copied aside by `unlink`.
+            remove(&entry, false)?;
+            continue;
+        }
+        for blob in fs::read_dir(entry.path())? {
+            remove(&blob?, true)?;
+        }
+    }
+    Ok(removed)
+}
+
+/// `collect` the store at `store` without holding up the mount.
+pub(super) fn collect_in_background(store: PathBuf) {
+    let spawned = thread::Builder::new().name("gitfs-store-gc".to_owned()).spawn(move || match collect(&store) {
+        Ok((files, bytes)) => info!(?store, files, bytes, "collected the blob store"),
+        Err(e) => warn!(?store, %e, "cannot collect the blob store"),
+    });
+    if let Err(e) = spawned {
+        warn!(%e, "cannot collect the blob store");
+    }
+}
+
+impl GitFS {
+    /// Give dirty file `ino` an inode of its own if it's a link, as
+    /// the blob store makes them, before it's changed.  Called with
+    /// the InoMap locked, as `inomap`.
+    pub(super) fn unshare(&self, inomap: &InoMap, ino: Ino) -> Result<(), Error> {
+        match inomap.get(ino) {
+            Some(entry) if matches!(entry.u, EntryKind::DirtyFile) => (),
+            _ => return Ok(()),
+        }
+        let path = self.overlay_path(inomap, ino)?;
+        if self.inner.underlying_dir.metadata(&path)?.stat().st_nlink <= 1 {
+            return Ok(());
+        }
+        // Copied aside in the store, where it's not seen by the mount.
+        let store = self.options_read().blob_store.clone();
+        match store.map(|store| Dir::open(&store)) {
+            Some(tmp) => unlink(&tmp?, &self.inner.underlying_dir, &path)?,
+            None => unlink(&self.inner.underlying_dir, &self.inner.underlying_dir, &path)?,
+        }
+        debug!(?path, "gave a linked file an inode of its own");
+        let mut handles = self.handles();
+        let open: Vec<_> = handles
+            .iter()
+            .filter(|(_, handle)| handle.ino == ino && handle.file.is_some())
+            .map(|(fh, _)| fh)
+            .collect();
+        for fh in open {
+            if let Some(handle) = handles.get_mut(fh) {
+                handle.file = Some(Arc::new(open_at(&self.inner.underlying_dir, &path, handle.flags, 0)?));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+/// Submodules, shown as the tree of the commit they're at, as
+/// `git submodule update` would check them out.
+///
+/// A submodule is found by its path in the `.gitmodules` of the
+/// mounted commit, and its repository under `modules/<name>` of the
+/// git dir, or in the worktree of an older checkout.  The objects of
+/// that repository are then read through the mounted one, as an
+/// alternate.  Submodules that aren't there, aren't in `.gitmodules`,
+/// or don't have their commit, are left out with a warning, as are the
+/// submodules of submodules.
+///
+/// The tree of a submodule is read-only (EROFS): its files belong to
+/// another repository, which a commit of the mount can't change.  The
+/// submodule itself can be renamed or removed, which a commit records
+/// as the gitlink moving.
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::PoisonError;
+
+use git2::{ObjectType, Oid, Repository};
+use libc::EROFS;
+
+use super::{common_dir, GitFS};
+use crate::error::Error;
+use crate::{EntryKind, Ino, InoMap};
+
+/// What is known of the submodules of the commit mounted when they
+/// were looked at.
+#[derive(Default)]
+pub(super) struct Submodules {
+    commit: Option<Oid>,
+    /// The name of each submodule, by path, once `.gitmodules` is read.
+    names: Option<HashMap<PathBuf, String>>,
+    /// The tree of each submodule at a commit, if it was found.
+    trees: HashMap<(PathBuf, Oid), Option<Oid>>,
+    /// The object dirs of submodules added to the repository, which
+    /// stay there.
+    alternates: HashSet<PathBuf>,
+}
+
+impl GitFS {
+    /// Forget the submodules of the commit mounted before `commit`,
+    /// mounted now.  Called with the head locked, which `walk_tree`
+    /// can't take to tell which commit is.
+    pub(super) fn forget_submodules(&self, commit: Oid) {
+        let mut submodules = self.inner.submodules.lock().unwrap_or_else(PoisonError::into_inner);
+        if submodules.commit != Some(commit) {
+            submodules.commit = Some(commit);
+            submodules.names = None;
+            submodules.trees.clear();
+        }
+    }
+
+    /// The tree of commit `oid` of the submodule at `path` in the
+    /// mount, from the `.gitmodules` of the mounted commit, if the
+    /// submodule is there with that commit.  Called with the repository
+    /// locked, as `repo`.
+    pub(super) fn submodule_tree(&self, repo: &Repository, path: &Path, oid: Oid) -> Option<Oid> {
+        let path = self.inner.subdir.join(path);
+        let mut submodules = self.inner.submodules.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&tree) = submodules.trees.get(&(path.clone(), oid)) {
+            return tree;
+        }
+        let tree = match find_tree(repo, &mut submodules, &path, oid) {
+            Ok(Some(tree)) => Some(tree),
+            Ok(None) => {
+                warn!(?path, commit = %oid, "submodule is not available, skipping");
+                None
+            }
+            Err(e) => {
+                warn!(?path, commit = %oid, %e, "cannot read submodule, skipping");
+                None
+            }
+        };
+        submodules.trees.insert((path, oid), tree);
+        tree
+    }
+
+    /// Whether the submodule at commit `oid` and `path` in the mount
+    /// was left out, as it couldn't be found.
+    pub(super) fn is_hidden_submodule(&self, path: &Path, oid: Oid) -> bool {
+        let submodules = self.inner.submodules.lock().unwrap_or_else(PoisonError::into_inner);
+        submodules.trees.get(&(self.inner.subdir.join(path), oid)) == Some(&None)
+    }
+
+    /// EROFS if `ino` is a submodule, or in one.
+    pub(super) fn check_submodule(&self, inomap: &InoMap, ino: Ino) -> Result<(), Error> {
+        match inomap.get(ino) {
+            Some(entry) if is_submodule(&entry.u) => Err(Error::Errno(EROFS)),
+            _ if in_submodule(inomap, ino) => Err(Error::Errno(EROFS)),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(super) fn is_submodule(kind: &EntryKind) -> bool {
+    matches!(kind, EntryKind::GitTree { commit: Some(_), .. })
+}
+
+/// Whether `ino` is in the tree of a submodule.
+pub(super) fn in_submodule(inomap: &InoMap, mut ino: Ino) -> bool {
+    while !ino.is_root() {
+        ino = match inomap.get(ino) {
+            Some(entry) => entry.parent,
+            None => return false,
+        };
+        match inomap.get(ino) {
+            Some(entry) if is_submodule(&entry.u) => return true,
+            _ => (),
+        }
+    }
+    false
+}
+
+/// The tree of commit `oid` of the submodule at `path` in the mounted
+/// commit, making its objects readable through `repo`.
+fn find_tree(repo: &Repository, submodules: &mut Submodules, path: &Path, oid: Oid) -> Result<Option<Oid>, Error> {
+    if submodules.names.is_none() {
+        submodules.names = Some(match submodules.commit {
+            Some(commit) => match repo.find_commit(commit)?.tree()?.get_name(".gitmodules") {
+                Some(entry) if entry.kind() == Some(ObjectType::Blob) => parse_gitmodules(repo.find_blob(entry.id())?.content()),
+                _ => HashMap::new(),
+            },
+            None => HashMap::new(),
+        });
+    }
+    let name = match submodules.names.as_ref().and_then(|names| names.get(path)) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let mut dirs = vec![common_dir(repo).join("modules").join(name)];
+    if let Some(worktree) = repo.workdir() {
+        dirs.push(worktree.join(path));
+    }
+    for dir in dirs {
+        let submodule = match Repository::open(&dir) {
+            Ok(submodule) => submodule,
+            Err(_) => continue,
+        };
+        let tree = match submodule.find_commit(oid) {
+            Ok(commit) => commit.tree_id(),
+            Err(_) => continue,
+        };
+        let objects = common_dir(&submodule).join("objects");
+        if !submodules.alternates.contains(&objects) {
+            let path = objects.to_str().ok_or(Error::Errno(libc::EINVAL))?;
+            repo.odb()?.add_disk_alternate(path)?;
+            debug!(?objects, "added the objects of a submodule");
+            submodules.alternates.insert(objects);
+        }
+        return Ok(Some(tree));
+    }
+    Ok(None)
+}
+
+/// The name of each submodule in a `.gitmodules`, by path.
+fn parse_gitmodules(content: &[u8]) -> HashMap<PathBuf, String> {
+    let mut names = HashMap::new();
+    let mut name = None;
+    for line in String::from_utf8_lossy(content).lines() {
+        let line = line.trim();
+        if let Some(section) = line.strip_prefix('[') {
+            // e.g. [submodule "name"]
+            name = section
+                .strip_prefix("submodule")
+                .map(str::trim_start)
+                .and_then(|rest| rest.strip_prefix('"'))
+                .and_then(|rest| rest.rfind("\"]").map(|end| unescape(&rest[..end])));
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        if let Some(name) = &name {
+            if key.eq_ignore_ascii_case("path") {
+                let path = unescape(value);
+                names.insert(PathBuf::from(path.trim_end_matches('/')), name.clone());
+            }
+        }
+    }
+    names
+}
+
+/// A value of git config, without its quotes, escapes and comment.
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                Some(c) => unescaped.push(c),
+                None => (),
+            },
+            '#' | ';' if !quoted => break,
+            c => unescaped.push(c),
+        }
+    }
+    unescaped.trim_end().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gitmodules() {
+        let gitmodules = b"# comment\n\
+            [submodule \"lib\"]\n\
+            \tpath = vendor/lib/\n\
+            \turl = https://example.com/lib.git\n\
+            [submodule \"with \\\"quotes\\\"\"]\n\
+            \tPath = \"a b\" ; comment\n\
+            [core]\n\
+            \tpath = not/a/submodule\n";
+        let names = parse_gitmodules(gitmodules);
+        assert_eq!(names.len(), 2);
+        assert_eq!(names[Path::new("vendor/lib")], "lib");
+        assert_eq!(names[Path::new("a b")], "with \"quotes\"");
+    }
+}
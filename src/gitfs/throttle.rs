@@ -0,0 +1,105 @@
+/// Throttles of reads and writes (`Options::read_rate`, `write_rate`
+/// and `iops`), so that e.g. an indexer walking the mount doesn't
+/// hog the disk where the repository is.
+///
+/// Each throttle is a token bucket holding up to a second's worth.  An
+/// operation takes what it needs, going into debt if there isn't
+/// enough, and sleeps until the debt is paid back.  Since operations
+/// are handled one at a time, this holds up the whole mount, which is
+/// the point.
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::GitFS;
+
+#[derive(Debug, Default)]
+pub(super) struct Throttles {
+    read: Mutex<Bucket>,
+    write: Mutex<Bucket>,
+    ops: Mutex<Bucket>,
+}
+
+/// Whether an operation reads or writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Io {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    /// None until first used.
+    last: Option<Instant>,
+    tokens: f64,
+}
+
+impl Bucket {
+    /// Take `n` tokens at `now`, given that `rate` come in per second,
+    /// and return how long to wait before going on.
+    fn take(&mut self, rate: u64, n: u64, now: Instant) -> Duration {
+        let rate = rate as f64;
+        let gained = match self.last {
+            Some(last) => now.saturating_duration_since(last).as_secs_f64() * rate,
+            None => rate,
+        };
+        self.last = Some(now);
+        self.tokens = (self.tokens + gained).min(rate) - n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+impl GitFS {
+    /// Wait until `bytes` may be read or written.
+    pub(super) fn throttle(&self, io: Io, bytes: u64) {
+        let (rate, iops) = {
+            let options = self.options_read();
+            let rate = match io {
+                Io::Read => options.read_rate,
+                Io::Write => options.write_rate,
+            };
+            (rate, options.iops)
+        };
+        let throttles = &self.inner.throttles;
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        if let Some(rate) = rate.filter(|&rate| rate > 0) {
+            let bucket = match io {
+                Io::Read => &throttles.read,
+                Io::Write => &throttles.write,
+            };
+            wait = wait.max(bucket.lock().unwrap_or_else(PoisonError::into_inner).take(rate, bytes, now));
+        }
+        if let Some(iops) = iops.filter(|&iops| iops > 0) {
+            wait = wait.max(throttles.ops.lock().unwrap_or_else(PoisonError::into_inner).take(iops, 1, now));
+        }
+        if wait > Duration::ZERO {
+            trace!(?io, bytes, ?wait, "throttled");
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_hold_a_second() {
+        let start = Instant::now();
+        let mut bucket = Bucket::default();
+        // A full second's worth to begin with.
+        assert_eq!(bucket.take(100, 100, start), Duration::ZERO);
+        assert_eq!(bucket.take(100, 50, start), Duration::from_millis(500));
+        // The debt is paid back over time.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(100, 0, later), Duration::ZERO);
+        // Idle time doesn't make for more than a second's worth.
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(bucket.take(100, 150, much_later), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,131 @@
+/// Dropping dirty files that are the same as their blob, so that the
+/// underlying dir doesn't fill up with copies of files that were only
+/// opened for writing, or saved unchanged by an editor.
+///
+/// A dirty file is dropped when it has the content and mode the blob
+/// of its name has in the tree of its dir, and nobody has it open: its
+/// copy is removed from the underlying dir and the entry is clean
+/// again, under the same ino.  Files that were chowned, or whose dir
+/// was made through the mount, are left as they are.  This happens on
+/// `tidy` in `ctl`, and with `Options::tidy`, whenever a file opened
+/// for writing is closed.
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use libc::{EIO, ENOENT, O_ACCMODE, O_RDONLY};
+
+use super::GitFS;
+use crate::error::Error;
+use crate::{EntryKind, Handle, Ino, Stamp};
+
+impl GitFS {
+    /// Drop every dirty file that is the same as its blob, and return
+    /// how many were.
+    pub fn tidy(&self) -> usize {
+        let dirty: Vec<_> = {
+            let inomap = self.inomap();
+            inomap.iter().filter(|(_, entry)| matches!(entry.u, EntryKind::DirtyFile)).map(|(ino, _)| ino).collect()
+        };
+        let mut dropped = 0;
+        for ino in dirty {
+            match self.tidy_file(ino) {
+                Ok(true) => dropped += 1,
+                Ok(false) => (),
+                // e.g. its blob is missing.
+                Err(e) => debug!(ino = u64::from(ino), %e, "cannot tidy"),
+            }
+        }
+        info!(dropped, "tidied the underlying dir");
+        dropped
+    }
+
+    /// With `Options::tidy`, drop the file of `handle`, just closed, if
+    /// it was opened for writing and is the same as its blob.
+    pub(super) fn tidy_on_release(&self, handle: &Handle) {
+        if handle.flags & O_ACCMODE == O_RDONLY || !self.options_read().tidy {
+            return;
+        }
+        if let Err(e) = self.tidy_file(handle.ino) {
+            debug!(ino = u64::from(handle.ino), %e, "cannot tidy");
+        }
+    }
+
+    /// Drop dirty file `ino` if it's the same as its blob, and tell
+    /// whether it was.
+    fn tidy_file(&self, ino: Ino) -> Result<bool, Error> {
+        // Compared without the lock, then dropped only if the file
+        // wasn't opened or changed meanwhile.
+        let (name, parent, dir, tree, path, stamp) = {
+            let inomap = self.inomap();
+            let entry = match inomap.get(ino) {
+                Some(entry) if matches!(entry.u, EntryKind::DirtyFile) => entry,
+                _ => return Ok(false),
+            };
+            let tree = match inomap.get(entry.parent).map(|parent| &parent.u) {
+                Some(EntryKind::GitTree { oid, .. }) => *oid,
+                _ => return Ok(false),
+            };
+            let dir = inomap.prefix(entry.parent).ok_or(Error::Errno(EIO))?;
+            let path = self.overlay_path(&inomap, ino)?;
+            let stamp = match self.unchanged_stamp(ino, &path)? {
+                Some(stamp) => stamp,
+                None => return Ok(false),
+            };
+            (entry.name.clone(), entry.parent, dir, tree, path, stamp)
+        };
+        let clean = self.walk_tree(parent, &dir, tree)?.remove(&name);
+        let same = match &clean {
+            Some(clean) => match &clean.u {
+                EntryKind::GitBlob { smudged, .. } if smudged.as_ref().is_some_and(|smudged| smudged.object.is_some()) => false,
+                EntryKind::GitBlob { oid, smudged } => {
+                    *self.mount_content(*oid, smudged.as_deref())? == *self.overlay_read(&path)?
+                }
+                _ => false,
+            },
+            None => false,
+        };
+        if !same {
+            return Ok(false);
+        }
+
+        let mut inomap = self.inomap();
+        let mode = inomap.get(ino).map(|entry| entry.perm.mode());
+        let clean = match clean {
+            Some(clean) if Some(clean.perm.mode()) == mode && self.unchanged_stamp(ino, &path)? == Some(stamp) => clean,
+            _ => return Ok(false),
+        };
+        match inomap.get(parent).map(|parent| &parent.u) {
+            Some(EntryKind::GitTree { oid, .. }) if *oid == tree => (),
+            _ => return Ok(false),
+        }
+        self.inner.underlying_dir.remove_file(&path)?;
+        let entry = inomap.get_mut(ino).ok_or(Error::Errno(ENOENT))?;
+        entry.u = clean.u;
+        entry.size = clean.size;
+        entry.atime = clean.atime;
+        entry.mtime = clean.mtime;
+        entry.ctime = clean.ctime;
+        entry.owner = None;
+        entry.flags = None;
+        entry.stamp = None;
+        debug!(?path, "dropped a dirty file the same as its blob");
+        Ok(true)
+    }
+
+    /// What the file of dirty `ino` at `path` in the underlying dir
+    /// looks like, or `None` if it's open, or can't be dropped.  Called
+    /// with the InoMap locked, which keeps it from being opened.
+    fn unchanged_stamp(&self, ino: Ino, path: &Path) -> Result<Option<Stamp>, Error> {
+        if self.handles().iter().any(|(_, handle)| handle.ino == ino) {
+            return Ok(None);
+        }
+        let metadata = self.inner.underlying_dir.metadata(path)?;
+        let stat = metadata.stat();
+        // Chowned, through the mount or when it was made from a clean
+        // file that was.
+        if (stat.st_uid, stat.st_gid) != unsafe { (libc::geteuid(), libc::getegid()) } {
+            return Ok(None);
+        }
+        Ok(Some(Stamp::from(stat)))
+    }
+}
@@ -0,0 +1,1148 @@
+/// A WebDAV (RFC 4918) server exposing the same tree as the FUSE mount
+/// (`--serve webdav`), for Finder, Explorer and davfs2, and for plain
+/// HTTP clients such as CI jobs fetching artifacts:
+///
+/// ```text
+/// curl http://localhost:8080/dir/b.txt
+/// ```
+///
+/// PUT, DELETE, MKCOL, COPY and MOVE go to the overlay as the FUSE
+/// handlers would, and are refused with 403 on a read-only mount
+/// (`--read-only`), which also leaves class 2 out of the `DAV` header:
+/// Finder mounts the server read-only then, rather than fail on LOCK.
+/// Locks are always granted, which leaves them to the clients, as with
+/// FUSE, and dead properties aren't kept, so PROPPATCH fails for each.
+/// There is no authentication; serve on localhost, or behind a proxy
+/// that does it.  Changes are audited with the uid of the server and
+/// pid 0, as HTTP doesn't tell who made them.
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType};
+use libc::{
+    EACCES, EDQUOT, EEXIST, EINVAL, EISDIR, ELOOP, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EPERM, EROFS, O_RDONLY, O_WRONLY,
+};
+
+use super::server::{self, normalize};
+use super::GitFS;
+use crate::error::Error;
+use crate::watch::Watcher;
+use crate::{Ino, MODE_FILE};
+
+/// The longest line accepted in the head of a request.
+const MAX_LINE: u64 = 8192;
+const MAX_HEADERS: usize = 100;
+/// The largest XML body accepted; those of WebDAV are small.
+const MAX_XML: u64 = 1 << 20;
+/// How much is read or written at a time.
+const IO_SIZE: u32 = 1 << 16;
+/// The timeout given with a lock, which is granted in name only.
+const LOCK_TIMEOUT: &str = "Second-3600";
+
+/// Tells the lock tokens given out apart.
+static LOCKS: AtomicU64 = AtomicU64::new(0);
+
+/// Serve `fs` over WebDAV at `addr`, until the returned watcher is
+/// dropped.
+pub(super) fn serve(fs: GitFS, addr: SocketAddr) -> Result<(Watcher, SocketAddr), Error> {
+    fs.mount_root()?;
+    Ok(server::listen("webdav", addr, move |stream| Connection { fs: fs.clone() }.run(stream))?)
+}
+
+struct Connection {
+    fs: GitFS,
+}
+
+pub(super) struct Request {
+    pub(super) method: String,
+    /// The path asked for, decoded and normalized.
+    pub(super) path: PathBuf,
+    /// The headers, with their names in lower case.
+    pub(super) headers: Vec<(String, String)>,
+    http10: bool,
+}
+
+impl Request {
+    pub(super) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.trim())
+    }
+
+    pub(super) fn keep_alive(&self) -> bool {
+        let connection = self.header("connection").map(str::to_ascii_lowercase);
+        match connection.as_deref() {
+            Some("close") => false,
+            Some("keep-alive") => true,
+            _ => !self.http10,
+        }
+    }
+}
+
+pub(super) struct Response {
+    pub(super) status: u16,
+    headers: Vec<(&'static str, String)>,
+    pub(super) body: Body,
+}
+
+pub(super) enum Body {
+    Bytes(Vec<u8>),
+    /// `len` bytes of a file opened for the response, from `offset`.
+    File { ino: Ino, fh: u64, offset: u64, len: u64 },
+}
+
+impl Response {
+    pub(super) fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body: Body::Bytes(vec![]),
+        }
+    }
+
+    pub(super) fn header(mut self, name: &'static str, value: String) -> Response {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn xml(status: u16, body: String) -> Response {
+        let mut response = Response::new(status).header("Content-Type", "application/xml; charset=utf-8".to_owned());
+        response.body = Body::Bytes(format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n{}", body).into_bytes());
+        response
+    }
+}
+
+/// Why a request failed: an error of the file system, or a status
+/// that tells more than the errno would.
+enum Failure {
+    Fs(Error),
+    Status(u16),
+}
+
+impl<E: Into<Error>> From<E> for Failure {
+    fn from(e: E) -> Failure {
+        Failure::Fs(e.into())
+    }
+}
+
+/// A missing parent is a conflict rather than not found.
+fn conflict(e: Error) -> Failure {
+    match e {
+        Error::Errno(ENOENT) | Error::Errno(ENOTDIR) => Failure::Status(409),
+        e => Failure::Fs(e),
+    }
+}
+
+/// Which properties PROPFIND asks for.
+enum Props {
+    All,
+    Names,
+    /// By namespace and name.
+    Some(Vec<(String, String)>),
+}
+
+/// The properties of every resource, all of them live.
+const LIVE_PROPS: &[&str] = &[
+    "creationdate",
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "resourcetype",
+    "supportedlock",
+];
+
+impl Connection {
+    /// Answer requests until the client hangs up.
+    fn run(&self, stream: &TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut writer = stream;
+        while let Some(request) = read_head(&mut reader)? {
+            let mut body = RequestBody::new(&request)?;
+            if request.header("expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue")) {
+                writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            }
+            let response = self.respond(&request, &mut body.reader(&mut reader));
+            // What wasn't read of the body is skipped to the next request.
+            io::copy(&mut body.reader(&mut reader), &mut io::sink())?;
+            let keep_alive = request.keep_alive();
+            send(&self.fs, writer, request.method == "HEAD", response, keep_alive)?;
+            if !keep_alive {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&self, request: &Request, body: &mut dyn Read) -> Response {
+        let op = op_name(&request.method);
+        let fs = &self.fs;
+        let _timer = fs.start_op(op, Ino::ROOT);
+        let span = debug_span!("webdav", op, path = %request.path.display(), status = tracing::field::Empty).entered();
+        fs.refresh_if_requested();
+        let response = match self.handle(op, request, body) {
+            Ok(response) => response,
+            Err(Failure::Status(status)) => Response::new(status),
+            Err(Failure::Fs(e)) => Response::new(match fs.errno(&e) {
+                ENOENT => 404,
+                EACCES | EPERM | EROFS => 403,
+                EEXIST => 405,
+                EISDIR | ENOTDIR | ENOTEMPTY => 409,
+                EINVAL => 400,
+                ELOOP => 508,
+                ENOSPC | EDQUOT => 507,
+                _ => 500,
+            }),
+        };
+        span.record("status", response.status);
+        response
+    }
+
+    fn handle(&self, op: &'static str, request: &Request, body: &mut dyn Read) -> Result<Response, Failure> {
+        let path = &request.path;
+        match op {
+            "options" => {
+                let class = if self.fs.options_read().read_only { "1" } else { "1, 2" };
+                Ok(Response::new(200)
+                    .header("DAV", class.to_owned())
+                    .header("Allow", "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, COPY, MOVE, PROPFIND, PROPPATCH, LOCK, UNLOCK".to_owned())
+                    .header("MS-Author-Via", "DAV".to_owned()))
+            }
+            "get" | "head" => self.get(request, op == "head"),
+            "put" => self.put(path, body),
+            "delete" => {
+                self.fs.resolve_path(path, false)?;
+                let (parent, name) = self.fs.resolve_parent(path).map_err(|_| Failure::Status(403))?;
+                self.remove_tree(parent, &name)?;
+                Ok(Response::new(204))
+            }
+            "mkcol" => {
+                if !read_xml(body)?.is_empty() {
+                    return Err(Failure::Status(415));
+                }
+                if self.fs.resolve_path(path, false).is_ok() {
+                    return Err(Failure::Status(405));
+                }
+                let (parent, name) = self.fs.resolve_parent(path).map_err(conflict)?;
+                self.fs.do_mkdir(parent, &name, 0o755)?;
+                self.audit("mkdir", || format!("{:?}", self.fs.child_path(parent.into(), &name)));
+                Ok(Response::new(201))
+            }
+            "copy" | "move" => self.copy_or_move(request, op == "move"),
+            "propfind" => self.propfind(request, body),
+            "proppatch" => {
+                let elements = parse_xml(&read_xml(body)?)?;
+                let attr = self.fs.do_getattr(self.fs.resolve_path(path, true)?)?;
+                // propertyupdate > set or remove > prop > the properties.
+                let names: Vec<_> = elements.into_iter().filter(|e| e.depth == 3).map(|e| (e.ns, e.name)).collect();
+                let mut xml = String::from("<D:multistatus xmlns:D=\"DAV:\"><D:response>");
+                let _ = write!(xml, "<D:href>{}</D:href>", href(path, attr.kind == FileType::Directory));
+                xml.push_str(&propstat(names.iter().map(|(ns, name)| prop_name(ns, name)), 403));
+                xml.push_str("</D:response></D:multistatus>");
+                Ok(Response::xml(207, xml))
+            }
+            "lock" => self.lock(request, body),
+            "unlock" => {
+                self.fs.resolve_path(path, true)?;
+                Ok(Response::new(204))
+            }
+            _ => Ok(Response::new(405)),
+        }
+    }
+
+    fn get(&self, request: &Request, head: bool) -> Result<Response, Failure> {
+        let ino = self.fs.resolve_path(&request.path, true)?;
+        let attr = self.fs.do_getattr(ino)?;
+        if attr.kind == FileType::Directory {
+            let mut html = String::new();
+            let title = escape(&format!("Index of {}", href(&request.path, true)));
+            let _ = write!(html, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body><h1>{0}</h1><ul>\n", title);
+            if request.path != Path::new("/") {
+                html.push_str("<li><a href=\"../\">../</a></li>\n");
+            }
+            self.fs.do_opendir(ino)?;
+            let mut listing = self.fs.do_readdir(ino)?;
+            listing.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, _, kind) in listing {
+                let dir = kind == FileType::Directory;
+                let name = format!("{}{}", name.to_string_lossy(), if dir { "/" } else { "" });
+                let _ = writeln!(html, "<li><a href=\"{}\">{}</a></li>", href(Path::new(&name), false), escape(&name));
+            }
+            html.push_str("</ul></body></html>\n");
+            let mut response = Response::new(200)
+                .header("Content-Type", "text/html; charset=utf-8".to_owned())
+                .header("Last-Modified", http_date(attr.mtime));
+            response.body = Body::Bytes(html.into_bytes());
+            return Ok(response);
+        }
+        let (status, offset, len) = match request.header("range").map(|range| parse_range(range, attr.size)) {
+            None | Some(Some(None)) => (200, 0, attr.size),
+            Some(Some(Some((start, end)))) => (206, start, end - start + 1),
+            Some(None) => {
+                return Ok(Response::new(416).header("Content-Range", format!("bytes */{}", attr.size)));
+            }
+        };
+        let mut response = Response::new(status)
+            .header("Content-Type", content_type(&request.path).to_owned())
+            .header("Last-Modified", http_date(attr.mtime))
+            .header("ETag", etag(&attr))
+            .header("Accept-Ranges", "bytes".to_owned());
+        if status == 206 {
+            response = response.header("Content-Range", format!("bytes {}-{}/{}", offset, offset + len - 1, attr.size));
+        }
+        response.body = if head {
+            Body::File { ino, fh: 0, offset, len }
+        } else {
+            Body::File {
+                ino,
+                fh: self.fs.do_open(ino, O_RDONLY)?,
+                offset,
+                len,
+            }
+        };
+        Ok(response)
+    }
+
+    fn put(&self, path: &Path, body: &mut dyn Read) -> Result<Response, Failure> {
+        let (ino, fh, created) = match self.fs.resolve_path(path, true) {
+            Ok(ino) => {
+                if self.fs.do_getattr(ino)?.kind == FileType::Directory {
+                    return Err(Failure::Status(405));
+                }
+                let fh = self.fs.do_open(ino, O_WRONLY)?;
+                if let Err(e) = self.fs.truncate_handle(ino, fh) {
+                    self.fs.release_handle(ino, fh);
+                    return Err(e.into());
+                }
+                self.audit("truncate", || format!("{:?} size=0", self.fs.path_of(ino)));
+                (ino, fh, false)
+            }
+            Err(Error::Errno(ENOENT)) => {
+                let (parent, name) = self.fs.resolve_parent(path).map_err(conflict)?;
+                let (attr, fh) = self.fs.do_create(parent, &name, MODE_FILE | 0o644)?;
+                self.audit("create", || format!("{:?}", self.fs.child_path(parent.into(), &name)));
+                (attr.ino.into(), fh, true)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let written = self.write_all(ino, fh, body);
+        self.fs.release_handle(ino, fh);
+        let written = written?;
+        self.audit("write", || format!("{:?} offset=0 size={}", self.fs.path_of(ino), written));
+        Ok(Response::new(if created { 201 } else { 204 }))
+    }
+
+    /// Write what `input` holds to an open file, from the start.
+    fn write_all(&self, ino: Ino, fh: u64, input: &mut dyn Read) -> Result<u64, Error> {
+        let mut buf = vec![0; IO_SIZE as usize];
+        let mut offset = 0;
+        loop {
+            let n = input.read(&mut buf)?;
+            if n == 0 {
+                return Ok(offset);
+            }
+            let mut written = 0;
+            while written < n {
+                written += self.fs.do_write(ino, fh, offset + written as u64, &buf[written..n])? as usize;
+            }
+            offset += n as u64;
+        }
+    }
+
+    /// Remove `name` from `parent`, with everything in it.
+    fn remove_tree(&self, parent: Ino, name: &OsStr) -> Result<(), Error> {
+        let attr = self.fs.do_lookup(parent, name)?;
+        let dir = attr.kind == FileType::Directory;
+        if dir {
+            let ino = attr.ino.into();
+            self.fs.do_opendir(ino)?;
+            for (child, _, _) in self.fs.do_readdir(ino)? {
+                self.remove_tree(ino, &child)?;
+            }
+        }
+        let path = self.fs.child_path(parent.into(), name);
+        self.fs.do_remove(parent, name)?;
+        self.audit(if dir { "rmdir" } else { "unlink" }, || format!("{:?}", path));
+        Ok(())
+    }
+
+    fn copy_or_move(&self, request: &Request, rename: bool) -> Result<Response, Failure> {
+        let from = &request.path;
+        let to = request.header("destination").and_then(request_path).ok_or(Failure::Status(400))?;
+        // Nor into itself.
+        if to.starts_with(from) {
+            return Err(Failure::Status(403));
+        }
+        self.fs.resolve_path(from, false)?;
+        let exists = self.fs.resolve_path(&to, false).is_ok();
+        if exists {
+            if request.header("overwrite").is_some_and(|overwrite| overwrite.eq_ignore_ascii_case("F")) {
+                return Err(Failure::Status(412));
+            }
+            let (parent, name) = self.fs.resolve_parent(&to)?;
+            self.remove_tree(parent, &name)?;
+        }
+        let (newp, newname) = self.fs.resolve_parent(&to).map_err(conflict)?;
+        if rename {
+            let (oldp, name) = self.fs.resolve_parent(from)?;
+            self.fs.do_rename(oldp, &name, newp, &newname)?;
+            self.audit("rename", || format!("{:?} {:?}", self.fs.child_path(oldp.into(), &name), self.fs.child_path(newp.into(), &newname)));
+        } else {
+            let deep = request.header("depth") != Some("0");
+            self.copy_tree(from, newp, &newname, deep)?;
+        }
+        Ok(Response::new(if exists { 204 } else { 201 }))
+    }
+
+    /// Copy what is at `from`, following symlinks, to `name` in
+    /// `parent`, and for a dir what is in it with `deep`.
+    fn copy_tree(&self, from: &Path, parent: Ino, name: &OsStr, deep: bool) -> Result<(), Error> {
+        let ino = self.fs.resolve_path(from, true)?;
+        let attr = self.fs.do_getattr(ino)?;
+        if attr.kind == FileType::Directory {
+            let copy: Ino = self.fs.do_mkdir(parent, name, attr.perm.into())?.ino.into();
+            self.audit("mkdir", || format!("{:?}", self.fs.child_path(parent.into(), name)));
+            if deep {
+                self.fs.do_opendir(ino)?;
+                self.fs.do_opendir(copy)?;
+                for (child, _, _) in self.fs.do_readdir(ino)? {
+                    self.copy_tree(&from.join(&child), copy, &child, deep)?;
+                }
+            }
+            return Ok(());
+        }
+        let input = self.fs.do_open(ino, O_RDONLY)?;
+        let output = self.fs.do_create(parent, name, MODE_FILE | u32::from(attr.perm));
+        let result = output.map(|(copy, output)| {
+            let copy: Ino = copy.ino.into();
+            self.audit("create", || format!("{:?}", self.fs.child_path(parent.into(), name)));
+            let mut source = FileReader { fs: &self.fs, ino, fh: input, offset: 0 };
+            let written = self.write_all(copy, output, &mut source);
+            self.fs.release_handle(copy, output);
+            written.map(|written| self.audit("write", || format!("{:?} offset=0 size={}", self.fs.path_of(copy), written)))
+        });
+        self.fs.release_handle(ino, input);
+        result?
+    }
+
+    fn propfind(&self, request: &Request, body: &mut dyn Read) -> Result<Response, Failure> {
+        let deep = match request.header("depth") {
+            Some("0") => false,
+            Some("1") => true,
+            _ => {
+                let xml = "<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>";
+                return Ok(Response::xml(403, xml.to_owned()));
+            }
+        };
+        let elements = parse_xml(&read_xml(body)?)?;
+        let props = if elements.iter().any(|e| e.depth == 1 && e.is_dav("propname")) {
+            Props::Names
+        } else if let Some(prop) = elements.iter().position(|e| e.depth == 1 && e.is_dav("prop")) {
+            let names = elements[prop + 1..].iter().take_while(|e| e.depth > 1).filter(|e| e.depth == 2);
+            Props::Some(names.map(|e| (e.ns.clone(), e.name.clone())).collect())
+        } else {
+            Props::All
+        };
+        let path = &request.path;
+        let ino = self.fs.resolve_path(path, true)?;
+        let attr = self.fs.do_getattr(ino)?;
+        let mut xml = String::from("<D:multistatus xmlns:D=\"DAV:\">");
+        xml.push_str(&self.prop_response(path, &attr, &props));
+        if deep && attr.kind == FileType::Directory {
+            self.fs.do_opendir(ino)?;
+            let mut listing = self.fs.do_readdir(ino)?;
+            listing.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, child, kind) in listing {
+                let path = path.join(&name);
+                let attr = match kind {
+                    // Shown as what they point to, as HTTP knows no symlinks.
+                    FileType::Symlink => self.fs.resolve_path(&path, true).and_then(|ino| self.fs.do_getattr(ino)),
+                    _ => self.fs.do_getattr(child),
+                };
+                // Broken symlinks, and entries gone since, are left out.
+                if let Ok(attr) = attr {
+                    xml.push_str(&self.prop_response(&path, &attr, &props));
+                }
+            }
+        }
+        xml.push_str("</D:multistatus>");
+        Ok(Response::xml(207, xml))
+    }
+
+    /// The `response` element of PROPFIND for one resource.
+    fn prop_response(&self, path: &Path, attr: &FileAttr, props: &Props) -> String {
+        let dir = attr.kind == FileType::Directory;
+        let mut xml = format!("<D:response><D:href>{}</D:href>", href(path, dir));
+        match props {
+            Props::All => {
+                let found = LIVE_PROPS.iter().filter_map(|name| self.live_prop(name, path, attr));
+                xml.push_str(&propstat(found, 200));
+            }
+            Props::Names => {
+                let names = LIVE_PROPS.iter().filter(|name| self.live_prop(name, path, attr).is_some());
+                xml.push_str(&propstat(names.map(|name| format!("<D:{}/>", name)), 200));
+            }
+            Props::Some(names) => {
+                let (mut found, mut missing) = (vec![], vec![]);
+                for (ns, name) in names {
+                    match self.live_prop(name, path, attr).filter(|_| ns == "DAV:") {
+                        Some(prop) => found.push(prop),
+                        None => missing.push(prop_name(ns, name)),
+                    }
+                }
+                if !found.is_empty() {
+                    xml.push_str(&propstat(found.into_iter(), 200));
+                }
+                if !missing.is_empty() {
+                    xml.push_str(&propstat(missing.into_iter(), 404));
+                }
+            }
+        }
+        xml.push_str("</D:response>");
+        xml
+    }
+
+    /// A live property of DAV:, with its value, if the resource has it.
+    fn live_prop(&self, name: &str, path: &Path, attr: &FileAttr) -> Option<String> {
+        let dir = attr.kind == FileType::Directory;
+        let value = match name {
+            "creationdate" => time::at_utc(timespec(attr.crtime)).rfc3339().to_string(),
+            "displayname" => escape(&path.file_name().unwrap_or_default().to_string_lossy()),
+            "getcontentlength" if !dir => attr.size.to_string(),
+            "getcontenttype" if !dir => content_type(path).to_owned(),
+            "getetag" if !dir => escape(&etag(attr)),
+            "getlastmodified" => http_date(attr.mtime),
+            "resourcetype" if dir => "<D:collection/>".to_owned(),
+            "resourcetype" => String::new(),
+            "supportedlock" if self.fs.options_read().read_only => String::new(),
+            "supportedlock" => "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+                 <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>"
+                .to_owned(),
+            _ => return None,
+        };
+        Some(format!("<D:{0}>{1}</D:{0}>", name, value))
+    }
+
+    fn lock(&self, request: &Request, body: &mut dyn Read) -> Result<Response, Failure> {
+        let elements = parse_xml(&read_xml(body)?)?;
+        let path = &request.path;
+        // LOCK on nothing makes an empty file, which Finder relies on.
+        let (status, dir) = match self.fs.resolve_path(path, true) {
+            Ok(ino) => {
+                // A write lock, on what can't be written either.
+                self.fs.check_rules(|| self.fs.inomap().prefix(ino), true)?;
+                (200, self.fs.do_getattr(ino)?.kind == FileType::Directory)
+            }
+            Err(Error::Errno(ENOENT)) => {
+                let (parent, name) = self.fs.resolve_parent(path).map_err(conflict)?;
+                let (attr, fh) = self.fs.do_create(parent, &name, MODE_FILE | 0o644)?;
+                self.fs.release_handle(attr.ino.into(), fh);
+                self.audit("create", || format!("{:?}", self.fs.child_path(parent.into(), &name)));
+                (201, false)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // A refresh has no body, and names the lock in `If`.
+        let token = match request.header("if").and_then(|cond| cond.split('<').nth(1)?.split('>').next()) {
+            Some(token) if elements.is_empty() => token.to_owned(),
+            _ => format!("opaquelocktoken:{:016x}-{}", nanos(SystemTime::now()), LOCKS.fetch_add(1, Ordering::Relaxed)),
+        };
+        let scope = if elements.iter().any(|e| e.is_dav("shared")) { "shared" } else { "exclusive" };
+        let depth = if request.header("depth") == Some("0") { "0" } else { "infinity" };
+        let mut xml = String::from("<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery><D:activelock>");
+        let _ = write!(
+            xml,
+            "<D:locktype><D:write/></D:locktype><D:lockscope><D:{}/></D:lockscope><D:depth>{}</D:depth>\
+             <D:timeout>{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot><D:href>{}</D:href></D:lockroot>",
+            scope,
+            depth,
+            LOCK_TIMEOUT,
+            escape(&token),
+            href(path, dir)
+        );
+        xml.push_str("</D:activelock></D:lockdiscovery></D:prop>");
+        Ok(Response::xml(status, xml).header("Lock-Token", format!("<{}>", token)))
+    }
+
+    fn audit<F: FnOnce() -> String>(&self, op: &str, describe: F) {
+        self.fs.audit_as(unsafe { libc::getuid() }, 0, op, describe)
+    }
+}
+
+/// Send `response`, and release the file it sends if any.
+pub(super) fn send(fs: &GitFS, writer: &TcpStream, head: bool, response: Response, keep_alive: bool) -> io::Result<()> {
+    let len = match response.body {
+        Body::Bytes(ref bytes) => bytes.len() as u64,
+        Body::File { len, .. } => len,
+    };
+    let mut out = format!(
+        "HTTP/1.1 {} {}\r\nServer: gitfs\r\nDate: {}\r\nContent-Length: {}\r\n",
+        response.status,
+        reason(response.status),
+        http_date(SystemTime::now()),
+        len
+    );
+    for (name, value) in &response.headers {
+        let _ = write!(out, "{}: {}\r\n", name, value);
+    }
+    if !keep_alive {
+        out.push_str("Connection: close\r\n");
+    }
+    out.push_str("\r\n");
+    let mut writer = io::BufWriter::new(writer);
+    writer.write_all(out.as_bytes())?;
+    match response.body {
+        Body::Bytes(bytes) if !head => writer.write_all(&bytes)?,
+        Body::Bytes(_) => (),
+        Body::File { .. } if head => (),
+        Body::File { ino, fh, offset, len } => {
+            let mut file = FileReader { fs, ino, fh, offset };
+            let sent = io::copy(&mut file.by_ref().take(len), &mut writer);
+            fs.release_handle(ino, fh);
+            // The file shrank meanwhile; the length was promised.
+            if sent? < len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while sent"));
+            }
+        }
+    }
+    writer.flush()
+}
+
+/// Reads an open file through `do_read`.
+struct FileReader<'a> {
+    fs: &'a GitFS,
+    ino: Ino,
+    fh: u64,
+    offset: u64,
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = buf.len().min(IO_SIZE as usize) as u32;
+        let data = self
+            .fs
+            .do_read(self.ino, self.fh, self.offset, size)
+            .map_err(|e| io::Error::from_raw_os_error(self.fs.errno(&e)))?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.offset += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+/// Where the body of a request stands: how much is left of the body,
+/// or of the current chunk when it's chunked.
+pub(super) struct RequestBody {
+    remaining: u64,
+    chunked: bool,
+    done: bool,
+}
+
+impl RequestBody {
+    pub(super) fn new(request: &Request) -> io::Result<RequestBody> {
+        let chunked = request.header("transfer-encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked"));
+        let remaining = match request.header("content-length") {
+            Some(len) if !chunked => len.parse().map_err(|_| invalid("bad Content-Length"))?,
+            _ => 0,
+        };
+        Ok(RequestBody {
+            remaining,
+            chunked,
+            done: !chunked && remaining == 0,
+        })
+    }
+
+    pub(super) fn reader<'a, R: BufRead>(&'a mut self, reader: &'a mut R) -> BodyReader<'a, R> {
+        BodyReader { body: self, reader }
+    }
+}
+
+pub(super) struct BodyReader<'a, R> {
+    body: &'a mut RequestBody,
+    reader: &'a mut R,
+}
+
+impl<R: BufRead> Read for BodyReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let body = &mut *self.body;
+        if body.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if body.chunked && body.remaining == 0 {
+            let line = read_line(self.reader)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            body.remaining = u64::from_str_radix(size, 16).map_err(|_| invalid("bad chunk size"))?;
+            if body.remaining == 0 {
+                // The trailers are of no interest.
+                while !read_line(self.reader)?.is_empty() {}
+                body.done = true;
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(body.remaining.min(usize::MAX as u64) as usize);
+        let n = self.reader.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        body.remaining -= n as u64;
+        if body.remaining == 0 {
+            if body.chunked {
+                read_line(self.reader)?;
+            } else {
+                body.done = true;
+            }
+        }
+        Ok(n)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// A line of the head of a request, without its end.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    reader.by_ref().take(MAX_LINE).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(if line.is_empty() { io::ErrorKind::UnexpectedEof.into() } else { invalid("line too long") });
+    }
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+/// Read the request line and the headers, or nothing when the client
+/// hangs up between requests.
+pub(super) fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    // Empty lines may come before a request.
+    while line.trim().is_empty() {
+        line.clear();
+        if reader.by_ref().take(MAX_LINE).read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => (method, target, version),
+        _ => return Err(invalid("bad request line")),
+    };
+    let path = if target == "*" { Some(PathBuf::from("/")) } else { request_path(target) };
+    let mut headers = vec![];
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid("too many headers"));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("bad header"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+    }
+    Ok(Some(Request {
+        method: method.to_ascii_uppercase(),
+        path: path.ok_or_else(|| invalid("bad request target"))?,
+        headers,
+        http10: version == "HTTP/1.0",
+    }))
+}
+
+/// The path of a request target or a `Destination`, which may be an
+/// absolute URL, decoded and normalized.
+fn request_path(target: &str) -> Option<PathBuf> {
+    let target = match target.find("://") {
+        Some(scheme) => {
+            let authority = &target[scheme + 3..];
+            &authority[authority.find('/').unwrap_or(authority.len())..]
+        }
+        None => target,
+    };
+    let target = target.split(&['?', '#'][..]).next()?;
+    if !target.starts_with('/') {
+        return None;
+    }
+    let (mut path, mut bytes) = (vec![], target.bytes());
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            path.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            path.push(b);
+        }
+    }
+    Some(normalize(Path::new(OsStr::from_bytes(&path))))
+}
+
+/// The href of a path, percent-encoded, with a `/` at the end for a
+/// dir.
+fn href(path: &Path, dir: bool) -> String {
+    let mut href = String::new();
+    for &b in path.as_os_str().as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => href.push(b as char),
+            b => {
+                let _ = write!(href, "%{:02X}", b);
+            }
+        }
+    }
+    if dir && !href.ends_with('/') {
+        href.push('/');
+    }
+    href
+}
+
+/// `text` escaped for XML and HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// A `propstat` element with the given properties and status.
+fn propstat<I: Iterator<Item = String>>(props: I, status: u16) -> String {
+    let props: String = props.collect();
+    format!(
+        "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {} {}</D:status></D:propstat>",
+        props,
+        status,
+        reason(status)
+    )
+}
+
+/// An empty element naming a property, in its namespace.
+fn prop_name(ns: &str, name: &str) -> String {
+    if ns == "DAV:" {
+        format!("<D:{}/>", name)
+    } else {
+        format!("<{} xmlns=\"{}\"/>", name, escape(ns))
+    }
+}
+
+/// The byte range asked for by `Range`, inclusive: `Some(None)` for
+/// one that isn't understood, as the whole file is sent then, and
+/// `None` for one outside the file.
+pub(super) fn parse_range(range: &str, size: u64) -> Option<Option<(u64, u64)>> {
+    let spec = match range.strip_prefix("bytes=") {
+        // Several ranges would need a multipart body.
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Some(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Some(None),
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        _ => return Some(None),
+    };
+    if start >= size {
+        return None;
+    }
+    Some(Some((start, end)))
+}
+
+/// The type of a file by its extension, for browsers and Finder.
+pub(super) fn content_type(path: &Path) -> &'static str {
+    let ext = path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("txt") | Some("md") | Some("rs") | Some("c") | Some("h") | Some("py") | Some("sh") | Some("toml") => {
+            "text/plain; charset=utf-8"
+        }
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") | Some("tgz") => "application/gzip",
+        Some("tar") => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+pub(super) fn etag(attr: &FileAttr) -> String {
+    format!("\"{:x}-{:x}-{:x}\"", attr.ino, attr.size, nanos(attr.mtime))
+}
+
+fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+fn timespec(time: SystemTime) -> time::Timespec {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    time::Timespec::new(since.as_secs() as i64, 0)
+}
+
+pub(super) fn http_date(time: SystemTime) -> String {
+    time::at_utc(timespec(time)).rfc822().to_string()
+}
+
+/// Read an XML body whole.
+fn read_xml(body: &mut dyn Read) -> Result<Vec<u8>, Failure> {
+    let mut xml = vec![];
+    body.take(MAX_XML + 1).read_to_end(&mut xml)?;
+    if xml.len() as u64 > MAX_XML {
+        return Err(Failure::Status(413));
+    }
+    Ok(xml)
+}
+
+/// An element of an XML body, with how deep it is.
+struct Element {
+    ns: String,
+    name: String,
+    depth: usize,
+}
+
+impl Element {
+    fn is_dav(&self, name: &str) -> bool {
+        self.ns == "DAV:" && self.name == name
+    }
+}
+
+/// The elements of an XML body, in order, which is all WebDAV needs of
+/// them: text, and so entities, are skipped.
+fn parse_xml(xml: &[u8]) -> Result<Vec<Element>, Failure> {
+    let xml = std::str::from_utf8(xml).map_err(|_| Failure::Status(400))?;
+    let bad = || Failure::Status(400);
+    let mut elements = vec![];
+    // The namespaces declared by each element still open.
+    let mut scopes: Vec<Vec<(String, String)>> = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let skip_to = if rest.starts_with("!--") {
+            Some("-->")
+        } else if rest.starts_with("![CDATA[") {
+            Some("]]>")
+        } else if rest.starts_with('?') || rest.starts_with('!') {
+            Some(">")
+        } else {
+            None
+        };
+        if let Some(end) = skip_to {
+            rest = &rest[rest.find(end).ok_or_else(bad)? + end.len()..];
+            continue;
+        }
+        if let Some(end_tag) = rest.strip_prefix('/') {
+            scopes.pop().ok_or_else(bad)?;
+            rest = &end_tag[end_tag.find('>').ok_or_else(bad)? + 1..];
+            continue;
+        }
+        // The end of the tag, outside the values of attributes.
+        let mut quote = None;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| match quote {
+                Some(q) if c == q => {
+                    quote = None;
+                    false
+                }
+                Some(_) => false,
+                None if c == '"' || c == '\'' => {
+                    quote = Some(c);
+                    false
+                }
+                None => c == '>',
+            })
+            .ok_or_else(bad)?
+            .0;
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        let empty = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let qname_end = tag.find(|c: char| c.is_whitespace()).unwrap_or(tag.len());
+        let (qname, mut attrs) = (&tag[..qname_end], &tag[qname_end..]);
+        let mut declared = vec![];
+        while let Some(eq) = attrs.find('=') {
+            let attr = attrs[..eq].trim();
+            let value = attrs[eq + 1..].trim_start();
+            let q = value.chars().next().ok_or_else(bad)?;
+            let value_end = value[1..].find(q).ok_or_else(bad)? + 1;
+            if attr == "xmlns" {
+                declared.push((String::new(), value[1..value_end].to_owned()));
+            } else if let Some(prefix) = attr.strip_prefix("xmlns:") {
+                declared.push((prefix.to_owned(), value[1..value_end].to_owned()));
+            }
+            attrs = &value[value_end + 1..];
+        }
+        let (prefix, name) = qname.split_once(':').unwrap_or(("", qname));
+        let ns = declared
+            .iter()
+            .chain(scopes.iter().rev().flatten())
+            .find(|(p, _)| p == prefix)
+            .map(|(_, ns)| ns.clone())
+            .unwrap_or_default();
+        elements.push(Element {
+            ns,
+            name: name.to_owned(),
+            depth: scopes.len(),
+        });
+        if !empty {
+            scopes.push(declared);
+        }
+    }
+    Ok(elements)
+}
+
+/// The name under which a request is counted and logged.
+fn op_name(method: &str) -> &'static str {
+    match method {
+        "OPTIONS" => "options",
+        "GET" => "get",
+        "HEAD" => "head",
+        "PUT" => "put",
+        "DELETE" => "delete",
+        "MKCOL" => "mkcol",
+        "COPY" => "copy",
+        "MOVE" => "move",
+        "PROPFIND" => "propfind",
+        "PROPPATCH" => "proppatch",
+        "LOCK" => "lock",
+        "UNLOCK" => "unlock",
+        _ => "unknown",
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        507 => "Insufficient Storage",
+        508 => "Loop Detected",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fs_for_test;
+
+    /// Serve a repo with `a.txt` and `dir/b.txt`.
+    fn serve(name: &str) -> (PathBuf, GitFS, Watcher, SocketAddr) {
+        let (root, fs) = fs_for_test(&format!("webdav-{}", name));
+        let (server, addr) = fs.serve_webdav("127.0.0.1:0".parse().unwrap()).unwrap();
+        (root, fs, server, addr)
+    }
+
+    /// Make a request on a connection of its own, and return the
+    /// status, the head and the body of the response.
+    fn call(addr: SocketAddr, head: &str, body: &[u8]) -> (u16, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut request = format!("{}\r\nHost: localhost\r\nConnection: close\r\n\r\n", head).into_bytes();
+        request.extend_from_slice(body);
+        stream.write_all(&request).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        let status = head[9..12].parse().unwrap();
+        (status, head, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn files_are_listed_and_read() {
+        let (root, _fs, _server, addr) = serve("read");
+        let (status, head, _) = call(addr, "OPTIONS / HTTP/1.1", b"");
+        assert_eq!(status, 200);
+        assert!(head.contains("DAV: 1, 2\r\n"), "{}", head);
+
+        let (status, _, body) = call(addr, "PROPFIND / HTTP/1.1\r\nDepth: 1", b"");
+        assert_eq!(status, 207);
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("<D:href>/a.txt</D:href>"), "{}", body);
+        assert!(body.contains("<D:getcontentlength>11</D:getcontentlength>"), "{}", body);
+        assert!(body.contains("<D:href>/dir/</D:href>"), "{}", body);
+        assert!(body.contains("<D:resourcetype><D:collection/></D:resourcetype>"), "{}", body);
+        assert!(!body.contains(".gitfs"), "{}", body);
+        assert_eq!(call(addr, "PROPFIND / HTTP/1.1\r\nDepth: infinity", b"").0, 403);
+
+        let xml = br#"<?xml version="1.0"?><propfind xmlns="DAV:" xmlns:x="urn:x"><prop><getcontentlength/><x:color/></prop></propfind>"#;
+        let (status, _, body) = call(addr, &format!("PROPFIND /a.txt HTTP/1.1\r\nDepth: 0\r\nContent-Length: {}", xml.len()), xml);
+        assert_eq!(status, 207);
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("<D:prop><D:getcontentlength>11</D:getcontentlength></D:prop><D:status>HTTP/1.1 200 OK"), "{}", body);
+        assert!(body.contains("<D:prop><color xmlns=\"urn:x\"/></D:prop><D:status>HTTP/1.1 404 Not Found"), "{}", body);
+        assert!(!body.contains("getlastmodified"), "{}", body);
+
+        let (status, _, body) = call(addr, "GET /dir/b.txt HTTP/1.1", b"");
+        assert_eq!((status, &body[..]), (200, &b"in a dir"[..]));
+        let (status, head, body) = call(addr, "GET /a.txt HTTP/1.1\r\nRange: bytes=6-", b"");
+        assert_eq!((status, &body[..]), (206, &b"world"[..]));
+        assert!(head.contains("Content-Range: bytes 6-10/11\r\n"), "{}", head);
+        let (status, _, body) = call(addr, "HEAD /a.txt HTTP/1.1", b"");
+        assert_eq!((status, body.len()), (200, 0));
+        assert_eq!(call(addr, "GET /a.txt HTTP/1.1\r\nRange: bytes=20-", b"").0, 416);
+        assert_eq!(call(addr, "GET /nope HTTP/1.1", b"").0, 404);
+        let (_, _, body) = call(addr, "GET /dir/ HTTP/1.1", b"");
+        assert!(String::from_utf8(body).unwrap().contains("<a href=\"b.txt\">b.txt</a>"));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn files_are_put_moved_and_deleted() {
+        let (root, _fs, _server, addr) = serve("write");
+        let chunked = b"5\r\nhello\r\n6\r\n there\r\n0\r\n\r\n";
+        assert_eq!(call(addr, "PUT /new%20file.txt HTTP/1.1\r\nTransfer-Encoding: chunked", chunked).0, 201);
+        assert_eq!(call(addr, "GET /new%20file.txt HTTP/1.1", b"").2, b"hello there");
+        assert_eq!(call(addr, "PUT /a.txt HTTP/1.1\r\nContent-Length: 3", b"new").0, 204);
+        assert_eq!(std::fs::read(root.join("overlay/a.txt")).unwrap(), b"new");
+        assert_eq!(call(addr, "PUT /nope/a.txt HTTP/1.1\r\nContent-Length: 0", b"").0, 409);
+
+        assert_eq!(call(addr, "MKCOL /sub HTTP/1.1", b"").0, 201);
+        assert_eq!(call(addr, "MKCOL /sub HTTP/1.1", b"").0, 405);
+        let moved = "MOVE /new%20file.txt HTTP/1.1\r\nDestination: http://localhost/sub/new.txt";
+        assert_eq!(call(addr, moved, b"").0, 201);
+        assert_eq!(std::fs::read(root.join("overlay/sub/new.txt")).unwrap(), b"hello there");
+        let copied = "COPY /sub HTTP/1.1\r\nDestination: /copy/";
+        assert_eq!(call(addr, copied, b"").0, 201);
+        assert_eq!(std::fs::read(root.join("overlay/copy/new.txt")).unwrap(), b"hello there");
+        let copied = "COPY /a.txt HTTP/1.1\r\nDestination: /copy/new.txt\r\nOverwrite: F";
+        assert_eq!(call(addr, copied, b"").0, 412);
+
+        let (status, head, _) = call(addr, "LOCK /locked.txt HTTP/1.1\r\nContent-Length: 0", b"");
+        assert_eq!(status, 201);
+        assert!(head.contains("Lock-Token: <opaquelocktoken:"), "{}", head);
+        assert_eq!(call(addr, "GET /locked.txt HTTP/1.1", b"").0, 200);
+
+        assert_eq!(call(addr, "DELETE /sub HTTP/1.1", b"").0, 204);
+        assert!(!root.join("overlay/sub").exists());
+        assert_eq!(call(addr, "DELETE /sub HTTP/1.1", b"").0, 404);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn read_only_mounts_refuse_changes() {
+        let (root, fs, _server, addr) = serve("ro");
+        fs.options().write().unwrap().read_only = true;
+        assert!(call(addr, "OPTIONS / HTTP/1.1", b"").1.contains("DAV: 1\r\n"));
+        assert_eq!(call(addr, "PUT /a.txt HTTP/1.1\r\nContent-Length: 3", b"new").0, 403);
+        assert_eq!(call(addr, "DELETE /a.txt HTTP/1.1", b"").0, 403);
+        assert_eq!(call(addr, "LOCK /a.txt HTTP/1.1", b"").0, 403);
+        assert_eq!(call(addr, "GET /a.txt HTTP/1.1", b"").2, b"hello world");
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,188 @@
+/// The `security.*` and `system.*` xattrs, which gitfs doesn't make
+/// up, so that `ls -Z`, `getcap` and container runtimes get sensible
+/// answers from the mount.
+///
+/// Dirty entries have those of their underlying files: SELinux labels
+/// and file capabilities are read from, and set in, the underlying
+/// dir, which may refuse them (EPERM).  Git-backed entries have none,
+/// as a checkout would, and can't be given any (EOPNOTSUPP), rather
+/// than being materialized for a relabel of the whole tree.  There's
+/// never a `system.*` xattr, POSIX ACLs included: modes are all there
+/// is.
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use libc::{c_int, ENOENT, EOPNOTSUPP, O_NONBLOCK, O_RDONLY};
+
+use super::{control, open_at, GitFS, ENOATTR};
+use crate::error::Error;
+use crate::{EntryKind, Ino};
+
+/// Whether `name` is in the namespace kept in the underlying dir.
+pub(super) fn is_security(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"security.")
+}
+
+pub(super) fn is_system(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b"system.")
+}
+
+impl GitFS {
+    pub(super) fn security_xattr(&self, ino: Ino, name: &OsStr) -> Result<Vec<u8>, Error> {
+        match self.underlying_file(ino)? {
+            Some(file) => Ok(fgetxattr(&file, name)?),
+            None => Err(Error::Errno(ENOATTR)),
+        }
+    }
+
+    /// The names of the `security.*` xattrs, each followed by NUL.
+    pub(super) fn security_xattr_names(&self, ino: Ino) -> Result<Vec<u8>, Error> {
+        let names = match self.underlying_file(ino)?.map(|file| flistxattr(&file)) {
+            Some(Ok(names)) => names,
+            // The underlying dir keeps no xattrs.
+            Some(Err(e)) if e.raw_os_error() == Some(EOPNOTSUPP) => return Ok(vec![]),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Ok(vec![]),
+        };
+        let mut security = vec![];
+        for name in names.split(|&c| c == 0).filter(|name| is_security(OsStr::from_bytes(name))) {
+            security.extend_from_slice(name);
+            security.push(0);
+        }
+        Ok(security)
+    }
+
+    /// Set an xattr, with the `XATTR_CREATE` or `XATTR_REPLACE` of
+    /// `flags`.  Only `security.*` xattrs of dirty entries can be.
+    pub(super) fn do_setxattr(&self, ino: Ino, name: &OsStr, value: &[u8], flags: c_int) -> Result<(), Error> {
+        self.check_rules(|| self.inomap().prefix(ino), true)?;
+        if !is_security(name) {
+            return Err(Error::Errno(EOPNOTSUPP));
+        }
+        self.unshare(&self.inomap(), ino)?;
+        match self.underlying_file(ino)? {
+            Some(file) => Ok(fsetxattr(&file, name, value, flags)?),
+            None => Err(Error::Errno(EOPNOTSUPP)),
+        }
+    }
+
+    pub(super) fn do_removexattr(&self, ino: Ino, name: &OsStr) -> Result<(), Error> {
+        self.check_rules(|| self.inomap().prefix(ino), true)?;
+        if !is_security(name) {
+            return Err(Error::Errno(EOPNOTSUPP));
+        }
+        self.unshare(&self.inomap(), ino)?;
+        match self.underlying_file(ino)? {
+            Some(file) => Ok(fremovexattr(&file, name)?),
+            None => Err(Error::Errno(ENOATTR)),
+        }
+    }
+
+    /// The underlying file of a dirty entry, opened for its xattrs, or
+    /// `None` for a git-backed one.
+    fn underlying_file(&self, ino: Ino) -> Result<Option<File>, Error> {
+        if control::owns(ino) {
+            return Ok(None);
+        }
+        let inomap = self.inomap();
+        let entry = inomap.get(ino).ok_or(Error::Errno(ENOENT))?;
+        match entry.u {
+            EntryKind::DirtyFile | EntryKind::DirtyDir { .. } => (),
+            EntryKind::GitBlob { .. } | EntryKind::GitTree { .. } => return Ok(None),
+        }
+        self.check_rules(|| inomap.prefix(ino), false)?;
+        let path = self.overlay_path(&inomap, ino)?;
+        let path = if path.as_os_str().is_empty() { Path::new(".") } else { &path };
+        Ok(Some(open_at(&self.inner.underlying_dir, path, O_RDONLY | O_NONBLOCK, 0)?))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn fgetxattr(file: &File, name: &OsStr) -> io::Result<Vec<u8>> {
+    use std::os::unix::io::AsRawFd;
+    let name = CString::new(name.as_bytes())?;
+    // Sized first, and again if it grew in between.
+    loop {
+        let size = unsafe { libc::fgetxattr(file.as_raw_fd(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe { libc::fgetxattr(file.as_raw_fd(), name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+        if size >= 0 {
+            value.truncate(size as usize);
+            return Ok(value);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn flistxattr(file: &File) -> io::Result<Vec<u8>> {
+    use std::os::unix::io::AsRawFd;
+    loop {
+        let size = unsafe { libc::flistxattr(file.as_raw_fd(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut names = vec![0u8; size as usize];
+        let size = unsafe { libc::flistxattr(file.as_raw_fd(), names.as_mut_ptr().cast(), names.len()) };
+        if size >= 0 {
+            names.truncate(size as usize);
+            return Ok(names);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn fsetxattr(file: &File, name: &OsStr, value: &[u8], flags: c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let name = CString::new(name.as_bytes())?;
+    if unsafe { libc::fsetxattr(file.as_raw_fd(), name.as_ptr(), value.as_ptr().cast(), value.len(), flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn fremovexattr(file: &File, name: &OsStr) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let name = CString::new(name.as_bytes())?;
+    if unsafe { libc::fremovexattr(file.as_raw_fd(), name.as_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Elsewhere, security labels and capabilities aren't xattrs.
+#[cfg(not(target_os = "linux"))]
+fn fgetxattr(_file: &File, _name: &OsStr) -> io::Result<Vec<u8>> {
+    Err(io::Error::from_raw_os_error(ENOATTR))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn flistxattr(_file: &File) -> io::Result<Vec<u8>> {
+    Ok(vec![])
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fsetxattr(_file: &File, _name: &OsStr, _value: &[u8], _flags: c_int) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(EOPNOTSUPP))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fremovexattr(_file: &File, _name: &OsStr) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(ENOATTR))
+}
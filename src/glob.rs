@@ -0,0 +1,111 @@
+/// Glob patterns of paths in the mount, as in `Options::denied_paths`.
+///
+/// A pattern is matched against a whole path, relative to the root,
+/// with `/` between names.  In a name, `*` matches any run of bytes
+/// and `?` any one byte; a name of `**` matches any number of names,
+/// none included, so `vendor/**` matches `vendor` and everything in
+/// it.  A leading `/` is ignored.
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Whether `path` matches `pattern`.
+pub(crate) fn matches(pattern: &str, path: &Path) -> bool {
+    let pattern = split(pattern);
+    let names: Vec<&[u8]> = path.iter().map(|name| name.as_bytes()).collect();
+    matches_names(&pattern, &names)
+}
+
+/// Whether `path` is among the paths to include, as in
+/// `Options::included_paths`: it, or a dir it's in, matches one of
+/// `patterns`, or it's a `dir` that may lead to paths that do.  All
+/// paths are when there are no patterns.
+pub(crate) fn included(patterns: &[String], path: &Path, dir: bool) -> bool {
+    if patterns.is_empty() {
+        return true;
+    }
+    patterns.iter().any(|pattern| {
+        let pattern = split(pattern);
+        let names: Vec<&[u8]> = path.iter().map(|name| name.as_bytes()).collect();
+        (1..=names.len()).any(|n| matches_names(&pattern, &names[..n])) || (dir && leads_to(&pattern, &names))
+    })
+}
+
+fn split(pattern: &str) -> Vec<&[u8]> {
+    pattern.trim_start_matches('/').split('/').filter(|name| !name.is_empty()).map(str::as_bytes).collect()
+}
+
+/// Whether the names are those of a dir paths matching `pattern` may
+/// be in.
+fn leads_to(pattern: &[&[u8]], names: &[&[u8]]) -> bool {
+    match (pattern.first(), names.first()) {
+        (None, _) => false,
+        (Some(&b"**"), _) | (Some(_), None) => true,
+        (Some(p), Some(name)) => matches_name(p, name) && leads_to(&pattern[1..], &names[1..]),
+    }
+}
+
+fn matches_names(pattern: &[&[u8]], names: &[&[u8]]) -> bool {
+    match (pattern.first(), names.first()) {
+        (None, _) => names.is_empty(),
+        (Some(&b"**"), _) => matches_names(&pattern[1..], names) || (!names.is_empty() && matches_names(pattern, &names[1..])),
+        (Some(_), None) => false,
+        (Some(p), Some(name)) => matches_name(p, name) && matches_names(&pattern[1..], &names[1..]),
+    }
+}
+
+fn matches_name(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, _) => name.is_empty(),
+        (Some(b'*'), _) => matches_name(&pattern[1..], name) || (!name.is_empty() && matches_name(pattern, &name[1..])),
+        (Some(_), None) => false,
+        (Some(b'?'), Some(_)) => matches_name(&pattern[1..], &name[1..]),
+        (Some(p), Some(c)) => p == c && matches_name(&pattern[1..], &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_globs() {
+        let cases = [
+            ("vendor/**", "vendor", true),
+            ("vendor/**", "vendor/a/b.c", true),
+            ("vendor/**", "vendors", false),
+            ("/secrets/*.key", "secrets/a.key", true),
+            ("secrets/*.key", "secrets/a/b.key", false),
+            ("**/*.pem", "a/b/c.pem", true),
+            ("**/*.pem", "c.pem", true),
+            ("a/**/z", "a/z", true),
+            ("a/**/z", "a/b/c/z", true),
+            ("a/**/z", "a/b/c/y", false),
+            ("file.?", "file.c", true),
+            ("file.?", "file.cc", false),
+            ("*", "a", true),
+            ("*", "a/b", false),
+        ];
+        for &(pattern, path, expected) in &cases {
+            assert_eq!(matches(pattern, Path::new(path)), expected, "{} against {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn includes_what_is_in_or_leads_to_included_paths() {
+        let patterns = ["src".to_owned(), "docs/**/*.md".to_owned()];
+        let cases = [
+            ("src", false, true),
+            ("src/a/b.rs", false, true),
+            ("docs", true, true),
+            ("docs/a", true, true),
+            ("docs/a/b.md", false, true),
+            ("docs/a/b.txt", false, false),
+            ("docs", false, false),
+            ("target", true, false),
+        ];
+        for &(path, dir, expected) in &cases {
+            assert_eq!(included(&patterns, Path::new(path), dir), expected, "{} (dir: {})", path, dir);
+        }
+        assert!(included(&[], Path::new("target"), false));
+    }
+}
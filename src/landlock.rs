@@ -0,0 +1,217 @@
+/// Restricting git-mount with Landlock (`--landlock`) to the files it
+/// has any business with: the repository, the overlay, and what is
+/// read for configuration (`/etc`, the user's git config).
+///
+/// Whatever goes wrong in resolving paths, files elsewhere on the host
+/// then can't be opened, made, or removed; only files and dirs are
+/// restricted, sockets aren't.  Rules are made before mounting, as the
+/// overlay dir is hidden by the mount, and enforced after it, as
+/// `fusermount3` is setuid.  Landlock restricts the thread enforcing
+/// it and the threads it starts since: with FUSE the thread serving
+/// the mount, so the watchers started before aren't.
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+use git2::Repository;
+use libc::c_long;
+
+use crate::gitfs::common_dir;
+
+/// The access rights of the file system in `linux/landlock.h`.
+const EXECUTE: u64 = 1 << 0;
+const WRITE_FILE: u64 = 1 << 1;
+const READ_FILE: u64 = 1 << 2;
+const READ_DIR: u64 = 1 << 3;
+const TRUNCATE: u64 = 1 << 14;
+const IOCTL_DEV: u64 = 1 << 15;
+/// The only rights that can be given on a file rather than a dir.
+const FILE_RIGHTS: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE | IOCTL_DEV;
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: u32 = 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// What may be done under a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Files read and dirs listed.
+    Read,
+    /// Anything but running programs.
+    Write,
+}
+
+/// The paths allowed, until enforced.
+#[derive(Debug)]
+pub struct Ruleset {
+    fd: File,
+    /// The rights the kernel knows of, which are all denied but where
+    /// allowed.
+    handled: u64,
+}
+
+impl Ruleset {
+    /// Fail unless Landlock is supported, and enabled.
+    pub fn new() -> io::Result<Ruleset> {
+        let abi = unsafe { syscall(libc::SYS_landlock_create_ruleset, 0, 0, CREATE_RULESET_VERSION as c_long, 0) }?;
+        let handled = match abi {
+            1 => (1 << 13) - 1,
+            // REFER, which renames between dirs take.
+            2 => (1 << 14) - 1,
+            3 | 4 => (1 << 15) - 1,
+            _ => (1 << 16) - 1,
+        };
+        let attr = RulesetAttr { handled_access_fs: handled };
+        let fd = unsafe {
+            syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr as c_long,
+                std::mem::size_of::<RulesetAttr>() as c_long,
+                0,
+                0,
+            )
+        }?;
+        Ok(Ruleset { fd: unsafe { File::from_raw_fd(fd as i32) }, handled })
+    }
+
+    /// Allow `access` to `path`, and all under it if it's a dir.
+    pub fn allow(&mut self, path: &Path, access: Access) -> io::Result<()> {
+        // O_PATH takes no permission to read the path.
+        let file = fs::OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_CLOEXEC).open(path)?;
+        let mut allowed = match access {
+            Access::Read => READ_FILE | READ_DIR,
+            Access::Write => !EXECUTE,
+        } & self.handled;
+        if !file.metadata()?.is_dir() {
+            allowed &= FILE_RIGHTS;
+        }
+        let attr = PathBeneathAttr { allowed_access: allowed, parent_fd: file.as_raw_fd() };
+        unsafe {
+            syscall(
+                libc::SYS_landlock_add_rule,
+                self.fd.as_raw_fd() as c_long,
+                RULE_PATH_BENEATH as c_long,
+                &attr as *const PathBeneathAttr as c_long,
+                0,
+            )
+        }?;
+        Ok(())
+    }
+
+    /// Allow writing to the git dir of `repo` and reading the objects
+    /// it borrows (alternates), and reading the configuration git
+    /// reads.
+    pub fn allow_repository(&mut self, repo: &Repository) -> io::Result<()> {
+        let common = common_dir(repo);
+        self.allow(repo.path(), Access::Write)?;
+        self.allow(&common, Access::Write)?;
+        let objects = common.join("objects");
+        if let Ok(alternates) = fs::read(objects.join("info/alternates")) {
+            for line in alternates.split(|&b| b == b'\n') {
+                if line.is_empty() || line[0] == b'#' {
+                    continue;
+                }
+                let path = objects.join(OsStr::from_bytes(line));
+                optional(self.allow(&path, Access::Read))?;
+            }
+        }
+        for path in config_paths() {
+            optional(self.allow(&path, Access::Read))?;
+        }
+        Ok(())
+    }
+
+    /// Restrict this thread, and the threads it starts, to the paths
+    /// allowed.  It can't be undone.
+    pub fn restrict_self(self) -> io::Result<()> {
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { syscall(libc::SYS_landlock_restrict_self, self.fd.as_raw_fd() as c_long, 0, 0, 0) }?;
+        Ok(())
+    }
+}
+
+/// Where git and the resolver read configuration from, besides the
+/// repository.
+fn config_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("/etc")];
+    let home = env::var_os("HOME").map(PathBuf::from);
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(config) => paths.push(Path::new(&config).join("git")),
+        None => paths.extend(home.as_ref().map(|home| home.join(".config/git"))),
+    }
+    paths.extend(home.map(|home| home.join(".gitconfig")));
+    paths
+}
+
+/// A path that needn't be there.
+fn optional(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+unsafe fn syscall(nr: c_long, a: c_long, b: c_long, c: c_long, d: c_long) -> io::Result<c_long> {
+    match libc::syscall(nr, a, b, c, d) {
+        -1 => Err(io::Error::last_os_error()),
+        ret => Ok(ret),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_allowed_paths_are_opened() {
+        let base = std::env::temp_dir().join(format!("gitfs-landlock-{}", std::process::id()));
+        let (inside, outside) = (base.join("inside"), base.join("outside"));
+        fs::create_dir_all(&inside).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("file"), "secret").unwrap();
+        let restricted = {
+            let (inside, outside) = (inside.clone(), outside.clone());
+            // In a thread of its own, which alone is restricted.
+            std::thread::spawn(move || {
+                let mut ruleset = match Ruleset::new() {
+                    Ok(ruleset) => ruleset,
+                    // Not in this kernel.
+                    Err(_) => return false,
+                };
+                ruleset.allow(&inside, Access::Write).unwrap();
+                ruleset.restrict_self().unwrap();
+                fs::write(inside.join("file"), "data").unwrap();
+                assert_eq!(fs::read(inside.join("file")).unwrap(), b"data");
+                fs::rename(inside.join("file"), inside.join("moved")).unwrap();
+                let denied = |result: io::Result<()>| result.unwrap_err().raw_os_error() == Some(libc::EACCES);
+                assert!(denied(fs::read(outside.join("file")).map(drop)));
+                assert!(denied(fs::write(outside.join("new"), "data")));
+                assert!(denied(fs::remove_file(outside.join("file"))));
+                true
+            })
+            .join()
+            .unwrap()
+        };
+        if restricted {
+            assert_eq!(fs::read(outside.join("file")).unwrap(), b"secret");
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
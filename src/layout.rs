@@ -0,0 +1,89 @@
+/// Mount layouts that lead to trouble.
+///
+/// The mountpoint doubles as the underlying dir, so mounting over the
+/// worktree of the repository shows every checked out file as dirty,
+/// and mounting over the repository itself makes gitfs read its own
+/// objects through itself, which hangs.
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use git2::Repository;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RiskyLayout {
+    /// The mountpoint is the worktree of the repository, or in it.
+    InWorktree(PathBuf),
+    /// The mountpoint is in the git dir.
+    InGitDir(PathBuf),
+    /// The git dir is under the mountpoint, and would be hidden by it.
+    HidesGitDir(PathBuf),
+}
+
+impl fmt::Display for RiskyLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RiskyLayout::InWorktree(dir) => write!(
+                f,
+                "the mountpoint is in the worktree {}, whose files would all show up as dirty",
+                dir.display()
+            ),
+            RiskyLayout::InGitDir(dir) => write!(f, "the mountpoint is in the git dir {}", dir.display()),
+            RiskyLayout::HidesGitDir(dir) => write!(
+                f,
+                "the git dir {} is under the mountpoint, and would be read through the mount",
+                dir.display()
+            ),
+        }
+    }
+}
+
+/// Find what is wrong with mounting `repo` at `mountpoint`.
+pub fn check(repo: &Repository, mountpoint: &Path) -> Vec<RiskyLayout> {
+    let mountpoint = match mountpoint.canonicalize() {
+        Ok(mountpoint) => mountpoint,
+        // Mounting will fail anyway.
+        Err(_) => return vec![],
+    };
+    let git_dir = repo.path().canonicalize().unwrap_or_else(|_| repo.path().to_owned());
+    let mut risks = vec![];
+    if let Some(worktree) = repo.workdir() {
+        let worktree = worktree.canonicalize().unwrap_or_else(|_| worktree.to_owned());
+        // A mountpoint in the git dir is reported as such.
+        if mountpoint.starts_with(&worktree) && !mountpoint.starts_with(&git_dir) {
+            risks.push(RiskyLayout::InWorktree(worktree));
+        }
+    }
+    if mountpoint.starts_with(&git_dir) {
+        risks.push(RiskyLayout::InGitDir(git_dir));
+    } else if git_dir.starts_with(&mountpoint) {
+        risks.push(RiskyLayout::HidesGitDir(git_dir));
+    }
+    risks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_risky_layouts() {
+        let root = std::env::temp_dir().join(format!("gitfs-layout-{}", std::process::id()));
+        let elsewhere = root.join("elsewhere");
+        std::fs::create_dir_all(&elsewhere).unwrap();
+        let repo = Repository::init(root.join("repo")).unwrap();
+        std::fs::create_dir_all(root.join("repo/sub")).unwrap();
+        let worktree = root.join("repo").canonicalize().unwrap();
+        let git_dir = worktree.join(".git");
+
+        assert_eq!(check(&repo, &elsewhere), vec![]);
+        assert_eq!(check(&repo, &root.join("repo")), vec![
+            RiskyLayout::InWorktree(worktree.clone()),
+            RiskyLayout::HidesGitDir(git_dir.clone()),
+        ]);
+        assert_eq!(check(&repo, &root.join("repo/sub")), vec![RiskyLayout::InWorktree(worktree)]);
+        assert_eq!(check(&repo, &root.join("repo/.git/refs")), vec![RiskyLayout::InGitDir(git_dir.clone())]);
+        assert_eq!(check(&repo, &root), vec![RiskyLayout::HidesGitDir(git_dir)]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
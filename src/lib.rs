@@ -1,19 +1,44 @@
 // This file contains definitions for data structures.
 
-use std::collections::{BTreeMap, HashMap};
+#![allow(clippy::needless_return)]
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::ops::AddAssign;
 use std::fs::{File, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::io::Write;
 use std::path::PathBuf;
 use std::ffi::{OsString, OsStr};
 use std::time::SystemTime;
+use std::sync::Arc;
 
 use git2::Oid;
 use fuser::FileType;
 
+use crate::gitfs::Smudged;
+
 #[macro_use]
-extern crate log;
+extern crate tracing;
 
+pub mod config;
+pub mod error;
 pub mod gitfs;
+mod glob;
+#[cfg(target_os = "linux")]
+pub mod landlock;
+pub mod layout;
+pub mod logging;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod names;
+pub mod options;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+#[cfg(target_os = "linux")]
+pub mod seccomp;
+pub mod systemd;
+pub mod testing;
+pub mod watch;
 
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
@@ -81,8 +106,95 @@ impl InoMap {
         self.inner.remove(&ino)
     }
 
-    fn next_ino(&self) -> Ino {
-        self.next_ino
+    fn iter(&self) -> impl Iterator<Item = (Ino, &Entry)> {
+        self.inner.iter().map(|(&ino, entry)| (ino, entry))
+    }
+
+    /// Forget the children of a directory, so that it's walked again
+    /// on next access.  All its descendants are removed from the
+    /// inomap; their inos are never reused.
+    fn invalidate(&mut self, ino: Ino) {
+        let entry = match self.get_mut(ino) {
+            Some(entry) => entry,
+            None => return,
+        };
+        entry.subdirs = None;
+        let children = match &mut entry.u {
+            EntryKind::GitTree { children, .. } => children.take(),
+            EntryKind::DirtyDir { children } => children.take(),
+            _ => None,
+        };
+        for (_, child) in children.into_iter().flatten() {
+            self.invalidate(child);
+            self.remove(child);
+        }
+    }
+
+    /// Check that the map holds together, and return how it doesn't:
+    /// every entry but the root is listed in its parent, under its
+    /// name, and nowhere else, so that nothing is orphaned and its
+    /// prefix leads back to it from the root.
+    fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+        match self.get(Ino::ROOT) {
+            Some(root) if root.parent == Ino::ROOT => (),
+            Some(root) => problems.push(format!("the root has parent {}", root.parent.0)),
+            None => return vec!["the root is missing".to_owned()],
+        }
+        for (ino, entry) in self.iter() {
+            if ino >= self.next_ino {
+                problems.push(format!("ino {} was never handed out", ino.0));
+            }
+            let children = match &entry.u {
+                EntryKind::GitTree { children, .. } | EntryKind::DirtyDir { children } => children.as_ref(),
+                _ => None,
+            };
+            for (name, &child) in children.into_iter().flatten() {
+                match self.get(child) {
+                    None => problems.push(format!("ino {} lists {:?} as ino {}, which is missing", ino.0, name, child.0)),
+                    Some(child_entry) if child_entry.parent != ino || child_entry.name != *name => problems.push(format!(
+                        "ino {} lists {:?} as ino {}, which is {:?} in ino {}",
+                        ino.0, name, child.0, child_entry.name, child_entry.parent.0
+                    )),
+                    Some(_) => (),
+                }
+            }
+            if ino.is_root() {
+                continue;
+            }
+            let problem = match self.get(entry.parent) {
+                None => Some(format!("ino {} ({:?}) is in ino {}, which is missing", ino.0, entry.name, entry.parent.0)),
+                Some(parent) if parent.get_child(&entry.name) != Some(ino) => Some(format!(
+                    "ino {} ({:?}) is not listed in its parent, ino {}",
+                    ino.0, entry.name, entry.parent.0
+                )),
+                Some(_) => None,
+            };
+            if let Some(problem) = problem {
+                problems.push(problem);
+                continue;
+            }
+            // Following parents must end at the root, in fewer steps
+            // than there are entries, before prefix() can be trusted.
+            let mut up = ino;
+            for _ in 0..self.inner.len() {
+                match self.get(up) {
+                    Some(entry) if !up.is_root() => up = entry.parent,
+                    _ => break,
+                }
+            }
+            if !up.is_root() {
+                problems.push(format!("ino {} ({:?}) doesn't lead up to the root", ino.0, entry.name));
+                continue;
+            }
+            let found = self.prefix(ino).and_then(|prefix| {
+                prefix.iter().try_fold(Ino::ROOT, |dir, name| self.get(dir)?.get_child(name))
+            });
+            if found != Some(ino) {
+                problems.push(format!("the prefix of ino {} ({:?}) leads to {:?}", ino.0, entry.name, found.map(|ino| ino.0)));
+            }
+        }
+        problems
     }
 
     /// Return a fs prefix as PathBuf.
@@ -98,12 +210,232 @@ impl InoMap {
         for part in parts.iter().rev() {
             prefix.push(part);
         }
-        println!("prefix: {:?}", prefix);
+        trace!(?prefix, "resolved prefix");
         Some(prefix)
     }
 }
 
 
+/// Keep recently read blobs in memory, so that reading a file chunk
+/// by chunk doesn't inflate the same blob again and again.
+///
+/// The cache is bounded by the total size of the blobs it holds.
+/// When it's full, the least recently used blob is evicted.  Pinned
+/// blobs (see `Options::pinned_paths`) are never evicted, and don't
+/// count against the capacity.
+#[derive(Debug)]
+pub struct BlobCache {
+    capacity: usize,
+    size: usize,
+    /// Blobs, with the generation they were last used in.
+    inner: HashMap<Oid, (Arc<[u8]>, u64)>,
+    /// Oids from the least to the most recently used, with the
+    /// generation each was used in.  A hit pushes the oid again rather
+    /// than looking for it, so older entries of it are stale and
+    /// skipped.
+    lru: VecDeque<(Oid, u64)>,
+    generation: u64,
+    /// Pinned blobs, with how many times they're pinned, as mounts
+    /// sharing the cache may pin the same blob.
+    pinned: HashMap<Oid, (Arc<[u8]>, usize)>,
+    pinned_size: usize,
+}
+
+impl BlobCache {
+    fn new(capacity: usize) -> BlobCache {
+        BlobCache {
+            capacity,
+            size: 0,
+            inner: HashMap::new(),
+            lru: VecDeque::new(),
+            generation: 0,
+            pinned: HashMap::new(),
+            pinned_size: 0,
+        }
+    }
+
+    fn get(&mut self, oid: Oid) -> Option<Arc<[u8]>> {
+        if let Some((content, _)) = self.pinned.get(&oid) {
+            return Some(content.clone());
+        }
+        let content = self.inner.get(&oid)?.0.clone();
+        self.touch(oid);
+        Some(content)
+    }
+
+    /// Insert a blob. Blobs larger than the whole cache are not kept.
+    fn insert(&mut self, oid: Oid, content: Arc<[u8]>) {
+        if content.len() > self.capacity || self.inner.contains_key(&oid) || self.pinned.contains_key(&oid) {
+            return;
+        }
+        self.size += content.len();
+        self.inner.insert(oid, (content, 0));
+        self.touch(oid);
+        self.shrink();
+    }
+
+    /// Keep a blob until it's unpinned as many times, whatever the
+    /// capacity.
+    fn pin(&mut self, oid: Oid, content: Arc<[u8]>) {
+        if let Some((_, count)) = self.pinned.get_mut(&oid) {
+            *count += 1;
+            return;
+        }
+        if let Some((cached, _)) = self.inner.remove(&oid) {
+            self.size -= cached.len();
+        }
+        self.pinned_size += content.len();
+        self.pinned.insert(oid, (content, 1));
+    }
+
+    /// Undo a `pin`.  The blob is dropped once it's no longer pinned,
+    /// rather than left to the LRU.
+    fn unpin(&mut self, oid: Oid) {
+        if let Some((content, count)) = self.pinned.get_mut(&oid) {
+            *count -= 1;
+            if *count == 0 {
+                self.pinned_size -= content.len();
+                self.pinned.remove(&oid);
+            }
+        }
+    }
+
+    /// The total size of the cached blobs, pinned ones left out.
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The total size of the pinned blobs.
+    fn pinned_size(&self) -> usize {
+        self.pinned_size
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink();
+    }
+
+    fn touch(&mut self, oid: Oid) {
+        if let Some((_, generation)) = self.inner.get_mut(&oid) {
+            self.generation += 1;
+            *generation = self.generation;
+            self.lru.push_back((oid, self.generation));
+        }
+        // Drop the stale entries once they outnumber the live ones, so
+        // the order stays within twice the number of blobs.
+        if self.lru.len() > 2 * self.inner.len() + 16 {
+            let inner = &self.inner;
+            self.lru.retain(|(oid, generation)| matches!(inner.get(oid), Some((_, g)) if g == generation));
+        }
+    }
+
+    fn shrink(&mut self) {
+        while self.size > self.capacity {
+            let (oid, generation) = match self.lru.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if matches!(self.inner.get(&oid), Some((_, g)) if *g == generation) {
+                let (content, _) = self.inner.remove(&oid).unwrap();
+                self.size -= content.len();
+            }
+        }
+    }
+}
+
+
+/// Open files, indexed by file handle.
+///
+/// Every open() of a file gets its own handle. Handles of dirty files
+/// own a descriptor of the file in the underlying dir, so the file
+/// stays usable even if it's renamed or unlinked meanwhile.
+#[derive(Debug)]
+pub struct HandleTable {
+    next_fh: u64,
+    inner: HashMap<u64, Handle>,
+}
+
+#[derive(Debug)]
+struct Handle {
+    ino: Ino,
+    /// The file in the underlying dir, if the entry is dirty.
+    file: Option<Arc<File>>,
+    /// What the file in the underlying dir is opened with, from the
+    /// flags of the open() (see `gitfs::overlay_flags`).
+    flags: libc::c_int,
+}
+
+impl HandleTable {
+    fn new() -> HandleTable {
+        HandleTable {
+            // fh 0 is what opendir() hands out.
+            next_fh: 1,
+            inner: HashMap::new(),
+        }
+    }
+
+    /// Add a handle, and return its fh.
+    fn add(&mut self, handle: Handle) -> u64 {
+        let fh = self.next_fh;
+        self.inner.insert(fh, handle);
+        self.next_fh += 1;
+        fh
+    }
+
+    fn get(&self, fh: u64) -> Option<&Handle> {
+        self.inner.get(&fh)
+    }
+
+    fn get_mut(&mut self, fh: u64) -> Option<&mut Handle> {
+        self.inner.get_mut(&fh)
+    }
+
+    fn remove(&mut self, fh: u64) -> Option<Handle> {
+        self.inner.remove(&fh)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u64, &Handle)> {
+        self.inner.iter().map(|(&fh, handle)| (fh, handle))
+    }
+
+    /// Flush and fsync every dirty file, then drop all handles.
+    fn close_all(&mut self) {
+        for (fh, handle) in self.inner.drain() {
+            if let Some(file) = handle.file {
+                if let Err(e) = (&*file).flush().and_then(|_| file.sync_all()) {
+                    error!(fh, ino = u64::from(handle.ino), %e, "cannot sync dirty file");
+                }
+            }
+        }
+    }
+}
+
+
+/// What an entry in the underlying dir looked like, so as to notice
+/// when it's changed by someone else.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Stamp {
+    ino: libc::ino_t,
+    size: libc::off_t,
+    mtime: (libc::time_t, libc::c_long),
+}
+
+impl From<&libc::stat> for Stamp {
+    fn from(stat: &libc::stat) -> Stamp {
+        Stamp {
+            ino: stat.st_ino,
+            size: stat.st_size,
+            mtime: (stat.st_mtime, stat.st_mtime_nsec),
+        }
+    }
+}
+
+/// File types in the mode bits of an entry, as stored in git trees.
+const MODE_TYPE: u32 = 0o170000;
+const MODE_FILE: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+const MODE_CHAR: u32 = 0o020000;
+
 #[derive(Debug)]
 struct Entry {
     name: OsString,
@@ -123,12 +455,31 @@ struct Entry {
     /// Created.
     crtime: SystemTime,
 
-    /// Permission bits.
+    /// Permission bits, and the file type as in git.
     perm: Permissions,
 
     /// Size.
     size: u64,
 
+    /// Whether the entry collides with another one in the underlying
+    /// dir, so that it must not be written to.  See `CaseCollisions`.
+    shadowed: bool,
+
+    /// Number of subdirs of a dir, once counted.
+    subdirs: Option<u32>,
+
+    /// The owner given to a clean entry by chown, until it's in the
+    /// underlying dir.
+    owner: Option<(u32, u32)>,
+
+    /// The BSD flags given to a clean entry by chflags, until it's in
+    /// the underlying dir.
+    flags: Option<u32>,
+
+    /// The entry in the underlying dir as last seen, if it's there.
+    /// Dirs are stamped when listed.
+    stamp: Option<Stamp>,
+
     /// Entry kind.
     u: EntryKind,
 }
@@ -141,19 +492,23 @@ pub enum EntryKind {
         /// Files under this directory.
         /// None means the tree is not traversed.
         children: Option<HashMap<OsString, Ino>>,
+        /// The commit of the submodule this is the tree of, if it is
+        /// one (see `gitfs::submodule`).
+        commit: Option<Oid>,
     },
     GitBlob {
         /// an OID pointing to the blob object
         oid: Oid,
+        /// How its content is converted for the mount, if it is (see
+        /// `Options::smudge`).
+        smudged: Option<Arc<Smudged>>,
     },
     DirtyDir {
         children: Option<HashMap<OsString, Ino>>,
     },
-    DirtyFile {
-        refcnt: i32,
-        /// The actual file on disk.
-        file: Option<File>,
-    },
+    /// The actual file is in the underlying dir.  Descriptors of it
+    /// are owned by the handles that opened it.
+    DirtyFile,
 }
 
 impl Entry {
@@ -161,32 +516,54 @@ impl Entry {
         match self.u {
             EntryKind::DirtyDir { children: Some(ref c) } => c.get(name).cloned(),
             EntryKind::GitTree { children: Some(ref c), .. } => c.get(name).cloned(),
-            _ => unreachable!(),
+            _ => None,
         }
     }
 
+    /// Add a child to a listed directory.  An unlisted directory is
+    /// left alone, as the child will be found when it's walked.
     fn add_child(&mut self, name: OsString, ino: Ino) {
+        self.subdirs = None;
         match self.u {
             EntryKind::DirtyDir { children: Some(ref mut c) } => {c.insert(name, ino);}
             EntryKind::GitTree { children: Some(ref mut c), .. } => {c.insert(name, ino);}
-            _ => unreachable!(),
+            _ => (),
         }
     }
 
     fn remove_child(&mut self, name: &OsStr) -> Option<Ino> {
+        self.subdirs = None;
         match self.u {
             EntryKind::DirtyDir { children: Some(ref mut c) } => c.remove(name),
             EntryKind::GitTree { children: Some(ref mut c), .. } => c.remove(name),
-            _ => unreachable!(),
+            _ => None,
         }
     }
+
+    /// Whether the entry is a listed directory with children.
+    fn has_children(&self) -> bool {
+        match self.u {
+            EntryKind::DirtyDir { children: Some(ref c) } => !c.is_empty(),
+            EntryKind::GitTree { children: Some(ref c), .. } => !c.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Whether the entry is as in the tree, with nothing of it in the
+    /// underlying dir.
+    fn is_clean(&self) -> bool {
+        matches!(self.u, EntryKind::GitBlob { .. } | EntryKind::GitTree { .. })
+    }
 }
 
 impl From<&Entry> for FileType {
     fn from(x: &Entry) -> FileType {
         match x.u {
             EntryKind::GitTree { .. } | EntryKind::DirtyDir { .. } => FileType::Directory,
-            EntryKind::GitBlob { .. } | EntryKind::DirtyFile { .. } => FileType::RegularFile,
+            EntryKind::GitBlob { .. } if x.perm.mode() & MODE_TYPE == MODE_SYMLINK => FileType::Symlink,
+            // A whiteout (see `Options::whiteouts`).
+            EntryKind::GitBlob { .. } if x.perm.mode() & MODE_TYPE == MODE_CHAR => FileType::CharDevice,
+            EntryKind::GitBlob { .. } | EntryKind::DirtyFile => FileType::RegularFile,
         }
     }
 }
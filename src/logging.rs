@@ -0,0 +1,267 @@
+/// Logs as JSON, one object per line (`--log-format json`).
+///
+/// Each line holds the time, level, target and fields of an event,
+/// plus the name (as `op`) and fields of the spans it happened in;
+/// every FUSE operation runs in a span with its ino and path, and
+/// records its errno if it fails.  With span closes, a line with the
+/// `duration_us` of the span is written when it closes.
+use std::fmt::{self, Write as _};
+use std::io::Write;
+use std::time::Instant;
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+/// A layer writing events as JSON lines to `W`.
+pub struct JsonLayer<W> {
+    make_writer: W,
+    span_closes: bool,
+}
+
+/// The fields of a span, encoded as JSON values, and when it started.
+struct SpanFields {
+    fields: Fields,
+    start: Instant,
+}
+
+/// Fields, by name, encoded as JSON values.  A field recorded again
+/// replaces the earlier value.
+#[derive(Default)]
+struct Fields(Vec<(&'static str, String)>);
+
+impl<W> JsonLayer<W>
+where
+    W: for<'a> MakeWriter<'a> + 'static,
+{
+    pub fn new(make_writer: W) -> JsonLayer<W> {
+        JsonLayer {
+            make_writer,
+            span_closes: false,
+        }
+    }
+
+    /// Also write a line when a span closes, with how long it was
+    /// open.
+    pub fn with_span_closes(mut self, span_closes: bool) -> JsonLayer<W> {
+        self.span_closes = span_closes;
+        self
+    }
+
+    fn write_line<S>(&self, metadata: &Metadata<'_>, scope: Option<SpanRef<'_, S>>, own: Fields)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let mut fields = Fields::default();
+        if let Some(span) = scope {
+            fields.set("op", json_str(span.name()));
+            for span in span.scope().from_root() {
+                if let Some(span_fields) = span.extensions().get::<SpanFields>() {
+                    fields.merge(&span_fields.fields);
+                }
+            }
+        }
+        fields.merge(&own);
+
+        let mut line = String::new();
+        write!(
+            line,
+            "{{\"timestamp\":{},\"level\":{},\"target\":{}",
+            json_str(&timestamp()),
+            json_str(level_name(metadata.level())),
+            json_str(metadata.target())
+        )
+        .unwrap();
+        for (name, value) in &fields.0 {
+            write!(line, ",{}:{}", json_str(name), value).unwrap();
+        }
+        line.push_str("}\n");
+        // There is nowhere to report that logging failed.
+        let _ = self.make_writer.make_writer_for(metadata).write_all(line.as_bytes());
+    }
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(SpanFields {
+                fields,
+                start: Instant::now(),
+            });
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(span_fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(&mut span_fields.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        self.write_line(event.metadata(), ctx.event_span(event), fields);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if !self.span_closes {
+            return;
+        }
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let start = match span.extensions().get::<SpanFields>() {
+            Some(span_fields) => span_fields.start,
+            None => return,
+        };
+        let mut fields = Fields::default();
+        fields.set("message", json_str("close"));
+        fields.set("duration_us", start.elapsed().as_micros().to_string());
+        self.write_line(span.metadata(), Some(span), fields);
+    }
+}
+
+impl Fields {
+    fn set(&mut self, name: &'static str, value: String) {
+        match self.0.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((name, value)),
+        }
+    }
+
+    fn merge(&mut self, other: &Fields) {
+        for (name, value) in &other.0 {
+            self.set(name, value.clone());
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field.name(), json_str(&format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), json_str(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field.name(), value.to_string());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field.name(), value.to_string());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if value.is_finite() {
+            self.set(field.name(), value.to_string());
+        } else {
+            self.set(field.name(), json_str(&value.to_string()));
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field.name(), value.to_string());
+    }
+}
+
+fn level_name(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "ERROR",
+        Level::WARN => "WARN",
+        Level::INFO => "INFO",
+        Level::DEBUG => "DEBUG",
+        Level::TRACE => "TRACE",
+    }
+}
+
+/// The time now, in RFC 3339 with microseconds.
+pub(crate) fn timestamp() -> String {
+    let now = time::now_utc();
+    format!("{}.{:06}Z", now.strftime("%Y-%m-%dT%H:%M:%S").unwrap(), now.tm_nsec / 1000)
+}
+
+/// `s` as a JSON string.
+pub(crate) fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(json_str("a\"b\\c\nd\u{1}é"), r#""a\"b\\c\nd\u0001é""#);
+    }
+
+    #[test]
+    fn writes_json_lines() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let writer = log.clone();
+        let layer = JsonLayer::new(move || Log(writer.clone())).with_span_closes(true);
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            let span = info_span!("read", ino = 2u64, path = "a.txt", errno = tracing::field::Empty);
+            let _entered = span.enter();
+            warn!(size = 10, "too slow");
+            span.record("errno", 5);
+        });
+        let log = String::from_utf8(log.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", log);
+        let fields = |line: &str| line.split_once(",\"target\":").unwrap().1.to_owned();
+        assert!(lines[0].starts_with("{\"timestamp\":\"") && lines[0].contains("Z\",\"level\":\"WARN\","));
+        assert_eq!(
+            fields(lines[0]),
+            r#""rockmore_git::logging::tests","op":"read","ino":2,"path":"a.txt","message":"too slow","size":10}"#
+        );
+        let close = fields(lines[1]);
+        assert!(
+            close.starts_with(r#""rockmore_git::logging::tests","op":"read","ino":2,"path":"a.txt","errno":5,"message":"close","duration_us":"#),
+            "{}",
+            close
+        );
+    }
+
+    struct Log(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Log {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,69 @@
+/// A Prometheus endpoint for the counters of a mount (the `metrics`
+/// feature).
+///
+/// The server answers `GET /metrics` with the counters shown in
+/// `/.gitfs/stats`, plus the time spent in each FUSE operation.  It
+/// handles one connection at a time, which is plenty for a scraper.
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use std::time::Duration;
+
+use crate::gitfs::GitFS;
+use crate::watch::Watcher;
+
+/// Serve the metrics of `fs` at `addr`, until the returned watcher is
+/// dropped.
+pub(crate) fn serve(fs: GitFS, addr: SocketAddr) -> io::Result<(Watcher, SocketAddr)> {
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    // Polled, so that the server notices when it's stopped.
+    listener.set_nonblocking(true)?;
+    let watcher = Watcher::run("gitfs-metrics", move |stopped| {
+        while let Err(TryRecvError::Empty) = stopped.try_recv() {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if let Err(e) = respond(&fs, stream) {
+                        debug!(%peer, %e, "cannot serve metrics");
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(100)),
+                Err(e) => {
+                    error!(%e, "cannot accept connections for metrics");
+                    break;
+                }
+            }
+        }
+    })?;
+    info!(%addr, "serving metrics");
+    Ok((watcher, addr))
+}
+
+fn respond(fs: &GitFS, stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers are of no interest.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", fs.metrics()),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "only GET\n".to_owned()),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    (&stream).flush()
+}
@@ -0,0 +1,265 @@
+/// Names in the mount vs. names in the underlying dir.
+///
+/// Names come from git trees, which may hold names that the file
+/// system of the underlying dir rejects.  When escaping is enabled,
+/// such names are stored in the underlying dir under an escaped name,
+/// and mapped back when the dir is listed.
+///
+/// The escaping is %XX of the offending bytes, and of `%` itself, so
+/// that it can be reversed.  Only names in the underlying dir are ever
+/// escaped; the mount always shows the names as in git.
+///
+/// Some file systems also return names in another Unicode
+/// normalization than the one they were created with, or ignore case.
+/// Such names are matched to the tree entry they stand for via
+/// `merge_key`.
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+use crate::options::{CaseCollisions, Options};
+
+/// Names reserved by Windows, with any extension.
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// How names are mapped, as configured in `Options`.
+#[derive(Debug, Clone, Copy)]
+pub struct NameMap {
+    escape: bool,
+    normalize: bool,
+    fold_case: bool,
+}
+
+impl NameMap {
+    pub fn new(options: &Options) -> NameMap {
+        NameMap {
+            escape: options.escape_names,
+            normalize: options.normalize_unicode,
+            fold_case: options.case_collisions != CaseCollisions::Ignore,
+        }
+    }
+
+    /// The name in the underlying dir of an entry named `name`.
+    pub fn overlay_name<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        if !self.escape || !needs_escape(name.as_bytes()) {
+            return Cow::Borrowed(name);
+        }
+        let bytes = name.as_bytes();
+        let reserved = is_reserved(bytes);
+        let mut escaped = Vec::with_capacity(bytes.len() + 6);
+        for (i, &b) in bytes.iter().enumerate() {
+            let trailing = i + 1 == bytes.len() && (b == b'.' || b == b' ');
+            if is_special(b) || trailing || (reserved && i == 0) {
+                escaped.extend_from_slice(format!("%{:02X}", b).as_bytes());
+            } else {
+                escaped.push(b);
+            }
+        }
+        Cow::Owned(OsString::from_vec(escaped))
+    }
+
+    /// The name in the mount of an entry named `name` in the underlying
+    /// dir.
+    pub fn mount_name<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        let bytes = name.as_bytes();
+        if !self.escape || !bytes.contains(&b'%') {
+            return Cow::Borrowed(name);
+        }
+        let mut unescaped = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let byte = match (bytes[i], bytes.get(i + 1..i + 3)) {
+                (b'%', Some(hex)) => std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                _ => None,
+            };
+            match byte {
+                Some(byte) => {
+                    unescaped.push(byte);
+                    i += 3;
+                }
+                None => {
+                    unescaped.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+        Cow::Owned(OsString::from_vec(unescaped))
+    }
+
+    /// Whether names have to be matched by `merge_key`.
+    pub fn normalizes(&self) -> bool {
+        self.normalize || self.fold_case
+    }
+
+    /// Whether names that only differ in case collide.
+    pub fn folds_case(&self) -> bool {
+        self.fold_case
+    }
+
+    /// The key by which a name in the underlying dir is matched to a
+    /// name in a tree: names with the same key are the same entry.
+    pub fn merge_key<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        let mut key = match name.to_str() {
+            Some(s) if self.normalize && !is_nfc(s) => Cow::Owned(s.nfc().collect::<String>().into()),
+            _ => Cow::Borrowed(name),
+        };
+        if self.fold_case {
+            key = match key.to_str() {
+                Some(s) => Cow::Owned(s.to_lowercase().into()),
+                None => Cow::Owned(OsString::from_vec(key.as_bytes().to_ascii_lowercase())),
+            };
+        }
+        key
+    }
+
+    /// The path in the underlying dir of an entry at `path` in the
+    /// mount.
+    pub fn overlay_path(&self, path: &Path) -> PathBuf {
+        path.iter().map(|name| self.overlay_name(name)).collect()
+    }
+}
+
+/// Whether `name` can be a name of an entry at all.  Trees can be
+/// crafted to hold names such as `..` or `a/b`, which would point
+/// outside of their dir if used in a path.
+pub fn is_valid(name: &OsStr) -> bool {
+    let bytes = name.as_bytes();
+    !bytes.is_empty() && bytes != b"." && bytes != b".." && !bytes.contains(&b'/') && !bytes.contains(&0)
+}
+
+/// Whether `path` stays inside the dir it's relative to.
+pub fn is_contained(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+fn is_special(b: u8) -> bool {
+    b < 0x20 || b == 0x7f || b"%<>:\"\\|?*".contains(&b)
+}
+
+fn is_reserved(name: &[u8]) -> bool {
+    let stem = match name.iter().position(|&b| b == b'.') {
+        Some(dot) => &name[..dot],
+        None => name,
+    };
+    RESERVED.iter().any(|r| r.as_bytes().eq_ignore_ascii_case(stem))
+}
+
+fn needs_escape(name: &[u8]) -> bool {
+    name.iter().any(|&b| is_special(b))
+        || matches!(name.last(), Some(b'.') | Some(b' '))
+        || is_reserved(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn escaping() -> NameMap {
+        NameMap {
+            escape: true,
+            normalize: false,
+            fold_case: false,
+        }
+    }
+
+    #[test]
+    fn escapes_invalid_names() {
+        let names = escaping();
+        let cases = [
+            ("plain.txt", "plain.txt"),
+            ("aux", "%61ux"),
+            ("Com1.txt", "%43om1.txt"),
+            ("auxiliary", "auxiliary"),
+            ("trailing.", "trailing%2E"),
+            ("trailing ", "trailing%20"),
+            ("new\nline", "new%0Aline"),
+            ("a:b?c", "a%3Ab%3Fc"),
+            ("100%", "100%25"),
+        ];
+        for &(name, escaped) in cases.iter() {
+            assert_eq!(names.overlay_name(OsStr::new(name)), OsStr::new(escaped));
+            assert_eq!(names.mount_name(OsStr::new(escaped)), OsStr::new(name));
+        }
+    }
+
+    #[test]
+    fn leaves_names_alone_unless_enabled() {
+        let names = NameMap {
+            escape: false,
+            normalize: false,
+            fold_case: false,
+        };
+        assert_eq!(names.overlay_name(OsStr::new("aux")), OsStr::new("aux"));
+        assert_eq!(names.mount_name(OsStr::new("%61ux")), OsStr::new("%61ux"));
+    }
+
+    #[test]
+    fn keeps_stray_percent_signs() {
+        let names = escaping();
+        assert_eq!(names.mount_name(OsStr::new("50%off")), OsStr::new("50%off"));
+        assert_eq!(names.mount_name(OsStr::new("end%")), OsStr::new("end%"));
+    }
+
+    #[test]
+    fn escapes_every_component() {
+        let names = escaping();
+        assert_eq!(
+            names.overlay_path(Path::new("con/dir./file")),
+            Path::new("%63on/dir%2E/file")
+        );
+    }
+
+    #[test]
+    fn matches_names_in_any_normalization() {
+        let names = NameMap {
+            escape: false,
+            normalize: true,
+            fold_case: false,
+        };
+        let nfc = OsStr::new("caf\u{e9}");
+        let nfd = OsStr::new("cafe\u{301}");
+        assert_eq!(names.merge_key(nfd), nfc);
+        assert_eq!(names.merge_key(nfc), nfc);
+
+        let names = NameMap {
+            escape: false,
+            normalize: false,
+            fold_case: false,
+        };
+        assert_eq!(names.merge_key(nfd), nfd);
+    }
+
+    #[test]
+    fn matches_names_in_any_case() {
+        let names = NameMap {
+            escape: false,
+            normalize: true,
+            fold_case: true,
+        };
+        assert_eq!(names.merge_key(OsStr::new("README.md")), OsStr::new("readme.md"));
+        assert_eq!(names.merge_key(OsStr::new("Cafe\u{301}")), OsStr::new("caf\u{e9}"));
+        assert_eq!(names.merge_key(OsStr::from_bytes(b"AB\xff")), OsStr::from_bytes(b"ab\xff"));
+    }
+
+    #[test]
+    fn rejects_names_that_escape_their_dir() {
+        for name in ["", ".", "..", "a/b", "/etc", "nul\0byte"].iter() {
+            assert!(!is_valid(OsStr::new(name)), "{:?}", name);
+        }
+        for name in ["a", ".a", "..a", "a..", "..."].iter() {
+            assert!(is_valid(OsStr::new(name)), "{:?}", name);
+        }
+        assert!(is_contained(Path::new("a/b/c")));
+        assert!(!is_contained(Path::new("a/../../b")));
+        assert!(!is_contained(Path::new("/a")));
+    }
+}
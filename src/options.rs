@@ -0,0 +1,281 @@
+/// Tunable settings of a gitfs instance.
+///
+/// `Options` is shared between the file system and whoever mounted
+/// it, so the settings can be changed while the file system is
+/// running.  Settings that are negotiated with the kernel (such as
+/// readahead) only take effect at mount time.
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// A handle to the options of a running gitfs.
+pub type SharedOptions = Arc<RwLock<Options>>;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// How long the kernel may cache the attributes of an entry.
+    pub attr_ttl: Duration,
+
+    /// How long the kernel may cache a name lookup.
+    pub entry_ttl: Duration,
+
+    /// Upper bound (in bytes) of blob contents kept in memory.
+    pub blob_cache_size: usize,
+
+    /// Maximum readahead (in bytes) requested from the kernel.
+    pub max_readahead: u32,
+
+    /// Maximum size (in bytes) of a single write request from the
+    /// kernel.
+    pub max_write: u32,
+
+    /// Maximum number of background requests (e.g. readahead) the
+    /// kernel keeps in flight.
+    pub max_background: u16,
+
+    /// Number of background requests in flight at which the kernel
+    /// considers the mount congested.  `None` is 3/4 of
+    /// `max_background`, as the kernel would choose.
+    pub congestion_threshold: Option<u16>,
+
+    /// Escape names that the underlying dir may reject (e.g. `aux`,
+    /// or names with trailing dots) when storing dirty files.  Dirty
+    /// files already stored under unescaped names may show up under
+    /// other names once this is changed.
+    pub escape_names: bool,
+
+    /// Treat names that only differ in their Unicode normalization as
+    /// the same name when merging the underlying dir with a tree.
+    /// Needed where the underlying dir returns names in NFD (as on
+    /// macOS) while git usually stores them in NFC.
+    pub normalize_unicode: bool,
+
+    /// What to do with tree entries whose names only differ in case,
+    /// which cannot be stored side by side in a case-insensitive
+    /// underlying dir.
+    pub case_collisions: CaseCollisions,
+
+    /// Owner shown for clean entries; by default, the user and group
+    /// running gitfs.  Dirty entries show their owner in the
+    /// underlying dir.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+
+    /// Refuse every change with EROFS.  Dirty files already in the
+    /// underlying dir still show up.
+    pub read_only: bool,
+
+    /// Where the times of clean entries come from.
+    pub mtime: MtimePolicy,
+
+    /// Whether the control dir `/.gitfs` is there.  It hides any
+    /// entry of that name.
+    pub control_dir: bool,
+
+    /// Whether there is a read-only `/.git` file pointing at the
+    /// repository, so that git commands work in the mount.  It hides
+    /// any entry of that name.
+    pub git_file: bool,
+
+    /// Log FUSE operations that take longer than this at warn level,
+    /// with the ino and path they were on.
+    pub slow_op_threshold: Option<Duration>,
+
+    /// Log a summary of what the mount did when it's unmounted, at
+    /// info level (see `GitFS::log_summary`).
+    pub summary_on_unmount: bool,
+
+    /// Refuse writes and new entries with EDQUOT once the files in
+    /// the underlying dir take up this many bytes.  The usage is
+    /// measured at most once a second, and estimated in between.
+    pub overlay_quota: Option<u64>,
+
+    /// Limit reads and writes of files to this many bytes per second.
+    pub read_rate: Option<u64>,
+    pub write_rate: Option<u64>,
+
+    /// Limit reads and writes of files, together, to this many per
+    /// second.
+    pub iops: Option<u64>,
+
+    /// Paths (as globs, e.g. `secrets/**`) that are hidden: they can't
+    /// be looked up (ENOENT) nor created (EACCES).
+    pub denied_paths: Vec<String>,
+
+    /// Paths (as globs, e.g. `vendor/**`) that can be read but not
+    /// changed (EROFS).
+    pub read_only_paths: Vec<String>,
+
+    /// If any, the only paths (as globs, e.g. `src/**` or `docs`) that
+    /// are shown, with what is in them and the dirs leading to them.
+    /// Others are as denied: in the tree as in the overlay, they can't
+    /// be looked up (ENOENT) nor created (EACCES).
+    pub included_paths: Vec<String>,
+
+    /// Paths (as globs) that are not shown, as `denied_paths`, even if
+    /// included.
+    pub excluded_paths: Vec<String>,
+
+    /// Paths (as globs, e.g. `Cargo.lock` or `build/**`) whose dirs
+    /// are listed and whose blobs are read when the commit is
+    /// mounted, and kept in the blob cache whatever its size, so that
+    /// they're served without going to the repository.  Changing it
+    /// takes effect on the next checkout or refresh.
+    pub pinned_paths: Vec<String>,
+
+    /// A dir where blobs checked out into the underlying dir are
+    /// kept, to be shared by mounts of the same repository.  Checked
+    /// out files are hard links to it until they're changed, if it's
+    /// on the same file system as the underlying dir.
+    pub blob_store: Option<PathBuf>,
+
+    /// Drop dirty files from the underlying dir when they're closed
+    /// after being opened for writing, if they're left the same as
+    /// their blob (see `GitFS::tidy`).  Each such close reads the file
+    /// and its blob to compare them.
+    pub tidy: bool,
+
+    /// Give clean entries xattrs telling the last commit that changed
+    /// them (see `gitfs::history`).  Finding it walks the history back
+    /// to that commit, once per path and mounted commit.
+    pub last_commit_xattrs: bool,
+
+    /// Show each file of the mounted commit larger than this many
+    /// bytes in chunks of that size too, in `.gitfs/chunks` (see
+    /// `gitfs::control`).
+    pub chunk_size: Option<u64>,
+
+    /// Convert clean files as a checkout would, going by their
+    /// `eol`, `text`, `ident` and `working-tree-encoding` attributes,
+    /// and convert dirty files back when they're committed.  Sizes
+    /// are those of the converted files.  Dirs already listed keep
+    /// their files as they are until they're listed again.
+    pub smudge: bool,
+
+    /// Show the markers of OCI image layers in the mounted commit as
+    /// overlayfs expects them in a lowerdir: `.wh.<name>` files as
+    /// whiteouts (char devices 0/0) named `<name>`, and dirs holding
+    /// `.wh..wh..opq` as opaque, with `trusted.overlay.opaque` and
+    /// `user.overlay.opaque` set to `y`.  Only on read-only mounts,
+    /// as the markers can't be changed through the mount.
+    pub whiteouts: bool,
+
+    /// Give every entry the DOS attributes Samba looks for in the
+    /// `user.DOSATTRIB` xattr, so that a mount shared over SMB shows
+    /// dot files as hidden, dirty files as changed (archive), and
+    /// files that can't be written to as read-only.  They can't be set.
+    pub dos_attributes: bool,
+
+    /// Show Git LFS pointer files as the objects they point to,
+    /// fetched from the LFS server into the LFS store of the
+    /// repository on first read if they're not there yet.  Dirs
+    /// already listed keep their pointer files until they're listed
+    /// again.
+    pub lfs: bool,
+
+    /// Show git-annex files (symlinks into the annex, and pointer
+    /// files) as their objects when those are in the local annex.
+    /// Dirs already listed keep their annexed files as they are until
+    /// they're listed again.
+    pub annex: bool,
+}
+
+/// How to handle names of a tree that collide in a case-insensitive
+/// underlying dir, e.g. `README.md` and `Readme.md`.
+///
+/// Colliding entries can always be read.  New names that collide with
+/// an existing one are refused with EEXIST, unless collisions are
+/// ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseCollisions {
+    /// Assume the underlying dir is case-sensitive.
+    Ignore,
+    /// Refuse to write to any of the colliding entries (EEXIST).
+    Error,
+    /// The first of the colliding entries (in byte order) can be
+    /// written to as usual; the others are read-only (EROFS).
+    ReadOnly,
+}
+
+impl std::str::FromStr for CaseCollisions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CaseCollisions, String> {
+        match s {
+            "ignore" => Ok(CaseCollisions::Ignore),
+            "error" => Ok(CaseCollisions::Error),
+            "read-only" => Ok(CaseCollisions::ReadOnly),
+            _ => Err(format!("unknown case collision policy: {}", s)),
+        }
+    }
+}
+
+/// Where the mtime and ctime of clean entries come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MtimePolicy {
+    /// The UNIX epoch.
+    Epoch,
+    /// The time of the last commit that changed the entry, following
+    /// first parents only.  The history of the mounted commit is
+    /// walked once, when a dir is first listed.
+    Commit,
+}
+
+impl std::str::FromStr for MtimePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MtimePolicy, String> {
+        match s {
+            "epoch" => Ok(MtimePolicy::Epoch),
+            "commit" => Ok(MtimePolicy::Commit),
+            _ => Err(format!("unknown mtime policy: {}", s)),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            attr_ttl: Duration::from_secs(1),
+            entry_ttl: Duration::from_secs(1),
+            blob_cache_size: 64 << 20,
+            max_readahead: 1 << 20,
+            max_write: 1 << 20,
+            max_background: 64,
+            congestion_threshold: None,
+            escape_names: false,
+            normalize_unicode: cfg!(target_os = "macos"),
+            case_collisions: if cfg!(target_os = "macos") {
+                CaseCollisions::ReadOnly
+            } else {
+                CaseCollisions::Ignore
+            },
+            uid: None,
+            gid: None,
+            read_only: false,
+            mtime: MtimePolicy::Epoch,
+            control_dir: true,
+            git_file: false,
+            slow_op_threshold: None,
+            summary_on_unmount: false,
+            overlay_quota: None,
+            read_rate: None,
+            write_rate: None,
+            iops: None,
+            denied_paths: vec![],
+            read_only_paths: vec![],
+            included_paths: vec![],
+            excluded_paths: vec![],
+            pinned_paths: vec![],
+            blob_store: None,
+            tidy: false,
+            last_commit_xattrs: false,
+            chunk_size: None,
+            smudge: false,
+            whiteouts: false,
+            dos_attributes: false,
+            lfs: false,
+            annex: false,
+        }
+    }
+}
@@ -0,0 +1,406 @@
+/// Export of the spans of FUSE operations over OTLP (the `otlp`
+/// feature).
+///
+/// Every operation already runs in a span with its ino, path and other
+/// arguments (e.g. the size of a read); this layer sends them, with
+/// their start and end times, to an OpenTelemetry collector with the
+/// JSON encoding of OTLP/HTTP.  Spans are batched, and sent by a thread
+/// of their own, so the operations don't wait on the collector, and
+/// dropped if it can't be reached, with a warning at most once a
+/// minute.  Only plain `http://` endpoints are supported.
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::fmt::{self, Write as _};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::logging::json_str;
+
+/// How many spans are sent at most in one request.
+const BATCH_SIZE: usize = 512;
+/// How long spans are held before they're sent.
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+/// How often failing to export is warned about, as a collector that's
+/// down fails every batch.
+const WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A layer sending every span it sees to an OTLP collector.  Filter
+/// it to the spans of interest, e.g. those of this crate.
+pub struct OtlpLayer {
+    spans: Mutex<Sender<String>>,
+}
+
+/// What is known of a span until it closes.
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+/// The spans that couldn't be exported since the last warning.
+#[derive(Default)]
+struct Dropped {
+    spans: usize,
+    warned: Option<Instant>,
+}
+
+impl Dropped {
+    /// Count `spans` more, and tell how many to warn about, if it's
+    /// time to.
+    fn add(&mut self, spans: usize, now: Instant) -> Option<usize> {
+        self.spans += spans;
+        if self.warned.is_some_and(|warned| now.saturating_duration_since(warned) < WARN_INTERVAL) {
+            return None;
+        }
+        self.warned = Some(now);
+        Some(std::mem::take(&mut self.spans))
+    }
+}
+
+impl OtlpLayer {
+    /// Send spans to `endpoint`, e.g. `http://localhost:4318/v1/traces`,
+    /// as the service `service`.
+    pub fn new(endpoint: &str, service: &str) -> io::Result<OtlpLayer> {
+        let endpoint = Endpoint::parse(endpoint)?;
+        let service = service.to_owned();
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("gitfs-otlp".to_owned())
+            .spawn(move || export(&endpoint, &service, receiver))?;
+        Ok(OtlpLayer {
+            spans: Mutex::new(sender),
+        })
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let parent = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<SpanData>().map(|data| (data.trace_id, data.span_id)));
+        let mut data = SpanData {
+            trace_id: parent.map_or_else(|| u128::from(random()) << 64 | u128::from(random()), |(trace, _)| trace),
+            span_id: random(),
+            parent_id: parent.map(|(_, span)| span),
+            start: SystemTime::now(),
+            attributes: vec![],
+        };
+        attrs.record(&mut data);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(data);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let data = match span.extensions_mut().remove::<SpanData>() {
+            Some(data) => data,
+            None => return,
+        };
+        let encoded = encode_span(span.name(), &data, SystemTime::now());
+        // The exporter only stops when the layer is dropped.
+        let _ = self.spans.lock().unwrap_or_else(|e| e.into_inner()).send(encoded);
+    }
+}
+
+impl Visit for SpanData {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.attributes.push((field.name(), Value::Str(format!("{:?}", value))));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.attributes.push((field.name(), Value::Str(value.to_owned())));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.attributes.push((field.name(), Value::Int(value)));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match i64::try_from(value) {
+            Ok(value) => self.attributes.push((field.name(), Value::Int(value))),
+            Err(_) => self.attributes.push((field.name(), Value::Str(value.to_string()))),
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.attributes.push((field.name(), Value::Bool(value)));
+    }
+}
+
+/// Random enough for ids, which need only be unique.
+fn random() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+/// Send the spans from `spans` in batches, until the layer is dropped.
+fn export(endpoint: &Endpoint, service: &str, spans: Receiver<String>) {
+    let mut batch = vec![];
+    let mut deadline: Option<Instant> = None;
+    let mut dropped = Dropped::default();
+    loop {
+        let received = match deadline {
+            Some(deadline) => spans.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => spans.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let done = match received {
+            Ok(span) => {
+                batch.push(span);
+                deadline.get_or_insert_with(|| Instant::now() + BATCH_DELAY);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        let due = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if !batch.is_empty() && (done || due || batch.len() >= BATCH_SIZE) {
+            if let Err(e) = endpoint.post(&encode_request(service, &batch)) {
+                // An event, which this layer doesn't export.
+                if let Some(spans) = dropped.add(batch.len(), Instant::now()) {
+                    warn!(spans, %e, "cannot export spans");
+                }
+            }
+            batch.clear();
+            deadline = None;
+        }
+        if done {
+            return;
+        }
+    }
+}
+
+/// Where spans are sent.
+struct Endpoint {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> io::Result<Endpoint> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", msg, url));
+        let rest = url.strip_prefix("http://").ok_or_else(|| invalid("not an http:// URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/v1/traces"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid("invalid port"))?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid("no host"));
+        }
+        Ok(Endpoint {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        write!(
+            &stream,
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            self.port,
+            body.len(),
+            body
+        )?;
+        (&stream).flush()?;
+        let mut status = String::new();
+        BufReader::new(&stream).read_line(&mut status)?;
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!("the collector answered {:?}", status.trim_end()))),
+        }
+    }
+}
+
+/// A span as an OTLP JSON object.
+fn encode_span(name: &str, data: &SpanData, end: SystemTime) -> String {
+    let mut out = String::new();
+    write!(
+        out,
+        "{{\"traceId\":\"{:032x}\",\"spanId\":\"{:016x}\",",
+        data.trace_id, data.span_id
+    )
+    .unwrap();
+    if let Some(parent_id) = data.parent_id {
+        write!(out, "\"parentSpanId\":\"{:016x}\",", parent_id).unwrap();
+    }
+    out.push_str("\"name\":");
+    out.push_str(&json_str(name));
+    write!(
+        out,
+        ",\"kind\":1,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[",
+        unix_nanos(data.start),
+        unix_nanos(end)
+    )
+    .unwrap();
+    for (i, (key, value)) in data.attributes.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        push_attribute(&mut out, key, value);
+    }
+    out.push_str("]}");
+    out
+}
+
+/// An export request of spans encoded with `encode_span`.
+fn encode_request(service: &str, spans: &[String]) -> String {
+    let mut out = String::from("{\"resourceSpans\":[{\"resource\":{\"attributes\":[");
+    push_attribute(&mut out, "service.name", &Value::Str(service.to_owned()));
+    out.push_str("]},\"scopeSpans\":[{\"scope\":{\"name\":\"rockmore-git\"},\"spans\":[");
+    out.push_str(&spans.join(","));
+    out.push_str("]}]}]}");
+    out
+}
+
+fn push_attribute(out: &mut String, key: &str, value: &Value) {
+    out.push_str("{\"key\":");
+    out.push_str(&json_str(key));
+    out.push_str(",\"value\":");
+    match value {
+        Value::Str(s) => {
+            out.push_str("{\"stringValue\":");
+            out.push_str(&json_str(s));
+            out.push('}');
+        }
+        // 64-bit integers are strings in the JSON encoding.
+        Value::Int(n) => write!(out, "{{\"intValue\":\"{}\"}}", n).unwrap(),
+        Value::Bool(b) => write!(out, "{{\"boolValue\":{}}}", b).unwrap(),
+    }
+    out.push('}');
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn encodes_spans() {
+        let data = SpanData {
+            trace_id: 1,
+            span_id: 2,
+            parent_id: Some(3),
+            start: UNIX_EPOCH + Duration::from_nanos(5),
+            attributes: vec![
+                ("ino", Value::Int(1)),
+                ("path", Value::Str("\"a\tb\"".to_owned())),
+                ("ok", Value::Bool(true)),
+            ],
+        };
+        let end = UNIX_EPOCH + Duration::from_nanos(8);
+        assert_eq!(
+            encode_span("read", &data, end),
+            concat!(
+                r#"{"traceId":"00000000000000000000000000000001","spanId":"0000000000000002","#,
+                r#""parentSpanId":"0000000000000003","name":"read","kind":1,"#,
+                r#""startTimeUnixNano":"5","endTimeUnixNano":"8","attributes":["#,
+                r#"{"key":"ino","value":{"intValue":"1"}},"#,
+                r#"{"key":"path","value":{"stringValue":"\"a\tb\""}},"#,
+                r#"{"key":"ok","value":{"boolValue":true}}]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn parses_endpoints() {
+        let endpoint = Endpoint::parse("http://collector:4318/v1/traces").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port, endpoint.path.as_str()), ("collector", 4318, "/v1/traces"));
+        let endpoint = Endpoint::parse("http://collector").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port, endpoint.path.as_str()), ("collector", 80, "/v1/traces"));
+        assert!(Endpoint::parse("https://collector").is_err());
+        assert!(Endpoint::parse("http://:4318").is_err());
+    }
+
+    #[test]
+    fn warns_of_dropped_spans_once_in_a_while() {
+        let mut dropped = Dropped::default();
+        let start = Instant::now();
+        assert_eq!(dropped.add(3, start), Some(3));
+        assert_eq!(dropped.add(4, start + Duration::from_secs(1)), None);
+        assert_eq!(dropped.add(5, start + Duration::from_secs(30)), None);
+        assert_eq!(dropped.add(1, start + WARN_INTERVAL), Some(10));
+        assert_eq!(dropped.add(2, start + WARN_INTERVAL), None);
+    }
+
+    #[test]
+    fn exports_spans() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let layer = OtlpLayer::new(&endpoint, "test").unwrap();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            let _outer = info_span!("lookup", ino = 1u64).entered();
+            let _inner = info_span!("read", size = 10usize).entered();
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = vec![];
+        let mut buf = [0; 4096];
+        while !String::from_utf8_lossy(&request).ends_with("]}]}]}") {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0);
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains(r#"{"key":"service.name","value":{"stringValue":"test"}}"#));
+        assert!(request.contains(r#""name":"lookup""#));
+        assert!(request.contains(r#"{"key":"size","value":{"intValue":"10"}}"#));
+        assert_eq!(request.matches("\"parentSpanId\"").count(), 1);
+    }
+}
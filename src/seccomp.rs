@@ -0,0 +1,309 @@
+/// Confining git-mount with a seccomp filter (`--seccomp`), once the
+/// mount or server is set up, to the system calls it makes from then
+/// on: file and socket I/O, memory, threads, signals and time.
+///
+/// Parsing repository data (packs, trees, attributes, LFS replies)
+/// then can't be turned into running programs, tracing other
+/// processes, mounting, or loading modules, whatever goes wrong in it.
+/// Unmounting is allowed, as the mount is undone on the way out: with
+/// `umount2` as root, and otherwise by the `fusermount3` started with
+/// `auto_unmount` when mounting, since libfuse can't run another one
+/// then.  The filter applies to every thread, and can't be lifted.  Since it
+/// takes `no_new_privs`, programs run later couldn't gain privileges
+/// either, which is why it's installed after mounting: `fusermount3`
+/// is setuid.  Only x86_64 and aarch64 are supported.
+use std::io;
+
+use libc::{c_long, c_uint, sock_filter, sock_fprog};
+
+/// What happens to a system call out of the list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// The whole process is killed, with SIGSYS.
+    Kill,
+    /// The call fails with EPERM.
+    Errno,
+    /// The call is logged by the kernel (in the audit log), and made:
+    /// to find what is missing from the list.
+    Log,
+}
+
+impl std::str::FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Action, String> {
+        match s {
+            "kill" => Ok(Action::Kill),
+            "errno" => Ok(Action::Errno),
+            "log" => Ok(Action::Log),
+            _ => Err(format!("unknown seccomp action: {}", s)),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+/// Set on the x32 system calls, which share the arch of x86_64.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// The system calls allowed on every arch.
+const ALLOWED: &[c_long] = &[
+    // Files and dirs.
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_getdents64,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_linkat,
+    libc::SYS_symlinkat,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_utimensat,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_fadvise64,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_flock,
+    libc::SYS_copy_file_range,
+    libc::SYS_sendfile,
+    libc::SYS_splice,
+    libc::SYS_getxattr,
+    libc::SYS_lgetxattr,
+    libc::SYS_fgetxattr,
+    libc::SYS_setxattr,
+    libc::SYS_lsetxattr,
+    libc::SYS_fsetxattr,
+    libc::SYS_listxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_flistxattr,
+    libc::SYS_removexattr,
+    libc::SYS_lremovexattr,
+    libc::SYS_fremovexattr,
+    libc::SYS_getcwd,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_pipe2,
+    libc::SYS_eventfd2,
+    libc::SYS_inotify_init1,
+    libc::SYS_inotify_add_watch,
+    libc::SYS_inotify_rm_watch,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    // Sockets, for the servers and LFS.
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_shutdown,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    // Memory.
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    // Threads.
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_get_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_membarrier,
+    libc::SYS_prctl,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // Unmounting on the way out, and waiting for the fusermount3 that
+    // libfuse then fails to run.
+    libc::SYS_umount2,
+    libc::SYS_wait4,
+    // Signals, also for panics, which abort.
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    // Who and when.
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getgroups,
+    libc::SYS_uname,
+    libc::SYS_sysinfo,
+    libc::SYS_getrandom,
+    libc::SYS_getrlimit,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_nanosleep,
+];
+
+/// The older system calls of x86_64 that libc may still make.
+#[cfg(target_arch = "x86_64")]
+const ALLOWED_ARCH: &[c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_rename,
+    libc::SYS_mkdir,
+    libc::SYS_rmdir,
+    libc::SYS_unlink,
+    libc::SYS_getdents,
+    libc::SYS_dup2,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_create,
+    libc::SYS_epoll_wait,
+    libc::SYS_time,
+    libc::SYS_arch_prctl,
+];
+#[cfg(target_arch = "aarch64")]
+const ALLOWED_ARCH: &[c_long] = &[];
+
+/// Confine this process, every thread of it, to the system calls
+/// gitfs makes, with `action` taken on the others.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn confine(action: Action) -> io::Result<()> {
+    install(&filter(action), libc::SECCOMP_FILTER_FLAG_TSYNC)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn confine(_action: Action) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "seccomp is only supported on x86_64 and aarch64"))
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn install(program: &[sock_filter], flags: libc::c_ulong) -> io::Result<()> {
+    let prog = sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_ptr() as *mut sock_filter,
+    };
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe { libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, flags, &prog as *const sock_fprog) };
+    match ret {
+        0 => Ok(()),
+        // With TSYNC, the id of a thread that can't take the filter.
+        ret if ret > 0 => Err(io::Error::other(format!("thread {} cannot be confined", ret))),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The BPF program of the filter: calls of another arch kill the
+/// process, and those not allowed get `action`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn filter(action: Action) -> Vec<sock_filter> {
+    const LD_W_ABS: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    const JEQ_K: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+    #[cfg(target_arch = "x86_64")]
+    const JGE_K: u16 = (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16;
+    const RET_K: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+    // The offsets of the fields of seccomp_data.
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    let op = |code, jt, jf, k| sock_filter { code, jt, jf, k };
+
+    let allowed: Vec<c_long> = ALLOWED.iter().chain(ALLOWED_ARCH).copied().collect();
+    let denied: c_uint = match action {
+        Action::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+        Action::Errno => libc::SECCOMP_RET_ERRNO | libc::EPERM as c_uint,
+        Action::Log => libc::SECCOMP_RET_LOG,
+    };
+    let mut program = vec![
+        op(LD_W_ABS, 0, 0, ARCH),
+        op(JEQ_K, 1, 0, AUDIT_ARCH),
+        op(RET_K, 0, 0, libc::SECCOMP_RET_KILL_PROCESS),
+        op(LD_W_ABS, 0, 0, NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    program.extend_from_slice(&[op(JGE_K, 0, 1, X32_SYSCALL_BIT), op(RET_K, 0, 0, libc::SECCOMP_RET_KILL_PROCESS)]);
+    // Each match jumps over the rest and the denial, to the allowance.
+    assert!(allowed.len() <= u8::MAX as usize);
+    for (i, &nr) in allowed.iter().enumerate() {
+        program.push(op(JEQ_K, (allowed.len() - i) as u8, 0, nr as u32));
+    }
+    program.push(op(RET_K, 0, 0, denied));
+    program.push(op(RET_K, 0, 0, libc::SECCOMP_RET_ALLOW));
+    program
+}
+
+#[cfg(test)]
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_allowed_calls_are_made() {
+        // In a thread of its own, which alone takes the filter.
+        std::thread::spawn(|| {
+            install(&filter(Action::Errno), 0).unwrap();
+            assert!(std::fs::read("/proc/self/status").is_ok());
+            assert_eq!(unsafe { libc::syscall(libc::SYS_getpid) }, std::process::id() as c_long);
+            let ret = unsafe { libc::syscall(libc::SYS_ptrace, libc::PTRACE_TRACEME, 0, 0, 0) };
+            assert_eq!((ret, io::Error::last_os_error().raw_os_error()), (-1, Some(libc::EPERM)));
+            let ret = unsafe { libc::unshare(libc::CLONE_NEWUSER) };
+            assert_eq!((ret, io::Error::last_os_error().raw_os_error()), (-1, Some(libc::EPERM)));
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+/// Running as a systemd service, without libsystemd: telling systemd
+/// that the mount is ready (`Type=notify`), and serving on a socket
+/// systemd listens on (socket activation).
+///
+/// Both are no-ops when not run by systemd, so they needn't be asked
+/// for: `NOTIFY_SOCKET` and `LISTEN_FDS` tell whether they apply.
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The first fd passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Whether the passed socket was taken, by the first server started.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Tell systemd how the service is doing, e.g. `READY=1`.  Return
+/// whether there was anyone to tell.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let socket = UnixDatagram::unbound()?;
+    send_to(&socket, state.as_bytes(), &path)?;
+    Ok(true)
+}
+
+#[cfg(target_os = "linux")]
+fn send_to(socket: &UnixDatagram, data: &[u8], path: &std::ffi::OsStr) -> io::Result<usize> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::SocketAddr;
+
+    // systemd may listen in the abstract namespace.
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    socket.send_to_addr(data, &addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_to(socket: &UnixDatagram, data: &[u8], path: &std::ffi::OsStr) -> io::Result<usize> {
+    socket.send_to(data, path)
+}
+
+/// The socket systemd passed to listen on, if it passed one to this
+/// process.  Only the first call gets it.
+pub(crate) fn take_listener() -> io::Result<Option<TcpListener>> {
+    let for_us = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok()) == Some(std::process::id());
+    let fds: usize = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse().ok()).unwrap_or(0);
+    if !for_us || fds == 0 || TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!(fds, "systemd passed more than one socket, serving on the first");
+    }
+    let fd = LISTEN_FDS_START;
+    let mut st = std::mem::MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::fstat(fd, st.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { st.assume_init() }.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the fd passed by systemd is not a socket"));
+    }
+    // Not to be inherited by what the mount runs, e.g. fusermount.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(unsafe { TcpListener::from_raw_fd(fd) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_sends_the_state() {
+        let path = std::env::temp_dir().join(format!("gitfs-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+        assert!(notify("READY=1").unwrap());
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(!notify("READY=1").unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn no_listener_unless_passed() {
+        assert!(take_listener().unwrap().is_none());
+    }
+}
@@ -0,0 +1,118 @@
+/// Mounting a repository in-process, for tests that go through the
+/// kernel rather than call into `GitFS`: plain `std::fs` calls on
+/// `TestMount::path()` are then served by gitfs on a background
+/// thread.
+///
+/// Each mount gets a dir of its own under the temp dir, which is also
+/// the underlying dir, as with `git-mount`.  It's unmounted and
+/// removed on drop.  Mounting takes FUSE (`/dev/fuse`, and
+/// `fusermount3` unless root), which tests may have to do without:
+/// `mount_for_test` fails then, and the test can be skipped.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use fuser::{BackgroundSession, MountOption};
+use git2::Repository;
+use openat::Dir;
+
+use crate::gitfs::GitFS;
+use crate::options::Options;
+
+mod genrepo;
+#[cfg(test)]
+pub(crate) use genrepo::Rng;
+pub use genrepo::{generate, generate_at, Spec};
+pub use crate::gitfs::model::{Model, Node, Op};
+
+/// A repository mounted until dropped.
+pub struct TestMount {
+    path: PathBuf,
+    fs: GitFS,
+    session: Option<BackgroundSession>,
+}
+
+/// Mount `repo` with the default options.
+pub fn mount_for_test(repo: Repository) -> io::Result<TestMount> {
+    mount_for_test_with(repo, Options::default())
+}
+
+/// Mount `repo` with `options`.
+pub fn mount_for_test_with(repo: Repository, options: Options) -> io::Result<TestMount> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "gitfs-mount-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&path)?;
+    let mounted = Dir::open(&path).and_then(|dir| {
+        let fs = GitFS::builder(repo, dir).options(options).build();
+        let options = [MountOption::FSName("gitfs".to_owned())];
+        // The mount is there once this returns; the kernel waits for
+        // the session thread to answer.
+        let session = fuser::spawn_mount2(fs.clone(), &path, &options)?;
+        Ok((fs, session))
+    });
+    match mounted {
+        Ok((fs, session)) => Ok(TestMount { path, fs, session: Some(session) }),
+        Err(e) => {
+            let _ = fs::remove_dir_all(&path);
+            Err(e)
+        }
+    }
+}
+
+/// A repository of `a.txt` and `dir/b.txt`, in a dir named after
+/// `name` under the temp dir, with the underlying dir at `overlay`.
+/// For the tests of the servers, which call into `GitFS` without
+/// mounting it.  Returns the dir, for the test to remove.
+#[cfg(test)]
+pub(crate) fn fs_for_test(name: &str) -> (PathBuf, GitFS) {
+    let root = std::env::temp_dir().join(format!("gitfs-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("overlay")).unwrap();
+    let repo = Repository::init(root.join("repo")).unwrap();
+    {
+        let mut sub = repo.treebuilder(None).unwrap();
+        sub.insert("b.txt", repo.blob(b"in a dir").unwrap(), 0o100644).unwrap();
+        let sub = sub.write().unwrap();
+        let mut top = repo.treebuilder(None).unwrap();
+        top.insert("a.txt", repo.blob(b"hello world").unwrap(), 0o100644).unwrap();
+        top.insert("dir", sub, 0o040000).unwrap();
+        let tree = repo.find_tree(top.write().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap();
+    }
+    let fs = GitFS::new(repo, Dir::open(&root.join("overlay")).unwrap());
+    (root, fs)
+}
+
+impl TestMount {
+    /// Where the repository is mounted.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The mounted file system, e.g. to `refresh()` or `commit()` it.
+    pub fn fs(&self) -> &GitFS {
+        &self.fs
+    }
+}
+
+impl Drop for TestMount {
+    fn drop(&mut self) {
+        // Unmount, and wait for the session to end, which fails the
+        // test if it failed; not while failing already.
+        if let Some(session) = self.session.take() {
+            match std::thread::panicking() {
+                false => session.join(),
+                true => drop(session),
+            }
+        }
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!(path = ?self.path, %e, "cannot remove the test mount");
+        }
+    }
+}
@@ -0,0 +1,241 @@
+/// Generating repositories to test against (`gitfs-genrepo`): trees
+/// of a given depth and width, with files of sizes in a given range,
+/// symlinks, submodules and non-ASCII names, all drawn from a seed.
+///
+/// The same `Spec` always makes the same commit, down to its id, so a
+/// failure or a timing seen on one tree can be had again elsewhere.
+/// Only objects are written, and HEAD moved: nothing is checked out.
+use std::ops::Range;
+use std::path::Path;
+
+use git2::{Oid, Repository, Signature, Time};
+
+/// What to generate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spec {
+    /// Where everything else is drawn from.
+    pub seed: u64,
+
+    /// Levels of dirs under the root.
+    pub depth: usize,
+
+    /// Dirs in each dir but the deepest.
+    pub fanout: usize,
+
+    /// Files in each dir.
+    pub files: usize,
+
+    /// Sizes (in bytes) of the files.
+    pub file_sizes: Range<usize>,
+
+    /// Symlinks in each dir, to files, dirs, or nothing.
+    pub symlinks: usize,
+
+    /// Submodules in the root, with their `.gitmodules`.  Their
+    /// commits aren't in the repository, as they'd be in a clone that
+    /// wasn't given `--recurse-submodules`.
+    pub submodules: usize,
+
+    /// Whether names may be non-ASCII, composed or not.
+    pub unicode: bool,
+}
+
+impl Default for Spec {
+    fn default() -> Spec {
+        Spec {
+            seed: 0,
+            depth: 2,
+            fanout: 3,
+            files: 4,
+            file_sizes: 0..4096,
+            symlinks: 1,
+            submodules: 0,
+            unicode: false,
+        }
+    }
+}
+
+/// Words names are made of.  The decomposed ones are equal to the
+/// composed ones once normalized, but never in the same dir under the
+/// same number.
+const WORDS: &[&str] = &["alpha", "beta", "gamma", "delta", "notes", "src", "data", "test"];
+const UNICODE_WORDS: &[&str] = &[
+    "café",
+    "cafe\u{301}",
+    "naïve",
+    "Ångström",
+    "A\u{30a}ngstro\u{308}m",
+    "日本語",
+    "Ελληνικά",
+    "русский",
+    "emoji 🎉",
+    "tab\tand space",
+];
+
+/// splitmix64, which is enough for picking names and sizes.
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub(crate) fn within(&mut self, range: &Range<usize>) -> usize {
+        match range.end > range.start {
+            true => range.start + self.below(range.end - range.start),
+            false => range.start,
+        }
+    }
+}
+
+struct Generator<'a> {
+    repo: &'a Repository,
+    spec: &'a Spec,
+    rng: Rng,
+}
+
+impl Generator<'_> {
+    /// A name for the `i`th entry of its kind in a dir, which no other
+    /// entry of the dir is given.
+    fn name(&mut self, kind: &str, i: usize) -> String {
+        let word = match self.spec.unicode && self.rng.below(2) == 0 {
+            true => UNICODE_WORDS[self.rng.below(UNICODE_WORDS.len())],
+            false => WORDS[self.rng.below(WORDS.len())],
+        };
+        format!("{}-{}{}", word, kind, i)
+    }
+
+    /// Text mostly, with a NUL in one file out of eight.
+    fn content(&mut self) -> Vec<u8> {
+        let size = self.rng.within(&self.spec.file_sizes);
+        let binary = self.rng.below(8) == 0;
+        (0..size)
+            .map(|_| match self.rng.below(64) {
+                0 => b'\n',
+                1 if binary => 0,
+                n => b'a' + (n % 26) as u8,
+            })
+            .collect()
+    }
+
+    fn tree(&mut self, depth: usize) -> Result<Oid, git2::Error> {
+        let mut tree = self.repo.treebuilder(None)?;
+        let mut targets = vec![];
+        for i in 0..self.spec.files {
+            let name = self.name("file", i);
+            let content = self.content();
+            let mode = match self.rng.below(4) {
+                0 => 0o100755,
+                _ => 0o100644,
+            };
+            tree.insert(&name, self.repo.blob(&content)?, mode)?;
+            targets.push(name);
+        }
+        if depth > 0 {
+            for i in 0..self.spec.fanout {
+                let name = self.name("dir", i);
+                let oid = self.tree(depth - 1)?;
+                tree.insert(&name, oid, 0o040000)?;
+                targets.push(format!("{}/", name));
+            }
+        }
+        for i in 0..self.spec.symlinks {
+            let name = self.name("link", i);
+            let target = match self.rng.below(targets.len() + 2) {
+                n if n < targets.len() => targets[n].clone(),
+                n if n == targets.len() => "../missing".to_owned(),
+                _ => "/etc/hostname".to_owned(),
+            };
+            tree.insert(&name, self.repo.blob(target.as_bytes())?, 0o120000)?;
+        }
+        tree.write()
+    }
+
+    /// The root: a tree of `depth`, and the submodules.
+    fn root(&mut self) -> Result<Oid, git2::Error> {
+        let oid = self.tree(self.spec.depth)?;
+        if self.spec.submodules == 0 {
+            return Ok(oid);
+        }
+        let base = self.repo.find_tree(oid)?;
+        let mut tree = self.repo.treebuilder(Some(&base))?;
+        let mut gitmodules = String::new();
+        for i in 0..self.spec.submodules {
+            let name = self.name("module", i);
+            let commit = Oid::from_bytes(&self.rng.next().to_be_bytes().repeat(3)[..20])?;
+            tree.insert(&name, commit, 0o160000)?;
+            gitmodules += &format!(
+                "[submodule \"{0}\"]\n\tpath = {0}\n\turl = https://example.com/{1}.git\n",
+                name, i
+            );
+        }
+        tree.insert(".gitmodules", self.repo.blob(gitmodules.as_bytes())?, 0o100644)?;
+        tree.write()
+    }
+}
+
+/// Write the tree of `spec` to `repo`, commit it on HEAD, and return
+/// the commit.
+pub fn generate(repo: &Repository, spec: &Spec) -> Result<Oid, git2::Error> {
+    let tree = Generator { repo, spec, rng: Rng(spec.seed) }.root()?;
+    let tree = repo.find_tree(tree)?;
+    let sig = Signature::new("gitfs", "gitfs@example.com", &Time::new(0, 0))?;
+    let message = format!("generated from seed {}", spec.seed);
+    repo.commit(Some("HEAD"), &sig, &sig, &message, &tree, &[])
+}
+
+/// Make a repository at `path` and generate the tree of `spec` in it.
+pub fn generate_at(path: &Path, spec: &Spec) -> Result<Repository, git2::Error> {
+    let repo = Repository::init(path)?;
+    generate(&repo, spec)?;
+    Ok(repo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{ObjectType, TreeWalkMode, TreeWalkResult};
+
+    #[test]
+    fn same_seed_same_repository() {
+        let base = std::env::temp_dir().join(format!("gitfs-genrepo-{}", std::process::id()));
+        let spec = Spec { symlinks: 2, submodules: 2, unicode: true, ..Spec::default() };
+        let first = generate_at(&base.join("first"), &spec).unwrap();
+        let second = generate_at(&base.join("second"), &spec).unwrap();
+        let other = generate_at(&base.join("other"), &Spec { seed: 1, ..spec.clone() }).unwrap();
+        let head = |repo: &Repository| repo.head().unwrap().target().unwrap();
+        assert_eq!(head(&first), head(&second));
+        assert_ne!(head(&first), head(&other));
+
+        let (mut blobs, mut trees, mut links, mut modules, mut unicode) = (0, 0, 0, 0, 0);
+        let tree = first.head().unwrap().peel_to_tree().unwrap();
+        tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+            match (entry.kind(), entry.filemode()) {
+                (Some(ObjectType::Blob), 0o120000) => links += 1,
+                (Some(ObjectType::Blob), _) => {
+                    blobs += 1;
+                    let size = first.find_blob(entry.id()).unwrap().size();
+                    assert!(entry.name() == Some(".gitmodules") || size < 4096);
+                }
+                (Some(ObjectType::Tree), _) => trees += 1,
+                (Some(ObjectType::Commit), _) => modules += 1,
+                _ => unreachable!(),
+            }
+            unicode += !entry.name_bytes().is_ascii() as usize;
+            TreeWalkResult::Ok
+        })
+        .unwrap();
+        // 1 + 3 + 9 dirs, with 4 files and 2 links each.
+        assert_eq!((trees, blobs, links, modules), (12, 13 * 4 + 1, 13 * 2, 2));
+        assert!(unicode > 0);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
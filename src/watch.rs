@@ -0,0 +1,435 @@
+/// Watch for changes made to the repository or the underlying dir by
+/// others.
+///
+/// The ref watcher polls the files git updates when a ref moves
+/// (`HEAD`, `packed-refs` and everything under `refs/`), and refreshes
+/// the file system when any of them changed, so that e.g. a commit
+/// made in the real worktree shows up in the mount.  Mounting a commit
+/// id rather than a ref makes watching a no-op.
+///
+/// The fetcher fetches a branch of a remote every so often, and
+/// refreshes the file system, so that a mount of its remote-tracking
+/// ref keeps up with the branch, as a mirror on a server would.
+///
+/// The overlay watcher (Linux only) follows the underlying dir with
+/// inotify, and marks the dirs that changed there to be listed again.
+///
+/// The kernel isn't told about changes; it sees them once the
+/// attributes and names it cached expire (see `Options`).
+///
+/// The state watcher isn't about changes: it waits for SIGUSR1, and
+/// logs what the mount holds when it comes.  Nor is the config
+/// watcher, which waits for SIGHUP to read the config file again.
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+#[cfg(target_os = "linux")]
+use std::sync::mpsc::TryRecvError;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::ffi::{CString, OsStr};
+use std::mem;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+#[cfg(target_os = "linux")]
+use libc::c_int;
+
+use git2::{Cred, CredentialType, FetchOptions, RemoteCallbacks, Repository};
+
+use crate::gitfs::GitFS;
+
+/// A running watcher (or other background thread of a mount, such as
+/// the metrics server).  Dropping it stops the watcher.
+#[derive(Debug)]
+pub struct Watcher {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// How the watched files looked: path, ino, size and mtime of each.
+type Fingerprint = Vec<(PathBuf, u64, u64, i64, i64)>;
+
+impl Watcher {
+    /// Watch the files of the repository at `git_dir` every
+    /// `interval`, and refresh `fs` when they change.
+    pub(crate) fn spawn(fs: GitFS, git_dir: &Path, interval: Duration) -> io::Result<Watcher> {
+        let mut dirs = vec![git_dir.to_owned()];
+        // Linked worktrees keep their refs in the main repository.
+        if let Ok(common) = fs::read_to_string(git_dir.join("commondir")) {
+            dirs.push(git_dir.join(common.trim_end()));
+        }
+        // Taken right away, so that changes made while the thread starts
+        // are not missed.
+        let mut seen = fingerprint(&dirs);
+        Self::run("gitfs-watcher", move |stopped| {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let now = fingerprint(&dirs);
+                if now == seen {
+                    continue;
+                }
+                match fs.refresh() {
+                    Ok(changed) => {
+                        debug!(changed, "refs changed, refreshed");
+                        seen = now;
+                    }
+                    // Keep the old fingerprint to try again later,
+                    // e.g. once dirty files are closed.
+                    Err(e) => warn!(%e, "refs changed, but cannot refresh"),
+                }
+            }
+        })
+    }
+
+    /// Fetch `branch` of `remote` into the repository at `git_dir`
+    /// every `interval`, and refresh `fs`, which switches to the new
+    /// commit if its ref was moved.
+    pub(crate) fn spawn_fetch(fs: GitFS, git_dir: &Path, remote: &str, branch: &str, interval: Duration) -> io::Result<Watcher> {
+        let (git_dir, remote, branch) = (git_dir.to_owned(), remote.to_owned(), branch.to_owned());
+        Self::run("gitfs-fetcher", move |stopped| {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = fetch(&git_dir, &remote, &branch) {
+                    // Tried again next time, e.g. once the network is back.
+                    warn!(%e, %remote, %branch, "cannot fetch");
+                    continue;
+                }
+                match fs.refresh() {
+                    Ok(changed) => debug!(changed, "fetched, refreshed"),
+                    Err(e) => warn!(%e, "fetched, but cannot refresh"),
+                }
+            }
+        })
+    }
+
+    /// Watch the underlying dir, opened as `dir`, and tell `fs` about
+    /// the dirs that changed.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn spawn_overlay(fs: GitFS, dir: RawFd) -> io::Result<Watcher> {
+        let mut inotify = Inotify::new(dir)?;
+        inotify.add_tree(Path::new(""));
+        Self::run("gitfs-overlay-watcher", move |stopped| {
+            fs.set_overlay_watched(true);
+            // Changes made before the watches were in place.
+            fs.overlay_changed(None);
+            while let Err(TryRecvError::Empty) = stopped.try_recv() {
+                match inotify.wait(Duration::from_millis(100)) {
+                    Ok(changes) => {
+                        for change in changes {
+                            fs.overlay_changed(change.as_deref());
+                        }
+                    }
+                    Err(e) => {
+                        error!(%e, "cannot watch the underlying dir");
+                        break;
+                    }
+                }
+            }
+            fs.set_overlay_watched(false);
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn spawn_overlay(_fs: GitFS, _dir: RawFd) -> io::Result<Watcher> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "cannot watch the underlying dir"))
+    }
+
+    /// Log the state of `fs` whenever the process gets SIGUSR1.  The
+    /// signal handler is left in place once the watcher is dropped, so
+    /// that a late signal doesn't kill the process.
+    pub(crate) fn spawn_state_dump(fs: GitFS) -> io::Result<Watcher> {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = request_state_dump as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // The FUSE session shouldn't see EINTR.
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Self::run("gitfs-state-dump", move |stopped| {
+            // Only an atomic store is safe in the handler, so the flag
+            // is polled.
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(Duration::from_millis(100)) {
+                if STATE_DUMP_REQUESTED.swap(false, Ordering::SeqCst) {
+                    fs.dump_state();
+                }
+            }
+        })
+    }
+
+    /// Reload the config file of `fs` whenever the process gets
+    /// SIGHUP, which would end it otherwise.  As with SIGUSR1, the
+    /// handler is left in place.  Every watcher reloads its mount, so
+    /// that one signal reloads them all when there are several.
+    pub(crate) fn spawn_config_reload(fs: GitFS) -> io::Result<Watcher> {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = request_config_reload as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Self::run("gitfs-config-reload", move |stopped| {
+            let mut seen = CONFIG_RELOADS.load(Ordering::SeqCst);
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(Duration::from_millis(100)) {
+                let requested = CONFIG_RELOADS.load(Ordering::SeqCst);
+                if requested != seen {
+                    seen = requested;
+                    // Logged, and the mount goes on as it was.
+                    let _ = fs.reload_config();
+                }
+            }
+        })
+    }
+
+    /// Run `body` in a thread, until the receiver it's given is
+    /// disconnected.
+    pub(crate) fn run<F>(name: &str, body: F) -> io::Result<Watcher>
+    where
+        F: FnOnce(Receiver<()>) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || body(stopped))?;
+        Ok(Watcher {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        // Disconnecting wakes the thread up.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("gitfs watcher panicked");
+            }
+        }
+    }
+}
+
+static STATE_DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_state_dump(_: libc::c_int) {
+    STATE_DUMP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// How many times SIGHUP came.
+static CONFIG_RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn request_config_reload(_: libc::c_int) {
+    CONFIG_RELOADS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Fetch `branch` of `remote` into the repository at `git_dir`, as
+/// `git fetch <remote> <branch>` does, to its remote-tracking ref
+/// (`refs/remotes/<remote>/<branch>`), even if that isn't a
+/// fast-forward.  The repository is opened anew, so that the mount
+/// isn't held up for as long as the fetch takes.
+///
+/// Credentials are asked of the SSH agent, then of git's credential
+/// helpers, once each.
+pub fn fetch(git_dir: &Path, remote: &str, branch: &str) -> Result<(), git2::Error> {
+    let repo = Repository::open(git_dir)?;
+    let config = repo.config()?;
+    let mut tried = CredentialType::empty();
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        if allowed.contains(CredentialType::SSH_KEY) && !tried.contains(CredentialType::SSH_KEY) {
+            tried |= CredentialType::SSH_KEY;
+            return Cred::ssh_key_from_agent(username.unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !tried.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            tried |= CredentialType::USER_PASS_PLAINTEXT;
+            return Cred::credential_helper(&config, url, username);
+        }
+        Err(git2::Error::from_str("no credentials left to try"))
+    });
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks);
+    let refspec = format!("+refs/heads/{}:refs/remotes/{}/{}", branch, remote, branch);
+    let mut remote = repo.find_remote(remote)?;
+    remote.fetch(&[&refspec], Some(&mut options), None)
+}
+
+fn fingerprint(dirs: &[PathBuf]) -> Fingerprint {
+    let mut files = vec![];
+    for dir in dirs {
+        files.push(dir.join("HEAD"));
+        files.push(dir.join("packed-refs"));
+        list_files(&dir.join("refs"), &mut files);
+    }
+    files
+        .into_iter()
+        .filter_map(|path| {
+            let metadata = fs::symlink_metadata(&path).ok()?;
+            let (ino, size) = (metadata.ino(), metadata.size());
+            Some((path, ino, size, metadata.mtime(), metadata.mtime_nsec()))
+        })
+        .collect()
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut paths: Vec<_> = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            list_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Watches on the underlying dir and all dirs in it.
+#[cfg(target_os = "linux")]
+struct Inotify {
+    fd: RawFd,
+    /// Where the underlying dir can be opened by path.
+    root: PathBuf,
+    /// The dir of each watch, relative to the underlying dir.
+    dirs: HashMap<c_int, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Inotify {
+    fn new(dir: RawFd) -> io::Result<Inotify> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify {
+            fd,
+            root: PathBuf::from(format!("/proc/self/fd/{}", dir)),
+            dirs: HashMap::new(),
+        })
+    }
+
+    /// Watch `dir` and the dirs in it, recursively.
+    fn add_tree(&mut self, dir: &Path) {
+        let mask = libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_ATTRIB
+            | libc::IN_ONLYDIR;
+        let path = CString::new(self.root.join(dir).into_os_string().into_vec());
+        let wd = match path {
+            Ok(path) => unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), mask) },
+            Err(_) => return,
+        };
+        if wd < 0 {
+            debug!(?dir, e = %io::Error::last_os_error(), "cannot watch dir");
+            return;
+        }
+        self.dirs.insert(wd, dir.to_owned());
+        let mut subdirs = vec![];
+        list_dirs(&self.root.join(dir), &mut subdirs);
+        for subdir in subdirs {
+            self.add_tree(&dir.join(subdir));
+        }
+    }
+
+    /// Wait up to `timeout` for changes, and return the dirs that
+    /// changed.  `None` means that events were lost, so that anything
+    /// may have changed.
+    fn wait(&mut self, timeout: Duration) -> io::Result<Vec<Option<PathBuf>>> {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as c_int) } < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::Interrupted => Ok(vec![]),
+                _ => Err(e),
+            };
+        }
+
+        let mut changes = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(e),
+                }
+            }
+            let mut offset = 0;
+            while offset + mem::size_of::<libc::inotify_event>() <= n as usize {
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event) };
+                let start = offset + mem::size_of::<libc::inotify_event>();
+                let name = &buf[start..start + event.len as usize];
+                let name = OsStr::from_bytes(name.split(|&b| b == 0).next().unwrap_or_default());
+                offset = start + event.len as usize;
+
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    changes.push(None);
+                    continue;
+                }
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+                let dir = match self.dirs.get(&event.wd) {
+                    Some(dir) => dir.clone(),
+                    None => continue,
+                };
+                let is_dir = event.mask & libc::IN_ISDIR != 0;
+                if is_dir && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    self.add_tree(&dir.join(name));
+                }
+                trace!(?dir, ?name, mask = event.mask, "underlying dir changed");
+                if event.mask & libc::IN_ATTRIB != 0 {
+                    // Files are looked at on every getattr anyway.
+                    if is_dir {
+                        changes.push(Some(dir.join(name)));
+                    }
+                    continue;
+                }
+                changes.push(Some(dir));
+            }
+        }
+        changes.dedup();
+        Ok(changes)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Append the names of the dirs in `dir` to `dirs`.
+#[cfg(target_os = "linux")]
+fn list_dirs(dir: &Path, dirs: &mut Vec<PathBuf>) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        let entries = entries.filter_map(|entry| entry.ok());
+        dirs.extend(entries.filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir())).map(|entry| entry.file_name().into()));
+    }
+}
@@ -0,0 +1,61 @@
+//! Going through the kernel: a repository mounted in-process.
+
+use std::fs;
+use std::io::ErrorKind;
+
+use git2::{Repository, Signature};
+use rockmore_git::testing::{mount_for_test, TestMount};
+
+/// A fresh repository with `a.txt` and `dir/b.txt`, mounted; `None`
+/// where FUSE can't be used.
+fn mount(name: &str) -> Option<(TestMount, std::path::PathBuf)> {
+    let path = std::env::temp_dir().join(format!("gitfs-it-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    let repo = Repository::init(&path).unwrap();
+    {
+        let a = repo.blob(b"hello world").unwrap();
+        let b = repo.blob(b"in a dir").unwrap();
+        let mut sub = repo.treebuilder(None).unwrap();
+        sub.insert("b.txt", b, 0o100644).unwrap();
+        let sub = sub.write().unwrap();
+        let mut top = repo.treebuilder(None).unwrap();
+        top.insert("a.txt", a, 0o100644).unwrap();
+        top.insert("dir", sub, 0o040000).unwrap();
+        let tree = repo.find_tree(top.write().unwrap()).unwrap();
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap();
+    }
+    match mount_for_test(repo) {
+        Ok(mount) => Some((mount, path)),
+        Err(e) => {
+            eprintln!("skipped, as FUSE can't be used: {}", e);
+            let _ = fs::remove_dir_all(&path);
+            None
+        }
+    }
+}
+
+#[test]
+fn files_are_read_and_written_through_the_kernel() {
+    let (mount, repo) = match mount("rw") {
+        Some(mounted) => mounted,
+        None => return,
+    };
+    let root = mount.path();
+    assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"hello world");
+    assert_eq!(fs::read(root.join("dir/b.txt")).unwrap(), b"in a dir");
+    let mut names: Vec<_> = fs::read_dir(root).unwrap().map(|e| e.unwrap().file_name()).collect();
+    names.sort();
+    assert_eq!(names, ["a.txt", "dir"]);
+
+    fs::write(root.join("a.txt"), "changed").unwrap();
+    fs::write(root.join("dir/new.txt"), "new").unwrap();
+    assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"changed");
+    fs::remove_file(root.join("dir/b.txt")).unwrap();
+    assert_eq!(fs::metadata(root.join("dir/b.txt")).unwrap_err().kind(), ErrorKind::NotFound);
+    let status = fs::read_to_string(root.join(".gitfs/status")).unwrap();
+    assert!(status.contains("M\ta.txt"), "{}", status);
+
+    drop(mount);
+    fs::remove_dir_all(repo).unwrap();
+}
@@ -0,0 +1,41 @@
+//! Unmounting under the seccomp filter, which takes the whole test
+//! binary: it can't be lifted once installed.
+#![cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+
+use std::fs;
+
+use git2::{Repository, Signature};
+use rockmore_git::seccomp::{self, Action};
+use rockmore_git::testing::mount_for_test;
+
+#[test]
+fn mounts_are_undone_under_the_filter() {
+    let path = std::env::temp_dir().join(format!("gitfs-it-seccomp-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    let repo = Repository::init(&path).unwrap();
+    {
+        let mut top = repo.treebuilder(None).unwrap();
+        top.insert("a.txt", repo.blob(b"hello world").unwrap(), 0o100644).unwrap();
+        let tree = repo.find_tree(top.write().unwrap()).unwrap();
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap();
+    }
+    let mount = match mount_for_test(repo) {
+        Ok(mount) => mount,
+        Err(e) => {
+            eprintln!("skipped, as FUSE can't be used: {}", e);
+            fs::remove_dir_all(path).unwrap();
+            return;
+        }
+    };
+    let mountpoint = mount.path().to_owned();
+
+    // Killed, rather than left mounted, on anything out of the list.
+    seccomp::confine(Action::Kill).unwrap();
+    assert_eq!(fs::read(mountpoint.join("a.txt")).unwrap(), b"hello world");
+    drop(mount);
+    let mounts = fs::read_to_string("/proc/self/mounts").unwrap();
+    assert!(!mounts.contains(mountpoint.to_str().unwrap()), "{}", mounts);
+    assert!(!mountpoint.exists());
+    fs::remove_dir_all(path).unwrap();
+}